@@ -0,0 +1,126 @@
+//! Compares the two backends of the main listener: epoll, the default,
+//! and io_uring (`--runtime uring`, see `src/uring.rs`).
+//!
+//! ```bash
+//! cargo bench --features uring --bench backends
+//! ```
+//!
+//! Each backend serves over loopback, on a thread of its own with its own
+//! runtime: a current_thread Tokio runtime for epoll, tokio-uring's for
+//! io_uring. Both serve the connection with `Connection::serve`, the
+//! io_uring one through a pipe and `uring::pump` as the server does, so the
+//! pipe's copies are part of its numbers. The client is a blocking socket
+//! on the benchmark's thread: it writes `BATCH` messages at once and reads
+//! every reply back.
+
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use socket2::SockRef;
+use std::hint::black_box;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use tokio_examples::listener::SocketOptions;
+use tokio_examples::logger::LogLevel;
+use tokio_examples::{Server, ServerConfig, uring};
+use tokio_stream::StreamExt;
+
+/// Messages written at once, and replies read back, per iteration.
+const BATCH: u64 = 100;
+
+#[derive(Clone, Copy)]
+enum Backend {
+    Epoll,
+    Uring,
+}
+
+fn config() -> ServerConfig {
+    ServerConfig {
+        listen: SocketAddr::from(([127, 0, 0, 1], 0)),
+        log_level: LogLevel::Error,
+        log_messages: false,
+        socket: SocketOptions { nodelay: true, ..SocketOptions::default() },
+        ..Default::default()
+    }
+}
+
+/// Starts a server on `backend`, on a thread of its own that serves until
+/// the benchmark exits, and returns its address.
+fn spawn_server(backend: Backend) -> SocketAddr {
+    let (addr_tx, addr_rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || match backend {
+        Backend::Epoll => {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(async {
+                let server = Server::bind(config()).await.unwrap();
+                addr_tx.send(server.local_addr().unwrap()).unwrap();
+                let mut incoming = server.incoming();
+                while let Some(conn) = incoming.next().await {
+                    tokio::spawn(conn.serve());
+                }
+            })
+        }
+        Backend::Uring => uring::start(async {
+            let config = config();
+            let options = config.socket;
+            // Its own listener stays idle: connections come from this one
+            let server = Server::bind(config).await.unwrap();
+            let listener = uring::TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)));
+            let listener = listener.unwrap();
+            addr_tx.send(listener.local_addr().unwrap()).unwrap();
+            loop {
+                let (stream, peer) = listener.accept().await.unwrap();
+                uring::apply(&options, &stream).unwrap();
+                let (client, socket) = tokio::io::duplex(64 * 1024);
+                let conn = server.adopt(socket, peer);
+                tokio::task::spawn_local(async move {
+                    tokio::join!(conn.serve(), uring::pump(stream, client))
+                });
+            }
+        }),
+    });
+    addr_rx.recv().unwrap()
+}
+
+/// A blocking client of the server at `addr`.
+struct Client {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    line: String,
+}
+
+impl Client {
+    fn connect(addr: SocketAddr) -> Self {
+        let writer = TcpStream::connect(addr).unwrap();
+        SockRef::from(&writer).set_tcp_nodelay(true).unwrap();
+        let reader = BufReader::new(writer.try_clone().unwrap());
+        Self { reader, writer, line: String::new() }
+    }
+
+    /// Sends a batch of messages in one write and waits for every reply.
+    fn round_trip(&mut self, batch: &[u8]) {
+        self.writer.write_all(batch).unwrap();
+        for _ in 0..BATCH {
+            self.line.clear();
+            assert!(self.reader.read_line(&mut self.line).unwrap() > 0, "connection closed");
+            black_box(&self.line);
+        }
+    }
+}
+
+fn backends(c: &mut Criterion) {
+    let batch = "hello from the benchmark\n".repeat(BATCH as usize);
+
+    let mut group = c.benchmark_group("backends");
+    group.throughput(Throughput::Elements(BATCH));
+    for (name, backend) in [("epoll", Backend::Epoll), ("io_uring", Backend::Uring)] {
+        // One connection for all iterations: serving is measured, not connecting
+        let mut client = Client::connect(spawn_server(backend));
+        group.bench_function(name, |b| b.iter(|| client.round_trip(batch.as_bytes())));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, backends);
+criterion_main!(benches);
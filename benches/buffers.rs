@@ -0,0 +1,119 @@
+//! Compares the allocations of the read path before and after buffer
+//! pooling and `Bytes` log messages.
+//!
+//! ```bash
+//! cargo bench --bench buffers
+//! ```
+//!
+//! A plain timing loop rather than a benchmark framework: the numbers are
+//! only meant to show the difference between the two approaches, for a
+//! short and a long line. Besides the time, a counting global allocator
+//! reports how many heap allocations each connection cost.
+
+use bytes::{Bytes, BytesMut};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio_examples::buffers::{BUFFER_SIZE, BufferPool};
+use tokio_examples::metrics::InMemorySink;
+
+const CONNECTIONS: usize = 100_000;
+const LINES_PER_CONNECTION: usize = 4;
+
+/// The system allocator, counting calls to `alloc`.
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+fn main() {
+    let pool = BufferPool::new(Arc::new(InMemorySink::default()));
+    let long = format!("SET blob {}", "x".repeat(1000));
+
+    for line in ["SET color blue", long.as_str()] {
+        println!(
+            "\n{} connections x {} lines of {} bytes",
+            CONNECTIONS,
+            LINES_PER_CONNECTION,
+            line.len()
+        );
+
+        let before = measure(|| {
+            for _ in 0..CONNECTIONS {
+                // A buffer per connection; the line is copied into an owned
+                // `String`, and copied again for the log message
+                let mut buf = BytesMut::with_capacity(BUFFER_SIZE);
+                serve(&mut buf, line, |line| {
+                    let input = String::from_utf8_lossy(&line).trim().to_string();
+                    black_box(input.clone());
+                    input
+                });
+            }
+        });
+
+        let after = measure(|| {
+            for _ in 0..CONNECTIONS {
+                // A pooled buffer; the input borrows the line and the log
+                // message shares its bytes
+                let mut buf = pool.get();
+                serve(&mut buf, line, |line| {
+                    let line = line.slice_ref(line.trim_ascii());
+                    black_box(String::from_utf8_lossy(&line).len());
+                    line.clone()
+                });
+            }
+        });
+
+        report("fresh buffer, String copies", before);
+        report("pooled buffer, shared Bytes", after);
+    }
+}
+
+/// Fills the buffer and splits off lines like the connection handler does;
+/// `handle` stands for everything done with one line.
+fn serve<T>(buf: &mut BytesMut, line: &str, handle: impl Fn(Bytes) -> T) {
+    for _ in 0..LINES_PER_CONNECTION {
+        buf.reserve(BUFFER_SIZE);
+        // Stands in for `read_buf`
+        buf.extend_from_slice(line.as_bytes());
+        buf.extend_from_slice(b"\n");
+        let frame = buf.split_to(line.len()).freeze();
+        let _newline = buf.split_to(1);
+        black_box(handle(frame));
+    }
+}
+
+/// Time and number of allocations of one round.
+fn measure(run: impl Fn()) -> (Duration, usize) {
+    // One round to warm up the allocator and the pool
+    run();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let started = Instant::now();
+    run();
+    (started.elapsed(), ALLOCATIONS.load(Ordering::Relaxed) - allocations)
+}
+
+fn report(name: &str, (elapsed, allocations): (Duration, usize)) {
+    println!(
+        "  {:<30} {:>10.2?} total, {:>8.2?} and {:.1} allocations per connection",
+        name,
+        elapsed,
+        elapsed / CONNECTIONS as u32,
+        allocations as f64 / CONNECTIONS as f64,
+    );
+}
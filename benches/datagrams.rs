@@ -0,0 +1,84 @@
+//! Compares two ways of reading datagrams off a UDP socket.
+//!
+//! ```bash
+//! cargo bench --bench datagrams
+//! ```
+//!
+//! Each iteration sends `DATAGRAMS` datagrams over loopback, then reads
+//! them all back:
+//!
+//! - `recv_from`: one `recv_from().await` per datagram, each a trip through
+//!   the future's poll and tokio's cooperative budget.
+//! - `batched`: what `src/udp.rs` does, `readable().await` and then up to
+//!   `udp::BATCH` `try_recv_from` calls without awaiting, with a
+//!   `yield_now` between batches.
+
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use socket2::SockRef;
+use std::hint::black_box;
+use tokio::net::UdpSocket;
+use tokio::runtime::Runtime;
+use tokio_examples::udp;
+
+/// Datagrams sent and read back per iteration; few enough to fit in the
+/// receive buffer, so none is dropped before it is read.
+const DATAGRAMS: usize = 128;
+
+/// A receiving socket and a sender connected to it.
+async fn pair() -> (UdpSocket, std::net::UdpSocket) {
+    let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    SockRef::from(&receiver).set_recv_buffer_size(1 << 20).unwrap();
+    let sender = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    sender.connect(receiver.local_addr().unwrap()).unwrap();
+    (receiver, sender)
+}
+
+fn send_all(sender: &std::net::UdpSocket) {
+    for _ in 0..DATAGRAMS {
+        sender.send(b"hello from the benchmark").unwrap();
+    }
+}
+
+async fn recv_each(receiver: &UdpSocket, buffer: &mut [u8]) {
+    for _ in 0..DATAGRAMS {
+        black_box(receiver.recv_from(buffer).await.unwrap());
+    }
+}
+
+async fn recv_batched(receiver: &UdpSocket, buffer: &mut [u8]) {
+    let mut received = 0;
+    while received < DATAGRAMS {
+        receiver.readable().await.unwrap();
+        let most = udp::BATCH.min(DATAGRAMS - received);
+        received += udp::try_recv_batch(receiver, buffer, most, |datagram, peer| {
+            black_box((datagram, peer));
+        })
+        .unwrap();
+        tokio::task::yield_now().await;
+    }
+}
+
+fn datagrams(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let (receiver, sender) = runtime.block_on(pair());
+    let mut buffer = vec![0; 64 * 1024];
+
+    let mut group = c.benchmark_group("datagrams");
+    group.throughput(Throughput::Elements(DATAGRAMS as u64));
+    group.bench_function("recv_from", |b| {
+        b.iter(|| {
+            send_all(&sender);
+            runtime.block_on(recv_each(&receiver, &mut buffer));
+        })
+    });
+    group.bench_function("batched", |b| {
+        b.iter(|| {
+            send_all(&sender);
+            runtime.block_on(recv_batched(&receiver, &mut buffer));
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, datagrams);
+criterion_main!(benches);
@@ -0,0 +1,125 @@
+//! Measures the request path, so that changes made for speed can show
+//! what they bought.
+//!
+//! ```bash
+//! cargo bench --bench request_path
+//! ```
+//!
+//! Unlike `benches/buffers.rs` this uses criterion: every number comes with
+//! a confidence interval, and a second run is compared with the first, so
+//! a change can be measured by benchmarking before and after it.
+//!
+//! - `messages`: messages per second through the whole connection handler,
+//!   over an in-process `duplex` connection, so no socket is involved. Once
+//!   with every message sent to the logger task, once with `log_messages`
+//!   off. The logger's level is `error` either way, so nothing is printed
+//!   and only the channel makes the difference.
+//! - `increment`: `State::increment` from several threads at once, to see
+//!   what the shared counter costs under contention: kept under one mutex,
+//!   in one atomic, or in one atomic per shard.
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use std::hint::black_box;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, DuplexStream, Lines, ReadHalf};
+use tokio::io::{WriteHalf, split};
+use tokio::runtime::Runtime;
+use tokio::sync::Mutex;
+use tokio_examples::logger::LogLevel;
+use tokio_examples::metrics::InMemorySink;
+use tokio_examples::state::{CounterKind, State};
+use tokio_examples::{Server, ServerConfig};
+
+/// Messages written at once, and replies read back, per iteration.
+const BATCH: u64 = 100;
+/// Increments per thread, per iteration.
+const INCREMENTS: u64 = 10_000;
+
+/// A client of an in-process connection.
+struct Client {
+    lines: Lines<BufReader<ReadHalf<DuplexStream>>>,
+    writer: WriteHalf<DuplexStream>,
+}
+
+impl Client {
+    /// Sends a batch of messages in one write and waits for every reply.
+    async fn round_trip(&mut self, batch: &[u8]) {
+        self.writer.write_all(batch).await.unwrap();
+        for _ in 0..BATCH {
+            black_box(self.lines.next_line().await.unwrap().expect("connection closed"));
+        }
+    }
+}
+
+/// Binds a server and opens one connection to it, served in the background.
+async fn connect(log_messages: bool) -> (Server, Client) {
+    let config = ServerConfig {
+        listen: SocketAddr::from(([127, 0, 0, 1], 0)),
+        log_level: LogLevel::Error,
+        log_messages,
+        ..Default::default()
+    };
+    let server = Server::bind(config).await.unwrap();
+    let (client, conn) = server.duplex();
+    tokio::spawn(conn.serve());
+    let (reader, writer) = split(client);
+    (server, Client { lines: BufReader::new(reader).lines(), writer })
+}
+
+fn messages(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let batch = "hello from the benchmark\n".repeat(BATCH as usize);
+
+    let mut group = c.benchmark_group("messages");
+    group.throughput(Throughput::Elements(BATCH));
+    for (name, log_messages) in [("logged", true), ("not logged", false)] {
+        // One connection for all iterations: the handler is measured, not
+        // connecting. The server has to live as long as its connection.
+        let (_server, client) = runtime.block_on(connect(log_messages));
+        // `to_async` wants a `Fn`, so the client sits behind an async mutex
+        let client = Arc::new(Mutex::new(client));
+        group.bench_function(name, |b| {
+            b.to_async(&runtime).iter(|| {
+                let client = client.clone();
+                let batch = batch.as_bytes();
+                async move { client.lock().await.round_trip(batch).await }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn increment(c: &mut Criterion) {
+    let kinds = [
+        ("mutex", CounterKind::Mutex),
+        ("atomic", CounterKind::Atomic),
+        ("sharded", CounterKind::Sharded),
+    ];
+
+    let mut group = c.benchmark_group("increment");
+    for (name, kind) in kinds {
+        let state = State::with_counter(Arc::new(InMemorySink::default()), kind);
+        for threads in [1, 2, 4, 8] {
+            group.throughput(Throughput::Elements(threads * INCREMENTS));
+            let id = BenchmarkId::new(name, threads);
+            group.bench_with_input(id, &threads, |b, &threads| {
+                b.iter(|| {
+                    std::thread::scope(|scope| {
+                        for _ in 0..threads {
+                            scope.spawn(|| {
+                                for _ in 0..INCREMENTS {
+                                    black_box(state.increment());
+                                }
+                            });
+                        }
+                    })
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, messages, increment);
+criterion_main!(benches);
@@ -0,0 +1,17 @@
+//! Generates the gRPC service from `proto/counter.proto`, with the `grpc`
+//! feature only. `protoc` comes from `protoc-bin-vendored`, so nothing has
+//! to be installed.
+
+fn main() {
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/counter.proto");
+        let protoc = protoc_bin_vendored::protoc_bin_path().expect("no vendored protoc");
+        let mut config = tonic_prost_build::Config::new();
+        config.protoc_executable(protoc);
+        tonic_prost_build::configure()
+            .build_client(true)
+            .compile_with_config(config, &["proto/counter.proto"], &["proto"])
+            .expect("cannot compile proto/counter.proto");
+    }
+}
@@ -0,0 +1,41 @@
+//! Embedding the server: drive `Server::incoming()` with stream combinators
+//! instead of the built-in accept loop.
+//!
+//! ```bash
+//! cargo run --example incoming
+//! ```
+//!
+//! Serves the first three connections on 127.0.0.1:7000, each at most for
+//! ten seconds, then stops accepting.
+
+use std::time::Duration;
+use tokio_examples::{Server, ServerConfig};
+use tokio_stream::StreamExt;
+
+#[tokio::main]
+async fn main() -> Result<(), tokio_examples::ServerError> {
+    let server = Server::bind(ServerConfig::default()).await?;
+    println!("Listening on {:?}, serving 3 connections", server.local_addr());
+
+    // ACL checks, metrics and registry bookkeeping already happened inside
+    // the stream; what comes out is ready to be served
+    let mut connections = server.incoming().take(3);
+    let mut handlers = Vec::new();
+
+    while let Some(conn) = connections.next().await {
+        println!("connection #{} from {}", conn.id(), conn.peer_addr());
+        handlers.push(tokio::spawn(async move {
+            tokio::time::timeout(Duration::from_secs(10), conn.serve()).await
+        }));
+    }
+
+    for handler in handlers {
+        match handler.await {
+            Ok(Ok(reason)) => println!("closed: {}", reason),
+            // Dropping the connection on timeout also removes it from the registry
+            Ok(Err(_)) => println!("closed: served for too long"),
+            Err(e) => eprintln!("handler failed: {}", e),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,31 @@
+//! Arbitrary lines through every parser a message may reach.
+//!
+//! The handler reads messages as lossy UTF-8, so any bytes are a line. The
+//! parsers may refuse a line, but must not panic on it.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tokio_examples::handshake::Handshake;
+use tokio_examples::kv::KvCommand;
+use tokio_examples::protocol::{Protocol, Request};
+use tokio_examples::session::SessionId;
+use tokio_examples::upload;
+
+fuzz_target!(|data: &[u8]| {
+    for payload in data.split(|&b| b == b'\n') {
+        // In JSON mode, a message is a request first
+        if let Ok(request) = Request::parse(payload, Protocol::Json) {
+            let _ = request.command_line();
+        }
+
+        let line = String::from_utf8_lossy(payload);
+        let _ = Handshake::new(true).hello(&line);
+        let _ = KvCommand::parse(&line);
+        let _ = upload::parse_put(&line);
+        let _ = upload::parse_get_file(&line);
+        if let Some(arg) = line.split_whitespace().nth(1) {
+            let _ = SessionId::parse(arg);
+        }
+    }
+});
@@ -0,0 +1,37 @@
+//! Arbitrary bytes through both framings, arriving in reads of any size.
+//!
+//! Nothing may panic, no message may exceed the limit, and however the
+//! input looks, the codec buffers at most one unfinished message.
+
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use tokio_examples::framing::{FrameError, Framing, MessageCodec};
+use tokio_util::codec::Decoder;
+
+const MAX_MESSAGE: usize = 64;
+
+fuzz_target!(|data: &[u8]| {
+    // The first byte picks the framing and the size of the reads
+    let Some((&first, input)) = data.split_first() else { return };
+    let framing = if first & 1 == 0 { Framing::Lines } else { Framing::LengthPrefixed };
+    let chunk = usize::from(first >> 1).max(1);
+
+    let mut codec = MessageCodec::new(framing, MAX_MESSAGE);
+    let mut src = BytesMut::new();
+    for read in input.chunks(chunk) {
+        src.extend_from_slice(read);
+        loop {
+            match codec.decode(&mut src) {
+                Ok(Some(message)) => assert!(message.payload.len() <= MAX_MESSAGE),
+                Ok(None) => break,
+                // Like the handler: a long line is skipped, anything else ends it
+                Err(FrameError::TooLong(_)) if codec.recovers_from_too_long() => {}
+                Err(_) => return,
+            }
+        }
+        assert!(src.len() <= MAX_MESSAGE + 4, "{} bytes buffered", src.len());
+    }
+    let _ = codec.decode_eof(&mut src);
+});
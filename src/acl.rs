@@ -0,0 +1,121 @@
+//! IP allow/deny lists checked when a connection is accepted.
+//!
+//! The list file has one rule per line:
+//!
+//! ```text
+//! # comments and blank lines are ignored
+//! allow 127.0.0.0/8
+//! allow ::1
+//! deny 10.0.0.0/8
+//! ```
+//!
+//! Deny rules win over allow rules. When there are no allow rules,
+//! every address that is not denied is accepted.
+//!
+//! The current list lives in a `watch` channel: the accept loop reads the
+//! latest value for every connection, and a reload (see `config`) simply
+//! sends a new one.
+
+use std::net::IpAddr;
+use std::path::Path;
+
+/// An address block such as `192.168.0.0/16`.
+#[derive(Debug, Clone, Copy)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let (addr, prefix) = match text.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (text, None),
+        };
+        let network: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("invalid address: {}", addr))?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| format!("invalid prefix length: {}", p))?,
+            None => max,
+        };
+
+        Ok(Self { network, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_eq(u32::from(net).into(), u32::from(ip).into(), self.prefix, 32)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_eq(u128::from(net), u128::from(ip), self.prefix, 128)
+            }
+            // IPv4-mapped IPv6 peers are matched against IPv4 rules
+            (IpAddr::V4(_), IpAddr::V6(ip)) => match ip.to_ipv4_mapped() {
+                Some(v4) => self.contains(IpAddr::V4(v4)),
+                None => false,
+            },
+            (IpAddr::V6(_), IpAddr::V4(_)) => false,
+        }
+    }
+}
+
+/// Compares the first `prefix` bits of two addresses that are `bits` wide.
+fn prefix_eq(a: u128, b: u128, prefix: u8, bits: u32) -> bool {
+    if prefix == 0 {
+        return true;
+    }
+    let shift = bits - prefix as u32;
+    (a >> shift) == (b >> shift)
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AccessList {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl AccessList {
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut list = Self::default();
+
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let err = |e: String| format!("line {}: {}", n + 1, e);
+            match line.split_once(char::is_whitespace) {
+                Some(("allow", cidr)) => list.allow.push(Cidr::parse(cidr.trim()).map_err(err)?),
+                Some(("deny", cidr)) => list.deny.push(Cidr::parse(cidr.trim()).map_err(err)?),
+                _ => return Err(err(format!("expected `allow <cidr>` or `deny <cidr>`, got {:?}", line))),
+            }
+        }
+
+        Ok(list)
+    }
+
+    pub async fn load(path: &Path) -> Result<Self, String> {
+        let text = tokio::fs::read_to_string(path)
+            .await
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        Self::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn summary(&self) -> String {
+        format!("{} allow / {} deny rules", self.allow.len(), self.deny.len())
+    }
+}
@@ -0,0 +1,392 @@
+//! Admin control socket.
+//!
+//! Administrative commands are served on their own listener, separate from
+//! the client protocol, so they can be restricted to the local machine:
+//! either a loopback TCP address or a Unix socket.
+//!
+//! The same commands are read from the server's stdin, so whoever started
+//! the server can type them into its terminal (see `spawn_console`).
+//!
+//! One command per line, in any case:
+//!
+//! ```text
+//! STATS        -> STAT <name> <value> ... END
+//! COUNT        -> COUNT <requests served>
+//! LISTENERS    -> LISTENER <mode> <addr> <accepted> <status> ... END
+//! LIST         -> CONN <id> <peer> <age>s <requests> [<alpn>] [cert=<identity>] ... END
+//!                 (or CLIENTS)
+//! KICK <id>    -> OK: kicked connection #<id> | ERR: no connection #<id>
+//! LOG <text>   -> OK: logged
+//! RECENT [n]   -> LINE <logged line> ... END  (the newest n, default 20)
+//! FOLLOW       -> LINE <logged line> ...  (as they are logged, until the next line) END
+//! FLAGS        -> FLAG <name> on|off ... END
+//! FLAG <name> on|off -> OK: <name> is on|off
+//! PAUSE [REJECT] -> OK: paused, new connections wait | are refused
+//! RESUME       -> OK: resumed
+//! SLOWMODE <ms> -> OK: every reply waits <ms>ms  (0 turns it off)
+//! RELOAD       -> OK: reloaded | ERR: <reason>
+//! SHUTDOWN     -> OK: shutting down
+//! ```
+
+use crate::config::Reloader;
+use crate::error::ServerError;
+use crate::flags::FeatureFlags;
+use crate::ingest::Ingest;
+use crate::listeners::Listeners;
+use crate::logger::{LogMessage, LogTx, LoggerHandle};
+use crate::readiness::Phase;
+use crate::restart::Handoff;
+use crate::registry::Registry;
+use crate::state::{MAX_SLOWMODE, State};
+use crate::{supervise, task};
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{self, AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::io::{BufReader, Lines, Stdin};
+use tokio::sync::{mpsc, watch};
+
+/// Lines `RECENT` returns when not given a number.
+const DEFAULT_RECENT: usize = 20;
+
+/// Where the control socket listens.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdminAddr {
+    /// A TCP address; only loopback addresses are accepted.
+    Tcp(SocketAddr),
+    /// A Unix domain socket at this path.
+    Unix(PathBuf),
+}
+
+impl AdminAddr {
+    /// Parses `unix:<path>` or `<ip>:<port>`.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        if let Some(path) = text.strip_prefix("unix:") {
+            return Ok(Self::Unix(PathBuf::from(path)));
+        }
+        let addr: SocketAddr = text
+            .parse()
+            .map_err(|_| format!("invalid admin address: {}", text))?;
+        if !addr.ip().is_loopback() {
+            return Err(format!("admin address must be a loopback address, got {}", addr));
+        }
+        Ok(Self::Tcp(addr))
+    }
+}
+
+impl fmt::Display for AdminAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{}", addr),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// What the admin commands act on.
+pub struct Admin {
+    pub state: Arc<State>,
+    pub registry: Arc<Registry>,
+    /// The listeners besides the main one, for `LISTENERS`.
+    pub listeners: Arc<Listeners>,
+    pub reloader: Reloader,
+    pub shutdown_tx: mpsc::Sender<&'static str>,
+    /// Every connection and accept loop sees a change at once.
+    pub flags_tx: watch::Sender<FeatureFlags>,
+    /// Admin actions are logged in the high-priority lane.
+    pub log_tx: LogTx,
+    /// What the logger prints, for `RECENT` and `FOLLOW`.
+    pub logger: LoggerHandle,
+}
+
+impl Admin {
+    /// Binds the control socket and spawns its accept loop.
+    pub async fn spawn(
+        self: Arc<Self>,
+        addr: &AdminAddr,
+        handoff: &Handoff,
+    ) -> Result<(), ServerError> {
+        let bind_error = |source| ServerError::Bind { addr: addr.to_string(), source };
+        let admin = self;
+
+        match addr {
+            AdminAddr::Tcp(addr) => {
+                let listener = handoff.bind(*addr).await.map_err(bind_error)?;
+                task::spawn("admin socket", async move {
+                    loop {
+                        match listener.accept().await {
+                            Ok((stream, _)) => admin.clone().spawn_session(stream),
+                            Err(e) => eprintln!("[ADMIN] accept failed: {}", e),
+                        }
+                    }
+                });
+            }
+            #[cfg(unix)]
+            AdminAddr::Unix(path) => {
+                // A socket file left over from a previous run would make bind fail
+                let _ = std::fs::remove_file(path);
+                let listener = tokio::net::UnixListener::bind(path).map_err(bind_error)?;
+                task::spawn("admin socket", async move {
+                    loop {
+                        match listener.accept().await {
+                            Ok((stream, _)) => admin.clone().spawn_session(stream),
+                            Err(e) => eprintln!("[ADMIN] accept failed: {}", e),
+                        }
+                    }
+                });
+            }
+            #[cfg(not(unix))]
+            AdminAddr::Unix(_) => {
+                return Err(bind_error(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    "Unix sockets are only available on Unix platforms",
+                )));
+            }
+        }
+
+        println!("Admin socket on {}", addr);
+        Ok(())
+    }
+
+    /// Serves one admin client; TCP and Unix streams share this code.
+    fn spawn_session<S>(self: Arc<Self>, stream: S)
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        task::spawn("admin session", async move {
+            let (reader, mut writer) = tokio::io::split(stream);
+            let mut lines = BufReader::new(reader).lines();
+
+            while let Ok(Some(line)) = lines.next_line().await {
+                if line.trim().eq_ignore_ascii_case("FOLLOW") {
+                    match self.follow(&mut lines, &mut writer).await {
+                        Ok(true) => continue,
+                        Ok(false) | Err(_) => break,
+                    }
+                }
+                let response = self.execute(line.trim()).await;
+                if writer.write_all(response.as_bytes()).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    /// `FOLLOW`: writes the logger's lines as they are printed, starting with
+    /// those it kept, until the admin client sends another line (which is
+    /// then ignored) or goes away. Only the socket has it; on the console
+    /// the log is already on screen.
+    ///
+    /// Returns whether the session goes on.
+    async fn follow<R, W>(&self, lines: &mut Lines<R>, writer: &mut W) -> io::Result<bool>
+    where
+        R: AsyncBufRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut subscription = self.logger.subscribe();
+        loop {
+            tokio::select! {
+                event = subscription.recv() => match event {
+                    Some(event) => {
+                        writer.write_all(format!("LINE {}\n", event.line).as_bytes()).await?;
+                    }
+                    // The logger has stopped: the server is going away
+                    None => break,
+                },
+                line = lines.next_line() => match line? {
+                    Some(_) => break,
+                    None => return Ok(false),
+                },
+            }
+        }
+        writer.write_all(b"END\n").await?;
+        Ok(true)
+    }
+
+    /// Serves commands typed into the server's stdin, answering on stdout.
+    ///
+    /// `BufReader::lines` turns the byte stream into lines, just like on
+    /// the control socket. Every line is also sent to `transcript`, whose
+    /// pipeline keeps a record of the console session; see `crate::ingest`.
+    ///
+    /// A command that panics takes the console down with it; it is
+    /// restarted, reading on from the same stdin into the same transcript.
+    pub fn spawn_console(self: Arc<Self>, transcript: Ingest, phase: watch::Receiver<Phase>) {
+        let lines = BufReader::new(tokio::io::stdin()).lines();
+        let console = Arc::new(tokio::sync::Mutex::new((lines, transcript)));
+        let metrics = self.state.metrics.clone();
+        supervise::spawn("stdin console", metrics, phase, move || {
+            let (admin, console) = (self.clone(), console.clone());
+            async move { admin.console(&mut *console.lock().await).await }
+        });
+    }
+
+    async fn console(&self, (lines, transcript): &mut (Lines<BufReader<Stdin>>, Ingest)) {
+        let mut stdout = tokio::io::stdout();
+
+        // Ends when stdin is closed, e.g. when the server runs in the background
+        while let Ok(Some(line)) = lines.next_line().await {
+            // Waits while the pipeline is full; once it has stopped, lines
+            // are only served
+            transcript.send(line.clone()).await;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let response = self.execute(line.trim()).await;
+            if stdout.write_all(response.as_bytes()).await.is_err() {
+                break;
+            }
+            let _ = stdout.flush().await;
+        }
+    }
+
+    async fn execute(&self, line: &str) -> String {
+        let (command, arg) = match line.split_once(char::is_whitespace) {
+            Some((command, arg)) => (command, arg.trim()),
+            None => (line, ""),
+        };
+
+        match command.to_ascii_uppercase().as_str() {
+            "STATS" => self.state.stat_lines(),
+            "LISTENERS" => self.listeners.stat_lines(),
+            "COUNT" => format!("COUNT {}\n", self.state.count()),
+            "LIST" | "CLIENTS" => {
+                let mut response = String::new();
+                for conn in self.registry.list() {
+                    response.push_str(&format!(
+                        "CONN {} {} {}s {}",
+                        conn.id,
+                        conn.peer,
+                        conn.age.as_secs(),
+                        conn.requests,
+                    ));
+                    // Only TLS connections have one
+                    if let Some(protocol) = conn.protocol {
+                        response.push_str(&format!(" {}", protocol));
+                    }
+                    if let Some(identity) = &conn.identity {
+                        response.push_str(&format!(" cert={}", identity));
+                    }
+                    if let Some(vhost) = &conn.vhost {
+                        response.push_str(&format!(" vhost={}", vhost));
+                    }
+                    response.push('\n');
+                }
+                response.push_str("END\n");
+                response
+            }
+            "KICK" => match arg.parse() {
+                Ok(id) if self.registry.kick(id) => {
+                    let text = format!("admin kicked connection #{}", id);
+                    self.log_tx.send(LogMessage::admin(text)).await;
+                    format!("OK: kicked connection #{}\n", id)
+                }
+                Ok(id) => format!("ERR: no connection #{}\n", id),
+                Err(_) => "ERR: usage: KICK <connection id>\n".to_string(),
+            },
+            "LOG" if !arg.is_empty() => {
+                self.log_tx.send(LogMessage::admin(format!("admin: {}", arg))).await;
+                "OK: logged\n".to_string()
+            }
+            "LOG" => "ERR: usage: LOG <text>\n".to_string(),
+            "RECENT" => {
+                let n = match arg {
+                    "" => DEFAULT_RECENT,
+                    arg => match arg.parse() {
+                        Ok(n) => n,
+                        Err(_) => return "ERR: usage: RECENT [<lines>]\n".to_string(),
+                    },
+                };
+                let mut response = String::new();
+                for line in self.logger.last(n) {
+                    response.push_str(&format!("LINE {}\n", line));
+                }
+                response.push_str("END\n");
+                response
+            }
+            "FLAGS" => {
+                let flags = *self.flags_tx.borrow();
+                let mut response = String::new();
+                for name in FeatureFlags::NAMES {
+                    let on = flags.get(name).unwrap_or_default();
+                    response.push_str(&format!("FLAG {} {}\n", name, on_off(on)));
+                }
+                response.push_str("END\n");
+                response
+            }
+            "FLAG" => self.set_flag(arg).await,
+            "PAUSE" | "RESUME" => self.pause(&command.to_ascii_uppercase(), arg).await,
+            "SLOWMODE" => match arg.parse().map(Duration::from_millis) {
+                Ok(delay) if delay <= MAX_SLOWMODE => {
+                    self.state.set_slowmode(delay);
+                    let text = format!("admin set SLOWMODE to {}ms", delay.as_millis());
+                    self.log_tx.send(LogMessage::admin(text)).await;
+                    format!("OK: every reply waits {}ms\n", delay.as_millis())
+                }
+                _ => format!("ERR: usage: SLOWMODE <ms>, at most {}ms\n", MAX_SLOWMODE.as_millis()),
+            },
+            "RELOAD" => match self.reloader.reload().await {
+                Ok(()) => "OK: reloaded\n".to_string(),
+                Err(e) => format!("ERR: {}\n", e),
+            },
+            "SHUTDOWN" => {
+                println!("[ADMIN] shutdown requested");
+                let _ = self.shutdown_tx.try_send("admin SHUTDOWN");
+                "OK: shutting down\n".to_string()
+            }
+            _ => format!("ERR: unknown admin command {:?}\n", command),
+        }
+    }
+
+    /// `FLAG <name> on|off`: publishes the changed flags to every receiver.
+    async fn set_flag(&self, arg: &str) -> String {
+        let usage = "ERR: usage: FLAG <name> on|off\n".to_string();
+        let Some((name, value)) = arg.split_once(char::is_whitespace) else {
+            return usage;
+        };
+        let on = match value.trim().to_ascii_lowercase().as_str() {
+            "on" => true,
+            "off" => false,
+            _ => return usage,
+        };
+        let name = name.to_ascii_lowercase();
+        // Changes the value in place; receivers are only notified if the
+        // closure returns true, so an unknown name wakes nobody
+        if !self.flags_tx.send_if_modified(|flags| flags.set(&name, on)) {
+            return format!("ERR: unknown flag {:?}\n", name);
+        }
+        let text = format!("admin set flag {} {}", name, on_off(on));
+        self.log_tx.send(LogMessage::admin(text)).await;
+        format!("OK: {} is {}\n", name, on_off(on))
+    }
+
+    /// `PAUSE`, `PAUSE REJECT` and `RESUME`: the two pause flags, set together.
+    ///
+    /// A plain pause stops the accept loops, so new clients wait in the
+    /// kernel's backlog until `RESUME`; with `REJECT` they are accepted and
+    /// turned away at once.
+    async fn pause(&self, command: &str, arg: &str) -> String {
+        let (hold, reject, response) = match (command, arg.to_ascii_uppercase().as_str()) {
+            ("PAUSE", "") => (true, false, "OK: paused, new connections wait\n"),
+            ("PAUSE", "REJECT") => (false, true, "OK: paused, new connections are refused\n"),
+            ("RESUME", "") => (false, false, "OK: resumed\n"),
+            _ => return "ERR: usage: PAUSE [REJECT] | RESUME\n".to_string(),
+        };
+        self.flags_tx.send_if_modified(|flags| {
+            let changed = (flags.accept_paused, flags.reject_connections) != (hold, reject);
+            flags.accept_paused = hold;
+            flags.reject_connections = reject;
+            changed
+        });
+        // Logged as e.g. "admin paused, new connections wait"
+        let text = format!("admin {}", response.trim_start_matches("OK: ").trim_end());
+        self.log_tx.send(LogMessage::admin(text)).await;
+        response.to_string()
+    }
+}
+
+fn on_off(on: bool) -> &'static str {
+    if on { "on" } else { "off" }
+}
@@ -0,0 +1,218 @@
+//! Tokens for `AUTH`, and what each one may do.
+//!
+//! The server never stores a token itself, only its SHA-256: the config
+//! lists tokens as `<name>:<permission>:<sha256 in hex>`, and
+//! `tokio-examples hash-token <token>` prints the hash of a new one. A
+//! token sent with `AUTH` is hashed and looked up by its hash.
+//!
+//! A `read-only` token may use every command that does not change the
+//! server's state; an `admin` token may use all of them. A few commands,
+//! such as `SLOWMODE`, change how the server behaves rather than what it
+//! stores, and only an `admin` token or certificate may use them at all.
+//! Wrong tokens are counted per client address, and an address that keeps
+//! sending them is refused for a while without its tokens being checked at
+//! all.
+//!
+//! On the TLS listener, a client certificate can stand in for a token: the
+//! config lists certificate identities as `<name>:<permission>`, and a
+//! connection whose certificate names one of them starts out with that
+//! permission (see `crate::tls::identity`).
+
+use crate::rate_limit::Limit;
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+/// Wrong tokens an address may send in a row, and how fast it gets another
+/// try after that: one every 12 seconds.
+pub const FAILURE_LIMIT: Limit = Limit { rate: 1.0 / 12.0, burst: 5.0 };
+
+/// What a client that authenticated with a token may do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// Every command except those that change the server's state.
+    ReadOnly,
+    /// Every command.
+    Admin,
+}
+
+impl Permission {
+    /// `read-only` or `admin`, as the config writes them.
+    pub fn parse(text: &str) -> Option<Self> {
+        match text {
+            "read-only" => Some(Self::ReadOnly),
+            "admin" => Some(Self::Admin),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::ReadOnly => "read-only",
+            Self::Admin => "admin",
+        })
+    }
+}
+
+/// A token `AUTH` accepts, by its hash.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthToken {
+    /// Names the token in the log.
+    pub name: String,
+    pub permission: Permission,
+    sha256: [u8; 32],
+}
+
+impl AuthToken {
+    /// A token given in the clear, e.g. by a test or an embedder.
+    pub fn new(name: &str, token: &str, permission: Permission) -> Self {
+        Self { name: name.to_string(), permission, sha256: hash(token) }
+    }
+
+    /// Parses `<name>:<permission>:<sha256 in hex>`.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let usage = || format!("an auth token must look like `name:permission:sha256`: {}", text);
+        let mut parts = text.splitn(3, ':');
+        let (Some(name), Some(permission), Some(hex)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(usage());
+        };
+        if name.is_empty() {
+            return Err(usage());
+        }
+        let Some(permission) = Permission::parse(permission) else {
+            return Err(format!("token permission must be `read-only` or `admin`: {}", text));
+        };
+        let sha256 = from_hex(hex).ok_or(format!("not a SHA-256 in hex: {}", hex))?;
+        Ok(Self { name: name.to_string(), permission, sha256 })
+    }
+
+    /// Whether `token` is this one.
+    pub fn matches(&self, token: &str) -> bool {
+        hash(token) == self.sha256
+    }
+}
+
+impl fmt::Display for AuthToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.name, self.permission, to_hex(&self.sha256))
+    }
+}
+
+/// A client certificate identity, and what a connection presenting it may do.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CertIdentity {
+    /// The certificate subject's common name.
+    pub name: String,
+    pub permission: Permission,
+}
+
+impl CertIdentity {
+    /// Parses `<name>:<permission>`; the name is the part before the last `:`.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let usage = || format!("a certificate identity must look like `name:permission`: {}", text);
+        let (name, permission) = text.rsplit_once(':').ok_or_else(usage)?;
+        if name.is_empty() {
+            return Err(usage());
+        }
+        let permission = Permission::parse(permission).ok_or_else(|| {
+            format!("identity permission must be `read-only` or `admin`: {}", text)
+        })?;
+        Ok(Self { name: name.to_string(), permission })
+    }
+}
+
+/// The SHA-256 of `token`, in hex, as the config lists it.
+pub fn hash_hex(token: &str) -> String {
+    to_hex(&hash(token))
+}
+
+fn hash(token: &str) -> [u8; 32] {
+    Sha256::digest(token.as_bytes()).into()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text: &str) -> Option<[u8; 32]> {
+    let mut bytes = [0; 32];
+    if text.len() != 64 || !text.is_ascii() {
+        return None;
+    }
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&text[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+/// The token of an `AUTH` line, if `input` is one.
+pub fn parse_auth(input: &str) -> Option<&str> {
+    let (command, token) = input.split_once(' ').unwrap_or((input, ""));
+    command.eq_ignore_ascii_case("AUTH").then(|| token.trim())
+}
+
+/// `input` with the token of an `AUTH` line hidden, for logs and traces.
+pub fn redact(input: &str) -> &str {
+    if parse_auth(input).is_some() { "AUTH ***" } else { input }
+}
+
+/// Whether the command in `input` changes the server's state, which a
+/// read-only token may not do.
+pub fn is_write(input: &str) -> bool {
+    let command = input.split_whitespace().next().unwrap_or("").to_ascii_uppercase();
+    matches!(
+        command.as_str(),
+        "SET" | "DEL" | "GETDEL" | "GETSET" | "CAS" | "EXPIRE" | "PUT" | "SCHEDULE" | "PUBLISH"
+            | "PUBLISH-AFTER"
+            | "CONSUME"
+    )
+}
+
+/// Whether `input` is a command only an admin may use, whatever else the
+/// connection may do: `SLOWMODE`, which slows other connections down, and
+/// `HISTORY`, which shows other clients' messages and addresses.
+pub fn is_admin_only(input: &str) -> bool {
+    let command = input.split_whitespace().next().unwrap_or("");
+    ["SLOWMODE", "HISTORY"].iter().any(|admin| command.eq_ignore_ascii_case(admin))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_are_matched_by_their_hash() {
+        let line = format!("ci:read-only:{}", hash_hex("secret"));
+        let token = AuthToken::parse(&line).unwrap();
+        assert_eq!(token, AuthToken::new("ci", "secret", Permission::ReadOnly));
+        assert_eq!(token.to_string(), line);
+        assert!(token.matches("secret"));
+        assert!(!token.matches("Secret"));
+
+        assert!(AuthToken::parse("ci:read-only:abc").is_err());
+        assert!(AuthToken::parse(&format!("ci:root:{}", hash_hex("x"))).is_err());
+    }
+
+    #[test]
+    fn certificate_identities_name_a_permission() {
+        let identity = CertIdentity::parse("ops team:admin").unwrap();
+        assert_eq!(identity.name, "ops team");
+        assert_eq!(identity.permission, Permission::Admin);
+        assert!(CertIdentity::parse("ops").is_err());
+        assert!(CertIdentity::parse(":admin").is_err());
+        assert!(CertIdentity::parse("ops:root").is_err());
+    }
+
+    #[test]
+    fn auth_lines_are_redacted_and_writes_recognized() {
+        assert_eq!(parse_auth("auth  secret "), Some("secret"));
+        assert_eq!(parse_auth("AUTHOR x"), None);
+        assert_eq!(redact("AUTH secret"), "AUTH ***");
+        assert_eq!(redact("GET key"), "GET key");
+        assert!(is_write("set key value") && is_write("PUT a.txt 3"));
+        assert!(is_write("publish-after 5 news hi") && !is_write("SUBSCRIBE news"));
+        assert!(!is_write("GET key") && !is_write("STATS"));
+    }
+}
@@ -0,0 +1,116 @@
+//! What happens to a client that sends faster than it reads.
+//!
+//! The handler keeps reading while the replies of earlier messages are
+//! being written, so a client that pipelines requests but does not read
+//! the replies makes its outbox grow. Once the queued bytes pass the
+//! high-water mark (`--outbox-limit-bytes`), the connection is slow, and
+//! the [`Backpressure`] policy decides what to do about it.
+//!
+//! Slowness is sticky: a connection stays slow until its queue has drained
+//! below half the mark, so a queue hovering around the mark does not flip
+//! the policy on and off with every reply.
+
+use crate::metrics::{self, Metrics};
+use std::fmt;
+
+/// The policy for a connection whose queued replies passed the high-water mark.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Backpressure {
+    /// Stop reading from the client until its replies are written.
+    #[default]
+    Pause,
+    /// Drop the oldest queued replies, and keep reading.
+    DropOldest,
+    /// Close the connection.
+    Disconnect,
+}
+
+impl fmt::Display for Backpressure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Pause => "pause",
+            Self::DropOldest => "drop-oldest",
+            Self::Disconnect => "disconnect",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A change in whether a connection is slow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    /// The queue passed the high-water mark.
+    Slowed,
+    /// The queue drained below half the mark.
+    CaughtUp,
+}
+
+/// Whether one connection is slow; counted in `clients_slow` while it is.
+pub struct SlowClient {
+    slow: bool,
+    metrics: Metrics,
+}
+
+impl SlowClient {
+    pub fn new(metrics: Metrics) -> Self {
+        Self { slow: false, metrics }
+    }
+
+    pub fn is_slow(&self) -> bool {
+        self.slow
+    }
+
+    /// Looks at the bytes `queued` against the high-water mark `limit`.
+    pub fn update(&mut self, queued: usize, limit: usize) -> Option<Change> {
+        if !self.slow && queued > limit {
+            self.slow = true;
+            self.metrics.adjust(metrics::CLIENTS_SLOW, 1);
+            Some(Change::Slowed)
+        } else if self.slow && queued <= limit / 2 {
+            self.slow = false;
+            self.metrics.adjust(metrics::CLIENTS_SLOW, -1);
+            Some(Change::CaughtUp)
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for SlowClient {
+    fn drop(&mut self) {
+        if self.slow {
+            self.metrics.adjust(metrics::CLIENTS_SLOW, -1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::InMemorySink;
+    use std::sync::Arc;
+
+    fn slow_clients(metrics: &Metrics) -> i64 {
+        let snapshot = metrics.snapshot();
+        snapshot.iter().find(|m| m.name == metrics::CLIENTS_SLOW).map_or(0, |m| m.value)
+    }
+
+    #[test]
+    fn a_slow_client_stays_slow_until_it_has_caught_up() {
+        let metrics: Metrics = Arc::new(InMemorySink::default());
+        let mut client = SlowClient::new(metrics.clone());
+        assert_eq!(client.update(100, 100), None);
+        assert_eq!(client.update(101, 100), Some(Change::Slowed));
+        assert_eq!(slow_clients(&metrics), 1);
+
+        // Back under the mark is not enough
+        assert_eq!(client.update(80, 100), None);
+        assert!(client.is_slow());
+        assert_eq!(client.update(50, 100), Some(Change::CaughtUp));
+        assert_eq!(slow_clients(&metrics), 0);
+
+        client.update(200, 100);
+        drop(client);
+        assert_eq!(slow_clients(&metrics), 0);
+    }
+}
@@ -0,0 +1,104 @@
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio_examples::dialer::Dialer;
+use tokio_examples::listener::SocketOptions;
+use tokio_examples::retry::{Policy, retry};
+
+#[derive(Debug, thiserror::Error)]
+pub enum RequestError {
+    /// No complete response arrived in time; the read was cancelled.
+    #[error("timed out")]
+    Timeout,
+    #[error("connection closed by server")]
+    Closed,
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    /// The connection timed out earlier and can no longer be trusted.
+    #[error("connection is suspect after a timeout")]
+    Suspect,
+}
+
+/// One connection to the server, speaking the line protocol.
+pub struct Connection {
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+    timeout: Duration,
+    /// Set once a request timed out.
+    ///
+    /// The response of the cancelled request may still arrive later, and
+    /// `read_line` is not cancel-safe (a partially read line is lost when
+    /// its future is dropped). Either way the next response read from this
+    /// connection could belong to the wrong request, so it is not reused.
+    suspect: bool,
+}
+
+impl Connection {
+    pub async fn connect(
+        addr: &str,
+        timeout: Duration,
+        socket: &SocketOptions,
+    ) -> std::io::Result<Self> {
+        // A server that is restarting refuses a moment, so try a few times
+        // Names resolving to IPv6 and IPv4 addresses are raced (happy eyeballs)
+        let dialer = Dialer::new();
+        let stream = retry(&Policy::default(), || dialer.dial(addr)).await?;
+        socket.apply(&stream)?;
+        let (reader, writer) = stream.into_split();
+        Ok(Self {
+            reader: BufReader::new(reader),
+            writer,
+            timeout,
+            suspect: false,
+        })
+    }
+
+    /// Sends one line and waits for the one-line response.
+    pub async fn request(&mut self, line: &str) -> Result<String, RequestError> {
+        self.send(line).await?;
+        self.read_line().await
+    }
+
+    /// Sends `STATS` and collects the `STAT <name> <value>` lines up to `END`.
+    pub async fn stats(&mut self) -> Result<Vec<(String, i64)>, RequestError> {
+        self.send("STATS").await?;
+        let mut stats = Vec::new();
+        loop {
+            let line = self.read_line().await?;
+            if line == "END" {
+                return Ok(stats);
+            }
+            let mut parts = line.split_whitespace();
+            if let (Some("STAT"), Some(name), Some(value)) = (parts.next(), parts.next(), parts.next()) {
+                stats.push((name.to_string(), value.parse().unwrap_or(0)));
+            }
+        }
+    }
+
+    /// Sends one line without waiting for a response.
+    pub async fn send(&mut self, line: &str) -> Result<(), RequestError> {
+        if self.suspect {
+            return Err(RequestError::Suspect);
+        }
+        self.writer
+            .write_all(format!("{}\n", line).as_bytes())
+            .await?;
+        Ok(())
+    }
+
+    /// Reads the next response line, within the timeout.
+    pub async fn read_line(&mut self) -> Result<String, RequestError> {
+        let mut response = String::new();
+        // When the timeout fires, the `read_line` future is dropped: the
+        // await is cancelled and nothing keeps waiting for the response.
+        match tokio::time::timeout(self.timeout, self.reader.read_line(&mut response)).await {
+            Err(_elapsed) => {
+                self.suspect = true;
+                Err(RequestError::Timeout)
+            }
+            Ok(Ok(0)) => Err(RequestError::Closed),
+            Ok(Ok(_)) => Ok(response.trim_end().to_string()),
+            Ok(Err(e)) => Err(RequestError::Io(e)),
+        }
+    }
+}
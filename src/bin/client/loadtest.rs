@@ -0,0 +1,146 @@
+//! A small load generator.
+//!
+//! `connections` tasks run concurrently, each sending `requests` messages
+//! one after another and measuring the round-trip time of each.
+
+use crate::Options;
+use crate::connection::{Connection, RequestError};
+use std::time::{Duration, Instant};
+use tokio_examples::listener::SocketOptions;
+
+#[derive(Default)]
+struct Report {
+    latencies: Vec<Duration>,
+    timeouts: usize,
+    reconnects: usize,
+    errors: usize,
+}
+
+pub async fn run(options: &Options) -> Result<(), RequestError> {
+    println!(
+        "{} connections x {} requests of {:?} to {} (timeout {:?})",
+        options.connections, options.requests, options.message, options.addr, options.timeout,
+    );
+
+    let started = Instant::now();
+    let mut tasks = Vec::with_capacity(options.connections);
+    for _ in 0..options.connections {
+        let addr = options.addr.clone();
+        let message = options.message.clone();
+        let (timeout, reconnect, requests) = (options.timeout, options.reconnect, options.requests);
+        let socket = options.socket;
+        tasks.push(tokio::spawn(async move {
+            worker(&addr, &message, timeout, &socket, reconnect, requests).await
+        }));
+    }
+
+    let mut total = Report::default();
+    for task in tasks {
+        match task.await {
+            Ok(Ok(report)) => {
+                total.latencies.extend(report.latencies);
+                total.timeouts += report.timeouts;
+                total.reconnects += report.reconnects;
+                total.errors += report.errors;
+            }
+            Ok(Err(e)) => {
+                eprintln!("worker failed: {}", e);
+                total.errors += 1;
+            }
+            Err(e) => {
+                eprintln!("worker panicked: {}", e);
+                total.errors += 1;
+            }
+        }
+    }
+    let elapsed = started.elapsed();
+
+    print_report(&mut total, elapsed);
+    check_server_cleanup(options).await
+}
+
+async fn worker(
+    addr: &str,
+    message: &str,
+    timeout: Duration,
+    socket: &SocketOptions,
+    reconnect: bool,
+    requests: usize,
+) -> Result<Report, RequestError> {
+    let mut report = Report::default();
+    let mut conn = Connection::connect(addr, timeout, socket).await?;
+
+    for _ in 0..requests {
+        let sent = Instant::now();
+        match conn.request(message).await {
+            Ok(_) => report.latencies.push(sent.elapsed()),
+            Err(RequestError::Timeout) => {
+                report.timeouts += 1;
+                if !reconnect {
+                    break;
+                }
+                // Dropping the suspect connection closes it, which lets the
+                // server notice and cancel the request it is still working on
+                conn = Connection::connect(addr, timeout, socket).await?;
+                report.reconnects += 1;
+            }
+            Err(_) => {
+                report.errors += 1;
+                break;
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+fn print_report(report: &mut Report, elapsed: Duration) {
+    report.latencies.sort();
+    let completed = report.latencies.len();
+    let percentile = |p: f64| -> Duration {
+        if completed == 0 {
+            return Duration::ZERO;
+        }
+        let idx = ((completed as f64 * p).ceil() as usize).clamp(1, completed) - 1;
+        report.latencies[idx]
+    };
+
+    println!("completed:  {} in {:.2?}", completed, elapsed);
+    println!("throughput: {:.0} req/s", completed as f64 / elapsed.as_secs_f64());
+    println!(
+        "latency:    p50 {:.2?}  p90 {:.2?}  p99 {:.2?}  max {:.2?}",
+        percentile(0.50),
+        percentile(0.90),
+        percentile(0.99),
+        percentile(1.0),
+    );
+    println!(
+        "timeouts:   {}  reconnects: {}  errors: {}",
+        report.timeouts, report.reconnects, report.errors,
+    );
+}
+
+/// After timeouts, asks the server whether the cancelled requests were cleaned up.
+async fn check_server_cleanup(options: &Options) -> Result<(), RequestError> {
+    // Give the server a moment to notice the closed connections
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let mut conn = Connection::connect(&options.addr, options.timeout, &options.socket).await?;
+    let stats = conn.stats().await?;
+    let get = |name: &str| {
+        stats
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| *v)
+            .unwrap_or(0)
+    };
+
+    let in_flight = get("requests_in_flight");
+    println!(
+        "server:     {} requests cancelled, {} still in flight{}",
+        get("requests_cancelled"),
+        in_flight,
+        if in_flight == 0 { " (cleaned up)" } else { "" },
+    );
+    Ok(())
+}
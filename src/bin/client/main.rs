@@ -0,0 +1,142 @@
+//! Command line client for the example server.
+//!
+//! ```text
+//! client [--addr <addr>] [--timeout-ms <ms>] [--reconnect] [socket options]
+//! client bench [--addr <addr>] [--timeout-ms <ms>] [--reconnect] [socket options]
+//!              [--connections <n>] [--requests <n>] [--message <text>]
+//! client --script <file> [--addr <addr>] [--timeout-ms <ms>] [socket options]
+//! ```
+//!
+//! The socket options are the server's: `--nodelay`, `--keepalive-secs <s>`,
+//! `--keepalive-interval-secs <s>`, `--send-buffer <bytes>` and
+//! `--recv-buffer <bytes>`.
+//!
+//! Without a subcommand, every line typed into STDIN is sent to the server
+//! and the response is printed. `bench` runs a small load test, and
+//! `--script` runs a scenario of several clients and checks their
+//! responses (see `script.rs`).
+//!
+//! Every request has a timeout. When it fires, the pending read is
+//! cancelled and the connection is marked suspect; with `--reconnect` a
+//! fresh connection is opened, otherwise the client stops.
+
+mod connection;
+mod loadtest;
+mod script;
+
+use connection::{Connection, RequestError};
+use tokio_examples::listener::SocketOptions;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+pub struct Options {
+    pub addr: String,
+    pub timeout: Duration,
+    pub reconnect: bool,
+    pub connections: usize,
+    pub requests: usize,
+    pub message: String,
+    pub socket: SocketOptions,
+    /// A scenario file to run instead of reading STDIN.
+    pub script: Option<PathBuf>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            addr: "127.0.0.1:7000".to_string(),
+            timeout: Duration::from_secs(5),
+            reconnect: false,
+            connections: 10,
+            requests: 1000,
+            message: "ping".to_string(),
+            socket: SocketOptions::default(),
+            script: None,
+        }
+    }
+}
+
+fn parse_args() -> Result<(bool, Options), String> {
+    let mut args = std::env::args().skip(1).peekable();
+    let bench = args.peek().map(String::as_str) == Some("bench");
+    if bench {
+        args.next();
+    }
+
+    let mut options = Options::default();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} expects a value", arg));
+        match arg.as_str() {
+            "--addr" => options.addr = value()?,
+            "--timeout-ms" => options.timeout = Duration::from_millis(number(&value()?)? as u64),
+            "--reconnect" => options.reconnect = true,
+            "--connections" => options.connections = number(&value()?)?,
+            "--requests" => options.requests = number(&value()?)?,
+            "--message" => options.message = value()?,
+            "--script" => options.script = Some(PathBuf::from(value()?)),
+            "--nodelay" => options.socket.nodelay = true,
+            "--keepalive-secs" => {
+                options.socket.keepalive = Some(Duration::from_secs(number(&value()?)? as u64));
+            }
+            "--keepalive-interval-secs" => {
+                let secs = number(&value()?)? as u64;
+                options.socket.keepalive_interval = Some(Duration::from_secs(secs));
+            }
+            "--send-buffer" => options.socket.send_buffer = Some(number(&value()?)?),
+            "--recv-buffer" => options.socket.recv_buffer = Some(number(&value()?)?),
+            other => return Err(format!("unknown argument: {}", other)),
+        }
+    }
+
+    Ok((bench, options))
+}
+
+fn number(value: &str) -> Result<usize, String> {
+    value
+        .parse()
+        .map_err(|_| format!("expected a number, got {:?}", value))
+}
+
+#[tokio::main]
+async fn main() {
+    let (bench, options) = match parse_args() {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    let result = if bench {
+        loadtest::run(&options).await.map_err(|e| e.to_string())
+    } else if let Some(script) = &options.script {
+        script::run(script, &options).await
+    } else {
+        interactive(&options).await.map_err(|e| e.to_string())
+    };
+
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Sends STDIN lines one by one and prints each response.
+async fn interactive(options: &Options) -> Result<(), RequestError> {
+    let mut conn = Connection::connect(&options.addr, options.timeout, &options.socket).await?;
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        match conn.request(&line).await {
+            Ok(response) => println!("{}", response),
+            Err(RequestError::Timeout) if options.reconnect => {
+                eprintln!("request timed out after {:?}, reconnecting", options.timeout);
+                conn = Connection::connect(&options.addr, options.timeout, &options.socket).await?;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,236 @@
+//! Scripted scenarios: several clients following a script at once.
+//!
+//! A scenario file says how many clients to connect, then what each of
+//! them sends and which responses it expects:
+//!
+//! ```text
+//! # A subscriber and a publisher
+//! connect 2
+//! 1 send SUBSCRIBE news
+//! 1 expect OK: subscribed to news, send anything to stop
+//! 2 sleep 100
+//! 2 send PUBLISH news hello
+//! 2 expect-prefix OK: delivered
+//! 1 expect MESSAGE news hello
+//! * send COUNT
+//! ```
+//!
+//! Every step starts with the client it is for, numbered from 1, or `*`
+//! for all of them. `send` writes a line; `expect` reads the next response
+//! line and compares it with the rest of the step, `expect-prefix` only
+//! with its start; `sleep` pauses that client for some milliseconds.
+//! Blank lines and lines starting with `#` are skipped.
+//!
+//! Each client runs its own steps in order, concurrently with the others,
+//! so steps of different clients are ordered only by their sleeps. Every
+//! step is reported as it passes or fails. A client stops at its first
+//! failure, and its remaining steps are reported as skipped.
+
+use crate::Options;
+use crate::connection::{Connection, RequestError};
+use std::path::Path;
+use std::time::Duration;
+use tokio::task::JoinSet;
+use tokio_examples::listener::SocketOptions;
+
+/// The most clients a scenario can connect.
+const MAX_CLIENTS: usize = 1000;
+
+#[derive(Debug, Clone)]
+enum Action {
+    Send(String),
+    Expect(String),
+    ExpectPrefix(String),
+    Sleep(Duration),
+}
+
+#[derive(Debug, Clone)]
+struct Step {
+    /// Where in the scenario file the step is, for the report.
+    line: usize,
+    action: Action,
+}
+
+/// Every client's steps, in the order the file lists them.
+struct Scenario {
+    clients: Vec<Vec<Step>>,
+}
+
+#[derive(Default)]
+struct Tally {
+    passed: usize,
+    failed: usize,
+    skipped: usize,
+}
+
+/// Runs the scenario in `path`. Returns an error if a step failed.
+pub async fn run(path: &Path, options: &Options) -> Result<(), String> {
+    let text = tokio::fs::read_to_string(path)
+        .await
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    let scenario = parse(&text).map_err(|e| format!("{}: {}", path.display(), e))?;
+    println!("{}: {} client(s) against {}", path.display(), scenario.clients.len(), options.addr);
+
+    let mut tasks = JoinSet::new();
+    for (index, steps) in scenario.clients.into_iter().enumerate() {
+        let addr = options.addr.clone();
+        let (timeout, socket) = (options.timeout, options.socket);
+        tasks.spawn(async move { client(index + 1, steps, &addr, timeout, &socket).await });
+    }
+
+    let mut total = Tally::default();
+    while let Some(result) = tasks.join_next().await {
+        match result {
+            Ok(tally) => {
+                total.passed += tally.passed;
+                total.failed += tally.failed;
+                total.skipped += tally.skipped;
+            }
+            Err(e) => {
+                eprintln!("client panicked: {}", e);
+                total.failed += 1;
+            }
+        }
+    }
+
+    println!("passed: {}  failed: {}  skipped: {}", total.passed, total.failed, total.skipped);
+    if total.failed > 0 {
+        return Err(format!("{} step(s) failed", total.failed));
+    }
+    Ok(())
+}
+
+/// Connects client `id` and runs its steps, printing a line per step.
+async fn client(
+    id: usize,
+    steps: Vec<Step>,
+    addr: &str,
+    timeout: Duration,
+    socket: &SocketOptions,
+) -> Tally {
+    let mut tally = Tally::default();
+    let mut conn = match Connection::connect(addr, timeout, socket).await {
+        Ok(conn) => conn,
+        Err(e) => {
+            println!("FAIL client {}: cannot connect: {}", id, e);
+            tally.failed += 1;
+            tally.skipped = steps.len();
+            return tally;
+        }
+    };
+
+    for (done, step) in steps.iter().enumerate() {
+        match execute(&mut conn, &step.action).await {
+            Ok(()) => {
+                println!("PASS client {} line {}: {}", id, step.line, step.action);
+                tally.passed += 1;
+            }
+            Err(why) => {
+                println!("FAIL client {} line {}: {}: {}", id, step.line, step.action, why);
+                tally.failed += 1;
+                tally.skipped = steps.len() - done - 1;
+                break;
+            }
+        }
+    }
+    tally
+}
+
+async fn execute(conn: &mut Connection, action: &Action) -> Result<(), String> {
+    let (expected, whole) = match action {
+        Action::Send(line) => return conn.send(line).await.map_err(|e| e.to_string()),
+        Action::Sleep(pause) => {
+            tokio::time::sleep(*pause).await;
+            return Ok(());
+        }
+        Action::Expect(line) => (line, true),
+        Action::ExpectPrefix(prefix) => (prefix, false),
+    };
+    let received = match conn.read_line().await {
+        Ok(line) => line,
+        Err(RequestError::Timeout) => return Err("no response in time".to_string()),
+        Err(e) => return Err(e.to_string()),
+    };
+    let matches = if whole {
+        received == *expected
+    } else {
+        received.starts_with(expected.as_str())
+    };
+    if !matches {
+        return Err(format!("got {:?}", received));
+    }
+    Ok(())
+}
+
+impl std::fmt::Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Action::Send(line) => write!(f, "send {:?}", line),
+            Action::Expect(line) => write!(f, "expect {:?}", line),
+            Action::ExpectPrefix(prefix) => write!(f, "expect-prefix {:?}", prefix),
+            Action::Sleep(pause) => write!(f, "sleep {}ms", pause.as_millis()),
+        }
+    }
+}
+
+/// Parses a scenario file; errors name the line they are on.
+fn parse(text: &str) -> Result<Scenario, String> {
+    let mut clients: Option<Vec<Vec<Step>>> = None;
+    for (index, line) in text.lines().enumerate() {
+        let number = index + 1;
+        let at = |e: String| format!("line {}: {}", number, e);
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (who, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim_start();
+        if who == "connect" {
+            if clients.is_some() {
+                return Err(at("`connect` can only be given once".to_string()));
+            }
+            let count = rest
+                .parse()
+                .ok()
+                .filter(|n| (1..=MAX_CLIENTS).contains(n))
+                .ok_or_else(|| at(format!("connect expects 1 to {} clients", MAX_CLIENTS)))?;
+            clients = Some(vec![Vec::new(); count]);
+            continue;
+        }
+        let Some(clients) = clients.as_mut() else {
+            return Err(at("the first step must be `connect <n>`".to_string()));
+        };
+
+        let (verb, arg) = rest.split_once(' ').unwrap_or((rest, ""));
+        let action = match verb {
+            "send" => Action::Send(arg.to_string()),
+            "expect" => Action::Expect(arg.to_string()),
+            "expect-prefix" => Action::ExpectPrefix(arg.to_string()),
+            "sleep" => {
+                let ms = arg.trim().parse().map_err(|_| at("sleep expects milliseconds".into()))?;
+                Action::Sleep(Duration::from_millis(ms))
+            }
+            other => return Err(at(format!("unknown step {:?}", other))),
+        };
+        let step = Step { line: number, action };
+
+        if who == "*" {
+            clients.iter_mut().for_each(|steps| steps.push(step.clone()));
+            continue;
+        }
+        match who.parse::<usize>() {
+            Ok(id) if (1..=clients.len()).contains(&id) => clients[id - 1].push(step),
+            _ => {
+                let count = clients.len();
+                let text = format!("expected a client from 1 to {} or `*`, got {:?}", count, who);
+                return Err(at(text));
+            }
+        }
+    }
+
+    match clients {
+        Some(clients) => Ok(Scenario { clients }),
+        None => Err("the scenario has no `connect <n>` line".to_string()),
+    }
+}
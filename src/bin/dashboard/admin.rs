@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio_examples::admin::AdminAddr;
+
+type Reader = BufReader<Box<dyn AsyncRead + Unpin + Send>>;
+type Writer = Box<dyn AsyncWrite + Unpin + Send>;
+
+/// A connection to the server's control socket.
+pub struct AdminClient {
+    reader: Reader,
+    writer: Writer,
+}
+
+impl AdminClient {
+    pub async fn connect(addr: &AdminAddr) -> std::io::Result<Self> {
+        let (reader, writer): (Box<dyn AsyncRead + Unpin + Send>, Writer) = match addr {
+            AdminAddr::Tcp(addr) => {
+                let (reader, writer) = tokio::net::TcpStream::connect(addr).await?.into_split();
+                (Box::new(reader), Box::new(writer))
+            }
+            #[cfg(unix)]
+            AdminAddr::Unix(path) => {
+                let (reader, writer) = tokio::net::UnixStream::connect(path).await?.into_split();
+                (Box::new(reader), Box::new(writer))
+            }
+            #[cfg(not(unix))]
+            AdminAddr::Unix(_) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    "Unix sockets are only available on Unix platforms",
+                ));
+            }
+        };
+        Ok(Self { reader: BufReader::new(reader), writer })
+    }
+
+    /// Sends a command whose reply is a list of lines, and returns the lines
+    /// before the closing `END`.
+    pub async fn list(&mut self, command: &str) -> std::io::Result<Vec<String>> {
+        self.writer.write_all(format!("{}\n", command).as_bytes()).await?;
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line).await? == 0 {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+            let line = line.trim_end();
+            if line == "END" {
+                return Ok(lines);
+            }
+            if let Some(error) = line.strip_prefix("ERR: ") {
+                return Err(std::io::Error::other(error.to_string()));
+            }
+            lines.push(line.to_string());
+        }
+    }
+}
+
+/// What one client address did, from a `PEER` line.
+#[derive(Debug, Clone)]
+pub struct Peer {
+    pub ip: IpAddr,
+    pub connections: u64,
+    pub requests: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+/// The reply to `STATS`: the metrics by name and the client addresses.
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub metrics: HashMap<String, i64>,
+    pub peers: Vec<Peer>,
+}
+
+impl Stats {
+    /// Lines that are neither `STAT` nor `PEER` are skipped.
+    pub fn parse(lines: &[String]) -> Self {
+        let mut stats = Self::default();
+        for line in lines {
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some("STAT") => {
+                    if let (Some(name), Some(Ok(value))) =
+                        (words.next(), words.next().map(str::parse))
+                    {
+                        stats.metrics.insert(name.to_string(), value);
+                    }
+                }
+                Some("PEER") => {
+                    let Some(Ok(ip)) = words.next().map(str::parse) else {
+                        continue;
+                    };
+                    let mut peer =
+                        Peer { ip, connections: 0, requests: 0, bytes_in: 0, bytes_out: 0 };
+                    for (name, value) in words.filter_map(|word| word.split_once('=')) {
+                        let value = value.parse().unwrap_or_default();
+                        match name {
+                            "connections" => peer.connections = value,
+                            "requests" => peer.requests = value,
+                            "bytes_in" => peer.bytes_in = value,
+                            "bytes_out" => peer.bytes_out = value,
+                            _ => {}
+                        }
+                    }
+                    stats.peers.push(peer);
+                }
+                _ => {}
+            }
+        }
+        stats
+    }
+
+    pub fn metric(&self, name: &str) -> i64 {
+        self.metrics.get(name).copied().unwrap_or_default()
+    }
+}
@@ -0,0 +1,133 @@
+//! Terminal dashboard for a running server.
+//!
+//! ```text
+//! dashboard [--admin <addr>|unix:<path>] [--interval-ms <ms>]
+//! ```
+//!
+//! Connects to the admin socket (`--admin` on the server) and, on every
+//! tick of a Tokio interval, asks for `STATS` and `RECENT`. The panels
+//! show the active connections, a sparkline of requests per second, the
+//! server's latest log lines and the client addresses that sent the most
+//! requests. Build with `--features dashboard`; `q` or Esc quits.
+//!
+//! Key presses arrive on crossterm's `EventStream`, so one `select!` waits
+//! for either the next tick or the next key. When the server goes away,
+//! the error is shown and the next tick connects again.
+
+mod admin;
+mod view;
+
+use admin::{AdminClient, Stats};
+use crossterm::event::{Event, EventStream, KeyCode, KeyEventKind, KeyModifiers};
+use std::time::Duration;
+use tokio::time::MissedTickBehavior;
+use tokio_examples::admin::AdminAddr;
+use tokio_stream::StreamExt;
+use view::Dashboard;
+
+/// Log lines asked for on every poll; more than any terminal shows.
+const LOG_LINES: usize = 100;
+
+struct Options {
+    admin: AdminAddr,
+    interval: Duration,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut options = Options {
+        admin: AdminAddr::Tcp(([127, 0, 0, 1], 7001).into()),
+        interval: Duration::from_secs(1),
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} expects a value", arg));
+        match arg.as_str() {
+            "--admin" => options.admin = AdminAddr::parse(&value()?)?,
+            "--interval-ms" => {
+                let value = value()?;
+                let ms: u64 =
+                    value.parse().map_err(|_| format!("expected a number, got {:?}", value))?;
+                options.interval = Duration::from_millis(ms.max(50));
+            }
+            other => return Err(format!("unknown argument: {}", other)),
+        }
+    }
+    Ok(options)
+}
+
+#[tokio::main]
+async fn main() {
+    let options = match parse_args() {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    // Raw mode and the alternate screen; `restore` puts the terminal back
+    let mut terminal = ratatui::init();
+    let result = run(&mut terminal, &options).await;
+    ratatui::restore();
+
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run(terminal: &mut ratatui::DefaultTerminal, options: &Options) -> std::io::Result<()> {
+    let mut dashboard = Dashboard::new(options.admin.to_string());
+    let mut client: Option<AdminClient> = None;
+    let mut events = EventStream::new();
+    let mut ticker = tokio::time::interval(options.interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {
+                if let Err(e) = poll(&mut client, &options.admin, &mut dashboard).await {
+                    // Dropped, so the next tick starts with a fresh connection
+                    client = None;
+                    dashboard.error = Some(e.to_string());
+                }
+            }
+            event = events.next() => match event {
+                Some(Ok(Event::Key(key))) if key.kind == KeyEventKind::Press => {
+                    let ctrl_c = key.code == KeyCode::Char('c')
+                        && key.modifiers.contains(KeyModifiers::CONTROL);
+                    if matches!(key.code, KeyCode::Char('q') | KeyCode::Esc) || ctrl_c {
+                        return Ok(());
+                    }
+                    continue;
+                }
+                // Redrawn below, at the new size
+                Some(Ok(Event::Resize(..))) => {}
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => return Err(e),
+                None => return Ok(()),
+            },
+        }
+        terminal.draw(|frame| dashboard.draw(frame))?;
+    }
+}
+
+/// One round of `STATS` and `RECENT`, connecting first if needed.
+async fn poll(
+    client: &mut Option<AdminClient>,
+    addr: &AdminAddr,
+    dashboard: &mut Dashboard,
+) -> std::io::Result<()> {
+    let client = match client {
+        Some(client) => client,
+        None => client.insert(AdminClient::connect(addr).await?),
+    };
+    let stats = Stats::parse(&client.list("STATS").await?);
+    let lines = client.list(&format!("RECENT {}", LOG_LINES)).await?;
+    let logs = lines
+        .into_iter()
+        .filter_map(|line| line.strip_prefix("LINE ").map(str::to_string))
+        .collect();
+    dashboard.update(stats, logs);
+    Ok(())
+}
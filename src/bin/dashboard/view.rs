@@ -0,0 +1,134 @@
+use crate::admin::{Peer, Stats};
+use ratatui::Frame;
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Style, Stylize};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, List, Paragraph, Row, Sparkline, Table};
+use std::collections::VecDeque;
+use tokio::time::Instant;
+
+/// Requests/sec readings kept for the sparkline; older ones scroll off.
+const HISTORY: usize = 240;
+
+/// Rows in the top talkers table.
+const TOP_TALKERS: usize = 10;
+
+/// Everything the panels show, updated after every poll.
+pub struct Dashboard {
+    pub admin: String,
+    stats: Stats,
+    logs: Vec<String>,
+    /// Requests per second, one reading per poll, oldest first.
+    rates: VecDeque<u64>,
+    /// `messages_received` at the previous poll, to turn it into a rate.
+    last_poll: Option<(Instant, i64)>,
+    /// Why the last poll failed, until one succeeds.
+    pub error: Option<String>,
+}
+
+impl Dashboard {
+    pub fn new(admin: String) -> Self {
+        Self {
+            admin,
+            stats: Stats::default(),
+            logs: Vec::new(),
+            rates: VecDeque::with_capacity(HISTORY),
+            last_poll: None,
+            error: None,
+        }
+    }
+
+    pub fn update(&mut self, stats: Stats, logs: Vec<String>) {
+        let now = Instant::now();
+        let received = stats.metric("messages_received");
+        if let Some((then, before)) = self.last_poll {
+            let secs = now.duration_since(then).as_secs_f64();
+            // A restarted server starts counting from zero again
+            let rate = (received - before).max(0) as f64 / secs.max(f64::EPSILON);
+            if self.rates.len() == HISTORY {
+                self.rates.pop_front();
+            }
+            self.rates.push_back(rate.round() as u64);
+        }
+        self.last_poll = Some((now, received));
+        self.stats = stats;
+        self.logs = logs;
+        self.error = None;
+    }
+
+    pub fn draw(&self, frame: &mut Frame) {
+        let [summary, sparkline, bottom] = Layout::vertical([
+            Constraint::Length(3),
+            Constraint::Length(6),
+            Constraint::Min(5),
+        ])
+        .areas(frame.area());
+        let [logs, talkers] =
+            Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)])
+                .areas(bottom);
+
+        frame.render_widget(self.summary(), summary);
+
+        // Only as many readings as fit, the newest on the right
+        let width = sparkline.width.saturating_sub(2) as usize;
+        let skip = self.rates.len().saturating_sub(width);
+        let rates: Vec<u64> = self.rates.iter().skip(skip).copied().collect();
+        let title = format!("requests/sec (now {})", rates.last().copied().unwrap_or_default());
+        let widget = Sparkline::default()
+            .block(Block::bordered().title(title))
+            .data(&rates)
+            .style(Style::default().fg(Color::Cyan));
+        frame.render_widget(widget, sparkline);
+
+        // The newest lines that fit, oldest first
+        let height = logs.height.saturating_sub(2) as usize;
+        let lines = self.logs.iter().skip(self.logs.len().saturating_sub(height));
+        let items = lines.map(|line| {
+            let style = if line.starts_with("[ERROR]") { Color::Red } else { Color::Reset };
+            Line::from(line.as_str()).style(style)
+        });
+        frame.render_widget(List::new(items).block(Block::bordered().title("recent log")), logs);
+
+        frame.render_widget(self.top_talkers(), talkers);
+    }
+
+    fn summary(&self) -> Paragraph<'_> {
+        let text = match &self.error {
+            Some(error) => Line::from(format!("{}: {}", self.admin, error)).red(),
+            None => Line::from(format!(
+                "active connections {}   requests {}   clients {}   accepted {}",
+                self.stats.metric("connections_active"),
+                self.stats.metric("messages_received"),
+                self.stats.metric("peers_tracked"),
+                self.stats.metric("connections_accepted"),
+            )),
+        };
+        let title = format!("{} (q to quit)", self.admin);
+        Paragraph::new(text).block(Block::bordered().title(title))
+    }
+
+    /// The addresses that sent the most requests.
+    fn top_talkers(&self) -> Table<'_> {
+        let mut peers: Vec<&Peer> = self.stats.peers.iter().collect();
+        peers.sort_by(|a, b| b.requests.cmp(&a.requests).then(a.ip.cmp(&b.ip)));
+        let rows = peers.into_iter().take(TOP_TALKERS).map(|peer| {
+            Row::new([
+                peer.ip.to_string(),
+                peer.requests.to_string(),
+                peer.connections.to_string(),
+                peer.bytes_in.to_string(),
+                peer.bytes_out.to_string(),
+            ])
+        });
+        let widths = [
+            Constraint::Min(15),
+            Constraint::Length(9),
+            Constraint::Length(6),
+            Constraint::Length(10),
+            Constraint::Length(10),
+        ];
+        Table::new(rows, widths)
+            .header(Row::new(["address", "requests", "conns", "bytes in", "bytes out"]).bold())
+            .block(Block::bordered().title("top talkers"))
+    }
+}
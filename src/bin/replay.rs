@@ -0,0 +1,157 @@
+//! Replays recorded client sessions against a running server.
+//!
+//! ```text
+//! replay [--addr <addr>] [--speed <factor>] <file>...
+//! ```
+//!
+//! Each file is one connection recorded with `--record <dir>` on the
+//! server. Every file gets its own connection, and its chunks are written
+//! with the pauses they had when they were recorded; `--speed 2` halves
+//! the pauses, `--speed 0` sends everything at once. Connections start as
+//! far apart as the recorded ones did, so replaying a whole directory
+//! reproduces the load pattern as well as each client's input.
+//!
+//! Replies are read and counted, not checked: the server is the one
+//! under observation. Once a connection has sent its last chunk, it closes
+//! its write half and waits a little for the rest of the replies.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use tokio_examples::record::{self, Session};
+
+/// How long a connection waits for replies after its last chunk.
+const LINGER: Duration = Duration::from_secs(5);
+
+struct Options {
+    addr: String,
+    speed: f64,
+    files: Vec<PathBuf>,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut options = Options { addr: "127.0.0.1:7000".to_string(), speed: 1.0, files: Vec::new() };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} expects a value", arg));
+        match arg.as_str() {
+            "--addr" => options.addr = value()?,
+            "--speed" => {
+                let value = value()?;
+                options.speed = value
+                    .parse()
+                    .ok()
+                    .filter(|speed: &f64| *speed >= 0.0)
+                    .ok_or(format!("--speed expects a factor of 0 or more, got {:?}", value))?;
+            }
+            other if other.starts_with("--") => return Err(format!("unknown argument: {}", other)),
+            file => options.files.push(PathBuf::from(file)),
+        }
+    }
+    if options.files.is_empty() {
+        return Err("usage: replay [--addr <addr>] [--speed <factor>] <file>...".to_string());
+    }
+    Ok(options)
+}
+
+#[tokio::main]
+async fn main() {
+    let options = match parse_args() {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    let mut sessions = Vec::new();
+    for path in &options.files {
+        match record::load(path).await {
+            Ok(session) => sessions.push((path.display().to_string(), session)),
+            Err(e) => {
+                eprintln!("{}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // The earliest recorded connection starts right away, the others after
+    // the same delay they had then
+    let first = sessions.iter().map(|(_, session)| session.started).min().unwrap_or(0);
+    let start = Instant::now();
+    let mut replays = JoinSet::new();
+    for (name, session) in sessions {
+        let offset = scale(Duration::from_micros(session.started - first), options.speed);
+        let (addr, speed) = (options.addr.clone(), options.speed);
+        replays.spawn(async move {
+            tokio::time::sleep_until(start + offset).await;
+            let result = replay(&addr, &session, speed).await;
+            (name, session, result)
+        });
+    }
+
+    let mut failed = false;
+    while let Some(joined) = replays.join_next().await {
+        let (name, session, result) = joined.expect("a replay task panicked");
+        let sent: usize = session.chunks.iter().map(|chunk| chunk.data.len()).sum();
+        match result {
+            Ok(received) => println!(
+                "{}: connection #{} from {}, sent {} chunks ({} bytes), received {} bytes",
+                name,
+                session.conn_id,
+                session.peer,
+                session.chunks.len(),
+                sent,
+                received,
+            ),
+            Err(e) => {
+                eprintln!("{}: {}", name, e);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+/// Sends one session's chunks on a connection of its own, and returns how
+/// many bytes of replies came back.
+async fn replay(addr: &str, session: &Session, speed: f64) -> std::io::Result<u64> {
+    let stream = TcpStream::connect(addr).await?;
+    let (mut reader, mut writer) = stream.into_split();
+    let received = Arc::new(AtomicU64::new(0));
+    let mut reading = {
+        let received = received.clone();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 8 * 1024];
+            while let Ok(n @ 1..) = reader.read(&mut buf).await {
+                received.fetch_add(n as u64, Ordering::Relaxed);
+            }
+        })
+    };
+
+    // Deadlines from the connection's start, so slow writes do not add up
+    let connected = Instant::now();
+    for chunk in &session.chunks {
+        tokio::time::sleep_until(connected + scale(chunk.at, speed)).await;
+        writer.write_all(&chunk.data).await?;
+    }
+    writer.shutdown().await?;
+
+    // If the server keeps the connection open, what came so far counts
+    if tokio::time::timeout(LINGER, &mut reading).await.is_err() {
+        reading.abort();
+    }
+    Ok(received.load(Ordering::Relaxed))
+}
+
+/// The recorded delay `at`, played `speed` times as fast.
+fn scale(at: Duration, speed: f64) -> Duration {
+    if speed == 0.0 { Duration::ZERO } else { at.div_f64(speed) }
+}
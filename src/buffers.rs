@@ -0,0 +1,127 @@
+//! A pool of read buffers shared by all connections.
+//!
+//! Every connection needs a buffer for bytes that have been read but not
+//! yet split into lines. Allocating a fresh one per connection is cheap
+//! but not free; with many short-lived connections the allocator shows up
+//! in profiles. The pool keeps the buffers of finished connections and
+//! hands them to new ones.
+//!
+//! The buffers are `BytesMut`: a line is split off the front with
+//! `split_to` and frozen into `Bytes`, which shares the allocation instead
+//! of copying the line. Once every line of a buffer has been dropped,
+//! `reserve` reuses the same memory for the next reads.
+
+use crate::metrics::{self, Metrics};
+use bytes::BytesMut;
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+
+/// Capacity of a new buffer, and how much room is made before every read.
+pub const BUFFER_SIZE: usize = 4 * 1024;
+
+/// At most this many idle buffers are kept.
+const MAX_POOLED: usize = 1024;
+
+/// A buffer that grew past this (a very long line) is freed, not pooled,
+/// so one odd message does not pin the memory forever.
+const MAX_POOLED_CAPACITY: usize = 64 * 1024;
+
+pub struct BufferPool {
+    free: Mutex<Vec<BytesMut>>,
+    metrics: Metrics,
+}
+
+impl BufferPool {
+    pub fn new(metrics: Metrics) -> Self {
+        Self { free: Mutex::new(Vec::new()), metrics }
+    }
+
+    /// An empty buffer with room for at least `BUFFER_SIZE` bytes; it goes
+    /// back to the pool when dropped.
+    pub fn get(&self) -> PooledBuffer<'_> {
+        let pooled = self.free.lock().unwrap().pop();
+        let buf = match pooled {
+            Some(mut buf) => {
+                self.metrics.incr(metrics::BUFFERS_REUSED, 1);
+                buf.reserve(BUFFER_SIZE);
+                buf
+            }
+            None => {
+                self.metrics.incr(metrics::BUFFERS_ALLOCATED, 1);
+                BytesMut::with_capacity(BUFFER_SIZE)
+            }
+        };
+        PooledBuffer { pool: self, buf }
+    }
+
+    /// Number of idle buffers.
+    pub fn idle(&self) -> usize {
+        self.free.lock().unwrap().len()
+    }
+
+    fn put(&self, mut buf: BytesMut) {
+        if buf.capacity() > MAX_POOLED_CAPACITY {
+            return;
+        }
+        buf.clear();
+        let mut free = self.free.lock().unwrap();
+        if free.len() < MAX_POOLED {
+            free.push(buf);
+        }
+    }
+}
+
+/// A buffer on loan from a [`BufferPool`].
+pub struct PooledBuffer<'a> {
+    pool: &'a BufferPool,
+    buf: BytesMut,
+}
+
+impl Deref for PooledBuffer<'_> {
+    type Target = BytesMut;
+
+    fn deref(&self) -> &BytesMut {
+        &self.buf
+    }
+}
+
+impl DerefMut for PooledBuffer<'_> {
+    fn deref_mut(&mut self) -> &mut BytesMut {
+        &mut self.buf
+    }
+}
+
+impl Drop for PooledBuffer<'_> {
+    fn drop(&mut self) {
+        self.pool.put(std::mem::take(&mut self.buf));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::InMemorySink;
+    use std::sync::Arc;
+
+    #[test]
+    fn buffers_are_reused_and_come_back_empty() {
+        let pool = BufferPool::new(Arc::new(InMemorySink::default()));
+        {
+            let mut buf = pool.get();
+            buf.extend_from_slice(b"leftover");
+        }
+        assert_eq!(pool.idle(), 1);
+
+        let buf = pool.get();
+        assert!(buf.is_empty());
+        assert!(buf.capacity() >= BUFFER_SIZE);
+        assert_eq!(pool.idle(), 0);
+    }
+
+    #[test]
+    fn grown_buffers_are_not_kept() {
+        let pool = BufferPool::new(Arc::new(InMemorySink::default()));
+        pool.get().reserve(MAX_POOLED_CAPACITY * 2);
+        assert_eq!(pool.idle(), 0);
+    }
+}
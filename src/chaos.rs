@@ -0,0 +1,292 @@
+//! `ChaosStream`: injects network trouble into a connection, on purpose.
+//!
+//! With `--chaos`, every client stream is wrapped in this adapter. It makes
+//! the server's side of a healthy connection misbehave the way a bad
+//! network does, so timeouts, retries and error handling can be watched at
+//! work without a bad network at hand:
+//!
+//! - a read or a write is held back for a random delay first;
+//! - a read returns fewer bytes than were available (a short read), which
+//!   the codec has to piece together into messages;
+//! - the connection is reset: the operation fails with `ConnectionReset`,
+//!   and so does everything after it, as with a real reset.
+//!
+//! Each fault has its own probability, rolled once per read or write. A delay
+//! is a `tokio::time::Sleep`, armed and polled like in
+//! [`ThrottledStream`](crate::throttle::ThrottledStream), so a delayed
+//! connection costs nothing while it waits.
+
+use crate::metrics::{self, Metrics};
+use crate::transport::Transport;
+use std::fmt;
+use std::future::Future;
+use std::hash::{BuildHasher, RandomState};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+
+/// How often each fault happens: probabilities from 0 (never) to 1 (always).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Chaos {
+    /// A read or write waits first...
+    pub delay: f64,
+    /// ...for up to this long.
+    pub max_delay: Duration,
+    /// A read returns only part of what is available.
+    pub short_read: f64,
+    /// The connection is reset instead of reading or writing.
+    pub reset: f64,
+}
+
+impl Default for Chaos {
+    /// Noticeable, but most requests still get through.
+    fn default() -> Self {
+        Self { delay: 0.1, max_delay: Duration::from_millis(200), short_read: 0.2, reset: 0.01 }
+    }
+}
+
+impl fmt::Display for Chaos {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "delays {}% (up to {:?}), short reads {}%, resets {}%",
+            self.delay * 100.0,
+            self.max_delay,
+            self.short_read * 100.0,
+            self.reset * 100.0,
+        )
+    }
+}
+
+/// A small xorshift generator. The faults only need to look random, so
+/// this avoids a dependency on `rand`; it is seeded like session ids.
+struct Rng(u64);
+
+impl Rng {
+    fn new() -> Self {
+        // xorshift gets stuck at zero
+        Self(RandomState::new().hash_one(SystemTime::now()) | 1)
+    }
+
+    /// A number in `[0, 1)`.
+    fn next(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        // The top 53 bits, the precision of an `f64`
+        (self.0 >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// True with probability `p`.
+    fn chance(&mut self, p: f64) -> bool {
+        self.next() < p
+    }
+}
+
+/// The fault rolled for the operation in progress in one direction.
+#[derive(Default)]
+struct Direction {
+    // Rolled at the start of a read or write, kept until it completes,
+    // so an operation that is polled again is not delayed twice
+    rolled: bool,
+    // Boxed because `Sleep` is `!Unpin`, and the stream should stay `Unpin`
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+pub struct ChaosStream<T> {
+    inner: T,
+    // `None` when chaos mode is off: everything passes straight through
+    chaos: Option<Chaos>,
+    rng: Rng,
+    read: Direction,
+    write: Direction,
+    reset: bool,
+    metrics: Metrics,
+}
+
+impl<T> ChaosStream<T> {
+    pub fn new(inner: T, chaos: Option<Chaos>, metrics: Metrics) -> Self {
+        Self {
+            inner,
+            chaos,
+            rng: Rng::new(),
+            read: Direction::default(),
+            write: Direction::default(),
+            reset: false,
+            metrics,
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Rolls the faults for a new operation in `direction`, then waits out
+    /// its delay, if any. `Err` when the connection has been reset.
+    fn poll_faults(
+        direction: &mut Direction,
+        chaos: &Chaos,
+        rng: &mut Rng,
+        reset: &mut bool,
+        metrics: &Metrics,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        if !direction.rolled {
+            direction.rolled = true;
+            if !*reset && rng.chance(chaos.reset) {
+                *reset = true;
+                metrics.incr(metrics::CHAOS_RESETS, 1);
+            }
+            if rng.chance(chaos.delay) {
+                let delay = chaos.max_delay.mul_f64(rng.next());
+                direction.delay = Some(Box::pin(tokio::time::sleep(delay)));
+                metrics.incr(metrics::CHAOS_DELAYS, 1);
+            }
+        }
+        if let Some(delay) = &mut direction.delay {
+            ready!(delay.as_mut().poll(cx));
+            direction.delay = None;
+        }
+        if *reset {
+            direction.rolled = false;
+            let error = io::Error::new(io::ErrorKind::ConnectionReset, "reset by chaos mode");
+            return Poll::Ready(Err(error));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for ChaosStream<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let Some(chaos) = &this.chaos else {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        };
+        let (rng, metrics) = (&mut this.rng, &this.metrics);
+        ready!(Self::poll_faults(&mut this.read, chaos, rng, &mut this.reset, metrics, cx))?;
+
+        // A short read: only a random part of the room in `buf` is offered
+        let mut room = buf.remaining();
+        if room > 1 && rng.chance(chaos.short_read) {
+            room = 1 + (rng.next() * (room - 1) as f64) as usize;
+            metrics.incr(metrics::CHAOS_SHORT_READS, 1);
+        }
+        let mut limited = ReadBuf::new(buf.initialize_unfilled_to(room));
+        ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+        let n = limited.filled().len();
+        buf.advance(n);
+        this.read.rolled = false;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for ChaosStream<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let Some(chaos) = &this.chaos else {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        };
+        let (rng, metrics) = (&mut this.rng, &this.metrics);
+        ready!(Self::poll_faults(&mut this.write, chaos, rng, &mut this.reset, metrics, cx))?;
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.write.rolled = false;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+impl<T: Transport> Transport for ChaosStream<T> {
+    fn peer_closed(&self) -> impl Future<Output = ()> + Send + '_ {
+        self.inner.peer_closed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::InMemorySink;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::time::Instant;
+
+    fn chaos(chaos: Chaos) -> (ChaosStream<tokio::io::DuplexStream>, tokio::io::DuplexStream) {
+        let (client, server) = tokio::io::duplex(4096);
+        let metrics: Metrics = Arc::new(InMemorySink::default());
+        (ChaosStream::new(server, Some(chaos), metrics), client)
+    }
+
+    const CALM: Chaos =
+        Chaos { delay: 0.0, max_delay: Duration::ZERO, short_read: 0.0, reset: 0.0 };
+
+    #[tokio::test]
+    async fn without_chaos_everything_passes_through() {
+        let (mut server, mut client) = chaos(CALM);
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 16];
+        assert_eq!(server.read(&mut buf).await.unwrap(), 5);
+        server.write_all(b"world").await.unwrap();
+        assert_eq!(client.read(&mut buf).await.unwrap(), 5);
+    }
+
+    #[tokio::test]
+    async fn short_reads_return_part_of_the_data() {
+        let (mut server, mut client) = chaos(Chaos { short_read: 1.0, ..CALM });
+        client.write_all(&[b'x'; 100]).await.unwrap();
+
+        let mut buf = [0u8; 100];
+        let mut reads = 0;
+        let mut total = 0;
+        while total < 100 {
+            let n = server.read(&mut buf[total..]).await.unwrap();
+            assert!(n > 0);
+            total += n;
+            reads += 1;
+        }
+        // Every read with room for more than a byte came up short
+        assert!(reads > 1);
+        assert_eq!(buf, [b'x'; 100]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_delay_holds_an_operation_back() {
+        let max_delay = Duration::from_secs(1);
+        let (mut server, mut client) = chaos(Chaos { delay: 1.0, max_delay, ..CALM });
+
+        let start = Instant::now();
+        server.write_all(b"late").await.unwrap();
+        assert!(start.elapsed() <= max_delay);
+        let mut buf = [0u8; 4];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"late");
+    }
+
+    #[tokio::test]
+    async fn after_a_reset_every_operation_fails() {
+        let (mut server, mut client) = chaos(Chaos { reset: 1.0, ..CALM });
+        client.write_all(b"lost").await.unwrap();
+
+        let mut buf = [0u8; 4];
+        let error = server.read(&mut buf).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionReset);
+        let error = server.write_all(b"too").await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionReset);
+    }
+}
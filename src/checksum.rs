@@ -0,0 +1,52 @@
+//! Checksumming a file with blocking `std::fs` I/O.
+//!
+//! `std::fs::File::read` blocks the calling thread until the disk answers.
+//! Called from an async task it would stall the runtime thread, and every
+//! task on it, for the whole file. `spawn_blocking` moves the work to
+//! Tokio's pool of blocking threads, where waiting is harmless.
+//!
+//! The result goes back into the async world through the logger channel:
+//! on a blocking thread there is no `.await`, so the message is sent with
+//! `blocking_send`.
+
+use crate::logger::{LogMessage, LogTx};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tokio::task::JoinHandle;
+
+/// Starts checksumming `path` on a blocking thread; the outcome is logged.
+pub fn spawn(path: PathBuf, log_tx: LogTx) -> JoinHandle<()> {
+    tokio::task::spawn_blocking(move || {
+        let started = Instant::now();
+        let message = match crc32(&path) {
+            Ok((crc, bytes)) => LogMessage::info(format!(
+                "checksum of {}: crc32 {:08x}, {} bytes in {:?}",
+                path.display(),
+                crc,
+                bytes,
+                started.elapsed(),
+            )),
+            Err(e) => LogMessage::error(format!("checksum of {} failed: {}", path.display(), e)),
+        };
+        // Only fails if the logger is gone, i.e. the server is shutting down
+        log_tx.blocking_send(message);
+    })
+}
+
+/// CRC-32 and length of a file, read in chunks so large files need little memory.
+fn crc32(path: &Path) -> io::Result<(u32, u64)> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut bytes = 0;
+
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            return Ok((hasher.finalize(), bytes));
+        }
+        hasher.update(&buf[..n]);
+        bytes += n as u64;
+    }
+}
@@ -0,0 +1,255 @@
+//! A circuit breaker: stop dialing a target that keeps failing.
+//!
+//! [`retry`](crate::retry) makes one connect try harder. When a target is
+//! down for good, that only makes every client wait longer for the same
+//! error. The breaker remembers how the last connects went:
+//!
+//! ```text
+//!          N failures in a row          cooldown passed
+//! Closed ─────────────────────▶ Open ─────────────────▶ HalfOpen
+//!   ▲                            ▲                         │
+//!   │                            └────── trial fails ──────┤
+//!   └─────────────────────── trial succeeds ───────────────┘
+//! ```
+//!
+//! - Closed: connects go ahead.
+//! - Open: connects are refused at once, without touching the network.
+//! - HalfOpen: the cooldown has passed and a single trial connect is let
+//!   through. If it succeeds the circuit closes; if not it opens again.
+//!
+//! The state is a small struct behind a `std::sync::Mutex`, only locked
+//! inside these synchronous methods, like the request counter in `State`.
+
+use crate::metrics::Metrics;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// When a circuit opens, and for how long.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Policy {
+    /// Consecutive failures that open the circuit.
+    pub failures: u32,
+    /// How long an open circuit refuses connects before a trial.
+    pub cooldown: Duration,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self { failures: 5, cooldown: Duration::from_secs(30) }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Circuit {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl Circuit {
+    /// The value of the circuit's gauge in `STATS`.
+    fn gauge(self) -> i64 {
+        match self {
+            Circuit::Closed => 0,
+            Circuit::HalfOpen => 1,
+            Circuit::Open => 2,
+        }
+    }
+}
+
+impl fmt::Display for Circuit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Circuit::Closed => "closed",
+            Circuit::Open => "open",
+            Circuit::HalfOpen => "half-open",
+        })
+    }
+}
+
+struct Inner {
+    circuit: Circuit,
+    // Failures since the last success
+    consecutive: u32,
+    opened_at: Instant,
+    // In `HalfOpen`: the trial connect has been handed out
+    trial: bool,
+    attempts: u64,
+    failures: u64,
+}
+
+pub struct CircuitBreaker {
+    policy: Policy,
+    inner: Mutex<Inner>,
+    metrics: Metrics,
+    // 0 closed, 1 half-open, 2 open
+    gauge: &'static str,
+}
+
+impl CircuitBreaker {
+    /// A closed circuit, reported in `STATS` as the gauge `gauge`.
+    pub fn new(policy: Policy, metrics: Metrics, gauge: &'static str) -> Self {
+        // Listed from the start, even before the first connect
+        metrics.adjust(gauge, 0);
+        let inner = Inner {
+            circuit: Circuit::Closed,
+            consecutive: 0,
+            opened_at: Instant::now(),
+            trial: false,
+            attempts: 0,
+            failures: 0,
+        };
+        Self { policy, inner: Mutex::new(inner), metrics, gauge }
+    }
+
+    pub fn cooldown(&self) -> Duration {
+        self.policy.cooldown
+    }
+
+    pub fn circuit(&self) -> Circuit {
+        self.inner.lock().unwrap().circuit
+    }
+
+    /// Whether a connect would be let through right now. Changes nothing.
+    pub fn allows(&self) -> bool {
+        let inner = self.inner.lock().unwrap();
+        match inner.circuit {
+            Circuit::Closed => true,
+            Circuit::Open => inner.opened_at.elapsed() >= self.policy.cooldown,
+            Circuit::HalfOpen => !inner.trial,
+        }
+    }
+
+    /// Asks to connect. An open circuit past its cooldown goes half-open and
+    /// hands out the trial; then, until its outcome is recorded, and while
+    /// the circuit is open, the answer is no.
+    pub fn acquire(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.circuit {
+            Circuit::Closed => true,
+            Circuit::Open if inner.opened_at.elapsed() >= self.policy.cooldown => {
+                self.set(&mut inner, Circuit::HalfOpen);
+                inner.trial = true;
+                true
+            }
+            Circuit::Open => false,
+            Circuit::HalfOpen if !inner.trial => {
+                inner.trial = true;
+                true
+            }
+            Circuit::HalfOpen => false,
+        }
+    }
+
+    /// Records the outcome of an acquired connect. Returns the new state
+    /// when the circuit opened or closed, for the log.
+    pub fn record(&self, success: bool) -> Option<Circuit> {
+        let mut inner = self.inner.lock().unwrap();
+        let before = inner.circuit;
+        inner.attempts += 1;
+        inner.trial = false;
+        if success {
+            inner.consecutive = 0;
+            self.set(&mut inner, Circuit::Closed);
+        } else {
+            inner.failures += 1;
+            inner.consecutive += 1;
+            // A failed trial reopens at once
+            if before == Circuit::HalfOpen || inner.consecutive >= self.policy.failures {
+                inner.opened_at = Instant::now();
+                self.set(&mut inner, Circuit::Open);
+            }
+        }
+        (inner.circuit != before).then_some(inner.circuit)
+    }
+
+    /// Failed connects, out of all recorded ones; 0 before the first.
+    pub fn failure_rate(&self) -> f64 {
+        let inner = self.inner.lock().unwrap();
+        if inner.attempts == 0 { 0.0 } else { inner.failures as f64 / inner.attempts as f64 }
+    }
+
+    fn set(&self, inner: &mut Inner, circuit: Circuit) {
+        self.metrics.adjust(self.gauge, circuit.gauge() - inner.circuit.gauge());
+        inner.circuit = circuit;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::InMemorySink;
+    use std::sync::Arc;
+
+    const POLICY: Policy = Policy { failures: 3, cooldown: Duration::from_secs(10) };
+
+    fn breaker() -> (CircuitBreaker, Metrics) {
+        let metrics: Metrics = Arc::new(InMemorySink::default());
+        (CircuitBreaker::new(POLICY, metrics.clone(), "circuit"), metrics)
+    }
+
+    fn gauge(metrics: &Metrics) -> i64 {
+        metrics.snapshot().iter().find(|m| m.name == "circuit").unwrap().value
+    }
+
+    /// Acquires and records a connect that fails.
+    fn fail(breaker: &CircuitBreaker) -> Option<Circuit> {
+        assert!(breaker.acquire());
+        breaker.record(false)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_circuit_opens_after_consecutive_failures() {
+        let (breaker, metrics) = breaker();
+        assert_eq!(fail(&breaker), None);
+        // A success in between starts the count again
+        assert!(breaker.acquire());
+        assert_eq!(breaker.record(true), None);
+        assert_eq!(fail(&breaker), None);
+        assert_eq!(fail(&breaker), None);
+        assert_eq!(fail(&breaker), Some(Circuit::Open));
+
+        assert!(!breaker.allows());
+        assert!(!breaker.acquire());
+        assert_eq!(gauge(&metrics), 2);
+        assert_eq!(breaker.failure_rate(), 0.8);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn after_the_cooldown_a_single_trial_is_let_through() {
+        let (breaker, metrics) = breaker();
+        for _ in 0..3 {
+            fail(&breaker);
+        }
+        tokio::time::advance(POLICY.cooldown).await;
+
+        assert!(breaker.allows());
+        assert!(breaker.acquire());
+        assert_eq!(breaker.circuit(), Circuit::HalfOpen);
+        assert_eq!(gauge(&metrics), 1);
+        // Only one trial at a time
+        assert!(!breaker.acquire());
+
+        assert_eq!(breaker.record(true), Some(Circuit::Closed));
+        assert!(breaker.acquire());
+        assert_eq!(gauge(&metrics), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_failed_trial_opens_the_circuit_again() {
+        let (breaker, _) = breaker();
+        for _ in 0..3 {
+            fail(&breaker);
+        }
+        tokio::time::advance(POLICY.cooldown).await;
+
+        assert_eq!(fail(&breaker), Some(Circuit::Open));
+        // The cooldown starts over
+        tokio::time::advance(POLICY.cooldown / 2).await;
+        assert!(!breaker.acquire());
+        tokio::time::advance(POLICY.cooldown / 2).await;
+        assert!(breaker.acquire());
+    }
+}
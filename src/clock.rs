@@ -0,0 +1,215 @@
+//! Time as a dependency: the rate limiter, the heartbeat and the scheduler
+//! ask a [`Clock`] for the time and for their timers instead of calling
+//! `tokio::time` directly.
+//!
+//! The server runs on [`TokioClock`]. Tests can hand the same code a
+//! [`ManualClock`] instead, whose time only moves when the test calls
+//! `advance`. Unlike `tokio::time::pause`, it does not affect the rest of
+//! the runtime, it needs no `start_paused` test, and a future waiting on
+//! it can be polled by hand, without a runtime at all.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// A timer from a [`Clock`]; completes at its deadline.
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Where the time comes from.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Completes once the clock reaches `deadline`.
+    fn sleep_until(&self, deadline: Instant) -> Sleep;
+
+    /// Completes once `duration` has passed.
+    fn sleep(&self, duration: Duration) -> Sleep {
+        self.sleep_until(self.now() + duration)
+    }
+
+    /// Ticks every `period`, first at `start`.
+    fn interval_at(&self, start: Instant, period: Duration) -> Interval;
+
+    /// Ticks every `period`, first right away.
+    fn interval(&self, period: Duration) -> Interval {
+        self.interval_at(self.now(), period)
+    }
+}
+
+/// The runtime's clock, `tokio::time`; paused and advanced with it in tests.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Sleep {
+        Box::pin(tokio::time::sleep_until(deadline))
+    }
+
+    fn interval_at(&self, start: Instant, period: Duration) -> Interval {
+        Interval::new(Arc::new(TokioClock), start, period)
+    }
+}
+
+/// A clock that stands still until it is advanced; for tests.
+///
+/// Clones share the time, so a test keeps one and hands the other to the
+/// code under test.
+#[derive(Clone)]
+pub struct ManualClock {
+    inner: Arc<Mutex<Manual>>,
+}
+
+struct Manual {
+    now: Instant,
+    // Timers that were polled before their deadline, to wake when it comes
+    sleepers: Vec<(Instant, Waker)>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        let manual = Manual { now: Instant::now(), sleepers: Vec::new() };
+        Self { inner: Arc::new(Mutex::new(manual)) }
+    }
+
+    /// Moves the time forward, and wakes the timers that are now due.
+    pub fn advance(&self, by: Duration) {
+        let due: Vec<_> = {
+            let mut manual = self.inner.lock().unwrap();
+            manual.now += by;
+            let now = manual.now;
+            let (due, waiting) = manual.sleepers.drain(..).partition(|(at, _)| *at <= now);
+            manual.sleepers = waiting;
+            due
+        };
+        // Woken outside the lock, in case a waker polls right away
+        for (_, waker) in due {
+            waker.wake();
+        }
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.inner.lock().unwrap().now
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Sleep {
+        Box::pin(ManualSleep { clock: self.inner.clone(), deadline })
+    }
+
+    fn interval_at(&self, start: Instant, period: Duration) -> Interval {
+        Interval::new(Arc::new(self.clone()), start, period)
+    }
+}
+
+struct ManualSleep {
+    clock: Arc<Mutex<Manual>>,
+    deadline: Instant,
+}
+
+impl Future for ManualSleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut manual = self.clock.lock().unwrap();
+        if manual.now >= self.deadline {
+            return Poll::Ready(());
+        }
+        manual.sleepers.push((self.deadline, cx.waker().clone()));
+        Poll::Pending
+    }
+}
+
+/// Ticks of a [`Clock`] at a fixed period.
+///
+/// A tick that comes late pushes the later ones back, like
+/// `MissedTickBehavior::Delay`: they never come in a burst to catch up.
+pub struct Interval {
+    clock: Arc<dyn Clock>,
+    next: Instant,
+    period: Duration,
+    // The timer for `next`, kept when a `select!` drops `tick` unfinished
+    sleep: Option<Sleep>,
+}
+
+impl Interval {
+    pub fn new(clock: Arc<dyn Clock>, start: Instant, period: Duration) -> Self {
+        Self { clock, next: start, period, sleep: None }
+    }
+
+    /// Waits for the next tick, and returns when it was due.
+    ///
+    /// Cancel-safe: a tick that was not waited for to the end is still the
+    /// next one.
+    pub async fn tick(&mut self) -> Instant {
+        let deadline = self.next;
+        self.sleep.get_or_insert_with(|| self.clock.sleep_until(deadline)).await;
+        self.sleep = None;
+        let now = self.clock.now();
+        self.next = deadline + self.period;
+        if self.next <= now {
+            self.next = now + self.period;
+        }
+        deadline
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::FutureExt;
+
+    #[test]
+    fn a_timer_is_due_when_the_clock_is_advanced_to_it() {
+        let clock = ManualClock::new();
+        let mut sleep = clock.sleep(Duration::from_secs(3));
+        assert_eq!(sleep.as_mut().now_or_never(), None);
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(sleep.as_mut().now_or_never(), None);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(sleep.now_or_never(), Some(()));
+    }
+
+    #[tokio::test]
+    async fn advancing_wakes_the_waiting_task() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let waiting = tokio::spawn(clock.sleep(Duration::from_secs(60)));
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+
+        clock.advance(Duration::from_secs(60));
+        waiting.await.unwrap();
+        assert_eq!(clock.now() - start, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn a_late_tick_pushes_the_next_ones_back() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let mut interval = clock.interval(Duration::from_secs(10));
+        assert_eq!(interval.tick().now_or_never(), Some(start));
+        assert_eq!(interval.tick().now_or_never(), None);
+
+        clock.advance(Duration::from_secs(25));
+        let late = interval.tick().now_or_never();
+        assert_eq!(late, Some(start + Duration::from_secs(10)));
+        // The next tick is a period after the late one, not at 20s
+        assert_eq!(interval.tick().now_or_never(), None);
+        clock.advance(Duration::from_secs(10));
+        assert!(interval.tick().now_or_never().is_some());
+    }
+}
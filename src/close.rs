@@ -0,0 +1,112 @@
+//! Why a connection ended.
+//!
+//! Every exit path of a connection task ends with exactly one
+//! [`CloseReason`]. It is written to the trace, logged, and counted in a
+//! `connections_closed_<reason>` metric, so every disconnect can be
+//! attributed.
+
+use crate::metrics;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The client closed its side of the connection.
+    ClientEof,
+    /// The client sent nothing for longer than the idle timeout.
+    IdleTimeout,
+    /// The client did not answer a heartbeat `PING` in time.
+    HeartbeatTimeout,
+    /// The client kept sending after too many rate-limited messages.
+    RateLimited,
+    /// An administrator disconnected the client (`KICK`).
+    AdminKill,
+    /// The server is shutting down.
+    ServerShutdown,
+    /// The client sent something that is not the text protocol.
+    ProtocolError,
+    /// The client sent a line longer than the limit (with `--disconnect-oversized`).
+    MessageTooLong,
+    /// The client did not read its replies (with `--backpressure disconnect`).
+    SlowClient,
+    /// Reading from or writing to the socket failed.
+    IoError,
+}
+
+impl CloseReason {
+    /// Whether the server decided to close; such connections get a
+    /// half-close (see `close_gracefully` in the server).
+    pub fn server_initiated(&self) -> bool {
+        match self {
+            Self::ClientEof | Self::IoError => false,
+            Self::IdleTimeout
+            | Self::HeartbeatTimeout
+            | Self::RateLimited
+            | Self::AdminKill
+            | Self::ServerShutdown
+            | Self::ProtocolError
+            | Self::MessageTooLong
+            | Self::SlowClient => true,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::ClientEof => "client_eof",
+            Self::IdleTimeout => "idle_timeout",
+            Self::HeartbeatTimeout => "heartbeat_timeout",
+            Self::RateLimited => "rate_limited",
+            Self::AdminKill => "admin_kill",
+            Self::ServerShutdown => "server_shutdown",
+            Self::ProtocolError => "protocol_error",
+            Self::MessageTooLong => "message_too_long",
+            Self::SlowClient => "slow_client",
+            Self::IoError => "io_error",
+        }
+    }
+
+    /// The counter incremented when a connection closes for this reason.
+    pub fn metric(&self) -> &'static str {
+        match self {
+            Self::ClientEof => metrics::CLOSED_CLIENT_EOF,
+            Self::IdleTimeout => metrics::CLOSED_IDLE_TIMEOUT,
+            Self::HeartbeatTimeout => metrics::CLOSED_HEARTBEAT_TIMEOUT,
+            Self::RateLimited => metrics::CLOSED_RATE_LIMITED,
+            Self::AdminKill => metrics::CLOSED_ADMIN_KILL,
+            Self::ServerShutdown => metrics::CLOSED_SERVER_SHUTDOWN,
+            Self::ProtocolError => metrics::CLOSED_PROTOCOL_ERROR,
+            Self::MessageTooLong => metrics::CLOSED_MESSAGE_TOO_LONG,
+            Self::SlowClient => metrics::CLOSED_SLOW_CLIENT,
+            Self::IoError => metrics::CLOSED_IO_ERROR,
+        }
+    }
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// How the close of a connection the server decided to close ended, once
+/// its last replies were flushed and its write half shut down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Drain {
+    /// The client closed its side within the grace period.
+    ClientClosed,
+    /// The client was gone before the end: flushing failed, or it reset
+    /// the connection.
+    ClientGone,
+    /// The grace period ran out first, and the socket was dropped.
+    Forced,
+}
+
+impl Drain {
+    /// The counter incremented when a close ends this way.
+    pub fn metric(&self) -> &'static str {
+        match self {
+            Self::ClientClosed => metrics::DRAIN_CLIENT_CLOSED,
+            Self::ClientGone => metrics::DRAIN_CLIENT_GONE,
+            Self::Forced => metrics::DRAIN_FORCED,
+        }
+    }
+}
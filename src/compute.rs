@@ -0,0 +1,109 @@
+//! A computation service: a task that answers requests sent over a channel.
+//!
+//! Handlers do not compute `FIB <n>` themselves. They send the service a
+//! request that carries a `oneshot::Sender`, and await the matching
+//! receiver for the answer:
+//!
+//! ```text
+//! connection task --(mpsc: Request + oneshot::Sender)--> compute task
+//!        ^                                                    |
+//!        +-------------------(oneshot: answer)----------------+
+//! ```
+//!
+//! The task owns its state outright (here, the Fibonacci numbers computed so
+//! far), so no lock is needed: requests are handled one at a time, in order.
+//! Unlike the `HASH` pool, every request is cheap, so an ordinary async task
+//! is enough.
+
+use crate::metrics::{self, Metrics};
+use crate::task;
+use tokio::sync::{mpsc, oneshot};
+
+/// The largest `n` whose Fibonacci number fits in a `u128`.
+pub const MAX_FIB: u32 = 186;
+
+enum Request {
+    Fib {
+        n: u32,
+        reply: oneshot::Sender<Result<u128, ComputeError>>,
+    },
+}
+
+/// Why a request did not produce an answer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComputeError {
+    /// The answer would not fit in a `u128`.
+    TooLarge,
+    /// The service has stopped.
+    Gone,
+}
+
+/// Handle to the compute task; cheap to clone. The task exits once every
+/// handle is dropped.
+#[derive(Clone)]
+pub struct Compute {
+    requests: mpsc::Sender<Request>,
+}
+
+impl Compute {
+    pub fn start(metrics: Metrics) -> Self {
+        let (requests, rx) = mpsc::channel(32);
+        task::spawn("compute", serve(rx, metrics));
+        Self { requests }
+    }
+
+    /// The `n`th Fibonacci number.
+    pub async fn fib(&self, n: u32) -> Result<u128, ComputeError> {
+        let (reply, answer) = oneshot::channel();
+        // `send` waits for room: the queue only fills up for a moment
+        self.requests
+            .send(Request::Fib { n, reply })
+            .await
+            .map_err(|_| ComputeError::Gone)?;
+        // The reply sender is dropped unanswered only if the task stops
+        answer.await.unwrap_or(Err(ComputeError::Gone))
+    }
+}
+
+async fn serve(mut requests: mpsc::Receiver<Request>, metrics: Metrics) {
+    // Fibonacci numbers computed so far; grows with the largest `n` asked for
+    let mut known: Vec<u128> = vec![0, 1];
+
+    while let Some(request) = requests.recv().await {
+        metrics.incr(metrics::COMPUTE_REQUESTS, 1);
+        match request {
+            Request::Fib { n, reply } => {
+                let answer = if n > MAX_FIB {
+                    Err(ComputeError::TooLarge)
+                } else {
+                    while known.len() <= n as usize {
+                        known.push(known[known.len() - 1] + known[known.len() - 2]);
+                    }
+                    Ok(known[n as usize])
+                };
+                // The caller may have given up waiting; nothing to do then
+                let _ = reply.send(answer);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::InMemorySink;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn fib_answers_come_back_over_oneshot_channels() {
+        let metrics: Metrics = Arc::new(InMemorySink::default());
+        let compute = Compute::start(metrics.clone());
+
+        assert_eq!(compute.fib(0).await, Ok(0));
+        assert_eq!(compute.fib(10).await, Ok(55));
+        let largest = 332_825_110_087_067_562_321_196_029_789_634_457_848;
+        assert_eq!(compute.fib(MAX_FIB).await, Ok(largest));
+        assert_eq!(compute.fib(MAX_FIB + 1).await, Err(ComputeError::TooLarge));
+        assert_eq!(metrics::stat_summary(&metrics), "compute_requests=4");
+    }
+}
@@ -0,0 +1,1709 @@
+//! Server configuration.
+//!
+//! Settings come from three layers, later ones winning:
+//! built-in defaults, an optional TOML file (`--config <file>`), and
+//! command line flags.
+//!
+//! The active configuration is shared through a `watch` channel. On SIGHUP
+//! the file is read again and the new `ServerConfig` is sent; the accept
+//! loop, connection handlers and the logger pick it up on their next
+//! iteration. Settings that belong to sockets or the runtime (`listen`,
+//! `runtime`, `workers`, `metrics`, `http`), and the state file, only apply
+//! after a restart.
+
+use crate::acl::AccessList;
+use crate::admin::AdminAddr;
+use crate::auth::{AuthToken, CertIdentity};
+use crate::backpressure::Backpressure;
+use crate::chaos::Chaos;
+use crate::circuit;
+use crate::health::{self, Target};
+use crate::heartbeat::Heartbeat;
+use crate::http::Destination;
+use crate::listener::SocketOptions;
+use crate::listeners::{ListenerMode, ListenerSpec};
+use crate::logger::{LogLevel, LogOverflow};
+use crate::metrics::MetricsBackend;
+use crate::middleware::{self, Layer};
+use crate::protocol::Utf8Mode;
+use crate::proxy::Balance;
+use crate::pubsub;
+use crate::rate_limit::{Limit, OnExceed};
+use crate::readiness::{NotReadyPolicy, Phase};
+use crate::shedding::{self, ShedPolicy, Thresholds};
+use crate::socks::Credentials;
+use crate::state::CounterKind;
+use crate::template::ResponseTemplate;
+use crate::vhost::VirtualHost;
+use crate::wal::Durability;
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// The receiving side of the configuration channel.
+pub type ConfigRx = watch::Receiver<Arc<ServerConfig>>;
+
+/// What the binary was asked to do.
+pub enum Command {
+    /// Run the TCP server (the default when no subcommand is given).
+    Serve(Box<ServerConfig>),
+    /// Render one or more trace files as a single human-readable timeline.
+    TraceDump(Vec<PathBuf>),
+    /// Print the SHA-256 of a token, as `--auth-token` takes it.
+    HashToken(String),
+    /// Check the configuration the rest of the arguments describe.
+    Doctor(Vec<String>),
+}
+
+/// Which Tokio runtime setup the server uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuntimeMode {
+    /// The default work-stealing runtime with one worker per core.
+    MultiThread,
+    /// Everything on a single thread.
+    CurrentThread,
+    /// One current_thread runtime and one `SO_REUSEPORT` listener per core.
+    ThreadPerCore,
+    /// The multi-thread runtime with several `SO_REUSEPORT` listeners, each
+    /// with its own accept loop.
+    MultiAcceptor,
+    /// A current_thread runtime with a `LocalSet`: connection tasks are
+    /// spawned with `spawn_local` and keep non-`Send` session state.
+    Local,
+    /// tokio-uring's runtime: the main listener's sockets are accepted,
+    /// read and written through io_uring. Linux only, with the `uring` feature.
+    Uring,
+}
+
+/// Settings for the TCP server.
+#[derive(Debug)]
+pub struct ServerConfig {
+    pub listen: SocketAddr,
+    /// A second listener whose connections use length-prefixed framing.
+    pub length_listen: Option<SocketAddr>,
+    /// A listener whose connections send JSON requests, one per line.
+    pub json_listen: Option<SocketAddr>,
+    /// More listeners, each in its own protocol mode, next to the ones above.
+    pub listeners: Vec<ListenerSpec>,
+    /// When set, every connection writes its trace into this directory.
+    pub trace_dir: Option<PathBuf>,
+    /// Directory each connection's input is recorded to, for `replay`.
+    pub record_dir: Option<PathBuf>,
+    /// Directory each connection's hex dump of its bytes goes to.
+    pub wire_debug_dir: Option<PathBuf>,
+    /// The most a single hex dump file grows to.
+    pub wire_debug_max: u64,
+    /// Message rate limit of a single connection.
+    pub conn_limit: Option<Limit>,
+    /// Message rate limit shared by all connections from one IP address.
+    pub ip_limit: Option<Limit>,
+    /// Request quota per client address or token, on every listener.
+    pub quota: Option<Limit>,
+    pub on_limit: OnExceed,
+    /// Close a connection after this many rejected messages in a row.
+    pub disconnect_after: Option<u32>,
+    /// File with allow/deny CIDR rules, re-read on SIGHUP.
+    pub acl_file: Option<PathBuf>,
+    pub metrics: MetricsBackend,
+    /// Address of the `/healthz` and `/readyz` endpoint, which also answers `CONNECT`.
+    pub http_addr: Option<SocketAddr>,
+    /// Where the HTTP endpoint's `CONNECT` may open tunnels to; empty refuses them all.
+    pub connect_allow: Vec<Destination>,
+    /// The directory the HTTP endpoint serves under `/files/`.
+    pub files_root: Option<PathBuf>,
+    /// From this many open connections on, `/readyz` says the server is not ready.
+    pub ready_max_connections: Option<usize>,
+    /// From which load on new connections are shed; a reload applies to the
+    /// next connection.
+    pub shedding: Thresholds,
+    /// Whether shed connections are rejected, or wait until the load falls.
+    pub shed_policy: ShedPolicy,
+    /// Address of the gRPC service; needs the `grpc` feature.
+    pub grpc_addr: Option<SocketAddr>,
+    /// Address of the HTTP/2 echo and counter endpoint.
+    pub h2_addr: Option<SocketAddr>,
+    /// Address of the UDP echo and counter socket.
+    pub udp_addr: Option<SocketAddr>,
+    /// Where the admin control socket listens.
+    pub admin_addr: Option<AdminAddr>,
+    /// Read admin commands from stdin. Off for servers embedded in tests,
+    /// whose stdin may be a terminal that never closes.
+    pub console: bool,
+    /// What happens to connections accepted before the server is ready.
+    pub not_ready: NotReadyPolicy,
+    /// Artificial extra warm-up time, to make the warming phase observable.
+    pub warmup_delay: Duration,
+    pub runtime: RuntimeMode,
+    /// Number of worker threads in thread-per-core mode, or of accept
+    /// loops in multi-acceptor mode.
+    pub workers: usize,
+    /// Connections that send nothing for this long are closed.
+    pub idle_timeout: Option<Duration>,
+    /// How long a connection the server closes waits, after its last reply,
+    /// for the client to close its side.
+    pub close_grace: Duration,
+    /// Connections of the main listener that send nothing for this long get
+    /// a `PING`; set when the listener is bound.
+    pub heartbeat_idle: Option<Duration>,
+    /// The same, for the length-prefixed listener.
+    pub length_heartbeat_idle: Option<Duration>,
+    /// The same, for the JSON listener.
+    pub json_heartbeat_idle: Option<Duration>,
+    /// How long a pinged connection has to answer `PONG`.
+    pub heartbeat_timeout: Duration,
+    /// Bytes per second each connection may read, and write; a reload
+    /// applies to new connections.
+    pub bandwidth_limit: Option<u64>,
+    /// Inject delays, short reads and resets into client connections; a
+    /// reload applies to new connections.
+    pub chaos: Option<Chaos>,
+    /// Longest accepted line, in bytes; a reload applies to new connections.
+    pub max_message: usize,
+    /// Close connections that exceed `max_message` instead of only answering `ERR`.
+    pub disconnect_oversized: bool,
+    /// What happens to messages that are not valid UTF-8.
+    pub utf8: Utf8Mode,
+    /// Reply bytes a connection may have queued before it counts as slow.
+    pub outbox_limit: usize,
+    /// What happens to a slow connection.
+    pub backpressure: Backpressure,
+    /// Requests of one connection that may run at once (`FIB`, `HASH`,
+    /// `SLEEP`); 1 serves them one at a time.
+    pub max_in_flight: usize,
+    /// The interceptors every request passes through, in order.
+    pub middleware: Vec<Layer>,
+    /// A rhai script that sees every request; needs the `scripting` feature.
+    pub script: Option<PathBuf>,
+    /// How long one call of the script may run before it is stopped.
+    pub script_budget: Duration,
+    /// Clients must send `AUTH <token>` before any other request.
+    pub require_auth: bool,
+    /// The tokens `AUTH` takes, by their SHA-256, with what each may do.
+    pub auth_tokens: Vec<AuthToken>,
+    /// Greet new connections with a banner and wait for `HELLO <mode>`.
+    pub handshake: bool,
+    /// Replies longer than this are gzipped for clients that asked for it.
+    pub gzip_threshold: usize,
+    /// Pipe connections to these `host:port` upstreams instead of serving them.
+    pub proxy: Vec<String>,
+    /// How the upstream of each proxied connection is picked.
+    pub proxy_balance: Balance,
+    /// How often every upstream is checked.
+    pub proxy_health_interval: Duration,
+    /// When the circuit breaker of an upstream opens, and for how long.
+    pub circuit: circuit::Policy,
+    /// Services to probe, how often, and when one counts as down.
+    pub health: health::Checks,
+    /// Where the SOCKS5 proxy listens.
+    pub socks_listen: Option<SocketAddr>,
+    /// Require this username and password from SOCKS5 clients.
+    pub socks_auth: Option<Credentials>,
+    /// Where the TLS listener, which picks the protocol by ALPN, listens.
+    pub tls_listen: Option<SocketAddr>,
+    /// The TLS listener's certificate chain, PEM encoded.
+    pub tls_cert: Option<PathBuf>,
+    /// The private key for `tls_cert`, PEM encoded.
+    pub tls_key: Option<PathBuf>,
+    /// TLS clients must present a certificate signed by this CA, PEM encoded.
+    pub tls_client_ca: Option<PathBuf>,
+    /// Client certificate identities, with what each may do.
+    pub tls_identities: Vec<CertIdentity>,
+    /// Virtual hosts on the TLS listener, picked by SNI.
+    pub tls_hosts: Vec<VirtualHost>,
+    /// Where `PUT` stores uploaded files.
+    pub upload_dir: PathBuf,
+    /// Largest file `PUT` accepts, in bytes.
+    pub max_upload: u64,
+    /// Log messages below this level are dropped by the logger.
+    pub log_level: LogLevel,
+    /// Whether every client message is logged; if not, it is never sent to
+    /// the logger at all.
+    pub log_messages: bool,
+    /// Log messages that waited in the queue longer than this are dropped.
+    pub log_ttl: Option<Duration>,
+    /// The logger prints a batch once this many messages are waiting...
+    pub log_batch: usize,
+    /// ...or when this timer ticks, whichever comes first.
+    pub log_flush: Duration,
+    /// What happens to a log message when the log channel is full.
+    pub log_overflow: LogOverflow,
+    /// A SQLite database that keeps every logged message, for `HISTORY`;
+    /// needs the `sqlite` feature.
+    pub history_db: Option<PathBuf>,
+    /// Reply to plain messages, compiled when the configuration is loaded.
+    pub response: Arc<ResponseTemplate>,
+    /// How the request counter is kept: one lock, one atomic, or sharded.
+    pub counter: CounterKind,
+    /// Where the counter and the key-value store are saved across restarts.
+    pub state_file: Option<PathBuf>,
+    /// How often the state file is written while running.
+    pub snapshot_interval: Duration,
+    /// Whether, and how soon, key-value changes go to a write-ahead log
+    /// next to the state file.
+    pub durability: Durability,
+    /// Follow the primary at this `host:port`, serving reads only.
+    pub replica_of: Option<String>,
+    /// When set, all metrics are written to the log this often.
+    pub stats_interval: Option<Duration>,
+    /// When set, the runtime's own metrics are sampled and logged this often.
+    pub runtime_stats_interval: Option<Duration>,
+    /// Blocking threads computing `HASH`.
+    pub hash_workers: usize,
+    /// `HASH` jobs that may wait for a worker before new ones are refused.
+    pub hash_queue: usize,
+    /// When set, changes below this directory are logged and sent to `WATCH`.
+    pub watch_dir: Option<PathBuf>,
+    /// Where the queues of the durable topics are kept.
+    pub queue_dir: Option<PathBuf>,
+    /// Topics whose messages are queued on disk until acknowledged; see `crate::queue`.
+    pub durable_topics: Vec<String>,
+    /// Client addresses not seen for this long are dropped from the peer statistics.
+    pub peer_idle: Duration,
+    /// How long a closed connection's session can still be resumed.
+    pub session_grace: Duration,
+    /// Connections with no message for this long are closed by the idle
+    /// sweeper, whatever they are doing.
+    pub session_ttl: Option<Duration>,
+    /// Tuning for accepted streams; a reload applies to new connections.
+    pub socket: SocketOptions,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen: SocketAddr::from(([127, 0, 0, 1], 7000)),
+            length_listen: None,
+            json_listen: None,
+            listeners: Vec::new(),
+            trace_dir: None,
+            record_dir: None,
+            wire_debug_dir: None,
+            wire_debug_max: 1024 * 1024,
+            conn_limit: None,
+            ip_limit: None,
+            quota: None,
+            on_limit: OnExceed::Delay,
+            disconnect_after: None,
+            acl_file: None,
+            metrics: MetricsBackend::Memory,
+            http_addr: None,
+            connect_allow: Vec::new(),
+            files_root: None,
+            ready_max_connections: None,
+            shedding: Thresholds::default(),
+            shed_policy: ShedPolicy::Reject,
+            grpc_addr: None,
+            h2_addr: None,
+            udp_addr: None,
+            admin_addr: None,
+            console: true,
+            not_ready: NotReadyPolicy::Queue,
+            warmup_delay: Duration::ZERO,
+            runtime: RuntimeMode::MultiThread,
+            workers: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            idle_timeout: None,
+            close_grace: Duration::from_secs(2),
+            heartbeat_idle: None,
+            length_heartbeat_idle: None,
+            json_heartbeat_idle: None,
+            heartbeat_timeout: Duration::from_secs(10),
+            bandwidth_limit: None,
+            chaos: None,
+            max_message: 8 * 1024,
+            disconnect_oversized: false,
+            utf8: Utf8Mode::Lossy,
+            outbox_limit: 256 * 1024,
+            backpressure: Backpressure::Pause,
+            max_in_flight: 1,
+            middleware: middleware::DEFAULT_LAYERS.to_vec(),
+            script: None,
+            script_budget: Duration::from_millis(50),
+            require_auth: false,
+            auth_tokens: Vec::new(),
+            handshake: false,
+            gzip_threshold: 1024,
+            proxy: Vec::new(),
+            proxy_balance: Balance::RoundRobin,
+            proxy_health_interval: Duration::from_secs(5),
+            circuit: circuit::Policy::default(),
+            health: health::Checks::default(),
+            socks_listen: None,
+            socks_auth: None,
+            tls_listen: None,
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+            tls_identities: Vec::new(),
+            tls_hosts: Vec::new(),
+            upload_dir: PathBuf::from("uploads"),
+            max_upload: 16 * 1024 * 1024,
+            log_level: LogLevel::Info,
+            log_messages: true,
+            log_ttl: None,
+            log_batch: 64,
+            log_flush: Duration::from_millis(100),
+            log_overflow: LogOverflow::Wait,
+            history_db: None,
+            response: Arc::new(ResponseTemplate::default()),
+            counter: CounterKind::Mutex,
+            state_file: None,
+            snapshot_interval: Duration::from_secs(30),
+            durability: Durability::None,
+            replica_of: None,
+            stats_interval: None,
+            runtime_stats_interval: None,
+            hash_workers: 2,
+            hash_queue: 32,
+            watch_dir: None,
+            queue_dir: None,
+            durable_topics: Vec::new(),
+            peer_idle: Duration::from_secs(600),
+            session_grace: Duration::from_secs(60),
+            session_ttl: None,
+            socket: SocketOptions::default(),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// The heartbeat of a listener whose connections are pinged after `idle`.
+    pub fn heartbeat(&self, idle: Option<Duration>) -> Option<Heartbeat> {
+        idle.map(|idle| Heartbeat { idle, timeout: self.heartbeat_timeout })
+    }
+
+    /// Every listener besides the main one: `length_listen`, `json_listen`,
+    /// `socks_listen` and `tls_listen` first, then `listeners`.
+    pub fn listener_specs(&self) -> Vec<ListenerSpec> {
+        let named = [
+            (ListenerMode::Length, self.length_listen),
+            (ListenerMode::Json, self.json_listen),
+            (ListenerMode::Socks, self.socks_listen),
+            (ListenerMode::Tls, self.tls_listen),
+        ];
+        named
+            .into_iter()
+            .filter_map(|(mode, addr)| addr.map(|addr| ListenerSpec { mode, addr }))
+            .chain(self.listeners.iter().copied())
+            .collect()
+    }
+
+    /// Names of settings that differ from `other` but cannot change while running.
+    pub fn restart_required(&self, other: &ServerConfig) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.listen != other.listen {
+            changed.push("listen");
+        }
+        // The certificate is loaded once, when the listener starts
+        if self.tls_cert != other.tls_cert
+            || self.tls_key != other.tls_key
+            || self.tls_client_ca != other.tls_client_ca
+        {
+            changed.push("tls");
+        }
+        // Each listener takes its heartbeat when it is bound
+        if self.heartbeat_idle != other.heartbeat_idle
+            || self.length_heartbeat_idle != other.length_heartbeat_idle
+            || self.json_heartbeat_idle != other.json_heartbeat_idle
+            || self.heartbeat_timeout != other.heartbeat_timeout
+        {
+            changed.push("heartbeat");
+        }
+        if self.runtime != other.runtime {
+            changed.push("runtime");
+        }
+        if self.workers != other.workers {
+            changed.push("workers");
+        }
+        if self.metrics != other.metrics {
+            changed.push("metrics");
+        }
+        if self.http_addr != other.http_addr {
+            changed.push("http");
+        }
+        if self.grpc_addr != other.grpc_addr {
+            changed.push("grpc");
+        }
+        if self.h2_addr != other.h2_addr {
+            changed.push("h2");
+        }
+        if self.udp_addr != other.udp_addr {
+            changed.push("udp");
+        }
+        if self.admin_addr != other.admin_addr {
+            changed.push("admin");
+        }
+        if self.console != other.console {
+            changed.push("console");
+        }
+        if self.counter != other.counter {
+            changed.push("counter");
+        }
+        if self.state_file != other.state_file
+            || self.snapshot_interval != other.snapshot_interval
+            || self.durability != other.durability
+        {
+            changed.push("state_file");
+        }
+        if self.replica_of != other.replica_of {
+            changed.push("replica_of");
+        }
+        if self.stats_interval != other.stats_interval {
+            changed.push("stats_every_secs");
+        }
+        if self.runtime_stats_interval != other.runtime_stats_interval {
+            changed.push("runtime_stats_secs");
+        }
+        if self.hash_workers != other.hash_workers || self.hash_queue != other.hash_queue {
+            changed.push("hash_workers");
+        }
+        if self.watch_dir != other.watch_dir {
+            changed.push("watch_dir");
+        }
+        // The script is compiled once, at startup
+        if self.script != other.script {
+            changed.push("script");
+        }
+        // The queues are loaded once, at startup
+        if self.queue_dir != other.queue_dir || self.durable_topics != other.durable_topics {
+            changed.push("queue_dir");
+        }
+        // The session store is created once, with its grace period
+        if self.session_grace != other.session_grace {
+            changed.push("session_grace_secs");
+        }
+        // The log channel is created once, with its overflow policy
+        if self.log_overflow != other.log_overflow {
+            changed.push("log.overflow");
+        }
+        // The database is opened once, with the logger
+        if self.history_db != other.history_db {
+            changed.push("log.history_db");
+        }
+        // The upstreams, their health checks and breakers are set up once, at startup
+        if self.proxy != other.proxy
+            || self.proxy_health_interval != other.proxy_health_interval
+            || self.circuit != other.circuit
+        {
+            changed.push("proxy");
+        }
+        if self.health != other.health {
+            changed.push("health");
+        }
+        changed
+    }
+}
+
+/// The TOML file layout. Every key is optional.
+///
+/// ```toml
+/// listen = "127.0.0.1:7000"
+/// length_listen = "127.0.0.1:7001"
+/// json_listen = "127.0.0.1:7002"
+/// listeners = ["json=127.0.0.1:7003", "text=[::1]:7000"]
+/// runtime = "multi-thread"
+/// trace_dir = "traces"
+/// record_dir = "recordings"
+/// wire_debug_dir = "dumps"
+/// wire_debug_max_bytes = 1048576
+/// acl_file = "acl.txt"
+/// metrics = "prometheus:127.0.0.1:9100"
+/// http = "127.0.0.1:8080"
+/// connect_allow = ["example.com:443", "*.internal:*"]
+/// files_root = "public"
+/// ready_max_connections = 1000
+/// grpc = "127.0.0.1:50051"
+/// h2 = "127.0.0.1:8443"
+/// udp = "127.0.0.1:7004"
+/// admin = "unix:/tmp/tokio-examples.sock"
+/// console = true
+/// idle_timeout_secs = 300
+/// close_grace_ms = 2000
+/// heartbeat_secs = 30
+/// length_heartbeat_secs = 30
+/// json_heartbeat_secs = 30
+/// heartbeat_timeout_secs = 10
+/// bandwidth_bytes_per_sec = 65536
+/// max_message_bytes = 8192
+/// disconnect_oversized = false
+/// utf8 = "lossy"
+/// outbox_limit_bytes = 262144
+/// backpressure = "pause"
+/// max_in_flight = 1
+/// middleware = ["metrics", "auth", "rate-limit", "log"]
+/// script = "hooks.rhai"
+/// script_budget_ms = 50
+/// handshake = false
+/// gzip_threshold_bytes = 1024
+/// proxy = ["127.0.0.1:8000", "127.0.0.1:8001"]
+/// proxy_balance = "round-robin"
+/// proxy_health_secs = 5
+/// circuit_failures = 5
+/// circuit_cooldown_secs = 30
+/// socks_listen = "127.0.0.1:1080"
+/// socks_auth = "user:password"
+/// tls_listen = "127.0.0.1:7443"
+/// upload_dir = "uploads"
+/// max_upload_bytes = 16777216
+/// response = "OK #{n} from {peer}: {input}"
+/// counter = "mutex"
+/// state_file = "state.toml"
+/// snapshot_secs = 30
+/// durability = "async"
+/// replica_of = "127.0.0.1:7000"
+/// stats_every_secs = 60
+/// runtime_stats_secs = 10
+/// hash_workers = 2
+/// hash_queue = 32
+/// watch_dir = "incoming"
+/// queue_dir = "queues"
+/// durable_topics = ["jobs/build", "jobs/deploy"]
+/// peer_idle_secs = 600
+/// session_grace_secs = 60
+/// session_ttl_secs = 900
+///
+/// [limits]
+/// rate = 5
+/// burst = 10
+/// ip_rate = 20
+/// quota_rate = 50
+/// on_exceed = "reject"
+/// disconnect_after = 20
+///
+/// [auth]
+/// required = true
+/// # `tokio-examples hash-token <token>` prints the hash
+/// tokens = ["ci:read-only:2bb80d53...", "ops:admin:5e884898..."]
+///
+/// [log]
+/// level = "info"
+/// messages = true
+/// ttl_ms = 2000
+/// batch = 64
+/// flush_ms = 100
+/// overflow = "dead-letter:dead-letters.log"
+/// history_db = "history.db"
+///
+/// [socket]
+/// nodelay = true
+/// keepalive_secs = 60
+/// keepalive_interval_secs = 10
+/// send_buffer = 65536
+/// recv_buffer = 65536
+///
+/// [tls]
+/// cert = "cert.pem"
+/// key = "key.pem"
+/// client_ca = "ca.pem"
+/// identities = ["ops:admin", "dashboard:read-only"]
+/// hosts = ["kv.example.com mode=kv rate=50", "*.example.com banner=Welcome"]
+///
+/// [health]
+/// targets = ["tcp://127.0.0.1:5432", "http://127.0.0.1:8080/healthz"]
+/// interval_secs = 10
+/// timeout_ms = 2000
+/// failures = 3
+///
+/// [shedding]
+/// connections = 5000
+/// log_queue = 80
+/// runtime_queue = 1000
+/// policy = "reject"
+///
+/// # Chaos mode is on when this section is present
+/// [chaos]
+/// delay = 0.1
+/// max_delay_ms = 200
+/// short_reads = 0.2
+/// resets = 0.01
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    listen: Option<SocketAddr>,
+    length_listen: Option<SocketAddr>,
+    json_listen: Option<SocketAddr>,
+    listeners: Option<Vec<String>>,
+    runtime: Option<String>,
+    workers: Option<usize>,
+    trace_dir: Option<PathBuf>,
+    record_dir: Option<PathBuf>,
+    wire_debug_dir: Option<PathBuf>,
+    wire_debug_max_bytes: Option<u64>,
+    acl_file: Option<PathBuf>,
+    metrics: Option<String>,
+    http: Option<SocketAddr>,
+    connect_allow: Option<Vec<String>>,
+    files_root: Option<PathBuf>,
+    ready_max_connections: Option<usize>,
+    grpc: Option<SocketAddr>,
+    h2: Option<SocketAddr>,
+    udp: Option<SocketAddr>,
+    admin: Option<String>,
+    console: Option<bool>,
+    not_ready: Option<String>,
+    warmup_ms: Option<u64>,
+    idle_timeout_secs: Option<u64>,
+    close_grace_ms: Option<u64>,
+    heartbeat_secs: Option<u64>,
+    length_heartbeat_secs: Option<u64>,
+    json_heartbeat_secs: Option<u64>,
+    heartbeat_timeout_secs: Option<u64>,
+    bandwidth_bytes_per_sec: Option<u64>,
+    max_message_bytes: Option<usize>,
+    disconnect_oversized: Option<bool>,
+    utf8: Option<String>,
+    outbox_limit_bytes: Option<usize>,
+    backpressure: Option<String>,
+    max_in_flight: Option<usize>,
+    middleware: Option<Vec<String>>,
+    script: Option<PathBuf>,
+    script_budget_ms: Option<u64>,
+    handshake: Option<bool>,
+    gzip_threshold_bytes: Option<usize>,
+    proxy: Option<Vec<String>>,
+    proxy_balance: Option<String>,
+    proxy_health_secs: Option<u64>,
+    circuit_failures: Option<u32>,
+    circuit_cooldown_secs: Option<u64>,
+    socks_listen: Option<SocketAddr>,
+    socks_auth: Option<String>,
+    tls_listen: Option<SocketAddr>,
+    upload_dir: Option<PathBuf>,
+    max_upload_bytes: Option<u64>,
+    response: Option<String>,
+    counter: Option<String>,
+    state_file: Option<PathBuf>,
+    snapshot_secs: Option<u64>,
+    durability: Option<String>,
+    replica_of: Option<String>,
+    stats_every_secs: Option<u64>,
+    runtime_stats_secs: Option<u64>,
+    hash_workers: Option<usize>,
+    hash_queue: Option<usize>,
+    watch_dir: Option<PathBuf>,
+    queue_dir: Option<PathBuf>,
+    durable_topics: Option<Vec<String>>,
+    peer_idle_secs: Option<u64>,
+    session_grace_secs: Option<u64>,
+    session_ttl_secs: Option<u64>,
+    limits: LimitsSection,
+    auth: AuthSection,
+    log: LogSection,
+    socket: SocketSection,
+    tls: TlsSection,
+    health: HealthSection,
+    shedding: SheddingSection,
+    chaos: Option<ChaosSection>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LimitsSection {
+    rate: Option<f64>,
+    burst: Option<f64>,
+    ip_rate: Option<f64>,
+    ip_burst: Option<f64>,
+    quota_rate: Option<f64>,
+    quota_burst: Option<f64>,
+    on_exceed: Option<String>,
+    disconnect_after: Option<u32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct AuthSection {
+    required: Option<bool>,
+    tokens: Option<Vec<String>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LogSection {
+    level: Option<String>,
+    messages: Option<bool>,
+    ttl_ms: Option<u64>,
+    batch: Option<usize>,
+    flush_ms: Option<u64>,
+    overflow: Option<String>,
+    history_db: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct SocketSection {
+    nodelay: Option<bool>,
+    keepalive_secs: Option<u64>,
+    keepalive_interval_secs: Option<u64>,
+    send_buffer: Option<usize>,
+    recv_buffer: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct TlsSection {
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+    client_ca: Option<PathBuf>,
+    identities: Option<Vec<String>>,
+    hosts: Option<Vec<String>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct HealthSection {
+    targets: Option<Vec<String>>,
+    interval_secs: Option<u64>,
+    timeout_ms: Option<u64>,
+    failures: Option<u32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct SheddingSection {
+    connections: Option<usize>,
+    log_queue: Option<usize>,
+    runtime_queue: Option<usize>,
+    policy: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ChaosSection {
+    delay: Option<f64>,
+    max_delay_ms: Option<u64>,
+    short_reads: Option<f64>,
+    resets: Option<f64>,
+}
+
+impl FileConfig {
+    fn apply(self, config: &mut ServerConfig) -> Result<(), String> {
+        if let Some(listen) = self.listen {
+            config.listen = listen;
+        }
+        if self.length_listen.is_some() {
+            config.length_listen = self.length_listen;
+        }
+        if self.json_listen.is_some() {
+            config.json_listen = self.json_listen;
+        }
+        if let Some(listeners) = self.listeners {
+            config.listeners =
+                listeners.iter().map(|spec| spec.parse()).collect::<Result<_, _>>()?;
+        }
+        if let Some(runtime) = self.runtime {
+            config.runtime = parse_runtime(&runtime)?;
+        }
+        if let Some(workers) = self.workers {
+            config.workers = workers.max(1);
+        }
+        if self.trace_dir.is_some() {
+            config.trace_dir = self.trace_dir;
+        }
+        if self.record_dir.is_some() {
+            config.record_dir = self.record_dir;
+        }
+        if self.wire_debug_dir.is_some() {
+            config.wire_debug_dir = self.wire_debug_dir;
+        }
+        if let Some(bytes) = self.wire_debug_max_bytes {
+            config.wire_debug_max = bytes;
+        }
+        if self.acl_file.is_some() {
+            config.acl_file = self.acl_file;
+        }
+        if let Some(metrics) = self.metrics {
+            config.metrics = MetricsBackend::parse(&metrics)?;
+        }
+        if self.http.is_some() {
+            config.http_addr = self.http;
+        }
+        if let Some(allow) = self.connect_allow {
+            config.connect_allow =
+                allow.iter().map(|d| Destination::parse(d)).collect::<Result<_, _>>()?;
+        }
+        if self.files_root.is_some() {
+            config.files_root = self.files_root;
+        }
+        if self.ready_max_connections.is_some() {
+            config.ready_max_connections = self.ready_max_connections;
+        }
+        if self.grpc.is_some() {
+            config.grpc_addr = self.grpc;
+        }
+        if self.h2.is_some() {
+            config.h2_addr = self.h2;
+        }
+        if self.udp.is_some() {
+            config.udp_addr = self.udp;
+        }
+        if let Some(admin) = self.admin {
+            config.admin_addr = Some(AdminAddr::parse(&admin)?);
+        }
+        if let Some(console) = self.console {
+            config.console = console;
+        }
+        if let Some(policy) = self.not_ready {
+            config.not_ready = parse_not_ready(&policy)?;
+        }
+        if let Some(ms) = self.warmup_ms {
+            config.warmup_delay = Duration::from_millis(ms);
+        }
+        if let Some(secs) = self.idle_timeout_secs {
+            config.idle_timeout = (secs > 0).then(|| Duration::from_secs(secs));
+        }
+        if let Some(ms) = self.close_grace_ms {
+            config.close_grace = Duration::from_millis(ms);
+        }
+        if let Some(secs) = self.heartbeat_secs {
+            config.heartbeat_idle = (secs > 0).then(|| Duration::from_secs(secs));
+        }
+        if let Some(secs) = self.length_heartbeat_secs {
+            config.length_heartbeat_idle = (secs > 0).then(|| Duration::from_secs(secs));
+        }
+        if let Some(secs) = self.json_heartbeat_secs {
+            config.json_heartbeat_idle = (secs > 0).then(|| Duration::from_secs(secs));
+        }
+        if let Some(secs) = self.heartbeat_timeout_secs {
+            config.heartbeat_timeout = Duration::from_secs(secs.max(1));
+        }
+        if self.bandwidth_bytes_per_sec.is_some() {
+            config.bandwidth_limit = self.bandwidth_bytes_per_sec;
+        }
+        if let Some(bytes) = self.max_message_bytes {
+            config.max_message = bytes.max(1);
+        }
+        if let Some(disconnect) = self.disconnect_oversized {
+            config.disconnect_oversized = disconnect;
+        }
+        if let Some(mode) = self.utf8 {
+            config.utf8 = parse_utf8(&mode)?;
+        }
+        if let Some(bytes) = self.outbox_limit_bytes {
+            config.outbox_limit = bytes.max(1);
+        }
+        if let Some(policy) = self.backpressure {
+            config.backpressure = parse_backpressure(&policy)?;
+        }
+        if let Some(n) = self.max_in_flight {
+            config.max_in_flight = n.max(1);
+        }
+        if let Some(layers) = self.middleware {
+            config.middleware = parse_middleware(layers.iter().map(String::as_str))?;
+        }
+        if self.script.is_some() {
+            config.script = self.script;
+        }
+        if let Some(ms) = self.script_budget_ms {
+            config.script_budget = Duration::from_millis(ms.max(1));
+        }
+        if let Some(handshake) = self.handshake {
+            config.handshake = handshake;
+        }
+        if let Some(bytes) = self.gzip_threshold_bytes {
+            config.gzip_threshold = bytes;
+        }
+        if let Some(upstreams) = self.proxy {
+            config.proxy = upstreams;
+        }
+        if let Some(balance) = self.proxy_balance {
+            config.proxy_balance = parse_balance(&balance)?;
+        }
+        if let Some(secs) = self.proxy_health_secs {
+            config.proxy_health_interval = Duration::from_secs(secs.max(1));
+        }
+        if let Some(failures) = self.circuit_failures {
+            config.circuit.failures = failures.max(1);
+        }
+        if let Some(secs) = self.circuit_cooldown_secs {
+            config.circuit.cooldown = Duration::from_secs(secs);
+        }
+        if self.socks_listen.is_some() {
+            config.socks_listen = self.socks_listen;
+        }
+        if let Some(auth) = self.socks_auth {
+            config.socks_auth = Some(Credentials::parse(&auth)?);
+        }
+        if self.tls_listen.is_some() {
+            config.tls_listen = self.tls_listen;
+        }
+        if self.tls.cert.is_some() {
+            config.tls_cert = self.tls.cert;
+        }
+        if self.tls.key.is_some() {
+            config.tls_key = self.tls.key;
+        }
+        if self.tls.client_ca.is_some() {
+            config.tls_client_ca = self.tls.client_ca;
+        }
+        if let Some(identities) = self.tls.identities {
+            config.tls_identities =
+                identities.iter().map(|id| CertIdentity::parse(id)).collect::<Result<_, _>>()?;
+        }
+        if let Some(hosts) = self.tls.hosts {
+            config.tls_hosts =
+                hosts.iter().map(|host| VirtualHost::parse(host)).collect::<Result<_, _>>()?;
+        }
+        if let Some(targets) = self.health.targets {
+            config.health.targets =
+                targets.iter().map(|target| Target::parse(target)).collect::<Result<_, _>>()?;
+        }
+        if let Some(secs) = self.health.interval_secs {
+            config.health.interval = Duration::from_secs(secs);
+        }
+        if let Some(ms) = self.health.timeout_ms {
+            config.health.timeout = Duration::from_millis(ms);
+        }
+        if let Some(failures) = self.health.failures {
+            config.health.failures = failures.max(1);
+        }
+        if self.shedding.connections.is_some() {
+            config.shedding.connections = self.shedding.connections;
+        }
+        if self.shedding.log_queue.is_some() {
+            config.shedding.log_queue = self.shedding.log_queue;
+        }
+        if self.shedding.runtime_queue.is_some() {
+            config.shedding.runtime_queue = self.shedding.runtime_queue;
+        }
+        if let Some(policy) = self.shedding.policy {
+            config.shed_policy = shedding::parse_policy(&policy)?;
+        }
+        if let Some(dir) = self.upload_dir {
+            config.upload_dir = dir;
+        }
+        if let Some(bytes) = self.max_upload_bytes {
+            config.max_upload = bytes;
+        }
+        if let Some(template) = self.response {
+            config.response = Arc::new(compile_response(&template)?);
+        }
+        if let Some(counter) = self.counter {
+            config.counter = CounterKind::parse(&counter)?;
+        }
+        if self.state_file.is_some() {
+            config.state_file = self.state_file;
+        }
+        if let Some(secs) = self.snapshot_secs {
+            config.snapshot_interval = Duration::from_secs(secs.max(1));
+        }
+        if let Some(durability) = self.durability {
+            config.durability = Durability::parse(&durability)?;
+        }
+        if self.replica_of.is_some() {
+            config.replica_of = self.replica_of;
+        }
+        if let Some(secs) = self.stats_every_secs {
+            config.stats_interval = (secs > 0).then(|| Duration::from_secs(secs));
+        }
+        if let Some(secs) = self.runtime_stats_secs {
+            config.runtime_stats_interval = (secs > 0).then(|| Duration::from_secs(secs));
+        }
+        if let Some(workers) = self.hash_workers {
+            config.hash_workers = workers.max(1);
+        }
+        if let Some(queue) = self.hash_queue {
+            config.hash_queue = queue.max(1);
+        }
+        if self.watch_dir.is_some() {
+            config.watch_dir = self.watch_dir;
+        }
+        if self.queue_dir.is_some() {
+            config.queue_dir = self.queue_dir;
+        }
+        if let Some(topics) = self.durable_topics {
+            config.durable_topics = topics;
+        }
+        if let Some(secs) = self.peer_idle_secs {
+            config.peer_idle = Duration::from_secs(secs.max(1));
+        }
+        if let Some(secs) = self.session_grace_secs {
+            config.session_grace = Duration::from_secs(secs.max(1));
+        }
+        if let Some(secs) = self.session_ttl_secs {
+            config.session_ttl = (secs > 0).then(|| Duration::from_secs(secs));
+        }
+        // A rate of zero or less would make the bucket's wait endless
+        let rates = [
+            ("rate", self.limits.rate),
+            ("ip_rate", self.limits.ip_rate),
+            ("quota_rate", self.limits.quota_rate),
+        ];
+        for (name, rate) in rates {
+            if let Some(rate) = rate {
+                positive(&format!("{} in [limits]", name), rate)?;
+            }
+        }
+        let bursts = [
+            ("burst", self.limits.burst),
+            ("ip_burst", self.limits.ip_burst),
+            ("quota_burst", self.limits.quota_burst),
+        ];
+        for (name, burst) in bursts {
+            if let Some(burst) = burst {
+                Limit::check_burst(&format!("{} in [limits]", name), burst)?;
+            }
+        }
+        config.conn_limit = self.limits.rate.map(|rate| Limit::new(rate, self.limits.burst));
+        config.ip_limit = self.limits.ip_rate.map(|rate| Limit::new(rate, self.limits.ip_burst));
+        let quota_burst = self.limits.quota_burst;
+        config.quota = self.limits.quota_rate.map(|rate| Limit::new(rate, quota_burst));
+        if let Some(on_exceed) = self.limits.on_exceed {
+            config.on_limit = parse_on_exceed(&on_exceed)?;
+        }
+        if let Some(n) = self.limits.disconnect_after {
+            config.disconnect_after = (n > 0).then_some(n);
+        }
+        if let Some(required) = self.auth.required {
+            config.require_auth = required;
+        }
+        if let Some(tokens) = self.auth.tokens {
+            config.auth_tokens =
+                tokens.iter().map(|token| AuthToken::parse(token)).collect::<Result<_, _>>()?;
+        }
+        if let Some(level) = self.log.level {
+            config.log_level = parse_log_level(&level)?;
+        }
+        if let Some(messages) = self.log.messages {
+            config.log_messages = messages;
+        }
+        if let Some(ms) = self.log.ttl_ms {
+            config.log_ttl = (ms > 0).then(|| Duration::from_millis(ms));
+        }
+        if let Some(batch) = self.log.batch {
+            config.log_batch = batch.max(1);
+        }
+        if let Some(ms) = self.log.flush_ms {
+            config.log_flush = Duration::from_millis(ms.max(1));
+        }
+        if let Some(overflow) = self.log.overflow {
+            config.log_overflow = LogOverflow::parse(&overflow)?;
+        }
+        if self.log.history_db.is_some() {
+            config.history_db = self.log.history_db;
+        }
+        if let Some(nodelay) = self.socket.nodelay {
+            config.socket.nodelay = nodelay;
+        }
+        if let Some(secs) = self.socket.keepalive_secs {
+            config.socket.keepalive = (secs > 0).then(|| Duration::from_secs(secs));
+        }
+        if let Some(secs) = self.socket.keepalive_interval_secs {
+            config.socket.keepalive_interval = (secs > 0).then(|| Duration::from_secs(secs));
+        }
+        if self.socket.send_buffer.is_some() {
+            config.socket.send_buffer = self.socket.send_buffer;
+        }
+        if self.socket.recv_buffer.is_some() {
+            config.socket.recv_buffer = self.socket.recv_buffer;
+        }
+        if let Some(section) = self.chaos {
+            let chaos = config.chaos.get_or_insert_default();
+            if let Some(p) = section.delay {
+                chaos.delay = probability("chaos.delay", p)?;
+            }
+            if let Some(ms) = section.max_delay_ms {
+                chaos.max_delay = Duration::from_millis(ms);
+            }
+            if let Some(p) = section.short_reads {
+                chaos.short_read = probability("chaos.short_reads", p)?;
+            }
+            if let Some(p) = section.resets {
+                chaos.reset = probability("chaos.resets", p)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parses the process arguments.
+///
+/// The parser is intentionally hand-written: the example only has a
+/// handful of flags and does not need a full CLI framework.
+pub fn parse_args() -> Result<Command, String> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    if args.first().map(String::as_str) == Some("trace-dump") {
+        let files: Vec<PathBuf> = args[1..].iter().map(PathBuf::from).collect();
+        if files.is_empty() {
+            return Err("trace-dump expects at least one trace file".to_string());
+        }
+        return Ok(Command::TraceDump(files));
+    }
+    if args.first().map(String::as_str) == Some("doctor") {
+        return Ok(Command::Doctor(args[1..].to_vec()));
+    }
+    if args.first().map(String::as_str) == Some("hash-token") {
+        return match &args[1..] {
+            [token] => Ok(Command::HashToken(token.clone())),
+            _ => Err("usage: hash-token <token>".to_string()),
+        };
+    }
+
+    // Runs before the runtime exists, so the file is read synchronously
+    let file_text = match config_file_arg(&args)? {
+        Some(path) => Some(
+            std::fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?,
+        ),
+        None => None,
+    };
+
+    Ok(Command::Serve(Box::new(build(&args, file_text.as_deref())?)))
+}
+
+/// Reads the configuration again: the same file and the same flags.
+pub async fn reload() -> Result<ServerConfig, String> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    load(&args).await
+}
+
+/// The configuration `args` describe, with the file they name, if any.
+pub async fn load(args: &[String]) -> Result<ServerConfig, String> {
+    let file_text = match config_file_arg(args)? {
+        Some(path) => Some(
+            tokio::fs::read_to_string(&path)
+                .await
+                .map_err(|e| format!("{}: {}", path.display(), e))?,
+        ),
+        None => None,
+    };
+    build(args, file_text.as_deref())
+}
+
+fn config_file_arg(args: &[String]) -> Result<Option<PathBuf>, String> {
+    match args.iter().position(|a| a == "--config") {
+        Some(i) => args
+            .get(i + 1)
+            .map(|p| Some(PathBuf::from(p)))
+            .ok_or_else(|| "--config expects a file".to_string()),
+        None => Ok(None),
+    }
+}
+
+/// Defaults, then the file, then the flags.
+fn build(args: &[String], file_text: Option<&str>) -> Result<ServerConfig, String> {
+    let mut config = ServerConfig::default();
+
+    if let Some(text) = file_text {
+        let file: FileConfig = toml::from_str(text).map_err(|e| format!("config file: {}", e))?;
+        file.apply(&mut config)?;
+    }
+
+    let mut args = args.iter().cloned();
+    let (mut rate, mut burst, mut ip_rate, mut ip_burst) = (None, None, None, None);
+    let (mut quota_rate, mut quota_burst) = (None, None);
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} expects a value", arg));
+        match arg.as_str() {
+            // Already read by `config_file_arg`
+            "--config" => {
+                value()?;
+            }
+            "--listen" => {
+                let addr = value()?;
+                config.listen = addr
+                    .parse()
+                    .map_err(|_| format!("invalid --listen address: {}", addr))?;
+            }
+            "--length-listen" => {
+                let addr = value()?;
+                let addr = addr
+                    .parse()
+                    .map_err(|_| format!("invalid --length-listen address: {}", addr))?;
+                config.length_listen = Some(addr);
+            }
+            "--json-listen" => {
+                let addr = value()?;
+                let addr = addr
+                    .parse()
+                    .map_err(|_| format!("invalid --json-listen address: {}", addr))?;
+                config.json_listen = Some(addr);
+            }
+            "--listener" => config.listeners.push(value()?.parse()?),
+            "--trace-dir" => config.trace_dir = Some(PathBuf::from(value()?)),
+            "--record" => config.record_dir = Some(PathBuf::from(value()?)),
+            "--wire-debug" => config.wire_debug_dir = Some(PathBuf::from(value()?)),
+            "--wire-debug-max-bytes" => {
+                config.wire_debug_max = number(&arg, &value()?)? as u64;
+            }
+            "--acl" => config.acl_file = Some(PathBuf::from(value()?)),
+            "--metrics" => config.metrics = MetricsBackend::parse(&value()?)?,
+            "--http" => {
+                let addr = value()?;
+                let addr = addr
+                    .parse()
+                    .map_err(|_| format!("invalid --http address: {}", addr))?;
+                config.http_addr = Some(addr);
+            }
+            "--connect-allow" => config.connect_allow.push(Destination::parse(&value()?)?),
+            "--files-root" => config.files_root = Some(PathBuf::from(value()?)),
+            "--ready-max-connections" => {
+                config.ready_max_connections = Some(number(&arg, &value()?)? as usize);
+            }
+            "--grpc" => {
+                let addr = value()?;
+                let addr = addr
+                    .parse()
+                    .map_err(|_| format!("invalid --grpc address: {}", addr))?;
+                config.grpc_addr = Some(addr);
+            }
+            "--h2" => {
+                let addr = value()?;
+                let addr = addr
+                    .parse()
+                    .map_err(|_| format!("invalid --h2 address: {}", addr))?;
+                config.h2_addr = Some(addr);
+            }
+            "--udp" => {
+                let addr = value()?;
+                let addr = addr
+                    .parse()
+                    .map_err(|_| format!("invalid --udp address: {}", addr))?;
+                config.udp_addr = Some(addr);
+            }
+            "--admin" => config.admin_addr = Some(AdminAddr::parse(&value()?)?),
+            "--no-console" => config.console = false,
+            "--not-ready" => config.not_ready = parse_not_ready(&value()?)?,
+            "--warmup-ms" => {
+                let ms = number(&arg, &value()?)?;
+                config.warmup_delay = Duration::from_millis(ms as u64);
+            }
+            "--runtime" => config.runtime = parse_runtime(&value()?)?,
+            "--workers" => config.workers = number(&arg, &value()?)? as usize,
+            "--idle-timeout-secs" => {
+                let secs = number(&arg, &value()?)?;
+                config.idle_timeout = Some(Duration::from_secs_f64(secs));
+            }
+            "--close-grace-ms" => {
+                config.close_grace = Duration::from_millis(number(&arg, &value()?)? as u64);
+            }
+            "--heartbeat-secs" => {
+                let secs = number(&arg, &value()?)?;
+                config.heartbeat_idle = Some(Duration::from_secs_f64(secs));
+            }
+            "--length-heartbeat-secs" => {
+                let secs = number(&arg, &value()?)?;
+                config.length_heartbeat_idle = Some(Duration::from_secs_f64(secs));
+            }
+            "--json-heartbeat-secs" => {
+                let secs = number(&arg, &value()?)?;
+                config.json_heartbeat_idle = Some(Duration::from_secs_f64(secs));
+            }
+            "--heartbeat-timeout-secs" => {
+                let secs = number(&arg, &value()?)?;
+                config.heartbeat_timeout = Duration::from_secs_f64(secs);
+            }
+            "--bandwidth-bytes-per-sec" => {
+                config.bandwidth_limit = Some(number(&arg, &value()?)? as u64);
+            }
+            // `--chaos` alone uses the defaults; any `--chaos-*` flag turns it on too
+            "--chaos" => _ = config.chaos.get_or_insert_default(),
+            "--chaos-delay" => {
+                let p = value()?.parse().unwrap_or(f64::NAN);
+                config.chaos.get_or_insert_default().delay = probability(&arg, p)?;
+            }
+            "--chaos-max-delay-ms" => {
+                let ms = number(&arg, &value()?)?;
+                config.chaos.get_or_insert_default().max_delay = Duration::from_millis(ms as u64);
+            }
+            "--chaos-short-reads" => {
+                let p = value()?.parse().unwrap_or(f64::NAN);
+                config.chaos.get_or_insert_default().short_read = probability(&arg, p)?;
+            }
+            "--chaos-resets" => {
+                let p = value()?.parse().unwrap_or(f64::NAN);
+                config.chaos.get_or_insert_default().reset = probability(&arg, p)?;
+            }
+            "--max-message-bytes" => config.max_message = number(&arg, &value()?)? as usize,
+            "--disconnect-oversized" => config.disconnect_oversized = true,
+            "--utf8" => config.utf8 = parse_utf8(&value()?)?,
+            "--outbox-limit-bytes" => config.outbox_limit = number(&arg, &value()?)? as usize,
+            "--backpressure" => config.backpressure = parse_backpressure(&value()?)?,
+            "--max-in-flight" => config.max_in_flight = (number(&arg, &value()?)? as usize).max(1),
+            // A comma-separated list, in order
+            "--middleware" => config.middleware = parse_middleware(value()?.split(','))?,
+            "--script" => config.script = Some(PathBuf::from(value()?)),
+            "--script-budget-ms" => {
+                let ms = number(&arg, &value()?)?;
+                config.script_budget = Duration::from_millis((ms as u64).max(1));
+            }
+            "--require-auth" => config.require_auth = true,
+            // Repeated, one token each
+            "--auth-token" => config.auth_tokens.push(AuthToken::parse(&value()?)?),
+            "--handshake" => config.handshake = true,
+            "--gzip-threshold-bytes" => config.gzip_threshold = number(&arg, &value()?)? as usize,
+            // Repeated, or a comma-separated list
+            "--proxy" => config.proxy.extend(value()?.split(',').map(str::to_string)),
+            "--proxy-balance" => config.proxy_balance = parse_balance(&value()?)?,
+            "--proxy-health-secs" => {
+                let secs = number(&arg, &value()?)?;
+                config.proxy_health_interval = Duration::from_secs_f64(secs);
+            }
+            "--circuit-failures" => config.circuit.failures = number(&arg, &value()?)? as u32,
+            "--circuit-cooldown-secs" => {
+                let secs = number(&arg, &value()?)?;
+                config.circuit.cooldown = Duration::from_secs_f64(secs);
+            }
+            // Repeated, or a comma-separated list
+            "--health-check" => {
+                for target in value()?.split(',') {
+                    config.health.targets.push(Target::parse(target)?);
+                }
+            }
+            "--health-interval-secs" => {
+                let secs = number(&arg, &value()?)?;
+                config.health.interval = Duration::from_secs_f64(secs);
+            }
+            "--health-timeout-ms" => {
+                let ms = number(&arg, &value()?)?;
+                config.health.timeout = Duration::from_secs_f64(ms / 1000.0);
+            }
+            "--health-failures" => {
+                config.health.failures = (number(&arg, &value()?)? as u32).max(1);
+            }
+            "--shed-connections" => {
+                config.shedding.connections = Some(number(&arg, &value()?)? as usize);
+            }
+            "--shed-log-queue" => {
+                config.shedding.log_queue = Some(number(&arg, &value()?)? as usize);
+            }
+            "--shed-runtime-queue" => {
+                config.shedding.runtime_queue = Some(number(&arg, &value()?)? as usize);
+            }
+            "--shed-policy" => config.shed_policy = shedding::parse_policy(&value()?)?,
+            "--socks-listen" => {
+                let addr = value()?;
+                let addr = addr
+                    .parse()
+                    .map_err(|_| format!("invalid --socks-listen address: {}", addr))?;
+                config.socks_listen = Some(addr);
+            }
+            "--socks-auth" => config.socks_auth = Some(Credentials::parse(&value()?)?),
+            "--tls-listen" => {
+                let addr = value()?;
+                let addr = addr
+                    .parse()
+                    .map_err(|_| format!("invalid --tls-listen address: {}", addr))?;
+                config.tls_listen = Some(addr);
+            }
+            "--tls-cert" => config.tls_cert = Some(PathBuf::from(value()?)),
+            "--tls-key" => config.tls_key = Some(PathBuf::from(value()?)),
+            "--tls-client-ca" => config.tls_client_ca = Some(PathBuf::from(value()?)),
+            // Repeated, one identity each
+            "--tls-identity" => config.tls_identities.push(CertIdentity::parse(&value()?)?),
+            // Repeated, one virtual host each
+            "--tls-host" => config.tls_hosts.push(VirtualHost::parse(&value()?)?),
+            "--upload-dir" => config.upload_dir = PathBuf::from(value()?),
+            "--max-upload-bytes" => config.max_upload = number(&arg, &value()?)? as u64,
+            "--response" => config.response = Arc::new(compile_response(&value()?)?),
+            "--counter" => config.counter = CounterKind::parse(&value()?)?,
+            "--state-file" => config.state_file = Some(PathBuf::from(value()?)),
+            "--snapshot-secs" => {
+                let secs = number(&arg, &value()?)?;
+                config.snapshot_interval = Duration::from_secs_f64(secs);
+            }
+            "--durability" => config.durability = Durability::parse(&value()?)?,
+            "--replica-of" => config.replica_of = Some(value()?),
+            "--stats-every-secs" => {
+                let secs = number(&arg, &value()?)?;
+                config.stats_interval = Some(Duration::from_secs_f64(secs));
+            }
+            "--runtime-stats-secs" => {
+                let secs = number(&arg, &value()?)?;
+                config.runtime_stats_interval = Some(Duration::from_secs_f64(secs));
+            }
+            "--hash-workers" => config.hash_workers = number(&arg, &value()?)? as usize,
+            "--hash-queue" => config.hash_queue = number(&arg, &value()?)? as usize,
+            "--watch-dir" => config.watch_dir = Some(PathBuf::from(value()?)),
+            "--queue-dir" => config.queue_dir = Some(PathBuf::from(value()?)),
+            "--durable-topic" => config.durable_topics.push(value()?),
+            "--peer-idle-secs" => {
+                let secs = number(&arg, &value()?)?;
+                config.peer_idle = Duration::from_secs_f64(secs.max(1.0));
+            }
+            "--session-grace-secs" => {
+                let secs = number(&arg, &value()?)?;
+                config.session_grace = Duration::from_secs_f64(secs);
+            }
+            "--session-ttl-secs" => {
+                let secs = number(&arg, &value()?)?;
+                config.session_ttl = (secs > 0.0).then(|| Duration::from_secs_f64(secs));
+            }
+            "--nodelay" => config.socket.nodelay = true,
+            "--keepalive-secs" => {
+                let secs = number(&arg, &value()?)?;
+                config.socket.keepalive = Some(Duration::from_secs_f64(secs));
+            }
+            "--keepalive-interval-secs" => {
+                let secs = number(&arg, &value()?)?;
+                config.socket.keepalive_interval = Some(Duration::from_secs_f64(secs));
+            }
+            "--send-buffer" => config.socket.send_buffer = Some(number(&arg, &value()?)? as usize),
+            "--recv-buffer" => config.socket.recv_buffer = Some(number(&arg, &value()?)? as usize),
+            "--log-level" => config.log_level = parse_log_level(&value()?)?,
+            "--no-log-messages" => config.log_messages = false,
+            "--log-ttl-ms" => {
+                let ms = number(&arg, &value()?)?;
+                config.log_ttl = Some(Duration::from_millis(ms as u64));
+            }
+            "--log-batch" => config.log_batch = (number(&arg, &value()?)? as usize).max(1),
+            "--log-flush-ms" => {
+                let ms = number(&arg, &value()?)?;
+                // A zero period would make the logger's interval panic
+                config.log_flush = Duration::from_millis((ms as u64).max(1));
+            }
+            "--log-overflow" => config.log_overflow = LogOverflow::parse(&value()?)?,
+            "--history-db" => config.history_db = Some(PathBuf::from(value()?)),
+            "--rate" => rate = Some(number(&arg, &value()?)?),
+            "--burst" => burst = Some(Limit::check_burst(&arg, number(&arg, &value()?)?)?),
+            "--ip-rate" => ip_rate = Some(number(&arg, &value()?)?),
+            "--ip-burst" => {
+                ip_burst = Some(Limit::check_burst(&arg, number(&arg, &value()?)?)?);
+            }
+            "--quota-rate" => quota_rate = Some(number(&arg, &value()?)?),
+            "--quota-burst" => {
+                quota_burst = Some(Limit::check_burst(&arg, number(&arg, &value()?)?)?);
+            }
+            "--on-limit" => config.on_limit = parse_on_exceed(&value()?)?,
+            "--disconnect-after" => {
+                config.disconnect_after = Some(number(&arg, &value()?)? as u32);
+            }
+            other => return Err(format!("unknown argument: {}", other)),
+        }
+    }
+
+    // A flag overrides the matching part of a limit from the file
+    config.conn_limit = override_limit(config.conn_limit, rate, burst);
+    config.ip_limit = override_limit(config.ip_limit, ip_rate, ip_burst);
+    config.quota = override_limit(config.quota, quota_rate, quota_burst);
+
+    if config.require_auth && config.auth_tokens.is_empty() && config.tls_identities.is_empty() {
+        let text = "--require-auth needs at least one token (--auth-token) or certificate identity";
+        return Err(text.to_string());
+    }
+    // A zero interval would probe the targets in a busy loop
+    if !config.health.targets.is_empty() && config.health.interval.is_zero() {
+        return Err("the health check interval must be above 0".to_string());
+    }
+    // Without a CA, no client ever presents a certificate
+    if !config.tls_identities.is_empty() && config.tls_client_ca.is_none() {
+        return Err("--tls-identity needs --tls-client-ca".to_string());
+    }
+    for topic in &config.durable_topics {
+        pubsub::check_topic(topic).map_err(|e| format!("durable topic {}: {}", topic, e))?;
+    }
+    if !config.durable_topics.is_empty() && config.queue_dir.is_none() {
+        return Err("--durable-topic needs --queue-dir".to_string());
+    }
+    // Unless the chain places it elsewhere, the script sees what was let through
+    match (&config.script, config.middleware.contains(&Layer::Script)) {
+        (Some(_), false) => config.middleware.push(Layer::Script),
+        (None, true) => return Err("middleware `script` needs --script".to_string()),
+        _ => {}
+    }
+    Ok(config)
+}
+
+fn override_limit(limit: Option<Limit>, rate: Option<f64>, burst: Option<f64>) -> Option<Limit> {
+    match (limit, rate) {
+        (_, Some(rate)) => Some(Limit::new(rate, burst)),
+        (Some(limit), None) => Some(Limit::new(limit.rate, burst.or(Some(limit.burst)))),
+        (None, None) => None,
+    }
+}
+
+/// Parses a numeric value; rates, bursts and durations must be positive.
+fn number(flag: &str, value: &str) -> Result<f64, String> {
+    positive(flag, value.parse::<f64>().unwrap_or(f64::NAN))
+}
+
+fn positive(flag: &str, value: f64) -> Result<f64, String> {
+    // NaN fails the comparison too
+    (value > 0.0).then_some(value).ok_or_else(|| format!("{} expects a positive number", flag))
+}
+
+fn probability(flag: &str, p: f64) -> Result<f64, String> {
+    // NaN, what an unparsable flag becomes, is not in the range either
+    (0.0..=1.0)
+        .contains(&p)
+        .then_some(p)
+        .ok_or_else(|| format!("{} expects a probability between 0 and 1", flag))
+}
+
+fn parse_runtime(value: &str) -> Result<RuntimeMode, String> {
+    match value {
+        "multi-thread" => Ok(RuntimeMode::MultiThread),
+        "current-thread" => Ok(RuntimeMode::CurrentThread),
+        "thread-per-core" => Ok(RuntimeMode::ThreadPerCore),
+        "multi-acceptor" => Ok(RuntimeMode::MultiAcceptor),
+        "local" => Ok(RuntimeMode::Local),
+        "uring" => Ok(RuntimeMode::Uring),
+        _ => Err("runtime must be `multi-thread`, `current-thread`, `thread-per-core`, \
+                  `multi-acceptor`, `local` or `uring`"
+            .to_string()),
+    }
+}
+
+fn parse_balance(value: &str) -> Result<Balance, String> {
+    match value {
+        "round-robin" => Ok(Balance::RoundRobin),
+        "least-connections" => Ok(Balance::LeastConnections),
+        _ => Err("proxy balance must be `round-robin` or `least-connections`".to_string()),
+    }
+}
+
+fn parse_not_ready(value: &str) -> Result<NotReadyPolicy, String> {
+    match value {
+        "queue" => Ok(NotReadyPolicy::Queue),
+        "reject" => Ok(NotReadyPolicy::Reject),
+        _ => Err("not-ready policy must be `queue` or `reject`".to_string()),
+    }
+}
+
+fn parse_utf8(value: &str) -> Result<Utf8Mode, String> {
+    match value {
+        "lossy" => Ok(Utf8Mode::Lossy),
+        "strict" => Ok(Utf8Mode::Strict),
+        "strict-close" => Ok(Utf8Mode::StrictClose),
+        "binary" => Ok(Utf8Mode::Binary),
+        _ => Err("utf8 must be `lossy`, `strict`, `strict-close` or `binary`".to_string()),
+    }
+}
+
+fn parse_backpressure(value: &str) -> Result<Backpressure, String> {
+    match value {
+        "pause" => Ok(Backpressure::Pause),
+        "drop-oldest" => Ok(Backpressure::DropOldest),
+        "disconnect" => Ok(Backpressure::Disconnect),
+        _ => Err("backpressure must be `pause`, `drop-oldest` or `disconnect`".to_string()),
+    }
+}
+
+fn parse_middleware<'a>(names: impl Iterator<Item = &'a str>) -> Result<Vec<Layer>, String> {
+    let mut layers = Vec::new();
+    for name in names {
+        let layer = match name.trim() {
+            "metrics" => Layer::Metrics,
+            "auth" => Layer::Auth,
+            "rate-limit" => Layer::RateLimit,
+            "log" => Layer::Log,
+            "script" => Layer::Script,
+            "" => continue,
+            other => {
+                return Err(format!(
+                    "unknown middleware `{}`, expected `metrics`, `auth`, `rate-limit`, `log` \
+                     or `script`",
+                    other,
+                ));
+            }
+        };
+        if layers.contains(&layer) {
+            return Err(format!("middleware `{}` is listed twice", layer));
+        }
+        layers.push(layer);
+    }
+    Ok(layers)
+}
+
+fn parse_on_exceed(value: &str) -> Result<OnExceed, String> {
+    match value {
+        "delay" => Ok(OnExceed::Delay),
+        "reject" => Ok(OnExceed::Reject),
+        _ => Err("rate limit policy must be `delay` or `reject`".to_string()),
+    }
+}
+
+fn compile_response(template: &str) -> Result<ResponseTemplate, String> {
+    ResponseTemplate::compile(template).map_err(|e| format!("response template: {}", e))
+}
+
+fn parse_log_level(value: &str) -> Result<LogLevel, String> {
+    match value {
+        "info" => Ok(LogLevel::Info),
+        "error" => Ok(LogLevel::Error),
+        _ => Err("log level must be `info` or `error`".to_string()),
+    }
+}
+
+/// Everything a reload publishes to.
+///
+/// Cloned into whatever can trigger a reload: the signal dispatcher
+/// (SIGHUP) and the admin socket (`RELOAD`).
+#[derive(Clone)]
+pub struct Reloader {
+    pub config_tx: watch::Sender<Arc<ServerConfig>>,
+    pub acl_tx: watch::Sender<AccessList>,
+    pub phase: watch::Receiver<Phase>,
+}
+
+impl Reloader {
+    /// Reloads the configuration and the access list.
+    ///
+    /// A file that fails to load is reported and the previous settings stay
+    /// active; the error is also returned for callers that answer someone.
+    pub async fn reload(&self) -> Result<(), String> {
+        // The initial load happens during warm-up; a reload must not race with it
+        if matches!(*self.phase.borrow(), Phase::Starting | Phase::Warming) {
+            println!("[CONFIG] still warming up, reload skipped");
+            return Err("server is still warming up".to_string());
+        }
+        reload_all(&self.config_tx, &self.acl_tx).await
+    }
+}
+
+async fn reload_all(
+    config_tx: &watch::Sender<Arc<ServerConfig>>,
+    acl_tx: &watch::Sender<AccessList>,
+) -> Result<(), String> {
+    let new = match reload().await {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("[CONFIG] reload failed, keeping previous settings: {}", e);
+            return Err(e);
+        }
+    };
+
+    let changed = config_tx.borrow().restart_required(&new);
+    if !changed.is_empty() {
+        eprintln!("[CONFIG] changes to {} only apply after a restart", changed.join(", "));
+    }
+
+    if let Some(dir) = &new.trace_dir
+        && let Err(e) = tokio::fs::create_dir_all(dir).await
+    {
+        eprintln!("[CONFIG] cannot create trace directory {}: {}", dir.display(), e);
+    }
+    if let Some(dir) = &new.record_dir
+        && let Err(e) = tokio::fs::create_dir_all(dir).await
+    {
+        eprintln!("[CONFIG] cannot create recording directory {}: {}", dir.display(), e);
+    }
+    if let Some(dir) = &new.wire_debug_dir
+        && let Err(e) = tokio::fs::create_dir_all(dir).await
+    {
+        eprintln!("[CONFIG] cannot create wire dump directory {}: {}", dir.display(), e);
+    }
+
+    if let Some(path) = &new.acl_file {
+        reload_acl(path, acl_tx).await;
+    }
+
+    // Subscribers see the new settings on their next `borrow()`
+    config_tx.send_replace(Arc::new(new));
+    println!("[CONFIG] reloaded");
+    Ok(())
+}
+
+async fn reload_acl(path: &Path, acl_tx: &watch::Sender<AccessList>) {
+    match AccessList::load(path).await {
+        Ok(list) => {
+            println!("[ACL] reloaded: {}", list.summary());
+            // New connections see the new list immediately
+            acl_tx.send_replace(list);
+        }
+        Err(e) => eprintln!("[ACL] reload failed, keeping previous list: {}", e),
+    }
+}
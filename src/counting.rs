@@ -0,0 +1,177 @@
+//! `CountingStream`: counts the bytes read from and written to a stream.
+//!
+//! Every connection is served through one, so `bytes_read` and
+//! `bytes_written` in `STATS` cover all traffic (commands, uploads,
+//! downloads, proxied bytes) without each code path counting for itself,
+//! and the close log line can say how much one connection moved. With
+//! `--wire-debug` it also hands the same bytes to a `WireDump`.
+//!
+//! The wrapper also shows pin projection done by hand. Its `poll_*`
+//! methods get `Pin<&mut Self>` but must call the inner stream's methods,
+//! which want `Pin<&mut T>`. For an `Unpin` stream that is free, but this
+//! wrapper accepts any stream, so it projects: the pin of the wrapper is
+//! passed on to `inner` (the field is "structurally pinned"), while the
+//! counters are plain `&mut`. That is what crates like `pin-project`
+//! generate; written out, it needs `unsafe` and the promises noted there.
+
+use crate::metrics::{self, Metrics};
+use crate::transport::Transport;
+use crate::wiredump::WireDump;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+pub struct CountingStream<T> {
+    inner: T,
+    metrics: Metrics,
+    read: u64,
+    written: u64,
+    dump: Option<WireDump>,
+}
+
+/// The fields of a pinned `CountingStream`, as `project` hands them out.
+struct Projection<'a, T> {
+    inner: Pin<&'a mut T>,
+    metrics: &'a Metrics,
+    read: &'a mut u64,
+    written: &'a mut u64,
+    dump: &'a Option<WireDump>,
+}
+
+impl<T> CountingStream<T> {
+    pub fn new(inner: T, metrics: Metrics) -> Self {
+        Self { inner, metrics, read: 0, written: 0, dump: None }
+    }
+
+    /// Also dumps every byte read and written, when `dump` is set.
+    pub fn with_dump(mut self, dump: Option<WireDump>) -> Self {
+        self.dump = dump;
+        self
+    }
+
+    pub fn bytes_read(&self) -> u64 {
+        self.read
+    }
+
+    pub fn bytes_written(&self) -> u64 {
+        self.written
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    fn project(self: Pin<&mut Self>) -> Projection<'_, T> {
+        // SAFETY: `inner` is never moved while the wrapper is pinned: there
+        // is no method taking `Pin<&mut Self>` that hands out `&mut T`, and no
+        // `Drop` impl that could move it. The counters are never pinned, so
+        // handing them out as `&mut` is fine, and `CountingStream` is only
+        // `Unpin` when `T` is (the auto trait follows the fields).
+        unsafe {
+            let this = self.get_unchecked_mut();
+            Projection {
+                inner: Pin::new_unchecked(&mut this.inner),
+                metrics: &this.metrics,
+                read: &mut this.read,
+                written: &mut this.written,
+                dump: &this.dump,
+            }
+        }
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for CountingStream<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let before = buf.filled().len();
+        ready!(this.inner.poll_read(cx, buf))?;
+        let read = &buf.filled()[before..];
+        if let Some(dump) = this.dump {
+            dump.read(read);
+        }
+        let n = read.len() as u64;
+        *this.read += n;
+        this.metrics.incr(metrics::BYTES_READ, n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncWrite> AsyncWrite for CountingStream<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let n = ready!(this.inner.poll_write(cx, buf))?;
+        if let Some(dump) = this.dump {
+            dump.written(&buf[..n]);
+        }
+        *this.written += n as u64;
+        this.metrics.incr(metrics::BYTES_WRITTEN, n as u64);
+        Poll::Ready(Ok(n))
+    }
+
+    // Forwarded, so replies batched into one `writev` stay one syscall
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let n = ready!(this.inner.poll_write_vectored(cx, bufs))?;
+        if let Some(dump) = this.dump {
+            dump.written_vectored(bufs, n);
+        }
+        *this.written += n as u64;
+        this.metrics.incr(metrics::BYTES_WRITTEN, n as u64);
+        Poll::Ready(Ok(n))
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
+
+impl<T: Transport> Transport for CountingStream<T> {
+    fn peer_closed(&self) -> impl Future<Output = ()> + Send + '_ {
+        self.inner.peer_closed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::InMemorySink;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn both_directions_are_counted() {
+        let metrics: Metrics = Arc::new(InMemorySink::default());
+        let (client, mut peer) = tokio::io::duplex(64);
+        let mut client = CountingStream::new(client, metrics.clone());
+
+        client.write_all(b"hello").await.unwrap();
+        peer.write_all(b"hi").await.unwrap();
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).await.unwrap();
+
+        assert_eq!((client.bytes_read(), client.bytes_written()), (2, 5));
+        assert_eq!(metrics::stat_summary(&metrics), "bytes_read=2 bytes_written=5");
+    }
+}
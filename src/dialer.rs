@@ -0,0 +1,245 @@
+//! Outbound connections: resolve a name, then race its addresses.
+//!
+//! A host name often resolves to several addresses, IPv6 and IPv4. Trying
+//! them one after the other means that an address which silently drops
+//! packets costs the whole connect timeout before the next one is tried.
+//! [`Dialer`] uses the "happy eyeballs" approach of RFC 8305 instead:
+//!
+//! ```text
+//! addresses, families alternating:  [v6 a] [v4 b] [v6 c]
+//!
+//! 0ms     connect to a ──────────────────────── (no answer yet)
+//! 250ms                 connect to b ─── OK ──▶ b wins, a is dropped
+//! ```
+//!
+//! A new attempt starts every 250ms, or as soon as the previous one fails,
+//! while the earlier ones keep going; the first to connect wins and the
+//! others are cancelled by dropping their futures. Resolving and racing
+//! together are bounded by one timeout.
+
+use crate::metrics::{self, Metrics};
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::time::Instant;
+
+/// How long resolving and connecting may take together.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long an attempt runs alone before the next address is tried too;
+/// the value RFC 8305 recommends.
+pub const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Opens TCP connections to names or addresses.
+#[derive(Clone)]
+pub struct Dialer {
+    timeout: Duration,
+    attempt_delay: Duration,
+    // `None`: nothing is counted, e.g. in the client binary
+    metrics: Option<Metrics>,
+}
+
+impl Default for Dialer {
+    fn default() -> Self {
+        Self { timeout: CONNECT_TIMEOUT, attempt_delay: ATTEMPT_DELAY, metrics: None }
+    }
+}
+
+impl Dialer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts dials, attempts, failures and timeouts in `metrics`.
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_attempt_delay(mut self, delay: Duration) -> Self {
+        self.attempt_delay = delay;
+        self
+    }
+
+    /// Connects to `target`, e.g. a `host:port` string or a `SocketAddr`.
+    pub async fn dial(&self, target: impl ToSocketAddrs) -> io::Result<TcpStream> {
+        self.count(metrics::DIALS);
+        let dial = async {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host(target).await?.collect();
+            let connect = |addr| {
+                self.count(metrics::DIAL_ATTEMPTS);
+                TcpStream::connect(addr)
+            };
+            race(interleave(addrs), self.attempt_delay, connect).await
+        };
+        let result = match tokio::time::timeout(self.timeout, dial).await {
+            Ok(result) => result,
+            Err(_) => {
+                self.count(metrics::DIAL_TIMEOUTS);
+                Err(io::Error::new(io::ErrorKind::TimedOut, "connect timed out"))
+            }
+        };
+        if result.is_err() {
+            self.count(metrics::DIAL_FAILURES);
+        }
+        result
+    }
+
+    fn count(&self, name: &'static str) {
+        if let Some(metrics) = &self.metrics {
+            metrics.incr(name, 1);
+        }
+    }
+}
+
+/// Orders addresses for racing: the family of the first one first, then
+/// alternating between IPv6 and IPv4, each family in its resolved order.
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let Some(first) = addrs.first() else {
+        return addrs;
+    };
+    let first_is_v6 = first.is_ipv6();
+    let (preferred, other): (Vec<_>, Vec<_>) =
+        addrs.into_iter().partition(|addr| addr.is_ipv6() == first_is_v6);
+    let mut ordered = Vec::with_capacity(preferred.len() + other.len());
+    let (mut preferred, mut other) = (preferred.into_iter(), other.into_iter());
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Starts `connect` on each address in turn, `delay` apart or as soon as
+/// every running attempt has failed, and returns the first connection.
+/// The error of the last attempt is returned if they all fail.
+async fn race<T, F, Fut>(addrs: Vec<SocketAddr>, delay: Duration, mut connect: F) -> io::Result<T>
+where
+    F: FnMut(SocketAddr) -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+{
+    let mut pending = addrs.into_iter();
+    let mut running = FuturesUnordered::new();
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no addresses found");
+    let mut next_start = Instant::now();
+
+    loop {
+        // Nothing running: start the next address now rather than wait
+        if running.is_empty() {
+            let Some(addr) = pending.next() else {
+                return Err(last_error);
+            };
+            running.push(connect(addr));
+            next_start = Instant::now() + delay;
+        }
+        tokio::select! {
+            Some(result) = running.next() => match result {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = e,
+            },
+            _ = tokio::time::sleep_until(next_start), if pending.len() > 0 => {
+                running.extend(pending.next().map(&mut connect));
+                next_start = Instant::now() + delay;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::pending;
+
+    fn addr(text: &str) -> SocketAddr {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn families_alternate_starting_with_the_first() {
+        let addrs = ["[::1]:1", "[::2]:1", "[::3]:1", "10.0.0.1:1"].map(addr).to_vec();
+        let ordered = ["[::1]:1", "10.0.0.1:1", "[::2]:1", "[::3]:1"].map(addr).to_vec();
+        assert_eq!(interleave(addrs), ordered);
+
+        let addrs = ["10.0.0.1:1", "[::1]:1", "10.0.0.2:1"].map(addr).to_vec();
+        let ordered = ["10.0.0.1:1", "[::1]:1", "10.0.0.2:1"].map(addr).to_vec();
+        assert_eq!(interleave(addrs), ordered);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_silent_address_is_raced_by_the_next_one() {
+        let start = Instant::now();
+        let addrs = vec![addr("[::1]:1"), addr("10.0.0.1:1")];
+        let winner = race(addrs, ATTEMPT_DELAY, |addr| async move {
+            if addr.is_ipv6() {
+                // Dropped packets: never answers
+                pending().await
+            } else {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                Ok(addr)
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(winner, addr("10.0.0.1:1"));
+        assert_eq!(start.elapsed(), ATTEMPT_DELAY + Duration::from_millis(10));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_failed_attempt_starts_the_next_at_once() {
+        let start = Instant::now();
+        let addrs = vec![addr("10.0.0.1:1"), addr("10.0.0.2:1")];
+        let winner = race(addrs, ATTEMPT_DELAY, |addr| async move {
+            if addr == "10.0.0.1:1".parse().unwrap() {
+                Err(io::Error::from(io::ErrorKind::ConnectionRefused))
+            } else {
+                Ok(addr)
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(winner, addr("10.0.0.2:1"));
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_last_error_is_returned_when_every_address_fails() {
+        let addrs = vec![addr("10.0.0.1:1"), addr("10.0.0.2:1")];
+        let result: io::Result<()> = race(addrs, ATTEMPT_DELAY, |addr| async move {
+            if addr == "10.0.0.1:1".parse().unwrap() {
+                Err(io::Error::from(io::ErrorKind::ConnectionRefused))
+            } else {
+                Err(io::Error::from(io::ErrorKind::HostUnreachable))
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::HostUnreachable);
+
+        let none: io::Result<()> = race(Vec::new(), ATTEMPT_DELAY, |_| pending()).await;
+        assert_eq!(none.unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn a_refused_address_falls_back_to_one_that_listens() {
+        // Bound and closed again: nothing listens there
+        let closed = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed_addr = closed.local_addr().unwrap();
+        drop(closed);
+        let open = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open_addr = open.local_addr().unwrap();
+
+        let dialer = Dialer::new();
+        let stream = dialer.dial(&[closed_addr, open_addr][..]).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), open_addr);
+    }
+}
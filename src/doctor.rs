@@ -0,0 +1,321 @@
+//! `tokio-examples doctor`: checks a configuration before it is served.
+//!
+//! The subcommand takes the same `--config` file and flags as the server,
+//! and instead of serving them checks what would stop the server, or one of
+//! its parts, at startup:
+//!
+//! ```text
+//! ok    config     tokio-examples.toml, 3 flag(s)
+//! ok    listen     127.0.0.1:7000 (text)
+//! FAIL  listen     127.0.0.1:8443 (TLS): Address already in use (os error 98)
+//! ok    tls        cert.pem and key.pem
+//! ok    script     hooks.rhai compiles
+//! ok    directory  traces (trace files) will be created in .
+//! FAIL  upstream   10.0.0.5:6379 (proxy): Connection refused (os error 111)
+//! 2 of 7 checks failed
+//! ```
+//!
+//! Every address is bound, all at once so that two parts of the config
+//! asking for the same one are caught too, and released again; every
+//! directory the server writes to is written to, or its nearest existing
+//! parent if the server is to create it; every upstream, health check
+//! target and primary is dialled. It all runs on the server's own modules,
+//! `crate::tls`, `crate::script`, `crate::dialer` and the config parser, so
+//! what fails here fails there. The process exits with 1 if any check failed.
+
+use crate::admin::AdminAddr;
+use crate::config::{self, ServerConfig};
+use crate::dialer::Dialer;
+use crate::logger::LogOverflow;
+use crate::metrics::{InMemorySink, MetricsBackend};
+use crate::script::Script;
+use crate::tls;
+use futures_util::future::join_all;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+/// How long dialling an upstream may take before it counts as unreachable.
+const DIAL_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Ok,
+    Fail,
+}
+
+/// One line of the report.
+#[derive(Debug)]
+pub struct Check {
+    pub name: &'static str,
+    pub outcome: Outcome,
+    pub detail: String,
+}
+
+/// Every check that ran, in order.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    fn record(&mut self, name: &'static str, result: Result<String, String>) {
+        let (outcome, detail) = match result {
+            Ok(detail) => (Outcome::Ok, detail),
+            Err(detail) => (Outcome::Fail, detail),
+        };
+        self.checks.push(Check { name, outcome, detail });
+    }
+
+    pub fn failures(&self) -> usize {
+        self.checks.iter().filter(|check| check.outcome == Outcome::Fail).count()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in &self.checks {
+            let outcome = match check.outcome {
+                Outcome::Ok => "ok",
+                Outcome::Fail => "FAIL",
+            };
+            writeln!(f, "{:<5} {:<10} {}", outcome, check.name, check.detail)?;
+        }
+        match self.failures() {
+            0 => writeln!(f, "all {} checks passed", self.checks.len()),
+            failed => writeln!(f, "{} of {} checks failed", failed, self.checks.len()),
+        }
+    }
+}
+
+/// Checks the config that `args` describe; stops after the config check
+/// if it cannot be read.
+pub async fn run(args: &[String]) -> Report {
+    let mut report = Report::default();
+    let config = match config::load(args).await {
+        Ok(config) => config,
+        Err(e) => {
+            report.record("config", Err(e));
+            return report;
+        }
+    };
+    let file = match args.iter().position(|arg| arg == "--config") {
+        Some(i) => format!("{}, ", args[i + 1]),
+        None => String::new(),
+    };
+    let flags = args.iter().filter(|arg| arg.starts_with("--") && *arg != "--config").count();
+    report.record("config", Ok(format!("{}{} flag(s)", file, flags)));
+
+    listeners(&config, &mut report).await;
+    certificates(&config, &mut report).await;
+    if let Some(path) = &config.script {
+        let loaded = Script::load(path, Arc::new(InMemorySink::default())).await;
+        report.record("script", loaded.map(|_| format!("{} compiles", path.display())));
+    }
+    for (dir, what) in directories(&config) {
+        report.record("directory", writable(&dir, what).await);
+    }
+    upstreams(&config, &mut report).await;
+    report
+}
+
+/// Binds every address the config listens on, and holds them all until
+/// the last is bound.
+async fn listeners(config: &ServerConfig, report: &mut Report) {
+    let mut addrs = vec![(config.listen, "text".to_string())];
+    let optional = [
+        (config.length_listen, "length-prefixed"),
+        (config.json_listen, "JSON"),
+        (config.http_addr, "HTTP"),
+        (config.grpc_addr, "gRPC"),
+        (config.h2_addr, "HTTP/2"),
+        (config.socks_listen, "SOCKS5"),
+        (config.tls_listen, "TLS"),
+    ];
+    addrs.extend(optional.into_iter().filter_map(|(addr, what)| Some((addr?, what.to_string()))));
+    for spec in &config.listeners {
+        addrs.push((spec.addr, spec.mode.describe().to_string()));
+    }
+    if let Some(AdminAddr::Tcp(addr)) = &config.admin_addr {
+        addrs.push((*addr, "admin".to_string()));
+    }
+    if let MetricsBackend::Prometheus(addr) = config.metrics {
+        addrs.push((addr, "metrics".to_string()));
+    }
+
+    let mut bound: Vec<TcpListener> = Vec::new();
+    for (addr, what) in addrs {
+        let result = match TcpListener::bind(addr).await {
+            Ok(listener) => {
+                bound.push(listener);
+                Ok(format!("{} ({})", addr, what))
+            }
+            Err(e) => Err(format!("{} ({}): {}", addr, what, e)),
+        };
+        report.record("listen", result);
+    }
+}
+
+/// Loads the TLS certificate and key, as the TLS listener does.
+async fn certificates(config: &ServerConfig, report: &mut Report) {
+    let result = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => {
+            let client_ca = config.tls_client_ca.as_deref();
+            match tls::acceptor(cert, key, client_ca).await {
+                Ok(_) => Ok(format!("{} and {}", cert.display(), key.display())),
+                Err(e) => Err(e.to_string()),
+            }
+        }
+        (None, None) => return,
+        _ => Err("--tls-cert and --tls-key must be set together".to_string()),
+    };
+    report.record("tls", result);
+}
+
+/// Every directory the server writes files to, with what goes there.
+fn directories(config: &ServerConfig) -> Vec<(PathBuf, &'static str)> {
+    let parent = |file: &Path| match file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let mut dirs = Vec::new();
+    if let LogOverflow::DeadLetter(file) = &config.log_overflow {
+        dirs.push((parent(file), "dead letters"));
+    }
+    if let Some(dir) = &config.trace_dir {
+        dirs.push((dir.clone(), "trace files"));
+    }
+    if let Some(dir) = &config.record_dir {
+        dirs.push((dir.clone(), "recordings"));
+    }
+    if let Some(dir) = &config.wire_debug_dir {
+        dirs.push((dir.clone(), "wire dumps"));
+    }
+    if let Some(dir) = &config.queue_dir {
+        dirs.push((dir.clone(), "durable queues"));
+    }
+    if let Some(file) = &config.state_file {
+        dirs.push((parent(file), "state file"));
+    }
+    if let Some(AdminAddr::Unix(path)) = &config.admin_addr {
+        dirs.push((parent(path), "admin socket"));
+    }
+    dirs.push((config.upload_dir.clone(), "uploads"));
+    dirs
+}
+
+/// Writes a file into `dir`, or into its nearest existing parent when the
+/// server is yet to create it, and removes it again.
+async fn writable(dir: &Path, what: &str) -> Result<String, String> {
+    let mut existing = dir.to_path_buf();
+    while !tokio::fs::try_exists(&existing).await.unwrap_or(false) {
+        match existing.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => existing = parent.to_path_buf(),
+            // A relative path: its first part goes into the working directory
+            _ => {
+                existing = PathBuf::from(".");
+                break;
+            }
+        }
+    }
+    let probe = existing.join(format!(".doctor-{}", std::process::id()));
+    let written = tokio::fs::write(&probe, b"").await;
+    let _ = tokio::fs::remove_file(&probe).await;
+    match written {
+        Ok(()) if existing == dir => Ok(format!("{} ({}) is writable", dir.display(), what)),
+        Ok(()) => Ok(format!(
+            "{} ({}) will be created in {}",
+            dir.display(),
+            what,
+            existing.display()
+        )),
+        Err(e) => Err(format!("{} ({}): {}", dir.display(), what, e)),
+    }
+}
+
+/// Dials every upstream the server connects to, all at once.
+async fn upstreams(config: &ServerConfig, report: &mut Report) {
+    let mut targets: Vec<(String, &str)> = Vec::new();
+    targets.extend(config.proxy.iter().map(|addr| (addr.clone(), "proxy")));
+    targets.extend(config.health.targets.iter().map(|t| (t.addr.clone(), "health check")));
+    targets.extend(config.replica_of.iter().map(|addr| (addr.clone(), "primary")));
+
+    let dialer = Dialer::new().with_timeout(DIAL_TIMEOUT);
+    let dials = targets.iter().map(|(addr, what)| {
+        let dialer = &dialer;
+        async move {
+            match dialer.dial(addr.as_str()).await {
+                Ok(_) => Ok(format!("{} ({})", addr, what)),
+                Err(e) => Err(format!("{} ({}): {}", addr, what, e)),
+            }
+        }
+    });
+    for result in join_all(dials).await {
+        report.record("upstream", result);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn every_part_of_the_config_is_checked() {
+        let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let taken = taken.local_addr().unwrap().to_string();
+        // Bound and dropped: nothing listens there any more
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let dir = std::env::temp_dir().join(format!("doctor-test-{}", std::process::id()));
+        let traces = dir.join("traces");
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+
+        let report = run(&args(&[
+            "--listen",
+            "127.0.0.1:0",
+            "--json-listen",
+            &taken,
+            "--trace-dir",
+            traces.to_str().unwrap(),
+            "--upload-dir",
+            dir.to_str().unwrap(),
+            "--tls-cert",
+            "missing.pem",
+            "--proxy",
+            &closed.to_string(),
+        ]))
+        .await;
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+
+        let lines: Vec<(&str, Outcome)> =
+            report.checks.iter().map(|check| (check.name, check.outcome)).collect();
+        assert_eq!(
+            lines,
+            [
+                ("config", Outcome::Ok),
+                ("listen", Outcome::Ok),
+                ("listen", Outcome::Fail),
+                ("tls", Outcome::Fail),
+                ("directory", Outcome::Ok),
+                ("directory", Outcome::Ok),
+                ("upstream", Outcome::Fail),
+            ]
+        );
+        let created = format!("{} (trace files) will be created in ", traces.display());
+        assert_eq!(report.checks[4].detail, format!("{}{}", created, dir.display()));
+        assert!(report.to_string().ends_with("3 of 7 checks failed\n"));
+    }
+
+    #[tokio::test]
+    async fn a_config_that_does_not_parse_is_the_only_check() {
+        let report = run(&args(&["--listen", "nowhere"])).await;
+        assert_eq!(report.checks.len(), 1);
+        assert_eq!(report.checks[0].outcome, Outcome::Fail);
+        assert_eq!(report.failures(), 1);
+    }
+}
@@ -0,0 +1,65 @@
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+/// Everything that can go wrong while running the server.
+///
+/// Startup errors end the process with a message; per-connection errors
+/// only end that connection and are reported through the logger task.
+#[derive(Debug, thiserror::Error)]
+pub enum ServerError {
+    #[error("invalid configuration: {0}")]
+    Config(String),
+
+    #[error("cannot bind {addr}: {source}")]
+    Bind {
+        addr: String,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("accepting a connection failed: {0}")]
+    Accept(#[source] io::Error),
+
+    #[error("reading from {peer} failed: {source}")]
+    Read {
+        peer: SocketAddr,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("writing to {peer} failed: {source}")]
+    Write {
+        peer: SocketAddr,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("proxying {peer} to {upstream} failed: {source}")]
+    Proxy {
+        peer: SocketAddr,
+        upstream: String,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("cannot install signal handlers: {0}")]
+    Signal(#[source] io::Error),
+
+    #[error("cannot start metrics backend: {0}")]
+    Metrics(#[source] io::Error),
+
+    #[error("{path}: {source}")]
+    File {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("cannot watch {path}: {source}")]
+    Watch {
+        path: PathBuf,
+        #[source]
+        source: notify::Error,
+    },
+}
@@ -0,0 +1,246 @@
+//! A map whose entries expire a fixed time after they were inserted.
+//!
+//! Meant for state that should outlive a connection for a grace period,
+//! such as a session a client may come back to, but not forever.
+//!
+//! Expiry happens in two places:
+//!
+//! - lazily: `get` and `take` never return an entry past its deadline;
+//! - actively: a sweeper task keeps a `DelayQueue` with one timer per
+//!   insert and removes entries when their timer fires, so memory is
+//!   reclaimed even for keys nobody asks about again.
+//!
+//! Re-inserting a key restarts its grace period. The old timer is not
+//! cancelled; each entry carries a generation number, and a timer whose
+//! generation no longer matches simply does nothing.
+
+use crate::metrics::Metrics;
+use crate::task;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tokio_stream::StreamExt;
+use tokio_util::time::DelayQueue;
+
+/// Metric names the map reports to.
+#[derive(Debug, Clone, Copy)]
+pub struct ExpiryMetrics {
+    /// Gauge: entries currently in the map.
+    pub live: &'static str,
+    /// Counter: entries removed because their grace period ran out.
+    pub expired: &'static str,
+}
+
+struct Slot<V> {
+    value: V,
+    generation: u64,
+    deadline: Instant,
+}
+
+struct Inner<K, V> {
+    entries: Mutex<Entries<K, V>>,
+    ttl: Duration,
+    metrics: Metrics,
+    names: ExpiryMetrics,
+    // Tells the sweeper about every insert: (key, generation)
+    timers: mpsc::UnboundedSender<(K, u64)>,
+}
+
+struct Entries<K, V> {
+    map: HashMap<K, Slot<V>>,
+    next_generation: u64,
+}
+
+/// Handle to an expiring map; clones share the same entries.
+pub struct ExpiringMap<K, V> {
+    inner: Arc<Inner<K, V>>,
+}
+
+impl<K, V> Clone for ExpiringMap<K, V> {
+    fn clone(&self) -> Self {
+        Self { inner: self.inner.clone() }
+    }
+}
+
+impl<K, V> ExpiringMap<K, V>
+where
+    K: Eq + Hash + Clone + Send + Sync + 'static,
+    V: Send + 'static,
+{
+    /// Creates the map and spawns its sweeper task.
+    ///
+    /// The sweeper stops once the last handle is dropped.
+    pub fn new(ttl: Duration, metrics: Metrics, names: ExpiryMetrics) -> Self {
+        let (timers, timers_rx) = mpsc::unbounded_channel();
+        let inner = Arc::new(Inner {
+            entries: Mutex::new(Entries { map: HashMap::new(), next_generation: 0 }),
+            ttl,
+            metrics,
+            names,
+            timers,
+        });
+        // A weak reference, so the sweeper does not keep the map alive
+        task::spawn("expiry sweeper", sweep(Arc::downgrade(&inner), timers_rx));
+        Self { inner }
+    }
+
+    /// Inserts or replaces an entry; its grace period starts now.
+    pub fn insert(&self, key: K, value: V) {
+        let inner = &self.inner;
+        let generation = {
+            let mut entries = inner.entries.lock().unwrap();
+            let generation = entries.next_generation;
+            entries.next_generation += 1;
+            let slot = Slot { value, generation, deadline: Instant::now() + inner.ttl };
+            if entries.map.insert(key.clone(), slot).is_none() {
+                inner.metrics.adjust(inner.names.live, 1);
+            }
+            generation
+        };
+        // Only fails when the sweeper is gone, i.e. the runtime is shutting down
+        let _ = inner.timers.send((key, generation));
+    }
+
+    /// Removes an entry and returns it, unless it has expired.
+    pub fn take(&self, key: &K) -> Option<V> {
+        let slot = {
+            let mut entries = self.inner.entries.lock().unwrap();
+            entries.map.remove(key)?
+        };
+        self.inner.metrics.adjust(self.inner.names.live, -1);
+
+        if slot.deadline <= Instant::now() {
+            // Due, but the sweeper has not got to it yet
+            self.inner.metrics.incr(self.inner.names.expired, 1);
+            return None;
+        }
+        Some(slot.value)
+    }
+
+    /// Number of entries, including expired ones the sweeper has not removed yet.
+    pub fn len(&self) -> usize {
+        self.inner.entries.lock().unwrap().map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<K, V> ExpiringMap<K, V>
+where
+    K: Eq + Hash + Clone + Send + Sync + 'static,
+    V: Clone + Send + 'static,
+{
+    /// A copy of an entry, unless it has expired. Does not extend the grace period.
+    pub fn get(&self, key: &K) -> Option<V> {
+        let entries = self.inner.entries.lock().unwrap();
+        entries
+            .map
+            .get(key)
+            .filter(|slot| slot.deadline > Instant::now())
+            .map(|slot| slot.value.clone())
+    }
+}
+
+/// Removes entries whose timers fire, until the map is dropped.
+async fn sweep<K, V>(inner: Weak<Inner<K, V>>, mut timers: mpsc::UnboundedReceiver<(K, u64)>)
+where
+    K: Eq + Hash + Clone,
+{
+    let mut queue = DelayQueue::new();
+    let Some(ttl) = inner.upgrade().map(|inner| inner.ttl) else { return };
+
+    loop {
+        tokio::select! {
+            timer = timers.recv() => match timer {
+                Some(timer) => {
+                    queue.insert(timer, ttl);
+                }
+                // Every handle is gone; the entries went with them
+                None => return,
+            },
+            // An empty `DelayQueue` yields `None` right away instead of waiting
+            Some(expired) = queue.next(), if !queue.is_empty() => {
+                let (key, generation) = expired.into_inner();
+                let Some(inner) = inner.upgrade() else { return };
+
+                let removed = {
+                    let mut entries = inner.entries.lock().unwrap();
+                    // An entry re-inserted since has a newer generation and its own timer
+                    let current = entries.map.get(&key).is_some_and(|s| s.generation == generation);
+                    current && entries.map.remove(&key).is_some()
+                };
+                if removed {
+                    inner.metrics.adjust(inner.names.live, -1);
+                    inner.metrics.incr(inner.names.expired, 1);
+                }
+            }
+        }
+    }
+}
+
+/// Time is paused, so the grace periods pass without real waiting and the
+/// tests can look right before and right after a deadline.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::{self, MetricsBackend};
+    use crate::restart::Handoff;
+
+    const NAMES: ExpiryMetrics = ExpiryMetrics { live: "test_live", expired: "test_expired" };
+
+    async fn map(ttl_ms: u64) -> (ExpiringMap<&'static str, u32>, Metrics) {
+        let metrics = metrics::start(&MetricsBackend::Memory, &Handoff::default()).await.unwrap();
+        let map = ExpiringMap::new(Duration::from_millis(ttl_ms), metrics.clone(), NAMES);
+        (map, metrics)
+    }
+
+    fn value(metrics: &Metrics, name: &str) -> i64 {
+        metrics.snapshot().iter().find(|m| m.name == name).map_or(0, |m| m.value)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn entries_are_there_until_taken() {
+        let (map, metrics) = map(10_000).await;
+        map.insert("a", 1);
+        map.insert("b", 2);
+
+        assert_eq!(map.get(&"a"), Some(1));
+        assert_eq!(map.take(&"a"), Some(1));
+        assert_eq!(map.take(&"a"), None);
+        assert_eq!(value(&metrics, NAMES.live), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_sweeper_removes_expired_entries() {
+        let (map, metrics) = map(20).await;
+        map.insert("a", 1);
+
+        tokio::time::sleep(Duration::from_millis(19)).await;
+        assert_eq!(map.get(&"a"), Some(1));
+        // Expired on the dot, whether or not the sweeper has run yet
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        assert_eq!(map.get(&"a"), None);
+
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        assert!(map.is_empty());
+        assert_eq!(value(&metrics, NAMES.live), 0);
+        assert_eq!(value(&metrics, NAMES.expired), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn inserting_again_restarts_the_grace_period() {
+        let (map, _metrics) = map(150).await;
+        map.insert("a", 1);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        map.insert("a", 2);
+        // Past the first deadline, before the second
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        assert_eq!(map.get(&"a"), Some(2));
+    }
+}
@@ -0,0 +1,128 @@
+//! `Fanout`: hands every published line to each subscriber's own channel.
+//!
+//! Used by the feeds a client can subscribe to (`TAIL`, `WATCH`), and by
+//! the changes streamed to replicas (see `crate::replica`). Each
+//! subscriber gets a bounded channel and lines are offered with
+//! `try_send`, so a subscriber that falls behind misses lines instead of
+//! holding up the publisher and the other subscribers. A subscriber that
+//! has gone away is removed on the next line.
+
+use crate::metrics::Metrics;
+use bytes::Bytes;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+
+/// Lines a subscriber may be behind before it misses some.
+const SUBSCRIBER_BUFFER: usize = 64;
+
+/// The subscribers of one feed; cheap to clone.
+#[derive(Clone)]
+pub struct Fanout {
+    subscribers: Arc<Mutex<Vec<mpsc::Sender<Bytes>>>>,
+    metrics: Metrics,
+    // Gauge of open subscriptions, and counter of lines they missed
+    gauge: &'static str,
+    missed: &'static str,
+    buffer: usize,
+}
+
+impl Fanout {
+    pub fn new(metrics: Metrics, gauge: &'static str, missed: &'static str) -> Self {
+        Self::with_buffer(metrics, gauge, missed, SUBSCRIBER_BUFFER)
+    }
+
+    /// A feed whose subscribers may be `buffer` lines behind, rather than
+    /// the default 64.
+    pub fn with_buffer(
+        metrics: Metrics,
+        gauge: &'static str,
+        missed: &'static str,
+        buffer: usize,
+    ) -> Self {
+        Self { subscribers: Arc::default(), metrics, gauge, missed, buffer }
+    }
+
+    /// Lines published from now on.
+    pub fn subscribe(&self) -> Subscription {
+        let (tx, lines) = mpsc::channel(self.buffer);
+        self.subscribers.lock().unwrap().push(tx);
+        self.metrics.adjust(self.gauge, 1);
+        Subscription { lines, metrics: self.metrics.clone(), gauge: self.gauge }
+    }
+
+    /// Whether anyone is still subscribed; those who went away are removed.
+    pub fn has_subscribers(&self) -> bool {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|tx| !tx.is_closed());
+        !subscribers.is_empty()
+    }
+
+    /// Hands `line` to every subscriber that has room for it, and returns
+    /// how many did.
+    pub fn publish(&self, line: Bytes) -> usize {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let mut delivered = 0;
+        subscribers.retain(|tx| match tx.try_send(line.clone()) {
+            Ok(()) => {
+                delivered += 1;
+                true
+            }
+            Err(TrySendError::Full(_)) => {
+                self.metrics.incr(self.missed, 1);
+                true
+            }
+            Err(TrySendError::Closed(_)) => false,
+        });
+        delivered
+    }
+
+    /// Ends every subscription: their `recv` returns `None` once drained.
+    pub fn close(&self) {
+        self.subscribers.lock().unwrap().clear();
+    }
+}
+
+/// One client's feed; leaves the subscriber gauge when dropped.
+pub struct Subscription {
+    lines: mpsc::Receiver<Bytes>,
+    metrics: Metrics,
+    gauge: &'static str,
+}
+
+impl Subscription {
+    /// The next line, without a newline; `None` once the feed is closed.
+    pub async fn recv(&mut self) -> Option<Bytes> {
+        self.lines.recv().await
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.metrics.adjust(self.gauge, -1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::{self, InMemorySink};
+
+    #[tokio::test]
+    async fn a_slow_subscriber_misses_lines_without_holding_up_others() {
+        let metrics: Metrics = Arc::new(InMemorySink::default());
+        let fanout = Fanout::new(metrics.clone(), "feed_subscribers", "feed_lines_missed");
+        let (mut fast, slow) = (fanout.subscribe(), fanout.subscribe());
+
+        for n in 0..SUBSCRIBER_BUFFER + 1 {
+            fanout.publish(Bytes::from(n.to_string()));
+            assert_eq!(fast.recv().await.unwrap(), n.to_string());
+        }
+        drop(slow);
+        let summary = "feed_lines_missed=1 feed_subscribers=1";
+        assert_eq!(metrics::stat_summary(&metrics), summary);
+
+        fanout.close();
+        assert!(fast.recv().await.is_none());
+    }
+}
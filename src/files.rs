@@ -0,0 +1,229 @@
+//! `GET /files/<path>` on the HTTP endpoint: static files from `--files-root`.
+//!
+//! A file is never read into memory. Its length comes from the metadata and
+//! goes into `Content-Length`, then the body is copied from the file to the
+//! socket a buffer at a time (`upload::send`, as for `GET-FILE`), so the
+//! socket's backpressure paces the reads.
+//!
+//! The path in the URL is percent-decoded and then checked one component
+//! at a time: `..`, empty and hidden components are refused before the
+//! file system is touched. The resolved path is canonicalized as well, so a
+//! symlink cannot lead out of the root either. A directory is answered with
+//! an HTML index of its entries.
+
+use crate::upload;
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+/// The URL prefix files are served under.
+pub const PREFIX: &str = "/files/";
+
+/// Why a request gets no file.
+#[derive(Debug, PartialEq, Eq)]
+enum Refusal {
+    /// Not there, outside the root, or hidden: the client cannot tell which.
+    NotFound,
+    /// Not a valid path at all.
+    BadRequest,
+}
+
+/// Turns the part of the URL after [`PREFIX`] into a path relative to the root.
+fn relative_path(url_path: &str) -> Result<PathBuf, Refusal> {
+    let decoded = percent_decode(url_path).ok_or(Refusal::BadRequest)?;
+    let mut relative = PathBuf::new();
+    // A trailing slash only says it is a directory
+    let decoded = decoded.strip_suffix('/').unwrap_or(&decoded);
+    if decoded.is_empty() {
+        return Ok(relative);
+    }
+    for component in decoded.split('/') {
+        if component.is_empty() || component.contains('\\') || component.contains('\0') {
+            return Err(Refusal::BadRequest);
+        }
+        // Also covers `.` and `..`
+        if component.starts_with('.') {
+            return Err(Refusal::NotFound);
+        }
+        relative.push(component);
+    }
+    Ok(relative)
+}
+
+/// Decodes `%xx` escapes; `None` for a broken escape or a result that is not UTF-8.
+fn percent_decode(text: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// Escapes everything but unreserved characters, for a link in the index.
+fn percent_encode(text: &str) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for byte in text.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// The `Content-Type` for a file, by its extension.
+fn content_type(path: &Path) -> &'static str {
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or_default();
+    match extension.to_ascii_lowercase().as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "txt" | "log" | "md" | "toml" => "text/plain; charset=utf-8",
+        "css" => "text/css",
+        "js" => "text/javascript",
+        "json" => "application/json",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Serves `url_path` (what follows [`PREFIX`]) from `root`.
+///
+/// Returns the number of body bytes sent, or the error that cut the
+/// response short.
+pub async fn serve(socket: &mut TcpStream, root: &Path, url_path: &str) -> io::Result<u64> {
+    let url_path = url_path.split(['?', '#']).next().unwrap_or_default();
+    let path = match resolve(root, url_path).await {
+        Ok(path) => path,
+        Err(Refusal::NotFound) => return refuse(socket, "404 Not Found", "not found\n").await,
+        Err(Refusal::BadRequest) => return refuse(socket, "400 Bad Request", "bad path\n").await,
+    };
+    if tokio::fs::metadata(&path).await?.is_dir() {
+        // Without the slash, the relative links of the index would be off by a directory
+        if !url_path.is_empty() && !url_path.ends_with('/') {
+            let location = format!("{}{}/", PREFIX, url_path);
+            let head = format!(
+                "HTTP/1.1 301 Moved Permanently\r\nLocation: {}\r\nContent-Length: 0\r\n\
+                 Connection: close\r\n\r\n",
+                location,
+            );
+            socket.write_all(head.as_bytes()).await?;
+            return Ok(0);
+        }
+        let body = index(&path, url_path).await?;
+        write_head(socket, "200 OK", "text/html; charset=utf-8", body.len() as u64).await?;
+        socket.write_all(body.as_bytes()).await?;
+        return Ok(body.len() as u64);
+    }
+
+    let file = File::open(&path).await?;
+    // Taken from the open file: what is sent is what was announced
+    let len = file.metadata().await?.len();
+    write_head(socket, "200 OK", content_type(&path), len).await?;
+    upload::send(file, len, socket).await
+}
+
+/// The file or directory `url_path` names, if it is inside `root`.
+async fn resolve(root: &Path, url_path: &str) -> Result<PathBuf, Refusal> {
+    let relative = relative_path(url_path)?;
+    let root = tokio::fs::canonicalize(root).await.map_err(|_| Refusal::NotFound)?;
+    let path = tokio::fs::canonicalize(root.join(relative)).await.map_err(|_| Refusal::NotFound)?;
+    // A symlink inside the root may still point outside of it
+    if !path.starts_with(&root) {
+        return Err(Refusal::NotFound);
+    }
+    Ok(path)
+}
+
+/// An HTML page linking to every visible entry of `dir`, directories first.
+async fn index(dir: &Path, url_path: &str) -> io::Result<String> {
+    let mut entries = Vec::new();
+    let mut read_dir = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = read_dir.next_entry().await? {
+        let Ok(name) = entry.file_name().into_string() else { continue };
+        if name.starts_with('.') {
+            continue;
+        }
+        let is_dir = entry.file_type().await?.is_dir();
+        entries.push((!is_dir, name));
+    }
+    entries.sort();
+
+    let title = html_escape(&format!("{}{}", PREFIX, url_path));
+    let mut page = format!("<!DOCTYPE html>\n<title>{}</title>\n<h1>{}</h1>\n<ul>\n", title, title);
+    if !url_path.is_empty() {
+        page.push_str("<li><a href=\"../\">../</a></li>\n");
+    }
+    for (is_file, name) in entries {
+        let slash = if is_file { "" } else { "/" };
+        page.push_str(&format!(
+            "<li><a href=\"{}{}\">{}{}</a></li>\n",
+            percent_encode(&name),
+            slash,
+            html_escape(&name),
+            slash,
+        ));
+    }
+    page.push_str("</ul>\n");
+    Ok(page)
+}
+
+async fn write_head(
+    socket: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    len: u64,
+) -> io::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status, content_type, len,
+    );
+    socket.write_all(head.as_bytes()).await
+}
+
+async fn refuse(socket: &mut TcpStream, status: &str, body: &str) -> io::Result<u64> {
+    write_head(socket, status, "text/plain", body.len() as u64).await?;
+    socket.write_all(body.as_bytes()).await?;
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_cannot_leave_the_root() {
+        assert_eq!(relative_path("docs/a%20b.txt"), Ok(PathBuf::from("docs/a b.txt")));
+        assert_eq!(relative_path("docs/"), Ok(PathBuf::from("docs")));
+        assert_eq!(relative_path(""), Ok(PathBuf::new()));
+        assert_eq!(relative_path("../etc/passwd"), Err(Refusal::NotFound));
+        assert_eq!(relative_path("docs/%2e%2e/%2e%2e/etc"), Err(Refusal::NotFound));
+        assert_eq!(relative_path("docs/.git/config"), Err(Refusal::NotFound));
+        assert_eq!(relative_path("/etc/passwd"), Err(Refusal::BadRequest));
+        assert_eq!(relative_path("docs%2f..%5c..%5cetc"), Err(Refusal::BadRequest));
+        assert_eq!(relative_path("a%5cb"), Err(Refusal::BadRequest));
+        assert_eq!(relative_path("%zz"), Err(Refusal::BadRequest));
+    }
+
+    #[test]
+    fn index_links_are_escaped() {
+        assert_eq!(percent_encode("a b&c.txt"), "a%20b%26c.txt");
+        assert_eq!(html_escape("<a & b>"), "&lt;a &amp; b&gt;");
+    }
+}
@@ -0,0 +1,90 @@
+//! Feature flags, switched while the server runs.
+//!
+//! The flags are a small `Copy` struct published through a `watch` channel.
+//! The admin interface holds the sender and changes one flag at a time with
+//! `send_modify`; every task that cares holds a receiver. Handlers read
+//! `*flags.borrow()` before each message, so a change applies to the very
+//! next message of every connection. The accept loops also wait on
+//! `changed()`, so they react to `accept_paused` right away instead of at
+//! the next connection.
+
+use std::fmt;
+use tokio::sync::watch;
+
+/// The receiving side, as held by connections and accept loops.
+pub type FlagsRx = watch::Receiver<FeatureFlags>;
+
+/// The current set of flags; all off by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FeatureFlags {
+    /// Echo replies show the client's input in upper case.
+    pub echo_uppercase: bool,
+    /// Every reply is written to the log, next to the input.
+    pub verbose_logging: bool,
+    /// The accept loops stop accepting; new clients wait in the backlog.
+    pub accept_paused: bool,
+    /// New connections are accepted, told the server is paused and closed.
+    pub reject_connections: bool,
+}
+
+impl FeatureFlags {
+    /// The flag names, as used by the admin `FLAG` command.
+    pub const NAMES: [&'static str; 4] =
+        ["echo_uppercase", "verbose_logging", "accept_paused", "reject_connections"];
+
+    pub fn get(&self, name: &str) -> Option<bool> {
+        let mut flags = *self;
+        flags.slot(name).map(|flag| *flag)
+    }
+
+    /// Sets the flag called `name`; returns `false` if there is no such flag.
+    pub fn set(&mut self, name: &str, on: bool) -> bool {
+        match self.slot(name) {
+            Some(flag) => {
+                *flag = on;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn slot(&mut self, name: &str) -> Option<&mut bool> {
+        match name.to_ascii_lowercase().as_str() {
+            "echo_uppercase" => Some(&mut self.echo_uppercase),
+            "verbose_logging" => Some(&mut self.verbose_logging),
+            "accept_paused" => Some(&mut self.accept_paused),
+            "reject_connections" => Some(&mut self.reject_connections),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for FeatureFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, name) in Self::NAMES.iter().enumerate() {
+            let on = self.get(name).unwrap_or_default();
+            let separator = if i == 0 { "" } else { " " };
+            write!(f, "{}{}={}", separator, name, if on { "on" } else { "off" })?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_are_set_by_name() {
+        let mut flags = FeatureFlags::default();
+        assert!(flags.set("ECHO_UPPERCASE", true));
+        assert!(flags.set("accept_paused", true));
+        assert!(!flags.set("no_such_flag", true));
+
+        assert_eq!(flags.get("echo_uppercase"), Some(true));
+        assert_eq!(flags.get("no_such_flag"), None);
+        let listed =
+            "echo_uppercase=on verbose_logging=off accept_paused=on reject_connections=off";
+        assert_eq!(flags.to_string(), listed);
+    }
+}
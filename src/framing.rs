@@ -0,0 +1,247 @@
+//! How messages are delimited on the wire.
+//!
+//! Two framings are supported, chosen per listener:
+//!
+//! - `Lines`: the text protocol. A message ends with `\n`; the bytes before
+//!   it must be text, so control bytes are refused.
+//! - `LengthPrefixed`: a 4-byte big-endian length, then that many bytes of
+//!   payload. Any byte may appear in the payload, newlines included, so
+//!   the framing is binary-safe.
+//!
+//! The handler does not care which one a connection uses. It decodes
+//! [`Message`]s with a [`MessageCodec`] and queues replies in an
+//! [`Outbox`](crate::outbox::Outbox), which frames them the same way.
+
+use bytes::{Bytes, BytesMut};
+use std::io;
+use tokio_util::codec::length_delimited::LengthDelimitedCodecError;
+use tokio_util::codec::{AnyDelimiterCodec, Decoder, LengthDelimitedCodec};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Framing {
+    /// Newline-terminated text.
+    #[default]
+    Lines,
+    /// A big-endian `u32` length, then the payload.
+    LengthPrefixed,
+}
+
+/// One message from a client, however it was framed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    /// The content without the framing: no newline, no length prefix.
+    pub payload: Bytes,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum FrameError {
+    /// A message was longer than the limit.
+    #[error("message longer than {0} bytes")]
+    TooLong(usize),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// Splits a connection's input into messages.
+#[derive(Debug)]
+pub enum MessageCodec {
+    Lines(AnyDelimiterCodec),
+    LengthPrefixed(LengthDelimitedCodec),
+}
+
+impl MessageCodec {
+    /// A codec refusing messages longer than `max_message` bytes.
+    pub fn new(framing: Framing, max_message: usize) -> Self {
+        match framing {
+            Framing::Lines => Self::Lines(AnyDelimiterCodec::new_with_max_length(
+                b"\n".to_vec(),
+                Vec::new(),
+                max_message,
+            )),
+            Framing::LengthPrefixed => Self::LengthPrefixed(
+                LengthDelimitedCodec::builder().max_frame_length(max_message).new_codec(),
+            ),
+        }
+    }
+
+    fn max_message(&self) -> usize {
+        match self {
+            Self::Lines(codec) => codec.max_length(),
+            Self::LengthPrefixed(codec) => codec.max_frame_length(),
+        }
+    }
+
+    /// Whether any byte may appear in a message.
+    pub fn binary_safe(&self) -> bool {
+        matches!(self, Self::LengthPrefixed(_))
+    }
+
+    /// Whether the connection can go on after `FrameError::TooLong`.
+    ///
+    /// The line codec discards the rest of a long line and resumes after
+    /// the next newline. A length prefix that is too large cannot be
+    /// skipped safely (the client could claim gigabytes), so with
+    /// `LengthPrefixed` the connection has to end.
+    pub fn recovers_from_too_long(&self) -> bool {
+        matches!(self, Self::Lines(_))
+    }
+}
+
+impl Decoder for MessageCodec {
+    type Item = Message;
+    type Error = FrameError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>, FrameError> {
+        let max = self.max_message();
+        let payload = match self {
+            Self::Lines(codec) => codec.decode(src).map_err(|_| FrameError::TooLong(max))?,
+            Self::LengthPrefixed(codec) => codec
+                .decode(src)
+                .map_err(|e| too_long(e, max))?
+                .map(BytesMut::freeze),
+        };
+        Ok(payload.map(|payload| Message { payload }))
+    }
+
+    /// At EOF a last line may lack its newline; a length-prefixed frame
+    /// cut short is an error.
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Message>, FrameError> {
+        let max = self.max_message();
+        match self {
+            Self::Lines(codec) => {
+                let payload = codec.decode_eof(src).map_err(|_| FrameError::TooLong(max))?;
+                Ok(payload.map(|payload| Message { payload }))
+            }
+            Self::LengthPrefixed(_) => match self.decode(src)? {
+                Some(message) => Ok(Some(message)),
+                None if src.is_empty() => Ok(None),
+                None => Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed in the middle of a frame",
+                )
+                .into()),
+            },
+        }
+    }
+}
+
+fn too_long(error: io::Error, max: usize) -> FrameError {
+    match error.get_ref() {
+        Some(inner) if inner.is::<LengthDelimitedCodecError>() => FrameError::TooLong(max),
+        _ => FrameError::Io(error),
+    }
+}
+
+/// The length prefix for a payload of `len` bytes.
+pub fn length_prefix(len: usize) -> Bytes {
+    let len = u32::try_from(len).expect("replies are far smaller than 4 GiB");
+    Bytes::copy_from_slice(&len.to_be_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn frame(payload: &[u8]) -> Vec<u8> {
+        let mut frame = (payload.len() as u32).to_be_bytes().to_vec();
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn length_prefixed_payloads_may_contain_anything() {
+        let mut codec = MessageCodec::new(Framing::LengthPrefixed, 64);
+        let mut src = BytesMut::from(&frame(b"a\nb\0c")[..]);
+        // Only half of the second frame has arrived
+        src.extend_from_slice(&frame(b"next")[..5]);
+
+        let message = codec.decode(&mut src).unwrap().unwrap();
+        assert_eq!(message.payload, &b"a\nb\0c"[..]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        assert!(matches!(codec.decode_eof(&mut src), Err(FrameError::Io(_))));
+    }
+
+    #[test]
+    fn an_oversized_length_is_refused() {
+        let mut codec = MessageCodec::new(Framing::LengthPrefixed, 16);
+        let mut src = BytesMut::from(&frame(&[b'x'; 17])[..]);
+        assert!(matches!(codec.decode(&mut src), Err(FrameError::TooLong(16))));
+    }
+
+    /// What a connection's read loop gets out of `input` arriving in reads
+    /// of `chunk` bytes: the messages, and how many were refused as too long.
+    /// Stops at the first error the codec cannot recover from.
+    fn decode_in_chunks(
+        framing: Framing,
+        max: usize,
+        input: &[u8],
+        chunk: usize,
+    ) -> (Vec<Bytes>, usize) {
+        let mut codec = MessageCodec::new(framing, max);
+        let mut src = BytesMut::new();
+        let mut messages = Vec::new();
+        let mut refused = 0;
+        for read in input.chunks(chunk) {
+            src.extend_from_slice(read);
+            loop {
+                match codec.decode(&mut src) {
+                    Ok(Some(message)) => messages.push(message.payload),
+                    Ok(None) => break,
+                    Err(FrameError::TooLong(_)) if codec.recovers_from_too_long() => refused += 1,
+                    Err(_) => return (messages, refused + 1),
+                }
+            }
+            // However the input looks, at most one unfinished message waits
+            // in the buffer: a length prefix and up to `max` bytes
+            assert!(src.len() <= max + 4, "{} bytes buffered", src.len());
+        }
+        match codec.decode_eof(&mut src) {
+            Ok(Some(message)) => messages.push(message.payload),
+            Ok(None) => {}
+            Err(_) => refused += 1,
+        }
+        (messages, refused)
+    }
+
+    fn framing() -> impl Strategy<Value = Framing> {
+        prop_oneof![Just(Framing::Lines), Just(Framing::LengthPrefixed)]
+    }
+
+    proptest! {
+        #[test]
+        fn any_input_decodes_into_messages_within_the_limit(
+            framing in framing(),
+            input in proptest::collection::vec(any::<u8>(), 0..512),
+            max in 1..64usize,
+            chunk in 1..64usize,
+        ) {
+            let (messages, _) = decode_in_chunks(framing, max, &input, chunk);
+            for message in messages {
+                prop_assert!(message.len() <= max);
+            }
+        }
+
+        #[test]
+        fn how_the_input_is_split_into_reads_does_not_matter(
+            framing in framing(),
+            input in proptest::collection::vec(any::<u8>(), 0..512),
+            max in 1..64usize,
+            chunk in 1..64usize,
+        ) {
+            let whole = decode_in_chunks(framing, max, &input, input.len().max(1));
+            prop_assert_eq!(decode_in_chunks(framing, max, &input, chunk), whole);
+        }
+
+        #[test]
+        fn lines_come_out_as_they_went_in(
+            lines in proptest::collection::vec("[^\n]{0,32}", 0..8),
+        ) {
+            let input: String = lines.iter().map(|line| format!("{}\n", line)).collect();
+            let (messages, refused) = decode_in_chunks(Framing::Lines, 128, input.as_bytes(), 7);
+            prop_assert_eq!(refused, 0);
+            let expected: Vec<Bytes> = lines.into_iter().map(Bytes::from).collect();
+            prop_assert_eq!(messages, expected);
+        }
+    }
+}
@@ -0,0 +1,205 @@
+//! One wait, written three ways: until the request counter has passed its
+//! milestones.
+//!
+//! The server's state watcher waits until 3 requests, then 5, have been
+//! served. The same wait is written here as:
+//!
+//! 1. [`WaitForStateMachine`], a hand-written `Future`. Its states are an
+//!    enum that `poll` moves through by hand, and what one state hands to
+//!    the next (the note) is a field of the variant. Nothing tells it the
+//!    counter changed, so it asks to be polled again right away every time:
+//!    it never blocks a thread, but it keeps one busy.
+//! 2. [`wait_for_milestones`], an `async fn` over
+//!    [`watch::Receiver::changed`]. The compiler turns it into the same
+//!    kind of enum, one state per `.await`, and the note is a local
+//!    variable. The channel wakes it only when a request was served.
+//! 3. [`milestones`], a `Stream` of [`Milestone`] events over the same
+//!    channel. The futures report once, at the end; the stream reports
+//!    every milestone as it is passed, and the caller may stop listening
+//!    at any point.
+//!
+//! None of them does any work of its own: they observe the counter, and
+//! complete because clients sent requests.
+
+use crate::state::State;
+use futures_util::stream;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::sync::watch;
+use tokio_stream::Stream;
+
+/// The request counts waited for: a midway point, then the end.
+pub const MILESTONES: [i32; 2] = [3, 5];
+
+/// What the futures complete with.
+fn report(note: &str) -> String {
+    format!("Reached {} total requests (note from mid-state: {})", MILESTONES[1], note)
+}
+
+/// WaitForStateMachine is a custom Future that completes
+/// when the shared request counter reaches a terminal state.
+///
+/// This demonstrates a Future that:
+/// - does NOT do work by itself
+/// - observes real application state
+/// - becomes ready when an external condition is met
+pub struct WaitForStateMachine {
+    state: Arc<State>,
+    machine: CountState,
+}
+
+enum CountState {
+    Start,
+    Mid { note: String },
+    Done,
+}
+
+impl WaitForStateMachine {
+    pub fn new(state: Arc<State>) -> Self {
+        Self {
+            state,
+            machine: CountState::Start,
+        }
+    }
+}
+
+impl Future for WaitForStateMachine {
+    type Output = String;
+
+    fn poll(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let current = this.state.count();
+
+        match &mut this.machine {
+            CountState::Start => {
+                if current >= MILESTONES[0] {
+                    this.machine = CountState::Mid {
+                        note: format!("reached {} requests", MILESTONES[0]),
+                    };
+                }
+                // ❗enqueue current task again, not for production!
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+            CountState::Mid { note } => {
+                if current >= MILESTONES[1] {
+                    let output = report(note);
+                    this.machine = CountState::Done;
+                    Poll::Ready(output)
+                } else {
+                    // ❗enqueue current task again, not for production!
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                }
+            }
+            CountState::Done => Poll::Pending,
+        }
+    }
+}
+
+/// The same wait as [`WaitForStateMachine`], as an `async fn`; `count` is
+/// [`State::watch_count`].
+///
+/// Like the hand-written future, it never completes if the counter stops
+/// before the last milestone, here because the state was dropped.
+pub async fn wait_for_milestones(mut count: watch::Receiver<i32>) -> String {
+    if reach(&mut count, MILESTONES[0]).await.is_none() {
+        return std::future::pending().await;
+    }
+    let note = format!("reached {} requests", MILESTONES[0]);
+    if reach(&mut count, MILESTONES[1]).await.is_none() {
+        return std::future::pending().await;
+    }
+    report(&note)
+}
+
+/// A milestone the counter has passed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Milestone {
+    pub requests: i32,
+}
+
+/// Every milestone, as the counter passes it; `count` is
+/// [`State::watch_count`].
+///
+/// The stream ends after the last milestone, or early if the state is
+/// dropped. A count that skips past several milestones at once (the
+/// channel only keeps the latest value) still yields each of them.
+pub fn milestones(count: watch::Receiver<i32>) -> impl Stream<Item = Milestone> + Send {
+    stream::unfold((count, 0), |(mut count, next)| async move {
+        let &requests = MILESTONES.get(next)?;
+        reach(&mut count, requests).await?;
+        Some((Milestone { requests }, (count, next + 1)))
+    })
+}
+
+/// Waits until the count is at least `target`; `None` if it never will be.
+async fn reach(count: &mut watch::Receiver<i32>, target: i32) -> Option<()> {
+    while *count.borrow_and_update() < target {
+        count.changed().await.ok()?;
+    }
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::InMemorySink;
+    use std::time::Duration;
+    use tokio_stream::StreamExt;
+
+    fn state() -> Arc<State> {
+        Arc::new(State::new(Arc::new(InMemorySink::default())))
+    }
+
+    /// Serves `n` requests, letting the waiting task run after each.
+    async fn serve(state: &State, n: usize) {
+        for _ in 0..n {
+            state.increment();
+            tokio::task::yield_now().await;
+        }
+    }
+
+    const REPORT: &str = "Reached 5 total requests (note from mid-state: reached 3 requests)";
+
+    #[tokio::test]
+    async fn the_hand_written_future_completes_at_the_last_milestone() {
+        let state = state();
+        let mut watcher = tokio::spawn(WaitForStateMachine::new(state.clone()));
+        serve(&state, 4).await;
+        assert!(tokio::time::timeout(Duration::from_millis(10), &mut watcher).await.is_err());
+        serve(&state, 1).await;
+        assert_eq!(watcher.await.unwrap(), REPORT);
+    }
+
+    #[tokio::test]
+    async fn the_async_fn_completes_at_the_last_milestone() {
+        let state = state();
+        let mut watcher = tokio::spawn(wait_for_milestones(state.watch_count()));
+        serve(&state, 4).await;
+        assert!(tokio::time::timeout(Duration::from_millis(10), &mut watcher).await.is_err());
+        serve(&state, 1).await;
+        assert_eq!(watcher.await.unwrap(), REPORT);
+    }
+
+    #[tokio::test]
+    async fn the_stream_yields_every_milestone_once() {
+        let state = state();
+        let mut events = Box::pin(milestones(state.watch_count()));
+        serve(&state, 3).await;
+        assert_eq!(events.next().await, Some(Milestone { requests: 3 }));
+        serve(&state, 3).await;
+        assert_eq!(events.next().await, Some(Milestone { requests: 5 }));
+        assert_eq!(events.next().await, None);
+
+        // Passing both before the stream is polled still yields both
+        let events = milestones(state.watch_count());
+        let passed: Vec<_> = events.map(|milestone| milestone.requests).collect().await;
+        assert_eq!(passed, MILESTONES);
+    }
+}
@@ -0,0 +1,246 @@
+//! The counter and echo as a gRPC service, with the `grpc` feature.
+//!
+//! `proto/counter.proto` defines the service; `build.rs` turns it into the
+//! [`proto`] module with tonic. It runs on its own port (`--grpc <addr>`),
+//! next to the raw TCP listener, and shares its `State`: an `Echo` counts
+//! as a request just like a line on the text protocol, and `GetCount` and
+//! `WatchCount` read the same counter.
+//!
+//! `WatchCount` is server streaming. Each call subscribes to the counter's
+//! `watch` channel, so a client gets the current count and then every
+//! change; a watcher that falls behind skips to the latest value instead
+//! of queueing old ones. The stream ends when the server drains, which
+//! lets tonic's graceful shutdown finish.
+//!
+//! The TLS listener can serve the service too: connections that negotiate
+//! `grpc` with ALPN are passed on through a [`Handover`].
+
+use crate::logger::{LogMessage, LogTx};
+use crate::metrics;
+use crate::readiness::{self, Phase};
+use crate::state::State;
+use crate::task;
+use futures_util::StreamExt;
+use proto::counter_server::{Counter, CounterServer};
+use proto::{CountReply, EchoReply, EchoRequest, GetCountRequest, WatchCountRequest};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{self, AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, watch};
+use tokio_stream::Stream;
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream, WatchStream};
+use tonic::transport::server::{Connected, TcpConnectInfo};
+use tonic::{Request, Response, Status};
+
+/// How many bytes the pipe to a handed-over connection buffers each way.
+const HANDOVER_BUFFER: usize = 64 * 1024;
+
+/// The messages and the client and server stubs generated from the proto.
+pub mod proto {
+    tonic::include_proto!("tokio_examples");
+}
+
+/// Implements the `Counter` service on the server's state.
+pub struct CounterService {
+    state: Arc<State>,
+    log_tx: LogTx,
+    phase: watch::Receiver<Phase>,
+}
+
+impl CounterService {
+    pub fn new(state: Arc<State>, log_tx: LogTx, phase: watch::Receiver<Phase>) -> Self {
+        Self { state, log_tx, phase }
+    }
+}
+
+type CountStream = Pin<Box<dyn Stream<Item = Result<CountReply, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl Counter for CounterService {
+    async fn echo(&self, request: Request<EchoRequest>) -> Result<Response<EchoReply>, Status> {
+        self.state.metrics.incr(metrics::MESSAGES_RECEIVED, 1);
+        if let Some(peer) = request.remote_addr() {
+            self.state.peers.request(peer.ip());
+        }
+        let message = request.into_inner().message;
+        self.log_tx.send(LogMessage::info(format!("grpc: {}", message))).await;
+        let request = self.state.increment();
+        Ok(Response::new(EchoReply { message, request }))
+    }
+
+    async fn get_count(
+        &self,
+        _: Request<GetCountRequest>,
+    ) -> Result<Response<CountReply>, Status> {
+        Ok(Response::new(CountReply { count: self.state.count() }))
+    }
+
+    type WatchCountStream = CountStream;
+
+    async fn watch_count(
+        &self,
+        _: Request<WatchCountRequest>,
+    ) -> Result<Response<Self::WatchCountStream>, Status> {
+        let mut phase = self.phase.clone();
+        let draining = async move { readiness::wait_draining(&mut phase).await };
+        let counts = WatchStream::new(self.state.watch_count())
+            .map(|count| Ok(CountReply { count }))
+            .take_until(draining);
+        Ok(Response::new(Box::pin(counts)))
+    }
+}
+
+/// Serves the service on `listener` until the server drains.
+pub async fn serve(
+    listener: TcpListener,
+    state: Arc<State>,
+    log_tx: LogTx,
+    phase: watch::Receiver<Phase>,
+) {
+    serve_incoming(TcpListenerStream::new(listener), state, log_tx, phase).await
+}
+
+/// Serves the service on every connection `incoming` yields, until the server drains.
+async fn serve_incoming<I, T>(
+    incoming: I,
+    state: Arc<State>,
+    log_tx: LogTx,
+    phase: watch::Receiver<Phase>,
+) where
+    I: Stream<Item = io::Result<T>>,
+    T: AsyncRead + AsyncWrite + Connected + Unpin + Send + 'static,
+{
+    let service = CounterService::new(state, log_tx.clone(), phase.clone());
+    let mut draining = phase;
+    let result = tonic::transport::Server::builder()
+        .add_service(CounterServer::new(service))
+        .serve_with_incoming_shutdown(incoming, async move {
+            readiness::wait_draining(&mut draining).await
+        })
+        .await;
+    if let Err(e) = result {
+        log_tx.send(LogMessage::error(format!("gRPC server failed: {}", e))).await;
+    }
+}
+
+/// Passes connections accepted elsewhere to the service.
+///
+/// tonic only serves connections it takes from a stream, so each one is
+/// bridged through an in-process pipe: tonic is sent one end, and
+/// [`serve`](Handover::serve) copies between the other end and the client
+/// until both sides are done. The caller keeps the client's connection,
+/// with its registry entry and its byte counts.
+#[derive(Clone)]
+pub struct Handover {
+    connections: mpsc::Sender<Piped>,
+}
+
+impl Handover {
+    /// Starts a gRPC server that serves only what is handed over; it stops
+    /// when the server drains.
+    pub fn start(state: Arc<State>, log_tx: LogTx, phase: watch::Receiver<Phase>) -> Handover {
+        let (connections, piped) = mpsc::channel(16);
+        let incoming = ReceiverStream::new(piped).map(Ok);
+        task::spawn("grpc handover", serve_incoming(incoming, state, log_tx, phase));
+        Handover { connections }
+    }
+
+    /// Serves `client` as a gRPC connection from `peer`, until it closes.
+    pub async fn serve<T>(&self, mut client: T, peer: SocketAddr) -> io::Result<()>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (mut pipe, io) = io::duplex(HANDOVER_BUFFER);
+        self.connections
+            .send(Piped { io, peer })
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::NotConnected, "gRPC server stopped"))?;
+        io::copy_bidirectional(&mut client, &mut pipe).await.map(|_| ())
+    }
+}
+
+/// tonic's end of a handed-over connection. It reports the client's
+/// address, so `remote_addr` works as for a TCP connection.
+struct Piped {
+    io: DuplexStream,
+    peer: SocketAddr,
+}
+
+impl Connected for Piped {
+    type ConnectInfo = TcpConnectInfo;
+
+    fn connect_info(&self) -> TcpConnectInfo {
+        TcpConnectInfo { local_addr: None, remote_addr: Some(self.peer) }
+    }
+}
+
+impl AsyncRead for Piped {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Piped {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logger::{self, LogOverflow};
+    use crate::metrics::{InMemorySink, Metrics};
+    use proto::counter_client::CounterClient;
+
+    #[tokio::test]
+    async fn echo_counts_and_watchers_see_it() {
+        let metrics: Metrics = Arc::new(InMemorySink::default());
+        let state = Arc::new(State::new(metrics.clone()));
+        let (log_tx, _log) = logger::channel(16, &LogOverflow::Drop, metrics);
+        let (phase_tx, phase) = watch::channel(Phase::Ready);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve(listener, state.clone(), log_tx, phase));
+
+        let mut client = CounterClient::connect(format!("http://{}", addr)).await.unwrap();
+        let mut counts = client.watch_count(WatchCountRequest {}).await.unwrap().into_inner();
+        assert_eq!(counts.message().await.unwrap().unwrap().count, 0);
+
+        let echo = EchoRequest { message: "hello".to_string() };
+        let reply = client.echo(echo).await.unwrap().into_inner();
+        assert_eq!((reply.message.as_str(), reply.request), ("hello", 1));
+        // A request on the text protocol moves the same counter
+        state.increment();
+        let count = client.get_count(GetCountRequest {}).await.unwrap().into_inner().count;
+        assert_eq!(count, 2);
+
+        // The watcher skips to the latest value, never back
+        let seen = counts.message().await.unwrap().unwrap().count;
+        assert!(seen == 1 || seen == 2, "{}", seen);
+
+        // Draining ends the stream and the server
+        phase_tx.send(Phase::Draining).unwrap();
+        while counts.message().await.unwrap().is_some() {}
+        server.await.unwrap();
+    }
+}
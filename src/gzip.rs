@@ -0,0 +1,128 @@
+//! `GzipEncoder`: an `AsyncWrite` layer that gzips everything written
+//! through it before passing it on to the inner writer.
+//!
+//! The compression itself is done by `flate2`'s synchronous encoder into an
+//! in-memory buffer, which is cheap and never blocks. Only handing the
+//! compressed bytes to the inner writer can wait, and that is done with the
+//! inner writer's own `poll_write`, so the layer fits over a socket like
+//! any other writer. The buffer is emptied before more input is taken, so
+//! a slow client pushes back on the writer instead of growing the buffer.
+//!
+//! A gzip member ends with a trailer (CRC-32 and length), so a client can
+//! tell where it ends without a length header. `finish` writes the trailer
+//! and leaves the inner writer open for whatever comes next.
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use std::io::{self, Write};
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use tokio::io::AsyncWrite;
+
+pub struct GzipEncoder<W> {
+    inner: W,
+    // Compresses into its `Vec`; `sent` bytes of it have reached `inner`
+    encoder: GzEncoder<Vec<u8>>,
+    sent: usize,
+    total_out: u64,
+}
+
+impl<W: AsyncWrite + Unpin> GzipEncoder<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            encoder: GzEncoder::new(Vec::new(), Compression::fast()),
+            sent: 0,
+            total_out: 0,
+        }
+    }
+
+    /// Compressed bytes written to the inner writer so far.
+    pub fn total_out(&self) -> u64 {
+        self.total_out
+    }
+
+    /// Ends the gzip member, without shutting down the inner writer.
+    pub async fn finish(&mut self) -> io::Result<()> {
+        std::future::poll_fn(|cx| self.poll_finish(cx)).await
+    }
+
+    fn poll_finish(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Writes the trailer into the buffer; repeated calls do nothing
+        self.encoder.try_finish()?;
+        self.poll_drain(cx)
+    }
+
+    /// Writes the buffered compressed bytes to the inner writer.
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            let pending = &self.encoder.get_ref()[self.sent..];
+            if pending.is_empty() {
+                break;
+            }
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, pending))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.sent += n;
+            self.total_out += n as u64;
+        }
+        self.encoder.get_mut().clear();
+        self.sent = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for GzipEncoder<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        // Earlier output goes out first: this is where backpressure happens
+        ready!(this.poll_drain(cx))?;
+        this.encoder.write_all(buf)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        // A sync flush makes everything written so far decodable
+        this.encoder.flush()?;
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_finish(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn a_finished_member_decodes_and_the_writer_stays_usable() {
+        let reply = "OK ".repeat(1000);
+        let mut out = Vec::new();
+        let mut encoder = GzipEncoder::new(&mut out);
+        encoder.write_all(reply.as_bytes()).await.unwrap();
+        encoder.finish().await.unwrap();
+        let compressed = encoder.total_out() as usize;
+        assert!(compressed < reply.len() / 10);
+        out.extend_from_slice(b"next\n");
+
+        let mut decoder = GzDecoder::new(&out[..]);
+        let mut decoded = String::new();
+        decoder.read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, reply);
+        assert_eq!(&out[compressed..], b"next\n");
+    }
+}
@@ -0,0 +1,188 @@
+//! The optional greeting and `HELLO` handshake at the start of a connection.
+//!
+//! With the handshake enabled, the server greets every client with a
+//! banner naming the protocol version, the modes it offers and the
+//! client's session (see [`crate::session`]):
+//!
+//! ```text
+//! WELCOME tokio-examples/1 MODES ECHO KV JSON OPTIONS GZIP SESSION 5f0c2d9e8a71b346
+//! HELLO KV
+//! OK: mode KV
+//! ```
+//!
+//! Until the client has picked a mode with `HELLO <mode>`, nothing else
+//! is served. The mode then narrows (or changes) what the connection does.
+//! Options may follow the mode: `GZIP` asks for long replies to be
+//! gzipped, see [`crate::outbox`], and `FORMAT=<format>` picks how plain
+//! messages are answered, see [`crate::template`].
+
+use crate::session::SessionId;
+use crate::template::Format;
+use std::fmt;
+
+/// Version of the text protocol, announced in the banner.
+pub const VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Every message is answered with the response template; no commands.
+    Echo,
+    /// Only the key-value commands.
+    Kv,
+    /// The JSON protocol, see [`crate::protocol`].
+    Json,
+}
+
+impl Mode {
+    const ALL: [Mode; 3] = [Mode::Echo, Mode::Kv, Mode::Json];
+
+    /// A mode by name, in any case.
+    pub fn parse(name: &str) -> Option<Mode> {
+        Self::ALL.into_iter().find(|mode| name.eq_ignore_ascii_case(&mode.to_string()))
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Mode::Echo => "ECHO",
+            Mode::Kv => "KV",
+            Mode::Json => "JSON",
+        };
+        f.write_str(name)
+    }
+}
+
+/// The first line a client receives; it names the client's session, too.
+pub fn banner(session: SessionId) -> String {
+    let modes: Vec<String> = Mode::ALL.iter().map(Mode::to_string).collect();
+    let formats: Vec<String> = Format::ALL.iter().map(Format::to_string).collect();
+    format!(
+        "WELCOME tokio-examples/{} MODES {} OPTIONS GZIP FORMAT={} SESSION {}\n",
+        VERSION,
+        modes.join(" "),
+        formats.join(","),
+        session,
+    )
+}
+
+/// Where a connection is in the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Handshake {
+    /// Greeted, waiting for `HELLO`.
+    AwaitingHello,
+    /// Serving; `mode` is `None` when the handshake is disabled and
+    /// everything is allowed.
+    Done { mode: Option<Mode>, gzip: bool, format: Format },
+}
+
+impl Handshake {
+    pub fn new(enabled: bool) -> Self {
+        if enabled {
+            Handshake::AwaitingHello
+        } else {
+            Handshake::Done { mode: None, gzip: false, format: Format::Plain }
+        }
+    }
+
+    /// The chosen mode, if any.
+    pub fn mode(&self) -> Option<Mode> {
+        match self {
+            Handshake::AwaitingHello => None,
+            Handshake::Done { mode, .. } => *mode,
+        }
+    }
+
+    /// Whether the client asked for gzipped replies.
+    pub fn gzip(&self) -> bool {
+        matches!(self, Handshake::Done { gzip: true, .. })
+    }
+
+    /// How the client wants plain messages answered.
+    pub fn format(&self) -> Format {
+        match self {
+            Handshake::AwaitingHello => Format::Plain,
+            Handshake::Done { format, .. } => *format,
+        }
+    }
+
+    /// Handles a message received while awaiting `HELLO` and returns the reply.
+    ///
+    /// A valid `HELLO <mode> [GZIP] [FORMAT=<format>]` moves on to `Done`;
+    /// anything else keeps waiting.
+    pub fn hello(&mut self, input: &str) -> String {
+        let mut words = input.split_whitespace();
+        if !words.next().is_some_and(|word| word.eq_ignore_ascii_case("HELLO")) {
+            return "ERR: choose a mode first: HELLO <mode>\n".to_string();
+        }
+        let usage = "ERR: usage: HELLO <mode> [GZIP] [FORMAT=<format>]\n".to_string();
+        let Some(name) = words.next() else { return usage };
+        let (mut gzip, mut format) = (false, None);
+        for option in words {
+            let upper = option.to_ascii_uppercase();
+            match upper.strip_prefix("FORMAT=") {
+                _ if upper == "GZIP" && !gzip => gzip = true,
+                Some(value) if format.is_none() => match Format::parse(value) {
+                    Some(picked) => format = Some(picked),
+                    None => return format!("ERR: unknown format {}\n", value),
+                },
+                _ => return usage,
+            }
+        }
+        let format = format.unwrap_or_default();
+        match Mode::parse(name) {
+            Some(mode) => {
+                *self = Handshake::Done { mode: Some(mode), gzip, format };
+                let gzip = if gzip { " GZIP" } else { "" };
+                let format = match format {
+                    Format::Plain => String::new(),
+                    format => format!(" FORMAT={}", format),
+                };
+                format!("OK: mode {}{}{}\n", mode, gzip, format)
+            }
+            None => format!("ERR: unknown mode {}, see the banner for the supported ones\n", name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_banner_lists_every_mode() {
+        let session = SessionId::parse("ff").unwrap();
+        let expected = "WELCOME tokio-examples/1 MODES ECHO KV JSON OPTIONS GZIP \
+                        FORMAT=PLAIN,JSON,COMPACT SESSION 00000000000000ff\n";
+        assert_eq!(banner(session), expected);
+    }
+
+    #[test]
+    fn only_hello_with_a_known_mode_completes_the_handshake() {
+        let mut handshake = Handshake::new(true);
+        assert!(handshake.hello("GET color").starts_with("ERR: choose a mode"));
+        assert!(handshake.hello("HELLO CHAT").starts_with("ERR: unknown mode CHAT"));
+        assert_eq!(handshake, Handshake::AwaitingHello);
+
+        assert_eq!(handshake.hello("hello kv"), "OK: mode KV\n");
+        assert_eq!(handshake.mode(), Some(Mode::Kv));
+        assert!(!handshake.gzip());
+
+        let mut handshake = Handshake::new(true);
+        assert!(handshake.hello("HELLO KV ZIP").starts_with("ERR: usage"));
+        assert_eq!(handshake.hello("HELLO ECHO gzip"), "OK: mode ECHO GZIP\n");
+        assert!(handshake.gzip());
+        assert_eq!(handshake.format(), Format::Plain);
+    }
+
+    #[test]
+    fn hello_options_come_in_any_order() {
+        let mut handshake = Handshake::new(true);
+        assert_eq!(handshake.hello("HELLO ECHO FORMAT=xml"), "ERR: unknown format XML\n");
+        assert!(handshake.hello("HELLO ECHO GZIP GZIP").starts_with("ERR: usage"));
+        let reply = handshake.hello("HELLO ECHO format=compact GZIP");
+        assert_eq!(reply, "OK: mode ECHO GZIP FORMAT=COMPACT\n");
+        assert_eq!(handshake.format(), Format::Compact);
+        assert!(handshake.gzip());
+    }
+}
@@ -0,0 +1,384 @@
+//! Health checks of the services the server depends on.
+//!
+//! Independent of proxy mode: any number of targets can be listed, and a
+//! background task probes all of them at once, every interval:
+//!
+//! ```text
+//! tcp://db:5432            -> the port accepts a connection
+//! db:5432                  -> the same
+//! http://api:8080/healthz  -> a GET answered with a 2xx or 3xx status
+//! ```
+//!
+//! A probe that takes longer than the timeout fails. One failure does not
+//! make a target down: it takes `failures` of them in a row, and a single
+//! success brings it back up. Until either happens it is `unknown`.
+//!
+//! The statuses are published through a `watch` channel in [`State`], so
+//! readers always see the latest round without waiting for it: `STATS`
+//! lists them as `HEALTH` lines, and the HTTP endpoint serves them as
+//! JSON on `/health`.
+//!
+//! [`State`]: crate::state::State
+
+use crate::clock::Clock;
+use crate::logger::{LogMessage, LogTx};
+use crate::metrics::{self, Metrics};
+use crate::readiness::{self, Phase};
+use crate::supervise;
+use futures_util::stream::FuturesUnordered;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio_stream::StreamExt;
+
+/// Longest status line an HTTP probe reads.
+const MAX_STATUS_LINE: u64 = 1024;
+
+/// What is probed, and how.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    /// `host:port`.
+    pub addr: String,
+    /// `None` for a TCP probe; the path to `GET` for an HTTP one.
+    pub path: Option<String>,
+}
+
+impl Target {
+    /// Parses `tcp://host:port`, `http://host:port/path` or `host:port`.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let invalid = || {
+            format!("invalid health check target: {} (expected tcp:// or http://host:port)", text)
+        };
+        let (addr, path) = match text.split_once("://") {
+            Some(("tcp", addr)) => (addr, None),
+            Some(("http", rest)) => match rest.find('/') {
+                Some(slash) => (&rest[..slash], Some(rest[slash..].to_string())),
+                None => (rest, Some("/".to_string())),
+            },
+            Some(_) => return Err(invalid()),
+            None => (text, None),
+        };
+        let (host, port) = addr.rsplit_once(':').ok_or_else(invalid)?;
+        if host.is_empty() || port.parse::<u16>().is_err() {
+            return Err(invalid());
+        }
+        Ok(Self { addr: addr.to_string(), path })
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.path {
+            Some(path) => write!(f, "http://{}{}", self.addr, path),
+            None => write!(f, "tcp://{}", self.addr),
+        }
+    }
+}
+
+/// The targets, how often they are probed, and when one counts as down.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checks {
+    pub targets: Vec<Target>,
+    pub interval: Duration,
+    /// How long a probe may take.
+    pub timeout: Duration,
+    /// Failed probes in a row that make a target down.
+    pub failures: u32,
+}
+
+impl Default for Checks {
+    fn default() -> Self {
+        Self {
+            targets: Vec::new(),
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(2),
+            failures: 3,
+        }
+    }
+}
+
+/// What the probes of a target say so far.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    /// Not probed yet, or failing, but not enough times in a row.
+    Unknown,
+    Up,
+    Down,
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Condition::Unknown => "unknown",
+            Condition::Up => "up",
+            Condition::Down => "down",
+        })
+    }
+}
+
+/// One target's entry in the published statuses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Status {
+    pub target: Target,
+    pub condition: Condition,
+    /// Failed probes since the last success.
+    pub failures: u32,
+    /// How long the last successful probe took.
+    pub latency: Option<Duration>,
+    /// Why the last probe failed, until one succeeds.
+    pub error: Option<String>,
+}
+
+impl Status {
+    pub fn new(target: Target) -> Self {
+        Self { target, condition: Condition::Unknown, failures: 0, latency: None, error: None }
+    }
+
+    /// Counts the result of a probe in. Returns the new condition if it changed.
+    pub fn record(
+        &mut self,
+        result: Result<Duration, String>,
+        threshold: u32,
+    ) -> Option<Condition> {
+        let before = self.condition;
+        match result {
+            Ok(latency) => {
+                self.failures = 0;
+                self.latency = Some(latency);
+                self.error = None;
+                self.condition = Condition::Up;
+            }
+            Err(error) => {
+                self.failures += 1;
+                self.error = Some(error);
+                if self.failures >= threshold {
+                    self.condition = Condition::Down;
+                }
+            }
+        }
+        (self.condition != before).then_some(self.condition)
+    }
+}
+
+/// The `HEALTH` lines of `STATS`, one per target.
+pub fn stat_lines(statuses: &[Status]) -> String {
+    let mut lines = String::new();
+    for status in statuses {
+        lines.push_str(&format!(
+            "HEALTH {} {} failures={}",
+            status.target, status.condition, status.failures,
+        ));
+        if let Some(latency) = status.latency {
+            lines.push_str(&format!(" latency_ms={}", latency.as_millis()));
+        }
+        lines.push('\n');
+    }
+    lines
+}
+
+/// Spawns the task that probes `checks.targets` until the server drains,
+/// publishing every result to `statuses`. If it panics, it is restarted
+/// and picks up from the statuses published so far.
+pub fn start(
+    checks: &Checks,
+    statuses: watch::Sender<Vec<Status>>,
+    log_tx: LogTx,
+    metrics: Metrics,
+    clock: Arc<dyn Clock>,
+    phase: watch::Receiver<Phase>,
+) {
+    statuses.send_replace(checks.targets.iter().cloned().map(Status::new).collect());
+    let checks = checks.clone();
+    supervise::spawn("health checks", metrics.clone(), phase.clone(), move || {
+        let (checks, statuses, log_tx) = (checks.clone(), statuses.clone(), log_tx.clone());
+        let (metrics, clock, phase) = (metrics.clone(), clock.clone(), phase.clone());
+        probe_all(checks, statuses, log_tx, metrics, clock, phase)
+    });
+}
+
+async fn probe_all(
+    checks: Checks,
+    statuses: watch::Sender<Vec<Status>>,
+    log_tx: LogTx,
+    metrics: Metrics,
+    clock: Arc<dyn Clock>,
+    mut phase: watch::Receiver<Phase>,
+) {
+    let mut interval = clock.interval(checks.interval);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = readiness::wait_draining(&mut phase) => return,
+        }
+
+        // All at once; each result is published as soon as it is in
+        let mut probes: FuturesUnordered<_> = checks
+            .targets
+            .iter()
+            .enumerate()
+            .map(|(index, target)| {
+                let clock = clock.clone();
+                async move { (index, probe(target, checks.timeout, &*clock).await) }
+            })
+            .collect();
+        while let Some((index, result)) = probes.next().await {
+            metrics.incr(metrics::HEALTH_PROBES, 1);
+            if result.is_err() {
+                metrics.incr(metrics::HEALTH_PROBES_FAILED, 1);
+            }
+            let mut changed = None;
+            statuses.send_modify(|statuses| {
+                let status = &mut statuses[index];
+                let before = status.condition;
+                if status.record(result, checks.failures).is_some() {
+                    changed = Some((before, status.clone()));
+                }
+            });
+            if let Some((before, status)) = changed {
+                report(before, &status, &metrics, &log_tx).await;
+            }
+        }
+    }
+}
+
+/// Logs a target going up or down, and keeps the gauge of down targets.
+async fn report(before: Condition, status: &Status, metrics: &Metrics, log_tx: &LogTx) {
+    if before == Condition::Down {
+        metrics.adjust(metrics::HEALTH_TARGETS_DOWN, -1);
+    }
+    let message = match status.condition {
+        Condition::Down => {
+            metrics.adjust(metrics::HEALTH_TARGETS_DOWN, 1);
+            let error = status.error.as_deref().unwrap_or("failed");
+            LogMessage::error(format!("health check: {} is down: {}", status.target, error))
+        }
+        // A target only ever leaves `unknown`
+        _ => LogMessage::info(format!("health check: {} is up", status.target)),
+    };
+    log_tx.send(message).await;
+}
+
+/// Probes `target` once. Returns how long it took, or why it failed.
+async fn probe(target: &Target, timeout: Duration, clock: &dyn Clock) -> Result<Duration, String> {
+    let started = clock.now();
+    let probe = async {
+        let mut socket = TcpStream::connect(&target.addr).await.map_err(|e| e.to_string())?;
+        if let Some(path) = &target.path {
+            http_get(&mut socket, &target.addr, path).await?;
+        }
+        Ok(())
+    };
+    tokio::select! {
+        result = probe => result.map(|()| clock.now() - started),
+        _ = clock.sleep(timeout) => Err(format!("no answer in {}ms", timeout.as_millis())),
+    }
+}
+
+/// Sends a `GET` and checks the status of the answer.
+async fn http_get(socket: &mut TcpStream, host: &str, path: &str) -> Result<(), String> {
+    let request = format!("GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", path, host);
+    socket.write_all(request.as_bytes()).await.map_err(|e| e.to_string())?;
+    let mut line = String::new();
+    let mut reader = BufReader::new(socket.take(MAX_STATUS_LINE));
+    reader.read_line(&mut line).await.map_err(|e| e.to_string())?;
+    // "HTTP/1.1 200 OK"
+    let status = line.split_whitespace().nth(1).and_then(|code| code.parse::<u16>().ok());
+    match status {
+        Some(200..=399) => Ok(()),
+        Some(code) => Err(format!("HTTP status {}", code)),
+        None => Err("not an HTTP response".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::logger::{self, LogOverflow};
+    use crate::metrics::InMemorySink;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn targets_are_tcp_unless_they_say_http() {
+        let tcp = Target::parse("db:5432").unwrap();
+        assert_eq!(tcp, Target::parse("tcp://db:5432").unwrap());
+        assert_eq!(tcp.to_string(), "tcp://db:5432");
+        let http = Target::parse("http://api:8080/healthz").unwrap();
+        assert_eq!(http.path.as_deref(), Some("/healthz"));
+        assert_eq!(http.to_string(), "http://api:8080/healthz");
+        assert_eq!(Target::parse("http://api:80").unwrap().to_string(), "http://api:80/");
+        for invalid in ["db", "db:http", ":5432", "https://api:443/"] {
+            assert!(Target::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn a_target_is_down_after_enough_failures_in_a_row() {
+        let mut status = Status::new(Target::parse("db:5432").unwrap());
+        let failed = || Err("connection refused".to_string());
+        assert_eq!(status.record(failed(), 2), None);
+        assert_eq!(status.condition, Condition::Unknown);
+        assert_eq!(status.record(failed(), 2), Some(Condition::Down));
+        assert_eq!(status.record(failed(), 2), None);
+        assert_eq!(status.failures, 3);
+
+        assert_eq!(status.record(Ok(Duration::from_millis(4)), 2), Some(Condition::Up));
+        assert_eq!((status.failures, status.error.as_ref()), (0, None));
+        // Up stays up through a failure below the threshold
+        assert_eq!(status.record(failed(), 2), None);
+        assert_eq!(status.condition, Condition::Up);
+    }
+
+    #[tokio::test]
+    async fn every_round_probes_all_targets_and_publishes_them() {
+        // One port that answers, one with nothing behind it, one with an HTTP error
+        let up = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let up_addr = up.local_addr().unwrap();
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let http = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let http_addr = http.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = http.accept().await.unwrap();
+                let _ = socket.write_all(b"HTTP/1.1 503 Service Unavailable\r\n\r\n").await;
+            }
+        });
+
+        let checks = Checks {
+            targets: vec![
+                Target::parse(&up_addr.to_string()).unwrap(),
+                Target::parse(&closed.to_string()).unwrap(),
+                Target::parse(&format!("http://{}/ready", http_addr)).unwrap(),
+            ],
+            failures: 2,
+            ..Checks::default()
+        };
+        let metrics: Metrics = Arc::new(InMemorySink::default());
+        let (log_tx, _log) = logger::channel(16, &LogOverflow::Drop, metrics.clone());
+        let (statuses, mut rx) = watch::channel(Vec::new());
+        let (_phase_tx, phase) = watch::channel(Phase::Ready);
+        let clock = ManualClock::new();
+        start(&checks, statuses, log_tx, metrics.clone(), Arc::new(clock.clone()), phase);
+
+        let failures =
+            |statuses: &Vec<Status>| -> Vec<u32> { statuses.iter().map(|s| s.failures).collect() };
+        let round = rx.wait_for(|s| failures(s) == vec![0, 1, 1]).await.unwrap().clone();
+        let conditions: Vec<_> = round.iter().map(|status| status.condition).collect();
+        assert_eq!(conditions, [Condition::Up, Condition::Unknown, Condition::Unknown]);
+        assert_eq!(round[2].error.as_deref(), Some("HTTP status 503"));
+
+        // The second failure in a row makes the other two down
+        clock.advance(checks.interval);
+        let round = rx.wait_for(|s| failures(s) == vec![0, 2, 2]).await.unwrap().clone();
+        let conditions: Vec<_> = round.iter().map(|status| status.condition).collect();
+        assert_eq!(conditions, [Condition::Up, Condition::Down, Condition::Down]);
+        assert!(metrics::stat_summary(&metrics).contains("health_targets_down=2"));
+        let lines = stat_lines(&round);
+        assert!(lines.starts_with(&format!("HEALTH tcp://{} up failures=0", up_addr)), "{}", lines);
+        assert!(lines.contains(&format!("HEALTH tcp://{} down failures=2\n", closed)), "{}", lines);
+        drop(up);
+    }
+}
@@ -0,0 +1,181 @@
+//! Application-level keepalive: `PING`, and a `PONG` expected back.
+//!
+//! TCP keepalive (`--keepalive-secs`) only shows that the peer's kernel is
+//! there. A heartbeat shows that the client application still reads and
+//! answers: once nothing has been received for a while, the server sends
+//! `PING`, and a client that does not send `PONG` before the deadline is
+//! disconnected.
+//!
+//! The handler waits for its next read in a `select!`; a [`Pulse`] is one
+//! more branch there. It ticks on an interval of the server's [`Clock`],
+//! and on each tick decides whether to ping, to give up, or to keep waiting.
+
+use crate::clock::{Clock, Interval};
+use crate::protocol::{Protocol, Request};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// The heartbeat of one listener's connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Heartbeat {
+    /// `PING` once nothing has been received for this long...
+    pub idle: Duration,
+    /// ...and close the connection if no `PONG` comes back in this long.
+    pub timeout: Duration,
+}
+
+/// What a tick of the [`Pulse`] calls for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Beat {
+    /// Send `PING`.
+    Ping,
+    /// The `PONG` is overdue: close the connection.
+    Dead,
+}
+
+/// The heartbeat state of one connection.
+pub struct Pulse {
+    // `None` without a heartbeat: `tick` never returns
+    heartbeat: Option<Heartbeat>,
+    ticker: Option<Interval>,
+    last_received: Instant,
+    ping_sent: Option<Instant>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Pulse {
+    pub fn new(heartbeat: Option<Heartbeat>, clock: Arc<dyn Clock>) -> Self {
+        let now = clock.now();
+        let ticker = heartbeat.map(|heartbeat| {
+            // Fine enough to act within a second of a deadline, without
+            // waking an idle connection more often than that
+            let period = heartbeat.idle.min(heartbeat.timeout).min(Duration::from_secs(1));
+            clock.interval_at(now + period, period)
+        });
+        Self { heartbeat, ticker, last_received: now, ping_sent: None, clock }
+    }
+
+    /// Data came in from the client: the idle time starts over.
+    pub fn received(&mut self) {
+        self.last_received = self.clock.now();
+    }
+
+    /// The client answered; an outstanding `PING` is settled.
+    pub fn pong(&mut self) {
+        self.ping_sent = None;
+    }
+
+    /// Whether `PING` and `PONG` are in use on this connection.
+    pub fn enabled(&self) -> bool {
+        self.heartbeat.is_some()
+    }
+
+    /// Waits for the tick that calls for a ping or a disconnect.
+    ///
+    /// Cancel-safe: only `Interval::tick` is awaited, so it can be a branch
+    /// of the handler's `select!` and lose to a read every time.
+    pub async fn tick(&mut self) -> Beat {
+        let (Some(heartbeat), Some(ticker)) = (self.heartbeat, &mut self.ticker) else {
+            return std::future::pending().await;
+        };
+        loop {
+            ticker.tick().await;
+            let now = self.clock.now();
+            match self.ping_sent {
+                Some(sent) if now - sent >= heartbeat.timeout => return Beat::Dead,
+                Some(_) => {}
+                None if now - self.last_received >= heartbeat.idle => {
+                    self.ping_sent = Some(now);
+                    return Beat::Ping;
+                }
+                None => {}
+            }
+        }
+    }
+}
+
+/// Whether `message` is the client's `PONG`, in the connection's protocol.
+pub fn is_pong(message: &[u8], protocol: Protocol) -> bool {
+    match protocol {
+        Protocol::Text | Protocol::Socks5 | Protocol::Tls => message.eq_ignore_ascii_case(b"PONG"),
+        // JSON, or MessagePack: a request
+        _ => Request::parse(message, protocol).is_ok_and(|request| {
+            request.cmd.eq_ignore_ascii_case("PONG") && request.args.is_empty()
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{ManualClock, TokioClock};
+    use futures_util::FutureExt;
+
+    const HEARTBEAT: Heartbeat =
+        Heartbeat { idle: Duration::from_secs(30), timeout: Duration::from_secs(10) };
+
+    #[tokio::test(start_paused = true)]
+    async fn an_idle_connection_is_pinged_then_given_up_on() {
+        let start = Instant::now();
+        let mut pulse = Pulse::new(Some(HEARTBEAT), Arc::new(TokioClock));
+
+        assert_eq!(pulse.tick().await, Beat::Ping);
+        assert_eq!(start.elapsed(), HEARTBEAT.idle);
+        assert_eq!(pulse.tick().await, Beat::Dead);
+        assert_eq!(start.elapsed(), HEARTBEAT.idle + HEARTBEAT.timeout);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_pong_settles_the_ping_and_the_idle_time_starts_over() {
+        let mut pulse = Pulse::new(Some(HEARTBEAT), Arc::new(TokioClock));
+        assert_eq!(pulse.tick().await, Beat::Ping);
+
+        tokio::time::advance(Duration::from_secs(5)).await;
+        pulse.received();
+        pulse.pong();
+        let answered = Instant::now();
+        assert_eq!(pulse.tick().await, Beat::Ping);
+        assert_eq!(answered.elapsed(), HEARTBEAT.idle);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn data_from_the_client_postpones_the_ping() {
+        let start = Instant::now();
+        let mut pulse = Pulse::new(Some(HEARTBEAT), Arc::new(TokioClock));
+        tokio::time::advance(Duration::from_secs(20)).await;
+        pulse.received();
+
+        assert_eq!(pulse.tick().await, Beat::Ping);
+        assert_eq!(start.elapsed(), Duration::from_secs(50));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn without_a_heartbeat_there_is_never_a_beat() {
+        let mut pulse = Pulse::new(None, Arc::new(TokioClock));
+        let tick = tokio::time::timeout(Duration::from_secs(3600), pulse.tick());
+        assert!(tick.await.is_err());
+    }
+
+    #[test]
+    fn the_pulse_follows_the_clock_it_is_given() {
+        let clock = ManualClock::new();
+        let mut pulse = Pulse::new(Some(HEARTBEAT), Arc::new(clock.clone()));
+        let mut tick = Box::pin(pulse.tick());
+        clock.advance(HEARTBEAT.idle - Duration::from_secs(1));
+        assert_eq!(tick.as_mut().now_or_never(), None);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(tick.now_or_never(), Some(Beat::Ping));
+
+        clock.advance(HEARTBEAT.timeout);
+        assert_eq!(pulse.tick().now_or_never(), Some(Beat::Dead));
+    }
+
+    #[test]
+    fn pong_is_recognized_in_either_protocol() {
+        assert!(is_pong(b"pong", Protocol::Text));
+        assert!(!is_pong(b"PONG now", Protocol::Text));
+        assert!(is_pong(br#"{"cmd":"PONG"}"#, Protocol::Json));
+        assert!(!is_pong(b"PONG", Protocol::Json));
+    }
+}
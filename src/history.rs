@@ -0,0 +1,252 @@
+//! A SQLite copy of the log, `--history-db`, read back with `HISTORY <n>`.
+//!
+//! Every message the logger prints is also kept as a row: its text, when
+//! it was sent to the logger, and the client's address for client lines.
+//!
+//! ```text
+//! HISTORY <n>  -> LINE <unix ms> <client address or -> <text> ... END  (the newest n)
+//! ```
+//!
+//! A length-prefixed message can hold newlines, so the text is escaped: a
+//! newline is sent as `\n`, a carriage return as `\r` and a backslash as
+//! `\\`, and every row stays one line. Only admins may use `HISTORY`.
+//!
+//! The logger never waits for the database. [`History::record`] hands the
+//! row to a writer task through a bounded channel and returns; a row that
+//! finds the channel full is dropped and counted in `history_rows_dropped`.
+//! The writer takes whatever has piled up, up to [`BATCH`] rows, and
+//! inserts it with one multi-row `INSERT` in one transaction: a burst of
+//! messages costs one commit, not one per message.
+//!
+//! The database is optional: without the `sqlite` feature, `--history-db`
+//! refuses to start.
+
+use crate::logger::LogMessage;
+use crate::metrics::{self, Metrics};
+use crate::task;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+
+/// Rows inserted at most by one statement.
+pub const BATCH: usize = 256;
+
+/// Rows waiting for the writer before new ones are dropped.
+const QUEUE: usize = 4 * BATCH;
+
+/// Rows `HISTORY` returns at most.
+pub const MAX_ROWS: u32 = 1000;
+
+/// A logged message, as stored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Row {
+    /// When the message was sent to the logger, in milliseconds since 1970.
+    pub at_ms: i64,
+    pub peer: Option<SocketAddr>,
+    pub text: String,
+}
+
+impl Row {
+    /// The row for `msg`, timed by when it entered the log channel.
+    pub fn new(msg: &LogMessage) -> Self {
+        let at = SystemTime::now() - msg.enqueued.elapsed();
+        let at_ms = at.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as i64);
+        // Client lines are not necessarily valid UTF-8
+        let text = String::from_utf8_lossy(&msg.text).into_owned();
+        Self { at_ms, peer: msg.peer, text }
+    }
+
+    /// The row as a `HISTORY` line, newline included, its text escaped.
+    pub fn line(&self) -> String {
+        let peer = self.peer.map_or("-".to_string(), |peer| peer.to_string());
+        let text = self.text.replace('\\', "\\\\").replace('\n', "\\n").replace('\r', "\\r");
+        format!("LINE {} {} {}\n", self.at_ms, peer, text)
+    }
+}
+
+/// The database and its writer; cheap to clone.
+#[derive(Clone)]
+pub struct History {
+    db: Arc<engine::Db>,
+    rows_tx: mpsc::Sender<Row>,
+    metrics: Metrics,
+}
+
+impl History {
+    /// Opens the database at `path`, creating it and its table if needed,
+    /// and starts the writer.
+    pub async fn open(path: &Path, metrics: Metrics) -> Result<Self, String> {
+        let db = engine::Db::open(path)
+            .await
+            .map_err(|e| format!("cannot open {}: {}", path.display(), e))?;
+        let db = Arc::new(db);
+        let (rows_tx, rows_rx) = mpsc::channel(QUEUE);
+        task::spawn("history writer", write(db.clone(), rows_rx, metrics.clone()));
+        Ok(Self { db, rows_tx, metrics })
+    }
+
+    /// Queues `msg` for the writer, or drops it if the writer is behind.
+    pub fn record(&self, msg: &LogMessage) {
+        if self.rows_tx.try_send(Row::new(msg)).is_err() {
+            self.metrics.incr(metrics::HISTORY_ROWS_DROPPED, 1);
+        }
+    }
+
+    /// The newest `n` rows written so far, oldest first.
+    pub async fn last(&self, n: u32) -> Result<Vec<Row>, String> {
+        self.db.last(n.min(MAX_ROWS)).await.map_err(|e| e.to_string())
+    }
+}
+
+/// Inserts the queued rows in batches, until every `History` is gone.
+async fn write(db: Arc<engine::Db>, mut rows_rx: mpsc::Receiver<Row>, metrics: Metrics) {
+    let mut rows = Vec::with_capacity(BATCH);
+    while rows_rx.recv_many(&mut rows, BATCH).await > 0 {
+        match db.insert(&rows).await {
+            Ok(()) => metrics.incr(metrics::HISTORY_ROWS, rows.len() as u64),
+            Err(e) => {
+                metrics.incr(metrics::HISTORY_ROWS_DROPPED, rows.len() as u64);
+                eprintln!("[HISTORY] cannot write {} row(s): {}", rows.len(), e);
+            }
+        }
+        rows.clear();
+    }
+}
+
+#[cfg(feature = "sqlite")]
+mod engine {
+    use super::Row;
+    use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePool};
+    use sqlx::{QueryBuilder, Row as _, Sqlite};
+    use std::path::Path;
+
+    pub struct Db {
+        pool: SqlitePool,
+    }
+
+    impl Db {
+        pub async fn open(path: &Path) -> Result<Self, sqlx::Error> {
+            let options = SqliteConnectOptions::new()
+                .filename(path)
+                .create_if_missing(true)
+                // Readers do not wait for the writer's transactions
+                .journal_mode(SqliteJournalMode::Wal);
+            let pool = SqlitePool::connect_with(options).await?;
+            sqlx::query(
+                "CREATE TABLE IF NOT EXISTS messages (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    at_ms INTEGER NOT NULL,
+                    peer TEXT,
+                    text TEXT NOT NULL
+                )",
+            )
+            .execute(&pool)
+            .await?;
+            Ok(Self { pool })
+        }
+
+        pub async fn insert(&self, rows: &[Row]) -> Result<(), sqlx::Error> {
+            let columns = "INSERT INTO messages (at_ms, peer, text) ";
+            let mut insert = QueryBuilder::<Sqlite>::new(columns);
+            insert.push_values(rows, |mut values, row| {
+                values.push_bind(row.at_ms);
+                values.push_bind(row.peer.map(|peer| peer.to_string()));
+                values.push_bind(row.text.clone());
+            });
+            let mut transaction = self.pool.begin().await?;
+            insert.build().execute(&mut *transaction).await?;
+            transaction.commit().await
+        }
+
+        pub async fn last(&self, n: u32) -> Result<Vec<Row>, sqlx::Error> {
+            let query = "SELECT at_ms, peer, text FROM messages ORDER BY id DESC LIMIT ?";
+            let found = sqlx::query(query).bind(n).fetch_all(&self.pool).await?;
+            let mut rows = Vec::with_capacity(found.len());
+            // Newest first from the query, oldest first in the reply
+            for row in found.iter().rev() {
+                let peer: Option<String> = row.try_get("peer")?;
+                rows.push(Row {
+                    at_ms: row.try_get("at_ms")?,
+                    peer: peer.and_then(|peer| peer.parse().ok()),
+                    text: row.try_get("text")?,
+                });
+            }
+            Ok(rows)
+        }
+    }
+}
+
+#[cfg(not(feature = "sqlite"))]
+mod engine {
+    use super::Row;
+    use std::path::Path;
+
+    pub struct Db;
+
+    impl Db {
+        pub async fn open(_path: &Path) -> Result<Self, String> {
+            Err("--history-db needs a build with --features sqlite".to_string())
+        }
+
+        pub async fn insert(&self, _rows: &[Row]) -> Result<(), String> {
+            Ok(())
+        }
+
+        pub async fn last(&self, _n: u32) -> Result<Vec<Row>, String> {
+            Ok(Vec::new())
+        }
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use super::*;
+    use crate::metrics::{InMemorySink, MetricsSink};
+    use std::time::Duration;
+
+    #[test]
+    fn a_row_stays_one_line_whatever_its_text() {
+        let text = "hi\nEND\nLINE 0 - forged\r\\n".to_string();
+        let row = Row { at_ms: 1, peer: None, text };
+        assert_eq!(row.line(), "LINE 1 - hi\\nEND\\nLINE 0 - forged\\r\\\\n\n");
+    }
+
+    #[tokio::test]
+    async fn logged_messages_come_back_newest_last() {
+        let path = std::env::temp_dir().join(format!("history-test-{}.db", std::process::id()));
+        let sink = Arc::new(InMemorySink::default());
+        let history = History::open(&path, sink.clone()).await.unwrap();
+
+        let peer: SocketAddr = "127.0.0.1:50312".parse().unwrap();
+        history.record(&LogMessage::info("hello").with_peer(peer));
+        for n in 0..3 {
+            history.record(&LogMessage::error(format!("failure {}", n)));
+        }
+        // The writer inserts in the background
+        let mut rows = Vec::new();
+        for _ in 0..100 {
+            rows = history.last(3).await.unwrap();
+            if rows.len() == 3 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let texts: Vec<&str> = rows.iter().map(|row| row.text.as_str()).collect();
+        assert_eq!(texts, ["failure 0", "failure 1", "failure 2"]);
+        assert_eq!(rows[0].peer, None);
+
+        let all = history.last(10).await.unwrap();
+        assert_eq!(all[0].peer, Some(peer));
+        assert!(all[0].line().starts_with("LINE "));
+        assert!(all[0].line().ends_with(" 127.0.0.1:50312 hello\n"));
+        let written = sink.snapshot().into_iter().find(|m| m.name == metrics::HISTORY_ROWS);
+        assert_eq!(written.map(|m| m.value), Some(4));
+
+        drop(history);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+}
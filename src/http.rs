@@ -0,0 +1,536 @@
+//! A deliberately tiny HTTP/1.1 endpoint for operational checks, which
+//! doubles as a file server and a forward proxy.
+//!
+//! The checks are the two a load balancer or an orchestrator asks for:
+//! `/healthz` (liveness) answers `200` for as long as the process can
+//! answer at all, and `/readyz` (readiness) answers `200` only while the
+//! server should get new connections; see [`readiness::Signals`]. A drain
+//! turns `/readyz` to `503` first thing, so traffic moves elsewhere while
+//! the open connections finish.
+//!
+//! Only the request line is looked at; this is enough for probes such as
+//! `curl` or a load balancer health check, and keeps the example free of
+//! an HTTP framework. `/files/` is served from a directory, see
+//! [`crate::files`], and `/health` reports the health checks of other
+//! services, see [`crate::health`].
+//!
+//! `CONNECT host:port` asks for a tunnel: when the destination is on the
+//! allowlist (`--connect-allow`), the server connects to it, answers `200`
+//! and from then on copies bytes both ways with `copy_bidirectional`. What
+//! goes through is none of its business, usually TLS. An empty allowlist
+//! refuses every `CONNECT`, so the endpoint is never an open proxy by
+//! accident.
+//!
+//! Files and tunnels count against the client's quota, see
+//! [`crate::quota`]; over it, they are answered with `429`.
+
+use crate::config::ConfigRx;
+use crate::dialer::Dialer;
+use crate::files;
+use crate::flags::FlagsRx;
+use crate::health::{Condition, Status};
+use crate::logger::{LogMessage, LogTx};
+use crate::metrics::{self, Metrics};
+use crate::readiness::{self, Phase, Signals};
+use crate::quota::{Identity, Quotas};
+use crate::registry::Registry;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+
+/// Largest request head accepted, request line and headers together.
+const MAX_HEAD: usize = 8 * 1024;
+
+/// The request line of a request.
+pub struct RequestHead {
+    pub method: String,
+    /// The path, or `host:port` for `CONNECT`.
+    pub target: String,
+}
+
+/// Reads the request head, up to the blank line that ends it.
+///
+/// Returns the request line and whatever was read past the head: with
+/// `CONNECT`, the first bytes for the tunnel.
+pub async fn read_request_head(socket: &mut TcpStream) -> Option<(RequestHead, Vec<u8>)> {
+    let mut buf = Vec::with_capacity(1024);
+    let end = loop {
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end + 4;
+        }
+        if buf.len() >= MAX_HEAD || socket.read_buf(&mut buf).await.ok()? == 0 {
+            // A probe that sends only the request line still gets an answer
+            break buf.len();
+        }
+    };
+    let rest = buf.split_off(end);
+    let head = String::from_utf8_lossy(&buf);
+    // "GET /healthz HTTP/1.1"
+    let mut parts = head.lines().next()?.split_whitespace();
+    let method = parts.next()?.to_string();
+    let target = parts.next()?.to_string();
+    Some((RequestHead { method, target }, rest))
+}
+
+/// Reads the request head and returns the request path.
+pub async fn read_request_path(socket: &mut TcpStream) -> Option<String> {
+    read_request_head(socket).await.map(|(head, _)| head.target)
+}
+
+/// Writes a complete response and lets the connection close.
+pub async fn respond(socket: &mut TcpStream, status: &str, content_type: &str, body: &str) {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body,
+    );
+    let _ = socket.write_all(response.as_bytes()).await;
+}
+
+/// A destination `CONNECT` may reach: `host:port`, where the host may be
+/// `*` or `*.<domain>` and the port `*`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Destination {
+    host: String,
+    port: Option<u16>,
+}
+
+impl Destination {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let invalid = || format!("invalid CONNECT destination: {} (expected host:port)", text);
+        let (host, port) = split_authority(text).ok_or_else(invalid)?;
+        let port = match port {
+            "*" => None,
+            port => Some(port.parse().map_err(|_| invalid())?),
+        };
+        Ok(Self { host: host.to_ascii_lowercase(), port })
+    }
+
+    /// Whether a tunnel to `host:port` is allowed by this entry.
+    pub fn matches(&self, host: &str, port: u16) -> bool {
+        let host = host.to_ascii_lowercase();
+        let host_matches = match self.host.strip_prefix("*") {
+            Some("") => true,
+            // "*.example.com" covers the subdomains, not example.com itself
+            Some(suffix) => host.ends_with(suffix) && host.len() > suffix.len(),
+            None => host == self.host,
+        };
+        host_matches && self.port.is_none_or(|allowed| allowed == port)
+    }
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.port {
+            Some(port) => write!(f, "{}:{}", self.host, port),
+            None => write!(f, "{}:*", self.host),
+        }
+    }
+}
+
+/// Splits `host:port`, taking the brackets off an IPv6 address.
+fn split_authority(authority: &str) -> Option<(&str, &str)> {
+    let (host, port) = authority.rsplit_once(':')?;
+    let host = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host);
+    (!host.is_empty() && !port.is_empty()).then_some((host, port))
+}
+
+/// What the endpoint needs besides the request: the configuration (the
+/// `CONNECT` allowlist and the files root), which a reload can change, a
+/// way to reach `CONNECT` destinations, the latest health checks, and what
+/// readiness is decided on besides the phase.
+#[derive(Clone)]
+pub struct Context {
+    pub config: ConfigRx,
+    pub dialer: Dialer,
+    pub log_tx: LogTx,
+    pub metrics: Metrics,
+    pub health: watch::Receiver<Vec<Status>>,
+    pub flags: FlagsRx,
+    pub registry: Arc<Registry>,
+    pub quotas: Quotas,
+}
+
+/// Serves `/healthz`, always 200, and `/readyz`: 200 when the server is
+/// ready, 503 with the reason otherwise.
+///
+/// Also serves `/health` and `/files/` from the files root, if one is set,
+/// and opens `CONNECT` tunnels to the allowed destinations.
+pub async fn serve_health(
+    listener: TcpListener,
+    phase: watch::Receiver<Phase>,
+    context: Context,
+) {
+    loop {
+        let (mut socket, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                // As on the main listener: log it and pause rather than spin
+                let text = format!("health endpoint accept failed: {}", e);
+                context.log_tx.send(LogMessage::error(text)).await;
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let (phase, context) = (phase.clone(), context.clone());
+        crate::task::spawn("health request", async move {
+            let Some((head, rest)) = read_request_head(&mut socket).await else {
+                return;
+            };
+            // Everything but the probes counts against the client's quota
+            let probe = ["/healthz", "/readyz", "/health"].contains(&head.target.as_str());
+            if !probe
+                && let Err(wait) = context.quotas.take(&Identity::Ip(peer.ip())).await
+            {
+                let body = format!("quota exceeded, retry in {}ms\n", wait.as_millis().max(1));
+                respond(&mut socket, "429 Too Many Requests", "text/plain", &body).await;
+                return;
+            }
+            if head.method.eq_ignore_ascii_case("CONNECT") {
+                connect(socket, peer, &head.target, rest, &context, phase).await;
+                return;
+            }
+            if let Some(path) = head.target.strip_prefix(files::PREFIX) {
+                serve_file(socket, peer, &head.method, path, &context).await;
+                return;
+            }
+            if head.target == "/health" {
+                let (status, body) = health_report(&context.health.borrow());
+                respond(&mut socket, status, "application/json", &body).await;
+                return;
+            }
+            match head.target.as_str() {
+                "/healthz" => respond(&mut socket, "200 OK", "text/plain", "alive\n").await,
+                "/readyz" => {
+                    let (status, body) = match signals(&phase, &context).check() {
+                        Ok(()) => ("200 OK", "ready\n".to_string()),
+                        Err(why) => ("503 Service Unavailable", format!("not ready: {}\n", why)),
+                    };
+                    respond(&mut socket, status, "text/plain", &body).await;
+                }
+                _ => respond(&mut socket, "404 Not Found", "text/plain", "not found\n").await,
+            }
+        });
+    }
+}
+
+/// What `/readyz` decides on, as of now. The watch guards are dropped
+/// before this returns, so none is held across an `.await`.
+fn signals(phase: &watch::Receiver<Phase>, context: &Context) -> Signals {
+    Signals {
+        phase: *phase.borrow(),
+        accept_paused: context.flags.borrow().accept_paused,
+        logger_running: !context.log_tx.is_closed(),
+        connections: context.registry.count(),
+        max_connections: context.config.borrow().ready_max_connections,
+    }
+}
+
+/// The status and JSON body of `/health`: 503 as soon as one target is
+/// down, 200 otherwise, with every target's latest check.
+fn health_report(statuses: &[Status]) -> (&'static str, String) {
+    let down = statuses.iter().any(|status| status.condition == Condition::Down);
+    let targets: Vec<_> = statuses
+        .iter()
+        .map(|status| {
+            serde_json::json!({
+                "target": status.target.to_string(),
+                "status": status.condition.to_string(),
+                "failures": status.failures,
+                "latency_ms": status.latency.map(|latency| latency.as_millis() as u64),
+                "error": status.error,
+            })
+        })
+        .collect();
+    let (status, overall) =
+        if down { ("503 Service Unavailable", "down") } else { ("200 OK", "up") };
+    let body = serde_json::json!({ "status": overall, "targets": targets });
+    (status, format!("{}\n", body))
+}
+
+/// Answers a `GET` under `/files/`, or 404 without a files root.
+async fn serve_file(
+    mut socket: TcpStream,
+    peer: SocketAddr,
+    method: &str,
+    path: &str,
+    context: &Context,
+) {
+    // Read per request, so a reload applies to the next one
+    let Some(root) = context.config.borrow().files_root.clone() else {
+        respond(&mut socket, "404 Not Found", "text/plain", "not found\n").await;
+        return;
+    };
+    if method != "GET" {
+        respond(&mut socket, "405 Method Not Allowed", "text/plain", "GET only\n").await;
+        return;
+    }
+    match files::serve(&mut socket, &root, path).await {
+        Ok(sent) => context.metrics.incr(metrics::DOWNLOAD_BYTES, sent),
+        Err(e) => {
+            let text = format!("serving {}{} to {} failed: {}", files::PREFIX, path, peer, e);
+            context.log_tx.send(LogMessage::error(text)).await;
+        }
+    }
+}
+
+/// Answers a `CONNECT`: checks the allowlist, dials the destination and
+/// bridges the two sockets until both sides are done or the server drains.
+///
+/// `early` is what the client sent right after the request head, without
+/// waiting for the `200`; it is the first thing the destination gets.
+async fn connect(
+    mut socket: TcpStream,
+    peer: SocketAddr,
+    target: &str,
+    early: Vec<u8>,
+    context: &Context,
+    mut phase: watch::Receiver<Phase>,
+) {
+    let authority = split_authority(target);
+    let Some((host, port)) = authority.and_then(|(host, port)| Some((host, port.parse().ok()?)))
+    else {
+        respond(&mut socket, "400 Bad Request", "text/plain", "expected CONNECT host:port\n").await;
+        return;
+    };
+    // Read per request, so a reload applies to the next tunnel
+    let allowed = context.config.borrow().connect_allow.iter().any(|d| d.matches(host, port));
+    if !allowed {
+        context.metrics.incr(metrics::CONNECT_DENIED, 1);
+        let text = format!("CONNECT from {} to {} denied", peer, target);
+        context.log_tx.send(LogMessage::info(text)).await;
+        respond(&mut socket, "403 Forbidden", "text/plain", "destination not allowed\n").await;
+        return;
+    }
+
+    let mut upstream = match context.dialer.dial((host, port)).await {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            let text = format!("CONNECT from {} to {} failed: {}", peer, target, e);
+            context.log_tx.send(LogMessage::error(text)).await;
+            let body = format!("cannot reach {}: {}\n", target, e);
+            respond(&mut socket, "502 Bad Gateway", "text/plain", &body).await;
+            return;
+        }
+    };
+    let established = b"HTTP/1.1 200 Connection Established\r\n\r\n";
+    if socket.write_all(established).await.is_err() || upstream.write_all(&early).await.is_err() {
+        return;
+    }
+    context.metrics.incr(metrics::CONNECT_TUNNELS, 1);
+    let text = format!("tunnel from {} to {} open", peer, target);
+    context.log_tx.send(LogMessage::info(text)).await;
+
+    // A tunnel carries no requests to finish, so a drain cuts it right away
+    let copied = tokio::select! {
+        copied = tokio::io::copy_bidirectional(&mut socket, &mut upstream) => copied,
+        _ = readiness::wait_draining(&mut phase) => return,
+    };
+    match copied {
+        Ok((up, down)) => {
+            let up = up + early.len() as u64;
+            context.metrics.incr(metrics::PROXY_BYTES_UP, up);
+            context.metrics.incr(metrics::PROXY_BYTES_DOWN, down);
+            let text = format!(
+                "tunnel from {} to {} closed: {} bytes up, {} bytes down",
+                peer, target, up, down,
+            );
+            context.log_tx.send(LogMessage::info(text)).await;
+        }
+        Err(e) => {
+            let text = format!("tunnel from {} to {} failed: {}", peer, target, e);
+            context.log_tx.send(LogMessage::error(text)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::flags::FeatureFlags;
+    use crate::health::Target;
+    use crate::logger::{self, LogOverflow, LogStream};
+    use crate::metrics::InMemorySink;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::AsyncBufReadExt;
+
+    /// A context on `config`, without health checks or connections. The log
+    /// is returned to be kept: without it, the logger counts as stopped.
+    fn context(config: ServerConfig) -> (Context, LogStream) {
+        let metrics: Metrics = Arc::new(InMemorySink::default());
+        let (log_tx, log) = logger::channel(16, &LogOverflow::Drop, metrics.clone());
+        let context = Context {
+            config: watch::channel(Arc::new(config)).1,
+            dialer: Dialer::new(),
+            log_tx,
+            metrics: metrics.clone(),
+            health: watch::channel(Vec::new()).1,
+            flags: watch::channel(FeatureFlags::default()).1,
+            registry: Arc::new(Registry::default()),
+            quotas: Quotas::new(metrics),
+        };
+        (context, log)
+    }
+
+    /// Serves `context` on an ephemeral port.
+    async fn serve(phase: watch::Receiver<Phase>, context: Context) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_health(listener, phase, context));
+        addr
+    }
+
+    /// Sends `GET path` and returns the whole response.
+    async fn get(addr: SocketAddr, path: &str) -> String {
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let request = format!("GET {} HTTP/1.1\r\nHost: test\r\n\r\n", path);
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[test]
+    fn destinations_match_hosts_and_ports() {
+        let exact = Destination::parse("Example.com:443").unwrap();
+        assert!(exact.matches("example.COM", 443));
+        assert!(!exact.matches("example.com", 80));
+        let subdomains = Destination::parse("*.internal:*").unwrap();
+        assert!(subdomains.matches("db.internal", 5432));
+        assert!(!subdomains.matches("internal", 5432));
+        assert!(!subdomains.matches("evilinternal", 5432));
+        assert!(Destination::parse("[::1]:22").unwrap().matches("::1", 22));
+        assert!(Destination::parse("example.com").is_err());
+        assert!(Destination::parse("example.com:https").is_err());
+    }
+
+    #[test]
+    fn health_is_down_as_soon_as_one_target_is() {
+        let target = |text| Status::new(Target::parse(text).unwrap());
+        let mut statuses = vec![target("db:5432"), target("http://api:8080/ready")];
+        statuses[0].record(Ok(Duration::from_millis(3)), 1);
+        assert_eq!(health_report(&statuses).0, "200 OK");
+
+        statuses[1].record(Err("HTTP status 500".to_string()), 1);
+        let (status, body) = health_report(&statuses);
+        assert_eq!(status, "503 Service Unavailable");
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["status"], "down");
+        assert_eq!(body["targets"][0]["latency_ms"], 3);
+        assert_eq!(body["targets"][1]["target"], "http://api:8080/ready");
+        assert_eq!(body["targets"][1]["error"], "HTTP status 500");
+    }
+
+    #[tokio::test]
+    async fn connect_tunnels_to_allowed_destinations_only() {
+        // The destination: sends back whatever it receives
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = upstream.accept().await.unwrap();
+            let (mut reader, mut writer) = socket.split();
+            tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+        });
+
+        let connect_allow = vec![Destination::parse(&target.to_string()).unwrap()];
+        let (context, _log) = context(ServerConfig { connect_allow, ..Default::default() });
+        let metrics = context.metrics.clone();
+        let (_phase_tx, phase) = watch::channel(Phase::Ready);
+        let addr = serve(phase, context).await;
+
+        // Bytes sent along with the request go through too
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\nearly ", target, target);
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut client = tokio::io::BufReader::new(client);
+        let mut line = String::new();
+        client.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "HTTP/1.1 200 Connection Established\r\n");
+        client.read_line(&mut line).await.unwrap();
+        client.get_mut().write_all(b"bird\n").await.unwrap();
+        line.clear();
+        client.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "early bird\n");
+
+        let mut denied = TcpStream::connect(addr).await.unwrap();
+        denied.write_all(b"CONNECT 127.0.0.1:1 HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        denied.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden"), "{}", response);
+        assert!(metrics::stat_summary(&metrics).contains("connect_denied=1"));
+    }
+
+    #[tokio::test]
+    async fn files_are_served_from_the_root_only() {
+        let root = std::env::temp_dir().join(format!("http-files-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("docs")).unwrap();
+        std::fs::write(root.join("docs/a b.txt"), "hello files\n").unwrap();
+        std::fs::write(root.join(".secret"), "hidden").unwrap();
+
+        let files_root = Some(root.clone());
+        let (context, _log) = context(ServerConfig { files_root, ..Default::default() });
+        let (_phase_tx, phase) = watch::channel(Phase::Ready);
+        let addr = serve(phase, context).await;
+        let get = |path| get(addr, path);
+
+        let response = get("/files/docs/a%20b.txt").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("Content-Length: 12\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\nhello files\n"), "{}", response);
+        // A directory without its slash is redirected, so relative links work
+        assert!(get("/files/docs").await.contains("Location: /files/docs/\r\n"));
+        let index = get("/files/").await;
+        assert!(index.contains("<a href=\"docs/\">docs/</a>"), "{}", index);
+        assert!(!index.contains("secret"), "{}", index);
+        assert!(get("/files/docs/").await.contains("<a href=\"a%20b.txt\">a b.txt</a>"));
+
+        for path in ["/files/.secret", "/files/docs/%2e%2e/.secret", "/files/missing"] {
+            let response = get(path).await;
+            assert!(response.starts_with("HTTP/1.1 404"), "{}: {}", path, response);
+        }
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn readiness_follows_the_phase_the_flags_and_the_connections() {
+        let config = ServerConfig { ready_max_connections: Some(1), ..Default::default() };
+        let (mut context, log) = context(config);
+        let (flags_tx, flags) = watch::channel(FeatureFlags::default());
+        context.flags = flags;
+        let registry = context.registry.clone();
+        let (phase_tx, phase) = watch::channel(Phase::Warming);
+        let addr = serve(phase, context).await;
+        let ready = || get(addr, "/readyz");
+
+        assert!(ready().await.ends_with("\r\n\r\nnot ready: warming\n"));
+        phase_tx.send(Phase::Ready).unwrap();
+        assert!(ready().await.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        flags_tx.send_modify(|flags| flags.accept_paused = true);
+        assert!(ready().await.ends_with("not ready: accepting is paused\n"));
+        flags_tx.send_modify(|flags| flags.accept_paused = false);
+
+        let peer = SocketAddr::from(([127, 0, 0, 1], 50000));
+        let connection = registry.register(1, peer);
+        assert!(ready().await.ends_with("not ready: at capacity, 1 of 1 connections\n"));
+        drop(connection);
+
+        // A drain turns readiness off, while the process is still alive
+        phase_tx.send(Phase::Draining).unwrap();
+        let response = ready().await;
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"), "{}", response);
+        assert!(response.ends_with("not ready: draining\n"));
+        assert!(get(addr, "/healthz").await.ends_with("\r\n\r\nalive\n"));
+
+        phase_tx.send(Phase::Ready).unwrap();
+        drop(log);
+        assert!(ready().await.ends_with("not ready: the logger has stopped\n"));
+    }
+}
@@ -0,0 +1,273 @@
+//! Echo and the counter over HTTP/2, on the `h2` crate directly.
+//!
+//! No HTTP framework sits in between: each connection is an
+//! `h2::server::Connection` that hands out one `(Request, SendResponse)`
+//! pair per stream, and each stream is served by its own task.
+//!
+//! ```text
+//! POST /echo   -> 200, the request body streamed back; x-request: <request number>
+//! GET /count   -> 200, <requests served>
+//! ```
+//!
+//! HTTP/2 has flow control at two levels. Every stream has a window of
+//! `STREAM_WINDOW` bytes the peer may send before it must wait, and all
+//! streams of a connection share a window of `CONNECTION_WINDOW`. A
+//! received chunk keeps its share of both windows in use until
+//! `release_capacity` hands it back. `/echo` only does that once the chunk
+//! has been sent back, and it only sends as much as the client's windows
+//! allow (`reserve_capacity`, then `poll_capacity`). A client that stops
+//! reading the echo therefore stops being able to upload: backpressure
+//! travels end to end, and the server never buffers more than a window
+//! per stream.
+//!
+//! Every request counts against the client's quota, see [`crate::quota`];
+//! over it, the stream is answered with `429` and a `retry-after`.
+
+use crate::logger::{LogMessage, LogTx};
+use crate::metrics;
+use crate::quota::Identity;
+use crate::readiness::{self, Phase};
+use crate::state::State;
+use crate::task;
+use bytes::Bytes;
+use h2::server::SendResponse;
+use h2::{RecvStream, SendStream};
+use http::{Method, Request, Response, StatusCode};
+use std::future::poll_fn;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+
+/// Bytes a client may send on one stream before the server reads them.
+pub const STREAM_WINDOW: u32 = 64 * 1024;
+
+/// Bytes a client may send on all streams of a connection together.
+pub const CONNECTION_WINDOW: u32 = 1024 * 1024;
+
+/// Streams a client may have open at once on one connection.
+const MAX_STREAMS: u32 = 100;
+
+/// Accepts connections on `listener` until the server drains.
+pub async fn serve(
+    listener: TcpListener,
+    state: Arc<State>,
+    log_tx: LogTx,
+    phase: watch::Receiver<Phase>,
+) {
+    let mut draining = phase.clone();
+    loop {
+        let (socket, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    log_tx.send(LogMessage::error(format!("HTTP/2 accept failed: {}", e))).await;
+                    continue;
+                }
+            },
+            _ = readiness::wait_draining(&mut draining) => return,
+        };
+        let (state, log_tx, phase) = (state.clone(), log_tx.clone(), phase.clone());
+        task::spawn("h2 connection", async move {
+            if let Err(e) = serve_connection(socket, Some(peer), state, phase).await {
+                let text = format!("HTTP/2 connection from {} failed: {}", peer, e);
+                log_tx.send(LogMessage::error(text)).await;
+            }
+        });
+    }
+}
+
+/// Serves the streams of one HTTP/2 connection, over any byte stream.
+///
+/// When the server drains, the client is sent a GOAWAY: streams already
+/// open run to completion, new ones are refused, and the function returns
+/// once the connection has closed.
+pub async fn serve_connection<T>(
+    io: T,
+    peer: Option<SocketAddr>,
+    state: Arc<State>,
+    mut phase: watch::Receiver<Phase>,
+) -> Result<(), h2::Error>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut connection = h2::server::Builder::new()
+        .initial_window_size(STREAM_WINDOW)
+        .initial_connection_window_size(CONNECTION_WINDOW)
+        .max_concurrent_streams(MAX_STREAMS)
+        .handshake::<_, Bytes>(io)
+        .await?;
+    let mut draining = false;
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = connection.accept() => accepted,
+            _ = readiness::wait_draining(&mut phase), if !draining => {
+                connection.graceful_shutdown();
+                draining = true;
+                continue;
+            }
+        };
+        let Some(stream) = accepted else {
+            return Ok(());
+        };
+        let (request, respond) = stream?;
+        let state = state.clone();
+        task::spawn("h2 stream", async move {
+            // A failed stream is reset; the connection and its other streams go on
+            let _ = serve_stream(request, respond, peer, &state).await;
+        });
+    }
+}
+
+async fn serve_stream(
+    request: Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    peer: Option<SocketAddr>,
+    state: &State,
+) -> Result<(), h2::Error> {
+    if let Some(peer) = peer
+        && let Err(wait) = state.quotas.take(&Identity::Ip(peer.ip())).await
+    {
+        let response = Response::builder()
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .header("retry-after", wait.as_secs_f64().ceil() as u64)
+            .body(())
+            .expect("static response parts");
+        respond.send_response(response, true)?;
+        return Ok(());
+    }
+    match (request.method(), request.uri().path()) {
+        (&Method::POST, "/echo") => {
+            state.metrics.incr(metrics::MESSAGES_RECEIVED, 1);
+            if let Some(peer) = peer {
+                state.peers.request(peer.ip());
+            }
+            let current = state.increment();
+            let response = Response::builder()
+                .header("x-request", current)
+                .body(())
+                .expect("static response parts");
+            let send = respond.send_response(response, false)?;
+            echo(request.into_body(), send).await
+        }
+        (&Method::GET, "/count") => {
+            let body = Bytes::from(format!("{}\n", state.count()));
+            let mut send = respond.send_response(Response::new(()), false)?;
+            send_all(&mut send, body).await?;
+            send.send_data(Bytes::new(), true)
+        }
+        _ => {
+            let response = Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(())
+                .expect("static response parts");
+            respond.send_response(response, true)?;
+            Ok(())
+        }
+    }
+}
+
+/// Sends every chunk of `body` back, handing its capacity back to the
+/// client only once the chunk is on its way.
+async fn echo(mut body: RecvStream, mut send: SendStream<Bytes>) -> Result<(), h2::Error> {
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        let len = chunk.len();
+        send_all(&mut send, chunk).await?;
+        body.flow_control().release_capacity(len)?;
+    }
+    send.send_data(Bytes::new(), true)
+}
+
+/// Sends `data` in pieces as large as the client's windows allow.
+async fn send_all(send: &mut SendStream<Bytes>, mut data: Bytes) -> Result<(), h2::Error> {
+    while !data.is_empty() {
+        send.reserve_capacity(data.len());
+        let granted = match poll_fn(|cx| send.poll_capacity(cx)).await {
+            Some(granted) => granted?,
+            // The stream was reset by the client
+            None => return Err(h2::Error::from(h2::Reason::CANCEL)),
+        };
+        if granted == 0 {
+            continue;
+        }
+        send.send_data(data.split_to(granted.min(data.len())), false)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::{InMemorySink, Metrics};
+    use h2::client;
+
+    fn state() -> Arc<State> {
+        let metrics: Metrics = Arc::new(InMemorySink::default());
+        Arc::new(State::new(metrics))
+    }
+
+    /// Reads a whole body, releasing the capacity as it goes.
+    async fn read_body(mut body: RecvStream) -> Vec<u8> {
+        let mut received = Vec::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.unwrap();
+            body.flow_control().release_capacity(chunk.len()).unwrap();
+            received.extend_from_slice(&chunk);
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn a_body_larger_than_the_windows_is_echoed_whole() {
+        let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+        let (_phase_tx, phase) = watch::channel(Phase::Ready);
+        tokio::spawn(serve_connection(server_io, None, state(), phase));
+        let (client, connection) = client::handshake(client_io).await.unwrap();
+        tokio::spawn(connection);
+        let mut client = client.ready().await.unwrap();
+
+        let request = Request::post("http://localhost/echo").body(()).unwrap();
+        let (response, mut upload) = client.send_request(request, false).unwrap();
+        // Four times the connection window: only fits if the echo is read
+        let sent: Vec<u8> = (0..4 * CONNECTION_WINDOW).map(|i| i as u8).collect();
+        let uploading = {
+            let sent = Bytes::from(sent.clone());
+            tokio::spawn(async move { send_all(&mut upload, sent).await.map(|_| upload) })
+        };
+
+        let response = response.await.unwrap();
+        assert_eq!(response.headers()["x-request"], "1");
+        let reading = tokio::spawn(read_body(response.into_body()));
+        let mut upload = uploading.await.unwrap().unwrap();
+        upload.send_data(Bytes::new(), true).unwrap();
+        assert!(reading.await.unwrap() == sent);
+
+        let request = Request::get("http://localhost/count").body(()).unwrap();
+        let (response, _) = client.send_request(request, true).unwrap();
+        assert_eq!(read_body(response.await.unwrap().into_body()).await, b"1\n");
+    }
+
+    #[tokio::test]
+    async fn draining_lets_open_streams_finish_then_closes() {
+        let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+        let (phase_tx, phase) = watch::channel(Phase::Ready);
+        let server = tokio::spawn(serve_connection(server_io, None, state(), phase));
+        let (client, connection) = client::handshake(client_io).await.unwrap();
+        tokio::spawn(connection);
+        let mut client = client.ready().await.unwrap();
+
+        let request = Request::post("http://localhost/echo").body(()).unwrap();
+        let (response, mut upload) = client.send_request(request, false).unwrap();
+        let response = response.await.unwrap();
+        phase_tx.send(Phase::Draining).unwrap();
+
+        // The stream opened before the drain is still served
+        upload.send_data(Bytes::from_static(b"last words"), true).unwrap();
+        assert_eq!(read_body(response.into_body()).await, b"last words");
+        drop(client);
+        server.await.unwrap().unwrap();
+    }
+}
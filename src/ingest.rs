@@ -0,0 +1,250 @@
+//! The stdin console's transcript, `log.txt`, written through a pipeline.
+//!
+//! Every line typed into the console goes through three stages on its way
+//! to the file, each a task of its own:
+//!
+//! ```text
+//!                       ┌─▶ transform ─┐
+//! console ─▶ parse ─────┤              ├─▶ writer ─▶ log.txt
+//!                       └─▶ transform ─┘
+//! ```
+//!
+//! - parse: replaces control characters (the escape sequences of arrow
+//!   keys, say) and cuts lines longer than `MAX_LINE`, then numbers each
+//!   line and hands it to the transform tasks in turns;
+//! - transform: hides secrets, the token of `AUTH` and the value of any
+//!   `password=`, `secret=`, `token=` or `key=` word, and puts the time the
+//!   line was typed in front of it;
+//! - writer: the fan-in. The transform tasks run side by side, so lines
+//!   may reach it out of order; it holds on to the early ones until the
+//!   lines before them have been written, and flushes whenever it has
+//!   nothing left to write, so `TAIL` sees every line right away.
+//!
+//! Every arrow is a bounded channel. When the file cannot keep up, the
+//! channels fill up from the writer back, and the console stops reading
+//! stdin until there is room again: backpressure, all the way to the
+//! terminal. `ingest_lines`, `ingest_lines_cleaned` and
+//! `ingest_lines_redacted` count what went through.
+
+use crate::auth;
+use crate::metrics::{self, Metrics};
+use crate::task;
+use std::collections::BTreeMap;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs::File;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// Lines each channel holds before its sender has to wait.
+const CHANNEL: usize = 64;
+
+/// Transform tasks running side by side.
+const TRANSFORMERS: usize = 2;
+
+/// The longest line kept, in bytes; the rest is cut off.
+const MAX_LINE: usize = 4096;
+
+/// Words naming a secret, in `<name>=<value>`.
+const SECRETS: [&str; 4] = ["password", "secret", "token", "key"];
+
+/// A line on its way through the pipeline.
+struct Line {
+    seq: u64,
+    typed: SystemTime,
+    text: String,
+}
+
+/// Where the console sends the lines it reads; cheap to clone.
+#[derive(Clone)]
+pub struct Ingest {
+    lines: mpsc::Sender<(SystemTime, String)>,
+}
+
+impl Ingest {
+    /// Starts the pipeline's tasks, writing to `file`.
+    pub fn start(file: File, metrics: Metrics) -> Self {
+        let (ingest, writer) = Self::pipeline(file, metrics);
+        task::spawn("transcript", async move {
+            if let Ok(Err(e)) = writer.await {
+                eprintln!("[STDIN] cannot write the transcript, it stops here: {}", e);
+            }
+        });
+        ingest
+    }
+
+    fn pipeline(file: File, metrics: Metrics) -> (Self, JoinHandle<io::Result<()>>) {
+        let (lines, parsed) = mpsc::channel(CHANNEL);
+        let (done_tx, done) = mpsc::channel(CHANNEL);
+        let mut transformers = Vec::with_capacity(TRANSFORMERS);
+        for _ in 0..TRANSFORMERS {
+            let (tx, rx) = mpsc::channel(CHANNEL);
+            task::spawn("transcript transform", transform(rx, done_tx.clone(), metrics.clone()));
+            transformers.push(tx);
+        }
+        task::spawn("transcript parse", parse(parsed, transformers, metrics.clone()));
+        let writer = task::spawn("transcript writer", write(file, done, metrics));
+        (Self { lines }, writer)
+    }
+
+    /// Sends a line typed now down the pipeline, waiting for room if it is
+    /// full. Returns false once the pipeline has stopped.
+    pub async fn send(&self, line: String) -> bool {
+        self.lines.send((SystemTime::now(), line)).await.is_ok()
+    }
+}
+
+async fn parse(
+    mut lines: mpsc::Receiver<(SystemTime, String)>,
+    transformers: Vec<mpsc::Sender<Line>>,
+    metrics: Metrics,
+) {
+    let mut seq = 0;
+    while let Some((typed, line)) = lines.recv().await {
+        let (text, cleaned) = clean(&line);
+        if cleaned {
+            metrics.incr(metrics::INGEST_LINES_CLEANED, 1);
+        }
+        let transformer = &transformers[seq as usize % transformers.len()];
+        if transformer.send(Line { seq, typed, text }).await.is_err() {
+            return;
+        }
+        seq += 1;
+    }
+}
+
+async fn transform(mut lines: mpsc::Receiver<Line>, done: mpsc::Sender<Line>, metrics: Metrics) {
+    while let Some(mut line) = lines.recv().await {
+        let (text, redacted) = redact(&line.text);
+        if redacted {
+            metrics.incr(metrics::INGEST_LINES_REDACTED, 1);
+        }
+        let typed = line.typed.duration_since(UNIX_EPOCH).unwrap_or_default();
+        line.text = format!("{}.{:03} {}\n", typed.as_secs(), typed.subsec_millis(), text);
+        if done.send(line).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Writes the lines in the order they were typed, until every `Ingest` is
+/// dropped or a write fails.
+async fn write(file: File, mut lines: mpsc::Receiver<Line>, metrics: Metrics) -> io::Result<()> {
+    let mut out = BufWriter::new(file);
+    // Lines that overtook one before them
+    let mut early = BTreeMap::new();
+    let mut next = 0;
+    while let Some(line) = lines.recv().await {
+        early.insert(line.seq, line.text);
+        while let Some(text) = early.remove(&next) {
+            out.write_all(text.as_bytes()).await?;
+            metrics.incr(metrics::INGEST_LINES, 1);
+            next += 1;
+        }
+        if lines.is_empty() {
+            out.flush().await?;
+        }
+    }
+    out.flush().await
+}
+
+/// `line` with its control characters replaced and cut to `MAX_LINE`, and
+/// whether either changed it.
+fn clean(line: &str) -> (String, bool) {
+    let mut text: String = line
+        .chars()
+        .map(|c| if c.is_control() && c != '\t' { char::REPLACEMENT_CHARACTER } else { c })
+        .collect();
+    let mut cleaned = text != line;
+    if text.len() > MAX_LINE {
+        let mut end = MAX_LINE;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        text.truncate(end);
+        text.push('…');
+        cleaned = true;
+    }
+    (text, cleaned)
+}
+
+/// `line` with its secrets hidden, and whether it had any.
+fn redact(line: &str) -> (String, bool) {
+    if auth::parse_auth(line).is_some() {
+        return (auth::redact(line).to_string(), true);
+    }
+    let mut redacted = false;
+    let words: Vec<String> = line
+        .split(' ')
+        .map(|word| match word.split_once('=') {
+            Some((name, value)) if !value.is_empty() && is_secret(name) => {
+                redacted = true;
+                format!("{}=***", name)
+            }
+            _ => word.to_string(),
+        })
+        .collect();
+    (words.join(" "), redacted)
+}
+
+fn is_secret(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    SECRETS.iter().any(|secret| name.contains(secret))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::{InMemorySink, MetricsSink};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn lines_are_cleaned_redacted_and_written_in_the_order_they_were_typed() {
+        let path = std::env::temp_dir().join(format!("ingest-test-{}.txt", std::process::id()));
+        let file = File::create(&path).await.unwrap();
+        let sink = Arc::new(InMemorySink::default());
+        let (ingest, writer) = Ingest::pipeline(file, sink.clone());
+        // More than the channels hold: the sender waits for the writer
+        for n in 0..200 {
+            assert!(ingest.send(format!("log line {}", n)).await);
+        }
+        ingest.send("log deploying with password=hunter2 now".to_string()).await;
+        ingest.send("AUTH s3cret".to_string()).await;
+        ingest.send("count\u{1b}[A".to_string()).await;
+        drop(ingest);
+        writer.await.unwrap().unwrap();
+
+        let written = tokio::fs::read_to_string(&path).await.unwrap();
+        tokio::fs::remove_file(&path).await.unwrap();
+        let lines: Vec<&str> = written
+            .lines()
+            .map(|line| {
+                let (time, text) = line.split_once(' ').unwrap();
+                assert!(time.parse::<f64>().is_ok(), "{}", line);
+                text
+            })
+            .collect();
+        assert_eq!(lines.len(), 203);
+        for (n, line) in lines[..200].iter().enumerate() {
+            assert_eq!(*line, format!("log line {}", n));
+        }
+        let secrets = ["log deploying with password=*** now", "AUTH ***", "count\u{fffd}[A"];
+        assert_eq!(lines[200..], secrets);
+
+        let count = |name| sink.snapshot().into_iter().find(|m| m.name == name).unwrap().value;
+        assert_eq!(count(metrics::INGEST_LINES), 203);
+        assert_eq!(count(metrics::INGEST_LINES_REDACTED), 2);
+        assert_eq!(count(metrics::INGEST_LINES_CLEANED), 1);
+    }
+
+    #[test]
+    fn long_lines_are_cut_on_a_character_boundary() {
+        let (text, cleaned) = clean(&"é".repeat(MAX_LINE));
+        assert!(cleaned);
+        assert_eq!(text.chars().count(), MAX_LINE / 2 + 1);
+        assert_eq!(clean("plain\ttext"), ("plain\ttext".to_string(), false));
+        assert_eq!(redact("SET apikey=abc"), ("SET apikey=***".to_string(), true));
+        assert_eq!(redact("SET key= value"), ("SET key= value".to_string(), false));
+    }
+}
@@ -0,0 +1,179 @@
+//! Runtime introspection: what the Tokio scheduler itself is doing.
+//!
+//! `tokio::runtime::Handle::metrics()` exposes counters the runtime keeps
+//! anyway: how many workers it has, how many tasks are alive, how long
+//! the queue of tasks waiting for a worker is, and how often workers ran
+//! out of work and parked. With `--runtime-stats-secs`, a task samples
+//! them on an interval, sets them as `runtime_*` metrics (so `STATS` and
+//! the Prometheus endpoint show them) and writes a summary to the log:
+//!
+//! ```text
+//! [LOG] runtime: workers=4 alive_tasks=12 global_queue=0 parks=1830 busy=2.4s
+//! ```
+//!
+//! A global queue that keeps growing means tasks are spawned faster than
+//! the workers can poll them. Built with `RUSTFLAGS="--cfg tokio_unstable"`,
+//! the sample also has the workers' local queues, the blocking threads and
+//! the number of tasks spawned so far.
+//!
+//! Only the runtime the task runs on is sampled: with `--runtime
+//! thread-per-core`, that is the main runtime, not the workers' own.
+
+use crate::logger::{LogMessage, LogTx};
+use crate::metrics::{self, Metrics};
+use crate::readiness::{self, Phase};
+use std::fmt;
+use std::time::Duration;
+use tokio::runtime::RuntimeMetrics;
+use tokio::sync::watch;
+
+/// One reading of the runtime's metrics.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RuntimeSample {
+    pub workers: usize,
+    pub alive_tasks: usize,
+    /// Tasks waiting in the shared queue for any worker to pick them up.
+    pub global_queue_depth: usize,
+    /// Times a worker found nothing to do and parked, summed over workers.
+    pub parks: u64,
+    /// Time the workers spent polling tasks, summed over workers.
+    pub busy: Duration,
+    /// Tasks waiting in the workers' own queues, summed over workers.
+    #[cfg(tokio_unstable)]
+    pub local_queue_depth: usize,
+    #[cfg(tokio_unstable)]
+    pub blocking_threads: usize,
+    #[cfg(tokio_unstable)]
+    pub spawned_tasks: u64,
+}
+
+impl RuntimeSample {
+    pub fn take(runtime: &RuntimeMetrics) -> Self {
+        let workers = runtime.num_workers();
+        Self {
+            workers,
+            alive_tasks: runtime.num_alive_tasks(),
+            global_queue_depth: runtime.global_queue_depth(),
+            parks: (0..workers).map(|worker| runtime.worker_park_count(worker)).sum(),
+            busy: (0..workers).map(|worker| runtime.worker_total_busy_duration(worker)).sum(),
+            #[cfg(tokio_unstable)]
+            local_queue_depth: (0..workers)
+                .map(|worker| runtime.worker_local_queue_depth(worker))
+                .sum(),
+            #[cfg(tokio_unstable)]
+            blocking_threads: runtime.num_blocking_threads(),
+            #[cfg(tokio_unstable)]
+            spawned_tasks: runtime.spawned_tasks_count(),
+        }
+    }
+
+    /// Brings the `runtime_*` metrics from the `previous` sample to this one.
+    fn publish(&self, previous: &Self, metrics: &Metrics) {
+        // Gauges move by the difference; counters only ever go up
+        let gauge = |name, now: usize, before: usize| {
+            metrics.adjust(name, now as i64 - before as i64);
+        };
+        gauge(metrics::RUNTIME_WORKERS, self.workers, previous.workers);
+        gauge(metrics::RUNTIME_ALIVE_TASKS, self.alive_tasks, previous.alive_tasks);
+        gauge(
+            metrics::RUNTIME_GLOBAL_QUEUE_DEPTH,
+            self.global_queue_depth,
+            previous.global_queue_depth,
+        );
+        metrics.incr(metrics::RUNTIME_PARKS, self.parks.saturating_sub(previous.parks));
+        let busy = self.busy.saturating_sub(previous.busy).as_millis() as u64;
+        metrics.incr(metrics::RUNTIME_BUSY_MS, busy);
+        #[cfg(tokio_unstable)]
+        {
+            gauge(
+                metrics::RUNTIME_LOCAL_QUEUE_DEPTH,
+                self.local_queue_depth,
+                previous.local_queue_depth,
+            );
+            gauge(metrics::RUNTIME_BLOCKING_THREADS, self.blocking_threads, previous.blocking_threads);
+            let spawned = self.spawned_tasks.saturating_sub(previous.spawned_tasks);
+            metrics.incr(metrics::RUNTIME_SPAWNED_TASKS, spawned);
+        }
+    }
+}
+
+impl fmt::Display for RuntimeSample {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "workers={} alive_tasks={} global_queue={} parks={} busy={:.1}s",
+            self.workers,
+            self.alive_tasks,
+            self.global_queue_depth,
+            self.parks,
+            self.busy.as_secs_f64(),
+        )?;
+        #[cfg(tokio_unstable)]
+        write!(
+            f,
+            " local_queues={} blocking_threads={} spawned_tasks={}",
+            self.local_queue_depth, self.blocking_threads, self.spawned_tasks,
+        )?;
+        Ok(())
+    }
+}
+
+/// Samples the current runtime every `interval` until the server drains.
+pub async fn run(interval: Duration, metrics: Metrics, log: LogTx, mut phase: watch::Receiver<Phase>) {
+    let runtime = tokio::runtime::Handle::current().metrics();
+    let mut previous = RuntimeSample::default();
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = readiness::wait_draining(&mut phase) => return,
+        }
+        let sample = RuntimeSample::take(&runtime);
+        sample.publish(&previous, &metrics);
+        log.send(LogMessage::info(format!("runtime: {}", sample))).await;
+        previous = sample;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logger::{self, LogOverflow};
+    use crate::metrics::InMemorySink;
+    use std::sync::Arc;
+    use tokio_stream::StreamExt;
+
+    fn value(metrics: &Metrics, name: &str) -> i64 {
+        metrics.snapshot().iter().find(|m| m.name == name).map_or(0, |m| m.value)
+    }
+
+    #[test]
+    fn the_metrics_follow_the_latest_sample() {
+        let metrics: Metrics = Arc::new(InMemorySink::default());
+        let first = RuntimeSample { workers: 4, alive_tasks: 10, parks: 100, ..Default::default() };
+        let second = RuntimeSample { alive_tasks: 3, parks: 150, ..first };
+        first.publish(&RuntimeSample::default(), &metrics);
+        second.publish(&first, &metrics);
+
+        assert_eq!(value(&metrics, metrics::RUNTIME_WORKERS), 4);
+        assert_eq!(value(&metrics, metrics::RUNTIME_ALIVE_TASKS), 3);
+        assert_eq!(value(&metrics, metrics::RUNTIME_PARKS), 150);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn each_interval_a_sample_is_logged() {
+        let metrics: Metrics = Arc::new(InMemorySink::default());
+        let (log_tx, mut log) = logger::channel(16, &LogOverflow::Wait, metrics.clone());
+        let (phase_tx, phase) = watch::channel(Phase::Ready);
+        tokio::spawn(run(Duration::from_secs(10), metrics.clone(), log_tx, phase));
+
+        let logged = log.next().await.unwrap();
+        let text = String::from_utf8_lossy(&logged.text).into_owned();
+        // A current_thread test runtime: one worker, this task and `run` alive
+        assert!(text.starts_with("runtime: workers=1 alive_tasks="), "{}", text);
+        assert_eq!(value(&metrics, metrics::RUNTIME_WORKERS), 1);
+
+        phase_tx.send(Phase::Draining).unwrap();
+    }
+}
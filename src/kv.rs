@@ -0,0 +1,800 @@
+//! A small in-memory key-value store exposed through the text protocol.
+//!
+//! ```text
+//! SET <key> <value>              -> OK
+//! GET <key>                      -> VALUE <value> | NIL
+//! DEL <key>                      -> DELETED | NOT_FOUND
+//! GETDEL <key>                   -> VALUE <value> | NIL
+//! GETSET <key> <value>           -> VALUE <old> | NIL
+//! CAS <key> <expected> <new>     -> OK | MISMATCH VALUE <current> | MISMATCH NIL
+//! EXPIRE <key> <secs>            -> OK | NOT_FOUND
+//! TTL <key>                      -> TTL <secs> | NO_TTL | NOT_FOUND
+//!
+//! MULTI                          -> OK
+//! <command>                      -> QUEUED | ERR: <why>
+//! EXEC                           -> <reply> ... END | ERR: transaction aborted, ...
+//! DISCARD                        -> OK: discarded <n> command(s)
+//! ```
+//!
+//! Keys and values are single words. Every command, including the compound
+//! ones, runs under a single acquisition of the store's lock: a client doing
+//! `GET` followed by `SET` can lose a concurrent update, while `GETSET` or
+//! `CAS` cannot. A [`Transaction`] stretches that to several commands: the
+//! ones queued after `MULTI` run together on `EXEC`, under one acquisition,
+//! so no other client sees the store halfway through them. If any of them
+//! was refused while queueing, `EXEC` runs none of them.
+//!
+//! A key given a TTL with `EXPIRE` is removed once it runs out, in two
+//! places, like the entries of an `ExpiringMap`:
+//!
+//! - lazily: a command on a key past its deadline finds it gone;
+//! - actively, once [`Store::spawn_sweeper`] has been called: a sweeper
+//!   task keeps a `DelayQueue` with a timer per `EXPIRE`, and removes the
+//!   key when it fires, so keys nobody asks about again are reclaimed too.
+//!
+//! `SET` and `GETSET` replace a key's TTL along with its value; `CAS`
+//! keeps it. A timer whose key has been given a new TTL since, or none,
+//! does nothing: each TTL carries a generation number, and so does its timer.
+//!
+//! Every command that changes the store is numbered, and handed to the
+//! replicas following it (see `crate::replica`) and, once
+//! [`Store::set_wal`] has been called, to a write-ahead log (see
+//! `crate::wal`).
+
+use crate::fanout::{Fanout, Subscription};
+use crate::metrics::{self, Metrics};
+use crate::task;
+use crate::wal::{Change, Record, Wal};
+use bytes::Bytes;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tokio_stream::StreamExt;
+use tokio_util::time::DelayQueue;
+
+/// Commands one transaction can queue.
+pub const MAX_QUEUED: usize = 100;
+
+/// Changes a replica may be behind before it misses some, and has to sync again.
+const REPLICA_BUFFER: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KvCommand {
+    Get(String),
+    Set(String, String),
+    Del(String),
+    GetDel(String),
+    GetSet(String, String),
+    Cas { key: String, expected: String, new: String },
+    Expire(String, Duration),
+    Ttl(String),
+}
+
+impl KvCommand {
+    /// Parses a line of input.
+    ///
+    /// Returns `None` when the line is not a KV command at all, so the
+    /// caller can fall back to its other commands.
+    pub fn parse(line: &str) -> Option<Result<Self, String>> {
+        let mut words = line.split_whitespace();
+        let name = words.next()?.to_ascii_uppercase();
+        let args: Vec<String> = words.map(str::to_string).collect();
+
+        let usage = match name.as_str() {
+            "GET" | "DEL" | "GETDEL" | "TTL" => "<key>",
+            "SET" | "GETSET" => "<key> <value>",
+            "EXPIRE" => "<key> <secs>",
+            "CAS" => "<key> <expected> <new>",
+            _ => return None,
+        };
+        let expected_args = usage.split(' ').count();
+        if args.len() != expected_args {
+            return Some(Err(format!("usage: {} {}", name, usage)));
+        }
+
+        let mut args = args.into_iter();
+        let mut arg = || args.next().unwrap();
+        Some(Ok(match name.as_str() {
+            "GET" => Self::Get(arg()),
+            "DEL" => Self::Del(arg()),
+            "GETDEL" => Self::GetDel(arg()),
+            "TTL" => Self::Ttl(arg()),
+            "SET" => Self::Set(arg(), arg()),
+            "GETSET" => Self::GetSet(arg(), arg()),
+            "EXPIRE" => {
+                let key = arg();
+                let ttl = arg()
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|secs| *secs >= 0.0)
+                    .and_then(|secs| Duration::try_from_secs_f64(secs).ok());
+                match ttl {
+                    Some(ttl) => Self::Expire(key, ttl),
+                    None => return Some(Err(format!("usage: {} {}", name, usage))),
+                }
+            }
+            _ => Self::Cas { key: arg(), expected: arg(), new: arg() },
+        }))
+    }
+
+    /// The key the command is about; every command has exactly one.
+    pub fn key(&self) -> &str {
+        match self {
+            Self::Get(key)
+            | Self::Set(key, _)
+            | Self::Del(key)
+            | Self::GetDel(key)
+            | Self::GetSet(key, _)
+            | Self::Cas { key, .. }
+            | Self::Expire(key, _)
+            | Self::Ttl(key) => key,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KvReply {
+    Ok,
+    /// The value read (or replaced) by the command, if there was one.
+    Value(Option<String>),
+    Deleted(bool),
+    /// A CAS did not match; carries the value that is actually stored.
+    Mismatch(Option<String>),
+    /// `EXPIRE` or `TTL` on a key that does not exist.
+    NotFound,
+    /// The time a key has left, if it has a TTL.
+    Ttl(Option<Duration>),
+}
+
+impl fmt::Display for KvReply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ok => write!(f, "OK"),
+            Self::Value(Some(value)) => write!(f, "VALUE {}", value),
+            Self::Value(None) => write!(f, "NIL"),
+            Self::Deleted(true) => write!(f, "DELETED"),
+            Self::Deleted(false) => write!(f, "NOT_FOUND"),
+            Self::Mismatch(Some(current)) => write!(f, "MISMATCH VALUE {}", current),
+            Self::Mismatch(None) => write!(f, "MISMATCH NIL"),
+            Self::NotFound => write!(f, "NOT_FOUND"),
+            // In whole seconds, rounded up: a key with `TTL 0` is gone
+            Self::Ttl(Some(left)) => write!(f, "TTL {}", left.as_secs_f64().ceil()),
+            Self::Ttl(None) => write!(f, "NO_TTL"),
+        }
+    }
+}
+
+/// `MULTI`, `EXEC` and `DISCARD`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxCommand {
+    Multi,
+    Exec,
+    Discard,
+}
+
+impl TxCommand {
+    pub fn parse(line: &str) -> Option<Self> {
+        match line.trim().to_ascii_uppercase().as_str() {
+            "MULTI" => Some(Self::Multi),
+            "EXEC" => Some(Self::Exec),
+            "DISCARD" => Some(Self::Discard),
+            _ => None,
+        }
+    }
+}
+
+/// The commands a connection queued after `MULTI`, until `EXEC` or `DISCARD`.
+#[derive(Debug, Default)]
+pub struct Transaction {
+    commands: Vec<KvCommand>,
+    // Set once a line was refused; `EXEC` then runs nothing
+    refused: bool,
+}
+
+impl Transaction {
+    /// Queues a line sent inside the transaction, and returns the reply to it.
+    pub fn queue(&mut self, line: &str) -> String {
+        let error = match KvCommand::parse(line) {
+            Some(Ok(_)) if self.commands.len() == MAX_QUEUED => {
+                format!("a transaction queues at most {} commands", MAX_QUEUED)
+            }
+            Some(Ok(command)) => {
+                self.commands.push(command);
+                return "QUEUED\n".to_string();
+            }
+            Some(Err(usage)) => usage,
+            None => "only key-value commands can be queued".to_string(),
+        };
+        self.refused = true;
+        format!("ERR: {}\n", error)
+    }
+
+    /// Runs the queued commands on `store`, all at once, and returns their
+    /// replies in order, then `END`.
+    pub fn exec(self, store: &Store) -> String {
+        if self.refused {
+            return "ERR: transaction aborted, a queued command was refused\n".to_string();
+        }
+        let mut response = String::new();
+        for reply in store.execute_all(self.commands) {
+            response.push_str(&format!("{}\n", reply));
+        }
+        response.push_str("END\n");
+        response
+    }
+
+    /// Commands queued so far.
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+}
+
+struct Slot {
+    value: String,
+    // When the key expires, and the generation of its timer
+    expiry: Option<(Instant, u64)>,
+}
+
+impl Slot {
+    fn new(value: String) -> Self {
+        Self { value, expiry: None }
+    }
+
+    fn expired(&self, now: Instant) -> bool {
+        self.expiry.is_some_and(|(deadline, _)| deadline <= now)
+    }
+}
+
+struct Entries {
+    map: HashMap<String, Slot>,
+    next_generation: u64,
+    wal: Option<Wal>,
+    // The number of the last change
+    seq: u64,
+    // The changes, as lines, for the replicas
+    feed: Fanout,
+}
+
+impl Entries {
+    /// Numbers what a command changed, and hands it to the replicas and
+    /// the log, if there is one.
+    fn log(&mut self, changes: Vec<Change>) {
+        if changes.is_empty() {
+            return;
+        }
+        self.seq += 1;
+        let record = Record { seq: self.seq, changes };
+        self.feed.publish(Bytes::from(record.to_string()));
+        if let Some(wal) = &self.wal {
+            wal.append(record);
+        }
+    }
+
+    fn copy(&self) -> BTreeMap<String, String> {
+        let now = Instant::now();
+        self.map
+            .iter()
+            .filter(|(_, slot)| !slot.expired(now))
+            .map(|(k, slot)| (k.clone(), slot.value.clone()))
+            .collect()
+    }
+}
+
+/// A timer for the sweeper: (key, generation, TTL).
+type Timer = (String, u64, Duration);
+
+pub struct Store {
+    entries: Arc<Mutex<Entries>>,
+    metrics: Metrics,
+    // Set once the sweeper runs; without it keys only expire lazily
+    timers: OnceLock<mpsc::UnboundedSender<Timer>>,
+}
+
+impl Store {
+    pub fn new(metrics: Metrics) -> Self {
+        let (gauge, missed) = (metrics::REPLICAS_CONNECTED, metrics::REPLICATION_CHANGES_MISSED);
+        let entries = Entries {
+            map: HashMap::new(),
+            next_generation: 0,
+            wal: None,
+            seq: 0,
+            feed: Fanout::with_buffer(metrics.clone(), gauge, missed, REPLICA_BUFFER),
+        };
+        Self { entries: Arc::new(Mutex::new(entries)), metrics, timers: OnceLock::new() }
+    }
+
+    /// Spawns the task that removes keys as their TTLs run out. It stops
+    /// once the store is dropped.
+    pub fn spawn_sweeper(&self) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        if self.timers.set(tx).is_ok() {
+            let entries = Arc::downgrade(&self.entries);
+            task::spawn("kv sweeper", sweep(entries, self.metrics.clone(), rx));
+        }
+    }
+
+    /// Runs one command atomically.
+    ///
+    /// The lock is synchronous and released before this returns, so it is
+    /// never held across an `.await`.
+    pub fn execute(&self, command: KvCommand) -> KvReply {
+        let mut entries = self.entries.lock().unwrap();
+        let mut changes = Vec::new();
+        let reply = self.apply(&mut entries, command, &mut changes);
+        entries.log(changes);
+        reply
+    }
+
+    /// Runs `commands` in order, under one acquisition of the lock: other
+    /// clients see the store as it was before all of them, or after.
+    pub fn execute_all(&self, commands: Vec<KvCommand>) -> Vec<KvReply> {
+        let mut entries = self.entries.lock().unwrap();
+        // One record for all of them, so a replay does not stop halfway either
+        let mut changes = Vec::new();
+        let replies = commands
+            .into_iter()
+            .map(|command| self.apply(&mut entries, command, &mut changes))
+            .collect();
+        entries.log(changes);
+        replies
+    }
+
+    /// A copy of every entry that has not expired, sorted by key.
+    pub fn entries(&self) -> BTreeMap<String, String> {
+        self.checkpoint().0
+    }
+
+    /// The entries, and the number of the last change they include, read
+    /// together.
+    pub fn checkpoint(&self) -> (BTreeMap<String, String>, u64) {
+        let entries = self.entries.lock().unwrap();
+        (entries.copy(), entries.seq)
+    }
+
+    /// Like [`checkpoint`](Self::checkpoint), plus every change from then
+    /// on, as lines: none can fall in between.
+    pub fn replicate(&self) -> (BTreeMap<String, String>, u64, Subscription) {
+        let entries = self.entries.lock().unwrap();
+        (entries.copy(), entries.seq, entries.feed.subscribe())
+    }
+
+    /// Replaces the whole store, e.g. with entries restored from disk;
+    /// `seq` is the last change they include. The restored keys have no TTL.
+    pub fn restore(&self, entries: BTreeMap<String, String>, seq: u64) {
+        let map = entries.into_iter().map(|(k, v)| (k, Slot::new(v))).collect();
+        let mut entries = self.entries.lock().unwrap();
+        entries.map = map;
+        entries.seq = seq;
+    }
+
+    /// Applies the records of a write-ahead log, or of a primary, on top of
+    /// a restored snapshot, without logging them again.
+    pub fn replay(&self, records: &[Record]) {
+        let mut entries = self.entries.lock().unwrap();
+        for record in records {
+            for change in &record.changes {
+                match change {
+                    Change::Set(key, value) => {
+                        entries.map.insert(key.clone(), Slot::new(value.clone()));
+                    }
+                    Change::Del(key) => {
+                        entries.map.remove(key);
+                    }
+                }
+            }
+            entries.seq = record.seq;
+        }
+    }
+
+    /// Logs every change from now on to `wal`, numbered after the last one
+    /// restored or replayed.
+    pub fn set_wal(&self, wal: Wal) {
+        self.entries.lock().unwrap().wal = Some(wal);
+    }
+
+    /// Drops the records up to `seq` from the log, once a snapshot
+    /// including them has been saved.
+    pub fn compact_wal(&self, seq: u64) {
+        if let Some(wal) = &self.entries.lock().unwrap().wal {
+            wal.compact(seq);
+        }
+    }
+
+    /// Waits until the changes made so far are on disk, if the log's
+    /// durability asks for that; see `crate::wal`.
+    pub async fn synced(&self) -> Result<(), String> {
+        let (wal, seq) = {
+            let entries = self.entries.lock().unwrap();
+            match &entries.wal {
+                Some(wal) => (wal.clone(), entries.seq),
+                None => return Ok(()),
+            }
+        };
+        wal.synced(seq).await
+    }
+
+    fn apply(&self, entries: &mut Entries, command: KvCommand, log: &mut Vec<Change>) -> KvReply {
+        let now = Instant::now();
+        // Lazily: whoever touches a key past its deadline first removes it
+        if entries.map.get(command.key()).is_some_and(|slot| slot.expired(now)) {
+            entries.map.remove(command.key());
+            self.metrics.incr(metrics::KV_KEYS_EXPIRED_ON_ACCESS, 1);
+            log.push(Change::Del(command.key().to_string()));
+        }
+
+        let map = &mut entries.map;
+        match command {
+            KvCommand::Get(key) => KvReply::Value(map.get(&key).map(|slot| slot.value.clone())),
+            KvCommand::Set(key, value) => {
+                log.push(Change::Set(key.clone(), value.clone()));
+                map.insert(key, Slot::new(value));
+                KvReply::Ok
+            }
+            KvCommand::Del(key) => {
+                let removed = map.remove(&key);
+                if removed.is_some() {
+                    log.push(Change::Del(key));
+                }
+                KvReply::Deleted(removed.is_some())
+            }
+            KvCommand::GetDel(key) => {
+                let removed = map.remove(&key);
+                if removed.is_some() {
+                    log.push(Change::Del(key));
+                }
+                KvReply::Value(removed.map(|slot| slot.value))
+            }
+            KvCommand::GetSet(key, value) => {
+                log.push(Change::Set(key.clone(), value.clone()));
+                KvReply::Value(map.insert(key, Slot::new(value)).map(|slot| slot.value))
+            }
+            KvCommand::Cas { key, expected, new } => match map.get_mut(&key) {
+                Some(current) if current.value == expected => {
+                    log.push(Change::Set(key, new.clone()));
+                    current.value = new;
+                    KvReply::Ok
+                }
+                current => KvReply::Mismatch(current.map(|slot| slot.value.clone())),
+            },
+            KvCommand::Expire(key, ttl) => {
+                let Some(slot) = map.get_mut(&key) else { return KvReply::NotFound };
+                let generation = entries.next_generation;
+                entries.next_generation += 1;
+                slot.expiry = Some((now + ttl, generation));
+                // Only fails when the sweeper is gone, i.e. the runtime is shutting down
+                if let Some(timers) = self.timers.get() {
+                    let _ = timers.send((key, generation, ttl));
+                }
+                KvReply::Ok
+            }
+            KvCommand::Ttl(key) => match map.get(&key) {
+                Some(slot) => {
+                    KvReply::Ttl(slot.expiry.map(|(deadline, _)| deadline.duration_since(now)))
+                }
+                None => KvReply::NotFound,
+            },
+        }
+    }
+}
+
+/// Removes keys whose timers fire, until the store is dropped.
+async fn sweep(
+    entries: Weak<Mutex<Entries>>,
+    metrics: Metrics,
+    mut timers: mpsc::UnboundedReceiver<Timer>,
+) {
+    let mut queue = DelayQueue::new();
+    loop {
+        tokio::select! {
+            timer = timers.recv() => match timer {
+                Some((key, generation, ttl)) => {
+                    queue.insert((key, generation), ttl);
+                }
+                // The store is gone, and its keys with it
+                None => return,
+            },
+            // An empty `DelayQueue` yields `None` right away instead of waiting
+            Some(expired) = queue.next(), if !queue.is_empty() => {
+                let (key, generation) = expired.into_inner();
+                let Some(entries) = entries.upgrade() else { return };
+                let mut entries = entries.lock().unwrap();
+                // A key given a new TTL since, or none, has moved on from this timer
+                let current = entries
+                    .map
+                    .get(&key)
+                    .is_some_and(|slot| slot.expiry.is_some_and(|(_, g)| g == generation));
+                if current {
+                    entries.map.remove(&key);
+                    metrics.incr(metrics::KV_KEYS_EXPIRED_BY_SWEEPER, 1);
+                    entries.log(vec![Change::Del(key)]);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::InMemorySink;
+    use proptest::prelude::*;
+    use std::collections::HashSet;
+    use std::sync::Arc;
+
+    fn store() -> Store {
+        Store::new(Arc::new(InMemorySink::default()))
+    }
+
+    fn run(store: &Store, line: &str) -> KvReply {
+        store.execute(KvCommand::parse(line).unwrap().unwrap())
+    }
+
+    #[test]
+    fn parses_commands_and_usage_errors() {
+        assert_eq!(KvCommand::parse("STATS"), None);
+        assert_eq!(KvCommand::parse("hello world"), None);
+        assert_eq!(
+            KvCommand::parse("getset a 1"),
+            Some(Ok(KvCommand::GetSet("a".into(), "1".into()))),
+        );
+        assert_eq!(
+            KvCommand::parse("CAS a 1"),
+            Some(Err("usage: CAS <key> <expected> <new>".into())),
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn any_line_parses_without_panicking(line in "\\PC*") {
+            if let Some(Err(usage)) = KvCommand::parse(&line) {
+                prop_assert!(usage.starts_with("usage: "));
+            }
+        }
+
+        /// A command name with the wrong number of words is a usage error.
+        #[test]
+        fn the_word_count_decides_between_a_command_and_its_usage(
+            name in prop_oneof!["get", "SET", "Del", "GETDEL", "getset", "CAS"],
+            args in proptest::collection::vec("[a-z0-9]{1,8}", 0..5),
+        ) {
+            let expected = match name.to_ascii_uppercase().as_str() {
+                "SET" | "GETSET" => 2,
+                "CAS" => 3,
+                _ => 1,
+            };
+            let parsed = KvCommand::parse(&format!("{} {}", name, args.join(" "))).unwrap();
+            prop_assert_eq!(parsed.is_ok(), args.len() == expected);
+        }
+    }
+
+    #[test]
+    fn compound_commands() {
+        let store = store();
+
+        assert_eq!(run(&store, "GETSET a 1"), KvReply::Value(None));
+        assert_eq!(run(&store, "GETSET a 2"), KvReply::Value(Some("1".into())));
+
+        assert_eq!(run(&store, "CAS a 1 3"), KvReply::Mismatch(Some("2".into())));
+        assert_eq!(run(&store, "CAS a 2 3"), KvReply::Ok);
+        assert_eq!(run(&store, "CAS b 1 2"), KvReply::Mismatch(None));
+
+        assert_eq!(run(&store, "GETDEL a"), KvReply::Value(Some("3".into())));
+        assert_eq!(run(&store, "GETDEL a"), KvReply::Value(None));
+        assert_eq!(run(&store, "GET a"), KvReply::Value(None));
+    }
+
+    #[test]
+    fn a_transaction_runs_all_of_its_commands_or_none() {
+        let store = store();
+        let mut transaction = Transaction::default();
+        assert_eq!(transaction.queue("SET a 1"), "QUEUED\n");
+        assert_eq!(transaction.queue("GETSET a 2"), "QUEUED\n");
+        assert_eq!(transaction.queue("CAS a 2 3"), "QUEUED\n");
+        assert_eq!(transaction.exec(&store), "OK\nVALUE 1\nOK\nEND\n");
+        assert_eq!(run(&store, "GET a"), KvReply::Value(Some("3".into())));
+
+        let mut transaction = Transaction::default();
+        transaction.queue("DEL a");
+        assert_eq!(transaction.queue("SET a"), "ERR: usage: SET <key> <value>\n");
+        assert!(transaction.exec(&store).starts_with("ERR: transaction aborted"));
+        assert_eq!(run(&store, "GET a"), KvReply::Value(Some("3".into())));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expired_keys_are_removed_on_access_or_by_the_sweeper() {
+        let metrics: Metrics = Arc::new(InMemorySink::default());
+        let store = Store::new(metrics.clone());
+        store.spawn_sweeper();
+        let value = |name: &str| {
+            metrics.snapshot().iter().find(|m| m.name == name).map_or(0, |m| m.value)
+        };
+        run(&store, "SET session abc");
+        run(&store, "SET token xyz");
+        assert_eq!(run(&store, "EXPIRE session 10"), KvReply::Ok);
+        assert_eq!(run(&store, "EXPIRE missing 10"), KvReply::NotFound);
+        assert_eq!(run(&store, "TTL session").to_string(), "TTL 10");
+        assert_eq!(run(&store, "TTL token"), KvReply::Ttl(None));
+
+        // A new TTL replaces the old one, whose timer then finds nothing to do
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        assert_eq!(run(&store, "TTL session").to_string(), "TTL 8");
+        run(&store, "EXPIRE session 20");
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert_eq!(run(&store, "GET session"), KvReply::Value(Some("abc".into())));
+        assert_eq!(value(metrics::KV_KEYS_EXPIRED_BY_SWEEPER), 0);
+
+        tokio::time::sleep(Duration::from_secs(11)).await;
+        assert_eq!(store.entries().len(), 1);
+        assert_eq!(value(metrics::KV_KEYS_EXPIRED_BY_SWEEPER), 1);
+
+        // `SET` clears a TTL; without a sweeper, a key expires on access
+        let lazy = Store::new(metrics.clone());
+        run(&lazy, "SET a 1");
+        run(&lazy, "EXPIRE a 1");
+        run(&lazy, "SET a 2");
+        run(&lazy, "SET b 3");
+        run(&lazy, "EXPIRE b 1");
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(run(&lazy, "GET a"), KvReply::Value(Some("2".into())));
+        assert_eq!(run(&lazy, "GETSET b 4"), KvReply::Value(None));
+        assert_eq!(value(metrics::KV_KEYS_EXPIRED_ON_ACCESS), 1);
+    }
+
+    /// Writers move two keys together in transactions; readers, also in
+    /// transactions, must never see one key moved without the other.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn nobody_sees_a_transaction_half_applied() {
+        const WRITES: usize = 200;
+
+        let store = Arc::new(store());
+        store.execute_all(vec![
+            KvCommand::Set("a".into(), "0".into()),
+            KvCommand::Set("b".into(), "0".into()),
+        ]);
+        let mut tasks = Vec::new();
+        for writer in 0..4 {
+            let store = store.clone();
+            tasks.push(tokio::spawn(async move {
+                for i in 0..WRITES {
+                    let mut transaction = Transaction::default();
+                    transaction.queue(&format!("SET a {}-{}", writer, i));
+                    transaction.queue(&format!("SET b {}-{}", writer, i));
+                    transaction.exec(&store);
+                    tokio::task::yield_now().await;
+                }
+            }));
+        }
+        for _ in 0..4 {
+            let store = store.clone();
+            tasks.push(tokio::spawn(async move {
+                for _ in 0..WRITES {
+                    let read = vec![KvCommand::Get("a".into()), KvCommand::Get("b".into())];
+                    let replies = store.execute_all(read);
+                    assert_eq!(replies[0], replies[1]);
+                    tokio::task::yield_now().await;
+                }
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+    }
+
+    /// Many clients increment one counter with CAS retry loops. If a CAS
+    /// could interleave with another update, increments would be lost.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_cas_loses_no_increments() {
+        const CLIENTS: usize = 8;
+        const INCREMENTS: usize = 200;
+
+        let store = Arc::new(store());
+        run(&store, "SET counter 0");
+
+        let mut tasks = Vec::new();
+        for _ in 0..CLIENTS {
+            let store = store.clone();
+            tasks.push(tokio::spawn(async move {
+                for _ in 0..INCREMENTS {
+                    let mut current = match run(&store, "GET counter") {
+                        KvReply::Value(Some(v)) => v,
+                        other => panic!("unexpected reply: {}", other),
+                    };
+                    loop {
+                        let next = current.parse::<usize>().unwrap() + 1;
+                        match run(&store, &format!("CAS counter {} {}", current, next)) {
+                            KvReply::Ok => break,
+                            KvReply::Mismatch(Some(actual)) => current = actual,
+                            other => panic!("unexpected reply: {}", other),
+                        }
+                        tokio::task::yield_now().await;
+                    }
+                }
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let expected = (CLIENTS * INCREMENTS).to_string();
+        assert_eq!(run(&store, "GET counter"), KvReply::Value(Some(expected)));
+    }
+
+    /// Clients swap unique values into one key with GETSET. Every value
+    /// must come back out exactly once (as some GETSET's old value or as
+    /// the final value): nothing is lost and nothing is seen twice.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_getset_hands_over_every_value_once() {
+        const CLIENTS: usize = 8;
+        const SWAPS: usize = 200;
+
+        let store = Arc::new(store());
+        let mut tasks = Vec::new();
+        for client in 0..CLIENTS {
+            let store = store.clone();
+            tasks.push(tokio::spawn(async move {
+                let mut seen = Vec::new();
+                for i in 0..SWAPS {
+                    let line = format!("GETSET key {}-{}", client, i);
+                    if let KvReply::Value(Some(old)) = run(&store, &line) {
+                        seen.push(old);
+                    }
+                    tokio::task::yield_now().await;
+                }
+                seen
+            }));
+        }
+
+        let mut seen = Vec::new();
+        for task in tasks {
+            seen.extend(task.await.unwrap());
+        }
+        match run(&store, "GETDEL key") {
+            KvReply::Value(Some(last)) => seen.push(last),
+            other => panic!("unexpected reply: {}", other),
+        }
+
+        let unique: HashSet<_> = seen.iter().collect();
+        assert_eq!(seen.len(), CLIENTS * SWAPS);
+        assert_eq!(unique.len(), CLIENTS * SWAPS);
+    }
+
+    #[tokio::test]
+    async fn a_replayed_log_rebuilds_the_store() {
+        use crate::wal::{self, Durability};
+
+        let dir = std::env::temp_dir().join(format!("kv-wal-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let path = wal::path(&dir.join("state.toml"));
+        let _ = tokio::fs::remove_file(&path).await;
+
+        let store = store();
+        let metrics = Arc::new(InMemorySink::default());
+        store.set_wal(Wal::open(path.clone(), Durability::Sync, 0, metrics).await.unwrap());
+        for line in ["SET a 1", "SET b 2", "GETSET a 3", "CAS b 2 4", "CAS b 9 9", "DEL b"] {
+            run(&store, line);
+        }
+        // Reads and failed commands change nothing, so they are not logged
+        run(&store, "GET a");
+        store.execute_all(vec![
+            KvCommand::Set("c".into(), "5".into()),
+            KvCommand::GetDel("a".into()),
+        ]);
+        store.synced().await.unwrap();
+
+        let records = wal::replay(&path, 0).await.unwrap();
+        assert_eq!(records.len(), 6);
+        assert_eq!(records[5].changes.len(), 2);
+        let replayed = Store::new(Arc::new(InMemorySink::default()));
+        replayed.replay(&records);
+        assert_eq!(replayed.entries(), store.entries());
+        assert_eq!(store.checkpoint().1, 6);
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -0,0 +1,111 @@
+//! The example server as a library.
+//!
+//! The `tokio-examples` binary is a thin wrapper around [`server::run`].
+//! Embedders can instead bind a [`Server`] themselves and drive its
+//! [`incoming`](Server::incoming) stream of connections with their own loop:
+//!
+//! ```no_run
+//! use tokio_examples::{Server, ServerConfig};
+//! use tokio_stream::StreamExt;
+//!
+//! # async fn example() -> Result<(), tokio_examples::ServerError> {
+//! let server = Server::bind(ServerConfig::default()).await?;
+//! let mut incoming = server.incoming();
+//! while let Some(conn) = incoming.next().await {
+//!     tokio::spawn(conn.serve());
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`Server::duplex`] opens an in-process connection over an in-memory
+//! pipe, for clients in the same process that do not need TCP.
+//!
+//! [`server::start`] starts the whole server as the binary runs it, and
+//! returns before serving, so a test can learn the bound port and stop the
+//! server through a [`ServerHandle`].
+
+pub mod acl;
+pub mod admin;
+pub mod auth;
+pub mod backpressure;
+pub mod buffers;
+pub mod chaos;
+pub mod circuit;
+pub mod checksum;
+pub mod clock;
+pub mod close;
+pub mod compute;
+pub mod config;
+pub mod counting;
+pub mod dialer;
+pub mod doctor;
+pub mod error;
+pub mod expiring;
+pub mod fanout;
+pub mod files;
+pub mod flags;
+pub mod framing;
+pub mod futures_examples;
+pub mod gzip;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod handshake;
+pub mod health;
+pub mod heartbeat;
+pub mod history;
+pub mod http;
+pub mod http2;
+pub mod ingest;
+pub mod introspect;
+pub mod kv;
+pub mod listener;
+pub mod listeners;
+pub mod logger;
+pub mod metrics;
+pub mod middleware;
+pub mod outbox;
+pub mod peers;
+pub mod persist;
+pub mod protocol;
+pub mod proxy;
+pub mod pubsub;
+pub mod queue;
+pub mod quota;
+pub mod rate_limit;
+pub mod readiness;
+pub mod record;
+pub mod registry;
+pub mod replica;
+pub mod restart;
+pub mod retry;
+pub mod scheduler;
+pub mod script;
+pub mod server;
+pub mod session;
+pub mod shedding;
+pub mod signals;
+pub mod socks;
+pub mod state;
+pub mod supervise;
+pub mod tail;
+pub mod task;
+pub mod template;
+pub mod throttle;
+pub mod tls;
+pub mod trace;
+pub mod transport;
+pub mod udp;
+pub mod upload;
+#[cfg(all(target_os = "linux", feature = "uring"))]
+pub mod uring;
+pub mod vhost;
+pub mod wal;
+pub mod watcher;
+pub mod wiredump;
+pub mod workers;
+
+pub use close::CloseReason;
+pub use config::ServerConfig;
+pub use error::ServerError;
+pub use server::{Connection, Incoming, RunningServer, Server, ServerHandle};
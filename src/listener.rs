@@ -0,0 +1,86 @@
+//! Socket options that `tokio::net` does not expose.
+//!
+//! Listener sockets with `SO_REUSEPORT`, and tuning for connected streams.
+//! Both go through `socket2`, which wraps the raw `setsockopt` calls.
+
+use socket2::{SockRef, TcpKeepalive};
+#[cfg(unix)]
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+/// Tuning applied to every connected stream, on the server and in the client.
+///
+/// `None` leaves the operating system default in place.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// `TCP_NODELAY`: send small writes right away instead of waiting to
+    /// coalesce them (Nagle's algorithm). One-line replies benefit most.
+    pub nodelay: bool,
+    /// `SO_KEEPALIVE`: probe a connection after it was idle this long, so a
+    /// peer that vanished without closing is eventually noticed.
+    pub keepalive: Option<Duration>,
+    /// Time between keepalive probes; defaults to the idle time.
+    pub keepalive_interval: Option<Duration>,
+    /// `SO_SNDBUF`, in bytes. The kernel may round or double the value.
+    pub send_buffer: Option<usize>,
+    /// `SO_RCVBUF`, in bytes. The kernel may round or double the value.
+    pub recv_buffer: Option<usize>,
+}
+
+impl SocketOptions {
+    /// Sets the options on `stream`; stops at the first one that fails.
+    pub fn apply(&self, stream: &TcpStream) -> std::io::Result<()> {
+        // A borrowed view of the same file descriptor; nothing is duplicated
+        self.apply_to(SockRef::from(stream))
+    }
+
+    /// Like [`apply`](Self::apply), for a stream that is not a tokio one.
+    pub fn apply_to(&self, socket: SockRef<'_>) -> std::io::Result<()> {
+        if self.nodelay {
+            socket.set_tcp_nodelay(true)?;
+        }
+        if let Some(time) = self.keepalive {
+            let keepalive = TcpKeepalive::new().with_time(time);
+            #[cfg(any(target_os = "linux", target_os = "macos", windows))]
+            let keepalive = keepalive.with_interval(self.keepalive_interval.unwrap_or(time));
+            socket.set_tcp_keepalive(&keepalive)?;
+        }
+        if let Some(size) = self.send_buffer {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer {
+            socket.set_recv_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
+
+/// Binds a listening socket with `SO_REUSEPORT`.
+///
+/// Several sockets bound this way to the same address can coexist; the
+/// kernel spreads incoming connections across them. Each socket can then
+/// be driven by a different thread or task without sharing an accept queue.
+///
+/// The socket is returned as a std listener in non-blocking mode, so it can
+/// be moved to another thread and registered with that thread's runtime via
+/// `tokio::net::TcpListener::from_std`.
+#[cfg(unix)]
+pub fn bind_reuse_port(addr: SocketAddr) -> std::io::Result<std::net::TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
+
+#[cfg(not(unix))]
+pub fn bind_reuse_port(_addr: SocketAddr) -> std::io::Result<std::net::TcpListener> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "SO_REUSEPORT is only available on Unix platforms",
+    ))
+}
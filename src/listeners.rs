@@ -0,0 +1,333 @@
+//! The listener manager: several addresses, one server.
+//!
+//! Besides the main `--listen` address, the server can listen on any number
+//! of other addresses, each in one protocol mode:
+//!
+//! ```text
+//! --listener json=127.0.0.1:7002 --listener length=127.0.0.1:7001 --listener text=[::1]:7000
+//! ```
+//!
+//! Every listener gets its own accept loop task. They all share the same
+//! `State`, logger and shutdown: a `SET` on one is seen by a `GET` on
+//! another, and a drain stops every loop. [`Listeners`] keeps track of what
+//! was bound, and counts the connections each listener accepted, so the
+//! admin command `LISTENERS` can report them.
+//!
+//! A reload can add and remove listeners too. The listener manager, a task
+//! of the server, compares the reloaded list with what is bound: a new
+//! listener is bound and starts accepting right away, a removed one closes
+//! its socket and is listed as `draining` until its last connection ends.
+//! Those connections are not cut short, unless the whole server drains.
+
+use crate::metrics::{self, Metrics};
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+
+/// What a listener's connections speak.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListenerMode {
+    /// The line-based text protocol of the main listener.
+    Text,
+    /// The text protocol in length-prefixed frames.
+    Length,
+    /// JSON requests, one per line.
+    Json,
+    /// A SOCKS5 proxy.
+    Socks,
+    /// TLS, then whatever protocol ALPN settles on; see [`crate::tls`].
+    Tls,
+}
+
+impl ListenerMode {
+    /// How the startup log announces a listener in this mode.
+    pub fn describe(self) -> &'static str {
+        match self {
+            ListenerMode::Text => "Text protocol",
+            ListenerMode::Length => "Length-prefixed framing",
+            ListenerMode::Json => "JSON requests",
+            ListenerMode::Socks => "SOCKS5 proxy",
+            ListenerMode::Tls => "TLS (ALPN)",
+        }
+    }
+}
+
+impl fmt::Display for ListenerMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ListenerMode::Text => "text",
+            ListenerMode::Length => "length",
+            ListenerMode::Json => "json",
+            ListenerMode::Socks => "socks",
+            ListenerMode::Tls => "tls",
+        })
+    }
+}
+
+impl FromStr for ListenerMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(ListenerMode::Text),
+            "length" => Ok(ListenerMode::Length),
+            "json" => Ok(ListenerMode::Json),
+            "socks" => Ok(ListenerMode::Socks),
+            "tls" => Ok(ListenerMode::Tls),
+            _ => Err(format!("unknown listener mode: {} (text, length, json, socks or tls)", s)),
+        }
+    }
+}
+
+/// One listener to bind: `<mode>=<addr>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ListenerSpec {
+    pub mode: ListenerMode,
+    pub addr: SocketAddr,
+}
+
+impl FromStr for ListenerSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (mode, addr) = s
+            .split_once('=')
+            .ok_or_else(|| format!("invalid listener, expected <mode>=<addr>: {}", s))?;
+        let addr = addr.parse().map_err(|_| format!("invalid listener address: {}", addr))?;
+        Ok(Self { mode: mode.parse()?, addr })
+    }
+}
+
+/// Whether a listener still takes new connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListenerStatus {
+    Accepting,
+    /// Removed by a reload; its socket is closed, its connections are not.
+    Draining,
+}
+
+impl fmt::Display for ListenerStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ListenerStatus::Accepting => "accepting",
+            ListenerStatus::Draining => "draining",
+        })
+    }
+}
+
+/// How the listener manager stops one listener's accept loop.
+#[derive(Debug, Clone, Default)]
+pub struct ListenerStop {
+    /// Cancelled when a reload removes the listener.
+    pub requested: CancellationToken,
+    /// Cancelled by the accept loop once it has closed the socket.
+    pub closed: CancellationToken,
+}
+
+/// A listener that was bound.
+struct Bound {
+    // As configured: with port 0, `addr` has the port that was picked
+    spec: ListenerSpec,
+    addr: SocketAddr,
+    // Counter of accepted connections, e.g. `listener_json_127_0_0_1_7002_connections`
+    accepted: &'static str,
+    status: ListenerStatus,
+    stop: ListenerStop,
+}
+
+/// The per-listener numbers reported by `LISTENERS`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListenerStats {
+    pub mode: ListenerMode,
+    pub addr: SocketAddr,
+    pub accepted: i64,
+    pub status: ListenerStatus,
+}
+
+/// What a reload changes, from [`Listeners::reconcile`].
+#[derive(Debug, Default)]
+pub struct Changes {
+    /// Configured, but not bound yet.
+    pub bind: Vec<ListenerSpec>,
+    /// Bound, but no longer configured; already asked to stop.
+    pub retired: Vec<Retired>,
+}
+
+/// A listener a reload removed.
+#[derive(Debug)]
+pub struct Retired {
+    pub spec: ListenerSpec,
+    pub addr: SocketAddr,
+    /// Cancelled once its socket is closed, and its address free again.
+    pub closed: CancellationToken,
+}
+
+/// The listeners bound next to the main one.
+///
+/// Filled in while the server starts and changed by reloads; the counts
+/// themselves live in the metrics sink, where the accept loops bump them.
+pub struct Listeners {
+    bound: Mutex<Vec<Bound>>,
+    metrics: Metrics,
+}
+
+impl Listeners {
+    pub fn new(metrics: Metrics) -> Self {
+        Self { bound: Mutex::new(Vec::new()), metrics }
+    }
+
+    /// Records a listener for `spec`, bound to `addr`. Returns the counter
+    /// its accept loop increments for every connection, and how to stop it.
+    pub fn add(&self, spec: ListenerSpec, addr: SocketAddr) -> (&'static str, ListenerStop) {
+        let accepted = metrics::listener_connections(&spec.mode.to_string(), &addr.to_string());
+        // Listed in STATS from the start, even before its first connection
+        self.metrics.incr(accepted, 0);
+        let stop = ListenerStop::default();
+        let status = ListenerStatus::Accepting;
+        let bound = Bound { spec, addr, accepted, status, stop: stop.clone() };
+        self.bound.lock().unwrap().push(bound);
+        (accepted, stop)
+    }
+
+    /// Forgets the listener bound to `addr`, once its accept loop is done.
+    pub fn remove(&self, addr: SocketAddr) {
+        self.bound.lock().unwrap().retain(|bound| bound.addr != addr);
+    }
+
+    /// Compares `wanted`, the reloaded list, with the accepting listeners:
+    /// stops those that are no longer wanted and returns them, with the
+    /// specs that are still to be bound.
+    ///
+    /// Specs are compared as configured, so a listener bound to port 0
+    /// stays as long as the config still asks for port 0.
+    pub fn reconcile(&self, wanted: &[ListenerSpec]) -> Changes {
+        let mut bind = wanted.to_vec();
+        let mut retired = Vec::new();
+        for bound in self.bound.lock().unwrap().iter_mut() {
+            if bound.status == ListenerStatus::Draining {
+                continue;
+            }
+            match bind.iter().position(|spec| *spec == bound.spec) {
+                Some(kept) => {
+                    bind.remove(kept);
+                }
+                None => {
+                    bound.status = ListenerStatus::Draining;
+                    bound.stop.requested.cancel();
+                    let closed = bound.stop.closed.clone();
+                    retired.push(Retired { spec: bound.spec, addr: bound.addr, closed });
+                }
+            }
+        }
+        Changes { bind, retired }
+    }
+
+    /// The address of the first accepting listener in `mode`, with the port
+    /// the operating system picked when it was bound to port 0.
+    pub fn local_addr(&self, mode: ListenerMode) -> Option<SocketAddr> {
+        let bound = self.bound.lock().unwrap();
+        let mut accepting = bound.iter().filter(|bound| bound.status == ListenerStatus::Accepting);
+        accepting.find(|bound| bound.spec.mode == mode).map(|bound| bound.addr)
+    }
+
+    pub fn stats(&self) -> Vec<ListenerStats> {
+        let snapshot = self.metrics.snapshot();
+        let count = |name: &str| {
+            snapshot.iter().find(|metric| metric.name == name).map_or(0, |metric| metric.value)
+        };
+        self.bound
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|bound| ListenerStats {
+                mode: bound.spec.mode,
+                addr: bound.addr,
+                accepted: count(bound.accepted),
+                status: bound.status,
+            })
+            .collect()
+    }
+
+    /// The reply to `LISTENERS`: `LISTENER <mode> <addr> <accepted> <status>`
+    /// lines, then `END`.
+    pub fn stat_lines(&self) -> String {
+        let mut response = String::new();
+        for listener in self.stats() {
+            response.push_str(&format!(
+                "LISTENER {} {} {} {}\n",
+                listener.mode, listener.addr, listener.accepted, listener.status,
+            ));
+        }
+        response.push_str("END\n");
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::InMemorySink;
+    use std::sync::Arc;
+
+    #[test]
+    fn a_spec_is_a_mode_and_an_address() {
+        let spec: ListenerSpec = "JSON=127.0.0.1:7002".parse().unwrap();
+        assert_eq!(spec.mode, ListenerMode::Json);
+        assert_eq!(spec.addr, "127.0.0.1:7002".parse().unwrap());
+
+        assert!("127.0.0.1:7002".parse::<ListenerSpec>().is_err());
+        assert!("gopher=127.0.0.1:70".parse::<ListenerSpec>().is_err());
+        assert!("text=localhost".parse::<ListenerSpec>().is_err());
+    }
+
+    fn spec(text: &str) -> ListenerSpec {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn each_listener_counts_its_own_connections() {
+        let metrics: Metrics = Arc::new(InMemorySink::default());
+        let listeners = Listeners::new(metrics.clone());
+        let json = spec("json=127.0.0.1:7002");
+        let (accepted, _) = listeners.add(json, json.addr);
+        let socks = spec("socks=127.0.0.1:1080");
+        listeners.add(socks, socks.addr);
+        metrics.incr(accepted, 2);
+
+        assert_eq!(accepted, "listener_json_127_0_0_1_7002_connections");
+        assert_eq!(
+            listeners.stat_lines(),
+            "LISTENER json 127.0.0.1:7002 2 accepting\n\
+             LISTENER socks 127.0.0.1:1080 0 accepting\nEND\n",
+        );
+        let socks = listeners.local_addr(ListenerMode::Socks);
+        assert_eq!(socks, Some("127.0.0.1:1080".parse().unwrap()));
+        assert_eq!(listeners.local_addr(ListenerMode::Text), None);
+    }
+
+    #[test]
+    fn a_reload_binds_what_is_new_and_stops_what_is_gone() {
+        let listeners = Listeners::new(Arc::new(InMemorySink::default()));
+        // Two on port 0, bound to ports of their own
+        let any = spec("text=127.0.0.1:0");
+        listeners.add(any, "127.0.0.1:7100".parse().unwrap());
+        let (_, stop) = listeners.add(any, "127.0.0.1:7101".parse().unwrap());
+        let json = spec("json=127.0.0.1:7002");
+
+        let changes = listeners.reconcile(&[any, json]);
+        assert_eq!(changes.bind, [json]);
+        assert_eq!(changes.retired.len(), 1);
+        assert_eq!(changes.retired[0].addr, "127.0.0.1:7101".parse().unwrap());
+        assert!(stop.requested.is_cancelled());
+        let status: Vec<_> = listeners.stats().iter().map(|l| l.status).collect();
+        assert_eq!(status, [ListenerStatus::Accepting, ListenerStatus::Draining]);
+
+        // Draining listeners are not stopped twice, nor counted as bound
+        assert!(listeners.reconcile(&[any]).retired.is_empty());
+        assert_eq!(listeners.reconcile(&[any, any]).bind, [any]);
+        listeners.remove("127.0.0.1:7101".parse().unwrap());
+        assert_eq!(listeners.stats().len(), 1);
+    }
+}
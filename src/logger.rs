@@ -0,0 +1,739 @@
+//! The logging task and the messages it receives.
+//!
+//! The channel between them is an `mpsc` channel, and each end also comes
+//! wrapped in the standard async traits: the logger consumes a
+//! [`LogStream`] (`Stream<Item = LogMessage>`), so it can be written with
+//! stream combinators, and a producer may hold a [`LogSink`]
+//! (`Sink<LogMessage>`) instead of the bare `Sender`, for code written
+//! against sinks (`SinkExt::send`, `StreamExt::forward`).
+//!
+//! The channel is bounded, so a stalled logger eventually fills it. What
+//! happens to a message that finds it full is the [`LogOverflow`] policy:
+//! the producer waits for room, the message is dropped, or it is spilled
+//! somewhere unbounded (a dead-letter file, or a queue that feeds the
+//! logger later). Producers hold a [`LogTx`], which applies it.
+//!
+//! There are two lanes. Errors and admin events are [`Priority::High`]
+//! and go through a second, small channel that the logger always drains
+//! first, so a flood of client lines in the main channel cannot hold them
+//! up. If that lane is full too, they queue in the main one.
+
+use crate::config::{ConfigRx, ServerConfig};
+use crate::history::History;
+use crate::metrics::{self, Metrics};
+use crate::task;
+use bytes::Bytes;
+use futures_util::Sink;
+use std::collections::VecDeque;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::{broadcast, mpsc};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::time::{Instant, MissedTickBehavior};
+use tokio_stream::{Stream, StreamExt};
+use tokio_util::sync::PollSender;
+
+/// Message sent to the logging task.
+/// Each message represents a line received from a client
+/// or a failure reported by one of the server tasks.
+///
+/// The text is `Bytes` so a client's line can be logged without copying
+/// it: the message shares the connection's read buffer. Formatted texts
+/// (`String`) convert without a copy too.
+#[derive(Debug)]
+pub struct LogMessage {
+    pub level: LogLevel,
+    pub priority: Priority,
+    pub text: Bytes,
+    /// The client a line came from; `None` for the server's own messages.
+    pub peer: Option<SocketAddr>,
+    /// When the message was created, i.e. roughly when it entered the queue.
+    pub enqueued: Instant,
+}
+
+/// Which lane a message takes to the logger.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// Client lines and routine events.
+    Normal,
+    /// Errors and admin events, printed ahead of the normal lane.
+    High,
+}
+
+/// Ordered by severity, so a minimum level can be compared against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Info,
+    Error,
+}
+
+impl LogMessage {
+    pub fn info(text: impl Into<Bytes>) -> Self {
+        Self::new(LogLevel::Info, Priority::Normal, text.into())
+    }
+
+    pub fn error(text: impl Into<Bytes>) -> Self {
+        Self::new(LogLevel::Error, Priority::High, text.into())
+    }
+
+    /// Something an operator did or asked for: logged as info, but urgently.
+    pub fn admin(text: impl Into<Bytes>) -> Self {
+        Self::new(LogLevel::Info, Priority::High, text.into())
+    }
+
+    /// Marks the message as a line from `peer`.
+    pub fn with_peer(mut self, peer: SocketAddr) -> Self {
+        self.peer = Some(peer);
+        self
+    }
+
+    fn new(level: LogLevel, priority: Priority, text: Bytes) -> Self {
+        Self { level, priority, text, peer: None, enqueued: Instant::now() }
+    }
+}
+
+/// What a producer does with a message when the log channel is full.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogOverflow {
+    /// Wait until the logger makes room.
+    Wait,
+    /// Drop the message; counted in `log_messages_dropped`.
+    Drop,
+    /// Append the message to this file instead of printing it.
+    DeadLetter(PathBuf),
+    /// Queue the message without a limit, with a warning, and print it later.
+    Unbounded,
+}
+
+impl LogOverflow {
+    /// Parses `wait`, `drop`, `dead-letter:<path>` or `unbounded`.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        match text.split_once(':') {
+            None if text == "wait" => Ok(Self::Wait),
+            None if text == "drop" => Ok(Self::Drop),
+            None if text == "unbounded" => Ok(Self::Unbounded),
+            Some(("dead-letter", path)) if !path.is_empty() => {
+                Ok(Self::DeadLetter(PathBuf::from(path)))
+            }
+            _ => Err(format!(
+                "unknown log overflow policy {:?} \
+                 (expected wait, drop, dead-letter:<path> or unbounded)",
+                text
+            )),
+        }
+    }
+}
+
+/// Capacity of the high-priority lane; it only has to absorb short bursts.
+const URGENT_CAPACITY: usize = 16;
+
+/// Creates the log channel: the producers' handle and the logger's stream.
+///
+/// For the spilling policies this also starts the task that takes the
+/// overflow: the dead-letter writer, or the forwarder that moves queued
+/// messages into the channel as room frees up.
+pub fn channel(capacity: usize, overflow: &LogOverflow, metrics: Metrics) -> (LogTx, LogStream) {
+    let (tx, rx) = mpsc::channel(capacity);
+    let (urgent_tx, urgent_rx) = mpsc::channel(URGENT_CAPACITY);
+    let spill = match overflow {
+        LogOverflow::Wait | LogOverflow::Drop => None,
+        LogOverflow::DeadLetter(path) => {
+            let (spill_tx, spill_rx) = mpsc::unbounded_channel();
+            task::spawn("dead letters", dead_letters(path.clone(), spill_rx));
+            Some(spill_tx)
+        }
+        LogOverflow::Unbounded => {
+            let (spill_tx, spill_rx) = mpsc::unbounded_channel();
+            task::spawn("log overflow", forward(spill_rx, tx.clone()));
+            Some(spill_tx)
+        }
+    };
+    let drop = *overflow == LogOverflow::Drop;
+    let log_tx = LogTx { tx, urgent: urgent_tx, drop, spill, metrics };
+    (log_tx, LogStream { urgent: urgent_rx, rx })
+}
+
+/// The producers' end of the log channel.
+///
+/// `send` never fails: if the logger is gone the server is shutting down
+/// and the message has nowhere to go anyway.
+#[derive(Clone)]
+pub struct LogTx {
+    tx: mpsc::Sender<LogMessage>,
+    urgent: mpsc::Sender<LogMessage>,
+    // Set for `LogOverflow::Drop`
+    drop: bool,
+    // Where overflow goes for the spilling policies
+    spill: Option<mpsc::UnboundedSender<LogMessage>>,
+    metrics: Metrics,
+}
+
+impl LogTx {
+    /// Queues `msg`, applying the overflow policy if the channel is full.
+    pub async fn send(&self, msg: LogMessage) {
+        if let Some(msg) = self.try_send(msg) {
+            let _ = self.tx.send(msg).await;
+        }
+    }
+
+    /// Messages waiting in the channel for the logger task.
+    pub fn queued(&self) -> usize {
+        self.tx.max_capacity() - self.tx.capacity()
+    }
+
+    /// Whether the logger task has stopped reading, so nothing sent gets logged.
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+
+    /// Like `send`, for code running outside the runtime (`spawn_blocking`).
+    pub fn blocking_send(&self, msg: LogMessage) {
+        if let Some(msg) = self.try_send(msg) {
+            let _ = self.tx.blocking_send(msg);
+        }
+    }
+
+    /// Whether a full channel makes producers wait.
+    fn waits(&self) -> bool {
+        !self.drop && self.spill.is_none()
+    }
+
+    /// Puts a high-priority `msg` into the urgent lane if it has room.
+    /// Returns the message if it has to take the normal lane.
+    fn try_send_urgent(&self, msg: LogMessage) -> Option<LogMessage> {
+        if msg.priority == Priority::Normal {
+            return Some(msg);
+        }
+        match self.urgent.try_send(msg) {
+            Ok(()) | Err(TrySendError::Closed(_)) => None,
+            Err(TrySendError::Full(msg)) => Some(msg),
+        }
+    }
+
+    /// Queues `msg` if there is room, or hands it to the overflow policy.
+    /// Returns the message if the policy is to wait for room.
+    fn try_send(&self, msg: LogMessage) -> Option<LogMessage> {
+        let msg = self.try_send_urgent(msg)?;
+        let msg = match self.tx.try_send(msg) {
+            Ok(()) | Err(TrySendError::Closed(_)) => return None,
+            Err(TrySendError::Full(msg)) => msg,
+        };
+        if let Some(spill) = &self.spill {
+            self.metrics.incr(metrics::LOG_MESSAGES_SPILLED, 1);
+            let _ = spill.send(msg);
+        } else if self.drop {
+            self.metrics.incr(metrics::LOG_MESSAGES_DROPPED, 1);
+        } else {
+            return Some(msg);
+        }
+        None
+    }
+}
+
+/// Appends overflowed messages to the dead-letter file, one line each.
+async fn dead_letters(path: PathBuf, mut rx: mpsc::UnboundedReceiver<LogMessage>) {
+    let file = tokio::fs::OpenOptions::new().create(true).append(true).open(&path).await;
+    let mut file = match file {
+        Ok(file) => BufWriter::new(file),
+        Err(e) => {
+            eprintln!("[LOG] cannot open dead-letter file {}: {}", path.display(), e);
+            // Keep receiving, so producers never notice
+            while rx.recv().await.is_some() {}
+            return;
+        }
+    };
+    while let Some(msg) = rx.recv().await {
+        let mut written = file.write_all(line(&msg).as_bytes()).await;
+        // Flush once the burst is over, not after every line
+        if written.is_ok() && rx.is_empty() {
+            written = file.flush().await;
+        }
+        if let Err(e) = written {
+            eprintln!("[LOG] cannot write dead-letter file {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Moves overflowed messages into the log channel as room frees up.
+async fn forward(mut rx: mpsc::UnboundedReceiver<LogMessage>, tx: mpsc::Sender<LogMessage>) {
+    while let Some(first) = rx.recv().await {
+        // The queue was empty, so this is a new burst of overflow
+        eprintln!("[LOG] log channel full, queueing messages without a limit");
+        let mut next = Some(first);
+        while let Some(msg) = next {
+            if tx.send(msg).await.is_err() {
+                return;
+            }
+            next = rx.try_recv().ok();
+        }
+    }
+}
+
+/// The receiving end of the log channel, as a `Stream`.
+///
+/// Both lanes are merged with a bias: the urgent one is polled first, so
+/// a message in it overtakes everything waiting in the normal one.
+pub struct LogStream {
+    urgent: mpsc::Receiver<LogMessage>,
+    rx: mpsc::Receiver<LogMessage>,
+}
+
+impl Stream for LogStream {
+    type Item = LogMessage;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<LogMessage>> {
+        let urgent = self.urgent.poll_recv(cx);
+        if let Poll::Ready(Some(msg)) = urgent {
+            return Poll::Ready(Some(msg));
+        }
+        match (urgent, self.rx.poll_recv(cx)) {
+            (_, Poll::Ready(Some(msg))) => Poll::Ready(Some(msg)),
+            // Ends once every sender is gone and both lanes are empty
+            (Poll::Ready(None), Poll::Ready(None)) => Poll::Ready(None),
+            // A lane that is still open has registered the waker
+            _ => Poll::Pending,
+        }
+    }
+}
+
+/// A sending end of the log channel, as a `Sink`.
+///
+/// With the `wait` policy `poll_ready` reserves a slot in the channel, so
+/// a full channel pushes back there, just like `LogTx::send` does. With
+/// the others a sink is always ready and `start_send` applies the policy.
+pub struct LogSink {
+    log: LogTx,
+    tx: PollSender<LogMessage>,
+}
+
+/// The logger task is gone, so nothing can be logged any more.
+#[derive(Debug, thiserror::Error)]
+#[error("the logger has stopped")]
+pub struct LoggerGone;
+
+impl LogSink {
+    pub fn new(log: LogTx) -> Self {
+        let tx = PollSender::new(log.tx.clone());
+        Self { log, tx }
+    }
+}
+
+impl Sink<LogMessage> for LogSink {
+    type Error = LoggerGone;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), LoggerGone>> {
+        if self.tx.is_closed() {
+            return Poll::Ready(Err(LoggerGone));
+        }
+        if !self.log.waits() {
+            return Poll::Ready(Ok(()));
+        }
+        self.tx.poll_reserve(cx).map_err(|_| LoggerGone)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, msg: LogMessage) -> Result<(), LoggerGone> {
+        let Some(msg) = self.log.try_send_urgent(msg) else {
+            // Taken by the urgent lane: the slot reserved in the normal one is not needed
+            self.tx.abort_send();
+            return Ok(());
+        };
+        if !self.log.waits() {
+            // Never gives the message back for these policies
+            self.log.try_send(msg);
+            return Ok(());
+        }
+        self.tx.send_item(msg).map_err(|_| LoggerGone)
+    }
+
+    // A sent message is already in the channel: there is nothing to flush
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), LoggerGone>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), LoggerGone>> {
+        self.tx.close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// What the logger printed, for whoever wants to watch besides stdout.
+///
+/// The log channel has a single consumer, the logger task; a handle lets
+/// any number of others see its output without touching that channel:
+/// `last` returns the newest lines (the admin `RECENT` command), and
+/// `subscribe` follows every line from now on (`FOLLOW`, tests).
+///
+/// Only the newest `capacity` lines are kept; each new one pushes the
+/// oldest out. A subscriber first gets those, so it starts with some
+/// context rather than an empty screen. Lines of one batch keep their
+/// order within stdout and within stderr, but the stderr lines come after
+/// the stdout ones.
+#[derive(Clone)]
+pub struct LoggerHandle {
+    inner: Arc<Printed>,
+}
+
+struct Printed {
+    lines: Mutex<VecDeque<LogEvent>>,
+    capacity: usize,
+    // Sent to under the lock, so a new subscriber misses nothing between
+    // the lines it copies and the ones it receives
+    events: broadcast::Sender<LogEvent>,
+}
+
+/// A line the logger printed, without its newline.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogEvent {
+    /// `Info` for stdout, `Error` for stderr.
+    pub level: LogLevel,
+    pub line: String,
+}
+
+/// Lines a subscriber may fall behind by before it misses some.
+const SUBSCRIBER_CAPACITY: usize = 256;
+
+impl LoggerHandle {
+    pub fn new(capacity: usize) -> Self {
+        let printed = Printed {
+            lines: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            events: broadcast::Sender::new(SUBSCRIBER_CAPACITY),
+        };
+        Self { inner: Arc::new(printed) }
+    }
+
+    fn extend<'a>(&self, level: LogLevel, printed: impl Iterator<Item = &'a str>) {
+        let mut lines = self.inner.lines.lock().unwrap();
+        for line in printed {
+            if lines.len() == self.inner.capacity {
+                lines.pop_front();
+            }
+            let event = LogEvent { level, line: line.to_string() };
+            // Nobody may be subscribed, which is fine
+            let _ = self.inner.events.send(event.clone());
+            lines.push_back(event);
+        }
+    }
+
+    /// Up to `n` of the newest lines, oldest first.
+    pub fn last(&self, n: usize) -> Vec<String> {
+        let lines = self.inner.lines.lock().unwrap();
+        let skip = lines.len().saturating_sub(n);
+        lines.iter().skip(skip).map(|event| event.line.clone()).collect()
+    }
+
+    /// Every line printed from now on, after the newest ones kept so far.
+    pub fn subscribe(&self) -> LogSubscription {
+        let lines = self.inner.lines.lock().unwrap();
+        let backlog = lines.clone();
+        LogSubscription { backlog, rx: self.inner.events.subscribe() }
+    }
+}
+
+/// The lines a subscriber has yet to see.
+pub struct LogSubscription {
+    backlog: VecDeque<LogEvent>,
+    rx: broadcast::Receiver<LogEvent>,
+}
+
+impl LogSubscription {
+    /// The next line, or `None` once the logger has stopped.
+    ///
+    /// A subscriber that falls more than a few hundred lines behind loses
+    /// the oldest ones, and gets an error line saying how many instead.
+    pub async fn recv(&mut self) -> Option<LogEvent> {
+        if let Some(event) = self.backlog.pop_front() {
+            return Some(event);
+        }
+        match self.rx.recv().await {
+            Ok(event) => Some(event),
+            Err(broadcast::error::RecvError::Lagged(n)) => Some(LogEvent {
+                level: LogLevel::Error,
+                line: format!("[LOG] fell behind, missed {} lines", n),
+            }),
+            Err(broadcast::error::RecvError::Closed) => None,
+        }
+    }
+}
+
+/// Dedicated task that owns the logging logic.
+/// This task is the ONLY place where logging happens.
+///
+/// The minimum level and the TTL are read from the latest configuration
+/// for every batch, so a reload changes them immediately.
+///
+/// After a stall (e.g. a blocked stdout) the channel holds a backlog.
+/// Messages that waited longer than the TTL are dropped instead of
+/// printed, so the logger catches up with fresh messages quickly; the
+/// number of dropped messages is reported once it has caught up.
+///
+/// Messages are printed in batches: they are collected until either
+/// `log_batch` of them are waiting or the `log_flush` timer ticks, and
+/// each batch is formatted into one block that is written with a single
+/// `write_all` per output, so lines from a burst are not interleaved
+/// with anything else. Both thresholds are read from the configuration
+/// on every round, so a reload applies to the next batch. A high-priority
+/// message does not wait for either: it flushes the batch right away.
+///
+/// Every printed line also goes to `printed`, and every printed message
+/// to `history`, if there is one.
+pub async fn run(
+    messages: &mut LogStream,
+    config: ConfigRx,
+    metrics: Metrics,
+    printed: LoggerHandle,
+    history: Option<History>,
+) {
+    let history = history.as_ref();
+    let mut expired = 0u64;
+    let mut batch = Vec::new();
+    let mut flush = config.borrow().log_flush;
+    let mut timer = tokio::time::interval(flush);
+    // After a slow write, tick once and carry on instead of catching up
+    timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        let config = config.borrow().clone();
+        if config.log_flush != flush {
+            flush = config.log_flush;
+            timer = tokio::time::interval(flush);
+            timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        }
+
+        tokio::select! {
+            msg = messages.next() => match msg {
+                Some(msg) => {
+                    let urgent = msg.priority == Priority::High;
+                    batch.push(msg);
+                    if batch.len() < config.log_batch && !urgent {
+                        continue;
+                    }
+                }
+                // Every sender is gone: print what is left and stop
+                None => {
+                    write_batch(&mut batch, &mut expired, &config, &metrics, &printed, history);
+                    break;
+                }
+            },
+            _ = timer.tick() => {}
+        }
+        write_batch(&mut batch, &mut expired, &config, &metrics, &printed, history);
+    }
+}
+
+/// Formats and prints the messages in `batch`, leaving it empty.
+fn write_batch(
+    batch: &mut Vec<LogMessage>,
+    expired: &mut u64,
+    config: &ServerConfig,
+    metrics: &Metrics,
+    printed: &LoggerHandle,
+    history: Option<&History>,
+) {
+    if batch.is_empty() {
+        return;
+    }
+    let (out, err) = format_batch(batch.drain(..), expired, config, metrics, history);
+    printed.extend(LogLevel::Info, out.lines());
+    printed.extend(LogLevel::Error, err.lines());
+    if !out.is_empty() {
+        let _ = std::io::stdout().lock().write_all(out.as_bytes());
+    }
+    if !err.is_empty() {
+        let _ = std::io::stderr().lock().write_all(err.as_bytes());
+    }
+}
+
+/// The stdout and stderr blocks for a batch of messages.
+fn format_batch(
+    batch: impl Iterator<Item = LogMessage>,
+    expired: &mut u64,
+    config: &ServerConfig,
+    metrics: &Metrics,
+    history: Option<&History>,
+) -> (String, String) {
+    let (mut out, mut err) = (String::new(), String::new());
+    for msg in batch {
+        if config.log_ttl.is_some_and(|ttl| msg.enqueued.elapsed() > ttl) {
+            *expired += 1;
+            metrics.incr(metrics::LOG_MESSAGES_EXPIRED, 1);
+            continue;
+        }
+        if *expired > 0 {
+            let ttl = config.log_ttl.unwrap_or_default();
+            err.push_str(&format!("[LOG] dropped {} messages older than {:?}\n", expired, ttl));
+            *expired = 0;
+        }
+
+        if msg.level < config.log_level {
+            continue;
+        }
+        if let Some(history) = history {
+            history.record(&msg);
+        }
+        match msg.level {
+            LogLevel::Info => out.push_str(&line(&msg)),
+            LogLevel::Error => err.push_str(&line(&msg)),
+        }
+    }
+    (out, err)
+}
+
+/// A message as a printed line, newline included.
+fn line(msg: &LogMessage) -> String {
+    // Client lines are not necessarily valid UTF-8
+    let text = String::from_utf8_lossy(&msg.text);
+    match msg.level {
+        LogLevel::Info => format!("[LOG] {}\n", text),
+        LogLevel::Error => format!("[ERROR] {}\n", text),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::SinkExt;
+
+    #[tokio::test]
+    async fn messages_go_from_the_sink_to_the_stream() {
+        let (log_tx, mut stream) = channel(1, &LogOverflow::Wait, test_metrics());
+        let mut sink = LogSink::new(log_tx);
+
+        sink.send(LogMessage::info("hello")).await.unwrap();
+        assert_eq!(stream.next().await.unwrap().text, "hello");
+
+        // Dropping the only sender ends the stream
+        drop(sink);
+        assert!(stream.next().await.is_none());
+
+        let (log_tx, stream) = channel(1, &LogOverflow::Wait, test_metrics());
+        drop(stream);
+        assert!(LogSink::new(log_tx).send(LogMessage::info("lost")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn a_full_channel_drops_or_spills_instead_of_waiting() {
+        let metrics = test_metrics();
+        let (log_tx, mut stream) = channel(1, &LogOverflow::Drop, metrics.clone());
+        log_tx.send(LogMessage::info("kept")).await;
+        log_tx.send(LogMessage::info("dropped")).await;
+        assert_eq!(stream.next().await.unwrap().text, "kept");
+        assert_eq!(metrics::stat_summary(&metrics), "log_messages_dropped=1");
+
+        let path = std::env::temp_dir().join(format!("dead-letters-{}", std::process::id()));
+        let overflow = LogOverflow::DeadLetter(path.clone());
+        let (log_tx, _stream) = channel(1, &overflow, metrics.clone());
+        log_tx.send(LogMessage::info("kept")).await;
+        log_tx.send(LogMessage::info("spilled")).await;
+        // The writer task flushes once it has nothing left to write
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert_eq!(tokio::fs::read(&path).await.unwrap(), b"[LOG] spilled\n");
+        tokio::fs::remove_file(&path).await.unwrap();
+
+        let (log_tx, mut stream) = channel(1, &LogOverflow::Unbounded, metrics);
+        for text in ["one", "two", "three"] {
+            log_tx.send(LogMessage::info(text)).await;
+        }
+        for text in ["one", "two", "three"] {
+            assert_eq!(stream.next().await.unwrap().text, text);
+        }
+    }
+
+    #[tokio::test]
+    async fn errors_overtake_a_flood_of_client_lines() {
+        let metrics = test_metrics();
+        let (log_tx, mut stream) = channel(4, &LogOverflow::Drop, metrics.clone());
+        for _ in 0..10 {
+            log_tx.send(LogMessage::info("chatter")).await;
+        }
+        log_tx.send(LogMessage::error("disk full")).await;
+        log_tx.send(LogMessage::admin("kicked #3")).await;
+
+        // The flood filled the normal lane, but nothing urgent was dropped
+        assert_eq!(metrics::stat_summary(&metrics), "log_messages_dropped=6");
+        assert_eq!(stream.next().await.unwrap().text, "disk full");
+        assert_eq!(stream.next().await.unwrap().text, "kicked #3");
+        assert_eq!(stream.next().await.unwrap().text, "chatter");
+    }
+
+    #[test]
+    fn overflow_policies_parse() {
+        assert_eq!(LogOverflow::parse("drop"), Ok(LogOverflow::Drop));
+        assert_eq!(
+            LogOverflow::parse("dead-letter:lost.log"),
+            Ok(LogOverflow::DeadLetter(PathBuf::from("lost.log")))
+        );
+        assert!(LogOverflow::parse("dead-letter:").is_err());
+        assert!(LogOverflow::parse("block").is_err());
+    }
+
+    fn test_metrics() -> Metrics {
+        std::sync::Arc::new(metrics::InMemorySink::default())
+    }
+
+    #[test]
+    fn a_batch_is_formatted_as_one_block_per_output() {
+        let config = ServerConfig { log_level: LogLevel::Info, ..ServerConfig::default() };
+        let metrics = test_metrics();
+        let batch = vec![
+            LogMessage::info("one"),
+            LogMessage::error("two"),
+            LogMessage::info(Bytes::from_static(b"three \xff")),
+        ];
+
+        let (out, err) = format_batch(batch.into_iter(), &mut 0, &config, &metrics, None);
+        assert_eq!(out, "[LOG] one\n[LOG] three \u{fffd}\n");
+        assert_eq!(err, "[ERROR] two\n");
+
+        // Below the level, nothing is printed
+        let config = ServerConfig { log_level: LogLevel::Error, ..config };
+        let batch = [LogMessage::info("one")].into_iter();
+        let (out, _) = format_batch(batch, &mut 0, &config, &metrics, None);
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn only_the_newest_lines_are_kept() {
+        let printed = LoggerHandle::new(3);
+        printed.extend(LogLevel::Info, "[LOG] a\n[LOG] b\n".lines());
+        assert_eq!(printed.last(10), ["[LOG] a", "[LOG] b"]);
+
+        printed.extend(LogLevel::Info, ["[LOG] c"].into_iter());
+        printed.extend(LogLevel::Error, ["[ERROR] d"].into_iter());
+        assert_eq!(printed.last(10), ["[LOG] b", "[LOG] c", "[ERROR] d"]);
+        assert_eq!(printed.last(1), ["[ERROR] d"]);
+    }
+
+    #[tokio::test]
+    async fn a_late_subscriber_gets_the_kept_lines_first() {
+        let printed = LoggerHandle::new(2);
+        printed.extend(LogLevel::Info, ["[LOG] a", "[LOG] b", "[LOG] c"].into_iter());
+        let mut subscription = printed.subscribe();
+        printed.extend(LogLevel::Error, ["[ERROR] d"].into_iter());
+
+        let mut seen = Vec::new();
+        for _ in 0..3 {
+            seen.push(subscription.recv().await.unwrap().line);
+        }
+        assert_eq!(seen, ["[LOG] b", "[LOG] c", "[ERROR] d"]);
+
+        // One that falls behind is told how much it missed
+        let lines: Vec<_> = (0..SUBSCRIBER_CAPACITY + 1).map(|i| format!("[LOG] {}", i)).collect();
+        printed.extend(LogLevel::Info, lines.iter().map(String::as_str));
+        let missed = subscription.recv().await.unwrap();
+        assert_eq!(missed.level, LogLevel::Error);
+        assert_eq!(missed.line, "[LOG] fell behind, missed 1 lines");
+        assert_eq!(subscription.recv().await.unwrap().line, "[LOG] 1");
+
+        drop(printed);
+        for _ in 1..SUBSCRIBER_CAPACITY {
+            assert!(subscription.recv().await.is_some());
+        }
+        assert_eq!(subscription.recv().await, None);
+    }
+}
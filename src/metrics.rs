@@ -0,0 +1,398 @@
+//! Instrumentation layer shared by the STATS command and the exporters.
+//!
+//! Code that wants to count something only talks to [`MetricsSink`]; which
+//! backend receives the numbers is decided once at startup from the config.
+//! Every backend keeps an in-memory copy of the values, so STATS works
+//! regardless of where the metrics are exported to.
+
+use crate::restart::Handoff;
+use crate::{http, task};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::net::{TcpListener, UdpSocket};
+
+pub const CONNECTIONS_ACCEPTED: &str = "connections_accepted";
+pub const CONNECTIONS_ACTIVE: &str = "connections_active";
+pub const CONNECTIONS_DENIED: &str = "connections_denied";
+pub const CONNECTIONS_NOT_READY: &str = "connections_not_ready";
+pub const CONNECTIONS_PAUSED: &str = "connections_paused";
+pub const CONNECTIONS_SHED: &str = "connections_shed";
+pub const ACCEPTS_DELAYED: &str = "accepts_delayed";
+// Why connections were shed or delayed; see `shedding::Overload`
+pub const SHED_CONNECTIONS: &str = "shed_over_connections";
+pub const SHED_LOG_QUEUE: &str = "shed_over_log_queue";
+pub const SHED_RUNTIME_QUEUE: &str = "shed_over_runtime_queue";
+pub const PEERS_TRACKED: &str = "peers_tracked";
+pub const PEERS_EVICTED: &str = "peers_evicted";
+pub const SESSIONS_SAVED: &str = "sessions_saved";
+pub const SESSIONS_EXPIRED: &str = "sessions_expired";
+pub const KV_KEYS_EXPIRED_ON_ACCESS: &str = "kv_keys_expired_on_access";
+pub const KV_KEYS_EXPIRED_BY_SWEEPER: &str = "kv_keys_expired_by_sweeper";
+pub const WAL_RECORDS: &str = "wal_records";
+pub const WAL_FSYNCS: &str = "wal_fsyncs";
+pub const WAL_COMPACTIONS: &str = "wal_compactions";
+pub const REPLICAS_CONNECTED: &str = "replicas_connected";
+pub const REPLICATION_CHANGES_MISSED: &str = "replication_changes_missed";
+pub const REPLICA_SYNCS: &str = "replica_syncs";
+pub const VHOST_ROUTED: &str = "tls_vhost_routed";
+pub const VHOST_UNMATCHED: &str = "tls_vhost_unmatched";
+pub const SESSIONS_RESUMED: &str = "sessions_resumed";
+pub const CONNECTION_TASKS: &str = "connection_tasks";
+pub const CONNECTION_TASKS_PANICKED: &str = "connection_tasks_panicked";
+pub const TASKS_PANICKED: &str = "background_tasks_panicked";
+pub const TASKS_RESTARTED: &str = "background_tasks_restarted";
+pub const MESSAGES_RECEIVED: &str = "messages_received";
+pub const REPLIES_SLOWED: &str = "replies_slowed";
+pub const MESSAGES_RATE_LIMITED: &str = "messages_rate_limited";
+pub const QUOTA_ASKS: &str = "quota_asks";
+pub const QUOTA_EXCEEDED: &str = "quota_exceeded";
+pub const MESSAGES_TOO_LONG: &str = "messages_too_long";
+pub const MESSAGES_MALFORMED: &str = "messages_malformed";
+pub const MESSAGES_INVALID_UTF8: &str = "messages_invalid_utf8";
+pub const AUTH_FAILURES: &str = "auth_failures";
+pub const AUTH_THROTTLED: &str = "auth_throttled";
+pub const REQUESTS_IN_FLIGHT: &str = "requests_in_flight";
+pub const REQUESTS_CANCELLED: &str = "requests_cancelled";
+pub const BYTES_READ: &str = "bytes_read";
+pub const BYTES_WRITTEN: &str = "bytes_written";
+pub const CHAOS_DELAYS: &str = "chaos_delays";
+pub const CHAOS_SHORT_READS: &str = "chaos_short_reads";
+pub const CHAOS_RESETS: &str = "chaos_resets";
+pub const DIALS: &str = "dials";
+pub const DIAL_ATTEMPTS: &str = "dial_attempts";
+pub const DIAL_FAILURES: &str = "dial_failures";
+pub const DIAL_TIMEOUTS: &str = "dial_timeouts";
+pub const PROXY_BYTES_UP: &str = "proxy_bytes_up";
+pub const PROXY_BYTES_DOWN: &str = "proxy_bytes_down";
+pub const CONNECT_TUNNELS: &str = "connect_tunnels";
+pub const CONNECT_DENIED: &str = "connect_denied";
+pub const UPLOAD_BYTES: &str = "upload_bytes";
+pub const DOWNLOAD_BYTES: &str = "download_bytes";
+pub const TAIL_SUBSCRIBERS: &str = "tail_subscribers";
+pub const TAIL_LINES_MISSED: &str = "tail_lines_missed";
+pub const WATCH_SUBSCRIBERS: &str = "watch_subscribers";
+pub const WATCH_LINES_MISSED: &str = "watch_lines_missed";
+pub const PUBSUB_SUBSCRIBERS: &str = "pubsub_subscribers";
+pub const PUBSUB_LINES_MISSED: &str = "pubsub_lines_missed";
+pub const PUBSUB_MESSAGES_PUBLISHED: &str = "pubsub_messages_published";
+pub const PUBSUB_MESSAGES_DELAYED: &str = "pubsub_messages_delayed";
+pub const PUBSUB_MESSAGES_HELD: &str = "pubsub_messages_held";
+pub const PUBSUB_MESSAGES_EXPIRED: &str = "pubsub_messages_expired";
+pub const QUEUE_MESSAGES_PENDING: &str = "queue_messages_pending";
+pub const QUEUE_MESSAGES_REDELIVERED: &str = "queue_messages_redelivered";
+pub const QUEUE_CONSUMERS: &str = "queue_consumers";
+pub const FS_EVENTS: &str = "fs_events";
+pub const INGEST_LINES: &str = "ingest_lines";
+pub const INGEST_LINES_CLEANED: &str = "ingest_lines_cleaned";
+pub const INGEST_LINES_REDACTED: &str = "ingest_lines_redacted";
+pub const SCRIPT_CALLS: &str = "script_calls";
+pub const SCRIPT_ERRORS: &str = "script_errors";
+pub const SCRIPT_OVER_BUDGET: &str = "script_over_budget";
+pub const WRITE_BATCHES: &str = "write_batches";
+pub const CLIENTS_SLOW: &str = "clients_slow";
+pub const BACKPRESSURE_PAUSED: &str = "backpressure_paused";
+pub const BACKPRESSURE_REPLIES_DROPPED: &str = "backpressure_replies_dropped";
+pub const BUFFERS_ALLOCATED: &str = "buffers_allocated";
+pub const BUFFERS_REUSED: &str = "buffers_reused";
+pub const LOG_MESSAGES_EXPIRED: &str = "log_messages_expired";
+pub const LOG_MESSAGES_DROPPED: &str = "log_messages_dropped";
+pub const LOG_MESSAGES_SPILLED: &str = "log_messages_spilled";
+pub const HISTORY_ROWS: &str = "history_rows";
+pub const HISTORY_ROWS_DROPPED: &str = "history_rows_dropped";
+pub const JOBS_PENDING: &str = "jobs_pending";
+pub const JOBS_RUN: &str = "jobs_run";
+pub const WORKER_QUEUE_DEPTH: &str = "worker_queue_depth";
+pub const WORKER_JOBS_DONE: &str = "worker_jobs_done";
+pub const WORKER_JOBS_REJECTED: &str = "worker_jobs_rejected";
+pub const COMPUTE_REQUESTS: &str = "compute_requests";
+pub const HEALTH_PROBES: &str = "health_probes";
+pub const HEALTH_PROBES_FAILED: &str = "health_probes_failed";
+pub const HEALTH_TARGETS_DOWN: &str = "health_targets_down";
+// Sampled from the Tokio runtime by `introspect::run`
+pub const RUNTIME_WORKERS: &str = "runtime_workers";
+pub const RUNTIME_ALIVE_TASKS: &str = "runtime_alive_tasks";
+pub const RUNTIME_GLOBAL_QUEUE_DEPTH: &str = "runtime_global_queue_depth";
+pub const RUNTIME_PARKS: &str = "runtime_parks";
+pub const RUNTIME_BUSY_MS: &str = "runtime_busy_ms";
+#[cfg(tokio_unstable)]
+pub const RUNTIME_LOCAL_QUEUE_DEPTH: &str = "runtime_local_queue_depth";
+#[cfg(tokio_unstable)]
+pub const RUNTIME_BLOCKING_THREADS: &str = "runtime_blocking_threads";
+#[cfg(tokio_unstable)]
+pub const RUNTIME_SPAWNED_TASKS: &str = "runtime_spawned_tasks";
+// One counter per `CloseReason`
+pub const CLOSED_CLIENT_EOF: &str = "connections_closed_client_eof";
+pub const CLOSED_IDLE_TIMEOUT: &str = "connections_closed_idle_timeout";
+pub const CLOSED_HEARTBEAT_TIMEOUT: &str = "connections_closed_heartbeat_timeout";
+pub const CLOSED_RATE_LIMITED: &str = "connections_closed_rate_limited";
+pub const CLOSED_ADMIN_KILL: &str = "connections_closed_admin_kill";
+pub const CLOSED_SERVER_SHUTDOWN: &str = "connections_closed_server_shutdown";
+pub const CLOSED_PROTOCOL_ERROR: &str = "connections_closed_protocol_error";
+pub const CLOSED_MESSAGE_TOO_LONG: &str = "connections_closed_message_too_long";
+pub const CLOSED_SLOW_CLIENT: &str = "connections_closed_slow_client";
+pub const CLOSED_IO_ERROR: &str = "connections_closed_io_error";
+// One counter per `Drain`, for the connections the server closed
+pub const DRAIN_CLIENT_CLOSED: &str = "drain_client_closed";
+pub const DRAIN_CLIENT_GONE: &str = "drain_client_gone";
+pub const DRAIN_FORCED: &str = "drain_forced";
+
+/// Name of the counter of connections accepted by one `SO_REUSEPORT`
+/// acceptor, e.g. `acceptor_2_connections`.
+///
+/// Metric names are `&'static str`; these are only known at startup, so
+/// the string is leaked. That happens once per acceptor, not per connection.
+pub fn acceptor_connections(index: usize) -> &'static str {
+    Box::leak(format!("acceptor_{}_connections", index).into_boxed_str())
+}
+
+/// Name of the gauge with the circuit breaker state of a proxy upstream,
+/// e.g. `upstream_127_0_0_1_8000_circuit`; leaked once per upstream, like
+/// `acceptor_connections`. Characters not allowed in a metric name become `_`.
+pub fn upstream_circuit(addr: &str) -> &'static str {
+    Box::leak(format!("upstream_{}_circuit", sanitize(addr)).into_boxed_str())
+}
+
+/// Name of the counter of connections accepted by one listener, e.g.
+/// `listener_json_127_0_0_1_7002_connections`; leaked once per listener.
+pub fn listener_connections(mode: &str, addr: &str) -> &'static str {
+    Box::leak(format!("listener_{}_{}_connections", mode, sanitize(addr)).into_boxed_str())
+}
+
+fn sanitize(text: &str) -> String {
+    text.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect()
+}
+
+/// Prefix added to metric names by the exporters.
+const PREFIX: &str = "tokio_examples_";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricKind {
+    /// Only goes up.
+    Counter,
+    /// Goes up and down (e.g. currently open connections).
+    Gauge,
+}
+
+#[derive(Debug, Clone)]
+pub struct Metric {
+    pub name: &'static str,
+    pub kind: MetricKind,
+    pub value: i64,
+}
+
+/// A destination for application metrics.
+///
+/// Methods are synchronous and cheap, so they can be called from any task
+/// without awaiting.
+pub trait MetricsSink: Send + Sync {
+    /// Adds `by` to a counter.
+    fn incr(&self, name: &'static str, by: u64);
+    /// Moves a gauge up or down by `delta`.
+    fn adjust(&self, name: &'static str, delta: i64);
+    /// Current values of all metrics, sorted by name.
+    fn snapshot(&self) -> Vec<Metric>;
+}
+
+/// Handle passed around the server.
+pub type Metrics = Arc<dyn MetricsSink>;
+
+/// One `STAT <name> <value>` line per metric, for the reply to `STATS`.
+pub fn stat_lines(metrics: &Metrics) -> String {
+    let mut response = String::new();
+    for metric in metrics.snapshot() {
+        response.push_str(&format!("STAT {} {}\n", metric.name, metric.value));
+    }
+    response
+}
+
+/// All metrics on one line, `name=value` separated by spaces, for the log.
+pub fn stat_summary(metrics: &Metrics) -> String {
+    let stats: Vec<String> = metrics
+        .snapshot()
+        .iter()
+        .map(|metric| format!("{}={}", metric.name, metric.value))
+        .collect();
+    stats.join(" ")
+}
+
+/// Which backend receives the metrics.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetricsBackend {
+    Memory,
+    /// Serve the Prometheus text format over HTTP on this address.
+    Prometheus(SocketAddr),
+    /// Push every update to a statsd daemon over UDP.
+    Statsd(SocketAddr),
+}
+
+impl MetricsBackend {
+    /// Parses `memory`, `prometheus:<addr>` or `statsd:<addr>`.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let addr = |a: &str| {
+            a.parse::<SocketAddr>()
+                .map_err(|_| format!("invalid metrics address: {}", a))
+        };
+        match text.split_once(':') {
+            None if text == "memory" => Ok(Self::Memory),
+            Some(("prometheus", a)) => Ok(Self::Prometheus(addr(a)?)),
+            Some(("statsd", a)) => Ok(Self::Statsd(addr(a)?)),
+            _ => Err(format!(
+                "unknown metrics backend {:?} (expected memory, prometheus:<addr> or statsd:<addr>)",
+                text
+            )),
+        }
+    }
+}
+
+/// Creates the configured sink and starts its exporter task, if any.
+pub async fn start(backend: &MetricsBackend, handoff: &Handoff) -> std::io::Result<Metrics> {
+    Ok(match backend {
+        MetricsBackend::Memory => Arc::new(InMemorySink::default()),
+        MetricsBackend::Prometheus(addr) => {
+            let sink = Arc::new(PrometheusSink::default());
+            let listener = handoff.bind(*addr).await?;
+            println!("Prometheus metrics on http://{}/metrics", addr);
+            task::spawn("prometheus exporter", serve_prometheus(listener, sink.clone()));
+            sink
+        }
+        MetricsBackend::Statsd(addr) => {
+            let socket = UdpSocket::bind("0.0.0.0:0").await?;
+            socket.connect(addr).await?;
+            println!("Sending metrics to statsd at {}", addr);
+            Arc::new(StatsdSink {
+                values: InMemorySink::default(),
+                socket,
+            })
+        }
+    })
+}
+
+/// Keeps the values in a map behind a synchronous mutex.
+#[derive(Default)]
+pub struct InMemorySink {
+    values: Mutex<BTreeMap<&'static str, Metric>>,
+}
+
+impl InMemorySink {
+    fn update(&self, name: &'static str, kind: MetricKind, delta: i64) {
+        let mut values = self.values.lock().unwrap();
+        values
+            .entry(name)
+            .or_insert(Metric {
+                name,
+                kind,
+                value: 0,
+            })
+            .value += delta;
+    }
+}
+
+impl MetricsSink for InMemorySink {
+    fn incr(&self, name: &'static str, by: u64) {
+        self.update(name, MetricKind::Counter, by as i64);
+    }
+
+    fn adjust(&self, name: &'static str, delta: i64) {
+        self.update(name, MetricKind::Gauge, delta);
+    }
+
+    fn snapshot(&self) -> Vec<Metric> {
+        self.values.lock().unwrap().values().cloned().collect()
+    }
+}
+
+/// In-memory values that a scrape endpoint renders on request.
+#[derive(Default)]
+pub struct PrometheusSink {
+    values: InMemorySink,
+}
+
+impl PrometheusSink {
+    /// Renders all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for metric in self.values.snapshot() {
+            let kind = match metric.kind {
+                MetricKind::Counter => "counter",
+                MetricKind::Gauge => "gauge",
+            };
+            out.push_str(&format!(
+                "# TYPE {p}{n} {k}\n{p}{n} {v}\n",
+                p = PREFIX,
+                n = metric.name,
+                k = kind,
+                v = metric.value,
+            ));
+        }
+        out
+    }
+}
+
+impl MetricsSink for PrometheusSink {
+    fn incr(&self, name: &'static str, by: u64) {
+        self.values.incr(name, by);
+    }
+
+    fn adjust(&self, name: &'static str, delta: i64) {
+        self.values.adjust(name, delta);
+    }
+
+    fn snapshot(&self) -> Vec<Metric> {
+        self.values.snapshot()
+    }
+}
+
+/// A minimal HTTP endpoint: every request gets the current metrics.
+async fn serve_prometheus(listener: TcpListener, sink: Arc<PrometheusSink>) {
+    loop {
+        let mut socket = match listener.accept().await {
+            Ok((socket, _)) => socket,
+            Err(e) => {
+                // Usually transient, like running out of file descriptors;
+                // the pause keeps the loop from spinning until it passes
+                eprintln!("[METRICS] accept failed: {}", e);
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let sink = sink.clone();
+        task::spawn("prometheus scrape", async move {
+            // Any path is answered; the request is read so the client
+            // is not reset while still sending headers.
+            let _ = http::read_request_path(&mut socket).await;
+            let body = sink.render();
+            http::respond(&mut socket, "200 OK", "text/plain; version=0.0.4", &body).await;
+        });
+    }
+}
+
+/// Pushes every update as a statsd datagram.
+pub struct StatsdSink {
+    values: InMemorySink,
+    socket: UdpSocket,
+}
+
+impl StatsdSink {
+    fn send(&self, line: String) {
+        // `try_send` never waits: if the socket buffer is full the sample
+        // is dropped, which is the usual statsd trade-off.
+        let _ = self.socket.try_send(line.as_bytes());
+    }
+}
+
+impl MetricsSink for StatsdSink {
+    fn incr(&self, name: &'static str, by: u64) {
+        self.values.incr(name, by);
+        self.send(format!("{}{}:{}|c", PREFIX, name, by));
+    }
+
+    fn adjust(&self, name: &'static str, delta: i64) {
+        self.values.adjust(name, delta);
+        self.send(format!("{}{}:{:+}|g", PREFIX, name, delta));
+    }
+
+    fn snapshot(&self) -> Vec<Metric> {
+        self.values.snapshot()
+    }
+}
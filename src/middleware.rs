@@ -0,0 +1,412 @@
+//! The interceptors every request passes through before it is served.
+//!
+//! Counting, authentication, rate limiting and logging apply to every
+//! command alike, so they are not part of any command's code: the handler
+//! hands each request to a [`Chain`], and serves it only if every layer
+//! lets it through. A layer can also answer the request itself, or close
+//! the connection.
+//!
+//! The layers run in the order they are configured (`--middleware`), and
+//! a layer left out of the list does not run at all, except `auth` when
+//! tokens or `--require-auth` are set (see [`layers`]). The order decides
+//! what each layer sees: with `auth` before `log`, requests refused for
+//! want of a token are not logged; with `metrics` after `rate-limit`,
+//! rejected requests are not counted. Wherever `AUTH` lines get to, their
+//! token is replaced with `***`. Whatever the order, the chain checks the
+//! client's permission again at its end, so no list lets a read-only token
+//! write.
+//!
+//! The `script` layer hands the request to the script of `--script` (see
+//! [`crate::script`]), which can answer it or replace it: the layers after
+//! it, and the handler, see the replacement. The permission check at the
+//! end of the chain sees it too.
+
+use crate::auth::{self, AuthToken, CertIdentity, Permission};
+use crate::close::CloseReason;
+use crate::config::ServerConfig;
+use crate::logger::{LogMessage, LogTx};
+use crate::metrics;
+use crate::protocol::{self, Utf8Mode};
+use crate::quota::Identity;
+use crate::rate_limit::{ConnLimiter, IpLimiter};
+use crate::registry::Registration;
+use crate::script::{Script, Verdict};
+use crate::session::Session;
+use crate::state::State;
+use crate::trace::{ConnTracer, TraceEvent};
+use crate::vhost;
+use bytes::Bytes;
+use std::fmt;
+use std::net::SocketAddr;
+use std::ops::ControlFlow;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// One interceptor of the chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layer {
+    /// Counts the request, for `STATS`, the registry and the session.
+    Metrics,
+    /// Takes `AUTH <token>`, and refuses what the client may not do: any
+    /// request before `AUTH` with `--require-auth`, writes with a read-only
+    /// token, admin commands without an admin one.
+    Auth,
+    /// Delays or rejects requests over the configured rates.
+    RateLimit,
+    /// Sends the request to the logger task.
+    Log,
+    /// Runs the request script, which may answer or rewrite the request.
+    Script,
+}
+
+/// Every request is counted, checked for a token, limited, then logged.
+pub const DEFAULT_LAYERS: [Layer; 4] =
+    [Layer::Metrics, Layer::Auth, Layer::RateLimit, Layer::Log];
+
+/// The layers of `config`'s chain. With tokens, certificate identities or
+/// `--require-auth`, `auth` runs even if the list leaves it out, first:
+/// it takes `AUTH`, and without it clients the config refuses would be served.
+pub fn layers(config: &ServerConfig) -> Vec<Layer> {
+    let mut layers = config.middleware.clone();
+    let auth = config.require_auth
+        || !config.auth_tokens.is_empty()
+        || !config.tls_identities.is_empty();
+    if auth && !layers.contains(&Layer::Auth) {
+        layers.insert(0, Layer::Auth);
+    }
+    layers
+}
+
+impl fmt::Display for Layer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Metrics => "metrics",
+            Self::Auth => "auth",
+            Self::RateLimit => "rate-limit",
+            Self::Log => "log",
+            Self::Script => "script",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A request on its way through the chain.
+pub struct Request<'a, T> {
+    /// The message as it was read.
+    pub line: &'a Bytes,
+    /// The message as text, with invalid UTF-8 replaced.
+    pub input: &'a str,
+    pub registration: &'a Registration,
+    pub session: &'a T,
+}
+
+/// Why a request went no further than the chain.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Stop {
+    /// A layer answered the request.
+    Reply(String),
+    /// A layer ends the connection, with a last reply.
+    Close(&'static str, CloseReason),
+}
+
+/// The layers of one connection, with the state they keep between requests.
+pub struct Chain {
+    layers: Vec<Layer>,
+    state: Arc<State>,
+    log_tx: LogTx,
+    peer: SocketAddr,
+    limiter: ConnLimiter,
+    // Rate-limited requests in a row, and how many end the connection
+    strikes: u32,
+    disconnect_after: Option<u32>,
+    require_auth: bool,
+    tokens: Vec<AuthToken>,
+    // The token the client sent, if it did
+    authenticated: Option<AuthToken>,
+    // Who the client certificate names, and the identities the config lists
+    identity: Option<String>,
+    identities: Vec<CertIdentity>,
+    // The TLS virtual host, whose limit replaces the connection limit
+    vhost: Option<String>,
+    // Wrong tokens, counted per address for the whole server
+    failures: Arc<IpLimiter>,
+    log_messages: bool,
+    utf8: Utf8Mode,
+    // Compiled once for the whole server, if `--script` is set
+    script: Option<Script>,
+    script_budget: Duration,
+}
+
+impl Chain {
+    pub fn new(
+        state: Arc<State>,
+        log_tx: LogTx,
+        limiter: ConnLimiter,
+        failures: Arc<IpLimiter>,
+        peer: SocketAddr,
+    ) -> Self {
+        Self {
+            layers: DEFAULT_LAYERS.to_vec(),
+            state,
+            log_tx,
+            peer,
+            limiter,
+            strikes: 0,
+            disconnect_after: None,
+            require_auth: false,
+            tokens: Vec::new(),
+            authenticated: None,
+            identity: None,
+            identities: Vec::new(),
+            vhost: None,
+            failures,
+            log_messages: true,
+            utf8: Utf8Mode::Lossy,
+            script: None,
+            script_budget: Duration::ZERO,
+        }
+    }
+
+    /// Applies (possibly reloaded) settings. What the layers keep, such as
+    /// the rate limiter's tokens or a client's authentication, stays.
+    pub fn configure(&mut self, config: &ServerConfig) {
+        let layers = layers(config);
+        if self.layers != layers {
+            self.layers = layers;
+        }
+        let host = self.vhost.as_deref().and_then(|name| vhost::find(&config.tls_hosts, name));
+        let conn_limit = host.and_then(|host| host.limit).or(config.conn_limit);
+        self.limiter.configure(conn_limit, config.ip_limit, config.on_limit);
+        self.disconnect_after = config.disconnect_after;
+        self.require_auth = config.require_auth;
+        if self.tokens != config.auth_tokens {
+            self.tokens.clone_from(&config.auth_tokens);
+        }
+        if self.identities != config.tls_identities {
+            self.identities.clone_from(&config.tls_identities);
+        }
+        self.log_messages = config.log_messages;
+        self.utf8 = config.utf8;
+        self.script_budget = config.script_budget;
+    }
+
+    /// Who the client certificate names, on a listener that asks for one.
+    /// What that identity may do is looked up in the config at every request,
+    /// so a reload can grant or revoke it.
+    pub fn set_identity(&mut self, identity: Option<String>) {
+        self.identity = identity;
+    }
+
+    /// The virtual host the connection was routed to, if any. Like an
+    /// identity, its settings are looked up in the config at every request.
+    pub fn set_vhost(&mut self, vhost: Option<String>) {
+        self.vhost = vhost;
+    }
+
+    /// The script the `script` layer runs; without one, the layer lets
+    /// every request through.
+    pub fn set_script(&mut self, script: Option<Script>) {
+        self.script = script;
+    }
+
+    /// Whether a request can wait in the chain, for the rate limiter.
+    ///
+    /// The handler sends earlier replies first, so they are not held back.
+    pub fn may_delay(&self) -> bool {
+        self.layers.contains(&Layer::RateLimit) && self.limiter.may_delay()
+    }
+
+    /// Whether the client's token, or else its certificate, is an admin one.
+    pub fn is_admin(&self) -> bool {
+        let token = self.authenticated.as_ref().map(|token| token.permission);
+        token.or_else(|| self.certified()) == Some(Permission::Admin)
+    }
+
+    /// Passes `request` through every layer, in order. Returns the request
+    /// the script replaced it with, if it did.
+    pub async fn run<T: Session>(
+        &mut self,
+        request: &Request<'_, T>,
+        tracer: &mut ConnTracer,
+    ) -> ControlFlow<Stop, Option<String>> {
+        let mut rewritten: Option<(Bytes, String)> = None;
+        for i in 0..self.layers.len() {
+            let replaced;
+            let request = match &rewritten {
+                Some((line, input)) => {
+                    let (registration, session) = (request.registration, request.session);
+                    replaced = Request { line, input, registration, session };
+                    &replaced
+                }
+                None => request,
+            };
+            match self.layers[i] {
+                Layer::Metrics => self.count(request),
+                Layer::Auth => self.authenticate(request).await?,
+                Layer::RateLimit => self.limit(tracer).await?,
+                Layer::Log => self.log(request, tracer).await,
+                Layer::Script => {
+                    if let Some(input) = self.script(request, tracer).await? {
+                        rewritten = Some((Bytes::from(input.clone()), input));
+                    }
+                }
+            }
+        }
+        // Checked again whatever the layers, on what the handler will get: a
+        // read-only token never writes, not even through the script
+        let input = rewritten.as_ref().map_or(request.input, |(_, input)| input);
+        if auth::parse_auth(input).is_none()
+            && let Some(reply) = self.refusal(input)
+        {
+            return ControlFlow::Break(Stop::Reply(reply.to_string()));
+        }
+        ControlFlow::Continue(rewritten.map(|(_, input)| input))
+    }
+
+    fn count<T: Session>(&self, request: &Request<'_, T>) {
+        self.state.metrics.incr(metrics::MESSAGES_RECEIVED, 1);
+        self.state.peers.request(self.peer.ip());
+        request.registration.record_request();
+        request.session.record(auth::redact(request.input));
+    }
+
+    async fn authenticate<T>(&mut self, request: &Request<'_, T>) -> ControlFlow<Stop> {
+        let Some(offered) = auth::parse_auth(request.input) else {
+            return match self.refusal(request.input) {
+                Some(reply) => ControlFlow::Break(Stop::Reply(reply.to_string())),
+                None => ControlFlow::Continue(()),
+            };
+        };
+        if self.tokens.is_empty() {
+            return ControlFlow::Break(Stop::Reply("OK: no token is required\n".to_string()));
+        }
+
+        // An address that sent too many wrong tokens is not even checked
+        let ip = self.peer.ip();
+        let wait = self.failures.wait_time(ip, auth::FAILURE_LIMIT, Instant::now());
+        if !wait.is_zero() {
+            self.state.metrics.incr(metrics::AUTH_THROTTLED, 1);
+            let reply =
+                format!("ERR: too many wrong tokens, retry in {}ms\n", wait.as_millis().max(1));
+            return ControlFlow::Break(Stop::Reply(reply));
+        }
+
+        let conn = request.registration.id();
+        let reply = match self.tokens.iter().find(|token| token.matches(offered)) {
+            Some(token) => {
+                let text = format!(
+                    "connection #{} from {} authenticated as {} ({})",
+                    conn, self.peer, token.name, token.permission,
+                );
+                self.log_tx.send(LogMessage::info(text)).await;
+                let reply = format!("OK: authenticated as {} ({})\n", token.name, token.permission);
+                self.authenticated = Some(token.clone());
+                self.limiter.set_identity(Identity::Token(token.name.clone()));
+                reply
+            }
+            None => {
+                let _ = self.failures.try_take(ip, auth::FAILURE_LIMIT, Instant::now());
+                self.state.metrics.incr(metrics::AUTH_FAILURES, 1);
+                let text = format!("connection #{} from {} sent a wrong token", conn, self.peer);
+                self.log_tx.send(LogMessage::error(text)).await;
+                "ERR: wrong token\n".to_string()
+            }
+        };
+        ControlFlow::Break(Stop::Reply(reply))
+    }
+
+    /// Why the client may not send `input`, if it may not: before `AUTH`
+    /// with `--require-auth`, a write with a read-only token, an admin
+    /// command without an admin one.
+    fn refusal(&self, input: &str) -> Option<&'static str> {
+        // A token the client sent counts over its certificate
+        let permission = match (&self.authenticated, self.certified()) {
+            (Some(token), _) => Some((token.permission, "ERR: the token is read-only\n")),
+            (None, Some(permission)) => Some((permission, "ERR: the certificate is read-only\n")),
+            (None, None) => None,
+        };
+        match permission {
+            Some((Permission::Admin, _)) => None,
+            _ if auth::is_admin_only(input) => {
+                Some("ERR: only an admin token or certificate may do that\n")
+            }
+            Some((Permission::ReadOnly, read_only)) => auth::is_write(input).then_some(read_only),
+            None => self.require_auth.then_some("ERR: authenticate first, with AUTH <token>\n"),
+        }
+    }
+
+    /// What the client certificate may do, if the config lists its identity.
+    fn certified(&self) -> Option<Permission> {
+        let identity = self.identity.as_deref()?;
+        self.identities.iter().find(|known| known.name == identity).map(|known| known.permission)
+    }
+
+    /// With the `delay` policy this await is where a fast client gets slowed down.
+    async fn limit(&mut self, tracer: &mut ConnTracer) -> ControlFlow<Stop> {
+        tracer.record(TraceEvent::Waiting("rate limit")).await;
+        match self.limiter.acquire().await {
+            Ok(()) => {
+                self.strikes = 0;
+                ControlFlow::Continue(())
+            }
+            Err(retry_in) => {
+                self.state.metrics.incr(metrics::MESSAGES_RATE_LIMITED, 1);
+                self.strikes += 1;
+                if self.disconnect_after.is_some_and(|limit| self.strikes >= limit) {
+                    let reply = "BYE: rate limit exceeded too often\n";
+                    return ControlFlow::Break(Stop::Close(reply, CloseReason::RateLimited));
+                }
+                let reply = format!(
+                    "ERR: rate limit exceeded, retry in {}ms\n",
+                    retry_in.as_millis().max(1),
+                );
+                ControlFlow::Break(Stop::Reply(reply))
+            }
+        }
+    }
+
+    /// Runs the script on its blocking thread. A request it fails on is
+    /// answered with an error, and the failure logged.
+    async fn script<T>(
+        &self,
+        request: &Request<'_, T>,
+        tracer: &mut ConnTracer,
+    ) -> ControlFlow<Stop, Option<String>> {
+        let Some(script) = &self.script else {
+            return ControlFlow::Continue(None);
+        };
+        tracer.record(TraceEvent::Waiting("script")).await;
+        let reply = match script.on_message(request.input, self.peer, self.script_budget).await {
+            Ok(Verdict::Pass) => return ControlFlow::Continue(None),
+            Ok(Verdict::Rewrite(input)) => return ControlFlow::Continue(Some(input)),
+            Ok(Verdict::Reply(reply)) => format!("{}\n", reply.trim_end_matches('\n')),
+            Err(failure) => {
+                let conn = request.registration.id();
+                let text = format!("script failed on connection #{}: {}", conn, failure);
+                self.log_tx.send(LogMessage::error(text)).await;
+                "ERR: the request script failed\n".to_string()
+            }
+        };
+        ControlFlow::Break(Stop::Reply(reply))
+    }
+
+    /// Instead of logging directly here, the request is sent to a dedicated
+    /// logging task, which decouples logging from request handling. With
+    /// `--no-log-messages` the channel is skipped altogether.
+    async fn log<T>(&self, request: &Request<'_, T>, tracer: &mut ConnTracer) {
+        if !self.log_messages {
+            return;
+        }
+        tracer.record(TraceEvent::Waiting("log channel")).await;
+        let line = request.line;
+        let message = if auth::parse_auth(request.input).is_some() {
+            LogMessage::info(auth::redact(request.input).to_string())
+        } else if self.utf8 == Utf8Mode::Binary && std::str::from_utf8(line).is_err() {
+            LogMessage::info(protocol::hex_dump(line))
+        } else {
+            LogMessage::info(line.clone())
+        };
+        self.log_tx.send(message.with_peer(self.peer)).await;
+    }
+}
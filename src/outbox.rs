@@ -0,0 +1,479 @@
+//! Replies waiting to be written, sent in batches.
+//!
+//! A client that pipelines several lines gets them delivered in one read,
+//! and the handler answers them back to back. Writing every reply on its
+//! own costs one `write` syscall per reply. Instead the replies are pushed
+//! into an [`Outbox`] and written together once the handler runs out of
+//! buffered input, with a single vectored write (`writev`): the kernel
+//! gathers the separate reply buffers, so they are not copied into one.
+//!
+//! The outbox implements `bytes::Buf` over its queue of chunks, which is
+//! what `AsyncWriteExt::write_all_buf` needs to use `poll_write_vectored`.
+//!
+//! Replies are written as text lines. The outbox encodes them for the
+//! connection's [`Wire`]: the JSON protocol wraps each into a `Response`,
+//! MessagePack does the same with `rmp-serde`, and with length prefixes
+//! the newline is replaced by a prefix chunk (the reply itself is still
+//! not copied).
+//!
+//! A client that opted into gzip gets replies longer than the threshold as
+//! a `GZIP <length>` line followed by one gzip member. The vectored write
+//! stops at such a reply, which then goes out through a [`GzipEncoder`]
+//! layered over the same writer.
+//!
+//! While the handler waits for input, [`poll_write`](Outbox::poll_write)
+//! writes the queue a piece at a time instead, so replies go out even as
+//! more requests come in. A client that does not read them makes the
+//! queue grow; see [`crate::backpressure`] for what happens then.
+//!
+//! Requests that run concurrently (`--max-in-flight`) finish in any order,
+//! but their replies must not. Such a request [`reserve`](Outbox::reserve)s
+//! its place first and [`fill`](Outbox::fill)s it when done; every reply
+//! pushed after an unfilled place is held back until the place is filled,
+//! and only then encoded and queued.
+
+use crate::framing::{self, Framing};
+use crate::gzip::GzipEncoder;
+use crate::protocol::{Protocol, Response, Wire};
+use bytes::{Buf, Bytes};
+use std::collections::VecDeque;
+use std::io::{self, IoSlice};
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// Replies handed to one vectored write by `poll_write`.
+const MAX_SLICES: usize = 64;
+
+#[derive(Debug, Default)]
+pub struct Outbox {
+    wire: Wire,
+    // Requests read so far, for `Response::request_no`
+    request_no: u64,
+    // Replies longer than this are gzipped, once the client asked for it
+    gzip_above: Option<usize>,
+    chunks: VecDeque<Chunk>,
+    // All queued bytes, and those before the first chunk to gzip
+    queued: usize,
+    plain: usize,
+    // Part of the first chunk is written already
+    started: bool,
+    // Replies behind a reserved place, and the slot number of the first one
+    held: VecDeque<Held>,
+    held_base: u64,
+    held_bytes: usize,
+}
+
+/// A place in the order of replies, for a request that is still running.
+#[derive(Debug)]
+#[must_use = "a reserved place holds back every later reply until it is filled"]
+pub struct Slot(u64);
+
+#[derive(Debug)]
+struct Held {
+    // The request it answers, for `Response::request_no`
+    request_no: u64,
+    reply: Option<Reply>,
+}
+
+#[derive(Debug)]
+enum Reply {
+    Line(Bytes),
+    Response(Response),
+}
+
+#[derive(Debug)]
+struct Chunk {
+    bytes: Bytes,
+    gzip: bool,
+    // The first chunk of a reply; a prefix or `GZIP` header comes with the next
+    first: bool,
+}
+
+impl Outbox {
+    pub fn new(wire: Wire) -> Self {
+        Self { wire, ..Self::default() }
+    }
+
+    /// Encodes the replies pushed from now on with `protocol`.
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.wire.protocol = protocol;
+    }
+
+    /// Gzips the replies longer than `threshold` pushed from now on.
+    pub fn set_gzip(&mut self, threshold: Option<usize>) {
+        self.gzip_above = threshold;
+    }
+
+    /// Counts a request; the replies pushed from now on answer it.
+    pub fn start_request(&mut self) {
+        self.request_no += 1;
+    }
+
+    /// Queues a reply, a line ending in `\n`; nothing is written yet.
+    pub fn push(&mut self, reply: impl Into<Bytes>) {
+        let reply = reply.into();
+        if reply.is_empty() {
+            return;
+        }
+        self.push_reply(Reply::Line(reply));
+    }
+
+    /// Queues a response of the JSON protocol, answering the current request.
+    pub fn push_response(&mut self, response: Response) {
+        self.push_reply(Reply::Response(response));
+    }
+
+    /// Reserves the place of the current request's reply, for when it is done.
+    pub fn reserve(&mut self) -> Slot {
+        let slot = Slot(self.held_base + self.held.len() as u64);
+        self.held.push_back(Held { request_no: self.request_no, reply: None });
+        slot
+    }
+
+    /// Puts the reply in its place, and queues the replies that waited for it.
+    pub fn fill(&mut self, slot: Slot, reply: impl Into<Bytes>) {
+        let reply = reply.into();
+        let held = &mut self.held[(slot.0 - self.held_base) as usize];
+        self.held_bytes += reply.len();
+        held.reply = Some(Reply::Line(reply));
+        self.release(false);
+    }
+
+    /// Gives up on the places still reserved, and queues everything else;
+    /// for requests that will not finish.
+    pub fn abandon(&mut self) {
+        self.release(true);
+    }
+
+    /// Whether a reserved place is not filled yet.
+    pub fn is_waiting(&self) -> bool {
+        !self.held.is_empty()
+    }
+
+    fn push_reply(&mut self, reply: Reply) {
+        if self.held.is_empty() {
+            self.encode(reply, self.request_no);
+        } else {
+            self.held_bytes += match &reply {
+                Reply::Line(line) => line.len(),
+                Reply::Response(_) => 0,
+            };
+            self.held.push_back(Held { request_no: self.request_no, reply: Some(reply) });
+        }
+    }
+
+    fn release(&mut self, abandon: bool) {
+        while let Some(held) = self.held.front_mut() {
+            let reply = match held.reply.take() {
+                Some(reply) => reply,
+                None if abandon => {
+                    self.held.pop_front();
+                    self.held_base += 1;
+                    continue;
+                }
+                None => return,
+            };
+            let request_no = held.request_no;
+            self.held.pop_front();
+            self.held_base += 1;
+            if let Reply::Line(line) = &reply {
+                self.held_bytes -= line.len();
+            }
+            self.encode(reply, request_no);
+        }
+    }
+
+    fn encode(&mut self, reply: Reply, request_no: u64) {
+        match (reply, self.wire.protocol) {
+            #[cfg(feature = "msgpack")]
+            (Reply::Response(response), Protocol::MessagePack) => {
+                self.frame_exact(Response { request_no, ..response }.to_msgpack());
+            }
+            (Reply::Response(response), _) => {
+                self.frame(Response { request_no, ..response }.to_line());
+            }
+            // A refusal (not ready, paused) is the only text a SOCKS client can get
+            (Reply::Line(line), Protocol::Text | Protocol::Socks5 | Protocol::Tls) => {
+                self.frame(line)
+            }
+            (Reply::Line(line), Protocol::Json) => {
+                self.frame(Response::from_reply(&line, request_no).to_line())
+            }
+            #[cfg(feature = "msgpack")]
+            (Reply::Line(line), Protocol::MessagePack) => {
+                self.frame_exact(Response::from_reply(&line, request_no).to_msgpack())
+            }
+        }
+    }
+
+    fn frame(&mut self, mut reply: Bytes) {
+        if self.wire.framing == Framing::LengthPrefixed {
+            // The prefix marks the end of the reply instead of the newline
+            if reply.ends_with(b"\n") {
+                reply.truncate(reply.len() - 1);
+            }
+            self.frame_exact(reply);
+        } else if self.gzip_above.is_some_and(|threshold| reply.len() > threshold) {
+            // The header gives the length once decompressed
+            self.queue(Bytes::from(format!("GZIP {}\n", reply.len())), false, true);
+            self.queue(reply, true, false);
+        } else {
+            self.queue(reply, false, true);
+        }
+    }
+
+    /// Queues `reply` behind its length prefix as it is: a binary reply may
+    /// end in a byte that only looks like a newline.
+    fn frame_exact(&mut self, reply: Bytes) {
+        self.queue(framing::length_prefix(reply.len()), false, true);
+        self.queue(reply, false, false);
+    }
+
+    fn queue(&mut self, bytes: Bytes, gzip: bool, first: bool) {
+        if bytes.is_empty() {
+            return;
+        }
+        if !gzip && self.plain == self.queued {
+            self.plain += bytes.len();
+        }
+        self.queued += bytes.len();
+        self.chunks.push_back(Chunk { bytes, gzip, first });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queued == 0
+    }
+
+    /// The bytes queued or held back, replies to gzip counted before
+    /// compression.
+    pub fn len(&self) -> usize {
+        self.queued + self.held_bytes
+    }
+
+    /// Drops the oldest replies until at most `limit` bytes are queued, and
+    /// returns how many were dropped. A reply that is partly written stays,
+    /// or the client would get half of it followed by the next one.
+    pub fn drop_oldest(&mut self, limit: usize) -> usize {
+        let mut at = 0;
+        if self.started {
+            at = 1;
+            while self.chunks.get(at).is_some_and(|chunk| !chunk.first) {
+                at += 1;
+            }
+        }
+        let mut dropped = 0;
+        while self.queued > limit && at < self.chunks.len() {
+            // The reply's first chunk, and the ones that belong to it
+            loop {
+                let chunk = self.chunks.remove(at).expect("checked against the length");
+                self.queued -= chunk.bytes.len();
+                if self.chunks.get(at).is_none_or(|chunk| chunk.first) {
+                    break;
+                }
+            }
+            dropped += 1;
+        }
+        self.plain = self.chunks.iter().take_while(|c| !c.gzip).map(|c| c.bytes.len()).sum();
+        dropped
+    }
+
+    /// Writes as many of the replies before the next one to gzip as the
+    /// writer takes in one vectored write.
+    ///
+    /// Cancel-safe, unlike `write_to`: the written bytes leave the queue
+    /// only once the write has completed.
+    pub fn poll_write<W: AsyncWrite + Unpin>(
+        &mut self,
+        cx: &mut Context<'_>,
+        writer: &mut W,
+    ) -> Poll<io::Result<usize>> {
+        let mut slices = [IoSlice::new(&[]); MAX_SLICES];
+        let filled = self.chunks_vectored(&mut slices);
+        let n = ready!(Pin::new(writer).poll_write_vectored(cx, &slices[..filled]))?;
+        if n == 0 && filled > 0 {
+            return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+        }
+        self.advance(n);
+        Poll::Ready(Ok(n))
+    }
+
+    /// Writes every queued reply and returns the number of bytes written.
+    ///
+    /// Not cancel-safe in the sense that a cancelled write may have sent a
+    /// prefix; what was sent is gone from the outbox, the rest stays.
+    pub async fn write_to<W: AsyncWrite + Unpin>(&mut self, writer: &mut W) -> io::Result<usize> {
+        let mut written = 0;
+        loop {
+            // The replies up to the next one to gzip, in one vectored write
+            written += self.plain;
+            writer.write_all_buf(self).await?;
+            let Some(chunk) = self.chunks.pop_front() else { return Ok(written) };
+            self.queued -= chunk.bytes.len();
+            self.started = false;
+
+            let mut encoder = GzipEncoder::new(&mut *writer);
+            encoder.write_all(&chunk.bytes).await?;
+            encoder.finish().await?;
+            written += encoder.total_out() as usize;
+            self.plain = self.chunks.iter().take_while(|c| !c.gzip).map(|c| c.bytes.len()).sum();
+        }
+    }
+}
+
+/// The replies before the first one to gzip, which `write_to` sends itself.
+impl Buf for Outbox {
+    fn remaining(&self) -> usize {
+        self.plain
+    }
+
+    fn chunk(&self) -> &[u8] {
+        match self.chunks.front() {
+            Some(chunk) if !chunk.gzip => &chunk.bytes,
+            _ => &[],
+        }
+    }
+
+    fn advance(&mut self, mut cnt: usize) {
+        assert!(cnt <= self.plain, "advanced past the end of the outbox");
+        self.plain -= cnt;
+        self.queued -= cnt;
+        while cnt > 0 {
+            let front = self.chunks.front_mut().expect("remaining counts queued bytes");
+            if cnt < front.bytes.len() {
+                // A partial write: the rest of this reply goes out next time
+                front.bytes.advance(cnt);
+                self.started = true;
+                return;
+            }
+            cnt -= front.bytes.len();
+            self.chunks.pop_front();
+            self.started = false;
+        }
+    }
+
+    /// One `IoSlice` per queued reply, as many as fit into `dst`.
+    fn chunks_vectored<'a>(&'a self, dst: &mut [IoSlice<'a>]) -> usize {
+        let plain = self.chunks.iter().take_while(|chunk| !chunk.gzip);
+        let mut filled = 0;
+        for (slot, chunk) in dst.iter_mut().zip(plain) {
+            *slot = IoSlice::new(&chunk.bytes);
+            filled += 1;
+        }
+        filled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn partial_writes_resume_in_the_middle_of_a_reply() {
+        let mut outbox = Outbox::default();
+        outbox.push("OK: 'a'\n");
+        outbox.push("");
+        outbox.push(String::from("OK: 'b'\n"));
+
+        let mut slices = [IoSlice::new(&[]); 4];
+        assert_eq!(outbox.chunks_vectored(&mut slices), 2);
+
+        outbox.advance(12);
+        assert_eq!(outbox.chunk(), b"'b'\n");
+        assert_eq!(outbox.remaining(), 4);
+        outbox.advance(4);
+        assert!(outbox.is_empty());
+    }
+
+    #[test]
+    fn only_whole_replies_are_dropped() {
+        let wire = Wire { framing: Framing::LengthPrefixed, ..Wire::default() };
+        let mut outbox = Outbox::new(wire);
+        outbox.push("one\n");
+        outbox.push("two\n");
+        outbox.push("three\n");
+        // Half of the first reply is on its way
+        outbox.advance(5);
+
+        assert_eq!(outbox.drop_oldest(12), 1);
+        let mut written = Vec::new();
+        while outbox.has_remaining() {
+            written.extend_from_slice(outbox.chunk());
+            outbox.advance(outbox.chunk().len());
+        }
+        assert_eq!(written, b"ne\0\0\0\x05three");
+    }
+
+    #[tokio::test]
+    async fn replies_wait_for_the_places_reserved_before_them() {
+        let mut outbox = Outbox::default();
+        outbox.push("one\n");
+        let slow = outbox.reserve();
+        let fast = outbox.reserve();
+        outbox.push("four\n");
+        outbox.fill(fast, "three\n");
+        assert!(outbox.is_waiting());
+        assert_eq!(outbox.remaining(), 4);
+
+        outbox.fill(slow, "two\n");
+        let mut written = Vec::new();
+        outbox.write_to(&mut written).await.unwrap();
+        assert_eq!(written, b"one\ntwo\nthree\nfour\n");
+
+        // An abandoned place lets the rest through
+        let lost = outbox.reserve();
+        outbox.push("five\n");
+        outbox.abandon();
+        drop(lost);
+        let mut written = Vec::new();
+        outbox.write_to(&mut written).await.unwrap();
+        assert_eq!(written, b"five\n");
+        assert_eq!(outbox.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn replies_are_written_in_order() {
+        let mut outbox = Outbox::default();
+        outbox.push("one\n");
+        outbox.push("two\n");
+
+        let mut written = Vec::new();
+        assert_eq!(outbox.write_to(&mut written).await.unwrap(), 8);
+        assert_eq!(written, b"one\ntwo\n");
+        assert!(outbox.is_empty());
+    }
+
+    #[tokio::test]
+    async fn length_prefixed_replies_drop_the_newline() {
+        let wire = Wire { framing: Framing::LengthPrefixed, ..Wire::default() };
+        let mut outbox = Outbox::new(wire);
+        outbox.push("OK\n");
+
+        let mut written = Vec::new();
+        outbox.write_to(&mut written).await.unwrap();
+        assert_eq!(written, b"\0\0\0\x02OK");
+    }
+
+    #[tokio::test]
+    async fn long_replies_are_gzipped_in_place() {
+        let mut outbox = Outbox::default();
+        outbox.set_gzip(Some(16));
+        outbox.push("short\n");
+        let long = format!("{}\n", "x".repeat(100));
+        outbox.push(long.clone());
+        outbox.push("after\n");
+
+        let mut written = Vec::new();
+        let len = outbox.write_to(&mut written).await.unwrap();
+        assert_eq!(len, written.len());
+        assert!(outbox.is_empty());
+        assert!(written.starts_with(b"short\nGZIP 101\n"));
+        assert!(written.ends_with(b"after\n"));
+
+        let member = &written[b"short\nGZIP 101\n".len()..];
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(member).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, long);
+    }
+}
@@ -0,0 +1,219 @@
+//! Per-client-address statistics.
+//!
+//! The request counter in `State` is global. `Peers` keeps the same kind of
+//! numbers for every client IP address: connections, requests, bytes in
+//! and out, and when the address was first and last seen. Ports are left
+//! out, since every new connection from a client gets a new one.
+//!
+//! The map would otherwise grow with every address that ever connected, so
+//! a sweeper task evicts addresses that have been idle longer than
+//! `peer_idle_secs` and logs the ones that remain.
+
+use crate::config::ConfigRx;
+use crate::logger::{LogMessage, LogTx};
+use crate::metrics::{self, Metrics};
+use crate::readiness::{self, Phase};
+use crate::state::State;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::{Instant, MissedTickBehavior};
+
+/// How often idle addresses are evicted and the others logged.
+pub const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What is known about one client address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerStats {
+    pub connections: u64,
+    pub requests: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub first_seen: Instant,
+    pub last_seen: Instant,
+}
+
+impl PeerStats {
+    fn new(now: Instant) -> Self {
+        Self {
+            connections: 0,
+            requests: 0,
+            bytes_read: 0,
+            bytes_written: 0,
+            first_seen: now,
+            last_seen: now,
+        }
+    }
+
+    /// `name=value` pairs; the two times as seconds ago.
+    fn describe(&self, now: Instant) -> String {
+        format!(
+            "connections={} requests={} bytes_in={} bytes_out={} first_seen={}s last_seen={}s",
+            self.connections,
+            self.requests,
+            self.bytes_read,
+            self.bytes_written,
+            now.duration_since(self.first_seen).as_secs(),
+            now.duration_since(self.last_seen).as_secs(),
+        )
+    }
+}
+
+/// The statistics of every client address seen recently.
+pub struct Peers {
+    map: Mutex<HashMap<IpAddr, PeerStats>>,
+    metrics: Metrics,
+}
+
+impl Peers {
+    pub fn new(metrics: Metrics) -> Self {
+        Self { map: Mutex::default(), metrics }
+    }
+
+    /// A connection from `ip` is being served.
+    pub fn connected(&self, ip: IpAddr) {
+        self.update(ip, |stats| stats.connections += 1);
+    }
+
+    /// `ip` sent a message.
+    pub fn request(&self, ip: IpAddr) {
+        self.update(ip, |stats| stats.requests += 1);
+    }
+
+    /// A connection from `ip` closed after moving these bytes.
+    pub fn transferred(&self, ip: IpAddr, read: u64, written: u64) {
+        self.update(ip, |stats| {
+            stats.bytes_read += read;
+            stats.bytes_written += written;
+        });
+    }
+
+    fn update(&self, ip: IpAddr, apply: impl FnOnce(&mut PeerStats)) {
+        let now = Instant::now();
+        let mut map = self.map.lock().unwrap();
+        let stats = map.entry(ip).or_insert_with(|| {
+            self.metrics.adjust(metrics::PEERS_TRACKED, 1);
+            PeerStats::new(now)
+        });
+        stats.last_seen = now;
+        apply(stats);
+    }
+
+    pub fn get(&self, ip: IpAddr) -> Option<PeerStats> {
+        self.map.lock().unwrap().get(&ip).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Removes the addresses not seen for longer than `idle`; returns how many.
+    ///
+    /// A client whose connection stays open without sending anything is
+    /// idle too; it starts over with its next message.
+    pub fn evict_idle(&self, idle: Duration) -> usize {
+        let now = Instant::now();
+        let evicted = {
+            let mut map = self.map.lock().unwrap();
+            let before = map.len();
+            map.retain(|_, stats| now.duration_since(stats.last_seen) <= idle);
+            before - map.len()
+        };
+        if evicted > 0 {
+            self.metrics.adjust(metrics::PEERS_TRACKED, -(evicted as i64));
+            self.metrics.incr(metrics::PEERS_EVICTED, evicted as u64);
+        }
+        evicted
+    }
+
+    /// `(address, description)` pairs, sorted by address.
+    fn described(&self) -> Vec<(IpAddr, String)> {
+        let now = Instant::now();
+        let mut peers: Vec<_> = {
+            let map = self.map.lock().unwrap();
+            map.iter().map(|(ip, stats)| (*ip, stats.describe(now))).collect()
+        };
+        peers.sort();
+        peers
+    }
+
+    /// One `PEER <ip> <name>=<value> ...` line per address, for `STATS`.
+    pub fn stat_lines(&self) -> String {
+        self.described()
+            .into_iter()
+            .map(|(ip, description)| format!("PEER {} {}\n", ip, description))
+            .collect()
+    }
+}
+
+/// Every `SWEEP_INTERVAL`, evicts idle addresses and logs the remaining
+/// ones, until the server drains.
+pub async fn sweep(
+    state: Arc<State>,
+    mut config: ConfigRx,
+    log_tx: LogTx,
+    mut phase: watch::Receiver<Phase>,
+) {
+    let mut ticker = tokio::time::interval(SWEEP_INTERVAL);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // The first tick completes immediately; there is nothing to sweep yet
+    ticker.tick().await;
+
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = readiness::wait_draining(&mut phase) => return,
+        }
+        // Read on every sweep, so a reload applies to the next one
+        let idle = config.borrow_and_update().peer_idle;
+        let evicted = state.peers.evict_idle(idle);
+        if evicted > 0 {
+            let text = format!("peers: evicted {} address(es) idle for over {:?}", evicted, idle);
+            log_tx.send(LogMessage::info(text)).await;
+        }
+        for (ip, description) in state.peers.described() {
+            log_tx.send(LogMessage::info(format!("peer {}: {}", ip, description))).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::InMemorySink;
+
+    #[tokio::test(start_paused = true)]
+    async fn addresses_are_counted_and_evicted_when_idle() {
+        let metrics: Metrics = Arc::new(InMemorySink::default());
+        let peers = Peers::new(metrics.clone());
+        let (first, second): (IpAddr, IpAddr) =
+            ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+
+        peers.connected(first);
+        peers.request(first);
+        peers.request(first);
+        peers.transferred(first, 12, 40);
+        tokio::time::advance(Duration::from_secs(30)).await;
+        peers.connected(second);
+
+        let stats = peers.get(first).unwrap();
+        assert_eq!((stats.connections, stats.requests), (1, 2));
+        assert_eq!((stats.bytes_read, stats.bytes_written), (12, 40));
+        let lines = peers.stat_lines();
+        let expected = "PEER 10.0.0.1 connections=1 requests=2 bytes_in=12 bytes_out=40 \
+                        first_seen=30s last_seen=30s\n";
+        assert!(lines.starts_with(expected), "{}", lines);
+
+        // Only the first address has been idle for more than 20s
+        assert_eq!(peers.evict_idle(Duration::from_secs(20)), 1);
+        assert!(peers.get(first).is_none());
+        assert_eq!(peers.len(), 1);
+        assert_eq!(metrics::stat_summary(&metrics), "peers_evicted=1 peers_tracked=1");
+    }
+}
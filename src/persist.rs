@@ -0,0 +1,116 @@
+//! Saving the request counter and the key-value store across restarts.
+//!
+//! The state is written as a small TOML file:
+//!
+//! ```toml
+//! counter = 42
+//! wal_seq = 7
+//!
+//! [kv]
+//! color = "blue"
+//! ```
+//!
+//! A snapshot is never written in place. It goes to `<file>.tmp` first,
+//! is flushed to disk, and then renamed over the real file. A rename within
+//! one directory is atomic, so a crash leaves either the old snapshot or
+//! the new one, never half of each.
+//!
+//! `wal_seq` is the number of the last change to the store the snapshot
+//! includes, once there has been one. With a write-ahead log, only the
+//! records after it are replayed on startup; see `crate::wal`.
+
+use crate::readiness::{self, Phase};
+use crate::state::State;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::watch;
+
+/// Everything that survives a restart.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Snapshot {
+    pub counter: i32,
+    #[serde(skip_serializing_if = "is_zero")]
+    pub wal_seq: u64,
+    // Sorted, so the file is stable and easy to diff
+    pub kv: BTreeMap<String, String>,
+}
+
+fn is_zero(seq: &u64) -> bool {
+    *seq == 0
+}
+
+/// Reads a snapshot; a missing file is not an error, just a first start.
+pub async fn load(path: &Path) -> io::Result<Option<Snapshot>> {
+    let text = match tokio::fs::read_to_string(path).await {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    toml::from_str(&text)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Writes a snapshot atomically: temp file, `sync_all`, rename.
+pub async fn save(path: &Path, snapshot: &Snapshot) -> io::Result<()> {
+    let text = toml::to_string(snapshot).map_err(io::Error::other)?;
+    let tmp = tmp_path(path);
+
+    let mut file = tokio::fs::File::create(&tmp).await?;
+    file.write_all(text.as_bytes()).await?;
+    // Without this the rename could reach the disk before the data does
+    file.sync_all().await?;
+    drop(file);
+
+    tokio::fs::rename(&tmp, path).await
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".tmp");
+    PathBuf::from(name)
+}
+
+/// Saves a snapshot every `interval` until the server starts draining.
+///
+/// Unchanged state is not written again. The final snapshot is taken by the
+/// caller after the connections have drained, so that it includes their
+/// last requests.
+pub async fn run(
+    path: PathBuf,
+    interval: Duration,
+    state: Arc<State>,
+    mut phase: watch::Receiver<Phase>,
+) -> Snapshot {
+    let mut saved = state.snapshot();
+    let mut ticks = tokio::time::interval(interval);
+    // The first tick completes immediately; the state was just restored
+    ticks.tick().await;
+
+    loop {
+        // A save is never interrupted half-way: the select only decides
+        // whether to start the next one
+        tokio::select! {
+            _ = ticks.tick() => {}
+            _ = readiness::wait_draining(&mut phase) => return saved,
+        }
+
+        let snapshot = state.snapshot();
+        if snapshot == saved {
+            continue;
+        }
+        match save(&path, &snapshot).await {
+            Ok(()) => {
+                state.kv.compact_wal(snapshot.wal_seq);
+                saved = snapshot;
+            }
+            Err(e) => eprintln!("[STATE] cannot save {}: {}", path.display(), e),
+        }
+    }
+}
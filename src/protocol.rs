@@ -0,0 +1,293 @@
+//! What a message means: plain text commands, or typed JSON requests.
+//!
+//! With the `Text` protocol a message is a command line such as
+//! `SET color blue`, and the reply is a line of text. With `Json` every
+//! message is a [`Request`] and every reply a [`Response`], one JSON
+//! document per line (NDJSON):
+//!
+//! ```text
+//! {"cmd":"SET","args":["color","blue"]}
+//! {"ok":true,"data":"OK","request_no":1}
+//! ```
+//!
+//! The handler itself only speaks text. A request is turned into the
+//! equivalent command line, and the outbox wraps each text reply into a
+//! response, so every command works the same in both protocols.
+//!
+//! `Socks5` connections are not served by the handler at all: after the
+//! SOCKS handshake they are tunnels, see [`crate::socks`]. `Tls`
+//! connections are decrypted first and then served in whichever protocol
+//! the client asked for with ALPN, see [`crate::tls`].
+//!
+//! With the `msgpack` feature, `MessagePack` carries the same [`Request`]
+//! and [`Response`] encoded with `rmp-serde`, one per length-prefixed
+//! frame. There is no listener of its own: the length-prefixed listener
+//! looks at the first byte of a connection's first message, and the marker
+//! of a small MessagePack map or array there ([`is_msgpack`]) switches the
+//! connection over. Those markers are UTF-8 continuation bytes, so text
+//! commands and JSON never start with one. Only the codec changes; the
+//! handler behind it is the same.
+//!
+//! Commands are text, but a client may send any bytes. [`Utf8Mode`]
+//! decides what happens to a message that is not valid UTF-8.
+
+use crate::framing::Framing;
+use crate::heartbeat::Heartbeat;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Protocol {
+    /// Command lines and text replies.
+    #[default]
+    Text,
+    /// A JSON [`Request`] per message, a JSON [`Response`] per reply.
+    Json,
+    /// A SOCKS5 proxy rather than a message protocol.
+    Socks5,
+    /// TLS, with the protocol inside picked by ALPN.
+    Tls,
+    /// A MessagePack [`Request`] per frame, a MessagePack [`Response`] per reply.
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+}
+
+/// Whether a message starts like a MessagePack request, a small map or
+/// array, which picks `MessagePack` on the length-prefixed listener.
+///
+/// Only the one-byte markers count: they are UTF-8 continuation bytes,
+/// which no text starts with. The longer maps' and arrays' markers are also
+/// lead bytes of UTF-8 (U+0700 to U+07FF), and a request never needs them.
+#[cfg(feature = "msgpack")]
+pub fn is_msgpack(message: &[u8]) -> bool {
+    use rmp::Marker;
+    let Some(&first) = message.first() else {
+        return false;
+    };
+    matches!(Marker::from_u8(first), Marker::FixMap(_) | Marker::FixArray(_))
+}
+
+/// How a listener's connections talk: how messages are delimited, what
+/// they contain, and whether idle ones are pinged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Wire {
+    pub framing: Framing,
+    pub protocol: Protocol,
+    pub heartbeat: Option<Heartbeat>,
+}
+
+/// What the handler does with a message that is not valid UTF-8.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Utf8Mode {
+    /// Replace the invalid bytes with U+FFFD and serve the message.
+    #[default]
+    Lossy,
+    /// Answer `ERR` and drop the message.
+    Strict,
+    /// Answer `ERR` and close the connection.
+    StrictClose,
+    /// Serve the message as `Lossy` does, but log its bytes as a hex dump.
+    Binary,
+}
+
+/// The bytes of `payload` in hex, followed by the printable ones, e.g.
+/// `68 69 ff |hi.|`.
+pub fn hex_dump(payload: &[u8]) -> String {
+    let mut dump = String::with_capacity(payload.len() * 4 + 2);
+    for byte in payload {
+        dump.push_str(&format!("{:02x} ", byte));
+    }
+    dump.push('|');
+    for &byte in payload {
+        let printable = byte.is_ascii_graphic() || byte == b' ';
+        dump.push(if printable { byte as char } else { '.' });
+    }
+    dump.push('|');
+    dump
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Request {
+    /// The command, e.g. `GET`; anything else is a plain message.
+    pub cmd: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+impl Request {
+    /// Parses a message in `protocol`'s encoding, JSON unless it is
+    /// `MessagePack`, or explains in a [`Response`] why it is not a request.
+    pub fn parse(payload: &[u8], protocol: Protocol) -> Result<Request, Response> {
+        let request: Request = match protocol {
+            #[cfg(feature = "msgpack")]
+            Protocol::MessagePack => rmp_serde::from_slice(payload).map_err(|e| {
+                Response::error(json!({
+                    "error": "malformed_msgpack",
+                    "message": e.to_string(),
+                }))
+            })?,
+            _ => serde_json::from_slice(payload).map_err(|e| {
+                Response::error(json!({
+                    "error": "malformed_json",
+                    "message": e.to_string(),
+                    "line": e.line(),
+                    "column": e.column(),
+                }))
+            })?,
+        };
+        // Words are split on whitespace, so a word must not contain any
+        let mut words = std::iter::once(&request.cmd).chain(&request.args);
+        if let Some(word) = words.find(|w| w.is_empty() || w.contains(char::is_whitespace)) {
+            return Err(Response::error(json!({
+                "error": "invalid_request",
+                "message": format!("{:?} is not a single word", word),
+            })));
+        }
+        Ok(request)
+    }
+
+    /// The equivalent text command.
+    pub fn command_line(&self) -> String {
+        std::iter::once(self.cmd.as_str())
+            .chain(self.args.iter().map(String::as_str))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Response {
+    pub ok: bool,
+    /// The reply text, or an object describing an error.
+    pub data: Value,
+    /// Which request of the connection this answers, counting from 1;
+    /// 0 for a reply before the first request.
+    pub request_no: u64,
+}
+
+impl Response {
+    /// Wraps a text reply. Replies starting with `ERR` or `BYE` are not ok.
+    pub fn from_reply(reply: &[u8], request_no: u64) -> Self {
+        let text = String::from_utf8_lossy(reply);
+        let text = text.strip_suffix('\n').unwrap_or(&text);
+        let ok = !(text.starts_with("ERR") || text.starts_with("BYE"));
+        Self { ok, data: Value::from(text), request_no }
+    }
+
+    /// A failed response; the outbox fills in `request_no`.
+    fn error(data: Value) -> Self {
+        Self { ok: false, data, request_no: 0 }
+    }
+
+    /// The response as one line of JSON.
+    pub fn to_line(&self) -> Bytes {
+        let mut line = serde_json::to_vec(self).expect("a response always serializes");
+        line.push(b'\n');
+        line.into()
+    }
+
+    /// The response as MessagePack, a map keyed like the JSON object. There
+    /// is no newline: the length prefix delimits it.
+    #[cfg(feature = "msgpack")]
+    pub fn to_msgpack(&self) -> Bytes {
+        rmp_serde::to_vec_named(self).expect("a response always serializes").into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn requests_become_command_lines() {
+        let json = Protocol::Json;
+        let request = Request::parse(br#"{"cmd":"SET","args":["color","blue"]}"#, json).unwrap();
+        assert_eq!(request.command_line(), "SET color blue");
+
+        let request = Request::parse(br#"{"cmd":"STATS"}"#, json).unwrap();
+        assert_eq!(request.command_line(), "STATS");
+    }
+
+    #[test]
+    fn bad_requests_get_structured_errors() {
+        let error = Request::parse(b"{\"cmd\":", Protocol::Json).unwrap_err();
+        assert!(!error.ok);
+        assert_eq!(error.data["error"], "malformed_json");
+        assert_eq!(error.data["column"], 7);
+
+        let payload = br#"{"cmd":"SET","args":["color","light blue"]}"#;
+        let error = Request::parse(payload, Protocol::Json);
+        assert_eq!(error.unwrap_err().data["error"], "invalid_request");
+    }
+
+    #[test]
+    fn replies_are_wrapped() {
+        let response = Response::from_reply(b"VALUE blue\n", 3);
+        let expected = br#"{"ok":true,"data":"VALUE blue","request_no":3}"#;
+        assert_eq!(response.to_line(), [&expected[..], b"\n"].concat());
+        assert!(!Response::from_reply(b"ERR: usage: GET <key>\n", 4).ok);
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn messagepack_carries_the_same_requests_and_responses() {
+        let msgpack = Protocol::MessagePack;
+        // As a map, like the JSON object, or as an array of the fields
+        let named = rmp_serde::to_vec_named(&json!({"cmd": "GET", "args": ["color"]})).unwrap();
+        let request = Request::parse(&named, msgpack).unwrap();
+        assert_eq!(request.command_line(), "GET color");
+        let compact = rmp_serde::to_vec(&("INCR", ["visits"])).unwrap();
+        assert_eq!(Request::parse(&compact, msgpack).unwrap().command_line(), "INCR visits");
+        assert!(is_msgpack(&named) && is_msgpack(&compact));
+        assert!(!is_msgpack(b"GET color") && !is_msgpack(br#"{"cmd":"GET"}"#));
+        // U+0700 to U+07FF start with the longer maps' and arrays' markers
+        assert!(!is_msgpack("\u{700} hello".as_bytes()) && !is_msgpack("\u{7ff}".as_bytes()));
+
+        let error = Request::parse(b"GET color", msgpack).unwrap_err();
+        assert_eq!(error.data["error"], "malformed_msgpack");
+
+        // Request 10 ends the encoding with 0x0a, which must not be taken for a newline
+        let response = Response::from_reply(b"VALUE blue\n", 10);
+        let encoded = response.to_msgpack();
+        assert_eq!(encoded.last(), Some(&b'\n'));
+        assert_eq!(rmp_serde::from_slice::<Response>(&encoded).unwrap(), response);
+    }
+
+    #[test]
+    fn hex_dumps_show_every_byte() {
+        assert_eq!(hex_dump(b"hi \xff\n"), "68 69 20 ff 0a |hi ..|");
+        assert_eq!(hex_dump(b""), "||");
+    }
+
+    proptest! {
+        #[test]
+        fn any_payload_is_a_request_or_an_error_response(
+            payload in proptest::collection::vec(any::<u8>(), 0..256),
+        ) {
+            if let Err(response) = Request::parse(&payload, Protocol::Json) {
+                prop_assert!(!response.ok);
+                prop_assert!(response.data["error"].is_string());
+            }
+        }
+
+        /// Whatever the words, a request is only accepted if its command
+        /// line splits back into exactly those words.
+        #[test]
+        fn accepted_requests_keep_their_words(
+            cmd in "\\PC{0,8}",
+            args in proptest::collection::vec("\\PC{0,8}", 0..4),
+        ) {
+            let payload = serde_json::to_vec(&json!({"cmd": cmd, "args": args})).unwrap();
+            if let Ok(request) = Request::parse(&payload, Protocol::Json) {
+                let line = request.command_line();
+                let words: Vec<&str> = line.split_whitespace().collect();
+                let expected: Vec<&str> =
+                    std::iter::once(&cmd).chain(&args).map(String::as_str).collect();
+                prop_assert_eq!(words, expected);
+            }
+        }
+    }
+}
@@ -0,0 +1,309 @@
+//! Proxy mode: instead of serving the text protocol, every accepted
+//! connection is piped to an upstream server.
+//!
+//! `tokio::io::copy_bidirectional` does the piping. It copies in both
+//! directions at once, and when one side finishes sending it shuts down
+//! the write half of the other, so a half-close travels through the proxy
+//! just like it would over a direct connection.
+//!
+//! With several upstreams, each connection goes to one picked by
+//! [`Balance`]. A background task connects to every upstream on an
+//! interval; an upstream that fails its check (or a real connection) is
+//! skipped until a later check succeeds again.
+//!
+//! Each upstream also has a [`CircuitBreaker`]: after a run of failed
+//! connects it is skipped for a cooldown, whatever its checks say, and
+//! then tried with a single connection before it gets traffic again.
+
+use crate::circuit::{self, Circuit, CircuitBreaker};
+use crate::dialer::Dialer;
+use crate::metrics::{self, Metrics};
+use crate::readiness::{self, Phase};
+use crate::retry::{self, Policy};
+use crate::task;
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+
+/// How the upstream of a new connection is picked.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Balance {
+    /// Each healthy upstream in turn.
+    #[default]
+    RoundRobin,
+    /// The healthy upstream with the fewest open connections.
+    LeastConnections,
+}
+
+struct Upstream {
+    addr: String,
+    // Cleared by a failed check or connect, set again by a successful check
+    healthy: AtomicBool,
+    // Proxied connections currently open
+    active: AtomicUsize,
+    breaker: CircuitBreaker,
+}
+
+/// The upstreams of proxy mode; clones share the same list.
+#[derive(Clone)]
+pub struct Upstreams {
+    list: Arc<Vec<Upstream>>,
+    // Where the next round-robin search starts
+    next: Arc<AtomicUsize>,
+    // Resolves upstream names and races their addresses
+    dialer: Dialer,
+}
+
+impl Upstreams {
+    /// All upstreams count as healthy until their first check.
+    pub fn new(addrs: &[String], circuit: circuit::Policy, metrics: Metrics) -> Self {
+        let list = addrs
+            .iter()
+            .map(|addr| Upstream {
+                addr: addr.clone(),
+                healthy: AtomicBool::new(true),
+                active: AtomicUsize::new(0),
+                breaker: CircuitBreaker::new(
+                    circuit,
+                    metrics.clone(),
+                    metrics::upstream_circuit(addr),
+                ),
+            })
+            .collect();
+        let dialer = Dialer::new().with_metrics(metrics);
+        Self { list: Arc::new(list), next: Arc::new(AtomicUsize::new(0)), dialer }
+    }
+
+    /// Like `new`, and checks the upstreams every `interval` until the server drains.
+    pub fn start(
+        addrs: &[String],
+        interval: Duration,
+        circuit: circuit::Policy,
+        metrics: Metrics,
+        phase: watch::Receiver<Phase>,
+    ) -> Self {
+        let upstreams = Self::new(addrs, circuit, metrics);
+        task::spawn("upstream health checks", health_checks(upstreams.clone(), interval, phase));
+        upstreams
+    }
+
+    /// Picks a healthy upstream whose circuit lets a connect through, for a
+    /// new connection, or `None` if all are down.
+    ///
+    /// The connection counts as open on the upstream until the lease is dropped.
+    pub fn pick(&self, balance: Balance) -> Option<Lease> {
+        let count = self.list.len();
+        // Both policies search from a rotating start, so ties are spread too
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut healthy = (0..count)
+            .map(|offset| (start + offset) % count)
+            .filter(|&index| {
+                let upstream = &self.list[index];
+                upstream.healthy.load(Ordering::Relaxed) && upstream.breaker.allows()
+            });
+        let index = match balance {
+            Balance::RoundRobin => healthy.next(),
+            Balance::LeastConnections => {
+                healthy.min_by_key(|&index| self.list[index].active.load(Ordering::Relaxed))
+            }
+        }?;
+        self.list[index].active.fetch_add(1, Ordering::Relaxed);
+        Some(Lease { upstreams: self.clone(), index })
+    }
+
+    /// Records the outcome of a check or a connect, logging changes.
+    fn set_healthy(&self, index: usize, result: &io::Result<TcpStream>) {
+        let upstream = &self.list[index];
+        let was_healthy = upstream.healthy.swap(result.is_ok(), Ordering::Relaxed);
+        match result {
+            Err(e) if was_healthy => println!("[PROXY] upstream {} is down: {}", upstream.addr, e),
+            Ok(_) if !was_healthy => println!("[PROXY] upstream {} is back up", upstream.addr),
+            _ => {}
+        }
+    }
+}
+
+/// An upstream picked for one connection.
+pub struct Lease {
+    upstreams: Upstreams,
+    index: usize,
+}
+
+impl Lease {
+    pub fn addr(&self) -> &str {
+        &self.upstreams.list[self.index].addr
+    }
+
+    /// Connects to the upstream, retrying with backoff, if its circuit lets
+    /// the connect through. If every attempt fails, the upstream is taken
+    /// out of rotation, and the failure counts towards opening the circuit.
+    pub async fn connect(&self) -> io::Result<TcpStream> {
+        let upstream = &self.upstreams.list[self.index];
+        // Another connection may have taken the half-open trial since `pick`
+        if !upstream.breaker.acquire() {
+            let circuit = upstream.breaker.circuit();
+            let text = format!("the circuit to {} is {}", upstream.addr, circuit);
+            return Err(io::Error::new(io::ErrorKind::ConnectionRefused, text));
+        }
+        let dialer = &self.upstreams.dialer;
+        let result = retry::retry(&Policy::default(), || dialer.dial(self.addr())).await;
+        match upstream.breaker.record(result.is_ok()) {
+            Some(Circuit::Open) => println!(
+                "[PROXY] circuit to {} is open for {:?} ({:.0}% of connects failed)",
+                upstream.addr,
+                upstream.breaker.cooldown(),
+                upstream.breaker.failure_rate() * 100.0,
+            ),
+            Some(circuit) => println!("[PROXY] circuit to {} is {}", upstream.addr, circuit),
+            None => {}
+        }
+        if result.is_err() {
+            self.upstreams.set_healthy(self.index, &result);
+        }
+        result
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.upstreams.list[self.index].active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Connects to every upstream each `interval`, concurrently.
+async fn health_checks(
+    upstreams: Upstreams,
+    interval: Duration,
+    mut phase: watch::Receiver<Phase>,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = readiness::wait_draining(&mut phase) => return,
+        }
+        let mut checks = JoinSet::new();
+        for index in 0..upstreams.list.len() {
+            let upstreams = upstreams.clone();
+            checks.spawn(async move {
+                // Reaching the port is the whole check; the stream is dropped right away.
+                // Not retried: the next check comes soon enough.
+                let result = upstreams.dialer.dial(&upstreams.list[index].addr).await;
+                upstreams.set_healthy(index, &result);
+            });
+        }
+        while checks.join_next().await.is_some() {}
+    }
+}
+
+/// Bytes copied by one proxied connection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Transferred {
+    /// From the client to the upstream.
+    pub up: u64,
+    /// From the upstream back to the client.
+    pub down: u64,
+}
+
+/// Copies between `client` and `upstream` until both directions are done.
+pub async fn pipe<C, U>(client: &mut C, upstream: &mut U) -> io::Result<Transferred>
+where
+    C: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    U: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let (up, down) = tokio::io::copy_bidirectional(client, upstream).await?;
+    Ok(Transferred { up, down })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::InMemorySink;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn both_directions_are_copied_and_counted() {
+        let (mut client, mut proxy_client) = tokio::io::duplex(64);
+        let (mut proxy_upstream, mut upstream) = tokio::io::duplex(64);
+        let proxy = tokio::spawn(async move { pipe(&mut proxy_client, &mut proxy_upstream).await });
+
+        client.write_all(b"ping").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut request = Vec::new();
+        upstream.read_to_end(&mut request).await.unwrap();
+        assert_eq!(request, b"ping");
+
+        upstream.write_all(b"pong!").await.unwrap();
+        drop(upstream);
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, b"pong!");
+
+        let transferred = proxy.await.unwrap().unwrap();
+        assert_eq!(transferred, Transferred { up: 4, down: 5 });
+    }
+
+    fn upstreams() -> Upstreams {
+        let addrs = ["a:1".to_string(), "b:1".to_string(), "c:1".to_string()];
+        Upstreams::new(&addrs, circuit::Policy::default(), Arc::new(InMemorySink::default()))
+    }
+
+    #[test]
+    fn round_robin_skips_unhealthy_upstreams() {
+        let upstreams = upstreams();
+        upstreams.list[1].healthy.store(false, Ordering::Relaxed);
+
+        let picked: Vec<String> = (0..4)
+            .map(|_| upstreams.pick(Balance::RoundRobin).unwrap().addr().to_string())
+            .collect();
+        assert_eq!(picked, ["a:1", "c:1", "c:1", "a:1"]);
+
+        for upstream in upstreams.list.iter() {
+            upstream.healthy.store(false, Ordering::Relaxed);
+        }
+        assert!(upstreams.pick(Balance::RoundRobin).is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn an_open_circuit_takes_an_upstream_out_of_rotation() {
+        let upstreams = upstreams();
+        let breaker = &upstreams.list[1].breaker;
+        for _ in 0..circuit::Policy::default().failures {
+            assert!(breaker.acquire());
+            breaker.record(false);
+        }
+
+        let picked: Vec<String> = (0..3)
+            .map(|_| upstreams.pick(Balance::RoundRobin).unwrap().addr().to_string())
+            .collect();
+        assert_eq!(picked, ["a:1", "c:1", "c:1"]);
+
+        // Back for its trial connect once the cooldown has passed
+        tokio::time::advance(circuit::Policy::default().cooldown).await;
+        let picked: Vec<String> = (0..3)
+            .map(|_| upstreams.pick(Balance::RoundRobin).unwrap().addr().to_string())
+            .collect();
+        assert_eq!(picked, ["a:1", "b:1", "c:1"]);
+    }
+
+    #[test]
+    fn least_connections_prefers_idle_upstreams() {
+        let upstreams = upstreams();
+        let first = upstreams.pick(Balance::LeastConnections).unwrap();
+        let second = upstreams.pick(Balance::LeastConnections).unwrap();
+        assert_ne!(first.addr(), second.addr());
+
+        // A dropped lease frees its slot
+        let freed = first.addr().to_string();
+        drop(first);
+        let _third = upstreams.pick(Balance::LeastConnections).unwrap();
+        let fourth = upstreams.pick(Balance::LeastConnections).unwrap();
+        assert_eq!(fourth.addr(), freed);
+    }
+}
@@ -0,0 +1,601 @@
+//! Topics: clients publish messages to them and subscribe to their feeds.
+//!
+//! ```text
+//! SUBSCRIBE <filter>...                              -> MESSAGE <topic> <text>, ...
+//! PUBLISH [ttl=<secs>] <topic> <text>                -> OK: delivered to <n> subscriber(s)
+//! PUBLISH-AFTER <secs> [ttl=<secs>] <topic> <text>   -> OK: message #<id> publishes in <secs>s
+//! ```
+//!
+//! Topics are hierarchical, with levels separated by `/`, and a subscription
+//! takes filters the way MQTT does: `+` stands for any one level and `#`,
+//! as the last level, for any number of them, none included. `a/+/c`
+//! matches `a/b/c`, `a/#` matches `a`, `a/b` and `a/b/c`. The filters live
+//! in a trie with one level per node, so a message is matched by walking
+//! its topic's levels once, however many filters there are. A subscriber
+//! whose filters overlap gets a message once, even if several match it.
+//!
+//! Every subscription is fed through a [`Fanout`], like the `TAIL` and
+//! `WATCH` feeds. A subscription ends when its feed is dropped; its filters
+//! leave the trie the next time a message reaches them, or at the next
+//! `SUBSCRIBE`, whichever comes first.
+//!
+//! What is left to time lives in one `DelayQueue`, a hashed timer wheel
+//! owned by the broker task, with two kinds of timers in it:
+//!
+//! - a delayed message, published when its timer fires;
+//! - a held message: one published while no filter matches its topic. With
+//!   a TTL it waits for the first subscriber to one that does, and is
+//!   dropped (`pubsub_messages_expired`) if none comes in time; without one
+//!   it is dropped at once.
+//!
+//! Inserting and firing a timer is O(1) however many are pending, which is
+//! what makes a timer per message affordable. Delayed and held messages are
+//! dropped when the server starts draining.
+//!
+//! None of this is kept anywhere but in memory. Durable topics, whose
+//! messages are written to disk and wait for a consumer's `ACK`, are queues
+//! rather than feeds, in `crate::queue`; `PUBLISH` hands their messages
+//! there instead.
+
+use crate::fanout::{Fanout, Subscription};
+use crate::metrics::{self, Metrics};
+use crate::readiness::{self, Phase};
+use crate::task;
+use bytes::Bytes;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tokio_stream::StreamExt;
+use tokio_util::time::DelayQueue;
+
+/// Upper bound on messages waiting for their `PUBLISH-AFTER` delay.
+pub const MAX_DELAYED: usize = 1000;
+
+/// Held messages a topic keeps for its first subscriber; older ones make room.
+pub const MAX_HELD: usize = 64;
+
+/// The longest delay or TTL a message may have.
+pub const MAX_DELAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The most filters one `SUBSCRIBE` can take.
+pub const MAX_FILTERS: usize = 16;
+
+/// A parsed `PUBLISH` or `PUBLISH-AFTER`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Publish {
+    pub topic: String,
+    pub text: String,
+    /// Set by `PUBLISH-AFTER`.
+    pub delay: Option<Duration>,
+    /// How long the message waits for a subscriber, once published.
+    pub ttl: Option<Duration>,
+}
+
+/// What happened to a published message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// Waiting for its `PUBLISH-AFTER` delay.
+    Delayed { id: u64, delay: Duration },
+    /// Handed to this many subscribers.
+    Delivered(usize),
+    /// Nobody is subscribed; kept for the first subscriber, for the TTL.
+    Held(Duration),
+    /// Nobody is subscribed and the message has no TTL.
+    Dropped,
+}
+
+/// One level of the filters: the subscriptions whose filters end here,
+/// and the next levels, `+` and `#` among them.
+#[derive(Default)]
+struct Node {
+    children: HashMap<String, Node>,
+    subscribers: Vec<u64>,
+}
+
+impl Node {
+    fn insert(&mut self, levels: &[&str], id: u64) {
+        match levels.split_first() {
+            Some((level, rest)) => {
+                self.children.entry(level.to_string()).or_default().insert(rest, id)
+            }
+            None => self.subscribers.push(id),
+        }
+    }
+
+    /// Removes `id` from the filter `levels`, and the nodes left empty.
+    fn remove(&mut self, levels: &[&str], id: u64) {
+        let Some((level, rest)) = levels.split_first() else {
+            self.subscribers.retain(|subscriber| *subscriber != id);
+            return;
+        };
+        if let Some(child) = self.children.get_mut(*level) {
+            child.remove(rest, id);
+            if child.is_empty() {
+                self.children.remove(*level);
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.children.is_empty() && self.subscribers.is_empty()
+    }
+
+    /// Adds the subscriptions with a filter matching the topic `levels` to `found`.
+    fn matches(&self, levels: &[&str], found: &mut Vec<u64>) {
+        // `#` also matches the level above it: `a/#` matches `a`
+        if let Some(all) = self.children.get("#") {
+            found.extend(&all.subscribers);
+        }
+        let Some((level, rest)) = levels.split_first() else {
+            found.extend(&self.subscribers);
+            return;
+        };
+        if let Some(any) = self.children.get("+") {
+            any.matches(rest, found);
+        }
+        if let Some(exact) = self.children.get(*level) {
+            exact.matches(rest, found);
+        }
+    }
+}
+
+struct Subscriber {
+    filters: Vec<String>,
+    feed: Fanout,
+}
+
+#[derive(Default)]
+struct Topics {
+    filters: Node,
+    subscribers: HashMap<u64, Subscriber>,
+    // Messages published while nobody was subscribed, by topic: (id, line)
+    held: HashMap<String, VecDeque<(u64, Bytes)>>,
+}
+
+impl Topics {
+    fn unsubscribe(&mut self, id: u64) {
+        let Some(subscriber) = self.subscribers.remove(&id) else { return };
+        for filter in &subscriber.filters {
+            self.filters.remove(&levels(filter), id);
+        }
+    }
+}
+
+enum Timer {
+    /// Publish once the delay has passed.
+    Publish(u64, Publish),
+    /// Drop the held message unless a subscriber took it.
+    Expire { topic: String, id: u64 },
+}
+
+/// Handle to the topics and the broker task; cheap to clone.
+#[derive(Clone)]
+pub struct Broker {
+    topics: Arc<Mutex<Topics>>,
+    // Timers for the broker task to put in its queue, with their delay
+    timers: mpsc::UnboundedSender<(Timer, Duration)>,
+    next_id: Arc<AtomicU64>,
+    delayed: Arc<AtomicUsize>,
+    metrics: Metrics,
+}
+
+impl Broker {
+    /// Spawns the broker task; it runs until the server starts draining.
+    pub fn start(metrics: Metrics, phase: watch::Receiver<Phase>) -> Self {
+        let (timers, timers_rx) = mpsc::unbounded_channel();
+        let broker = Self {
+            topics: Arc::default(),
+            timers,
+            next_id: Arc::new(AtomicU64::new(1)),
+            delayed: Arc::new(AtomicUsize::new(0)),
+            metrics,
+        };
+        task::spawn("pubsub broker", run(timers_rx, broker.clone(), phase));
+        broker
+    }
+
+    /// Messages published from now on to a topic any of `filters` match, as
+    /// `MESSAGE <topic> <text>` lines, after the ones held for such topics.
+    pub fn subscribe(&self, filters: &[&str]) -> Result<Subscription, String> {
+        if filters.is_empty() || filters.len() > MAX_FILTERS {
+            return Err(format!("usage: SUBSCRIBE <filter>..., at most {} of them", MAX_FILTERS));
+        }
+        filters.iter().try_for_each(|filter| check_filter(filter))?;
+        let mut filters: Vec<String> = filters.iter().map(|f| f.to_string()).collect();
+        filters.sort();
+        filters.dedup();
+
+        let mut topics = self.topics.lock().unwrap();
+        // Forget the subscriptions that ended since
+        let ended: Vec<u64> = topics
+            .subscribers
+            .iter()
+            .filter(|(_, subscriber)| !subscriber.feed.has_subscribers())
+            .map(|(id, _)| *id)
+            .collect();
+        ended.into_iter().for_each(|id| topics.unsubscribe(id));
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let feed = Fanout::new(
+            self.metrics.clone(),
+            metrics::PUBSUB_SUBSCRIBERS,
+            metrics::PUBSUB_LINES_MISSED,
+        );
+        let subscription = feed.subscribe();
+        for filter in &filters {
+            topics.filters.insert(&levels(filter), id);
+        }
+
+        // The held messages of every matching topic, oldest first
+        let matching: Vec<String> = topics
+            .held
+            .keys()
+            .filter(|topic| filters.iter().any(|filter| matches(filter, topic)))
+            .cloned()
+            .collect();
+        let mut held: Vec<(u64, Bytes)> =
+            matching.iter().flat_map(|topic| topics.held.remove(topic).unwrap()).collect();
+        held.sort_by_key(|(id, _)| *id);
+        self.metrics.adjust(metrics::PUBSUB_MESSAGES_HELD, -(held.len() as i64));
+        for (_, line) in held {
+            feed.publish(line);
+        }
+        topics.subscribers.insert(id, Subscriber { filters, feed });
+        Ok(subscription)
+    }
+
+    /// Publishes now, or enqueues a `PUBLISH-AFTER`.
+    pub fn publish(&self, message: Publish) -> Result<Delivery, String> {
+        check_topic(&message.topic)?;
+        for limit in [message.delay, message.ttl].into_iter().flatten() {
+            if limit > MAX_DELAY {
+                return Err(format!("delays and TTLs are at most {}s", MAX_DELAY.as_secs()));
+            }
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+
+        let Some(delay) = message.delay else {
+            return Ok(self.deliver(id, message));
+        };
+        // Reserve a slot first, so concurrent callers cannot overshoot the limit
+        if self.delayed.fetch_add(1, Ordering::Relaxed) >= MAX_DELAYED {
+            self.delayed.fetch_sub(1, Ordering::Relaxed);
+            return Err("too many delayed messages".to_string());
+        }
+        if self.timers.send((Timer::Publish(id, message), delay)).is_err() {
+            self.delayed.fetch_sub(1, Ordering::Relaxed);
+            return Err("the broker is not accepting messages".to_string());
+        }
+        self.metrics.adjust(metrics::PUBSUB_MESSAGES_DELAYED, 1);
+        Ok(Delivery::Delayed { id, delay })
+    }
+
+    fn deliver(&self, id: u64, message: Publish) -> Delivery {
+        self.metrics.incr(metrics::PUBSUB_MESSAGES_PUBLISHED, 1);
+        let line = Bytes::from(format!("MESSAGE {} {}", message.topic, message.text));
+        let mut topics = self.topics.lock().unwrap();
+
+        let mut matched = Vec::new();
+        topics.filters.matches(&levels(&message.topic), &mut matched);
+        // Once per subscriber, however many of its filters match
+        matched.sort();
+        matched.dedup();
+        let (mut delivered, mut live) = (0, false);
+        for subscriber in matched {
+            let feed = &topics.subscribers[&subscriber].feed;
+            delivered += feed.publish(line.clone());
+            // Publishing removed the subscriber if it had gone away
+            if feed.has_subscribers() {
+                live = true;
+            } else {
+                topics.unsubscribe(subscriber);
+            }
+        }
+        if live {
+            return Delivery::Delivered(delivered);
+        }
+        let Some(ttl) = message.ttl else {
+            return Delivery::Dropped;
+        };
+
+        let held = topics.held.entry(message.topic.clone()).or_default();
+        if held.len() == MAX_HELD {
+            held.pop_front();
+            self.metrics.adjust(metrics::PUBSUB_MESSAGES_HELD, -1);
+            self.metrics.incr(metrics::PUBSUB_MESSAGES_EXPIRED, 1);
+        }
+        held.push_back((id, line));
+        self.metrics.adjust(metrics::PUBSUB_MESSAGES_HELD, 1);
+        // Only fails when the broker task is gone; draining drops held messages anyway
+        let _ = self.timers.send((Timer::Expire { topic: message.topic, id }, ttl));
+        Delivery::Held(ttl)
+    }
+
+    /// Drops the held message `id`, unless a subscriber has taken it already.
+    fn expire(&self, topic: &str, id: u64) {
+        let mut topics = self.topics.lock().unwrap();
+        let Some(held) = topics.held.get_mut(topic) else { return };
+        let Some(index) = held.iter().position(|(held_id, _)| *held_id == id) else { return };
+        held.remove(index);
+        if held.is_empty() {
+            topics.held.remove(topic);
+        }
+        self.metrics.adjust(metrics::PUBSUB_MESSAGES_HELD, -1);
+        self.metrics.incr(metrics::PUBSUB_MESSAGES_EXPIRED, 1);
+    }
+}
+
+fn levels(name: &str) -> Vec<&str> {
+    name.split('/').collect()
+}
+
+/// Whether `filter` matches `topic`, level by level; for the held messages.
+fn matches(filter: &str, topic: &str) -> bool {
+    let mut topic = topic.split('/');
+    for level in filter.split('/') {
+        match (level, topic.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (level, Some(name)) if level == name => {}
+            _ => return false,
+        }
+    }
+    topic.next().is_none()
+}
+
+fn check_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > 128 || name.contains(char::is_whitespace) {
+        return Err("a topic is 1 to 128 characters, without spaces".to_string());
+    }
+    Ok(())
+}
+
+pub(crate) fn check_topic(topic: &str) -> Result<(), String> {
+    check_name(topic)?;
+    if topic.contains(['+', '#']) {
+        return Err("messages are published to a topic, without wildcards".to_string());
+    }
+    Ok(())
+}
+
+fn check_filter(filter: &str) -> Result<(), String> {
+    check_name(filter)?;
+    let levels = levels(filter);
+    for (index, level) in levels.iter().enumerate() {
+        let wildcard = *level == "+" || (*level == "#" && index == levels.len() - 1);
+        if !wildcard && level.contains(['+', '#']) {
+            return Err(format!("`+` and `#` must be a whole level, and `#` the last: {}", filter));
+        }
+    }
+    Ok(())
+}
+
+/// Parses `PUBLISH` and `PUBLISH-AFTER`.
+///
+/// Returns `None` when the line is neither, like `KvCommand::parse`.
+pub fn parse_publish(line: &str) -> Option<Result<Publish, String>> {
+    let (name, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let (delayed, usage) = if name.eq_ignore_ascii_case("PUBLISH") {
+        (false, "usage: PUBLISH [ttl=<secs>] <topic> <message>")
+    } else if name.eq_ignore_ascii_case("PUBLISH-AFTER") {
+        (true, "usage: PUBLISH-AFTER <secs> [ttl=<secs>] <topic> <message>")
+    } else {
+        return None;
+    };
+
+    let parse = |mut rest: &str| -> Option<Publish> {
+        let delay = if delayed { Some(seconds(next_word(&mut rest)?)?) } else { None };
+        let mut word = next_word(&mut rest)?;
+        let mut ttl = None;
+        if let Some(secs) = word.strip_prefix("ttl=") {
+            ttl = Some(seconds(secs)?);
+            word = next_word(&mut rest)?;
+        }
+        let (topic, text) = (word.to_string(), rest.trim().to_string());
+        Some(Publish { topic, text, delay, ttl }).filter(|p| !p.text.is_empty())
+    };
+    Some(parse(rest).ok_or_else(|| usage.to_string()))
+}
+
+/// Splits the first word off `rest`; `None` if nothing follows it.
+fn next_word<'a>(rest: &mut &'a str) -> Option<&'a str> {
+    let (word, after) = rest.trim_start().split_once(char::is_whitespace)?;
+    *rest = after;
+    Some(word)
+}
+
+fn seconds(text: &str) -> Option<Duration> {
+    text.parse::<f64>()
+        .ok()
+        .filter(|s| s.is_finite() && *s >= 0.0)
+        .map(Duration::from_secs_f64)
+}
+
+async fn run(
+    mut timers: mpsc::UnboundedReceiver<(Timer, Duration)>,
+    broker: Broker,
+    mut phase: watch::Receiver<Phase>,
+) {
+    let mut queue = DelayQueue::new();
+
+    loop {
+        tokio::select! {
+            Some((timer, delay)) = timers.recv() => {
+                queue.insert(timer, delay);
+            }
+            // An empty `DelayQueue` yields `None` right away instead of waiting
+            Some(expired) = queue.next(), if !queue.is_empty() => match expired.into_inner() {
+                Timer::Publish(id, message) => {
+                    broker.delayed.fetch_sub(1, Ordering::Relaxed);
+                    broker.metrics.adjust(metrics::PUBSUB_MESSAGES_DELAYED, -1);
+                    broker.deliver(id, message);
+                }
+                Timer::Expire { topic, id } => broker.expire(&topic, id),
+            },
+            _ = readiness::wait_draining(&mut phase) => break,
+        }
+    }
+
+    // Ends every feed, and drops what was still waiting
+    let delayed = broker.delayed.swap(0, Ordering::Relaxed);
+    broker.metrics.adjust(metrics::PUBSUB_MESSAGES_DELAYED, -(delayed as i64));
+    let topics = std::mem::take(&mut *broker.topics.lock().unwrap());
+    for subscriber in topics.subscribers.values() {
+        subscriber.feed.close();
+    }
+    let held: usize = topics.held.values().map(VecDeque::len).sum();
+    broker.metrics.adjust(metrics::PUBSUB_MESSAGES_HELD, -(held as i64));
+}
+
+/// Time is paused, so delays and TTLs pass without real waiting.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::InMemorySink;
+    use futures_util::FutureExt;
+    use tokio::time::Instant;
+
+    fn broker() -> (Broker, Metrics, watch::Sender<Phase>) {
+        let metrics: Metrics = Arc::new(InMemorySink::default());
+        let (phase_tx, phase) = watch::channel(Phase::Ready);
+        (Broker::start(metrics.clone(), phase), metrics, phase_tx)
+    }
+
+    fn value(metrics: &Metrics, name: &str) -> i64 {
+        metrics.snapshot().iter().find(|m| m.name == name).map_or(0, |m| m.value)
+    }
+
+    fn publish(text: &str) -> Publish {
+        parse_publish(text).unwrap().unwrap()
+    }
+
+    #[test]
+    fn publish_commands_are_parsed() {
+        let plain = publish("PUBLISH news hi there");
+        assert_eq!((plain.topic.as_str(), plain.text.as_str()), ("news", "hi there"));
+        assert_eq!((plain.delay, plain.ttl), (None, None));
+
+        let delayed = publish("publish-after 1.5 ttl=30 news hi there");
+        assert_eq!(delayed.delay, Some(Duration::from_millis(1500)));
+        assert_eq!(delayed.ttl, Some(Duration::from_secs(30)));
+        assert_eq!(delayed.text, "hi there");
+
+        assert!(parse_publish("PUBLISH news").unwrap().is_err());
+        assert!(parse_publish("PUBLISH-AFTER soon news hi").unwrap().is_err());
+        assert!(parse_publish("PUBLISH ttl=-1 news hi").unwrap().is_err());
+        assert!(parse_publish("PUBLISHED news hi").is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_delayed_message_is_published_once_its_delay_has_passed() {
+        let (broker, metrics, _phase) = broker();
+        let mut feed = broker.subscribe(&["news"]).unwrap();
+        let start = Instant::now();
+        broker.publish(publish("PUBLISH-AFTER 5 news later")).unwrap();
+        broker.publish(publish("PUBLISH-AFTER 2 news sooner")).unwrap();
+        assert_eq!(value(&metrics, metrics::PUBSUB_MESSAGES_DELAYED), 2);
+
+        for (text, at) in [("MESSAGE news sooner", 2), ("MESSAGE news later", 5)] {
+            assert_eq!(feed.recv().await.unwrap(), text);
+            assert_eq!(start.elapsed(), Duration::from_secs(at));
+        }
+        assert_eq!(value(&metrics, metrics::PUBSUB_MESSAGES_DELAYED), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_held_message_waits_for_a_subscriber_until_its_ttl_runs_out() {
+        let (broker, metrics, _phase) = broker();
+        let kept = broker.publish(publish("PUBLISH ttl=10 news kept")).unwrap();
+        assert_eq!(kept, Delivery::Held(Duration::from_secs(10)));
+        broker.publish(publish("PUBLISH ttl=1 news expired")).unwrap();
+        let dropped = broker.publish(publish("PUBLISH news dropped")).unwrap();
+        assert_eq!(dropped, Delivery::Dropped);
+
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        assert_eq!(value(&metrics, metrics::PUBSUB_MESSAGES_EXPIRED), 1);
+        let mut feed = broker.subscribe(&["news"]).unwrap();
+        assert_eq!(feed.recv().await.unwrap(), "MESSAGE news kept");
+        assert!(feed.recv().now_or_never().is_none());
+        assert_eq!(value(&metrics, metrics::PUBSUB_MESSAGES_HELD), 0);
+
+        // Its timer still fires, but the message is no longer held
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert_eq!(value(&metrics, metrics::PUBSUB_MESSAGES_EXPIRED), 1);
+        let live = broker.publish(publish("PUBLISH news live")).unwrap();
+        assert_eq!(live, Delivery::Delivered(1));
+    }
+
+    #[test]
+    fn wildcards_match_one_level_or_all_the_rest() {
+        for (filter, topic) in [("a/+/c", "a/b/c"), ("a/#", "a"), ("a/#", "a/b/c"), ("#", "a/b")] {
+            assert!(matches(filter, topic), "{} should match {}", filter, topic);
+        }
+        for (filter, topic) in [("a/+/c", "a/b/c/d"), ("a/+", "a"), ("a/b", "a/b/c")] {
+            assert!(!matches(filter, topic), "{} should not match {}", filter, topic);
+        }
+        assert!(check_filter("a/+/#").is_ok());
+        assert!(check_filter("a/#/c").is_err());
+        assert!(check_filter("a/b+").is_err());
+        assert!(check_topic("a/+").is_err());
+    }
+
+    #[tokio::test]
+    async fn overlapping_filters_deliver_a_message_once_per_subscriber() {
+        let (broker, _metrics, _phase) = broker();
+        let mut both = broker.subscribe(&["sensors/#", "sensors/+/temp"]).unwrap();
+        let mut one = broker.subscribe(&["sensors/+/temp"]).unwrap();
+        let mut other = broker.subscribe(&["sensors/kitchen/humidity"]).unwrap();
+
+        let delivered = broker.publish(publish("PUBLISH sensors/kitchen/temp 21")).unwrap();
+        assert_eq!(delivered, Delivery::Delivered(2));
+        assert_eq!(both.recv().await.unwrap(), "MESSAGE sensors/kitchen/temp 21");
+        assert_eq!(one.recv().await.unwrap(), "MESSAGE sensors/kitchen/temp 21");
+        assert!(both.recv().now_or_never().is_none());
+        assert!(other.recv().now_or_never().is_none());
+
+        broker.publish(publish("PUBLISH sensors 1")).unwrap();
+        assert_eq!(both.recv().await.unwrap(), "MESSAGE sensors 1");
+        assert!(one.recv().now_or_never().is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_dropped_subscription_leaves_the_trie() {
+        let (broker, metrics, _phase) = broker();
+        let feed = broker.subscribe(&["a/+/c", "a/#"]).unwrap();
+        let mut kept = broker.subscribe(&["x/y"]).unwrap();
+        drop(feed);
+        assert_eq!(value(&metrics, metrics::PUBSUB_SUBSCRIBERS), 1);
+
+        // Nobody matches any more: held for the next subscriber to `a/#`
+        let held = broker.publish(publish("PUBLISH ttl=10 a/b/c gone")).unwrap();
+        assert_eq!(held, Delivery::Held(Duration::from_secs(10)));
+        assert!(!broker.topics.lock().unwrap().filters.children.contains_key("a"));
+
+        let mut feed = broker.subscribe(&["a/#"]).unwrap();
+        assert_eq!(feed.recv().await.unwrap(), "MESSAGE a/b/c gone");
+        drop(feed);
+        broker.subscribe(&["x/+"]).unwrap();
+        let topics = broker.topics.lock().unwrap();
+        assert_eq!(topics.subscribers.len(), 2);
+        assert!(!topics.filters.children.contains_key("a"));
+        drop(topics);
+        assert!(kept.recv().now_or_never().is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn draining_ends_the_feeds_and_drops_what_is_waiting() {
+        let (broker, metrics, phase) = broker();
+        let mut feed = broker.subscribe(&["news"]).unwrap();
+        broker.publish(publish("PUBLISH-AFTER 60 news never")).unwrap();
+        broker.publish(publish("PUBLISH ttl=60 other never")).unwrap();
+        tokio::task::yield_now().await;
+
+        phase.send(Phase::Draining).unwrap();
+        assert!(feed.recv().await.is_none());
+        assert_eq!(value(&metrics, metrics::PUBSUB_MESSAGES_DELAYED), 0);
+        assert_eq!(value(&metrics, metrics::PUBSUB_MESSAGES_HELD), 0);
+        assert!(broker.publish(publish("PUBLISH-AFTER 1 news late")).is_err());
+    }
+}
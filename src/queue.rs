@@ -0,0 +1,455 @@
+//! Durable topics: queues kept on disk, with acknowledgements.
+//!
+//! A topic named with `--durable-topic` (which needs `--queue-dir`) is a
+//! queue rather than a feed:
+//!
+//! ```text
+//! PUBLISH <topic> <text>   -> OK: queued as #<id>
+//! CONSUME <topic>          -> MESSAGE <topic> <id> <text>, ...
+//! ACK <id>                 -> OK: acknowledged #<id>, while consuming
+//! ```
+//!
+//! A published message is numbered, one after the other for each topic,
+//! and appended to `<queue dir>/<topic>.queue`; the publisher is answered
+//! once it is on disk. It stays there until a consumer acknowledges it.
+//! Each message goes to one consumer of its topic at a time, in turns, and
+//! a consumer is handed at most `PREFETCH` it has not acknowledged yet. The
+//! messages a consumer still holds when it stops, or its connection drops,
+//! go to the other consumers, or to the next one to come: delivery is at
+//! least once, so a consumer may see a message again, by its id.
+//!
+//! The file holds one record per line, tab-separated:
+//!
+//! ```text
+//! PUB    7    build 41 is ready
+//! ACK    7
+//! NEXT   8
+//! ```
+//!
+//! On startup the records are replayed: what was published and not
+//! acknowledged is waiting again. Acknowledgements are not waited for: one
+//! lost in a crash only means its message is delivered again. Once most of
+//! a file is acknowledged messages, it is rewritten with only the waiting
+//! ones, after a `NEXT` record that keeps the numbers going up.
+//!
+//! As with `crate::wal`, the records go over a channel to a writer task,
+//! which appends them and calls `fsync` once per batch.
+
+use crate::metrics::{self, Metrics};
+use crate::task;
+use bytes::Bytes;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::{mpsc, watch};
+
+/// Messages a consumer may hold without acknowledging them, before it is
+/// handed more.
+pub const PREFETCH: usize = 16;
+
+/// Records a file may have before it is worth rewriting.
+const COMPACT_AFTER: usize = 1024;
+
+/// The most records the writer takes from its channel per batch.
+const BATCH: usize = 256;
+
+/// A message waiting for its acknowledgement.
+struct Pending {
+    /// The line consumers get.
+    line: Bytes,
+    /// The consumer it was handed to, if any.
+    holder: Option<u64>,
+}
+
+struct Slot {
+    id: u64,
+    tx: mpsc::UnboundedSender<Bytes>,
+    holding: usize,
+}
+
+#[derive(Default)]
+struct Queue {
+    next_id: u64,
+    messages: BTreeMap<u64, Pending>,
+    consumers: Vec<Slot>,
+    // The consumer whose turn is next
+    turn: usize,
+    // Records in the file, to know when to rewrite it
+    records: usize,
+}
+
+impl Queue {
+    /// Hands the messages nobody holds to the consumers with room, in turns.
+    fn dispatch(&mut self) {
+        let Queue { messages, consumers, turn, .. } = self;
+        let waiting = messages.values_mut().filter(|message| message.holder.is_none());
+        for message in waiting {
+            let count = consumers.len();
+            let Some(index) = (0..count)
+                .map(|offset| (*turn + offset) % count)
+                .find(|index| consumers[*index].holding < PREFETCH)
+            else {
+                break;
+            };
+            *turn = index + 1;
+            let consumer = &mut consumers[index];
+            consumer.holding += 1;
+            message.holder = Some(consumer.id);
+            let _ = consumer.tx.send(message.line.clone());
+        }
+    }
+
+    /// The file's contents with only the waiting messages.
+    fn compacted(&self) -> String {
+        let mut text = format!("NEXT\t{}\n", self.next_id);
+        for (id, message) in &self.messages {
+            text.push_str(&format!("PUB\t{}\t{}\n", id, text_of(&message.line)));
+        }
+        text
+    }
+}
+
+/// The text of a `MESSAGE <topic> <id> <text>` line.
+fn text_of(line: &[u8]) -> String {
+    let line = String::from_utf8_lossy(line);
+    line.splitn(4, ' ').nth(3).unwrap_or_default().to_string()
+}
+
+enum Write {
+    /// Records to append to a topic's file.
+    Append { topic: String, records: String },
+    /// The whole new contents of a topic's file.
+    Rewrite { topic: String, text: String },
+}
+
+struct Topics {
+    queues: HashMap<String, Queue>,
+    // Numbers the writes, so publishers can wait for theirs to be on disk
+    ticket: u64,
+}
+
+/// Handle to the durable topics and their writer; cheap to clone.
+#[derive(Clone)]
+pub struct Queues {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    topics: Mutex<Topics>,
+    writes: mpsc::UnboundedSender<(u64, Write)>,
+    // The last write known to be on disk
+    synced: watch::Receiver<u64>,
+    next_consumer: AtomicU64,
+    metrics: Metrics,
+}
+
+impl Queues {
+    /// Loads the queues of `topics` from `dir`, creating it if need be, and
+    /// spawns the task writing to them.
+    pub async fn open(dir: &Path, topics: &[String], metrics: Metrics) -> io::Result<Self> {
+        tokio::fs::create_dir_all(dir).await?;
+        let mut queues = HashMap::new();
+        for topic in topics {
+            let queue = load(&file(dir, topic), topic).await?;
+            metrics.adjust(metrics::QUEUE_MESSAGES_PENDING, queue.messages.len() as i64);
+            queues.insert(topic.clone(), queue);
+        }
+        let (writes, rx) = mpsc::unbounded_channel();
+        let (synced_tx, synced) = watch::channel(0);
+        let dir = dir.to_path_buf();
+        task::spawn("queue writer", async move {
+            if let Err(e) = write(&dir, rx, &synced_tx).await {
+                let dir = dir.display();
+                eprintln!("[QUEUE] cannot write to {}, messages are no longer queued: {}", dir, e);
+            }
+        });
+        let inner = Inner {
+            topics: Mutex::new(Topics { queues, ticket: 0 }),
+            writes,
+            synced,
+            next_consumer: AtomicU64::new(1),
+            metrics,
+        };
+        Ok(Self { inner: Arc::new(inner) })
+    }
+
+    /// Whether `topic` is one of the durable topics.
+    pub fn is_durable(&self, topic: &str) -> bool {
+        self.inner.topics.lock().unwrap().queues.contains_key(topic)
+    }
+
+    /// Queues a message; returns its id once it is on disk.
+    pub async fn publish(&self, topic: &str, text: &str) -> Result<u64, String> {
+        let (id, ticket) = {
+            let mut topics = self.inner.topics.lock().unwrap();
+            let Topics { queues, ticket } = &mut *topics;
+            let queue = queues.get_mut(topic).ok_or_else(|| not_durable(topic))?;
+            let id = queue.next_id;
+            queue.next_id += 1;
+            // Sent under the lock, so the records reach the file in order
+            let records = format!("PUB\t{}\t{}\n", id, text);
+            *ticket += 1;
+            let write = Write::Append { topic: topic.to_string(), records };
+            let _ = self.inner.writes.send((*ticket, write));
+            queue.records += 1;
+            let line = Bytes::from(format!("MESSAGE {} {} {}", topic, id, text));
+            queue.messages.insert(id, Pending { line, holder: None });
+            self.inner.metrics.adjust(metrics::QUEUE_MESSAGES_PENDING, 1);
+            queue.dispatch();
+            (id, *ticket)
+        };
+        let mut synced = self.inner.synced.clone();
+        match synced.wait_for(|synced| *synced >= ticket).await {
+            Ok(_) => Ok(id),
+            Err(_) => Err("the queue could not be written, the message may be lost".to_string()),
+        }
+    }
+
+    /// Becomes a consumer of `topic`: gets the messages waiting in it, and
+    /// then the new ones, until the consumer is dropped.
+    pub fn consume(&self, topic: &str) -> Result<Consumer, String> {
+        let mut topics = self.inner.topics.lock().unwrap();
+        let queue = topics.queues.get_mut(topic).ok_or_else(|| not_durable(topic))?;
+        let id = self.inner.next_consumer.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = mpsc::unbounded_channel();
+        queue.consumers.push(Slot { id, tx, holding: 0 });
+        queue.dispatch();
+        self.inner.metrics.adjust(metrics::QUEUE_CONSUMERS, 1);
+        Ok(Consumer { queues: self.clone(), topic: topic.to_string(), id, rx })
+    }
+}
+
+fn not_durable(topic: &str) -> String {
+    format!("{} is not a durable topic", topic)
+}
+
+/// One consumer of a durable topic.
+pub struct Consumer {
+    queues: Queues,
+    topic: String,
+    id: u64,
+    rx: mpsc::UnboundedReceiver<Bytes>,
+}
+
+impl Consumer {
+    /// The next message handed to this consumer.
+    pub async fn recv(&mut self) -> Option<Bytes> {
+        self.rx.recv().await
+    }
+
+    /// Takes message `id` out of the queue; only the consumer holding it can.
+    pub fn ack(&self, id: u64) -> Result<(), String> {
+        let inner = &self.queues.inner;
+        let mut topics = inner.topics.lock().unwrap();
+        let Topics { queues, ticket } = &mut *topics;
+        let queue = queues.get_mut(&self.topic).expect("consumers only exist for durable topics");
+        if queue.messages.get(&id).is_none_or(|message| message.holder != Some(self.id)) {
+            return Err(format!("message #{} is not waiting for an ACK from you", id));
+        }
+        queue.messages.remove(&id);
+        inner.metrics.adjust(metrics::QUEUE_MESSAGES_PENDING, -1);
+        if let Some(slot) = queue.consumers.iter_mut().find(|slot| slot.id == self.id) {
+            slot.holding -= 1;
+        }
+
+        *ticket += 1;
+        let topic = self.topic.clone();
+        queue.records += 1;
+        let write = if queue.records > COMPACT_AFTER && queue.records > 2 * queue.messages.len() {
+            queue.records = queue.messages.len() + 1;
+            Write::Rewrite { topic, text: queue.compacted() }
+        } else {
+            Write::Append { topic, records: format!("ACK\t{}\n", id) }
+        };
+        let _ = inner.writes.send((*ticket, write));
+        queue.dispatch();
+        Ok(())
+    }
+}
+
+impl Drop for Consumer {
+    fn drop(&mut self) {
+        let inner = &self.queues.inner;
+        let mut topics = inner.topics.lock().unwrap();
+        let Some(queue) = topics.queues.get_mut(&self.topic) else { return };
+        queue.consumers.retain(|slot| slot.id != self.id);
+        // What it held goes to the others, or waits for the next consumer
+        for message in queue.messages.values_mut() {
+            if message.holder == Some(self.id) {
+                message.holder = None;
+                inner.metrics.incr(metrics::QUEUE_MESSAGES_REDELIVERED, 1);
+            }
+        }
+        inner.metrics.adjust(metrics::QUEUE_CONSUMERS, -1);
+        queue.dispatch();
+    }
+}
+
+/// The file of `topic`'s queue: a `/` in its name becomes `%2F`.
+fn file(dir: &Path, topic: &str) -> PathBuf {
+    dir.join(format!("{}.queue", topic.replace('%', "%25").replace('/', "%2F")))
+}
+
+/// Replays the records in `path`; a missing file is an empty queue. A last
+/// line cut off by a crash was never acknowledged to anyone, so it is
+/// ignored, like the write-ahead log's.
+async fn load(path: &Path, topic: &str) -> io::Result<Queue> {
+    let mut queue = Queue { next_id: 1, ..Queue::default() };
+    let bytes = match tokio::fs::read(path).await {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(queue),
+        Err(e) => return Err(e),
+    };
+    let complete = bytes.iter().rposition(|b| *b == b'\n').map_or(0, |end| end + 1);
+    let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+    let text = std::str::from_utf8(&bytes[..complete]).map_err(|e| invalid(e.to_string()))?;
+
+    for (index, line) in text.lines().enumerate() {
+        let mut fields = line.splitn(3, '\t');
+        let (kind, id) = (fields.next(), fields.next().and_then(|id| id.parse::<u64>().ok()));
+        match (kind, id, fields.next()) {
+            (Some("PUB"), Some(id), Some(text)) => {
+                let line = Bytes::from(format!("MESSAGE {} {} {}", topic, id, text));
+                queue.messages.insert(id, Pending { line, holder: None });
+                queue.next_id = queue.next_id.max(id + 1);
+            }
+            (Some("ACK"), Some(id), None) => {
+                queue.messages.remove(&id);
+            }
+            (Some("NEXT"), Some(id), None) => queue.next_id = queue.next_id.max(id),
+            _ => return Err(invalid(format!("line {}: not a queue record", index + 1))),
+        }
+        queue.records += 1;
+    }
+    Ok(queue)
+}
+
+/// Writes records until every `Queues` is dropped or a write fails.
+async fn write(
+    dir: &Path,
+    mut rx: mpsc::UnboundedReceiver<(u64, Write)>,
+    synced: &watch::Sender<u64>,
+) -> io::Result<()> {
+    let mut files: HashMap<String, BufWriter<File>> = HashMap::new();
+    let mut batch = Vec::with_capacity(BATCH);
+    while rx.recv_many(&mut batch, BATCH).await > 0 {
+        let mut written = HashSet::new();
+        let mut last = *synced.borrow();
+        for (ticket, write) in batch.drain(..) {
+            last = ticket;
+            match write {
+                Write::Append { topic, records } => {
+                    if !files.contains_key(&topic) {
+                        let path = file(dir, &topic);
+                        let opened = OpenOptions::new().create(true).append(true).open(path).await?;
+                        files.insert(topic.clone(), BufWriter::new(opened));
+                    }
+                    files.get_mut(&topic).unwrap().write_all(records.as_bytes()).await?;
+                    written.insert(topic);
+                }
+                // Like a snapshot: temp file, `sync_all`, rename; appends reopen it
+                Write::Rewrite { topic, text } => {
+                    if let Some(mut old) = files.remove(&topic) {
+                        old.flush().await?;
+                    }
+                    written.remove(&topic);
+                    let path = file(dir, &topic);
+                    let mut tmp = path.as_os_str().to_owned();
+                    tmp.push(".tmp");
+                    let mut new = File::create(&tmp).await?;
+                    new.write_all(text.as_bytes()).await?;
+                    new.sync_all().await?;
+                    tokio::fs::rename(&tmp, &path).await?;
+                }
+            }
+        }
+        for topic in written {
+            let file = files.get_mut(&topic).unwrap();
+            file.flush().await?;
+            file.get_ref().sync_data().await?;
+        }
+        synced.send_replace(last);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::InMemorySink;
+    use futures_util::FutureExt;
+
+    fn dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("queue-test-{}-{}", name, std::process::id()))
+    }
+
+    async fn open(dir: &Path) -> Queues {
+        let topics = ["jobs/build".to_string()];
+        Queues::open(dir, &topics, Arc::new(InMemorySink::default())).await.unwrap()
+    }
+
+    fn id_of(line: &Bytes) -> u64 {
+        let line = String::from_utf8_lossy(line);
+        line.split(' ').nth(2).unwrap().parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn messages_take_turns_and_come_back_until_they_are_acknowledged() {
+        let dir = dir("turns");
+        let queues = open(&dir).await;
+        assert_eq!(queues.publish("jobs/build", "one").await, Ok(1));
+        assert_eq!(queues.publish("jobs/build", "two").await, Ok(2));
+        assert!(queues.publish("jobs/test", "three").await.is_err());
+
+        let mut first = queues.consume("jobs/build").unwrap();
+        let mut second = queues.consume("jobs/build").unwrap();
+        // Both went to the first consumer, which was alone; the next goes to the other
+        assert_eq!(first.recv().await.unwrap(), "MESSAGE jobs/build 1 one");
+        assert_eq!(first.recv().await.unwrap(), "MESSAGE jobs/build 2 two");
+        queues.publish("jobs/build", "three").await.unwrap();
+        assert_eq!(second.recv().await.unwrap(), "MESSAGE jobs/build 3 three");
+
+        assert_eq!(first.ack(1), Ok(()));
+        assert!(first.ack(1).is_err());
+        assert!(second.ack(2).is_err());
+        // Message 2 was never acknowledged: the other consumer gets it
+        drop(first);
+        let redelivered = second.recv().await.unwrap();
+        assert_eq!(id_of(&redelivered), 2);
+        assert_eq!(second.ack(2), Ok(()));
+        assert!(second.recv().now_or_never().is_none());
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn waiting_messages_survive_a_restart_and_ids_keep_going_up() {
+        let dir = dir("restart");
+        let queues = open(&dir).await;
+        for text in ["one", "two", "three"] {
+            queues.publish("jobs/build", text).await.unwrap();
+        }
+        let mut consumer = queues.consume("jobs/build").unwrap();
+        consumer.recv().await.unwrap();
+        consumer.ack(1).unwrap();
+        // The acknowledgement is not waited for; a publish after it is
+        queues.publish("jobs/build", "four").await.unwrap();
+        drop((consumer, queues));
+
+        let queues = open(&dir).await;
+        let mut consumer = queues.consume("jobs/build").unwrap();
+        for expected in [2, 3, 4] {
+            assert_eq!(id_of(&consumer.recv().await.unwrap()), expected);
+        }
+        assert_eq!(queues.publish("jobs/build", "five").await, Ok(5));
+
+        // Rewritten without the acknowledged messages, the numbers still go up
+        let queue = queues.inner.topics.lock().unwrap().queues["jobs/build"].compacted();
+        assert_eq!(queue.lines().next(), Some("NEXT\t6"));
+        assert_eq!(queue.lines().count(), 5);
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -0,0 +1,331 @@
+//! Quotas per client, shared by every listener: `--quota-rate`.
+//!
+//! The limits of `crate::rate_limit` belong to a connection, or to the
+//! connections of one address on the line listeners. A client that opens
+//! another connection, or moves to HTTP/2 or the HTTP endpoint, starts
+//! over with full buckets. A quota is kept per identity instead, whichever
+//! listener the requests come in on: the address, or the name of the token
+//! once a client has sent `AUTH`.
+//!
+//! The buckets live in one task, the quota actor, which answers every
+//! listener's requests for tokens over a channel; no lock is shared
+//! between them. A round trip per request would be slow, so a connection
+//! leases tokens in batches and spends them locally: most requests only
+//! take a token from their own [`Lease`], without a message, and a
+//! connection that ends hands back what it did not spend. A lease holds at
+//! most a quarter of the burst, so one connection cannot hoard the lot.
+//!
+//! Requests over the quota are delayed or rejected like those over the
+//! rate limit (`--on-limit`); on HTTP they are answered with `429`. The
+//! actor reads the quota from the config at every request, so a reload
+//! applies right away; without one, leases never ask it anything.
+
+use crate::clock::Clock;
+use crate::config::ConfigRx;
+use crate::metrics::{self, Metrics};
+use crate::rate_limit::{Limit, TokenBucket};
+use crate::task;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock, Weak};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+
+/// Number of tracked identities above which idle (full) buckets are dropped.
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// Requests waiting for the actor before senders have to wait too.
+const QUEUE: usize = 1024;
+
+/// Tokens a connection leases at once, at most.
+const LEASE_BATCH: u32 = 16;
+
+/// Whose quota a request counts against.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Identity {
+    Ip(IpAddr),
+    /// The name of the token the client authenticated with.
+    Token(String),
+}
+
+enum Message {
+    /// Up to `want` tokens, or how long until the first is available.
+    Ask {
+        identity: Identity,
+        want: u32,
+        reply: oneshot::Sender<Result<u32, Duration>>,
+    },
+    /// Leased tokens that were not spent.
+    Return { identity: Identity, tokens: u32 },
+}
+
+/// The handle every listener asks for tokens; clones share the actor.
+#[derive(Clone)]
+pub struct Quotas {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    // Set once the actor runs; without it every request passes
+    actor: OnceLock<mpsc::Sender<Message>>,
+    // Whether the config sets a quota, kept up to date by the actor
+    enabled: AtomicBool,
+    metrics: Metrics,
+}
+
+impl Quotas {
+    pub fn new(metrics: Metrics) -> Self {
+        let inner = Inner { actor: OnceLock::new(), enabled: AtomicBool::new(false), metrics };
+        Self { inner: Arc::new(inner) }
+    }
+
+    /// Starts the actor, with the quota from `config`; only the first call
+    /// does anything.
+    pub fn spawn(&self, config: ConfigRx, clock: Arc<dyn Clock>) {
+        let (tx, rx) = mpsc::channel(QUEUE);
+        if self.inner.actor.set(tx).is_ok() {
+            // Right away, so the first requests count before the actor runs
+            let enabled = config.borrow().quota.is_some();
+            self.inner.enabled.store(enabled, Ordering::Relaxed);
+            let (inner, metrics) = (Arc::downgrade(&self.inner), self.inner.metrics.clone());
+            task::spawn("quota actor", run(inner, rx, config, clock, metrics));
+        }
+    }
+
+    /// Whether requests count against a quota at all.
+    pub fn enabled(&self) -> bool {
+        self.inner.enabled.load(Ordering::Relaxed)
+    }
+
+    /// A lease for the requests of one connection, at first by `identity`.
+    pub fn lease(&self, identity: Identity) -> Lease {
+        Lease { quotas: self.clone(), identity, tokens: 0 }
+    }
+
+    /// Takes a single token of `identity`'s quota, for a request that is
+    /// not part of a longer conversation; or returns how long to wait.
+    pub async fn take(&self, identity: &Identity) -> Result<(), Duration> {
+        if !self.enabled() {
+            return Ok(());
+        }
+        self.ask(identity, 1).await.map(drop)
+    }
+
+    async fn ask(&self, identity: &Identity, want: u32) -> Result<u32, Duration> {
+        let Some(actor) = self.inner.actor.get() else {
+            return Ok(want);
+        };
+        self.inner.metrics.incr(metrics::QUOTA_ASKS, 1);
+        let (reply, granted) = oneshot::channel();
+        let ask = Message::Ask { identity: identity.clone(), want, reply };
+        // Only fails when the actor is gone, i.e. the runtime is shutting down
+        if actor.send(ask).await.is_err() {
+            return Ok(want);
+        }
+        granted.await.unwrap_or(Ok(want))
+    }
+
+    fn give_back(&self, identity: Identity, tokens: u32) {
+        if let Some(actor) = self.inner.actor.get() {
+            // With the queue full the tokens are lost; the bucket refills anyway
+            let _ = actor.try_send(Message::Return { identity, tokens });
+        }
+    }
+}
+
+/// Tokens one connection leased from the actor, and spends without asking.
+pub struct Lease {
+    quotas: Quotas,
+    identity: Identity,
+    tokens: u32,
+}
+
+impl Lease {
+    /// Whether `take` can refuse, or wait for the actor.
+    pub fn enabled(&self) -> bool {
+        self.quotas.enabled()
+    }
+
+    /// Takes a token for one request: from the lease if it has any left,
+    /// otherwise from a new batch. Returns how long to wait when the
+    /// identity's quota is used up.
+    pub async fn take(&mut self) -> Result<(), Duration> {
+        if !self.quotas.enabled() {
+            return Ok(());
+        }
+        if self.tokens == 0 {
+            self.tokens = self.quotas.ask(&self.identity, LEASE_BATCH).await?;
+        }
+        self.tokens -= 1;
+        Ok(())
+    }
+
+    /// Counts the requests from now on against `identity`, once the client
+    /// has authenticated; the tokens left go back to the old one.
+    pub fn set_identity(&mut self, identity: Identity) {
+        if identity != self.identity {
+            self.give_back();
+            self.identity = identity;
+        }
+    }
+
+    fn give_back(&mut self) {
+        let tokens = std::mem::take(&mut self.tokens);
+        if tokens > 0 {
+            self.quotas.give_back(self.identity.clone(), tokens);
+        }
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.give_back();
+    }
+}
+
+/// The actor: owns every identity's bucket and answers the leases, until
+/// the last `Quotas` is dropped.
+async fn run(
+    inner: Weak<Inner>,
+    mut messages: mpsc::Receiver<Message>,
+    mut config: ConfigRx,
+    clock: Arc<dyn Clock>,
+    metrics: Metrics,
+) {
+    let mut buckets: HashMap<Identity, TokenBucket> = HashMap::new();
+    let mut quota = current_quota(&inner, &mut config);
+    let mut watching = true;
+    loop {
+        let message = tokio::select! {
+            message = messages.recv() => match message {
+                Some(message) => message,
+                None => return,
+            },
+            changed = config.changed(), if watching => {
+                // Without a sender the config stays as it is
+                watching = changed.is_ok();
+                quota = current_quota(&inner, &mut config);
+                continue;
+            }
+        };
+
+        let now = clock.now();
+        match message {
+            Message::Ask { identity, want, reply } => {
+                let Some(limit) = quota else {
+                    let _ = reply.send(Ok(want));
+                    continue;
+                };
+                if buckets.len() > PRUNE_THRESHOLD {
+                    // A full bucket behaves exactly like a fresh one, so it can be forgotten
+                    buckets.retain(|_, bucket| !bucket.is_full(now));
+                }
+                let bucket =
+                    buckets.entry(identity).or_insert_with(|| TokenBucket::new(limit, now));
+                if bucket.limit() != limit {
+                    // The quota was reconfigured: start over with a full bucket
+                    *bucket = TokenBucket::new(limit, now);
+                }
+                let share = ((limit.burst / 4.0) as u32).max(1);
+                let granted = bucket.take_up_to(want.min(share), now);
+                if granted.is_err() {
+                    metrics.incr(metrics::QUOTA_EXCEEDED, 1);
+                }
+                let _ = reply.send(granted);
+            }
+            Message::Return { identity, tokens } => {
+                if let Some(bucket) = buckets.get_mut(&identity) {
+                    bucket.put_back(tokens);
+                }
+            }
+        }
+    }
+}
+
+/// The quota the config sets, also published to the leases.
+fn current_quota(inner: &Weak<Inner>, config: &mut ConfigRx) -> Option<Limit> {
+    let quota = config.borrow_and_update().quota;
+    if let Some(inner) = inner.upgrade() {
+        inner.enabled.store(quota.is_some(), Ordering::Relaxed);
+    }
+    quota
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::config::ServerConfig;
+    use crate::metrics::{InMemorySink, MetricsSink};
+    use std::net::Ipv4Addr;
+    use tokio::sync::watch;
+
+    const IP: Identity = Identity::Ip(IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+    /// Quotas with a running actor, on a clock that only moves when told.
+    fn quotas(quota: Option<Limit>) -> (Quotas, Arc<InMemorySink>, ManualClock) {
+        let sink = Arc::new(InMemorySink::default());
+        let quotas = Quotas::new(sink.clone());
+        let config = ServerConfig { quota, ..ServerConfig::default() };
+        let config = watch::channel(Arc::new(config)).1;
+        let clock = ManualClock::new();
+        quotas.spawn(config, Arc::new(clock.clone()));
+        (quotas, sink, clock)
+    }
+
+    fn asks(sink: &InMemorySink) -> i64 {
+        let asks = sink.snapshot().into_iter().find(|m| m.name == metrics::QUOTA_ASKS);
+        asks.map_or(0, |metric| metric.value)
+    }
+
+    #[tokio::test]
+    async fn leases_spend_batches_of_tokens_without_asking_the_actor() {
+        let (quotas, sink, clock) = quotas(Some(Limit::new(1.0, Some(8.0))));
+        let mut lease = quotas.lease(IP);
+        // A quarter of the burst at a time: two round trips for four requests
+        for _ in 0..4 {
+            assert_eq!(lease.take().await, Ok(()));
+        }
+        assert_eq!(asks(&sink), 2);
+
+        // Another connection of the same client shares what is left
+        let mut other = quotas.lease(IP);
+        for _ in 0..4 {
+            assert_eq!(other.take().await, Ok(()));
+        }
+        assert_eq!(other.take().await, Err(Duration::from_secs(1)));
+        assert_eq!(quotas.take(&IP).await, Err(Duration::from_secs(1)));
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(quotas.take(&IP).await, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn unspent_tokens_go_back_and_tokens_have_quotas_of_their_own() {
+        let (quotas, _sink, _clock) = quotas(Some(Limit::new(1.0, Some(8.0))));
+        let mut lease = quotas.lease(IP);
+        // Two tokens leased, one spent
+        assert_eq!(lease.take().await, Ok(()));
+        // Authenticated: the token's quota is full, the address gets one back
+        lease.set_identity(Identity::Token("ops".to_string()));
+        for _ in 0..8 {
+            assert_eq!(lease.take().await, Ok(()));
+        }
+        assert!(lease.take().await.is_err());
+        for _ in 0..7 {
+            assert_eq!(quotas.take(&IP).await, Ok(()));
+        }
+        assert!(quotas.take(&IP).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn without_a_quota_nothing_is_asked() {
+        let (quotas, sink, _clock) = quotas(None);
+        let mut lease = quotas.lease(IP);
+        for _ in 0..100 {
+            assert_eq!(lease.take().await, Ok(()));
+        }
+        assert_eq!(quotas.take(&IP).await, Ok(()));
+        assert_eq!(asks(&sink), 0);
+    }
+}
@@ -0,0 +1,389 @@
+//! Token-bucket rate limiting.
+//!
+//! Each connection can own a bucket, and all connections coming from the
+//! same IP address can additionally share one. A message is accepted only
+//! when both buckets have a token available, and the client's quota has
+//! one too; see [`crate::quota`].
+//!
+//! The time comes from a [`Clock`], so the tests can move it by hand.
+
+use crate::clock::Clock;
+use crate::quota::{Identity, Lease};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Number of tracked addresses above which idle (full) buckets are dropped.
+const IP_PRUNE_THRESHOLD: usize = 1024;
+
+/// What to do with a message that exceeds the limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnExceed {
+    /// Sleep until a token becomes available, slowing the client down.
+    Delay,
+    /// Answer with an error line and drop the message.
+    Reject,
+}
+
+/// `rate` tokens per second, at most `burst` tokens saved up.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limit {
+    pub rate: f64,
+    pub burst: f64,
+}
+
+impl Limit {
+    /// The smallest burst that lets a message through: tokens never grow
+    /// past the burst, and a message takes a whole one.
+    pub const MIN_BURST: f64 = 1.0;
+
+    /// `burst` defaults to one second worth of tokens.
+    pub fn new(rate: f64, burst: Option<f64>) -> Self {
+        Self {
+            rate,
+            burst: burst.unwrap_or(rate.ceil().max(1.0)),
+        }
+    }
+
+    /// Checks a configured burst, named `name` in the error: below
+    /// [`MIN_BURST`](Self::MIN_BURST) a limited client would wait forever.
+    pub fn check_burst(name: &str, burst: f64) -> Result<f64, String> {
+        if burst >= Self::MIN_BURST {
+            Ok(burst)
+        } else {
+            Err(format!("{} must be at least 1, a message takes a whole token", name))
+        }
+    }
+}
+
+pub struct TokenBucket {
+    limit: Limit,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket, as of `now`.
+    pub fn new(limit: Limit, now: Instant) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.rate).min(self.limit.burst);
+        self.last = now;
+    }
+
+    /// Returns how long to wait until a token is available (zero if one is).
+    pub fn wait_time(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1.0 - self.tokens) / self.limit.rate)
+        }
+    }
+
+    /// Takes a token, or returns how long to wait for one.
+    pub fn try_take(&mut self, now: Instant) -> Result<(), Duration> {
+        let wait = self.wait_time(now);
+        if wait.is_zero() {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(wait)
+        }
+    }
+
+    /// Takes as many tokens as are available, between one and `most`, or
+    /// returns how long to wait for the first.
+    pub fn take_up_to(&mut self, most: u32, now: Instant) -> Result<u32, Duration> {
+        let wait = self.wait_time(now);
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+        let taken = (self.tokens as u32).clamp(1, most.max(1));
+        self.tokens -= f64::from(taken);
+        Ok(taken)
+    }
+
+    /// Puts back tokens that were taken but not used, up to the burst.
+    pub fn put_back(&mut self, tokens: u32) {
+        self.tokens = (self.tokens + f64::from(tokens)).min(self.limit.burst);
+    }
+
+    pub fn limit(&self) -> Limit {
+        self.limit
+    }
+
+    /// Whether the bucket holds the whole burst, so behaves like a new one.
+    pub fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.limit.burst
+    }
+}
+
+/// Buckets shared by all connections from the same address.
+///
+/// The limit is passed in on every call rather than stored here, so a
+/// reloaded configuration takes effect without rebuilding the limiter.
+#[derive(Default)]
+pub struct IpLimiter {
+    buckets: Mutex<HashMap<IpAddr, TokenBucket>>,
+}
+
+impl IpLimiter {
+    /// Takes one of `ip`'s tokens, or returns how long to wait for one.
+    pub fn try_take(&self, ip: IpAddr, limit: Limit, now: Instant) -> Result<(), Duration> {
+        self.with_bucket(ip, limit, now, |bucket| bucket.try_take(now))
+    }
+
+    /// How long `ip` has to wait for a token, without taking one.
+    pub fn wait_time(&self, ip: IpAddr, limit: Limit, now: Instant) -> Duration {
+        self.with_bucket(ip, limit, now, |bucket| bucket.wait_time(now))
+    }
+
+    fn with_bucket<R>(
+        &self,
+        ip: IpAddr,
+        limit: Limit,
+        now: Instant,
+        f: impl FnOnce(&mut TokenBucket) -> R,
+    ) -> R {
+        // Synchronous lock, never held across an `.await`
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() > IP_PRUNE_THRESHOLD {
+            // A full bucket behaves exactly like a fresh one, so it can be forgotten
+            buckets.retain(|_, bucket| !bucket.is_full(now));
+        }
+
+        let bucket = buckets.entry(ip).or_insert_with(|| TokenBucket::new(limit, now));
+        if bucket.limit != limit {
+            // The limit was reconfigured: start over with a full bucket
+            *bucket = TokenBucket::new(limit, now);
+        }
+        f(bucket)
+    }
+}
+
+/// Rate limiting state of a single connection.
+pub struct ConnLimiter {
+    own: Option<TokenBucket>,
+    shared: Arc<IpLimiter>,
+    ip: IpAddr,
+    ip_limit: Option<Limit>,
+    quota: Option<Lease>,
+    on_exceed: OnExceed,
+    clock: Arc<dyn Clock>,
+}
+
+impl ConnLimiter {
+    pub fn new(shared: Arc<IpLimiter>, ip: IpAddr, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            own: None,
+            shared,
+            ip,
+            ip_limit: None,
+            quota: None,
+            on_exceed: OnExceed::Delay,
+            clock,
+        }
+    }
+
+    /// Also spends the client's quota, shared with its other connections.
+    pub fn with_quota(mut self, quota: Lease) -> Self {
+        self.quota = Some(quota);
+        self
+    }
+
+    /// Counts the messages from now on against `identity`'s quota.
+    pub fn set_identity(&mut self, identity: Identity) {
+        if let Some(quota) = self.quota.as_mut() {
+            quota.set_identity(identity);
+        }
+    }
+
+    /// Applies (possibly changed) limits; an unchanged connection bucket
+    /// keeps its tokens.
+    pub fn configure(&mut self, own: Option<Limit>, ip_limit: Option<Limit>, on_exceed: OnExceed) {
+        if self.own.as_ref().map(|b| b.limit) != own {
+            self.own = own.map(|limit| TokenBucket::new(limit, self.clock.now()));
+        }
+        self.ip_limit = ip_limit;
+        self.on_exceed = on_exceed;
+    }
+
+    /// Whether `acquire` can suspend: a limit is set and excess messages wait.
+    pub fn may_delay(&self) -> bool {
+        let limited = self.own.is_some() || self.ip_limit.is_some();
+        self.on_exceed == OnExceed::Delay
+            && (limited || self.quota.as_ref().is_some_and(Lease::enabled))
+    }
+
+    /// Waits for permission to process one message.
+    ///
+    /// Returns `Err(wait)` when the message must be rejected.
+    pub async fn acquire(&mut self) -> Result<(), Duration> {
+        loop {
+            let now = self.clock.now();
+
+            // The connection bucket is only peeked at here, so a token is
+            // not lost when the shared bucket turns the message down.
+            let own_wait = match self.own.as_mut() {
+                Some(bucket) => bucket.wait_time(now),
+                None => Duration::ZERO,
+            };
+
+            let mut wait = if !own_wait.is_zero() {
+                own_wait
+            } else {
+                match self.ip_limit {
+                    Some(limit) => match self.shared.try_take(self.ip, limit, now) {
+                        Ok(()) => Duration::ZERO,
+                        Err(wait) => wait,
+                    },
+                    None => Duration::ZERO,
+                }
+            };
+            // The quota comes last: asking it may mean a trip to its actor
+            if wait.is_zero()
+                && let Some(quota) = self.quota.as_mut()
+                && let Err(quota_wait) = quota.take().await
+            {
+                wait = quota_wait;
+            }
+
+            if wait.is_zero() {
+                if let Some(bucket) = self.own.as_mut() {
+                    let _ = bucket.try_take(now);
+                }
+                return Ok(());
+            }
+
+            match self.on_exceed {
+                OnExceed::Reject => return Err(wait),
+                // The task is suspended; other connections keep running
+                OnExceed::Delay => self.clock.sleep(wait).await,
+            }
+        }
+    }
+}
+
+/// Time is paused in these tests: it only moves when told to, or when every
+/// task is waiting on a timer, so refill times can be checked exactly. The
+/// limiter can also be given a `ManualClock`, and polled by hand.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{ManualClock, TokioClock};
+    use futures_util::FutureExt;
+    use std::net::Ipv4Addr;
+
+    const IP: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    fn limiter(shared: &Arc<IpLimiter>, own: Option<Limit>, ip: Option<Limit>) -> ConnLimiter {
+        let mut limiter = ConnLimiter::new(shared.clone(), IP, Arc::new(TokioClock));
+        limiter.configure(own, ip, OnExceed::Reject);
+        limiter
+    }
+
+    #[test]
+    fn a_burst_must_hold_one_whole_token() {
+        assert_eq!(Limit::check_burst("--burst", 1.0), Ok(1.0));
+        let error = Limit::check_burst("--burst", 0.99).unwrap_err();
+        assert_eq!(error, "--burst must be at least 1, a message takes a whole token");
+        // The smallest burst still lets a message through
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(Limit::new(0.5, Some(1.0)), now);
+        assert_eq!(bucket.try_take(now), Ok(()));
+        assert_eq!(bucket.try_take(now), Err(Duration::from_secs(2)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_bucket_refills_at_its_rate_up_to_the_burst() {
+        let mut bucket = TokenBucket::new(Limit::new(2.0, Some(2.0)), Instant::now());
+        assert_eq!(bucket.try_take(Instant::now()), Ok(()));
+        assert_eq!(bucket.try_take(Instant::now()), Ok(()));
+        // Empty: at 2 tokens a second, the next one is half a second away
+        assert_eq!(bucket.try_take(Instant::now()), Err(Duration::from_millis(500)));
+
+        tokio::time::advance(Duration::from_millis(250)).await;
+        assert_eq!(bucket.try_take(Instant::now()), Err(Duration::from_millis(250)));
+        tokio::time::advance(Duration::from_millis(250)).await;
+        assert_eq!(bucket.try_take(Instant::now()), Ok(()));
+
+        // A long pause saves up no more than the burst
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert_eq!(bucket.try_take(Instant::now()), Ok(()));
+        assert_eq!(bucket.try_take(Instant::now()), Ok(()));
+        assert!(bucket.try_take(Instant::now()).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn delay_waits_exactly_until_the_next_token() {
+        let shared = Arc::new(IpLimiter::default());
+        let mut limiter = ConnLimiter::new(shared, IP, Arc::new(TokioClock));
+        limiter.configure(Some(Limit::new(4.0, Some(1.0))), None, OnExceed::Delay);
+
+        let start = Instant::now();
+        limiter.acquire().await.unwrap();
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        limiter.acquire().await.unwrap();
+        assert_eq!(start.elapsed(), Duration::from_millis(250));
+        limiter.acquire().await.unwrap();
+        assert_eq!(start.elapsed(), Duration::from_millis(500));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connections_from_one_address_share_its_bucket() {
+        let shared = Arc::new(IpLimiter::default());
+        let ip_limit = Some(Limit::new(1.0, Some(1.0)));
+        let mut first = limiter(&shared, None, ip_limit);
+        let mut second = limiter(&shared, None, ip_limit);
+
+        assert_eq!(first.acquire().await, Ok(()));
+        assert_eq!(second.acquire().await, Err(Duration::from_secs(1)));
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(second.acquire().await, Ok(()));
+        assert_eq!(first.acquire().await, Err(Duration::from_secs(1)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_rejected_message_does_not_use_up_the_connection_bucket() {
+        let shared = Arc::new(IpLimiter::default());
+        let own = Some(Limit::new(1.0, Some(1.0)));
+        let ip_limit = Some(Limit::new(1.0, Some(1.0)));
+        let mut other = limiter(&shared, None, ip_limit);
+        let mut limiter = limiter(&shared, own, ip_limit);
+
+        // The address's token goes to another connection
+        assert_eq!(other.acquire().await, Ok(()));
+        assert_eq!(limiter.acquire().await, Err(Duration::from_secs(1)));
+        // Its own bucket is still full, so only the shared one was waited for
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(limiter.acquire().await, Ok(()));
+    }
+
+    #[test]
+    fn a_delayed_message_waits_for_the_clock() {
+        let clock = ManualClock::new();
+        let shared = Arc::new(IpLimiter::default());
+        let mut limiter = ConnLimiter::new(shared, IP, Arc::new(clock.clone()));
+        limiter.configure(Some(Limit::new(4.0, Some(1.0))), None, OnExceed::Delay);
+        assert_eq!(limiter.acquire().now_or_never(), Some(Ok(())));
+
+        let mut next = Box::pin(limiter.acquire());
+        assert_eq!(next.as_mut().now_or_never(), None);
+        clock.advance(Duration::from_millis(249));
+        assert_eq!(next.as_mut().now_or_never(), None);
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(next.now_or_never(), Some(Ok(())));
+    }
+}
@@ -0,0 +1,128 @@
+//! Server lifecycle phases.
+//!
+//! ```text
+//! Starting ──> Warming ──> Ready ──> Draining
+//! ```
+//!
+//! - `Starting`: configuration is read and the listener is being bound.
+//! - `Warming`: the listener accepts, but startup tasks are still running.
+//! - `Ready`: connections are served normally.
+//! - `Draining`: shutdown was requested; no new connections are served.
+//!
+//! The current phase is published through a `watch` channel, so any task
+//! can look at it (`borrow`) or wait for a change (`wait_for`).
+//!
+//! Whether the server should get new traffic takes more than the phase:
+//! [`Signals::check`] also looks at the accept loops, the logger and the
+//! number of open connections. The HTTP endpoint answers `/readyz` with it.
+
+use std::fmt;
+use tokio::sync::watch;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Starting,
+    Warming,
+    Ready,
+    Draining,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Phase::Starting => "starting",
+            Phase::Warming => "warming",
+            Phase::Ready => "ready",
+            Phase::Draining => "draining",
+        };
+        f.write_str(name)
+    }
+}
+
+/// What readiness is decided on, read from the server's state when asked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Signals {
+    pub phase: Phase,
+    /// The `accept_paused` feature flag.
+    pub accept_paused: bool,
+    /// The logger task still reads the log channel.
+    pub logger_running: bool,
+    pub connections: usize,
+    /// `ready_max_connections`.
+    pub max_connections: Option<usize>,
+}
+
+/// Why the server should not get new traffic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotReady {
+    /// Still starting, or draining.
+    Phase(Phase),
+    AcceptPaused,
+    LoggerStopped,
+    AtCapacity { connections: usize, max: usize },
+}
+
+impl fmt::Display for NotReady {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NotReady::Phase(phase) => write!(f, "{}", phase),
+            NotReady::AcceptPaused => f.write_str("accepting is paused"),
+            NotReady::LoggerStopped => f.write_str("the logger has stopped"),
+            NotReady::AtCapacity { connections, max } => {
+                write!(f, "at capacity, {} of {} connections", connections, max)
+            }
+        }
+    }
+}
+
+impl Signals {
+    /// Ready, or the first reason not to be.
+    pub fn check(&self) -> Result<(), NotReady> {
+        if self.phase != Phase::Ready {
+            return Err(NotReady::Phase(self.phase));
+        }
+        if self.accept_paused {
+            return Err(NotReady::AcceptPaused);
+        }
+        if !self.logger_running {
+            return Err(NotReady::LoggerStopped);
+        }
+        match self.max_connections {
+            Some(max) if self.connections >= max => {
+                Err(NotReady::AtCapacity { connections: self.connections, max })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// What the accept loop does with connections that arrive before `Ready`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotReadyPolicy {
+    /// Answer with an error line and close the connection.
+    Reject,
+    /// Keep the connection open; its task waits until the server is ready.
+    Queue,
+}
+
+/// Suspends until the server is `Ready`.
+///
+/// Returns `false` if the server started draining instead (or the phase
+/// sender is gone), in which case the caller should give up.
+pub async fn wait_ready(phase: &mut watch::Receiver<Phase>) -> bool {
+    match phase
+        .wait_for(|p| matches!(p, Phase::Ready | Phase::Draining))
+        .await
+    {
+        Ok(p) => *p == Phase::Ready,
+        Err(_) => false,
+    }
+}
+
+/// Suspends until the server starts draining (or the phase sender is gone).
+///
+/// Unlike a bare `wait_for`, no borrow of the channel outlives this call,
+/// so the caller can keep awaiting afterwards.
+pub async fn wait_draining(phase: &mut watch::Receiver<Phase>) {
+    let _ = phase.wait_for(|p| *p == Phase::Draining).await;
+}
@@ -0,0 +1,274 @@
+//! Recording what clients send, to replay it later.
+//!
+//! With `--record <dir>` every connection writes the bytes it reads, as
+//! they arrive, to `<dir>/conn-<id>.rec`:
+//!
+//! ```text
+//! # session <connection id> <peer> <unix micros at connect>
+//! <micros since connect> <length>
+//! <length raw bytes>
+//! ```
+//!
+//! A chunk is its header line, exactly `length` bytes and a newline, so
+//! any bytes can be recorded. The `replay` binary reads these files and
+//! sends the chunks to a server again, with the same pauses in between.
+//!
+//! The bytes are recorded where they come off the socket, beneath the
+//! codec, so a replay reproduces how the input was split across reads as
+//! well as what it was.
+
+use crate::task;
+use crate::transport::Transport;
+use bytes::Bytes;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufWriter, ReadBuf};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+/// A connection's input, as read back from its file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Session {
+    pub conn_id: u64,
+    pub peer: String,
+    /// When the connection was accepted, in microseconds since the epoch.
+    pub started: u64,
+    pub chunks: Vec<Chunk>,
+}
+
+/// The bytes of one read, and when it happened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    /// Time since the connection was accepted.
+    pub at: Duration,
+    pub data: Bytes,
+}
+
+/// Hands the chunks read on one connection to the task writing its file.
+///
+/// `poll_read` cannot wait for a file write, so chunks go through an
+/// unbounded channel. It stays short: the file is written as fast as the
+/// socket is read, and the connection's own buffers hold no less.
+pub struct Recorder {
+    accepted: Instant,
+    chunks: mpsc::UnboundedSender<Chunk>,
+}
+
+impl Recorder {
+    /// Creates `conn-<id>.rec` in `dir`, or `None` when that fails.
+    ///
+    /// Recording is best-effort, like tracing: the connection is served
+    /// either way.
+    pub async fn create(dir: &Path, conn_id: u64, peer: SocketAddr) -> Option<Recorder> {
+        let path = dir.join(format!("conn-{}.rec", conn_id));
+        let file = match File::create(&path).await {
+            Ok(file) => file,
+            Err(e) => {
+                eprintln!("cannot create recording {}: {}", path.display(), e);
+                return None;
+            }
+        };
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_micros() as u64);
+        let header = format!("# session {} {} {}\n", conn_id, peer, started);
+        let (chunks, received) = mpsc::unbounded_channel();
+        task::spawn("recorder", async move {
+            if let Err(e) = write_session(file, header, received).await {
+                eprintln!("recording {} stopped: {}", path.display(), e);
+            }
+        });
+        Some(Recorder { accepted: Instant::now(), chunks })
+    }
+
+    fn record(&self, data: &[u8]) {
+        let chunk = Chunk { at: self.accepted.elapsed(), data: Bytes::copy_from_slice(data) };
+        // The writer only goes away after a failed write, which it reported
+        let _ = self.chunks.send(chunk);
+    }
+}
+
+/// Writes chunks until the connection drops its `Recorder`.
+async fn write_session(
+    file: File,
+    header: String,
+    mut chunks: mpsc::UnboundedReceiver<Chunk>,
+) -> io::Result<()> {
+    let mut out = BufWriter::new(file);
+    out.write_all(header.as_bytes()).await?;
+    while let Some(chunk) = chunks.recv().await {
+        let line = format!("{} {}\n", chunk.at.as_micros(), chunk.data.len());
+        out.write_all(line.as_bytes()).await?;
+        out.write_all(&chunk.data).await?;
+        out.write_all(b"\n").await?;
+    }
+    out.flush().await
+}
+
+/// Passes everything through, recording what is read.
+///
+/// Without a recorder it is a plain pass-through, so every connection can
+/// be wrapped whether recording is on or not.
+pub struct RecordingStream<T> {
+    inner: T,
+    recorder: Option<Recorder>,
+}
+
+impl<T> RecordingStream<T> {
+    pub fn new(inner: T, recorder: Option<Recorder>) -> Self {
+        Self { inner, recorder }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for RecordingStream<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        let read = &buf.filled()[before..];
+        if let Some(recorder) = &this.recorder
+            && !read.is_empty()
+        {
+            recorder.record(read);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for RecordingStream<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+impl<T: Transport> Transport for RecordingStream<T> {
+    fn peer_closed(&self) -> impl Future<Output = ()> + Send + '_ {
+        self.inner.peer_closed()
+    }
+}
+
+/// Reads a recording back.
+pub async fn load(path: &Path) -> io::Result<Session> {
+    let content = tokio::fs::read(path).await?;
+    parse(&content).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Parses the contents of a `.rec` file.
+pub fn parse(content: &[u8]) -> Result<Session, String> {
+    let (header, mut rest) = split_line(content).ok_or("the session header is missing")?;
+    let fields = header.strip_prefix("# session ").ok_or("not a session recording")?;
+    let mut fields = fields.split(' ');
+    let mut field = |name| fields.next().ok_or(format!("the header has no {}", name));
+    let conn_id = field("connection id")?;
+    let peer = field("peer")?.to_string();
+    let started = field("start time")?;
+    let conn_id = conn_id.parse().map_err(|_| format!("invalid connection id {:?}", conn_id))?;
+    let started = started.parse().map_err(|_| format!("invalid start time {:?}", started))?;
+
+    let mut chunks = Vec::new();
+    while !rest.is_empty() {
+        let n = chunks.len() + 1;
+        let (line, after) = split_line(rest).ok_or(format!("chunk {} has no header", n))?;
+        let (micros, len) = line
+            .split_once(' ')
+            .and_then(|(micros, len)| Some((micros.parse().ok()?, len.parse::<usize>().ok()?)))
+            .ok_or(format!("chunk {} has an invalid header {:?}", n, line))?;
+        let data = after.get(..len).ok_or(format!("chunk {} is cut short", n))?;
+        if after.get(len) != Some(&b'\n') {
+            return Err(format!("chunk {} is longer than its header says", n));
+        }
+        let at = Duration::from_micros(micros);
+        chunks.push(Chunk { at, data: Bytes::copy_from_slice(data) });
+        rest = &after[len + 1..];
+    }
+    Ok(Session { conn_id, peer, started, chunks })
+}
+
+/// The first line of `content` as text, and what follows it.
+fn split_line(content: &[u8]) -> Option<(&str, &[u8])> {
+    let end = content.iter().position(|&b| b == b'\n')?;
+    let line = std::str::from_utf8(&content[..end]).ok()?;
+    Some((line, &content[end + 1..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn what_is_read_is_recorded_and_parsed_back() {
+        let dir = std::env::temp_dir().join(format!("record-test-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let peer: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+
+        let (mut client, server) = tokio::io::duplex(1024);
+        let recorder = Recorder::create(&dir, 7, peer).await;
+        let mut server = RecordingStream::new(server, recorder);
+        let mut buf = [0u8; 64];
+        client.write_all(b"SET a 1\n").await.unwrap();
+        assert_eq!(server.read(&mut buf).await.unwrap(), 8);
+        // Any bytes, newlines and all
+        client.write_all(b"\xff\n\n").await.unwrap();
+        assert_eq!(server.read(&mut buf).await.unwrap(), 3);
+        drop(server);
+
+        // The file is complete once the writer task has seen the channel close
+        let path = dir.join("conn-7.rec");
+        let session = loop {
+            if let Ok(session) = load(&path).await
+                && session.chunks.len() == 2
+            {
+                break session;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+
+        assert_eq!((session.conn_id, session.peer.as_str()), (7, "127.0.0.1:4000"));
+        assert_eq!(session.chunks[0].data, "SET a 1\n");
+        assert_eq!(session.chunks[1].data, &b"\xff\n\n"[..]);
+        assert!(session.chunks[0].at <= session.chunks[1].at);
+    }
+
+    #[test]
+    fn truncated_recordings_are_refused() {
+        let content = b"# session 1 127.0.0.1:4000 0\n10 5\nab";
+        assert_eq!(parse(content).unwrap_err(), "chunk 1 is cut short");
+        assert!(parse(b"10 5\nhello\n").is_err());
+    }
+}
@@ -0,0 +1,331 @@
+//! Registry of live connections, used by the admin `LIST` and `KICK` commands.
+//!
+//! A connection task registers itself and gets a [`Registration`] back.
+//! The registration removes the entry in `Drop`, so the registry is cleaned
+//! up however the task ends: normal return, error, panic or cancellation.
+//!
+//! Each connection has a `CancellationToken`. `KICK` cancels it, and so does
+//! the idle sweeper: with `session_ttl_secs` set, it closes connections that
+//! have not sent a message for that long. The entry keeps the reason, which
+//! the connection task reads once it sees the token cancelled.
+
+use crate::close::CloseReason;
+use crate::config::ConfigRx;
+use crate::logger::{LogMessage, LogTx};
+use crate::readiness::{self, Phase};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::{Instant, MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
+
+/// How often the idle sweeper looks for connections past the TTL.
+pub const IDLE_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// One row of `LIST`.
+pub struct ConnectionInfo {
+    pub id: u64,
+    pub peer: SocketAddr,
+    pub age: Duration,
+    pub requests: u64,
+    /// Time since the last message, or since connecting.
+    pub idle: Duration,
+    /// What the client negotiated with ALPN, on the TLS listener.
+    pub protocol: Option<&'static str>,
+    /// Who the client certificate names, on a TLS listener that asks for one.
+    pub identity: Option<String>,
+    /// The virtual host SNI picked, on a TLS listener that has them.
+    pub vhost: Option<String>,
+}
+
+struct Entry {
+    peer: SocketAddr,
+    connected_at: Instant,
+    requests: u64,
+    last_active: Instant,
+    protocol: Option<&'static str>,
+    identity: Option<String>,
+    vhost: Option<String>,
+    // Cancelled by `kick` or the idle sweeper, which first set the reason;
+    // the connection task holds a clone
+    cancel: CancellationToken,
+    reason: Option<CloseReason>,
+}
+
+impl Entry {
+    /// Cancels the connection for `reason`, unless it was cancelled already.
+    fn cancel(&mut self, reason: CloseReason) -> bool {
+        if self.reason.is_some() {
+            return false;
+        }
+        self.reason = Some(reason);
+        self.cancel.cancel();
+        true
+    }
+}
+
+impl Entry {
+    fn info(&self, id: u64, now: Instant) -> ConnectionInfo {
+        ConnectionInfo {
+            id,
+            peer: self.peer,
+            age: now.duration_since(self.connected_at),
+            requests: self.requests,
+            idle: now.duration_since(self.last_active),
+            protocol: self.protocol,
+            identity: self.identity.clone(),
+            vhost: self.vhost.clone(),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Registry {
+    // Ordered by id, so LIST shows the oldest connections first
+    connections: Mutex<BTreeMap<u64, Entry>>,
+}
+
+impl Registry {
+    pub fn register(self: &Arc<Self>, id: u64, peer: SocketAddr) -> Registration {
+        let cancel = CancellationToken::new();
+        let now = Instant::now();
+        let entry = Entry {
+            peer,
+            connected_at: now,
+            requests: 0,
+            last_active: now,
+            protocol: None,
+            identity: None,
+            vhost: None,
+            cancel: cancel.clone(),
+            reason: None,
+        };
+        self.connections.lock().unwrap().insert(id, entry);
+
+        Registration {
+            registry: self.clone(),
+            id,
+            peer,
+            cancel,
+        }
+    }
+
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        let now = Instant::now();
+        let connections = self.connections.lock().unwrap();
+        connections.iter().map(|(id, entry)| entry.info(*id, now)).collect()
+    }
+
+    /// How many connections are open.
+    pub fn count(&self) -> usize {
+        self.connections.lock().unwrap().len()
+    }
+
+    /// Asks the connection to close. Returns `false` if there is no such
+    /// connection (or it was already kicked).
+    pub fn kick(&self, id: u64) -> bool {
+        let mut connections = self.connections.lock().unwrap();
+        connections.get_mut(&id).is_some_and(|entry| entry.cancel(CloseReason::AdminKill))
+    }
+
+    /// Asks every connection idle for longer than `ttl` to close, and
+    /// returns them. One that was already kicked is left alone.
+    pub fn evict_idle(&self, ttl: Duration) -> Vec<ConnectionInfo> {
+        let now = Instant::now();
+        let mut connections = self.connections.lock().unwrap();
+        let mut evicted = Vec::new();
+        for (id, entry) in connections.iter_mut() {
+            if now.duration_since(entry.last_active) <= ttl {
+                continue;
+            }
+            if entry.cancel(CloseReason::IdleTimeout) {
+                evicted.push(entry.info(*id, now));
+            }
+        }
+        evicted
+    }
+}
+
+/// Every `IDLE_SWEEP_INTERVAL`, closes the connections that sent nothing for
+/// longer than `session_ttl`, until the server drains.
+///
+/// Unlike `idle_timeout`, which times out a single read, this covers every
+/// kind of connection, including those following a feed.
+pub async fn sweep_idle(
+    registry: Arc<Registry>,
+    mut config: ConfigRx,
+    log_tx: LogTx,
+    mut phase: watch::Receiver<Phase>,
+) {
+    let mut ticker = tokio::time::interval(IDLE_SWEEP_INTERVAL);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = readiness::wait_draining(&mut phase) => return,
+        }
+        // Read on every sweep, so a reload applies to the next one
+        let Some(ttl) = config.borrow_and_update().session_ttl else { continue };
+        for conn in registry.evict_idle(ttl) {
+            let text = format!(
+                "closing connection #{} from {}: idle for {}s",
+                conn.id,
+                conn.peer,
+                conn.idle.as_secs(),
+            );
+            log_tx.send(LogMessage::info(text)).await;
+        }
+    }
+}
+
+/// A connection's entry in the registry, removed when this is dropped.
+pub struct Registration {
+    registry: Arc<Registry>,
+    id: u64,
+    peer: SocketAddr,
+    cancel: CancellationToken,
+}
+
+impl Registration {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    pub fn record_request(&self) {
+        if let Some(entry) = self.registry.connections.lock().unwrap().get_mut(&self.id) {
+            entry.requests += 1;
+            entry.last_active = Instant::now();
+        }
+    }
+
+    /// Records the protocol the connection speaks, once TLS has settled it.
+    pub fn set_protocol(&self, protocol: &'static str) {
+        if let Some(entry) = self.registry.connections.lock().unwrap().get_mut(&self.id) {
+            entry.protocol = Some(protocol);
+        }
+    }
+
+    /// Records who the client certificate names, once TLS has verified it.
+    pub fn set_identity(&self, identity: String) {
+        if let Some(entry) = self.registry.connections.lock().unwrap().get_mut(&self.id) {
+            entry.identity = Some(identity);
+        }
+    }
+
+    /// The identity recorded with `set_identity`, if any.
+    pub fn identity(&self) -> Option<String> {
+        let connections = self.registry.connections.lock().unwrap();
+        connections.get(&self.id).and_then(|entry| entry.identity.clone())
+    }
+
+    /// Records the virtual host the client asked for with SNI.
+    pub fn set_vhost(&self, vhost: String) {
+        if let Some(entry) = self.registry.connections.lock().unwrap().get_mut(&self.id) {
+            entry.vhost = Some(vhost);
+        }
+    }
+
+    /// The virtual host recorded with `set_vhost`, if any.
+    pub fn vhost(&self) -> Option<String> {
+        let connections = self.registry.connections.lock().unwrap();
+        connections.get(&self.id).and_then(|entry| entry.vhost.clone())
+    }
+
+    /// The connection's token, for work of its own that should stop with it,
+    /// or to close it from code.
+    pub fn token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    /// Completes when an administrator kicks this connection or the idle
+    /// sweeper closes it, with the reason to report.
+    ///
+    /// Cancel-safe, so it can be one branch of a `select!` in a loop: the
+    /// cancellation is kept in the token, and a later call sees it at once.
+    pub async fn kicked(&mut self) -> CloseReason {
+        self.cancel.cancelled().await;
+        // The entry is only removed when this registration is dropped.
+        // Without a reason the token was cancelled from code: that is a kick too.
+        let connections = self.registry.connections.lock().unwrap();
+        connections[&self.id].reason.unwrap_or(CloseReason::AdminKill)
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.registry.connections.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::logger::{self, LogOverflow};
+    use crate::metrics::{InMemorySink, Metrics};
+    use tokio_stream::StreamExt;
+
+    #[tokio::test(start_paused = true)]
+    async fn the_sweeper_closes_idle_connections() {
+        let registry = Arc::new(Registry::default());
+        let peer: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let mut idle = registry.register(1, peer);
+        let busy = registry.register(2, peer);
+
+        let ttl = Some(Duration::from_secs(10));
+        let config = ServerConfig { session_ttl: ttl, ..Default::default() };
+        let (_config_tx, config) = watch::channel(Arc::new(config));
+        let metrics: Metrics = Arc::new(InMemorySink::default());
+        let (log_tx, mut log) = logger::channel(16, &LogOverflow::Wait, metrics);
+        let (_phase_tx, phase) = watch::channel(Phase::Ready);
+        tokio::spawn(sweep_idle(registry.clone(), config, log_tx, phase));
+
+        // Paused time jumps ahead whenever every task is waiting
+        tokio::time::sleep(Duration::from_secs(6)).await;
+        busy.record_request();
+        tokio::time::sleep(Duration::from_secs(6)).await;
+
+        assert_eq!(idle.kicked().await, CloseReason::IdleTimeout);
+        let message = log.next().await.unwrap();
+        assert_eq!(&message.text[..], b"closing connection #1 from 10.0.0.1:5000: idle for 11s");
+        // The other one is still open, and can still be kicked
+        assert!(registry.kick(2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_kick_is_not_lost_when_kicked_loses_a_select() {
+        let registry = Arc::new(Registry::default());
+        let mut registration = registry.register(1, "10.0.0.1:5000".parse().unwrap());
+
+        // `kicked` loses to the timer, as it loses to a read in the handler
+        tokio::select! {
+            _ = registration.kicked() => panic!("nobody kicked yet"),
+            _ = tokio::time::sleep(Duration::from_secs(1)) => {}
+        }
+        assert!(registry.kick(1));
+        // Kicked between two polls: the next one still sees it, with its reason
+        assert_eq!(registration.kicked().await, CloseReason::AdminKill);
+        assert_eq!(registration.kicked().await, CloseReason::AdminKill);
+        // Only the first reason counts
+        assert!(!registry.kick(1));
+        assert!(registry.evict_idle(Duration::ZERO).is_empty());
+    }
+
+    #[tokio::test]
+    async fn the_token_is_cancelled_with_the_connection() {
+        let registry = Arc::new(Registry::default());
+        let registration = registry.register(1, "10.0.0.1:5000".parse().unwrap());
+        let token = registration.token();
+        let work = tokio::spawn(async move { token.cancelled().await });
+
+        assert!(registry.kick(1));
+        work.await.unwrap();
+    }
+}
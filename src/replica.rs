@@ -0,0 +1,194 @@
+//! Following a primary: `--replica-of <addr>`.
+//!
+//! A replica connects to its primary like any client, and sends
+//! `REPLICATE`. The primary answers with a snapshot of the key-value store
+//! and its counter, then streams every change from then on:
+//!
+//! ```text
+//! REPLICATE
+//! SNAPSHOT 41 17 2          <- last change, counter, keys
+//! color blue
+//! size 3
+//! COUNT 18
+//! CHANGE 42    DEL size
+//! CHANGE 43    SET a 1    SET b 2
+//! ```
+//!
+//! `CHANGE` lines carry the records of `crate::wal` (tabs shown as
+//! spaces), numbered one after the other; `COUNT` lines the counter, whenever it has moved. The
+//! snapshot is taken and the stream subscribed to under the store's lock,
+//! so no change falls in between.
+//!
+//! The primary hands the changes to each replica's own bounded channel,
+//! like the lines of a `TAIL`, so a replica that cannot keep up misses
+//! some rather than holding up the primary. The numbers give that away:
+//! the replica then drops the connection and starts over with a fresh
+//! snapshot. It does the same whenever the connection is lost, after a
+//! pause that grows while the primary stays unreachable.
+//!
+//! Meanwhile the replica serves its copy to its own clients, reads only:
+//! writes are refused, and plain messages are answered with the primary's
+//! count instead of counting them.
+
+use crate::dialer::Dialer;
+use crate::metrics;
+use crate::persist::Snapshot;
+use crate::readiness::{self, Phase};
+use crate::retry::Policy;
+use crate::state::State;
+use crate::wal::Record;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+/// The pause before reconnecting, by failures in a row; a replica never gives up.
+const BACKOFF: Policy = Policy {
+    max_attempts: u32::MAX,
+    initial_delay: Duration::from_millis(100),
+    max_delay: Duration::from_secs(10),
+    jitter: 0.1,
+};
+
+/// A connection that stayed up this long starts over at the shortest pause.
+const STABLE: Duration = Duration::from_secs(30);
+
+/// The first line of the primary's answer to `REPLICATE`.
+pub fn snapshot_header(seq: u64, counter: i32, keys: usize) -> String {
+    format!("SNAPSHOT {} {} {}\n", seq, counter, keys)
+}
+
+/// Keeps `state` a copy of the primary's at `primary`, until the server
+/// starts draining.
+pub async fn follow(primary: String, state: Arc<State>, mut phase: watch::Receiver<Phase>) {
+    let dialer = Dialer::new().with_metrics(state.metrics.clone());
+    let mut failures = 0;
+    loop {
+        let started = Instant::now();
+        let error = tokio::select! {
+            error = sync(&dialer, &primary, &state) => error,
+            _ = readiness::wait_draining(&mut phase) => return,
+        };
+
+        if started.elapsed() >= STABLE {
+            failures = 0;
+        }
+        failures += 1;
+        let pause = BACKOFF.backoff(failures);
+        println!(
+            "[REPLICA] {}: {}; reconnecting in {}ms",
+            primary,
+            error,
+            pause.as_millis()
+        );
+        tokio::select! {
+            _ = tokio::time::sleep(pause) => {}
+            _ = readiness::wait_draining(&mut phase) => return,
+        }
+    }
+}
+
+/// Loads the primary's snapshot and applies its changes, until the
+/// connection ends or a change is missed; returns why.
+async fn sync(dialer: &Dialer, primary: &str, state: &State) -> String {
+    let stream = match dialer.dial(primary).await {
+        Ok(stream) => stream,
+        Err(e) => return format!("cannot connect: {}", e),
+    };
+    let (reader, mut writer) = stream.into_split();
+    if let Err(e) = writer.write_all(b"REPLICATE\n").await {
+        return e.to_string();
+    }
+    let mut lines = BufReader::new(reader).lines();
+    let mut next_line = async || match lines.next_line().await {
+        Ok(Some(line)) => Ok(line),
+        Ok(None) => Err("the primary closed the connection".to_string()),
+        Err(e) => Err(e.to_string()),
+    };
+
+    let header = match next_line().await {
+        Ok(line) => line,
+        Err(e) => return e,
+    };
+    let Some((mut seq, counter, keys)) = parse_header(&header) else {
+        if header.starts_with("WELCOME") {
+            return "the primary waits for HELLO; turn its handshake off".to_string();
+        }
+        return format!("unexpected answer {:?}", header);
+    };
+    let mut kv = BTreeMap::new();
+    for _ in 0..keys {
+        let line = match next_line().await {
+            Ok(line) => line,
+            Err(e) => return e,
+        };
+        let Some((key, value)) = line.split_once(' ') else {
+            return format!("unexpected entry {:?}", line);
+        };
+        kv.insert(key.to_string(), value.to_string());
+    }
+    state.restore(Snapshot { counter, wal_seq: seq, kv });
+    state.metrics.incr(metrics::REPLICA_SYNCS, 1);
+    println!("[REPLICA] synced {} key(s) and counter {} from {}", keys, counter, primary);
+
+    loop {
+        let line = match next_line().await {
+            Ok(line) => line,
+            Err(e) => return e,
+        };
+        if let Some(count) = line.strip_prefix("COUNT ").and_then(|n| n.parse().ok()) {
+            state.set_count(count);
+        } else if let Some(record) = line.strip_prefix("CHANGE ").and_then(Record::parse) {
+            if record.seq != seq + 1 {
+                return format!("missed changes after #{}, syncing again", seq);
+            }
+            seq = record.seq;
+            state.kv.replay(&[record]);
+        } else if line.starts_with("BYE") {
+            return "the primary is shutting down".to_string();
+        } else {
+            return format!("unexpected line {:?}", line);
+        }
+    }
+}
+
+fn parse_header(line: &str) -> Option<(u64, i32, usize)> {
+    let words: Vec<&str> = line.split(' ').collect();
+    match words[..] {
+        ["SNAPSHOT", seq, counter, keys] => {
+            Some((seq.parse().ok()?, counter.parse().ok()?, keys.parse().ok()?))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::InMemorySink;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn a_gap_in_the_changes_ends_the_sync() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let primary = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let snapshot = "SNAPSHOT 4 10 1\ncolor blue\nCOUNT 12\n";
+            let changes = "CHANGE 5\tSET a 1\nCHANGE 7\tDEL a\n";
+            socket.write_all(format!("{}{}", snapshot, changes).as_bytes()).await.unwrap();
+            // Held open: the replica is the one to hang up
+            std::future::pending::<()>().await;
+        });
+
+        let state = State::new(Arc::new(InMemorySink::default()));
+        let error = sync(&Dialer::new(), &primary, &state).await;
+        assert_eq!(error, "missed changes after #5, syncing again");
+        assert_eq!(state.count(), 12);
+        assert_eq!(state.kv.checkpoint().1, 5);
+        let entries: Vec<_> = state.kv.entries().into_iter().collect();
+        assert_eq!(entries, [("a".into(), "1".into()), ("color".into(), "blue".into())]);
+    }
+}
@@ -0,0 +1,278 @@
+//! Zero-downtime restart: hand the listening sockets to a new process.
+//!
+//! On SIGUSR2 the server starts a new copy of itself, with the same
+//! arguments, and lets it inherit the file descriptors of its listening
+//! sockets. The new process adopts them instead of binding, so the ports
+//! are never closed, not even for a moment:
+//!
+//! ```text
+//! old process                                     new process
+//!   SIGUSR2 ── exec, with the listening FDs ───▶  adopts the sockets
+//!   still accepting                               warms up
+//!           ◀─────────────── READY ────────────── (over a socketpair)
+//!   drains and exits                              serves alone
+//! ```
+//!
+//! Until the old process drains, both accept from the same sockets and the
+//! kernel hands every new connection to one of them, so none is refused.
+//! If the new process fails to start or is not ready in time, the old one
+//! simply carries on.
+//!
+//! The SO_REUSEPORT runtime modes bind their own sockets instead: the
+//! option already lets the new process bind the same address next to the
+//! old one.
+
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+/// `addr=fd` pairs, separated by commas: the sockets a new process adopts.
+pub const LISTEN_FDS: &str = "TOKIO_EXAMPLES_LISTEN_FDS";
+/// The new process's end of the socketpair it reports `READY` on.
+pub const READY_FD: &str = "TOKIO_EXAMPLES_READY_FD";
+
+/// How long the new process may take to warm up.
+const READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The listening sockets of one server, to hand over on a restart.
+///
+/// Holds a duplicate of each socket until [`close`](Handoff::close), which
+/// the server calls once it has stopped, so that its ports are closed.
+#[derive(Default)]
+pub struct Handoff {
+    kept: Mutex<Vec<(SocketAddr, unix::Kept)>>,
+    // Set while a new process is starting
+    restarting: AtomicBool,
+}
+
+impl Handoff {
+    /// Binds a listener to `addr`, or adopts the socket the previous
+    /// process bound to it, and keeps the socket for the next restart.
+    ///
+    /// `addr` is the configured address: with port 0 the new process
+    /// adopts the port the old one was given.
+    pub async fn bind(&self, addr: SocketAddr) -> std::io::Result<TcpListener> {
+        let listener = match unix::adopt(addr) {
+            Some(listener) => {
+                println!("[RESTART] adopted the listener on {}", addr);
+                listener.set_nonblocking(true)?;
+                TcpListener::from_std(listener)?
+            }
+            None => TcpListener::bind(addr).await?,
+        };
+        let kept = unix::keep(&listener)?;
+        self.kept.lock().unwrap().push((addr, kept));
+        Ok(listener)
+    }
+
+    /// Stops keeping the socket bound to `addr`, the configured address,
+    /// for a listener that a reload removed.
+    pub fn release(&self, addr: SocketAddr) {
+        let mut kept = self.kept.lock().unwrap();
+        if let Some(index) = kept.iter().position(|(kept, _)| *kept == addr) {
+            kept.remove(index);
+        }
+    }
+
+    /// Closes the duplicates. Later restarts start a process that binds anew.
+    pub fn close(&self) {
+        self.kept.lock().unwrap().clear();
+    }
+
+    /// Starts the new process and waits until it is ready. `Ok` means this
+    /// process should now drain and exit.
+    pub async fn restart(&self) -> Result<(), String> {
+        if self.restarting.swap(true, Ordering::SeqCst) {
+            return Err("a restart is already in progress".to_string());
+        }
+        let listeners = unix::inheritable(&self.kept.lock().unwrap());
+        let result = match listeners {
+            Ok(listeners) => unix::restart(listeners).await,
+            Err(e) => Err(e),
+        };
+        self.restarting.store(false, Ordering::SeqCst);
+        result
+    }
+}
+
+/// Closes inherited sockets that no listener adopted, for instance after
+/// an address was removed from the config file. Call once everything is bound.
+pub fn close_unclaimed() {
+    for addr in unix::unclaimed() {
+        println!("[RESTART] closing the inherited listener on {}: no longer configured", addr);
+    }
+}
+
+/// Tells the old process, if there is one, that this one is ready to serve.
+pub async fn notify_ready() {
+    if let Err(e) = unix::notify_ready().await {
+        eprintln!("[RESTART] cannot tell the old process we are ready: {}", e);
+    }
+}
+
+/// Parses `LISTEN_FDS`; entries that do not parse are skipped.
+fn parse_fds(text: &str) -> Vec<(SocketAddr, i32)> {
+    text.split(',')
+        .filter_map(|entry| {
+            // `rsplit`: an IPv6 address has no `=`, but the fd is always last
+            let (addr, fd) = entry.rsplit_once('=')?;
+            Some((addr.parse().ok()?, fd.parse().ok()?))
+        })
+        .collect()
+}
+
+fn format_fds(fds: &[(SocketAddr, i32)]) -> String {
+    let entries: Vec<String> = fds.iter().map(|(addr, fd)| format!("{}={}", addr, fd)).collect();
+    entries.join(",")
+}
+
+#[cfg(unix)]
+mod unix {
+    use super::{LISTEN_FDS, READY_FD, READY_TIMEOUT, format_fds, parse_fds};
+    use socket2::SockRef;
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd};
+    use std::process::Stdio;
+    use std::sync::{LazyLock, Mutex};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    pub type Kept = OwnedFd;
+
+    /// The sockets inherited from the old process, until they are adopted.
+    static INHERITED: LazyLock<Mutex<HashMap<SocketAddr, OwnedFd>>> = LazyLock::new(|| {
+        let Ok(text) = std::env::var(LISTEN_FDS) else {
+            return Mutex::default();
+        };
+        let inherited = parse_fds(&text)
+            .into_iter()
+            // SAFETY: the old process put these descriptors in the
+            // environment for us alone; nothing else in this process
+            // knows them, so each one gets exactly one owner
+            .map(|(addr, fd)| (addr, unsafe { OwnedFd::from_raw_fd(fd) }))
+            .collect();
+        Mutex::new(inherited)
+    });
+
+    pub fn adopt(addr: SocketAddr) -> Option<std::net::TcpListener> {
+        INHERITED.lock().unwrap().remove(&addr).map(std::net::TcpListener::from)
+    }
+
+    pub fn unclaimed() -> Vec<SocketAddr> {
+        // Dropping the descriptors closes them
+        INHERITED.lock().unwrap().drain().map(|(addr, _)| addr).collect()
+    }
+
+    pub fn keep(listener: &tokio::net::TcpListener) -> std::io::Result<Kept> {
+        listener.as_fd().try_clone_to_owned()
+    }
+
+    /// Copies of the kept sockets that survive an exec.
+    pub fn inheritable(kept: &[(SocketAddr, Kept)]) -> Result<Vec<(SocketAddr, OwnedFd)>, String> {
+        let mut listeners = Vec::with_capacity(kept.len());
+        for (addr, fd) in kept {
+            let fd = fd.try_clone().map_err(|e| e.to_string())?;
+            // Descriptors are opened close-on-exec; these must stay open
+            SockRef::from(&fd).set_cloexec(false).map_err(|e| e.to_string())?;
+            listeners.push((*addr, fd));
+        }
+        Ok(listeners)
+    }
+
+    pub async fn notify_ready() -> std::io::Result<()> {
+        let Some(fd) = std::env::var(READY_FD).ok().and_then(|fd| fd.parse().ok()) else {
+            return Ok(());
+        };
+        // SAFETY: as with the listening sockets, the descriptor was passed to
+        // this process alone, and the variable is read only once, here
+        let stream = unsafe { std::os::unix::net::UnixStream::from_raw_fd(fd) };
+        stream.set_nonblocking(true)?;
+        let mut stream = tokio::net::UnixStream::from_std(stream)?;
+        stream.write_all(b"READY\n").await
+    }
+
+    pub async fn restart(listeners: Vec<(SocketAddr, OwnedFd)>) -> Result<(), String> {
+        let (ours, theirs) = std::os::unix::net::UnixStream::pair()
+            .map_err(|e| format!("cannot create the socketpair: {}", e))?;
+        SockRef::from(&theirs).set_cloexec(false).map_err(|e| e.to_string())?;
+        let fds: Vec<(SocketAddr, i32)> =
+            listeners.iter().map(|(addr, fd)| (*addr, fd.as_raw_fd())).collect();
+
+        let exe = std::env::current_exe().map_err(|e| e.to_string())?;
+        let mut child = tokio::process::Command::new(&exe)
+            .args(std::env::args_os().skip(1))
+            .env(LISTEN_FDS, format_fds(&fds))
+            .env(READY_FD, theirs.as_raw_fd().to_string())
+            // The old process's console keeps stdin
+            .stdin(Stdio::null())
+            .spawn()
+            .map_err(|e| format!("cannot start {}: {}", exe.display(), e))?;
+        println!("[RESTART] started {} as pid {}", exe.display(), child.id().unwrap_or(0));
+        // The child has its copies now; ours would keep the socketpair open
+        drop((theirs, listeners));
+
+        ours.set_nonblocking(true).map_err(|e| e.to_string())?;
+        let ours = tokio::net::UnixStream::from_std(ours).map_err(|e| e.to_string())?;
+        let mut line = String::new();
+        let mut reader = BufReader::new(ours);
+        let error = match tokio::time::timeout(READY_TIMEOUT, reader.read_line(&mut line)).await {
+            Ok(Ok(_)) if line.trim() == "READY" => return Ok(()),
+            // The child exited, or closed the socketpair without a word
+            Ok(_) => "the new process failed to start".to_string(),
+            Err(_) => format!("the new process was not ready within {:?}", READY_TIMEOUT),
+        };
+        let _ = child.kill().await;
+        Err(error)
+    }
+}
+
+#[cfg(not(unix))]
+mod unix {
+    use std::net::SocketAddr;
+
+    pub type Kept = ();
+
+    pub fn adopt(_addr: SocketAddr) -> Option<std::net::TcpListener> {
+        None
+    }
+
+    pub fn unclaimed() -> Vec<SocketAddr> {
+        Vec::new()
+    }
+
+    pub fn keep(_listener: &tokio::net::TcpListener) -> std::io::Result<Kept> {
+        Ok(())
+    }
+
+    pub fn inheritable(_kept: &[(SocketAddr, Kept)]) -> Result<Vec<(SocketAddr, Kept)>, String> {
+        Ok(Vec::new())
+    }
+
+    pub async fn notify_ready() -> std::io::Result<()> {
+        Ok(())
+    }
+
+    pub async fn restart(_listeners: Vec<(SocketAddr, Kept)>) -> Result<(), String> {
+        Err("restarts need Unix file descriptors".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listen_fds_round_trip() {
+        let fds = vec![
+            ("127.0.0.1:7000".parse().unwrap(), 3),
+            ("[::1]:7002".parse().unwrap(), 5),
+        ];
+        let text = format_fds(&fds);
+        assert_eq!(text, "127.0.0.1:7000=3,[::1]:7002=5");
+        assert_eq!(parse_fds(&text), fds);
+        // Garbage is skipped, not fatal
+        assert_eq!(parse_fds("nonsense,127.0.0.1:7000=x"), []);
+    }
+}
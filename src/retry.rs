@@ -0,0 +1,144 @@
+//! Retrying with exponential backoff, for connections the crate opens itself.
+//!
+//! A connect that fails once often succeeds a moment later: the upstream
+//! was restarting, its accept queue was full, or (with `--chaos`) the
+//! network was having a bad day. [`retry`] runs an operation again after
+//! a pause that doubles with every failure, up to a limit:
+//!
+//! ```text
+//! attempt 1 ─ fails ─ 100ms ─ attempt 2 ─ fails ─ 200ms ─ attempt 3 ─ ...
+//! ```
+//!
+//! Each pause is shortened by a random part (the jitter), so clients that
+//! failed together do not all come back at the same instant and fail
+//! together again. The pauses are `tokio::time::sleep`, so a retrying task
+//! costs nothing while it waits, and paused time skips them in tests.
+
+use std::future::Future;
+use std::hash::{BuildHasher, RandomState};
+use std::time::{Duration, SystemTime};
+
+/// How often, and how patiently, an operation is retried.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Policy {
+    /// Attempts in total, the first one included; at least 1.
+    pub max_attempts: u32,
+    /// The pause after the first failure.
+    pub initial_delay: Duration,
+    /// The pause never grows beyond this.
+    pub max_delay: Duration,
+    /// Up to this fraction of each pause is taken off at random, from 0 to 1.
+    pub jitter: f64,
+}
+
+impl Default for Policy {
+    /// A failing connect gives up after at most 300ms of pauses.
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(2),
+            jitter: 0.5,
+        }
+    }
+}
+
+impl Policy {
+    /// The pause after the `failures`-th failure, before any jitter.
+    pub fn backoff(&self, failures: u32) -> Duration {
+        // 2^(failures - 1), saturating long before it could overflow
+        let factor = 1u32 << failures.saturating_sub(1).min(16);
+        self.initial_delay.saturating_mul(factor).min(self.max_delay)
+    }
+
+    /// `backoff` with the jitter taken off.
+    fn pause(&self, failures: u32) -> Duration {
+        // Random bits without a dependency on `rand`, like session ids
+        let random = RandomState::new().hash_one(SystemTime::now()) as f64 / u64::MAX as f64;
+        self.backoff(failures).mul_f64(1.0 - self.jitter.clamp(0.0, 1.0) * random)
+    }
+}
+
+/// Runs `operation` until it succeeds or `policy.max_attempts` are used up,
+/// pausing between attempts. The error of the last attempt is returned.
+pub async fn retry<T, E, F, Fut>(policy: &Policy, mut operation: F) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut failures = 0;
+    loop {
+        match operation().await {
+            Ok(value) => return Ok(value),
+            Err(e) => {
+                failures += 1;
+                if failures >= policy.max_attempts {
+                    return Err(e);
+                }
+            }
+        }
+        tokio::time::sleep(policy.pause(failures)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::Instant;
+
+    const STEADY: Policy = Policy {
+        max_attempts: 4,
+        initial_delay: Duration::from_millis(100),
+        max_delay: Duration::from_millis(250),
+        jitter: 0.0,
+    };
+
+    #[test]
+    fn the_backoff_doubles_up_to_the_maximum() {
+        let pauses: Vec<u128> = (1..=4).map(|n| STEADY.backoff(n).as_millis()).collect();
+        assert_eq!(pauses, [100, 200, 250, 250]);
+        // A huge failure count does not overflow
+        assert_eq!(STEADY.backoff(u32::MAX), STEADY.max_delay);
+    }
+
+    #[test]
+    fn jitter_only_shortens_the_pause() {
+        let policy = Policy { jitter: 0.5, ..STEADY };
+        for _ in 0..100 {
+            let pause = policy.pause(2);
+            assert!(pause >= Duration::from_millis(100) && pause <= Duration::from_millis(200));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn an_operation_is_retried_until_it_succeeds() {
+        let start = Instant::now();
+        let mut attempts = 0;
+        let result: Result<u32, &str> = retry(&STEADY, || {
+            attempts += 1;
+            let attempt = attempts;
+            async move { if attempt < 3 { Err("refused") } else { Ok(attempt) } }
+        })
+        .await;
+
+        assert_eq!(result, Ok(3));
+        // Paused 100ms after the first failure, 200ms after the second
+        assert_eq!(start.elapsed(), Duration::from_millis(300));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_last_error_is_returned_once_the_attempts_are_used_up() {
+        let start = Instant::now();
+        let mut attempts = 0;
+        let result: Result<(), u32> = retry(&STEADY, || {
+            attempts += 1;
+            let attempt = attempts;
+            async move { Err(attempt) }
+        })
+        .await;
+
+        assert_eq!(result, Err(4));
+        // No pause after the last attempt
+        assert_eq!(start.elapsed(), Duration::from_millis(100 + 200 + 250));
+    }
+}
@@ -0,0 +1,325 @@
+//! Delayed and recurring jobs.
+//!
+//! A single task owns the queue: the jobs ordered by when they are due,
+//! and one timer of the server's [`Clock`], for the first of them. Anything
+//! holding a [`Scheduler`] handle can enqueue jobs; they travel to the task
+//! over a channel, so the queue itself is never shared or locked.
+//!
+//! Clients use it through the text protocol:
+//!
+//! ```text
+//! SCHEDULE <secs> <message>   -> OK: job #<id> runs in <secs>s
+//! ```
+//!
+//! and `--stats-every-secs` adds a recurring job that logs all metrics.
+//! Pending jobs are dropped when the server starts draining.
+//!
+//! The task runs under `supervise::spawn`, and the queue outlives it: if a
+//! job panics, the task is restarted with every other job still queued.
+//! The job that panicked is not run again, recurring or not.
+
+use crate::clock::{Clock, Sleep};
+use crate::logger::{LogMessage, LogSink};
+use crate::metrics::{self, Metrics};
+use crate::readiness::{self, Phase};
+use crate::supervise;
+use futures_util::SinkExt;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::{Mutex, mpsc, watch};
+use tokio::time::Instant;
+
+/// Upper bound on jobs waiting in the queue, recurring ones included.
+pub const MAX_PENDING: usize = 1000;
+
+/// The longest delay `SCHEDULE` accepts.
+pub const MAX_DELAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// What a job does when it is due.
+#[derive(Debug, Clone)]
+pub enum Job {
+    /// Writes a message to the log; enqueued by `SCHEDULE`.
+    Message { conn_id: u64, text: String },
+    /// Writes all metrics to the log, like SIGUSR1.
+    Stats,
+}
+
+struct Entry {
+    id: u64,
+    job: Job,
+    // Set when the job is enqueued, not when the task gets to it
+    due: Instant,
+    delay: Duration,
+    // Recurring jobs go back into the queue after every run
+    recurring: bool,
+}
+
+/// What the scheduler task keeps between runs.
+struct Queue {
+    rx: mpsc::Receiver<Entry>,
+    // By due time, then id: jobs due at once run in the order they came
+    entries: BTreeMap<(Instant, u64), Entry>,
+    log: LogSink,
+}
+
+/// Handle for enqueueing jobs; cheap to clone.
+#[derive(Clone)]
+pub struct Scheduler {
+    tx: mpsc::Sender<Entry>,
+    next_id: Arc<AtomicU64>,
+    pending: Arc<AtomicUsize>,
+    metrics: Metrics,
+    clock: Arc<dyn Clock>,
+}
+
+impl Scheduler {
+    /// Spawns the scheduler task; it runs until the server starts draining.
+    pub fn start(
+        log: LogSink,
+        metrics: Metrics,
+        phase: watch::Receiver<Phase>,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        let (tx, rx) = mpsc::channel(100);
+        let scheduler = Self {
+            tx,
+            next_id: Arc::new(AtomicU64::new(1)),
+            pending: Arc::new(AtomicUsize::new(0)),
+            metrics,
+            clock,
+        };
+        let queue = Arc::new(Mutex::new(Queue { rx, entries: BTreeMap::new(), log }));
+        let (handle, metrics) = (scheduler.clone(), scheduler.metrics.clone());
+        supervise::spawn("scheduler", metrics, phase.clone(), move || {
+            let (queue, scheduler, phase) = (queue.clone(), handle.clone(), phase.clone());
+            async move { run(&mut *queue.lock().await, scheduler, phase).await }
+        });
+        scheduler
+    }
+
+    /// Runs `job` once, after `delay`. Returns the job id.
+    pub fn after(&self, delay: Duration, job: Job) -> Result<u64, String> {
+        self.enqueue(delay, job, false)
+    }
+
+    /// Runs `job` every `interval`, first after one interval. Returns the job id.
+    pub fn every(&self, interval: Duration, job: Job) -> Result<u64, String> {
+        self.enqueue(interval, job, true)
+    }
+
+    fn enqueue(&self, delay: Duration, job: Job, recurring: bool) -> Result<u64, String> {
+        if delay > MAX_DELAY {
+            return Err(format!("delay must be at most {}s", MAX_DELAY.as_secs()));
+        }
+        // Reserve a slot first, so concurrent callers cannot overshoot the limit
+        if self.pending.fetch_add(1, Ordering::Relaxed) >= MAX_PENDING {
+            self.pending.fetch_sub(1, Ordering::Relaxed);
+            return Err("too many scheduled jobs".to_string());
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let due = self.clock.now() + delay;
+        // `try_send`: a handler must not wait for the scheduler task
+        if self.tx.try_send(Entry { id, job, due, delay, recurring }).is_err() {
+            self.pending.fetch_sub(1, Ordering::Relaxed);
+            return Err("scheduler is not accepting jobs".to_string());
+        }
+        self.metrics.adjust(metrics::JOBS_PENDING, 1);
+        Ok(id)
+    }
+
+    fn finished(&self, jobs: usize) {
+        self.pending.fetch_sub(jobs, Ordering::Relaxed);
+        self.metrics.adjust(metrics::JOBS_PENDING, -(jobs as i64));
+    }
+}
+
+/// Parses `SCHEDULE <secs> <message>`.
+///
+/// Returns `None` when the line is not a `SCHEDULE` command, like
+/// `KvCommand::parse`.
+pub fn parse_schedule(line: &str) -> Option<Result<(Duration, String), String>> {
+    let (name, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    if !name.eq_ignore_ascii_case("SCHEDULE") {
+        return None;
+    }
+
+    let usage = || "usage: SCHEDULE <secs> <message>".to_string();
+    let Some((secs, text)) = rest.trim().split_once(char::is_whitespace) else {
+        return Some(Err(usage()));
+    };
+    let Some(delay) = secs
+        .parse::<f64>()
+        .ok()
+        .filter(|s| s.is_finite() && *s >= 0.0)
+        .map(Duration::from_secs_f64)
+    else {
+        return Some(Err(usage()));
+    };
+    Some(Ok((delay, text.trim().to_string())))
+}
+
+async fn run(queue: &mut Queue, scheduler: Scheduler, mut phase: watch::Receiver<Phase>) {
+    let Queue { rx, entries: queue, log } = queue;
+    let clock = scheduler.clock.clone();
+    // The timer for the first job, replaced when an earlier one comes in
+    let mut timer: Option<((Instant, u64), Sleep)> = None;
+
+    loop {
+        if let Some(&first) = queue.keys().next()
+            && timer.as_ref().is_none_or(|(key, _)| *key != first)
+        {
+            timer = Some((first, clock.sleep_until(first.0)));
+        }
+        tokio::select! {
+            Some(entry) = rx.recv() => {
+                queue.insert((entry.due, entry.id), entry);
+            }
+            // Only enabled while jobs are queued
+            _ = async { (&mut timer.as_mut().unwrap().1).await }, if timer.is_some() => {
+                let (first, _) = timer.take().unwrap();
+                let mut entry = queue.remove(&first).unwrap();
+                // Counted out before it runs, in case it panics
+                scheduler.finished(1);
+                execute(&entry, &scheduler.metrics, log).await;
+                scheduler.metrics.incr(metrics::JOBS_RUN, 1);
+
+                if entry.recurring {
+                    scheduler.pending.fetch_add(1, Ordering::Relaxed);
+                    scheduler.metrics.adjust(metrics::JOBS_PENDING, 1);
+                    entry.due = clock.now() + entry.delay;
+                    queue.insert((entry.due, entry.id), entry);
+                }
+            }
+            _ = readiness::wait_draining(&mut phase) => break,
+        }
+    }
+
+    if !queue.is_empty() {
+        println!("[SCHEDULER] dropped {} pending job(s)", queue.len());
+        scheduler.finished(queue.len());
+        queue.clear();
+    }
+}
+
+async fn execute(entry: &Entry, metrics: &Metrics, log: &mut LogSink) {
+    let text = match &entry.job {
+        Job::Message { conn_id, text } => {
+            format!("job #{} from connection #{}: {}", entry.id, conn_id, text)
+        }
+        Job::Stats => format!("stats: {}", metrics::stat_summary(metrics)),
+    };
+    let _ = log.send(LogMessage::info(text)).await;
+}
+
+/// With time paused, a test waits for a job exactly as long as its delay,
+/// without sleeping for real: the clock jumps to the next timer whenever
+/// every task is idle.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{ManualClock, TokioClock};
+    use crate::logger::{self, LogOverflow, LogStream};
+    use crate::metrics::{InMemorySink, Metrics};
+    use futures_util::FutureExt;
+    use tokio_stream::StreamExt;
+
+    type Parts = (Scheduler, LogStream, Metrics, watch::Sender<Phase>);
+
+    fn scheduler() -> Parts {
+        scheduler_on(Arc::new(TokioClock))
+    }
+
+    fn scheduler_on(clock: Arc<dyn Clock>) -> Parts {
+        let metrics: Metrics = Arc::new(InMemorySink::default());
+        let (log_tx, log) = logger::channel(16, &LogOverflow::Wait, metrics.clone());
+        let (phase_tx, phase) = watch::channel(Phase::Ready);
+        let scheduler = Scheduler::start(LogSink::new(log_tx), metrics.clone(), phase, clock);
+        (scheduler, log, metrics, phase_tx)
+    }
+
+    fn pending(metrics: &Metrics) -> i64 {
+        let snapshot = metrics.snapshot();
+        snapshot.iter().find(|m| m.name == metrics::JOBS_PENDING).map_or(0, |m| m.value)
+    }
+
+    fn message(text: &str) -> Job {
+        Job::Message { conn_id: 7, text: text.to_string() }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_job_runs_once_its_delay_has_passed() {
+        let (scheduler, mut log, metrics, _phase) = scheduler();
+        let start = Instant::now();
+        let id = scheduler.after(Duration::from_secs(3), message("wake up")).unwrap();
+        assert_eq!(pending(&metrics), 1);
+
+        let logged = log.next().await.unwrap();
+        assert_eq!(start.elapsed(), Duration::from_secs(3));
+        let expected = format!("job #{} from connection #7: wake up", id);
+        assert_eq!(logged.text, expected.as_bytes());
+        assert_eq!(pending(&metrics), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn jobs_run_in_the_order_they_are_due() {
+        let (scheduler, mut log, _metrics, _phase) = scheduler();
+        let start = Instant::now();
+        scheduler.after(Duration::from_secs(5), message("second")).unwrap();
+        scheduler.after(Duration::from_secs(2), message("first")).unwrap();
+
+        for (text, at) in [("first", 2), ("second", 5)] {
+            let logged = log.next().await.unwrap();
+            assert!(logged.text.ends_with(text.as_bytes()));
+            assert_eq!(start.elapsed(), Duration::from_secs(at));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_recurring_job_runs_every_interval() {
+        let (scheduler, mut log, metrics, _phase) = scheduler();
+        let start = Instant::now();
+        scheduler.every(Duration::from_secs(10), Job::Stats).unwrap();
+
+        for round in 1..=3 {
+            let logged = log.next().await.unwrap();
+            assert!(logged.text.starts_with(b"stats: "));
+            assert_eq!(start.elapsed(), Duration::from_secs(10 * round));
+        }
+        // Back in the queue for the next round
+        assert_eq!(pending(&metrics), 1);
+    }
+
+    #[tokio::test]
+    async fn jobs_wait_for_the_clock_they_are_given() {
+        let clock = ManualClock::new();
+        let (scheduler, mut log, _metrics, _phase) = scheduler_on(Arc::new(clock.clone()));
+        scheduler.after(Duration::from_secs(3600), message("an hour later")).unwrap();
+        scheduler.after(Duration::from_secs(60), message("a minute later")).unwrap();
+
+        clock.advance(Duration::from_secs(3599));
+        let logged = log.next().await.unwrap();
+        assert!(logged.text.ends_with(b"a minute later"));
+        tokio::task::yield_now().await;
+        assert!(log.next().now_or_never().is_none());
+
+        clock.advance(Duration::from_secs(1));
+        let logged = log.next().await.unwrap();
+        assert!(logged.text.ends_with(b"an hour later"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn draining_drops_the_pending_jobs() {
+        let (scheduler, _log, metrics, phase) = scheduler();
+        scheduler.after(Duration::from_secs(60), message("never")).unwrap();
+        scheduler.every(Duration::from_secs(1), Job::Stats).unwrap();
+        tokio::task::yield_now().await;
+
+        phase.send(Phase::Draining).unwrap();
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        assert_eq!(pending(&metrics), 0);
+        assert!(scheduler.after(Duration::from_secs(1), message("late")).is_err());
+    }
+}
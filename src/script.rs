@@ -0,0 +1,261 @@
+//! Request scripts: a rhai script that sees every message, `--script`.
+//!
+//! The script defines one function, which the `script` layer of the
+//! middleware chain calls with each message and the client's address:
+//!
+//! ```text
+//! fn on_message(message, peer) {
+//!     if message == "ping" { return #{ reply: "pong" }; }   // answered here
+//!     if message.starts_with("shout ") { return message.sub_string(6).to_upper(); }
+//!     ()                                                    // served as sent
+//! }
+//! ```
+//!
+//! What it returns decides what happens to the message: `()` lets it
+//! through, a string replaces it for the rest of the chain and the
+//! handler, and a map with a `reply` answers the client without serving
+//! the message at all.
+//!
+//! The script is compiled once, at startup. Each call runs on a blocking
+//! thread with `spawn_blocking`, so a slow script never holds up a runtime
+//! thread, and gets a time budget (`--script-budget-ms`): the engine checks
+//! the clock between operations and stops a script that runs past it. Such
+//! a message, or one the script fails on, is answered with an error.
+//! `script_calls`, `script_errors` and `script_over_budget` count them.
+//!
+//! The engine is optional: without the `scripting` feature, `--script`
+//! refuses to start.
+
+use crate::metrics::{self, Metrics};
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// What the script made of a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    /// Served as it was sent.
+    Pass,
+    /// Served as this message instead.
+    Rewrite(String),
+    /// Answered with this, and not served.
+    Reply(String),
+}
+
+/// Why a call gave no verdict.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Failure {
+    /// Still running when the budget was spent.
+    OverBudget(Duration),
+    /// An error in the script, or a value it should not return.
+    Error(String),
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::OverBudget(budget) => write!(f, "ran longer than {:?}", budget),
+            Failure::Error(e) => f.write_str(e),
+        }
+    }
+}
+
+/// A compiled script, shared by every connection; cheap to clone.
+#[derive(Clone)]
+pub struct Script {
+    compiled: Arc<engine::Compiled>,
+    metrics: Metrics,
+}
+
+impl Script {
+    /// Reads and compiles the script at `path`.
+    pub async fn load(path: &Path, metrics: Metrics) -> Result<Self, String> {
+        let source = tokio::fs::read_to_string(path)
+            .await
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        let compiled = engine::compile(&source)
+            .map_err(|e| format!("cannot load {}: {}", path.display(), e))?;
+        Ok(Self { compiled: Arc::new(compiled), metrics })
+    }
+
+    /// Calls `on_message` on a blocking thread, for at most `budget`.
+    pub async fn on_message(
+        &self,
+        message: &str,
+        peer: SocketAddr,
+        budget: Duration,
+    ) -> Result<Verdict, Failure> {
+        self.metrics.incr(metrics::SCRIPT_CALLS, 1);
+        let (compiled, message) = (self.compiled.clone(), message.to_string());
+        let call = tokio::task::spawn_blocking(move || compiled.call(message, peer, budget));
+        let result = match call.await {
+            Ok(result) => result,
+            // The engine catches what the script does; this is a bug in it
+            Err(e) => Err(Failure::Error(format!("the engine panicked: {}", e))),
+        };
+        match &result {
+            Ok(_) => {}
+            Err(Failure::OverBudget(_)) => self.metrics.incr(metrics::SCRIPT_OVER_BUDGET, 1),
+            Err(Failure::Error(_)) => self.metrics.incr(metrics::SCRIPT_ERRORS, 1),
+        }
+        result
+    }
+}
+
+#[cfg(feature = "scripting")]
+mod engine {
+    use super::{Failure, Verdict};
+    use rhai::{AST, CallFnOptions, Dynamic, Engine, EvalAltResult, Map, Scope};
+    use std::cell::Cell;
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    thread_local! {
+        // When the call running on this thread must stop; the blocking
+        // thread runs one call at a time
+        static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+    }
+
+    pub struct Compiled {
+        engine: Engine,
+        ast: AST,
+    }
+
+    pub fn compile(source: &str) -> Result<Compiled, String> {
+        let mut engine = Engine::new();
+        // Called between operations; a value stops the script with `ErrorTerminated`
+        engine.on_progress(|_| {
+            let deadline = DEADLINE.with(Cell::get)?;
+            (Instant::now() >= deadline).then_some(Dynamic::UNIT)
+        });
+        let ast = engine.compile(source).map_err(|e| e.to_string())?;
+        if !ast.iter_functions().any(|f| f.name == "on_message" && f.params.len() == 2) {
+            return Err("the script has no `fn on_message(message, peer)`".to_string());
+        }
+        Ok(Compiled { engine, ast })
+    }
+
+    impl Compiled {
+        pub fn call(
+            &self,
+            message: String,
+            peer: SocketAddr,
+            budget: Duration,
+        ) -> Result<Verdict, Failure> {
+            DEADLINE.with(|deadline| deadline.set(Some(Instant::now() + budget)));
+            // The statements outside functions ran once, when it was compiled
+            let options = CallFnOptions::new().eval_ast(false);
+            let args = (message, peer.to_string());
+            let result = self.engine.call_fn_with_options::<Dynamic>(
+                options,
+                &mut Scope::new(),
+                &self.ast,
+                "on_message",
+                args,
+            );
+            DEADLINE.with(|deadline| deadline.set(None));
+            match result {
+                Ok(value) => verdict(value),
+                Err(e) if matches!(*e, EvalAltResult::ErrorTerminated(..)) => {
+                    Err(Failure::OverBudget(budget))
+                }
+                Err(e) => Err(Failure::Error(e.to_string())),
+            }
+        }
+    }
+
+    fn verdict(value: Dynamic) -> Result<Verdict, Failure> {
+        let type_name = value.type_name();
+        if value.is_unit() {
+            return Ok(Verdict::Pass);
+        }
+        if value.is_string() {
+            return Ok(Verdict::Rewrite(value.to_string()));
+        }
+        if let Some(reply) = value.try_cast::<Map>().and_then(|map| map.get("reply").cloned()) {
+            return Ok(Verdict::Reply(reply.to_string()));
+        }
+        let expected = "(), a string or #{ reply: .. }";
+        Err(Failure::Error(format!("on_message returned {}, expected {}", type_name, expected)))
+    }
+}
+
+#[cfg(not(feature = "scripting"))]
+mod engine {
+    use super::{Failure, Verdict};
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    pub struct Compiled;
+
+    pub fn compile(_source: &str) -> Result<Compiled, String> {
+        Err("--script needs a build with --features scripting".to_string())
+    }
+
+    impl Compiled {
+        pub fn call(&self, _: String, _: SocketAddr, _: Duration) -> Result<Verdict, Failure> {
+            Ok(Verdict::Pass)
+        }
+    }
+}
+
+#[cfg(all(test, feature = "scripting"))]
+mod tests {
+    use super::*;
+    use crate::metrics::{InMemorySink, MetricsSink};
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+
+    const PEER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+    const BUDGET: Duration = Duration::from_millis(50);
+
+    async fn compile(source: &str) -> Result<(Script, Arc<InMemorySink>), String> {
+        // Tests run side by side: each script gets a file of its own
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let name = format!("script-test-{}-{}.rhai", std::process::id(), NEXT.fetch_add(1, SeqCst));
+        let path = std::env::temp_dir().join(name);
+        tokio::fs::write(&path, source).await.unwrap();
+        let sink = Arc::new(InMemorySink::default());
+        let script = Script::load(&path, sink.clone()).await;
+        tokio::fs::remove_file(&path).await.unwrap();
+        script.map(|script| (script, sink))
+    }
+
+    #[tokio::test]
+    async fn the_script_passes_rewrites_or_answers_each_message() {
+        let source = r#"
+            fn on_message(message, peer) {
+                if message == "ping" { return #{ reply: "pong from " + peer }; }
+                if message.starts_with("shout ") { return message.sub_string(6).to_upper(); }
+                if message == "broken" { return 42; }
+                ()
+            }
+        "#;
+        let (script, sink) = compile(source).await.unwrap();
+        let call = |message| script.on_message(message, PEER, BUDGET);
+        assert_eq!(call("COUNT").await, Ok(Verdict::Pass));
+        assert_eq!(call("shout hello").await, Ok(Verdict::Rewrite("HELLO".to_string())));
+        assert_eq!(call("ping").await, Ok(Verdict::Reply("pong from 127.0.0.1:0".to_string())));
+        let Err(Failure::Error(e)) = call("broken").await else { panic!("42 is no verdict") };
+        assert!(e.starts_with("on_message returned i64"), "{}", e);
+
+        let count = |name| sink.snapshot().into_iter().find(|m| m.name == name).unwrap().value;
+        assert_eq!(count(metrics::SCRIPT_CALLS), 4);
+        assert_eq!(count(metrics::SCRIPT_ERRORS), 1);
+    }
+
+    #[tokio::test]
+    async fn a_script_that_runs_past_its_budget_is_stopped() {
+        let (script, sink) = compile("fn on_message(message, peer) { loop {} }").await.unwrap();
+        let result = script.on_message("anything", PEER, BUDGET).await;
+        assert_eq!(result, Err(Failure::OverBudget(BUDGET)));
+        let over = sink.snapshot().into_iter().find(|m| m.name == metrics::SCRIPT_OVER_BUDGET);
+        assert_eq!(over.map(|m| m.value), Some(1));
+
+        let missing = compile("fn on_request(message) { () }").await.err().unwrap();
+        assert!(missing.ends_with("the script has no `fn on_message(message, peer)`"));
+        assert!(compile("fn on_message(message, peer) {").await.is_err());
+    }
+}
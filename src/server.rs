@@ -0,0 +1,3498 @@
+//! The TCP server: startup, accepting connections and the text protocol.
+//!
+//! [`run`] is what the binary does: it owns the whole lifecycle, including
+//! warm-up, signals and shutdown. Embedders use [`Server`] instead and
+//! consume its [`Incoming`] stream of connections with their own loop.
+
+use crate::acl::AccessList;
+use crate::admin::Admin;
+use crate::auth;
+use crate::buffers::{BufferPool, BUFFER_SIZE};
+use crate::chaos::ChaosStream;
+use crate::clock::{Clock, TokioClock};
+use crate::close::{CloseReason, Drain};
+use crate::compute::{self, Compute, ComputeError};
+use crate::config::{ConfigRx, Reloader, RuntimeMode, ServerConfig};
+use crate::counting::CountingStream;
+use crate::dialer::Dialer;
+use crate::error::ServerError;
+use crate::expiring::{ExpiringMap, ExpiryMetrics};
+use crate::fanout::Subscription;
+use crate::flags::{FeatureFlags, FlagsRx};
+use crate::framing::{FrameError, Framing, MessageCodec};
+use crate::handshake::{self, Handshake, Mode};
+use crate::health;
+use crate::heartbeat::{self, Beat, Heartbeat, Pulse};
+use crate::history::{self, History};
+use crate::ingest::Ingest;
+use crate::kv::{KvCommand, Transaction, TxCommand};
+use crate::listeners::{ListenerMode, ListenerSpec, ListenerStop, Listeners};
+use crate::logger::{self, LogMessage, LogSink, LogTx, LoggerHandle};
+use crate::metrics::{self, Metrics};
+use crate::middleware::{self, Chain, Stop};
+use crate::outbox::{Outbox, Slot};
+use crate::peers;
+use crate::proxy::Upstreams;
+use crate::pubsub::{self, Broker, Delivery, Publish};
+use crate::queue::{Consumer, Queues};
+use crate::protocol::{Protocol, Request, Utf8Mode, Wire};
+use crate::quota::Identity;
+use crate::rate_limit::{ConnLimiter, IpLimiter};
+use crate::backpressure::{Backpressure, Change, SlowClient};
+use crate::readiness::{self, NotReadyPolicy, Phase};
+use crate::record::{Recorder, RecordingStream};
+use crate::restart::{self, Handoff};
+use crate::registry::{self, Registration, Registry};
+use crate::scheduler::{self, Job, Scheduler};
+use crate::script::Script;
+use crate::session::{Session, SessionId, SessionStats};
+use crate::shedding::{Load, Overload, ShedPolicy};
+use crate::futures_examples::WaitForStateMachine;
+use crate::state::{MAX_SLOWMODE, State};
+use crate::tail::Tailer;
+use crate::watcher::Watcher;
+use crate::template;
+use crate::throttle::ThrottledStream;
+use crate::tls::{self, Alpn};
+use crate::trace::{ConnTracer, TraceEvent};
+use crate::transport::Transport;
+use crate::upload::{self, Upload};
+use crate::vhost;
+use crate::wal::{self, Durability, Wal};
+use crate::wiredump::WireDump;
+use crate::workers::{PoolError, WorkerPool};
+#[cfg(feature = "grpc")]
+use crate::grpc;
+use crate::{checksum, http, http2, introspect, listener, persist, proxy, signals, socks};
+use crate::{replica, supervise, task, udp};
+#[cfg(all(target_os = "linux", feature = "uring"))]
+use crate::uring;
+use futures_util::stream::FuturesUnordered;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::ops::ControlFlow;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, ready};
+use bytes::{Buf, Bytes, BytesMut};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::io;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{mpsc, watch};
+use tokio::task::{JoinError, JoinSet};
+use tokio_stream::wrappers::{TcpListenerStream, WatchStream};
+use tokio_rustls::TlsAcceptor;
+use tokio_stream::{Stream, StreamExt};
+use tokio_util::codec::Decoder;
+use tokio_util::sync::CancellationToken;
+
+/// Test struct, used only to demonstrate move semantics
+#[derive(Debug)]
+struct Test {
+    test: i32,
+}
+
+/// Everything the accept loops and connection tasks share.
+///
+/// All fields are `Send + Sync`, so one instance can be used from several
+/// runtimes at once (see the thread-per-core mode).
+struct Shared {
+    /// The latest configuration; see `config` for what a reload can change.
+    config: ConfigRx,
+    state: Arc<State>,
+    log_tx: LogTx,
+    acl: watch::Receiver<AccessList>,
+    phase: watch::Receiver<Phase>,
+    // Switched from the admin interface; read before every message
+    flags: FlagsRx,
+    // Where the rate limiter, the heartbeat and the scheduler get the time
+    clock: Arc<dyn Clock>,
+    // One set of per-address buckets for the whole server
+    ip_limiter: Arc<IpLimiter>,
+    // Wrong `AUTH` tokens, per address
+    auth_failures: Arc<IpLimiter>,
+    registry: Arc<Registry>,
+    scheduler: Scheduler,
+    workers: WorkerPool,
+    // Answers `FIB` over request/response channels
+    compute: Compute,
+    // Read buffers, handed from finished connections to new ones
+    buffers: BufferPool,
+    // Where connections are piped in proxy mode
+    upstreams: Option<Upstreams>,
+    // Opens the SOCKS5 proxy's connections to its targets
+    dialer: Dialer,
+    // Follows log.txt for `TAIL`
+    tailer: Tailer,
+    // The topics of `PUBLISH` and `SUBSCRIBE`, with delayed and held messages
+    broker: Broker,
+    // The durable topics' queues, if there are any
+    queues: Option<Queues>,
+    // The request script of `--script`, compiled once
+    script: Option<Script>,
+    // What the logger prints, for the admin socket and embedders
+    logger: LoggerHandle,
+    // Every logged message, in SQLite, for `HISTORY`
+    history: Option<History>,
+    // Takes TLS listeners' connections through the handshake, if a certificate is set
+    tls: Option<TlsAcceptor>,
+    // Serves the TLS connections that negotiate `grpc`
+    #[cfg(feature = "grpc")]
+    grpc: Option<grpc::Handover>,
+    // Reports changes in the watched directory for `WATCH`, if there is one
+    watcher: Option<Watcher>,
+    // Sessions of closed connections, kept for `RESUME` during the grace period
+    sessions: ExpiringMap<SessionId, SessionStats>,
+    // The listening sockets, bound or inherited, for a restart to hand over
+    handoff: Arc<Handoff>,
+}
+
+/// The sending sides of the channels in `Shared`.
+///
+/// Whoever holds these drives the server's lifecycle; dropping them makes
+/// every receiver see a closed channel.
+struct Controls {
+    phase_tx: watch::Sender<Phase>,
+    config_tx: watch::Sender<Arc<ServerConfig>>,
+    acl_tx: watch::Sender<AccessList>,
+    flags_tx: watch::Sender<FeatureFlags>,
+    log_tx: LogTx,
+}
+
+impl Shared {
+    /// Starts what every server needs, however its connections are accepted:
+    /// metrics, the logger and the channels connections read from.
+    async fn start(config: ServerConfig) -> Result<(Arc<Shared>, Controls), ServerError> {
+        // Starting: set up only what the accept loop itself depends on
+        let (phase_tx, phase_rx) = watch::channel(Phase::Starting);
+
+        let handoff = Arc::new(Handoff::default());
+        let metrics = metrics::start(&config.metrics, &handoff)
+            .await
+            .map_err(ServerError::Metrics)?;
+
+        // Shared state for all connections
+        let state = Arc::new(State::with_counter(metrics, config.counter));
+        // Removes keys as their TTLs run out, not only when they are next used
+        state.kv.spawn_sweeper();
+        if config.durability != Durability::None && config.state_file.is_none() {
+            let text = "--durability needs --state-file, the log is kept next to it";
+            return Err(ServerError::Config(text.to_string()));
+        }
+        if config.durability != Durability::None && config.replica_of.is_some() {
+            let text = "--replica-of takes no --durability, the primary's changes are not logged";
+            return Err(ServerError::Config(text.to_string()));
+        }
+
+        // The time for the rate limiter, the heartbeat and the scheduler
+        let clock: Arc<dyn Clock> = Arc::new(TokioClock);
+
+        // Channel used for logging client input and task failures.
+        // mpsc = many producers (client handlers), single consumer (logger task)
+        let metrics = state.metrics.clone();
+        let (log_tx, log_stream) = logger::channel(100, &config.log_overflow, metrics);
+
+        // Delayed and recurring jobs, including those clients enqueue
+        let scheduler = Scheduler::start(
+            LogSink::new(log_tx.clone()),
+            state.metrics.clone(),
+            phase_rx.clone(),
+            clock.clone(),
+        );
+        if let Some(interval) = config.stats_interval {
+            scheduler.every(interval, Job::Stats).map_err(ServerError::Config)?;
+        }
+
+        // CPU-heavy commands run here, off the runtime threads
+        let metrics = state.metrics.clone();
+        let workers = WorkerPool::start(config.hash_workers, config.hash_queue, metrics);
+        let compute = Compute::start(state.metrics.clone());
+
+        // Closed connections' sessions, until their grace period runs out
+        let names =
+            ExpiryMetrics { live: metrics::SESSIONS_SAVED, expired: metrics::SESSIONS_EXPIRED };
+        let sessions = ExpiringMap::new(config.session_grace, state.metrics.clone(), names);
+
+        // The services the server depends on, probed in the background
+        if !config.health.targets.is_empty() {
+            let (statuses, metrics) = (state.health.clone(), state.metrics.clone());
+            let phase = phase_rx.clone();
+            health::start(&config.health, statuses, log_tx.clone(), metrics, clock.clone(), phase);
+        }
+
+        // Proxy mode: the upstreams, checked in the background
+        let upstreams = (!config.proxy.is_empty()).then(|| {
+            let (interval, metrics) = (config.proxy_health_interval, state.metrics.clone());
+            Upstreams::start(&config.proxy, interval, config.circuit, metrics, phase_rx.clone())
+        });
+
+        // The certificate of the TLS listeners, and the gRPC server behind them
+        let tls = match (&config.tls_cert, &config.tls_key) {
+            (Some(cert), Some(key)) => {
+                Some(tls::acceptor(cert, key, config.tls_client_ca.as_deref()).await?)
+            }
+            (None, None) => None,
+            _ => {
+                let text = "--tls-cert and --tls-key must be set together";
+                return Err(ServerError::Config(text.to_string()));
+            }
+        };
+        #[cfg(feature = "grpc")]
+        let grpc = tls.as_ref().map(|_| {
+            grpc::Handover::start(state.clone(), log_tx.clone(), phase_rx.clone())
+        });
+
+        // The feeds for TAIL, SUBSCRIBE and, if a directory is configured, WATCH
+        let tailer =
+            Tailer::start(PathBuf::from(STDIN_LOG), state.metrics.clone(), phase_rx.clone());
+        let broker = Broker::start(state.metrics.clone(), phase_rx.clone());
+        let watcher = match &config.watch_dir {
+            Some(dir) => {
+                let metrics = state.metrics.clone();
+                Some(Watcher::start(dir, log_tx.clone(), metrics, phase_rx.clone())?)
+            }
+            None => None,
+        };
+        // The durable topics, with the messages still waiting for an ACK
+        let queues = match &config.queue_dir {
+            Some(dir) if !config.durable_topics.is_empty() => {
+                let (topics, metrics) = (&config.durable_topics, state.metrics.clone());
+                let opened = Queues::open(dir, topics, metrics).await;
+                let queues =
+                    opened.map_err(|source| ServerError::File { path: dir.clone(), source })?;
+                println!("[QUEUE] {} durable topic(s) in {}", topics.len(), dir.display());
+                Some(queues)
+            }
+            _ => None,
+        };
+        let script = match &config.script {
+            Some(path) => {
+                let script = Script::load(path, state.metrics.clone()).await;
+                let script = script.map_err(ServerError::Config)?;
+                println!("[SCRIPT] {} sees every request", path.display());
+                Some(script)
+            }
+            None => None,
+        };
+        let history = match &config.history_db {
+            Some(path) => {
+                let history = History::open(path, state.metrics.clone()).await;
+                let history = history.map_err(ServerError::Config)?;
+                println!("[HISTORY] logging to {}", path.display());
+                Some(history)
+            }
+            None => None,
+        };
+
+        // Every part of the server that can be reconfigured holds a receiver;
+        // the reloader keeps the sender.
+        let (config_tx, config_rx) = watch::channel(Arc::new(config));
+        // Hands out the per-client quotas every listener consults
+        state.quotas.spawn(config_rx.clone(), clock.clone());
+
+        // The last lines printed, for the admin `RECENT` and `FOLLOW` commands
+        let logger = LoggerHandle::new(RECENT_LINES);
+        // Restarted if it panics; the messages still queued wait for the next run
+        let log_stream = Arc::new(tokio::sync::Mutex::new(log_stream));
+        let (config, metrics, printed) = (config_rx.clone(), state.metrics.clone(), logger.clone());
+        let logged = history.clone();
+        supervise::spawn("logger", state.metrics.clone(), phase_rx.clone(), move || {
+            let (log_stream, config) = (log_stream.clone(), config.clone());
+            let (metrics, printed, history) = (metrics.clone(), printed.clone(), logged.clone());
+            async move {
+                let mut messages = log_stream.lock().await;
+                logger::run(&mut messages, config, metrics, printed, history).await;
+            }
+        });
+        // Forgets client addresses that stopped coming, and logs the others
+        let sweep =
+            peers::sweep(state.clone(), config_rx.clone(), log_tx.clone(), phase_rx.clone());
+        task::spawn("peer sweeper", sweep);
+        // Closes connections idle for longer than `session_ttl`, if set
+        let registry = Arc::new(Registry::default());
+        let sweep = registry::sweep_idle(
+            registry.clone(),
+            config_rx.clone(),
+            log_tx.clone(),
+            phase_rx.clone(),
+        );
+        task::spawn("idle sweeper", sweep);
+        // Samples the runtime's own metrics, if asked to
+        if let Some(interval) = config_rx.borrow().runtime_stats_interval {
+            let metrics = state.metrics.clone();
+            let sample = introspect::run(interval, metrics, log_tx.clone(), phase_rx.clone());
+            task::spawn("runtime stats", sample);
+        }
+
+        // The access list is published through a watch channel so that a reload
+        // affects the very next accepted connection without a restart.
+        // It is filled in during warm-up.
+        let (acl_tx, acl_rx) = watch::channel(AccessList::default());
+        // Feature flags travel the same way, from the admin interface
+        let (flags_tx, flags_rx) = watch::channel(FeatureFlags::default());
+
+        let buffers = BufferPool::new(state.metrics.clone());
+        let dialer = Dialer::new().with_metrics(state.metrics.clone());
+        let shared = Arc::new(Shared {
+            config: config_rx,
+            state,
+            log_tx: log_tx.clone(),
+            acl: acl_rx,
+            phase: phase_rx,
+            flags: flags_rx,
+            clock,
+            ip_limiter: Arc::new(IpLimiter::default()),
+            auth_failures: Arc::new(IpLimiter::default()),
+            registry,
+            scheduler,
+            workers,
+            compute,
+            buffers,
+            upstreams,
+            dialer,
+            tailer,
+            broker,
+            queues,
+            script,
+            logger,
+            history,
+            tls,
+            #[cfg(feature = "grpc")]
+            grpc,
+            watcher,
+            sessions,
+            handoff,
+        });
+        let controls = Controls { phase_tx, config_tx, acl_tx, flags_tx, log_tx };
+
+        Ok((shared, controls))
+    }
+}
+
+/// Starts the background tasks and the accept loop(s), then waits for a shutdown signal.
+///
+/// Only startup failures are returned; errors of individual connections
+/// are logged and never stop the server.
+pub async fn run(config: ServerConfig) -> Result<(), ServerError> {
+    start(config).await?.wait().await
+}
+
+/// A server started by [`start`]: bound and accepting, but not warmed up
+/// until [`wait`](RunningServer::wait) runs.
+pub struct RunningServer {
+    shared: Arc<Shared>,
+    config: Arc<ServerConfig>,
+    local_addr: SocketAddr,
+    listeners: Arc<Listeners>,
+    phase_tx: watch::Sender<Phase>,
+    acl_tx: watch::Sender<AccessList>,
+    admin: Arc<Admin>,
+    shutdown_tx: mpsc::Sender<&'static str>,
+    shutdown_rx: mpsc::Receiver<&'static str>,
+    drained_rx: mpsc::Receiver<()>,
+}
+
+/// Lets code other than signals and the admin socket stop a running server.
+#[derive(Clone)]
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    shutdown_tx: mpsc::Sender<&'static str>,
+}
+
+impl ServerHandle {
+    /// The address of the main listener; with port 0, the port the OS chose.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Starts draining, like `SIGTERM`. `wait` returns once it is done.
+    pub fn shutdown(&self) {
+        // Full means a shutdown is already on its way
+        let _ = self.shutdown_tx.try_send("shutdown requested");
+    }
+}
+
+/// Binds the listeners and starts the background tasks and accept loops.
+///
+/// Connections are accepted right away, but wait in the queue until the
+/// returned server's `wait` has warmed up; it then serves until a shutdown
+/// signal or [`ServerHandle::shutdown`]. Tests bind port 0 and connect to
+/// [`RunningServer::local_addr`].
+pub async fn start(config: ServerConfig) -> Result<RunningServer, ServerError> {
+    let (shared, controls) = Shared::start(config).await?;
+    let Controls { phase_tx, config_tx, acl_tx, flags_tx, log_tx } = controls;
+    let config = shared.config.borrow().clone();
+
+    // This background task demonstrates how a custom Future is used in practice.
+    // It polls in a busy loop, so embedded servers (`Server::bind`) leave it out.
+    // So does the io_uring backend: its runtime would never park, and
+    // tokio-uring only submits to the ring when it does.
+    if config.runtime != RuntimeMode::Uring {
+        let wait_state = shared.state.clone();
+        task::spawn("state watcher", async move {
+            let reached = WaitForStateMachine::new(wait_state).await;
+            println!("{}", reached);
+        });
+    }
+
+    // TCP server; with port 0 the first bind picks the port and the
+    // others, if any, share it
+    let listen = config.listen;
+    let bind_error = |source| ServerError::Bind { addr: listen.to_string(), source };
+    let mut addr = listen;
+    // Every accept loop holds a sender; `recv` returns `None` once all of
+    // them have drained their connections and returned
+    let (drained_tx, drained_rx) = mpsc::channel::<()>(1);
+
+    match config.runtime {
+        RuntimeMode::ThreadPerCore => {
+            // Bind every listener up front so bind errors are reported here
+            let mut listeners = Vec::with_capacity(config.workers);
+            for _ in 0..config.workers {
+                let listener = listener::bind_reuse_port(addr).map_err(bind_error)?;
+                addr = listener.local_addr().map_err(bind_error)?;
+                listeners.push(listener);
+            }
+            for (worker, listener) in listeners.into_iter().enumerate() {
+                spawn_worker_thread(worker, listener, shared.clone(), drained_tx.clone());
+            }
+            println!(
+                "Server listening on {} ({} thread-per-core workers)",
+                addr, config.workers,
+            );
+        }
+        RuntimeMode::MultiAcceptor => {
+            let mut listeners = Vec::with_capacity(config.workers);
+            for _ in 0..config.workers {
+                let listener = listener::bind_reuse_port(addr).map_err(bind_error)?;
+                addr = listener.local_addr().map_err(bind_error)?;
+                listeners.push(TcpListener::from_std(listener).map_err(bind_error)?);
+            }
+            // One accept loop per socket, all on the shared multi-thread runtime.
+            // The kernel picks a socket for each new connection, so the loops
+            // never contend for one accept queue; the connection tasks they
+            // spawn are still balanced across threads by work stealing.
+            for (index, listener) in listeners.into_iter().enumerate() {
+                let acceptor = metrics::acceptor_connections(index);
+                // Listed in STATS from the start, even before its first connection
+                shared.state.metrics.incr(acceptor, 0);
+                let server = Server::listening(listener, shared.clone(), Some(acceptor));
+                let name = format!("accept loop {}", index);
+                task::spawn(&name, accept_loop(server, drained_tx.clone()));
+            }
+            println!("Server listening on {} ({} SO_REUSEPORT acceptors)", addr, config.workers);
+        }
+        RuntimeMode::Local => {
+            let listener = shared.handoff.bind(addr).await.map_err(bind_error)?;
+            addr = listener.local_addr().map_err(bind_error)?;
+            let server = Server::listening(listener, shared.clone(), None);
+            // `run` itself runs inside the `LocalSet` built by `main`
+            tokio::task::spawn_local(local_accept_loop(server, drained_tx.clone()));
+            println!("Server listening on {} (LocalSet, spawn_local)", addr);
+        }
+        #[cfg(all(target_os = "linux", feature = "uring"))]
+        RuntimeMode::Uring => {
+            // tokio-uring's listener cannot adopt a socket, but it binds with
+            // `SO_REUSEPORT`: a restarted process binds next to this one
+            let listener = uring::TcpListener::bind(addr).map_err(bind_error)?;
+            addr = listener.local_addr().map_err(bind_error)?;
+            // `run` itself runs on tokio-uring's runtime, started by `main`
+            let accepting = uring_accept_loop(listener, shared.clone(), drained_tx.clone());
+            tokio::task::spawn_local(accepting);
+            println!("Server listening on {} (io_uring)", addr);
+        }
+        #[cfg(not(all(target_os = "linux", feature = "uring")))]
+        RuntimeMode::Uring => {
+            let text = "--runtime uring needs a build on Linux with --features uring";
+            return Err(ServerError::Config(text.to_string()));
+        }
+        RuntimeMode::MultiThread | RuntimeMode::CurrentThread => {
+            let listener = shared.handoff.bind(addr).await.map_err(bind_error)?;
+            addr = listener.local_addr().map_err(bind_error)?;
+            let server = Server::listening(listener, shared.clone(), None);
+            task::spawn("accept loop", accept_loop(server, drained_tx.clone()));
+            println!("Server listening on {}", addr);
+        }
+    }
+    if let Some(chaos) = &config.chaos {
+        println!("[CHAOS] client connections misbehave: {}", chaos);
+    }
+
+    // The other listeners: each one its own accept loop, all sharing the
+    // state, the logger and the drain
+    let listeners = Arc::new(Listeners::new(shared.state.metrics.clone()));
+    for spec in config.listener_specs() {
+        let local_addr = start_listener(spec, &shared, &listeners, &drained_tx).await?;
+        println!("{} on {}", spec.mode.describe(), local_addr);
+    }
+    // Binds and stops them as reloads change the list
+    let manager = manage_listeners(shared.clone(), listeners.clone(), drained_tx.clone());
+    task::spawn("listener manager", manager);
+    drop(drained_tx);
+
+    if let Some(http_addr) = config.http_addr {
+        let http_listener = shared
+            .handoff
+            .bind(http_addr)
+            .await
+            .map_err(|source| ServerError::Bind { addr: http_addr.to_string(), source })?;
+        println!("Health endpoint on http://{}/healthz and /readyz", http_addr);
+        let context = http::Context {
+            config: shared.config.clone(),
+            dialer: shared.dialer.clone(),
+            log_tx: log_tx.clone(),
+            metrics: shared.state.metrics.clone(),
+            health: shared.state.health.subscribe(),
+            flags: shared.flags.clone(),
+            registry: shared.registry.clone(),
+            quotas: shared.state.quotas.clone(),
+        };
+        let health = http::serve_health(http_listener, shared.phase.clone(), context);
+        task::spawn("health endpoint", health);
+    }
+
+    // Echo and the counter over HTTP/2, served with the h2 crate directly
+    if let Some(h2_addr) = config.h2_addr {
+        let h2_listener = shared
+            .handoff
+            .bind(h2_addr)
+            .await
+            .map_err(|source| ServerError::Bind { addr: h2_addr.to_string(), source })?;
+        println!("HTTP/2 endpoint on http://{} (prior knowledge)", h2_addr);
+        let (state, phase) = (shared.state.clone(), shared.phase.clone());
+        task::spawn("h2 endpoint", http2::serve(h2_listener, state, log_tx.clone(), phase));
+    }
+
+    // The same over UDP; a restart binds it anew rather than handing it over
+    if let Some(udp_addr) = config.udp_addr {
+        let socket = UdpSocket::bind(udp_addr)
+            .await
+            .map_err(|source| ServerError::Bind { addr: udp_addr.to_string(), source })?;
+        println!("UDP echo and counter on {}", udp_addr);
+        let (state, phase) = (shared.state.clone(), shared.phase.clone());
+        task::spawn("udp socket", udp::serve(socket, state, log_tx.clone(), phase));
+    }
+
+    // The counter and echo over gRPC, on a port of their own
+    #[cfg(feature = "grpc")]
+    if let Some(grpc_addr) = config.grpc_addr {
+        let grpc_listener = shared
+            .handoff
+            .bind(grpc_addr)
+            .await
+            .map_err(|source| ServerError::Bind { addr: grpc_addr.to_string(), source })?;
+        println!("gRPC service on {}", grpc_addr);
+        let (state, phase) = (shared.state.clone(), shared.phase.clone());
+        task::spawn("grpc server", grpc::serve(grpc_listener, state, log_tx.clone(), phase));
+    }
+    #[cfg(not(feature = "grpc"))]
+    if config.grpc_addr.is_some() {
+        return Err(ServerError::Config("--grpc needs a build with --features grpc".to_string()));
+    }
+
+    // Signals, the admin socket and `ServerHandle` can ask for a shutdown;
+    // the first two can also ask for a reload
+    let (shutdown_tx, shutdown_rx) = mpsc::channel(1);
+    let reloader = Reloader {
+        config_tx,
+        acl_tx: acl_tx.clone(),
+        phase: shared.phase.clone(),
+    };
+
+    // Serves the control socket, if configured, and the stdin console
+    let admin = Arc::new(Admin {
+        state: shared.state.clone(),
+        registry: shared.registry.clone(),
+        listeners: listeners.clone(),
+        reloader: reloader.clone(),
+        shutdown_tx: shutdown_tx.clone(),
+        flags_tx,
+        log_tx: log_tx.clone(),
+        logger: shared.logger.clone(),
+    });
+    if let Some(admin_addr) = &config.admin_addr {
+        admin.clone().spawn(admin_addr, &shared.handoff).await?;
+    }
+    restart::close_unclaimed();
+
+    // From here on, SIGINT/SIGTERM/SIGHUP/SIGUSR1/SIGUSR2 are handled by the dispatcher
+    signals::Dispatcher {
+        reloader,
+        log_tx: log_tx.clone(),
+        metrics: shared.state.metrics.clone(),
+        handoff: shared.handoff.clone(),
+        shutdown_tx: shutdown_tx.clone(),
+    }
+    .spawn()?;
+
+    Ok(RunningServer {
+        shared,
+        config,
+        local_addr: addr,
+        listeners,
+        phase_tx,
+        acl_tx,
+        admin,
+        shutdown_tx,
+        shutdown_rx,
+        drained_rx,
+    })
+}
+
+impl RunningServer {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// The listeners bound besides the main one, with their actual ports.
+    pub fn listeners(&self) -> Arc<Listeners> {
+        self.listeners.clone()
+    }
+
+    pub fn handle(&self) -> ServerHandle {
+        ServerHandle { local_addr: self.local_addr, shutdown_tx: self.shutdown_tx.clone() }
+    }
+
+    /// What the logger prints, to read or follow from outside the server.
+    pub fn logger(&self) -> LoggerHandle {
+        self.shared.logger.clone()
+    }
+
+    /// Warms up, serves until asked to shut down, then drains the
+    /// connections and saves the state.
+    pub async fn wait(self) -> Result<(), ServerError> {
+        let RunningServer {
+            shared,
+            config,
+            phase_tx,
+            acl_tx,
+            admin,
+            mut shutdown_rx,
+            mut drained_rx,
+            ..
+        } = self;
+        // Warming: startup tasks run concurrently with the accept loop,
+        // so early clients are already accepted (and rejected or queued)
+        set_phase(&phase_tx, Phase::Warming);
+        tokio::select! {
+            result = warm_up(&config, &shared.state, &acl_tx, admin, shared.phase.clone()) => {
+                result?;
+                set_phase(&phase_tx, Phase::Ready);
+                // After a restart, the old process can now drain
+                restart::notify_ready().await;
+            }
+            _ = shutdown_rx.recv() => {
+                set_phase(&phase_tx, Phase::Draining);
+                drained_rx.recv().await;
+                shared.handoff.close();
+                return Ok(());
+            }
+        }
+
+        if let Some(primary) = config.replica_of.clone() {
+            let (state, phase) = (shared.state.clone(), shared.phase.clone());
+            task::spawn("replica", replica::follow(primary, state, phase));
+        }
+
+        let snapshots = config.state_file.clone().map(|path| {
+            let state = shared.state.clone();
+            let phase = shared.phase.clone();
+            let snapshots = persist::run(path, config.snapshot_interval, state, phase);
+            task::spawn("state snapshots", snapshots)
+        });
+
+        shutdown_rx.recv().await;
+        // Draining: accept loops stop, queued connections give up and open
+        // ones are told to disconnect; wait for them before returning
+        set_phase(&phase_tx, Phase::Draining);
+        drained_rx.recv().await;
+        println!("[PHASE] all connections closed");
+        shared.handoff.close();
+
+        // The periodic task has stopped by now, so the two never write at once
+        if let (Some(snapshots), Some(path)) = (snapshots, &config.state_file) {
+            let saved = snapshots.await.unwrap_or_default();
+            let snapshot = shared.state.snapshot();
+            if snapshot != saved {
+                match persist::save(path, &snapshot).await {
+                    Ok(()) => {
+                        println!("[STATE] saved to {}", path.display());
+                        shared.state.kv.compact_wal(snapshot.wal_seq);
+                    }
+                    Err(e) => eprintln!("[STATE] cannot save {}: {}", path.display(), e),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Runs one worker of the thread-per-core mode.
+///
+/// The thread owns a current_thread runtime and its own `SO_REUSEPORT`
+/// listener. Connections it accepts are spawned on the same runtime, so
+/// a connection never moves between threads and there is no work stealing.
+fn spawn_worker_thread(
+    worker: usize,
+    listener: std::net::TcpListener,
+    shared: Arc<Shared>,
+    drained: mpsc::Sender<()>,
+) {
+    std::thread::Builder::new()
+        .name(format!("worker-{}", worker))
+        .spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("failed to build a worker runtime");
+
+            runtime.block_on(async move {
+                // `from_std` registers the socket with the reactor of the
+                // runtime that is current here: this worker's runtime
+                match TcpListener::from_std(listener) {
+                    Ok(listener) => {
+                        let acceptor = metrics::acceptor_connections(worker);
+                        shared.state.metrics.incr(acceptor, 0);
+                        let acceptor = Some(acceptor);
+                        let server = Server::listening(listener, shared, acceptor);
+                        accept_loop(server, drained).await
+                    }
+                    Err(e) => eprintln!("worker {}: cannot register listener: {}", worker, e),
+                }
+            });
+        })
+        .expect("failed to spawn a worker thread");
+}
+
+/// Binds one of the listeners besides the main one and starts its accept
+/// loop, with the heartbeat of the current config. Returns the address it
+/// was bound to.
+async fn start_listener(
+    spec: ListenerSpec,
+    shared: &Arc<Shared>,
+    listeners: &Arc<Listeners>,
+    drained_tx: &mpsc::Sender<()>,
+) -> Result<SocketAddr, ServerError> {
+    if spec.mode == ListenerMode::Tls && shared.tls.is_none() {
+        let text = "a TLS listener needs --tls-cert and --tls-key";
+        return Err(ServerError::Config(text.to_string()));
+    }
+    let bind_error = |source| ServerError::Bind { addr: spec.addr.to_string(), source };
+    let listener = shared.handoff.bind(spec.addr).await.map_err(bind_error)?;
+    let local_addr = listener.local_addr().map_err(bind_error)?;
+    let (accepted, stop) = listeners.add(spec, local_addr);
+    let config = shared.config.borrow().clone();
+    let server = Server::listening(listener, shared.clone(), Some(accepted));
+    let server = match spec.mode {
+        ListenerMode::Text => server,
+        ListenerMode::Length => server
+            .with_framing(Framing::LengthPrefixed)
+            .with_heartbeat(config.heartbeat(config.length_heartbeat_idle)),
+        ListenerMode::Json => server
+            .with_protocol(Protocol::Json)
+            .with_heartbeat(config.heartbeat(config.json_heartbeat_idle)),
+        // Tunnels carry someone else's bytes: no heartbeat
+        ListenerMode::Socks => server.with_protocol(Protocol::Socks5).with_heartbeat(None),
+        ListenerMode::Tls => server.with_protocol(Protocol::Tls),
+    };
+    let server = Server { stop: stop.clone(), ..server };
+
+    let (listeners, drained_tx) = (listeners.clone(), drained_tx.clone());
+    let name = format!("{} accept loop on {}", spec.mode, local_addr);
+    task::spawn(&name, async move {
+        accept_loop(server, drained_tx).await;
+        if stop.requested.is_cancelled() {
+            listeners.remove(local_addr);
+            println!("[CONFIG] the {} listener on {} is gone", spec.mode, local_addr);
+        }
+    });
+    Ok(local_addr)
+}
+
+/// The listener manager: after every reload, binds the listeners the new
+/// config adds and stops those it removes, until the server drains.
+///
+/// The removed ones close their sockets before the new ones are bound, so
+/// a reload can move an address from one mode to another. A listener that
+/// cannot be bound is reported, and tried again at the next reload.
+async fn manage_listeners(
+    shared: Arc<Shared>,
+    listeners: Arc<Listeners>,
+    drained_tx: mpsc::Sender<()>,
+) {
+    let (mut config, mut phase) = (shared.config.clone(), shared.phase.clone());
+    config.borrow_and_update();
+    loop {
+        tokio::select! {
+            _ = readiness::wait_draining(&mut phase) => return,
+            changed = config.changed() => if changed.is_err() {
+                return;
+            },
+        }
+        let wanted = config.borrow_and_update().listener_specs();
+        let changes = listeners.reconcile(&wanted);
+        for retired in &changes.retired {
+            shared.handoff.release(retired.spec.addr);
+            retired.closed.cancelled().await;
+            let (mode, addr) = (retired.spec.mode, retired.addr);
+            println!("[CONFIG] stopped the {} listener on {}, its connections drain", mode, addr);
+        }
+        for spec in changes.bind {
+            match start_listener(spec, &shared, &listeners, &drained_tx).await {
+                Ok(addr) => println!("[CONFIG] {} on {}", spec.mode.describe(), addr),
+                Err(e) => {
+                    let text = format!("cannot add the {} listener: {}", spec.mode, e);
+                    shared.log_tx.send(LogMessage::error(text)).await;
+                }
+            }
+        }
+    }
+}
+
+/// How long an accept loop waits for its connections when draining,
+/// before aborting the ones that are still open.
+const DRAIN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Spawns a task for every connection until the server starts draining,
+/// then waits for those tasks to finish.
+///
+/// The tasks run on whichever runtime runs this loop; see [`Supervisor`]
+/// for how they are tracked.
+///
+/// `_drained` is dropped when the loop returns; [`run`] waits until every
+/// accept loop has dropped its sender.
+async fn accept_loop(server: Server, _drained: mpsc::Sender<()>) {
+    let mut supervisor = Supervisor::new(server.shared.clone());
+    let (mut flags, mut phase) = (server.shared.flags.clone(), server.shared.phase.clone());
+    let mut paused = flags.borrow_and_update().accept_paused;
+    let stop = server.stop.clone();
+    // Boxed rather than pinned in place, so the socket can close before
+    // the connections do
+    let mut connections = Box::pin(connections(server));
+
+    loop {
+        tokio::select! {
+            // The stream keeps its pending accept between polls, so losing
+            // the race to `join_next` does not lose a connection. While
+            // paused it is not polled at all: `accept` is not called.
+            conn = connections.next(), if !paused => {
+                let Some(conn) = conn else { break };
+                let label = format!("connection #{} from {}", conn.id(), conn.peer_addr());
+
+                // Used only to demonstrate ownership transfer into the spawned task
+                let test = Test{ test: 1 };
+
+                // Each connection is handled in a separate task
+                // Variables used inside the spawned task are moved into it
+                let name = format!("connection {}", conn.peer_addr());
+                let handle = task::spawn_in(&mut supervisor.tasks, &name, async move {
+                    println!("Using test value: {:?}", test.test);
+                    conn.serve().await
+                });
+                supervisor.started(handle.id(), label);
+
+                // `test` is no longer accessible here because it was moved
+                // test;
+            }
+            Some(_) = supervisor.join_next() => {}
+            changed = accept_paused_changed(&mut flags, &mut phase) => match changed {
+                Some(now) => paused = now,
+                None => break,
+            },
+            _ = stop.requested.cancelled() => break,
+        }
+    }
+
+    drop(connections);
+    stop.closed.cancel();
+    if stop.requested.is_cancelled() {
+        // Removed by a reload: the connections end when their clients are
+        // done, unless the whole server drains first
+        while !supervisor.tasks.is_empty() {
+            tokio::select! {
+                _ = supervisor.join_next() => {}
+                _ = readiness::wait_draining(&mut phase) => break,
+            }
+        }
+    }
+    supervisor.drain().await;
+}
+
+/// The accept loop of `--runtime local`; must run inside a `LocalSet`.
+///
+/// Connection tasks are started with `spawn_local`, so they stay on this
+/// thread and may hold state that is not `Send`. Each session is an
+/// `Rc<RefCell<..>>` shared by the connection task, which updates it, and
+/// this loop, which logs it when the connection is over. Neither side
+/// needs a lock.
+async fn local_accept_loop(server: Server, _drained: mpsc::Sender<()>) {
+    let mut supervisor = Supervisor::new(server.shared.clone());
+    let mut sessions = HashMap::new();
+    let (mut flags, mut phase) = (server.shared.flags.clone(), server.shared.phase.clone());
+    let mut paused = flags.borrow_and_update().accept_paused;
+    let connections = connections(server);
+    tokio::pin!(connections);
+
+    loop {
+        tokio::select! {
+            conn = connections.next(), if !paused => {
+                let Some(conn) = conn else { break };
+                let label = format!("connection #{} from {}", conn.id(), conn.peer_addr());
+
+                let session = Rc::new(RefCell::new(SessionStats::default()));
+                let conn_id = conn.id();
+                // `tokio::spawn(conn.serve_local(..))` would not compile:
+                // the future holds an `Rc`, so it is not `Send`
+                let handle = supervisor.tasks.spawn_local(conn.serve_local(session.clone()));
+                supervisor.started(handle.id(), label);
+                sessions.insert(handle.id(), (conn_id, session));
+            }
+            Some(id) = supervisor.join_next() => {
+                if let Some((conn_id, session)) = sessions.remove(&id) {
+                    println!("[LOCAL] connection #{} session: {}", conn_id, session.borrow());
+                }
+            }
+            changed = accept_paused_changed(&mut flags, &mut phase) => match changed {
+                Some(now) => paused = now,
+                None => break,
+            },
+        }
+    }
+
+    supervisor.drain().await;
+    for (conn_id, session) in sessions.into_values() {
+        println!("[LOCAL] connection #{} session: {}", conn_id, session.borrow());
+    }
+}
+
+/// The accept loop of the io_uring backend: like [`local_accept_loop`],
+/// with sockets from tokio-uring, each served through a pipe by
+/// `Connection::serve` while [`uring::pump`] moves its bytes.
+#[cfg(all(target_os = "linux", feature = "uring"))]
+async fn uring_accept_loop(
+    listener: uring::TcpListener,
+    shared: Arc<Shared>,
+    _drained: mpsc::Sender<()>,
+) {
+    let mut supervisor = Supervisor::new(shared.clone());
+    let (mut flags, mut phase) = (shared.flags.clone(), shared.phase.clone());
+    let mut paused = flags.borrow_and_update().accept_paused;
+    let heartbeat = {
+        let config = shared.config.borrow();
+        config.heartbeat(config.heartbeat_idle)
+    };
+    let wire = Wire { heartbeat, ..Wire::default() };
+    // Kept across iterations: dropping an accept that is already in the
+    // ring could lose the connection it completes with
+    let mut accepting = Box::pin(listener.accept());
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = &mut accepting, if !paused => {
+                accepting.set(listener.accept());
+                accepted
+            }
+            Some(_) = supervisor.join_next() => continue,
+            changed = accept_paused_changed(&mut flags, &mut phase) => match changed {
+                Some(now) => {
+                    paused = now;
+                    continue;
+                }
+                None => break,
+            },
+        };
+        let (stream, peer) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                let text = ServerError::Accept(e).to_string();
+                shared.log_tx.send(LogMessage::error(text)).await;
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        if !permitted(&shared, peer) {
+            continue;
+        }
+        let options = shared.config.borrow().socket;
+        if let Err(e) = uring::apply(&options, &stream) {
+            let text = format!("socket options for {}: {}", peer, e);
+            shared.log_tx.send(LogMessage::error(text)).await;
+        }
+        if let Err(reply) = screen(&shared, peer).await {
+            tokio::task::spawn_local(uring::refuse(stream, reply));
+            continue;
+        }
+
+        let (client, socket) = io::duplex(DUPLEX_BUFFER);
+        let conn = register(shared.clone(), None, wire, socket, peer);
+        let label = format!("connection #{} from {}", conn.id(), conn.peer_addr());
+        // One task for both: the pump holds the socket, which is not `Send`,
+        // and ends once the connection is served
+        let handle = supervisor.tasks.spawn_local(async move {
+            let (reason, _) = tokio::join!(conn.serve(), uring::pump(stream, client));
+            reason
+        });
+        supervisor.started(handle.id(), label);
+    }
+
+    supervisor.drain().await;
+}
+
+/// Waits until the `accept_paused` flag changes and returns its new value,
+/// or `None` once the server starts draining.
+///
+/// A paused accept loop does not poll its connection stream, so it relies
+/// on this to notice the drain.
+async fn accept_paused_changed(
+    flags: &mut FlagsRx,
+    phase: &mut watch::Receiver<Phase>,
+) -> Option<bool> {
+    let paused = flags.borrow().accept_paused;
+    loop {
+        tokio::select! {
+            _ = readiness::wait_draining(phase) => return None,
+            // Other flags change too; only a change of this one matters here
+            Ok(()) = flags.changed() => {
+                let now = flags.borrow_and_update().accept_paused;
+                if now != paused {
+                    return Some(now);
+                }
+            }
+        }
+    }
+}
+
+/// Tracks the connection tasks of one accept loop.
+///
+/// Connection tasks go into a `JoinSet` instead of being spawned and
+/// forgotten: they are reaped as they finish, so a handler that panics is
+/// logged rather than silently swallowed, and the set is what the loop
+/// waits on when the server drains.
+struct Supervisor {
+    shared: Arc<Shared>,
+    tasks: JoinSet<CloseReason>,
+    // Which connection each task serves, for the panic message
+    labels: HashMap<tokio::task::Id, String>,
+}
+
+impl Supervisor {
+    fn new(shared: Arc<Shared>) -> Self {
+        Self { shared, tasks: JoinSet::new(), labels: HashMap::new() }
+    }
+
+    /// Records a task just spawned into `self.tasks`.
+    fn started(&mut self, id: tokio::task::Id, label: String) {
+        self.labels.insert(id, label);
+        self.shared.state.metrics.adjust(metrics::CONNECTION_TASKS, 1);
+    }
+
+    /// Reaps the next finished task and returns its id; `None` when there
+    /// are no tasks (a disabled branch in `select!`).
+    async fn join_next(&mut self) -> Option<tokio::task::Id> {
+        let result = self.tasks.join_next_with_id().await?;
+        Some(self.reap(result).await)
+    }
+
+    /// Waits for the remaining tasks, aborting them after `DRAIN_TIMEOUT`.
+    async fn drain(&mut self) {
+        // The handlers notice the phase change and say goodbye themselves
+        if !self.tasks.is_empty() {
+            println!("[PHASE] waiting for {} connection(s) to close", self.tasks.len());
+        }
+        let drain = async { while self.join_next().await.is_some() {} };
+        if tokio::time::timeout(DRAIN_TIMEOUT, drain).await.is_err() {
+            println!(
+                "[PHASE] {} connection(s) still open after {:?}, aborting them",
+                self.tasks.len(),
+                DRAIN_TIMEOUT,
+            );
+            self.tasks.abort_all();
+            while self.join_next().await.is_some() {}
+        }
+    }
+
+    /// Accounts for one finished connection task.
+    async fn reap(
+        &mut self,
+        result: Result<(tokio::task::Id, CloseReason), JoinError>,
+    ) -> tokio::task::Id {
+        self.shared.state.metrics.adjust(metrics::CONNECTION_TASKS, -1);
+        let error = match result {
+            // The close reason was already logged by `Connection::serve`
+            Ok((id, _reason)) => {
+                self.labels.remove(&id);
+                return id;
+            }
+            Err(error) => error,
+        };
+        let id = error.id();
+        let label = self.labels.remove(&id).unwrap_or_else(|| "connection task".to_string());
+
+        if error.is_panic() {
+            self.shared.state.metrics.incr(metrics::CONNECTION_TASKS_PANICKED, 1);
+            let message = supervise::panic_message(error.into_panic());
+            let text = format!("{} panicked: {}", label, message);
+            self.shared.log_tx.send(LogMessage::error(text)).await;
+        } else {
+            // Cancelled: aborted after the drain timeout
+            println!("[PHASE] {} aborted", label);
+        }
+        id
+    }
+}
+
+/// The address in-process connections report; they all share one
+/// per-IP rate limit bucket.
+pub const IN_PROCESS_PEER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+
+/// The transcript of what was typed into the stdin console.
+const STDIN_LOG: &str = "log.txt";
+
+/// How many of the logger's lines the admin socket can show.
+const RECENT_LINES: usize = 200;
+
+/// How many bytes an in-process pipe buffers in each direction.
+const DUPLEX_BUFFER: usize = 64 * 1024;
+
+/// A listening socket plus everything its connections share.
+pub struct Server {
+    listener: TcpListener,
+    shared: Arc<Shared>,
+    // Counter of the connections this listener accepted, when it is one of several
+    acceptor: Option<&'static str>,
+    // How this listener's connections delimit and encode messages
+    wire: Wire,
+    // Asked for when a reload removes this listener; never, for the main one
+    stop: ListenerStop,
+    // A standalone server keeps its channels open for as long as it lives
+    controls: Option<Controls>,
+}
+
+impl Server {
+    /// Binds `config.listen` and starts the background tasks.
+    ///
+    /// Unlike [`run`] there is no warm-up phase, no signal handling, no
+    /// stdin console, no state file and no state watcher: the access list
+    /// (if any) is loaded right away and the server is ready as soon as this
+    /// returns.
+    pub async fn bind(config: ServerConfig) -> Result<Server, ServerError> {
+        let (shared, controls) = Shared::start(config).await?;
+        let config = shared.config.borrow().clone();
+
+        let addr = config.listen;
+        let listener = TcpListener::bind(addr)
+            .await
+            .map_err(|source| ServerError::Bind { addr: addr.to_string(), source })?;
+
+        prepare(&config, &controls.acl_tx).await?;
+        set_phase(&controls.phase_tx, Phase::Ready);
+
+        let server = Server::listening(listener, shared, None);
+        Ok(Server { controls: Some(controls), ..server })
+    }
+
+    /// A listener of `run`, which keeps the channels itself.
+    ///
+    /// Its connections get the main listener's heartbeat, if one is set.
+    fn listening(
+        listener: TcpListener,
+        shared: Arc<Shared>,
+        acceptor: Option<&'static str>,
+    ) -> Server {
+        let heartbeat = {
+            let config = shared.config.borrow();
+            config.heartbeat(config.heartbeat_idle)
+        };
+        let wire = Wire { heartbeat, ..Wire::default() };
+        Server { listener, shared, acceptor, wire, stop: ListenerStop::default(), controls: None }
+    }
+
+    /// Serves this listener's connections with `framing` instead of lines.
+    pub fn with_framing(mut self, framing: Framing) -> Self {
+        self.wire.framing = framing;
+        self
+    }
+
+    /// Serves this listener's connections with `protocol` instead of text commands.
+    pub fn with_protocol(mut self, protocol: Protocol) -> Self {
+        self.wire.protocol = protocol;
+        self
+    }
+
+    /// Pings this listener's idle connections as `heartbeat` says, or not at all.
+    pub fn with_heartbeat(mut self, heartbeat: Option<Heartbeat>) -> Self {
+        self.wire.heartbeat = heartbeat;
+        self
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// The accepted connections, as a stream.
+    ///
+    /// The access list, the not-ready policy and the registry are applied
+    /// inside the stream: a connection that comes out of it is permitted,
+    /// counted and registered. The stream ends when the server starts draining.
+    pub fn incoming(&self) -> Incoming<'_> {
+        Incoming { server: self, next: None }
+    }
+
+    /// Opens an in-process connection over `tokio::io::duplex`.
+    ///
+    /// Returns the client's end of the pipe and the server's end as a
+    /// [`Connection`], which still has to be served (usually by spawning
+    /// [`Connection::serve`]). No socket is involved, so the access list
+    /// does not apply; the connection is registered and counted like any
+    /// other and reports [`IN_PROCESS_PEER`] as its address.
+    pub fn duplex(&self) -> (DuplexStream, Connection<DuplexStream>) {
+        let (client, socket) = io::duplex(DUPLEX_BUFFER);
+        (client, self.adopt(socket, IN_PROCESS_PEER))
+    }
+
+    /// Turns a stream accepted elsewhere into a [`Connection`] of this server.
+    ///
+    /// The connection is registered and counted like an accepted one, and
+    /// rate-limited by `peer`'s address; the access list is not consulted.
+    pub fn adopt<S: Transport>(&self, socket: S, peer: SocketAddr) -> Connection<S> {
+        register(self.shared.clone(), None, self.wire, socket, peer)
+    }
+
+    /// What the logger prints, to read or follow from outside the server.
+    pub fn logger(&self) -> LoggerHandle {
+        self.shared.logger.clone()
+    }
+
+    /// Starts draining: `incoming` ends and open connections say goodbye.
+    pub fn shutdown(&self) {
+        if let Some(controls) = &self.controls {
+            set_phase(&controls.phase_tx, Phase::Draining);
+        }
+    }
+
+    /// Serves connections from turmoil's simulated network until the server
+    /// drains, then waits for them to close.
+    ///
+    /// `listener` takes the place of this server's own, which stays idle, so
+    /// a simulation controls every byte the server sees and every delay.
+    #[cfg(feature = "sim")]
+    pub async fn serve_simulated(&self, listener: turmoil::net::TcpListener) -> io::Result<()> {
+        let mut phase = self.shared.phase.clone();
+        let mut connections = JoinSet::new();
+        loop {
+            let (socket, peer) = tokio::select! {
+                accepted = listener.accept() => accepted?,
+                _ = readiness::wait_draining(&mut phase) => break,
+            };
+            connections.spawn(self.adopt(socket, peer).serve());
+        }
+        while connections.join_next().await.is_some() {}
+        Ok(())
+    }
+
+    /// Accepts until a connection passes all checks, or the server drains.
+    async fn next_connection(&self) -> Option<Connection> {
+        let (mut flags, mut phase) = (self.shared.flags.clone(), self.shared.phase.clone());
+        loop {
+            let paused = flags.borrow_and_update().accept_paused;
+            let accepted = tokio::select! {
+                // `accept` is cancel-safe: if the other branch wins, no connection is lost
+                accepted = self.listener.accept(), if !paused => accepted,
+                changed = accept_paused_changed(&mut flags, &mut phase) => match changed {
+                    Some(_) => continue,
+                    None => return None,
+                },
+            };
+            let accepted = accepted.map(|(socket, _)| socket);
+            let admitted = admit(self.shared.clone(), self.acceptor, self.wire, accepted);
+            if let Some(conn) = admitted.await {
+                return Some(conn);
+            }
+        }
+    }
+}
+
+/// The connections of an accept loop, as a pipeline of stream adapters.
+///
+/// `TcpListenerStream` turns the listener into a stream of accepted
+/// sockets. Merged with the phase, it yields `None` once the server drains,
+/// which `map_while` turns into the end of the stream (what `take_until`
+/// would do, if `tokio-stream` had it). `then` runs the admission checks,
+/// and `filter_map` drops the sockets that fail them.
+fn connections(server: Server) -> impl Stream<Item = Connection> {
+    let Server { listener, shared, acceptor, wire, .. } = server;
+    let draining = WatchStream::new(shared.phase.clone())
+        .filter(|phase| *phase == Phase::Draining)
+        .map(|_| None);
+    TcpListenerStream::new(listener)
+        .map(Some)
+        .merge(draining)
+        .map_while(|accepted| accepted)
+        .then(move |accepted| admit(shared.clone(), acceptor, wire, accepted))
+        .filter_map(|conn| conn)
+}
+
+/// Applies the access list, socket options, not-ready policy, pause and load
+/// shedding to an accepted socket, and registers it if it may be served.
+async fn admit(
+    shared: Arc<Shared>,
+    acceptor: Option<&'static str>,
+    wire: Wire,
+    accepted: io::Result<TcpStream>,
+) -> Option<Connection> {
+    // The stream only yields the socket; a peer that is already gone has no address
+    let (socket, peer) = match accepted.and_then(|s| s.peer_addr().map(|peer| (s, peer))) {
+        Ok(accepted) => accepted,
+        Err(e) => {
+            // Accept errors are usually transient (e.g. too many open files),
+            // so the server keeps running; the short pause avoids a busy loop.
+            let text = ServerError::Accept(e).to_string();
+            shared.log_tx.send(LogMessage::error(text)).await;
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            return None;
+        }
+    };
+
+    if !permitted(&shared, peer) {
+        return None;
+    }
+    // Read per connection, so a reload tunes every connection accepted after it.
+    // A failed option is not worth dropping the client for; it is only logged.
+    let options = shared.config.borrow().socket;
+    if let Err(e) = options.apply(&socket) {
+        let text = format!("socket options for {}: {}", peer, e);
+        shared.log_tx.send(LogMessage::error(text)).await;
+    }
+    if let Err(reply) = screen(&shared, peer).await {
+        refuse(socket, wire, reply);
+        return None;
+    }
+    Some(register(shared, acceptor, wire, socket, peer))
+}
+
+/// Applies the access list to a connection from `peer`.
+fn permitted(shared: &Shared, peer: SocketAddr) -> bool {
+    // `borrow()` gives the latest published list; the guard is dropped
+    // right away, so the reloader is never blocked by the accept loop
+    if !shared.acl.borrow().permits(peer.ip()) {
+        println!("[ACL] rejected connection from {}", peer);
+        shared.state.metrics.incr(metrics::CONNECTIONS_DENIED, 1);
+        return false;
+    }
+    true
+}
+
+/// Applies the not-ready policy, pause and load shedding to a permitted
+/// connection. `Err` is the reply that tells the client why it is refused.
+async fn screen(shared: &Shared, peer: SocketAddr) -> Result<(), &'static str> {
+    let warming = *shared.phase.borrow() != Phase::Ready;
+    if warming && shared.config.borrow().not_ready == NotReadyPolicy::Reject {
+        shared.state.metrics.incr(metrics::CONNECTIONS_NOT_READY, 1);
+        return Err("ERR: server is warming up, try again later\n");
+    }
+    // Paused with `PAUSE REJECT`: the client is told instead of left waiting
+    if shared.flags.borrow().reject_connections {
+        shared.state.metrics.incr(metrics::CONNECTIONS_PAUSED, 1);
+        return Err("ERR: server paused, try again later\n");
+    }
+    if let Err(overload) = shed(shared).await {
+        println!("[SHED] rejected connection from {}: {}", peer, overload);
+        shared.state.metrics.incr(metrics::CONNECTIONS_SHED, 1);
+        return Err("ERR: server overloaded, try again later\n");
+    }
+    Ok(())
+}
+
+/// Counts and registers a connection that is going to be served.
+fn register<S>(
+    shared: Arc<Shared>,
+    acceptor: Option<&'static str>,
+    wire: Wire,
+    socket: S,
+    peer: SocketAddr,
+) -> Connection<S> {
+    shared.state.metrics.incr(metrics::CONNECTIONS_ACCEPTED, 1);
+    if let Some(acceptor) = acceptor {
+        shared.state.metrics.incr(acceptor, 1);
+    }
+
+    let id = shared.state.next_connection_id();
+    Connection {
+        socket,
+        peer,
+        id,
+        wire,
+        registration: shared.registry.register(id, peer),
+        limiter: conn_limiter(&shared, peer),
+        shared,
+    }
+}
+
+/// The rate limiter of a new connection from `peer`, which also spends the
+/// quota of its address until the client authenticates.
+fn conn_limiter(shared: &Shared, peer: SocketAddr) -> ConnLimiter {
+    let quota = shared.state.quotas.lease(Identity::Ip(peer.ip()));
+    ConnLimiter::new(shared.ip_limiter.clone(), peer.ip(), shared.clock.clone()).with_quota(quota)
+}
+
+/// How often a delayed accept looks at the load again.
+const SHED_RECHECK: std::time::Duration = std::time::Duration::from_millis(50);
+
+/// Compares the load with the shedding thresholds. Over one of them, fails
+/// with `ShedPolicy::Reject`; with `Delay`, waits until the load is under
+/// them again, and only fails if the server starts draining meanwhile.
+async fn shed(shared: &Shared) -> Result<(), Overload> {
+    let mut phase = shared.phase.clone();
+    let mut delayed = false;
+    loop {
+        // Read every time, so a reload tunes a wait that is already going on
+        let (thresholds, policy) = {
+            let current = shared.config.borrow();
+            (current.shedding, current.shed_policy)
+        };
+        if !thresholds.is_set() {
+            return Ok(());
+        }
+        let load = Load {
+            connections: shared.registry.count(),
+            log_queue: shared.log_tx.queued(),
+            runtime_queue: tokio::runtime::Handle::current().metrics().global_queue_depth(),
+        };
+        let Err(overload) = thresholds.check(&load) else { return Ok(()) };
+        // Counted once per connection, for the first reason it met
+        if !delayed {
+            shared.state.metrics.incr(overload.metric(), 1);
+        }
+        if policy == ShedPolicy::Reject {
+            return Err(overload);
+        }
+        if !delayed {
+            println!("[SHED] accepting paused: {}", overload);
+            shared.state.metrics.incr(metrics::ACCEPTS_DELAYED, 1);
+            delayed = true;
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(SHED_RECHECK) => {}
+            _ = readiness::wait_draining(&mut phase) => return Err(overload),
+        }
+    }
+}
+
+/// Sends `reply` to a connection that will not be served, then closes it.
+///
+/// The reply is written by a short-lived task so a slow client cannot hold
+/// up the accept loop.
+fn refuse(mut socket: TcpStream, wire: Wire, reply: &'static str) {
+    let mut outbox = Outbox::new(wire);
+    outbox.push(reply);
+    task::spawn("refusal reply", async move {
+        let _ = outbox.write_to(&mut socket).await;
+    });
+}
+
+/// Stream of accepted connections; see [`Server::incoming`].
+///
+/// Implemented by hand on top of an async fn: the future for the next
+/// connection is boxed and kept across polls until it completes.
+pub struct Incoming<'a> {
+    server: &'a Server,
+    next: Option<Pin<Box<dyn Future<Output = Option<Connection>> + Send + 'a>>>,
+}
+
+impl Stream for Incoming<'_> {
+    type Item = Connection;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Connection>> {
+        let server = self.server;
+        let next = self
+            .next
+            .get_or_insert_with(|| Box::pin(server.next_connection()));
+
+        let conn = ready!(next.as_mut().poll(cx));
+        // The next poll starts accepting the following connection
+        self.next = None;
+        Poll::Ready(conn)
+    }
+}
+
+/// An accepted connection that passed the access checks.
+///
+/// Usually a TCP connection from [`Server::incoming`]; [`Server::duplex`]
+/// creates one over an in-memory pipe instead. It stays in the registry
+/// (and visible to `LIST`) until it is dropped.
+pub struct Connection<S = TcpStream> {
+    socket: S,
+    peer: SocketAddr,
+    id: u64,
+    wire: Wire,
+    registration: Registration,
+    limiter: ConnLimiter,
+    shared: Arc<Shared>,
+}
+
+impl<S> Connection<S> {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+
+    pub fn wire(&self) -> Wire {
+        self.wire
+    }
+
+    /// The underlying socket, for embedders that speak their own protocol.
+    pub fn socket(&mut self) -> &mut S {
+        &mut self.socket
+    }
+
+    /// The connection's cancellation token. Cancelling it closes the
+    /// connection like `KICK` does, even in the middle of a request.
+    pub fn token(&self) -> CancellationToken {
+        self.registration.token()
+    }
+}
+
+impl<S: Transport> Connection<S> {
+    /// Serves the built-in text protocol until the connection ends.
+    ///
+    /// A connection of a TLS listener first completes the handshake, then
+    /// is served in the protocol negotiated with ALPN.
+    pub async fn serve(self) -> CloseReason {
+        let session = Mutex::new(SessionStats::default());
+        if self.wire.protocol == Protocol::Tls {
+            return self.serve_tls(session).await;
+        }
+        self.serve_with(session).await
+    }
+
+    /// Like [`serve`](Self::serve), with a session that is not `Send`.
+    ///
+    /// The returned future is not `Send` either, so it has to run on a
+    /// `LocalSet` (`spawn_local`) rather than `tokio::spawn`.
+    pub async fn serve_local(self, session: Rc<RefCell<SessionStats>>) -> CloseReason {
+        if self.wire.protocol == Protocol::Tls {
+            return self.serve_tls(session).await;
+        }
+        self.serve_with(session).await
+    }
+
+    /// Completes the TLS handshake, records the protocol ALPN settled on
+    /// and hands the decrypted stream to that protocol's handler.
+    async fn serve_tls<T: Session>(self, session: T) -> CloseReason {
+        let Connection { socket, peer, id, wire, registration, limiter, shared } = self;
+        let metrics = &shared.state.metrics;
+        let Some(acceptor) = shared.tls.clone() else {
+            let text = format!("connection #{} from {}: TLS is not configured", id, peer);
+            shared.log_tx.send(LogMessage::error(text)).await;
+            metrics.incr(CloseReason::ProtocolError.metric(), 1);
+            return CloseReason::ProtocolError;
+        };
+        let handshake = tokio::time::timeout(tls::HANDSHAKE_TIMEOUT, acceptor.accept(socket));
+        let socket = match handshake.await {
+            Ok(Ok(socket)) => socket,
+            Ok(Err(e)) => {
+                let text = format!("TLS handshake with {} failed: {}", peer, e);
+                shared.log_tx.send(LogMessage::error(text)).await;
+                metrics.incr(CloseReason::ProtocolError.metric(), 1);
+                return CloseReason::ProtocolError;
+            }
+            Err(_) => {
+                let text = format!("TLS handshake with {} timed out", peer);
+                shared.log_tx.send(LogMessage::info(text)).await;
+                metrics.incr(CloseReason::IdleTimeout.metric(), 1);
+                return CloseReason::IdleTimeout;
+            }
+        };
+
+        let alpn = Alpn::negotiated(socket.get_ref().1.alpn_protocol());
+        registration.set_protocol(alpn.name());
+        // Only there if the listener asked for it, and then already verified
+        let certificate = socket.get_ref().1.peer_certificates().and_then(|certs| certs.first());
+        if let Some(identity) = certificate.and_then(tls::identity) {
+            let text =
+                format!("connection #{} from {} has a certificate for {}", id, peer, identity);
+            shared.log_tx.send(LogMessage::info(text)).await;
+            registration.set_identity(identity);
+        }
+        // Held while the connection is served, so `STATS` counts it as active
+        let _vhost = route_vhost(socket.get_ref().1.server_name(), &registration, &shared);
+        // Inside the tunnel, `echo/1` is the text protocol of the main listener
+        let wire = Wire { protocol: Protocol::Text, ..wire };
+        let conn = Connection { socket, peer, id, wire, registration, limiter, shared };
+        match alpn {
+            Alpn::Echo => conn.serve_with(session).await,
+            Alpn::H2 | Alpn::Grpc => conn.serve_http2(alpn).await,
+        }
+    }
+
+    /// Serves a connection that speaks HTTP/2: the h2 endpoint's routes, or
+    /// the gRPC service. Either way it is counted and listed like any other.
+    async fn serve_http2(self, alpn: Alpn) -> CloseReason {
+        let Connection { socket, peer, id, mut registration, shared, .. } = self;
+        let state = &shared.state;
+        let mut socket = CountingStream::new(socket, state.metrics.clone())
+            .with_dump(wire_dump(&shared, id, peer).await);
+        state.metrics.adjust(metrics::CONNECTIONS_ACTIVE, 1);
+        state.peers.connected(peer.ip());
+
+        let serving = async {
+            match alpn {
+                #[cfg(feature = "grpc")]
+                Alpn::Grpc => match &shared.grpc {
+                    Some(grpc) => grpc.serve(&mut socket, peer).await,
+                    None => Err(io::Error::other("the gRPC service is not running")),
+                },
+                // Without the grpc feature, `grpc` is never negotiated
+                _ => {
+                    let phase = shared.phase.clone();
+                    http2::serve_connection(&mut socket, Some(peer), state.clone(), phase)
+                        .await
+                        .map_err(io::Error::other)
+                }
+            }
+        };
+        let reason = tokio::select! {
+            result = serving => match result {
+                Ok(()) => CloseReason::ClientEof,
+                Err(e) if is_disconnect(&e) => CloseReason::ClientEof,
+                Err(e) => {
+                    let text = format!("{} connection from {} failed: {}", alpn.name(), peer, e);
+                    shared.log_tx.send(LogMessage::error(text)).await;
+                    CloseReason::IoError
+                }
+            },
+            reason = registration.kicked() => reason,
+        };
+
+        state.metrics.adjust(metrics::CONNECTIONS_ACTIVE, -1);
+        state.metrics.incr(reason.metric(), 1);
+        state.peers.transferred(peer.ip(), socket.bytes_read(), socket.bytes_written());
+        let text = format!(
+            "connection #{} from {} ({}) closed: {} ({} bytes in, {} out)",
+            id,
+            peer,
+            alpn.name(),
+            reason,
+            socket.bytes_read(),
+            socket.bytes_written(),
+        );
+        shared.log_tx.send(LogMessage::info(text)).await;
+        reason
+    }
+
+    async fn serve_with<T: Session>(self, session: T) -> CloseReason {
+        let Connection { socket, peer, id, wire, mut registration, limiter, shared } = self;
+        let state = &shared.state;
+        // Everything below reads and writes through the limit, if one is
+        // set, and is counted in `bytes_read` and `bytes_written`. With
+        // `--chaos` the faults are injected beneath, as if by the network.
+        let (limit, chaos) = {
+            let config = shared.config.borrow();
+            (config.bandwidth_limit, config.chaos)
+        };
+        // With `--record`, the bytes are recorded as the client sent them
+        let record_dir = shared.config.borrow().record_dir.clone();
+        let recorder = match record_dir {
+            Some(dir) => Recorder::create(&dir, id, peer).await,
+            None => None,
+        };
+        let socket = RecordingStream::new(socket, recorder);
+        let socket = ChaosStream::new(socket, chaos, state.metrics.clone());
+        let mut socket =
+            CountingStream::new(ThrottledStream::new(socket, limit), state.metrics.clone())
+                .with_dump(wire_dump(&shared, id, peer).await);
+
+        // A connection accepted during warm-up is queued here:
+        // the task is suspended until the phase changes.
+        let mut phase = shared.phase.clone();
+        if !readiness::wait_ready(&mut phase).await {
+            state.metrics.incr(CloseReason::ServerShutdown.metric(), 1);
+            return CloseReason::ServerShutdown;
+        }
+
+        // Cloned so the channel's read lock is released before the awaits below
+        let trace_dir = shared.config.borrow().trace_dir.clone();
+        let mut tracer = ConnTracer::create(trace_dir.as_deref(), id).await;
+        tracer.record(TraceEvent::Connected(peer)).await;
+        state.metrics.adjust(metrics::CONNECTIONS_ACTIVE, 1);
+        state.peers.connected(peer.ip());
+
+        let result = if wire.protocol == Protocol::Socks5 {
+            socks_connection(&mut socket, &shared, &mut registration, &mut tracer).await
+        } else if let Some(upstreams) = &shared.upstreams {
+            proxy_connection(&mut socket, upstreams, &shared, &mut registration, &mut tracer).await
+        } else {
+            let result = handle_tcp_request(
+                &mut socket,
+                wire,
+                &shared,
+                &mut registration,
+                &session,
+                limiter,
+                &mut tracer,
+            )
+            .await;
+            // However the connection ended, its session can be resumed for a while
+            let saved = session.stats();
+            shared.sessions.insert(saved.id, saved);
+            result
+        };
+        // A failed connection is reported, not propagated: it must not
+        // affect the accept loop or any other connection.
+        let reason = match result {
+            Ok(reason) => reason,
+            Err(e) => {
+                shared.log_tx.send(LogMessage::error(e.to_string())).await;
+                CloseReason::IoError
+            }
+        };
+        if reason.server_initiated() {
+            tracer.record(TraceEvent::Waiting("close")).await;
+            let grace = shared.config.borrow().close_grace;
+            let drained = close_gracefully(&mut socket, grace).await;
+            state.metrics.incr(drained.metric(), 1);
+        }
+
+        state.metrics.adjust(metrics::CONNECTIONS_ACTIVE, -1);
+        state.metrics.incr(reason.metric(), 1);
+        state.peers.transferred(peer.ip(), socket.bytes_read(), socket.bytes_written());
+        let text = format!(
+            "connection #{} from {} closed: {} ({} bytes in, {} out)",
+            id,
+            peer,
+            reason,
+            socket.bytes_read(),
+            socket.bytes_written(),
+        );
+        shared.log_tx.send(LogMessage::info(text)).await;
+        tracer.record(TraceEvent::Closed(reason)).await;
+        tracer.finish().await;
+        reason
+    }
+}
+
+fn set_phase(phase_tx: &watch::Sender<Phase>, phase: Phase) {
+    phase_tx.send_replace(phase);
+    println!("[PHASE] {}", phase);
+}
+
+/// Startup work that does not have to finish before the listener is bound.
+///
+/// The steps are independent of each other, so they run concurrently;
+/// `try_join!` fails fast if any of them fails.
+async fn warm_up(
+    config: &ServerConfig,
+    state: &State,
+    acl_tx: &watch::Sender<AccessList>,
+    admin: Arc<Admin>,
+    phase: watch::Receiver<Phase>,
+) -> Result<(), ServerError> {
+    // Background task reading admin commands from STDIN, line by line;
+    // every line typed also goes through the pipeline writing log.txt.
+    // This shows that stdin and files are just AsyncRead / AsyncWrite streams.
+    let storage = async {
+        let file = File::create(STDIN_LOG)
+            .await
+            .map_err(|source| ServerError::File { path: STDIN_LOG.into(), source })?;
+        // A read from stdin runs on a blocking thread, which keeps a runtime
+        // from shutting down until the read returns
+        if config.console {
+            let transcript = Ingest::start(file, state.metrics.clone());
+            admin.spawn_console(transcript, phase);
+        }
+        Ok(())
+    };
+
+    // Stands in for slow startup work such as warming caches
+    let delay = async {
+        tokio::time::sleep(config.warmup_delay).await;
+        Ok(())
+    };
+
+    // Queued connections are served only after this, so none of them
+    // sees the counter or the store before the saved state is back
+    let restore = async {
+        let Some(path) = &config.state_file else { return Ok(()) };
+        let mut covered = 0;
+        match persist::load(path).await {
+            Ok(Some(snapshot)) => {
+                println!(
+                    "[STATE] restored counter {} and {} key(s) from {}",
+                    snapshot.counter,
+                    snapshot.kv.len(),
+                    path.display(),
+                );
+                covered = snapshot.wal_seq;
+                state.restore(snapshot);
+            }
+            Ok(None) => println!("[STATE] {} does not exist yet, starting empty", path.display()),
+            // Refuse to start rather than overwrite a file we cannot read
+            Err(source) => return Err(ServerError::File { path: path.clone(), source }),
+        }
+        if config.durability == Durability::None {
+            return Ok(());
+        }
+
+        // Then the changes made after the snapshot was taken
+        let path = wal::path(path);
+        let file_error = |source| ServerError::File { path: path.clone(), source };
+        let records = wal::replay(&path, covered).await.map_err(file_error)?;
+        if !records.is_empty() {
+            println!("[STATE] replayed {} change(s) from {}", records.len(), path.display());
+        }
+        state.kv.replay(&records);
+        let seq = records.last().map_or(covered, |record| record.seq);
+        let metrics = state.metrics.clone();
+        let log = Wal::open(path.clone(), config.durability, seq, metrics).await;
+        state.kv.set_wal(log.map_err(file_error)?);
+        Ok(())
+    };
+
+    tokio::try_join!(prepare(config, acl_tx), storage, delay, restore)?;
+    Ok(())
+}
+
+/// Creates the trace, recording and wire dump directories and loads the
+/// access list, concurrently.
+async fn prepare(
+    config: &ServerConfig,
+    acl_tx: &watch::Sender<AccessList>,
+) -> Result<(), ServerError> {
+    let trace_dir = async {
+        if let Some(dir) = &config.trace_dir {
+            tokio::fs::create_dir_all(dir)
+                .await
+                .map_err(|source| ServerError::File { path: dir.clone(), source })?;
+            println!("Writing connection traces to {}", dir.display());
+        }
+        Ok(())
+    };
+
+    let record_dir = async {
+        if let Some(dir) = &config.record_dir {
+            tokio::fs::create_dir_all(dir)
+                .await
+                .map_err(|source| ServerError::File { path: dir.clone(), source })?;
+            println!("Recording connections to {}", dir.display());
+        }
+        Ok(())
+    };
+
+    let wire_debug_dir = async {
+        if let Some(dir) = &config.wire_debug_dir {
+            tokio::fs::create_dir_all(dir)
+                .await
+                .map_err(|source| ServerError::File { path: dir.clone(), source })?;
+            println!("Dumping connection bytes to {}", dir.display());
+        }
+        Ok(())
+    };
+
+    let acl = async {
+        if let Some(path) = &config.acl_file {
+            let list = AccessList::load(path).await.map_err(ServerError::Config)?;
+            println!("[ACL] loaded: {}", list.summary());
+            acl_tx.send_replace(list);
+        }
+        Ok(())
+    };
+
+    tokio::try_join!(trace_dir, record_dir, wire_debug_dir, acl)?;
+    Ok(())
+}
+
+/// Records the virtual host the client's SNI names, if one is configured.
+fn route_vhost(
+    server_name: Option<&str>,
+    registration: &Registration,
+    shared: &Shared,
+) -> Option<vhost::VhostGuard> {
+    let config = shared.config.borrow();
+    if config.tls_hosts.is_empty() {
+        return None;
+    }
+    let metrics = &shared.state.metrics;
+    let Some(host) = server_name.and_then(|name| vhost::route(&config.tls_hosts, name)) else {
+        metrics.incr(metrics::VHOST_UNMATCHED, 1);
+        return None;
+    };
+    metrics.incr(metrics::VHOST_ROUTED, 1);
+    registration.set_vhost(host.name.clone());
+    Some(shared.state.vhosts.connected(&host.name))
+}
+
+/// With `--wire-debug`, the hex dump of connection `id`.
+async fn wire_dump(shared: &Shared, id: u64, peer: SocketAddr) -> Option<WireDump> {
+    // Cloned so the channel's read lock is released before the await
+    let (dir, max_bytes) = {
+        let config = shared.config.borrow();
+        (config.wire_debug_dir.clone()?, config.wire_debug_max)
+    };
+    WireDump::create(&dir, id, peer, max_bytes).await
+}
+
+/// Serves one client until the connection ends.
+///
+/// Every way out of the loop says why the connection closed; I/O failures
+/// are returned as errors and reported as `CloseReason::IoError`.
+async fn handle_tcp_request<S: Transport, T: Session>(
+    socket: &mut S,
+    mut wire: Wire,
+    shared: &Shared,
+    registration: &mut Registration,
+    session: &T,
+    limiter: ConnLimiter,
+    tracer: &mut ConnTracer,
+) -> Result<CloseReason, ServerError> {
+    let peer = registration.peer();
+    let state = &shared.state;
+    let log_tx = &shared.log_tx;
+    let mut config = shared.config.clone();
+    let mut phase = shared.phase.clone();
+
+    // Bytes read but not yet split into messages. A read can end in the
+    // middle of a message or contain several; the codec finds the message
+    // boundaries and refuses to let a message grow past `max_message`.
+    // The buffer comes from the shared pool and returns to it when dropped.
+    let mut pending = shared.buffers.get();
+    let max_message = config.borrow().max_message;
+    let mut codec = MessageCodec::new(wire.framing, max_message);
+    let mut eof = false;
+    // Replies of messages read together, written together; see `flush_replies`
+    let mut outbox = Outbox::new(wire);
+    // Requests running side by side with `--max-in-flight`, and their places
+    // in the outbox
+    let mut running = Running::new();
+    // Counting, auth, rate limiting and logging; see `crate::middleware`
+    let failures = shared.auth_failures.clone();
+    let mut chain = Chain::new(state.clone(), log_tx.clone(), limiter, failures, peer);
+    chain.set_identity(registration.identity());
+    // Routed by SNI on the TLS listener; see `crate::vhost`
+    let vhost = registration.vhost();
+    chain.set_vhost(vhost.clone());
+    chain.set_script(shared.script.clone());
+    // Pings the client once it has been quiet for a while, if the listener says so
+    let mut pulse = Pulse::new(wire.heartbeat, shared.clock.clone());
+    // Whether the outbox has grown past `outbox_limit`, and what to do about it
+    let mut slow = SlowClient::new(state.metrics.clone());
+    let (mut outbox_limit, mut backpressure);
+
+    // The banner goes out with the first flush, before the first read
+    let mut handshake = Handshake::new(config.borrow().handshake);
+    // Key-value commands queued since `MULTI`, if one was sent
+    let mut transaction: Option<Transaction> = None;
+    // The delay `SLOWMODE` put on this connection's replies, if it did
+    let mut slowmode: Option<std::time::Duration> = None;
+    // The primary this server follows, if it is a replica; see `crate::replica`
+    let replica_of = config.borrow().replica_of.clone();
+    // A virtual host may greet the client first, and settle the mode for it
+    let host = vhost.and_then(|name| vhost::find(&config.borrow().tls_hosts, &name).cloned());
+    if let Some(banner) = host.as_ref().and_then(|host| host.banner.as_ref()) {
+        outbox.push(format!("{}\n", banner));
+    }
+    if let Some(mode) = host.and_then(|host| host.mode) {
+        let format = template::Format::Plain;
+        handshake = Handshake::Done { mode: Some(mode), gzip: false, format };
+        apply_handshake(handshake, &mut wire, &mut outbox, &config);
+    }
+    if handshake == Handshake::AwaitingHello {
+        outbox.push(handshake::banner(session.id()));
+    }
+    // On the length-prefixed listener the first message picks the codec
+    #[cfg(feature = "msgpack")]
+    let mut negotiate = wire.framing == Framing::LengthPrefixed && wire.protocol == Protocol::Text;
+
+    let reason = loop {
+        // The latest settings are picked up before every message, so a reload
+        // reaches long-lived connections too. The read guard is dropped at
+        // the end of this block, before the next `.await`.
+        let (idle_timeout, disconnect_oversized, utf8, max_in_flight, template) = {
+            let current = config.borrow_and_update();
+            chain.configure(&current);
+            (outbox_limit, backpressure) = (current.outbox_limit, current.backpressure);
+            (
+                current.idle_timeout,
+                current.disconnect_oversized,
+                current.utf8,
+                current.max_in_flight,
+                current.response.clone(),
+            )
+        };
+
+        // Messages already buffered are served before reading again.
+        // After EOF, a last line without a newline still counts.
+        let frame = if eof { codec.decode_eof(&mut pending) } else { codec.decode(&mut pending) };
+        let frame = match frame {
+            Ok(frame) => frame,
+            // A frame cut short by EOF
+            Err(FrameError::Io(source)) => return Err(ServerError::Read { peer, source }),
+            // The line codec discards the rest of the line as it arrives
+            Err(FrameError::TooLong(_)) => {
+                state.metrics.incr(metrics::MESSAGES_TOO_LONG, 1);
+                let text = format!("{} sent a message longer than {} bytes", peer, max_message);
+                log_tx.send(LogMessage::error(text)).await;
+                if disconnect_oversized || !codec.recovers_from_too_long() {
+                    outbox.push("BYE: message too long\n");
+                    break CloseReason::MessageTooLong;
+                }
+                let response =
+                    format!("ERR: message too long, the limit is {} bytes\n", max_message);
+                outbox.push(response);
+                continue;
+            }
+        };
+        let Some(message) = frame else {
+            // Client closed the connection, and everything it sent has been served
+            if eof {
+                break CloseReason::ClientEof;
+            }
+            // All buffered messages are answered: the replies go out as far as the
+            // socket takes them now, the rest while waiting for more input. One to
+            // gzip goes through `write_to`, which must not be cancelled, so it is
+            // waited for here.
+            write_ready(socket, &mut outbox, peer, state, tracer).await?;
+            if !outbox.is_empty() && !outbox.has_remaining() {
+                flush_replies(socket, &mut outbox, peer, state, tracer).await?;
+            }
+
+            // A client that does not read its replies; see `crate::backpressure`
+            if let Some(change) = slow.update(outbox.len(), outbox_limit) {
+                let (id, queued) = (registration.id(), outbox.len());
+                let text = match change {
+                    Change::Slowed => format!(
+                        "connection #{} from {} is slow, {} reply bytes queued ({})",
+                        id, peer, queued, backpressure,
+                    ),
+                    Change::CaughtUp => format!("connection #{} from {} caught up", id, peer),
+                };
+                log_tx.send(LogMessage::info(text)).await;
+                if change == Change::Slowed && backpressure == Backpressure::Pause {
+                    state.metrics.incr(metrics::BACKPRESSURE_PAUSED, 1);
+                }
+            }
+            if slow.is_slow() {
+                match backpressure {
+                    Backpressure::Pause => {}
+                    Backpressure::DropOldest => {
+                        let dropped = outbox.drop_oldest(outbox_limit);
+                        state.metrics.incr(metrics::BACKPRESSURE_REPLIES_DROPPED, dropped as u64);
+                    }
+                    Backpressure::Disconnect => break CloseReason::SlowClient,
+                }
+            }
+            let paused = slow.is_slow() && backpressure == Backpressure::Pause;
+
+            tracer.record(TraceEvent::Waiting(if paused { "write" } else { "read" })).await;
+            // `read_buf` reads straight into the spare capacity of `pending`,
+            // so there is no intermediate buffer to copy out of
+            pending.reserve(BUFFER_SIZE);
+            let start = pending.len();
+            let read = async {
+                let transfer = transfer(socket, &mut pending, &mut outbox, paused, peer);
+                match idle_timeout {
+                    Some(limit) => tokio::time::timeout(limit, transfer).await.ok(),
+                    None => Some(transfer.await),
+                }
+            };
+            // Whichever branch wins, the others are dropped mid-await, and the
+            // loop comes back here to wait again. That is only correct because
+            // every branch is cancel-safe, i.e. dropping it loses nothing:
+            //
+            // - `read_buf` reads into `pending` only when it completes, so a
+            //   message is never half-consumed; the codec resumes from `pending`
+            //   (what `FramedRead` does, with the buffer kept in our hands).
+            //   Likewise, written replies leave the outbox only once written.
+            //   The idle timeout wraps the transfer alone, and restarts with it.
+            // - `config.changed()` and `wait_draining` watch a channel whose
+            //   version is kept in the receiver, so a change is seen later.
+            // - `kicked()` waits on the connection's `CancellationToken`, which
+            //   stays cancelled; the reason is kept in the registry.
+            // - `pulse.tick()` only awaits its `Interval`.
+            //
+            // The tests `a_partial_message_survives_the_branches_that_win` and
+            // `a_kick_is_not_lost_when_kicked_loses_a_select` hold this in place.
+            let n = tokio::select! {
+                result = read => match result {
+                    Some(Transferred::Read(n)) => n?,
+                    Some(Transferred::Written(n)) => {
+                        let n = n?;
+                        tracer.record(TraceEvent::Written(n)).await;
+                        if outbox.is_empty() {
+                            state.metrics.incr(metrics::WRITE_BATCHES, 1);
+                        }
+                        continue;
+                    }
+                    None => {
+                        outbox.push("BYE: idle timeout\n");
+                        break CloseReason::IdleTimeout;
+                    }
+                },
+                // A request that ran concurrently is done; its reply takes its place
+                Some((slot, reply)) = running.next(), if !running.is_empty() => {
+                    outbox.fill(slot, reply);
+                    continue;
+                }
+                // A reload while waiting: apply it above, then wait again
+                // (a disabled branch if the config sender is gone)
+                Ok(()) = config.changed() => continue,
+                reason = registration.kicked() => {
+                    outbox.push(farewell(reason));
+                    break reason;
+                }
+                beat = pulse.tick() => match beat {
+                    Beat::Ping => {
+                        outbox.push("PING\n");
+                        continue;
+                    }
+                    Beat::Dead => {
+                        outbox.push(farewell(CloseReason::HeartbeatTimeout));
+                        break CloseReason::HeartbeatTimeout;
+                    }
+                },
+                // `read` is cancel-safe, so nothing is lost if shutdown wins
+                _ = readiness::wait_draining(&mut phase) => {
+                    let response = "BYE: server is shutting down\n";
+                    outbox.push(response);
+                    break CloseReason::ServerShutdown;
+                }
+            };
+            tracer.record(TraceEvent::Read(n)).await;
+            pulse.received();
+
+            // Client closed its side; serve what is left in `pending`, then stop
+            if n == 0 {
+                eof = true;
+                continue;
+            }
+
+            // Control bytes mean the client speaks some other protocol
+            // (e.g. a TLS handshake); there is no point in answering line by line.
+            // Length-prefixed messages may carry any byte.
+            let binary = pending[start..]
+                .iter()
+                .any(|b| b.is_ascii_control() && !b.is_ascii_whitespace());
+            if binary && !codec.binary_safe() {
+                let response = "ERR: binary data is not supported\n";
+                outbox.push(response);
+                break CloseReason::ProtocolError;
+            }
+            continue;
+        };
+
+        // `slice_ref` keeps sharing the buffer: no copy of the line is made.
+        // `from_utf8_lossy` tolerates invalid UTF-8 input, and only
+        // allocates when it has to replace something.
+        // A length-prefixed payload is taken exactly as sent.
+        let line = match wire.framing {
+            Framing::Lines => message.payload.slice_ref(message.payload.trim_ascii()),
+            Framing::LengthPrefixed => message.payload,
+        };
+        // A MessagePack map or array: this client speaks MessagePack from now on
+        #[cfg(feature = "msgpack")]
+        if std::mem::take(&mut negotiate) && crate::protocol::is_msgpack(&line) {
+            wire.protocol = Protocol::MessagePack;
+            outbox.set_protocol(Protocol::MessagePack);
+        }
+        // A session of an earlier connection, taken over with its mode.
+        // Allowed before HELLO, since a resumed session has picked one already.
+        if let Some(arg) = parse_command(&String::from_utf8_lossy(&line), "RESUME") {
+            // The replies of the running requests are encoded for the old mode
+            settle(&mut running, &mut outbox).await;
+            let response = match arg.map(|arg| (arg, SessionId::parse(arg))) {
+                Some((_, Some(id))) => match shared.sessions.take(&id) {
+                    Some(saved) => {
+                        if let Some(saved) = saved.handshake {
+                            handshake = saved;
+                            apply_handshake(handshake, &mut wire, &mut outbox, &config);
+                        }
+                        state.metrics.incr(metrics::SESSIONS_RESUMED, 1);
+                        let conn = registration.id();
+                        let text = format!("connection #{} resumed session {}", conn, id);
+                        log_tx.send(LogMessage::info(text)).await;
+                        let response = resumed(&saved);
+                        session.restore(saved);
+                        response
+                    }
+                    None => format!("ERR: no session {} to resume\n", id),
+                },
+                Some((arg, None)) => format!("ERR: no session {} to resume\n", arg),
+                None => "ERR: usage: RESUME <session id>\n".to_string(),
+            };
+            outbox.push(response);
+            continue;
+        }
+        // The answer to a heartbeat; not a request, so it gets no reply
+        if pulse.enabled() && heartbeat::is_pong(&line, wire.protocol) {
+            pulse.pong();
+            continue;
+        }
+        // Until the client has picked a mode, HELLO is all it may send
+        if handshake == Handshake::AwaitingHello {
+            let response = handshake.hello(&String::from_utf8_lossy(&line));
+            outbox.push(response);
+            if handshake != Handshake::AwaitingHello {
+                session.set_handshake(handshake);
+            }
+            apply_handshake(handshake, &mut wire, &mut outbox, &config);
+            continue;
+        }
+        outbox.start_request();
+        // A JSON or MessagePack request is served as the equivalent command line
+        let line = match wire.protocol {
+            Protocol::Text | Protocol::Socks5 | Protocol::Tls => line,
+            _ => match Request::parse(&line, wire.protocol) {
+                Ok(request) => Bytes::from(request.command_line()),
+                Err(response) => {
+                    state.metrics.incr(metrics::MESSAGES_MALFORMED, 1);
+                    outbox.push_response(response);
+                    continue;
+                }
+            },
+        };
+        // Invalid UTF-8 is served as U+FFFD, refused, or logged in hex; see `Utf8Mode`
+        if let Err(e) = std::str::from_utf8(&line) {
+            state.metrics.incr(metrics::MESSAGES_INVALID_UTF8, 1);
+            let response = format!("ERR: invalid UTF-8 at byte {}\n", e.valid_up_to());
+            match utf8 {
+                Utf8Mode::Lossy | Utf8Mode::Binary => {}
+                Utf8Mode::Strict => {
+                    outbox.push(response);
+                    continue;
+                }
+                Utf8Mode::StrictClose => {
+                    outbox.push(response);
+                    break CloseReason::ProtocolError;
+                }
+            }
+        }
+        let input = String::from_utf8_lossy(&line);
+        tracer.record(TraceEvent::Message(auth::redact(&input))).await;
+
+        // With `SLOWMODE`, the reply waits, as from a slow server; earlier
+        // replies are sent first. A `KICK` or the idle sweeper cuts it short.
+        let delay = slowmode.unwrap_or_else(|| state.slowmode());
+        if !delay.is_zero() {
+            flush_replies(socket, &mut outbox, peer, state, tracer).await?;
+            tracer.record(TraceEvent::Waiting("slowmode")).await;
+            let token = registration.token();
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = token.cancelled() => {}
+            }
+            state.metrics.incr(metrics::REPLIES_SLOWED, 1);
+        }
+
+        // Earlier replies are sent first, so a delayed message does not hold them back
+        if chain.may_delay() {
+            flush_replies(socket, &mut outbox, peer, state, tracer).await?;
+        }
+        let request = middleware::Request { line: &line, input: &input, registration, session };
+        // From here on, the request as the script rewrote it, if it did
+        let input = match chain.run(&request, tracer).await {
+            ControlFlow::Continue(rewritten) => rewritten.map_or(input, Cow::Owned),
+            ControlFlow::Break(Stop::Reply(response)) => {
+                outbox.push(response);
+                continue;
+            }
+            ControlFlow::Break(Stop::Close(response, reason)) => {
+                outbox.push(response);
+                break reason;
+            }
+        };
+
+        // A replica's data is the primary's: it only takes reads
+        if let Some(primary) = &replica_of
+            && auth::is_write(&input)
+        {
+            outbox.push(format!("ERR: read-only replica of {}\n", primary));
+            continue;
+        }
+
+        // KV mode only takes the key-value commands
+        let kv_only = KvCommand::parse(&input).is_some() || TxCommand::parse(&input).is_some();
+        if handshake.mode() == Some(Mode::Kv) && !kv_only {
+            let response = "ERR: KV mode only takes key-value commands and MULTI\n";
+            outbox.push(response);
+            continue;
+        }
+
+        // ECHO mode answers every message as a plain one
+        if handshake.mode() != Some(Mode::Echo) {
+            // After MULTI, key-value commands wait for EXEC to run them under one lock
+            let response = match (TxCommand::parse(&input), transaction.as_mut()) {
+                (Some(TxCommand::Multi), None) => {
+                    transaction = Some(Transaction::default());
+                    Some("OK\n".to_string())
+                }
+                (Some(TxCommand::Multi), Some(_)) => Some("ERR: MULTI cannot be nested\n".into()),
+                (Some(TxCommand::Exec), Some(_)) => {
+                    let response = transaction.take().unwrap_or_default().exec(&state.kv);
+                    match state.kv.synced().await {
+                        Ok(()) => Some(response),
+                        Err(e) => Some(format!("ERR: {}\n", e)),
+                    }
+                }
+                (Some(TxCommand::Discard), Some(queued)) => {
+                    let response = format!("OK: discarded {} command(s)\n", queued.len());
+                    transaction = None;
+                    Some(response)
+                }
+                (Some(_), None) => {
+                    Some(format!("ERR: {} without MULTI\n", input.trim().to_ascii_uppercase()))
+                }
+                (None, Some(queued)) => Some(queued.queue(&input)),
+                (None, None) => None,
+            };
+            if let Some(response) = response {
+                outbox.push(response);
+                continue;
+            }
+
+            // STATS reads the same instrumentation layer the exporters use
+            if input.eq_ignore_ascii_case("STATS") {
+                let response = state.stat_lines();
+                outbox.push(response);
+                continue;
+            }
+
+            // Delays replies, to test how a client copes with a slow server
+            if let Some(arg) = parse_command(&input, "SLOWMODE") {
+                // It slows every connection down, so it is checked here too
+                let response = match arg.map(parse_slowmode) {
+                    _ if !chain.is_admin() => {
+                        "ERR: only an admin token or certificate may do that\n".to_string()
+                    }
+                    Some(Some((true, delay))) => {
+                        state.set_slowmode(delay);
+                        format!("OK: every reply waits {}ms\n", delay.as_millis())
+                    }
+                    Some(Some((false, delay))) => {
+                        slowmode = Some(delay);
+                        format!("OK: this connection's replies wait {}ms\n", delay.as_millis())
+                    }
+                    Some(None) => {
+                        let max = MAX_SLOWMODE.as_millis();
+                        format!("ERR: usage: SLOWMODE [ALL] <ms>, at most {}ms\n", max)
+                    }
+                    None => {
+                        let delay = slowmode.unwrap_or_else(|| state.slowmode()).as_millis();
+                        let all = state.slowmode().as_millis();
+                        format!("SLOWMODE {}ms (all connections {}ms)\n", delay, all)
+                    }
+                };
+                outbox.push(response);
+                continue;
+            }
+
+            // What this connection has sent so far
+            if input.eq_ignore_ascii_case("SESSION") {
+                let response = format!("SESSION {} {}\n", session.id(), session.stats());
+                outbox.push(response);
+                continue;
+            }
+
+            // The newest logged messages, read back from the SQLite database
+            if let Some(arg) = parse_command(&input, "HISTORY") {
+                let n = arg.and_then(|n| n.parse::<u32>().ok()).filter(|n| *n > 0);
+                let response = match (n, &shared.history) {
+                    // Other clients' messages and addresses are for admins only
+                    _ if !chain.is_admin() => {
+                        "ERR: only an admin token or certificate may do that\n".to_string()
+                    }
+                    (None, _) => {
+                        format!("ERR: usage: HISTORY <n>, at most {}\n", history::MAX_ROWS)
+                    }
+                    (Some(_), None) => "ERR: no history, start with --history-db\n".to_string(),
+                    (Some(n), Some(history)) => match history.last(n).await {
+                        Ok(rows) => {
+                            let mut response: String = rows.iter().map(|row| row.line()).collect();
+                            response.push_str("END\n");
+                            response
+                        }
+                        Err(e) => format!("ERR: {}\n", e),
+                    },
+                };
+                outbox.push(response);
+                continue;
+            }
+
+            // Every KV command, compound ones included, is a single call into the store
+            if let Some(command) = KvCommand::parse(&input) {
+                let response = match command {
+                    Ok(command) => {
+                        let reply = state.kv.execute(command);
+                        // With `sync` durability, only once the change is on disk
+                        match state.kv.synced().await {
+                            Ok(()) => format!("{}\n", reply),
+                            Err(e) => format!("ERR: {}\n", e),
+                        }
+                    }
+                    Err(usage) => format!("ERR: {}\n", usage),
+                };
+                outbox.push(response);
+                continue;
+            }
+
+            // SCHEDULE only enqueues the job; the scheduler task runs it later
+            if let Some(command) = scheduler::parse_schedule(&input) {
+                let response = match command {
+                    Ok((delay, text)) => {
+                        let job = Job::Message { conn_id: registration.id(), text };
+                        match shared.scheduler.after(delay, job) {
+                            Ok(id) => format!("OK: job #{} runs in {}s\n", id, delay.as_secs_f64()),
+                            Err(e) => format!("ERR: {}\n", e),
+                        }
+                    }
+                    Err(usage) => format!("ERR: {}\n", usage),
+                };
+                outbox.push(response);
+                continue;
+            }
+
+            // PUBLISH hands the message to the topic's subscribers, or to the broker
+            // task if it has to wait; a durable topic's goes to its queue instead
+            if let Some(command) = pubsub::parse_publish(&input) {
+                let queues = shared.queues.as_ref();
+                let durable = |publish: &Publish| queues.filter(|q| q.is_durable(&publish.topic));
+                if let Some(queues) = command.as_ref().ok().and_then(durable) {
+                    outbox.push(publish_durable(queues, command.unwrap()).await);
+                    continue;
+                }
+                let response = match command.and_then(|publish| shared.broker.publish(publish)) {
+                    Ok(Delivery::Delivered(n)) => format!("OK: delivered to {} subscriber(s)\n", n),
+                    Ok(Delivery::Delayed { id, delay }) => {
+                        format!("OK: message #{} publishes in {}s\n", id, delay.as_secs_f64())
+                    }
+                    Ok(Delivery::Held(ttl)) => {
+                        let secs = ttl.as_secs_f64();
+                        format!("OK: no subscribers, held for {}s\n", secs)
+                    }
+                    Ok(Delivery::Dropped) => "OK: no subscribers, dropped\n".to_string(),
+                    Err(e) => format!("ERR: {}\n", e),
+                };
+                outbox.push(response);
+                continue;
+            }
+
+            // PUT is answered with READY, then the body follows as raw bytes
+            if let Some(put) = upload::parse_put(&input) {
+                settle(&mut running, &mut outbox).await;
+                let (dir, max_upload) = {
+                    let current = config.borrow();
+                    (current.upload_dir.clone(), current.max_upload)
+                };
+                let (name, size) = match put {
+                    Ok((name, size)) if size <= max_upload => (name, size),
+                    Ok(_) => {
+                        let response =
+                            format!("ERR: file too large, the limit is {} bytes\n", max_upload);
+                        outbox.push(response);
+                        continue;
+                    }
+                    Err(usage) => {
+                        outbox.push(format!("ERR: {}\n", usage));
+                        continue;
+                    }
+                };
+                let mut upload = match Upload::create(&dir, &name, size).await {
+                    Ok(upload) => upload,
+                    Err(e) => {
+                        let text =
+                            format!("cannot store upload {} in {}: {}", name, dir.display(), e);
+                        log_tx.send(LogMessage::error(text)).await;
+                        outbox.push(format!("ERR: cannot store {}\n", name));
+                        continue;
+                    }
+                };
+                outbox.push(format!("READY: send {} bytes\n", size));
+                flush_replies(socket, &mut outbox, peer, state, tracer).await?;
+
+                tracer.record(TraceEvent::Waiting("upload")).await;
+                let received =
+                    receive_upload(socket, &mut pending, &mut upload, idle_timeout, peer, shared);
+                if let Err(e) = received.await {
+                    upload.abort().await;
+                    return Err(e);
+                }
+                let path = upload.path().to_path_buf();
+                let response = match upload.finish().await {
+                    Ok(written) => {
+                        state.metrics.incr(metrics::UPLOAD_BYTES, written);
+                        let text =
+                            format!("{} uploaded {} ({} bytes)", peer, path.display(), written);
+                        log_tx.send(LogMessage::info(text)).await;
+                        format!("OK: stored {} ({} bytes)\n", name, written)
+                    }
+                    Err(e) => {
+                        let text = format!("cannot store upload {}: {}", path.display(), e);
+                        log_tx.send(LogMessage::error(text)).await;
+                        format!("ERR: cannot store {}\n", name)
+                    }
+                };
+                outbox.push(response);
+                continue;
+            }
+
+            // GET-FILE answers with a FILE header, then the raw contents
+            if let Some(name) = upload::parse_get_file(&input) {
+                settle(&mut running, &mut outbox).await;
+                let name = match name {
+                    Ok(name) => name,
+                    Err(usage) => {
+                        outbox.push(format!("ERR: {}\n", usage));
+                        continue;
+                    }
+                };
+                let dir = config.borrow().upload_dir.clone();
+                let (file, len) = match upload::open(&dir, &name).await {
+                    Ok(opened) => opened,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                        outbox.push(format!("ERR: no file named {}\n", name));
+                        continue;
+                    }
+                    Err(e) => {
+                        let text = format!("cannot open {} in {}: {}", name, dir.display(), e);
+                        log_tx.send(LogMessage::error(text)).await;
+                        outbox.push(format!("ERR: cannot read {}\n", name));
+                        continue;
+                    }
+                };
+                outbox.push(format!("FILE {}\n", len));
+                flush_replies(socket, &mut outbox, peer, state, tracer).await?;
+
+                tracer.record(TraceEvent::Waiting("download")).await;
+                match upload::send(file, len, socket).await {
+                    Ok(sent) => {
+                        tracer.record(TraceEvent::Written(sent as usize)).await;
+                        state.metrics.incr(metrics::DOWNLOAD_BYTES, sent);
+                    }
+                    // The client hung up mid-transfer; there is nobody left to answer
+                    Err(e) if is_disconnect(&e) => {
+                        state.metrics.incr(metrics::REQUESTS_CANCELLED, 1);
+                        let text = format!("download of {} by {} cancelled: {}", name, peer, e);
+                        log_tx.send(LogMessage::info(text)).await;
+                        break CloseReason::ClientEof;
+                    }
+                    // The client counts on `len` bytes, so after a short copy the
+                    // stream is out of step and can only be closed
+                    Err(source) if source.kind() == io::ErrorKind::UnexpectedEof => {
+                        return Err(ServerError::File { path: dir.join(&name), source });
+                    }
+                    Err(source) => return Err(ServerError::Write { peer, source }),
+                }
+                continue;
+            }
+
+            // With `--max-in-flight` above 1, slow requests run side by side
+            // while the next ones are read; see `Outbox::reserve`
+            if max_in_flight > 1
+                && let Some(request) = Concurrent::parse(&input)
+            {
+                if running.len() >= max_in_flight {
+                    tracer.record(TraceEvent::Waiting("in-flight limit")).await;
+                }
+                while running.len() >= max_in_flight {
+                    let (slot, reply) = running.next().await.expect("requests are running");
+                    outbox.fill(slot, reply);
+                }
+                let slot = outbox.reserve();
+                running.push(Box::pin(async move { (slot, request.run(shared).await) }));
+                continue;
+            }
+
+            // FIB is answered by the compute task; this task sends the request
+            // and waits for the reply on a oneshot channel
+            if let Some(n) = parse_command(&input, "FIB") {
+                outbox.push(fib(shared, n).await);
+                continue;
+            }
+
+            // HASH is CPU-bound: the connection task only waits for the result
+            if let Some(data) = parse_command(&input, "HASH") {
+                flush_replies(socket, &mut outbox, peer, state, tracer).await?;
+                outbox.push(hash(shared, data.map(str::to_string)).await);
+                continue;
+            }
+
+            // CHECKSUM only starts the job; the result arrives in the server log
+            if input.eq_ignore_ascii_case("CHECKSUM") {
+                checksum::spawn(PathBuf::from(STDIN_LOG), log_tx.clone());
+                let response = format!("OK: checksumming {}, see the server log\n", STDIN_LOG);
+                outbox.push(response);
+                continue;
+            }
+
+            // REPLICATE streams the store's changes to a replica, after a snapshot
+            if input.eq_ignore_ascii_case("REPLICATE") {
+                if let Some(primary) = &replica_of {
+                    outbox.push(format!("ERR: this is a replica of {}\n", primary));
+                    continue;
+                }
+                settle(&mut running, &mut outbox).await;
+                let (pending, outbox) = (&mut pending, &mut outbox);
+                let streamed =
+                    stream_replication(socket, pending, outbox, registration, shared, tracer);
+                match streamed.await? {
+                    Some(reason) => break reason,
+                    None => continue,
+                }
+            }
+
+            // CONSUME streams a durable topic's messages, and takes their ACKs
+            if let Some(topic) = parse_command(&input, "CONSUME") {
+                let consumer = match (topic, &shared.queues) {
+                    (None, _) => Err("usage: CONSUME <topic>".to_string()),
+                    (Some(_), None) => Err("no topic is durable, see --durable-topic".to_string()),
+                    (Some(topic), Some(queues)) => queues.consume(topic),
+                };
+                let consumer = match consumer {
+                    Ok(consumer) => consumer,
+                    Err(e) => {
+                        outbox.push(format!("ERR: {}\n", e));
+                        continue;
+                    }
+                };
+                let topic = topic.unwrap_or_default();
+                outbox.push(format!("OK: consuming {}, ACK <id> each message\n", topic));
+                settle(&mut running, &mut outbox).await;
+                let (pending, outbox) = (&mut pending, &mut outbox);
+                let streamed =
+                    stream_queue(socket, pending, outbox, registration, shared, tracer, consumer);
+                match streamed.await? {
+                    Some(reason) => break reason,
+                    None => continue,
+                }
+            }
+
+            // TAIL, WATCH and SUBSCRIBE stream a feed until the client sends something
+            let feed = if input.eq_ignore_ascii_case("TAIL") {
+                outbox.push(format!("OK: tailing {}, send anything to stop\n", STDIN_LOG));
+                Some(shared.tailer.subscribe())
+            } else if input.eq_ignore_ascii_case("WATCH") {
+                match &shared.watcher {
+                    Some(watcher) => {
+                        let dir = watcher.dir().display();
+                        outbox.push(format!("OK: watching {}, send anything to stop\n", dir));
+                        Some(watcher.subscribe())
+                    }
+                    None => {
+                        outbox.push("ERR: no directory is being watched\n");
+                        continue;
+                    }
+                }
+            } else if let Some(topic) = parse_command(&input, "SUBSCRIBE") {
+                let filters: Vec<&str> = topic.unwrap_or_default().split_whitespace().collect();
+                match shared.broker.subscribe(&filters) {
+                    Ok(feed) => {
+                        let text = filters.join(" ");
+                        outbox.push(format!("OK: subscribed to {}, send anything to stop\n", text));
+                        Some(feed)
+                    }
+                    Err(e) => {
+                        outbox.push(format!("ERR: {}\n", e));
+                        continue;
+                    }
+                }
+            } else {
+                None
+            };
+            if let Some(feed) = feed {
+                settle(&mut running, &mut outbox).await;
+                let streamed = stream_feed(
+                    socket,
+                    &mut pending,
+                    &mut outbox,
+                    registration,
+                    shared,
+                    tracer,
+                    feed,
+                );
+                match streamed.await? {
+                    Some(reason) => break reason,
+                    None => continue,
+                }
+            }
+
+            // SLEEP <ms> simulates a slow request, so client-side timeouts can be tried out
+            if let Some(ms) = input.strip_prefix("SLEEP ").and_then(|ms| ms.trim().parse().ok()) {
+                // Partial state of the request lives in this guard; it is cleaned
+                // up in `Drop`, no matter how the request ends
+                let _in_flight = InFlight::new(&state.metrics);
+                flush_replies(socket, &mut outbox, peer, state, tracer).await?;
+
+                tracer.record(TraceEvent::Waiting("sleep")).await;
+                tokio::select! {
+                    _ = tokio::time::sleep(std::time::Duration::from_millis(ms)) => {}
+                    // The client gave up (e.g. its timeout fired and it disconnected):
+                    // abandon the request instead of finishing work nobody waits for
+                    _ = socket.peer_closed() => {
+                        state.metrics.incr(metrics::REQUESTS_CANCELLED, 1);
+                        let text = format!(
+                            "request {:?} from {} cancelled: client went away",
+                            input, peer,
+                        );
+                        log_tx.send(LogMessage::info(text)).await;
+                        break CloseReason::ClientEof;
+                    }
+                    // A kick does not wait for the request to finish either
+                    reason = registration.kicked() => {
+                        state.metrics.incr(metrics::REQUESTS_CANCELLED, 1);
+                        outbox.push(farewell(reason));
+                        break reason;
+                    }
+                }
+
+                let response = format!("OK: slept {}ms\n", ms);
+                outbox.push(response);
+                continue;
+            }
+        }
+
+        // A replica answers with the primary's count, instead of counting
+        let current = if replica_of.is_some() { state.count() } else { state.increment() };
+
+        // The latest flags, copied out so the read lock is released at once
+        let flags = *shared.flags.borrow();
+        let echoed = if flags.echo_uppercase { input.to_uppercase().into() } else { input };
+        // The configured template, unless the client picked another format
+        let formatter = handshake.format().formatter(&template);
+        let response = formatter.format(&template::Context {
+            input: &echoed,
+            request: current,
+            peer,
+            conn_id: registration.id(),
+        });
+        if flags.verbose_logging {
+            let text = format!("reply to #{}: {}", registration.id(), response.trim_end());
+            log_tx.send(LogMessage::info(text)).await;
+        }
+
+        outbox.push(response);
+    };
+
+    // A client that only closed its sending side still waits for the replies
+    // of its running requests; otherwise nobody does
+    let settled = reason == CloseReason::ClientEof
+        && tokio::select! {
+            _ = settle(&mut running, &mut outbox) => true,
+            _ = socket.peer_closed() => false,
+        };
+    if !settled && !running.is_empty() {
+        state.metrics.incr(metrics::REQUESTS_CANCELLED, running.len() as u64);
+        drop(running);
+        outbox.abandon();
+    }
+
+    // Replies still queued, such as the `BYE` line of a server-side close.
+    // A client closed for not reading gets none: it would not read them.
+    if reason != CloseReason::SlowClient {
+        flush_replies(socket, &mut outbox, peer, state, tracer).await?;
+    }
+    Ok(reason)
+}
+
+/// Streams the lines of a feed (`TAIL`, `WATCH`, `SUBSCRIBE`) to the client, until it
+/// sends anything; that input is then served as usual.
+///
+/// Returns the reason to close the connection if it ended while streaming.
+async fn stream_feed<S: Transport>(
+    socket: &mut S,
+    pending: &mut BytesMut,
+    outbox: &mut Outbox,
+    registration: &mut Registration,
+    shared: &Shared,
+    tracer: &mut ConnTracer,
+    mut feed: Subscription,
+) -> Result<Option<CloseReason>, ServerError> {
+    let peer = registration.peer();
+    let mut phase = shared.phase.clone();
+
+    let reason = loop {
+        flush_replies(socket, outbox, peer, &shared.state, tracer).await?;
+        // Input that was already buffered stops the tail right away
+        if !pending.is_empty() {
+            break None;
+        }
+        tracer.record(TraceEvent::Waiting("feed")).await;
+        pending.reserve(BUFFER_SIZE);
+        tokio::select! {
+            line = feed.recv() => match line {
+                Some(line) => outbox.push(format!("{}\n", String::from_utf8_lossy(&line))),
+                // The feed has stopped, because the server is draining
+                None => break None,
+            },
+            read = socket.read_buf(pending) => {
+                let n = read.map_err(|source| ServerError::Read { peer, source })?;
+                tracer.record(TraceEvent::Read(n)).await;
+                if n == 0 {
+                    break Some(CloseReason::ClientEof);
+                }
+            }
+            reason = registration.kicked() => {
+                outbox.push(farewell(reason));
+                break Some(reason);
+            }
+            _ = readiness::wait_draining(&mut phase) => {
+                outbox.push("BYE: server is shutting down\n");
+                break Some(CloseReason::ServerShutdown);
+            }
+        }
+    };
+    if reason.is_none() {
+        outbox.push("END: feed stopped\n");
+    }
+    Ok(reason)
+}
+
+/// `PUBLISH` to a durable topic: answered once the message is on disk.
+async fn publish_durable(queues: &Queues, publish: Publish) -> String {
+    if publish.delay.is_some() || publish.ttl.is_some() {
+        return format!("ERR: {} is durable, its messages cannot wait or expire\n", publish.topic);
+    }
+    match queues.publish(&publish.topic, &publish.text).await {
+        Ok(id) => format!("OK: queued as #{}\n", id),
+        Err(e) => format!("ERR: {}\n", e),
+    }
+}
+
+/// Streams the messages of a durable topic to a `CONSUME`r and answers its
+/// `ACK`s, until it sends anything else; that input is then served as
+/// usual. What it did not acknowledge goes to the next consumer.
+///
+/// Returns the reason to close the connection if it ended while streaming.
+async fn stream_queue<S: Transport>(
+    socket: &mut S,
+    pending: &mut BytesMut,
+    outbox: &mut Outbox,
+    registration: &mut Registration,
+    shared: &Shared,
+    tracer: &mut ConnTracer,
+    mut consumer: Consumer,
+) -> Result<Option<CloseReason>, ServerError> {
+    let peer = registration.peer();
+    let mut phase = shared.phase.clone();
+
+    let reason = loop {
+        while let Some(ack) = take_ack(pending) {
+            let acked = ack.and_then(|id| consumer.ack(id).map(|()| id));
+            match acked {
+                Ok(id) => outbox.push(format!("OK: acknowledged #{}\n", id)),
+                Err(e) => outbox.push(format!("ERR: {}\n", e)),
+            }
+        }
+        flush_replies(socket, outbox, peer, &shared.state, tracer).await?;
+        // Input that cannot be an `ACK` stops the consumer right away
+        if !pending.is_empty() && !partial_ack(pending) {
+            break None;
+        }
+        tracer.record(TraceEvent::Waiting("queue")).await;
+        pending.reserve(BUFFER_SIZE);
+        tokio::select! {
+            line = consumer.recv() => match line {
+                Some(line) => outbox.push(format!("{}\n", String::from_utf8_lossy(&line))),
+                None => break None,
+            },
+            read = socket.read_buf(pending) => {
+                let n = read.map_err(|source| ServerError::Read { peer, source })?;
+                tracer.record(TraceEvent::Read(n)).await;
+                if n == 0 {
+                    break Some(CloseReason::ClientEof);
+                }
+            }
+            reason = registration.kicked() => {
+                outbox.push(farewell(reason));
+                break Some(reason);
+            }
+            _ = readiness::wait_draining(&mut phase) => {
+                outbox.push("BYE: server is shutting down\n");
+                break Some(CloseReason::ServerShutdown);
+            }
+        }
+    };
+    if reason.is_none() {
+        outbox.push("END: consumer stopped\n");
+    }
+    Ok(reason)
+}
+
+/// Takes a complete `ACK <id>` line off the front of `pending`, with the
+/// id, or the usage if it is not one. `None` if the first line is not an
+/// `ACK`, or not complete yet.
+fn take_ack(pending: &mut BytesMut) -> Option<Result<u64, String>> {
+    let end = pending.iter().position(|b| *b == b'\n')?;
+    let line = std::str::from_utf8(&pending[..end]).ok()?;
+    let id = parse_command(line.trim(), "ACK")?;
+    let id = id.and_then(|id| id.parse().ok()).ok_or_else(|| "usage: ACK <id>".to_string());
+    pending.advance(end + 1);
+    Some(id)
+}
+
+/// Whether the start of a line in `pending` may still turn out to be an `ACK`.
+fn partial_ack(pending: &[u8]) -> bool {
+    let start = &pending[..pending.len().min(4)];
+    !pending.contains(&b'\n') && start.eq_ignore_ascii_case(&b"ACK "[..start.len()])
+}
+
+/// Sends a replica a snapshot of the store and the counter, then every
+/// change to either, until it sends anything; see `crate::replica`.
+///
+/// Returns the reason to close the connection if it ended while streaming.
+async fn stream_replication<S: Transport>(
+    socket: &mut S,
+    pending: &mut BytesMut,
+    outbox: &mut Outbox,
+    registration: &mut Registration,
+    shared: &Shared,
+    tracer: &mut ConnTracer,
+) -> Result<Option<CloseReason>, ServerError> {
+    let (peer, state) = (registration.peer(), &shared.state);
+    let mut phase = shared.phase.clone();
+    let mut count = state.watch_count();
+    let (entries, seq, mut changes) = state.kv.replicate();
+    outbox.push(replica::snapshot_header(seq, *count.borrow_and_update(), entries.len()));
+    for (key, value) in entries {
+        outbox.push(format!("{} {}\n", key, value));
+    }
+    let text = format!("connection #{} from {} is replicating", registration.id(), peer);
+    shared.log_tx.send(LogMessage::info(text)).await;
+
+    let reason = loop {
+        flush_replies(socket, outbox, peer, state, tracer).await?;
+        if !pending.is_empty() {
+            break None;
+        }
+        tracer.record(TraceEvent::Waiting("replication")).await;
+        pending.reserve(BUFFER_SIZE);
+        tokio::select! {
+            change = changes.recv() => match change {
+                Some(change) => {
+                    outbox.push(format!("CHANGE {}\n", String::from_utf8_lossy(&change)));
+                }
+                None => break None,
+            },
+            // Only the latest count: the ones in between do not matter
+            Ok(()) = count.changed() => {
+                outbox.push(format!("COUNT {}\n", *count.borrow_and_update()));
+            }
+            read = socket.read_buf(pending) => {
+                let n = read.map_err(|source| ServerError::Read { peer, source })?;
+                tracer.record(TraceEvent::Read(n)).await;
+                if n == 0 {
+                    break Some(CloseReason::ClientEof);
+                }
+            }
+            reason = registration.kicked() => {
+                outbox.push(farewell(reason));
+                break Some(reason);
+            }
+            _ = readiness::wait_draining(&mut phase) => {
+                outbox.push("BYE: server is shutting down\n");
+                break Some(CloseReason::ServerShutdown);
+            }
+        }
+    };
+    if reason.is_none() {
+        outbox.push("END: replication stopped\n");
+    }
+    Ok(reason)
+}
+
+/// Pipes the client to one of the upstreams until either side is done.
+async fn proxy_connection<S: Transport>(
+    socket: &mut S,
+    upstreams: &Upstreams,
+    shared: &Shared,
+    registration: &mut Registration,
+    tracer: &mut ConnTracer,
+) -> Result<CloseReason, ServerError> {
+    let peer = registration.peer();
+
+    // Held until the connection ends, so least-connections sees it as open
+    let balance = shared.config.borrow().proxy_balance;
+    let Some(lease) = upstreams.pick(balance) else {
+        let source = io::Error::new(io::ErrorKind::NotConnected, "every upstream is down");
+        return Err(ServerError::Proxy { peer, upstream: "any upstream".to_string(), source });
+    };
+    let upstream = lease.addr();
+
+    tracer.record(TraceEvent::Waiting("upstream connect")).await;
+    let mut upstream_socket = lease
+        .connect()
+        .await
+        .map_err(|source| ServerError::Proxy { peer, upstream: upstream.to_string(), source })?;
+    tunnel(socket, &mut upstream_socket, upstream, shared, registration, tracer).await
+}
+
+/// Runs the SOCKS5 handshake, then tunnels the client to the target it asked for.
+async fn socks_connection<S: Transport>(
+    socket: &mut S,
+    shared: &Shared,
+    registration: &mut Registration,
+    tracer: &mut ConnTracer,
+) -> Result<CloseReason, ServerError> {
+    let peer = registration.peer();
+    let credentials = shared.config.borrow().socks_auth.clone();
+
+    tracer.record(TraceEvent::Waiting("socks handshake")).await;
+    let mut phase = shared.phase.clone();
+    let accepted = tokio::select! {
+        accepted = socks::accept(socket, credentials.as_ref(), &shared.dialer) => accepted,
+        reason = registration.kicked() => return Ok(reason),
+        _ = readiness::wait_draining(&mut phase) => return Ok(CloseReason::ServerShutdown),
+    };
+    let (target, mut outbound) = match accepted {
+        Ok(accepted) => accepted,
+        // The client has already been told; the log says why
+        Err(e) => {
+            let text = format!("SOCKS5 client {}: {}", peer, e);
+            shared.log_tx.send(LogMessage::error(text)).await;
+            if e.is_protocol_error() {
+                return Ok(CloseReason::ProtocolError);
+            }
+            return Ok(CloseReason::IoError);
+        }
+    };
+    tunnel(socket, &mut outbound, &target, shared, registration, tracer).await
+}
+
+/// Copies between the client and `outbound` (connected to `target`) until
+/// both directions are done.
+///
+/// The copy stops early when an administrator kicks the connection or the
+/// server drains; the bytes copied up to then are not counted.
+async fn tunnel<S: Transport>(
+    socket: &mut S,
+    outbound: &mut TcpStream,
+    target: &str,
+    shared: &Shared,
+    registration: &mut Registration,
+    tracer: &mut ConnTracer,
+) -> Result<CloseReason, ServerError> {
+    let peer = registration.peer();
+    let metrics = &shared.state.metrics;
+    let options = shared.config.borrow().socket;
+    if let Err(e) = options.apply(outbound) {
+        let text = format!("socket options for {}: {}", target, e);
+        shared.log_tx.send(LogMessage::error(text)).await;
+    }
+    let text = format!("proxying {} to {}", peer, target);
+    shared.log_tx.send(LogMessage::info(text)).await;
+
+    tracer.record(TraceEvent::Waiting("proxy")).await;
+    let mut phase = shared.phase.clone();
+    let proxy_error = |source| ServerError::Proxy { peer, upstream: target.to_string(), source };
+    let transferred = tokio::select! {
+        result = proxy::pipe(socket, outbound) => result.map_err(proxy_error)?,
+        reason = registration.kicked() => return Ok(reason),
+        _ = readiness::wait_draining(&mut phase) => return Ok(CloseReason::ServerShutdown),
+    };
+
+    metrics.incr(metrics::PROXY_BYTES_UP, transferred.up);
+    metrics.incr(metrics::PROXY_BYTES_DOWN, transferred.down);
+    let text = format!(
+        "proxied {} to {}: {} bytes up, {} bytes down",
+        peer, target, transferred.up, transferred.down,
+    );
+    shared.log_tx.send(LogMessage::info(text)).await;
+    Ok(CloseReason::ClientEof)
+}
+
+/// Moves the body of a `PUT` into its file: first what is already in
+/// `pending`, then what the socket delivers. Bytes after the body stay in
+/// `pending` for the next message.
+async fn receive_upload<S: Transport>(
+    socket: &mut S,
+    pending: &mut BytesMut,
+    upload: &mut Upload,
+    idle_timeout: Option<std::time::Duration>,
+    peer: SocketAddr,
+    shared: &Shared,
+) -> Result<(), ServerError> {
+    loop {
+        if let Err(source) = upload.write_from(pending).await {
+            return Err(ServerError::File { path: upload.path().to_path_buf(), source });
+        }
+        if let Some(written) = upload.progress() {
+            let text = format!(
+                "upload {} from {}: {} of {} bytes",
+                upload.path().display(),
+                peer,
+                written,
+                upload.size(),
+            );
+            shared.log_tx.send(LogMessage::info(text)).await;
+        }
+        if upload.remaining() == 0 {
+            return Ok(());
+        }
+
+        pending.reserve(BUFFER_SIZE);
+        let read = socket.read_buf(pending);
+        let stalled = || Err(io::Error::new(io::ErrorKind::TimedOut, "upload stalled"));
+        let n = match idle_timeout {
+            Some(limit) => tokio::time::timeout(limit, read).await.unwrap_or_else(|_| stalled()),
+            None => read.await,
+        }
+        .map_err(|source| ServerError::Read { peer, source })?;
+        if n == 0 {
+            let source =
+                io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed mid-upload");
+            return Err(ServerError::Read { peer, source });
+        }
+    }
+}
+
+/// Whether a write failed because the client closed the connection.
+fn is_disconnect(error: &io::Error) -> bool {
+    use io::ErrorKind::*;
+    matches!(error.kind(), BrokenPipe | ConnectionReset | ConnectionAborted)
+}
+
+/// The last line for a connection closed by `KICK` or the idle sweeper.
+fn farewell(reason: CloseReason) -> &'static str {
+    match reason {
+        CloseReason::IdleTimeout => "BYE: idle timeout\n",
+        CloseReason::HeartbeatTimeout => "BYE: no PONG in time\n",
+        _ => "BYE: disconnected by an administrator\n",
+    }
+}
+
+/// Switches the connection to what a finished handshake asked for.
+fn apply_handshake(handshake: Handshake, wire: &mut Wire, outbox: &mut Outbox, config: &ConfigRx) {
+    if handshake.mode() == Some(Mode::Json) {
+        wire.protocol = Protocol::Json;
+        outbox.set_protocol(Protocol::Json);
+    }
+    if handshake.gzip() {
+        outbox.set_gzip(Some(config.borrow().gzip_threshold));
+    }
+}
+
+/// The reply to a successful `RESUME`.
+fn resumed(saved: &SessionStats) -> String {
+    match saved.handshake.and_then(|handshake| handshake.mode()) {
+        Some(mode) => format!("OK: resumed session {}, {}, mode {}\n", saved.id, saved, mode),
+        None => format!("OK: resumed session {}, {}\n", saved.id, saved),
+    }
+}
+
+/// `Some(Some(arg))` for `<command> <arg>`, `Some(None)` for `<command>` alone.
+fn parse_command<'a>(input: &'a str, command: &str) -> Option<Option<&'a str>> {
+    let (name, data) = input.split_once(char::is_whitespace).unwrap_or((input, ""));
+    if !name.eq_ignore_ascii_case(command) {
+        return None;
+    }
+    let data = data.trim();
+    Some((!data.is_empty()).then_some(data))
+}
+
+/// The arguments of `SLOWMODE [ALL] <ms>`: whether it is for every
+/// connection, and the delay.
+fn parse_slowmode(arg: &str) -> Option<(bool, std::time::Duration)> {
+    let (all, ms) = match arg.split_once(char::is_whitespace) {
+        Some((all, ms)) if all.eq_ignore_ascii_case("ALL") => (true, ms.trim()),
+        Some(_) => return None,
+        None => (false, arg),
+    };
+    let delay = std::time::Duration::from_millis(ms.parse().ok()?);
+    (delay <= MAX_SLOWMODE).then_some((all, delay))
+}
+
+/// Closes the server's side of a connection without losing the last response.
+///
+/// Dropping a TCP socket that still has unread input makes the kernel send
+/// an RST instead of a FIN, and an RST can destroy data the client has not
+/// read yet, such as the `BYE` line. So the socket is flushed and its write
+/// half shut down (the client reads EOF right after the last response),
+/// then input is read and discarded until the client closes its side too,
+/// or `grace` passes. Nothing read then is served.
+async fn close_gracefully<S: Transport>(socket: &mut S, grace: std::time::Duration) -> Drain {
+    // Either failing only means the client is already gone
+    if socket.flush().await.is_err() || socket.shutdown().await.is_err() {
+        return Drain::ClientGone;
+    }
+    let mut buf = [0u8; 1024];
+    let drain = async {
+        loop {
+            match socket.read(&mut buf).await {
+                Ok(0) => return Drain::ClientClosed,
+                Ok(_) => continue,
+                Err(_) => return Drain::ClientGone,
+            }
+        }
+    };
+    tokio::time::timeout(grace, drain).await.unwrap_or(Drain::Forced)
+}
+
+/// Requests of one connection running side by side, each with the place
+/// its reply goes to.
+type Running<'a> = FuturesUnordered<Pin<Box<dyn Future<Output = (Slot, String)> + Send + 'a>>>;
+
+/// A request that can run while the connection goes on with the next ones:
+/// it only waits, and touches nothing of the connection.
+enum Concurrent {
+    Fib(Option<String>),
+    Hash(Option<String>),
+    Sleep(u64),
+}
+
+impl Concurrent {
+    fn parse(input: &str) -> Option<Self> {
+        if let Some(n) = parse_command(input, "FIB") {
+            Some(Self::Fib(n.map(str::to_string)))
+        } else if let Some(data) = parse_command(input, "HASH") {
+            Some(Self::Hash(data.map(str::to_string)))
+        } else {
+            let ms = input.strip_prefix("SLEEP ")?.trim().parse().ok()?;
+            Some(Self::Sleep(ms))
+        }
+    }
+
+    async fn run(self, shared: &Shared) -> String {
+        match self {
+            Self::Fib(n) => fib(shared, n.as_deref()).await,
+            Self::Hash(data) => hash(shared, data).await,
+            // Unlike the sequential `SLEEP`, this one is not watching the
+            // socket: the handler drops it when the connection ends
+            Self::Sleep(ms) => {
+                let _in_flight = InFlight::new(&shared.state.metrics);
+                tokio::time::sleep(std::time::Duration::from_millis(ms)).await;
+                format!("OK: slept {}ms\n", ms)
+            }
+        }
+    }
+}
+
+/// Waits for the running requests, and puts their replies in place.
+async fn settle(running: &mut Running<'_>, outbox: &mut Outbox) {
+    while let Some((slot, reply)) = running.next().await {
+        outbox.fill(slot, reply);
+    }
+}
+
+/// The reply to `FIB <n>`.
+async fn fib(shared: &Shared, n: Option<&str>) -> String {
+    match n.map(str::parse::<u32>) {
+        Some(Ok(n)) => match shared.compute.fib(n).await {
+            Ok(value) => format!("FIB {} {}\n", n, value),
+            Err(ComputeError::TooLarge) => {
+                format!("ERR: FIB is limited to n <= {}\n", compute::MAX_FIB)
+            }
+            Err(ComputeError::Gone) => "ERR: FIB is not available\n".to_string(),
+        },
+        _ => "ERR: usage: FIB <n>\n".to_string(),
+    }
+}
+
+/// The reply to `HASH <data>`.
+async fn hash(shared: &Shared, data: Option<String>) -> String {
+    match data {
+        Some(data) => match shared.workers.hash(data).await {
+            Ok(digest) => format!("HASH {:016x}\n", digest),
+            Err(PoolError::Busy) => "ERR: busy, try again later\n".to_string(),
+            Err(PoolError::Gone) => "ERR: hashing is not available\n".to_string(),
+        },
+        None => "ERR: usage: HASH <data>\n".to_string(),
+    }
+}
+
+/// Marks a request as in flight for as long as the guard is alive.
+struct InFlight<'a> {
+    metrics: &'a Metrics,
+}
+
+impl<'a> InFlight<'a> {
+    fn new(metrics: &'a Metrics) -> Self {
+        metrics.adjust(metrics::REQUESTS_IN_FLIGHT, 1);
+        Self { metrics }
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.metrics.adjust(metrics::REQUESTS_IN_FLIGHT, -1);
+    }
+}
+
+/// Writes as many queued replies as the socket takes without waiting.
+async fn write_ready<S: Transport>(
+    socket: &mut S,
+    outbox: &mut Outbox,
+    peer: SocketAddr,
+    state: &State,
+    tracer: &mut ConnTracer,
+) -> Result<(), ServerError> {
+    while outbox.has_remaining() {
+        let attempt = std::future::poll_fn(|cx| Poll::Ready(outbox.poll_write(cx, socket))).await;
+        let Poll::Ready(written) = attempt else { break };
+        let written = written.map_err(|source| ServerError::Write { peer, source })?;
+        tracer.record(TraceEvent::Written(written)).await;
+        if outbox.is_empty() {
+            state.metrics.incr(metrics::WRITE_BATCHES, 1);
+        }
+    }
+    Ok(())
+}
+
+/// What [`transfer`] did.
+enum Transferred {
+    Read(Result<usize, ServerError>),
+    Written(Result<usize, ServerError>),
+}
+
+/// Writes queued replies and, unless `paused`, reads input into `pending`,
+/// whichever the socket is ready for first.
+///
+/// Cancel-safe: each attempt is a single `poll_read` or `poll_write`, and
+/// nothing is taken from the outbox or added to `pending` until it is done.
+async fn transfer<S: Transport>(
+    socket: &mut S,
+    pending: &mut BytesMut,
+    outbox: &mut Outbox,
+    paused: bool,
+    peer: SocketAddr,
+) -> Transferred {
+    std::future::poll_fn(|cx| {
+        if outbox.has_remaining()
+            && let Poll::Ready(written) = outbox.poll_write(cx, socket)
+        {
+            let written = written.map_err(|source| ServerError::Write { peer, source });
+            return Poll::Ready(Transferred::Written(written));
+        }
+        if paused {
+            return Poll::Pending;
+        }
+        let read = std::pin::pin!(socket.read_buf(pending));
+        read.poll(cx).map(|read| {
+            Transferred::Read(read.map_err(|source| ServerError::Read { peer, source }))
+        })
+    })
+    .await
+}
+
+/// Writes the queued replies and records them in the trace and metrics.
+///
+/// One call is one (vectored) write however many replies were queued, so
+/// `write_batches` next to `messages_received` shows how well they batch.
+async fn flush_replies<S: Transport>(
+    socket: &mut S,
+    outbox: &mut Outbox,
+    peer: SocketAddr,
+    state: &State,
+    tracer: &mut ConnTracer,
+) -> Result<(), ServerError> {
+    if outbox.is_empty() {
+        return Ok(());
+    }
+    tracer.record(TraceEvent::Waiting("write")).await;
+    let written = outbox
+        .write_to(socket)
+        .await
+        .map_err(|source| ServerError::Write { peer, source })?;
+    tracer.record(TraceEvent::Written(written)).await;
+    state.metrics.incr(metrics::WRITE_BATCHES, 1);
+    Ok(())
+}
+
+/// The drain deadline, on paused time: the clock jumps ahead whenever every
+/// task waits on a timer, so waiting out `DRAIN_TIMEOUT` takes no real time.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listeners::ListenerStatus;
+    use tokio::time::{Duration, Instant};
+
+    async fn supervisor() -> (Supervisor, Controls) {
+        // `HASH` workers are blocking threads, which would keep the clock still
+        let config = ServerConfig { hash_workers: 0, ..Default::default() };
+        let (shared, controls) = Shared::start(config).await.unwrap();
+        (Supervisor::new(shared), controls)
+    }
+
+    fn spawn<F>(supervisor: &mut Supervisor, task: F)
+    where
+        F: Future<Output = CloseReason> + Send + 'static,
+    {
+        let handle = supervisor.tasks.spawn(task);
+        supervisor.started(handle.id(), "test connection".to_string());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn draining_waits_for_connections_that_close_in_time() {
+        let (mut supervisor, _controls) = supervisor().await;
+        spawn(&mut supervisor, async {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            CloseReason::ServerShutdown
+        });
+
+        let start = Instant::now();
+        supervisor.drain().await;
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn draining_aborts_connections_still_open_at_the_deadline() {
+        let (mut supervisor, _controls) = supervisor().await;
+        spawn(&mut supervisor, std::future::pending());
+        spawn(&mut supervisor, async {
+            tokio::time::sleep(Duration::from_secs(2)).await;
+            CloseReason::ServerShutdown
+        });
+
+        let start = Instant::now();
+        supervisor.drain().await;
+        assert_eq!(start.elapsed(), DRAIN_TIMEOUT);
+        assert!(supervisor.tasks.is_empty());
+    }
+
+    /// Waits, on real time, until `done` holds.
+    async fn eventually(done: impl Fn() -> bool) {
+        while !done() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn a_reload_adds_listeners_and_lets_removed_ones_drain() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let text: ListenerSpec = "text=127.0.0.1:0".parse().unwrap();
+        let config = ServerConfig { listeners: vec![text], ..Default::default() };
+        let (shared, controls) = Shared::start(config).await.unwrap();
+        set_phase(&controls.phase_tx, Phase::Ready);
+        let listeners = Arc::new(Listeners::new(shared.state.metrics.clone()));
+        let (drained_tx, mut drained_rx) = mpsc::channel(1);
+        let old = start_listener(text, &shared, &listeners, &drained_tx).await.unwrap();
+        task::spawn("listener manager", manage_listeners(shared, listeners.clone(), drained_tx));
+        let mut client = BufReader::new(TcpStream::connect(old).await.unwrap());
+
+        let json: ListenerSpec = "json=127.0.0.1:0".parse().unwrap();
+        let reloaded = ServerConfig { listeners: vec![json], ..Default::default() };
+        controls.config_tx.send_replace(Arc::new(reloaded));
+        eventually(|| listeners.local_addr(ListenerMode::Json).is_some()).await;
+        let status: Vec<_> = listeners.stats().iter().map(|l| (l.mode, l.status)).collect();
+        let draining = (ListenerMode::Text, ListenerStatus::Draining);
+        assert_eq!(status, [draining, (ListenerMode::Json, ListenerStatus::Accepting)]);
+
+        // The removed listener's socket is closed, its connection is not
+        assert!(TcpStream::connect(old).await.is_err());
+        client.write_all(b"hello\n").await.unwrap();
+        let mut reply = String::new();
+        client.read_line(&mut reply).await.unwrap();
+        assert!(reply.starts_with("OK: 'hello'"), "{}", reply);
+        drop(client);
+        eventually(|| listeners.stats().len() == 1).await;
+
+        set_phase(&controls.phase_tx, Phase::Draining);
+        assert!(drained_rx.recv().await.is_none());
+    }
+}
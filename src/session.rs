@@ -0,0 +1,144 @@
+//! Per-connection session state, in a `Send` and a non-`Send` flavor.
+//!
+//! The handler updates the session through `&self` after every message, so
+//! the state needs interior mutability. Which kind depends on where the
+//! connection task runs:
+//!
+//! - `tokio::spawn` may move a task to another thread at any `.await`, so
+//!   everything the task holds across an await must be `Send`. That means
+//!   `Arc` and `Mutex`, with atomic reference counts and a lock per update.
+//! - `spawn_local` (inside a `LocalSet`, `--runtime local`) pins the task to
+//!   the current thread. The `Send` bound goes away and plain `Rc` and
+//!   `RefCell` are enough: no atomics, no locks.
+//!
+//! A task holding an `Rc` is rejected by `tokio::spawn` at compile time:
+//! the future is not `Send`. Nothing can go wrong at run time.
+//!
+//! A session can outlive its connection. Every session has an id, announced
+//! in the greeting; when the connection ends, the session is kept for a
+//! grace period, and a client that reconnects with `RESUME <id>` in time
+//! gets it back.
+
+use crate::handshake::Handshake;
+use std::cell::RefCell;
+use std::fmt;
+use std::hash::{BuildHasher, RandomState};
+use std::rc::Rc;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Names a session across connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SessionId(u64);
+
+impl SessionId {
+    /// A fresh id, hard to guess so nobody resumes someone else's session.
+    ///
+    /// `RandomState` is seeded from the OS, so hashing the time with it
+    /// gives unpredictable bits without a dependency on `rand`. Good enough
+    /// for an example, not for anything that needs a cryptographic token.
+    pub fn generate() -> Self {
+        Self(RandomState::new().hash_one(SystemTime::now()))
+    }
+
+    /// Parses the hexadecimal form shown to clients.
+    pub fn parse(text: &str) -> Option<Self> {
+        u64::from_str_radix(text, 16).ok().map(Self)
+    }
+}
+
+impl fmt::Display for SessionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+/// What a session remembers about its connection.
+#[derive(Debug, Clone)]
+pub struct SessionStats {
+    pub id: SessionId,
+    pub messages: u64,
+    pub bytes: u64,
+    pub last: Option<String>,
+    /// The mode picked with `HELLO`, given back by `RESUME`.
+    pub handshake: Option<Handshake>,
+}
+
+impl Default for SessionStats {
+    fn default() -> Self {
+        Self { id: SessionId::generate(), messages: 0, bytes: 0, last: None, handshake: None }
+    }
+}
+
+impl SessionStats {
+    fn record(&mut self, input: &str) {
+        self.messages += 1;
+        self.bytes += input.len() as u64;
+        self.last = Some(input.to_string());
+    }
+}
+
+impl fmt::Display for SessionStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.last {
+            Some(last) => write!(
+                f,
+                "{} message(s), {} byte(s), last {:?}",
+                self.messages, self.bytes, last,
+            ),
+            None => write!(f, "no messages"),
+        }
+    }
+}
+
+/// Session storage the connection handler can update through `&self`.
+pub trait Session {
+    fn record(&self, input: &str);
+    fn stats(&self) -> SessionStats;
+    fn set_handshake(&self, handshake: Handshake);
+    /// Replaces everything, id included, with a session being resumed.
+    fn restore(&self, saved: SessionStats);
+
+    fn id(&self) -> SessionId {
+        self.stats().id
+    }
+}
+
+/// For tasks that may move between threads.
+impl Session for Mutex<SessionStats> {
+    fn record(&self, input: &str) {
+        self.lock().unwrap().record(input);
+    }
+
+    fn stats(&self) -> SessionStats {
+        self.lock().unwrap().clone()
+    }
+
+    fn set_handshake(&self, handshake: Handshake) {
+        self.lock().unwrap().handshake = Some(handshake);
+    }
+
+    fn restore(&self, saved: SessionStats) {
+        *self.lock().unwrap() = saved;
+    }
+}
+
+/// For tasks pinned to one thread by a `LocalSet`.
+impl Session for Rc<RefCell<SessionStats>> {
+    fn record(&self, input: &str) {
+        // Single-threaded, and never borrowed across an await: cannot panic
+        self.borrow_mut().record(input);
+    }
+
+    fn stats(&self) -> SessionStats {
+        self.borrow().clone()
+    }
+
+    fn set_handshake(&self, handshake: Handshake) {
+        self.borrow_mut().handshake = Some(handshake);
+    }
+
+    fn restore(&self, saved: SessionStats) {
+        *self.borrow_mut() = saved;
+    }
+}
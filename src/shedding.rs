@@ -0,0 +1,123 @@
+//! Load shedding: while the server is overloaded, new connections are
+//! turned away at once instead of queueing behind the ones it cannot keep
+//! up with.
+//!
+//! The accept loop measures the [`Load`] for every connection it accepts
+//! and compares it with the configured [`Thresholds`]. Over any of them,
+//! the connection is shed: it gets `ERR: server overloaded, try again later`
+//! and is closed. With [`ShedPolicy::Delay`] it is kept instead, and the
+//! loop waits for the load to fall before serving it. That holds up the
+//! accept loop too, so later clients wait in the listen backlog meanwhile.
+
+use crate::metrics;
+use std::fmt;
+
+/// What the accept loop looks at; read when a connection comes in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Load {
+    /// Open client connections.
+    pub connections: usize,
+    /// Messages waiting in the log channel for the logger task.
+    pub log_queue: usize,
+    /// Tasks waiting in the runtime's global queue for a worker.
+    pub runtime_queue: usize,
+}
+
+/// From which load on connections are shed; `None` never sheds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Thresholds {
+    pub connections: Option<usize>,
+    pub log_queue: Option<usize>,
+    pub runtime_queue: Option<usize>,
+}
+
+/// What happens to a connection that comes in while the server is overloaded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ShedPolicy {
+    /// Answer with an error line and close the connection.
+    #[default]
+    Reject,
+    /// Keep it, and stop accepting until the load is under the thresholds.
+    Delay,
+}
+
+/// The first threshold a load is over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overload {
+    Connections { load: usize, max: usize },
+    LogQueue { load: usize, max: usize },
+    RuntimeQueue { load: usize, max: usize },
+}
+
+impl Overload {
+    /// The counter of connections shed or delayed for this reason.
+    pub fn metric(&self) -> &'static str {
+        match self {
+            Overload::Connections { .. } => metrics::SHED_CONNECTIONS,
+            Overload::LogQueue { .. } => metrics::SHED_LOG_QUEUE,
+            Overload::RuntimeQueue { .. } => metrics::SHED_RUNTIME_QUEUE,
+        }
+    }
+}
+
+impl fmt::Display for Overload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (what, load, max) = match *self {
+            Overload::Connections { load, max } => ("connections", load, max),
+            Overload::LogQueue { load, max } => ("log messages queued", load, max),
+            Overload::RuntimeQueue { load, max } => ("tasks queued", load, max),
+        };
+        write!(f, "{} {}, the limit is {}", load, what, max)
+    }
+}
+
+impl Thresholds {
+    /// Whether any threshold is set; the load is only measured if so.
+    pub fn is_set(&self) -> bool {
+        *self != Self::default()
+    }
+
+    /// Under every threshold, or the first one `load` reaches.
+    pub fn check(&self, load: &Load) -> Result<(), Overload> {
+        let over = |max: Option<usize>, load: usize| max.filter(|max| load >= *max);
+        if let Some(max) = over(self.connections, load.connections) {
+            return Err(Overload::Connections { load: load.connections, max });
+        }
+        if let Some(max) = over(self.log_queue, load.log_queue) {
+            return Err(Overload::LogQueue { load: load.log_queue, max });
+        }
+        if let Some(max) = over(self.runtime_queue, load.runtime_queue) {
+            return Err(Overload::RuntimeQueue { load: load.runtime_queue, max });
+        }
+        Ok(())
+    }
+}
+
+pub fn parse_policy(value: &str) -> Result<ShedPolicy, String> {
+    match value {
+        "reject" => Ok(ShedPolicy::Reject),
+        "delay" => Ok(ShedPolicy::Delay),
+        _ => Err("shed policy must be `reject` or `delay`".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_first_threshold_reached_is_reported() {
+        let thresholds =
+            Thresholds { connections: Some(100), log_queue: Some(50), runtime_queue: None };
+        let load = Load { connections: 99, log_queue: 10, runtime_queue: 5000 };
+        assert_eq!(thresholds.check(&load), Ok(()));
+        assert!(!Thresholds::default().is_set());
+
+        let load = Load { connections: 100, log_queue: 80, ..load };
+        let overload = thresholds.check(&load).unwrap_err();
+        assert_eq!(overload, Overload::Connections { load: 100, max: 100 });
+        assert_eq!(overload.to_string(), "100 connections, the limit is 100");
+        let load = Load { connections: 1, ..load };
+        assert_eq!(thresholds.check(&load).unwrap_err().metric(), metrics::SHED_LOG_QUEUE);
+    }
+}
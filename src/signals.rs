@@ -0,0 +1,146 @@
+//! Process signals, turned into server actions by one dispatcher task.
+//!
+//! | signal           | action                                  |
+//! |------------------|-----------------------------------------|
+//! | SIGINT (Ctrl-C)  | graceful shutdown                       |
+//! | SIGTERM          | graceful shutdown                       |
+//! | SIGHUP           | reload the config file and access list  |
+//! | SIGUSR1          | write the current stats to the log      |
+//! | SIGUSR2          | restart without closing the listeners   |
+//!
+//! Having a single task own every signal stream keeps the reactions in
+//! one place: the rest of the server only sees the shutdown request and
+//! the channels a reload publishes to.
+
+use crate::config::Reloader;
+use crate::error::ServerError;
+use crate::logger::{LogMessage, LogTx};
+use crate::metrics::{self, Metrics};
+use crate::restart::Handoff;
+use crate::task;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+/// What the server does in response to a signal.
+#[derive(Debug, Clone, Copy)]
+enum Action {
+    Shutdown,
+    Reload,
+    DumpStats,
+    Restart,
+}
+
+/// Everything the dispatcher needs to act on a signal.
+pub struct Dispatcher {
+    pub reloader: Reloader,
+    pub log_tx: LogTx,
+    pub metrics: Metrics,
+    /// The listening sockets a restart hands to the new process.
+    pub handoff: Arc<Handoff>,
+    /// Receives the name of the signal that asked the server to shut down.
+    pub shutdown_tx: mpsc::Sender<&'static str>,
+}
+
+impl Dispatcher {
+    /// Installs the signal handlers and spawns the dispatcher task.
+    pub fn spawn(self) -> Result<(), ServerError> {
+        let mut signals = Signals::install().map_err(ServerError::Signal)?;
+
+        task::spawn("signal dispatcher", async move {
+            loop {
+                let (name, action) = signals.recv().await;
+                println!("[SIGNAL] {} received", name);
+
+                match action {
+                    // `try_send`: a shutdown that is already pending needs no second request
+                    Action::Shutdown => {
+                        let _ = self.shutdown_tx.try_send(name);
+                    }
+                    Action::Reload => self.reload().await,
+                    Action::DumpStats => self.dump_stats().await,
+                    Action::Restart => self.restart(),
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    async fn reload(&self) {
+        // The outcome is reported by the reloader itself
+        let _ = self.reloader.reload().await;
+    }
+
+    /// Starts the new process in the background, so signals are still
+    /// handled while it warms up; once it is ready, this one drains.
+    fn restart(&self) {
+        let (handoff, shutdown_tx) = (self.handoff.clone(), self.shutdown_tx.clone());
+        task::spawn("restart", async move {
+            match handoff.restart().await {
+                Ok(()) => {
+                    println!("[RESTART] the new process is ready; draining this one");
+                    let _ = shutdown_tx.try_send("SIGUSR2");
+                }
+                Err(e) => eprintln!("[RESTART] {}; still serving", e),
+            }
+        });
+    }
+
+    async fn dump_stats(&self) {
+        let text = format!("stats: {}", metrics::stat_summary(&self.metrics));
+        self.log_tx.send(LogMessage::admin(text)).await;
+    }
+}
+
+/// The signal streams the dispatcher listens to.
+#[cfg(unix)]
+struct Signals {
+    interrupt: tokio::signal::unix::Signal,
+    terminate: tokio::signal::unix::Signal,
+    hangup: tokio::signal::unix::Signal,
+    user1: tokio::signal::unix::Signal,
+    user2: tokio::signal::unix::Signal,
+}
+
+#[cfg(unix)]
+impl Signals {
+    fn install() -> std::io::Result<Self> {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        // Once a stream is registered, the default action of the signal
+        // (terminating the process) no longer applies
+        Ok(Self {
+            interrupt: signal(SignalKind::interrupt())?,
+            terminate: signal(SignalKind::terminate())?,
+            hangup: signal(SignalKind::hangup())?,
+            user1: signal(SignalKind::user_defined1())?,
+            user2: signal(SignalKind::user_defined2())?,
+        })
+    }
+
+    async fn recv(&mut self) -> (&'static str, Action) {
+        tokio::select! {
+            _ = self.interrupt.recv() => ("SIGINT", Action::Shutdown),
+            _ = self.terminate.recv() => ("SIGTERM", Action::Shutdown),
+            _ = self.hangup.recv() => ("SIGHUP", Action::Reload),
+            _ = self.user1.recv() => ("SIGUSR1", Action::DumpStats),
+            _ = self.user2.recv() => ("SIGUSR2", Action::Restart),
+        }
+    }
+}
+
+/// Other platforms only have Ctrl-C.
+#[cfg(not(unix))]
+struct Signals;
+
+#[cfg(not(unix))]
+impl Signals {
+    fn install() -> std::io::Result<Self> {
+        Ok(Self)
+    }
+
+    async fn recv(&mut self) -> (&'static str, Action) {
+        let _ = tokio::signal::ctrl_c().await;
+        ("Ctrl-C", Action::Shutdown)
+    }
+}
@@ -0,0 +1,311 @@
+//! A SOCKS5 server (RFC 1928), `CONNECT` only.
+//!
+//! The client first offers authentication methods. The server accepts
+//! "no authentication", or username/password (RFC 1929) when credentials
+//! are configured. Then the client names a target as an IPv4 address, an
+//! IPv6 address or a host name, which the [`Dialer`] resolves, racing the
+//! addresses it finds. Once the server has connected to the target
+//! and replied, the connection is a plain tunnel, bridged with
+//! `copy_bidirectional` like in proxy mode.
+
+use crate::dialer::Dialer;
+use crate::retry::{Policy, retry};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0x00;
+const USER_PASS: u8 = 0x02;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const USER_PASS_VERSION: u8 = 1;
+const CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+/// Reply codes of RFC 1928, section 6.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum Reply {
+    Succeeded = 0,
+    GeneralFailure = 1,
+    NetworkUnreachable = 3,
+    HostUnreachable = 4,
+    ConnectionRefused = 5,
+    CommandNotSupported = 7,
+    AddressTypeNotSupported = 8,
+}
+
+/// The username and password clients must send, when configured.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl Credentials {
+    /// Parses `user:password`.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        match text.split_once(':') {
+            Some((username, password)) if !username.is_empty() => Ok(Self {
+                username: username.to_string(),
+                password: password.to_string(),
+            }),
+            _ => Err("SOCKS credentials must look like `user:password`".to_string()),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SocksError {
+    #[error("not a SOCKS5 client (version byte {0})")]
+    Version(u8),
+    #[error("the client offers no acceptable authentication method")]
+    NoAcceptableMethod,
+    #[error("wrong username or password")]
+    AuthFailed,
+    #[error("command {0} is not supported, only CONNECT")]
+    CommandNotSupported(u8),
+    #[error("address type {0} is not supported")]
+    AddressTypeNotSupported(u8),
+    #[error("cannot connect to {target}: {source}")]
+    Connect {
+        target: String,
+        #[source]
+        source: io::Error,
+    },
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl SocksError {
+    /// Whether the client broke the protocol, as opposed to an I/O failure.
+    pub fn is_protocol_error(&self) -> bool {
+        !matches!(self, SocksError::Connect { .. } | SocksError::Io(_))
+    }
+}
+
+/// Runs the handshake with a client and connects to the target it asks for.
+///
+/// Returns the target as the client named it and the connected stream.
+/// Every failure after the greeting has already been answered with the
+/// matching reply code.
+pub async fn accept<S>(
+    client: &mut S,
+    credentials: Option<&Credentials>,
+    dialer: &Dialer,
+) -> Result<(String, TcpStream), SocksError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    authenticate(client, credentials).await?;
+
+    // VER CMD RSV ATYP
+    let mut header = [0u8; 4];
+    client.read_exact(&mut header).await?;
+    let [version, command, _, address_type] = header;
+    if version != VERSION {
+        return Err(SocksError::Version(version));
+    }
+    let target = match read_target(client, address_type).await {
+        Err(SocksError::AddressTypeNotSupported(other)) => {
+            reply(client, Reply::AddressTypeNotSupported, None).await?;
+            return Err(SocksError::AddressTypeNotSupported(other));
+        }
+        result => result?,
+    };
+    if command != CONNECT {
+        reply(client, Reply::CommandNotSupported, None).await?;
+        return Err(SocksError::CommandNotSupported(command));
+    }
+
+    match retry(&Policy::default(), || dialer.dial(target.as_str())).await {
+        Ok(stream) => {
+            reply(client, Reply::Succeeded, stream.local_addr().ok()).await?;
+            Ok((target, stream))
+        }
+        Err(source) => {
+            reply(client, reply_for(&source), None).await?;
+            Err(SocksError::Connect { target, source })
+        }
+    }
+}
+
+/// The method negotiation, and the username/password exchange if chosen.
+async fn authenticate<S>(
+    client: &mut S,
+    credentials: Option<&Credentials>,
+) -> Result<(), SocksError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // VER NMETHODS METHODS...
+    let version = client.read_u8().await?;
+    if version != VERSION {
+        return Err(SocksError::Version(version));
+    }
+    let mut methods = vec![0u8; client.read_u8().await? as usize];
+    client.read_exact(&mut methods).await?;
+
+    let wanted = if credentials.is_some() { USER_PASS } else { NO_AUTH };
+    if !methods.contains(&wanted) {
+        client.write_all(&[VERSION, NO_ACCEPTABLE_METHOD]).await?;
+        return Err(SocksError::NoAcceptableMethod);
+    }
+    client.write_all(&[VERSION, wanted]).await?;
+    let Some(credentials) = credentials else { return Ok(()) };
+
+    // VER ULEN UNAME PLEN PASSWD
+    let version = client.read_u8().await?;
+    if version != USER_PASS_VERSION {
+        return Err(SocksError::Version(version));
+    }
+    let username = read_string(client).await?;
+    let password = read_string(client).await?;
+    let valid = username == credentials.username && password == credentials.password;
+    client.write_all(&[USER_PASS_VERSION, if valid { 0 } else { 1 }]).await?;
+    if valid { Ok(()) } else { Err(SocksError::AuthFailed) }
+}
+
+/// Reads DST.ADDR and DST.PORT as `host:port`.
+async fn read_target<S: AsyncRead + Unpin>(
+    client: &mut S,
+    address_type: u8,
+) -> Result<String, SocksError> {
+    let host = match address_type {
+        ATYP_IPV4 => {
+            let mut octets = [0u8; 4];
+            client.read_exact(&mut octets).await?;
+            Ipv4Addr::from(octets).to_string()
+        }
+        ATYP_IPV6 => {
+            let mut octets = [0u8; 16];
+            client.read_exact(&mut octets).await?;
+            // Brackets, so the port can be appended
+            format!("[{}]", Ipv6Addr::from(octets))
+        }
+        ATYP_DOMAIN => read_string(client).await?,
+        other => return Err(SocksError::AddressTypeNotSupported(other)),
+    };
+    let port = client.read_u16().await?;
+    Ok(format!("{}:{}", host, port))
+}
+
+/// A length byte followed by that many bytes.
+async fn read_string<S: AsyncRead + Unpin>(client: &mut S) -> io::Result<String> {
+    let mut bytes = vec![0u8; client.read_u8().await? as usize];
+    client.read_exact(&mut bytes).await?;
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+fn reply_for(error: &io::Error) -> Reply {
+    match error.kind() {
+        io::ErrorKind::ConnectionRefused => Reply::ConnectionRefused,
+        io::ErrorKind::NetworkUnreachable => Reply::NetworkUnreachable,
+        io::ErrorKind::HostUnreachable | io::ErrorKind::NotFound | io::ErrorKind::TimedOut => {
+            Reply::HostUnreachable
+        }
+        _ => Reply::GeneralFailure,
+    }
+}
+
+/// VER REP RSV ATYP BND.ADDR BND.PORT; the bound address is zeros on failure.
+async fn reply<S: AsyncWrite + Unpin>(
+    client: &mut S,
+    code: Reply,
+    bound: Option<SocketAddr>,
+) -> io::Result<()> {
+    let bound = bound.unwrap_or(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0));
+    let mut message = vec![VERSION, code as u8, 0];
+    match bound.ip() {
+        IpAddr::V4(ip) => {
+            message.push(ATYP_IPV4);
+            message.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            message.push(ATYP_IPV6);
+            message.extend_from_slice(&ip.octets());
+        }
+    }
+    message.extend_from_slice(&bound.port().to_be_bytes());
+    client.write_all(&message).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::DuplexStream;
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+
+    fn credentials() -> Credentials {
+        Credentials::parse("alice:secret").unwrap()
+    }
+
+    type Accepted = JoinHandle<Result<(String, TcpStream), SocksError>>;
+
+    /// Runs `accept` on one end of a pipe and returns the other end.
+    fn server(credentials: Option<Credentials>) -> (DuplexStream, Accepted) {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let handle = tokio::spawn(async move {
+            accept(&mut server, credentials.as_ref(), &Dialer::new()).await
+        });
+        (client, handle)
+    }
+
+    fn connect_request(port: u16) -> Vec<u8> {
+        let mut request = vec![VERSION, CONNECT, 0, ATYP_IPV4, 127, 0, 0, 1];
+        request.extend_from_slice(&port.to_be_bytes());
+        request
+    }
+
+    #[tokio::test]
+    async fn connects_after_username_and_password() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = target.local_addr().unwrap().port();
+        let (mut client, handle) = server(Some(credentials()));
+
+        client.write_all(&[VERSION, 2, NO_AUTH, USER_PASS]).await.unwrap();
+        assert_eq!(client.read_u16().await.unwrap(), u16::from_be_bytes([VERSION, USER_PASS]));
+
+        client.write_all(b"\x01\x05alice\x06secret").await.unwrap();
+        assert_eq!(client.read_u16().await.unwrap(), 0x0100);
+
+        client.write_all(&connect_request(port)).await.unwrap();
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..4], [VERSION, Reply::Succeeded as u8, 0, ATYP_IPV4]);
+
+        let (name, _stream) = handle.await.unwrap().unwrap();
+        assert_eq!(name, format!("127.0.0.1:{}", port));
+    }
+
+    #[tokio::test]
+    async fn a_wrong_password_is_refused() {
+        let (mut client, handle) = server(Some(credentials()));
+        client.write_all(&[VERSION, 1, USER_PASS]).await.unwrap();
+        client.read_u16().await.unwrap();
+
+        client.write_all(b"\x01\x05alice\x05guess").await.unwrap();
+        assert_eq!(client.read_u16().await.unwrap(), 0x0101);
+        assert!(matches!(handle.await.unwrap(), Err(SocksError::AuthFailed)));
+    }
+
+    #[tokio::test]
+    async fn only_connect_is_supported() {
+        let (mut client, handle) = server(None);
+        client.write_all(&[VERSION, 1, NO_AUTH]).await.unwrap();
+        client.read_u16().await.unwrap();
+
+        // BIND
+        let mut request = connect_request(80);
+        request[1] = 2;
+        client.write_all(&request).await.unwrap();
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], Reply::CommandNotSupported as u8);
+        assert!(matches!(handle.await.unwrap(), Err(SocksError::CommandNotSupported(2))));
+    }
+}
@@ -0,0 +1,361 @@
+//! State shared by all connections.
+
+use crate::health;
+use crate::kv;
+use crate::metrics::{self, Metrics};
+use crate::peers::Peers;
+use crate::persist::Snapshot;
+use crate::quota::Quotas;
+use crate::vhost::VhostStats;
+use std::time::Duration;
+use tokio::sync::watch;
+
+// Under `--cfg loom` the lock and the atomics are loom's, so a model can run
+// the threads touching them through every possible interleaving
+#[cfg(loom)]
+use loom::sync::{
+    Mutex,
+    atomic::{AtomicI32, AtomicU64, Ordering},
+};
+#[cfg(not(loom))]
+use std::sync::{
+    Mutex,
+    atomic::{AtomicI32, AtomicU64, Ordering},
+};
+
+/// The longest delay `SLOWMODE` puts on a reply.
+pub const MAX_SLOWMODE: Duration = Duration::from_secs(60);
+
+/// How the request counter is kept.
+///
+/// `benches/request_path.rs` compares the three under contention.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CounterKind {
+    /// One `Mutex`: every request gets its own number, and watchers see
+    /// every value in order.
+    #[default]
+    Mutex,
+    /// One atomic: still a number per request, without a lock, but every
+    /// increment moves the same cache line between the cores.
+    Atomic,
+    /// One atomic per shard, each on its own cache line; a thread only
+    /// writes to its own shard. Reads add the shards up, so concurrent
+    /// requests may get the same number.
+    Sharded,
+}
+
+impl CounterKind {
+    /// `mutex`, `atomic` or `sharded`, as the config writes them.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "mutex" => Ok(Self::Mutex),
+            "atomic" => Ok(Self::Atomic),
+            "sharded" => Ok(Self::Sharded),
+            _ => Err("counter must be `mutex`, `atomic` or `sharded`".to_string()),
+        }
+    }
+}
+
+enum Counter {
+    Mutex(Mutex<i32>),
+    Atomic(AtomicI32),
+    Sharded(Box<[Shard]>),
+}
+
+/// Padded to a cache line of its own (two, on CPUs that fetch them in
+/// pairs), so that shards written by different cores do not share one.
+#[repr(align(128))]
+struct Shard(AtomicI32);
+
+impl Counter {
+    fn new(kind: CounterKind) -> Self {
+        match kind {
+            CounterKind::Mutex => Self::Mutex(Mutex::new(0)),
+            CounterKind::Atomic => Self::Atomic(AtomicI32::new(0)),
+            CounterKind::Sharded => {
+                let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+                Self::Sharded((0..cores.min(64)).map(|_| Shard(AtomicI32::new(0))).collect())
+            }
+        }
+    }
+
+    fn get(&self) -> i32 {
+        match self {
+            Self::Mutex(counter) => *counter.lock().unwrap(),
+            Self::Atomic(counter) => counter.load(Ordering::Relaxed),
+            Self::Sharded(shards) => shards.iter().map(|s| s.0.load(Ordering::Relaxed)).sum(),
+        }
+    }
+}
+
+/// The shard of the calling thread: threads take turns, as they first count.
+fn shard_index(shards: usize) -> usize {
+    // Plain std types: only the `Sharded` counter uses them, never a loom model
+    static NEXT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+    thread_local! {
+        static INDEX: usize = NEXT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    }
+    INDEX.with(|index| index % shards)
+}
+
+/// Current state for transferring between threads
+pub struct State {
+    counter: Counter,
+    // The counter's latest value, for tasks that wait for it to change
+    count_tx: watch::Sender<i32>,
+    // Only ever incremented, so an atomic is enough; no lock needed
+    next_conn_id: AtomicU64,
+    // The delay `SLOWMODE ALL` puts on every reply, in milliseconds
+    slowmode_ms: AtomicU64,
+    pub metrics: Metrics,
+    pub kv: kv::Store,
+    /// The same kind of numbers per client address.
+    pub peers: Peers,
+    /// Connections per TLS virtual host; see `crate::vhost`.
+    pub vhosts: VhostStats,
+    /// Request quotas per client, on every listener; see `crate::quota`.
+    pub quotas: Quotas,
+    /// The latest health check of every target; see `crate::health`.
+    pub health: watch::Sender<Vec<health::Status>>,
+}
+
+impl State {
+    pub fn new(metrics: Metrics) -> Self {
+        Self::with_counter(metrics, CounterKind::Mutex)
+    }
+
+    pub fn with_counter(metrics: Metrics, counter: CounterKind) -> Self {
+        Self {
+            counter: Counter::new(counter),
+            count_tx: watch::Sender::new(0),
+            next_conn_id: AtomicU64::new(1),
+            slowmode_ms: AtomicU64::new(0),
+            kv: kv::Store::new(metrics.clone()),
+            peers: Peers::new(metrics.clone()),
+            vhosts: VhostStats::default(),
+            quotas: Quotas::new(metrics.clone()),
+            health: watch::Sender::new(Vec::new()),
+            metrics,
+        }
+    }
+
+    pub fn next_connection_id(&self) -> u64 {
+        self.next_conn_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub fn increment(&self) -> i32 {
+        let current = match &self.counter {
+            Counter::Mutex(counter) => {
+                // Lock is acquired and released inside a synchronous method
+                // to guarantee it is never held across an `.await`
+                let mut lock = counter.lock().unwrap();
+                *lock += 1;
+                // Published under the lock, so watchers see the values in order
+                self.count_tx.send_replace(*lock);
+                return *lock;
+            } // mutex is free
+            Counter::Atomic(counter) => counter.fetch_add(1, Ordering::Relaxed) + 1,
+            Counter::Sharded(shards) => {
+                shards[shard_index(shards.len())].0.fetch_add(1, Ordering::Relaxed);
+                self.counter.get()
+            }
+        };
+        // The channel has a lock of its own, only worth taking for a watcher
+        if self.count_tx.receiver_count() > 0 {
+            self.publish(current);
+        }
+        current
+    }
+
+    /// How long every reply waits, for connections without a `SLOWMODE` of
+    /// their own.
+    pub fn slowmode(&self) -> Duration {
+        Duration::from_millis(self.slowmode_ms.load(Ordering::Relaxed))
+    }
+
+    pub fn set_slowmode(&self, delay: Duration) {
+        self.slowmode_ms.store(delay.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Requests served so far.
+    pub fn count(&self) -> i32 {
+        self.counter.get()
+    }
+
+    /// The count, updated after every request.
+    pub fn watch_count(&self) -> watch::Receiver<i32> {
+        let count = self.count_tx.subscribe();
+        // Increments from now on are published; catch up with the earlier ones
+        if !matches!(self.counter, Counter::Mutex(_)) {
+            self.publish(self.count());
+        }
+        count
+    }
+
+    /// Publishes `current` unless a larger value already was: without the
+    /// lock, increments can get here out of order.
+    fn publish(&self, current: i32) {
+        self.count_tx.send_if_modified(|published| {
+            let newer = current > *published;
+            if newer {
+                *published = current;
+            }
+            newer
+        });
+    }
+
+    /// The reply to `STATS`: every metric, then every client address, then
+    /// every virtual host and every health check target, then `END`.
+    pub fn stat_lines(&self) -> String {
+        let mut response = metrics::stat_lines(&self.metrics);
+        response.push_str(&self.peers.stat_lines());
+        response.push_str(&self.vhosts.stat_lines());
+        response.push_str(&health::stat_lines(&self.health.borrow()));
+        response.push_str("END\n");
+        response
+    }
+
+    /// The counter and the key-value entries, for saving to disk.
+    ///
+    /// The two are read under separate locks; a request that runs in between
+    /// is simply part of the next snapshot.
+    pub fn snapshot(&self) -> Snapshot {
+        let (kv, wal_seq) = self.kv.checkpoint();
+        Snapshot { counter: self.count(), wal_seq, kv }
+    }
+
+    pub fn restore(&self, snapshot: Snapshot) {
+        self.set_count(snapshot.counter);
+        self.kv.restore(snapshot.kv, snapshot.wal_seq);
+    }
+
+    /// Sets the counter, e.g. to a primary's on a replica.
+    pub fn set_count(&self, count: i32) {
+        match &self.counter {
+            Counter::Mutex(counter) => {
+                let mut counter = counter.lock().unwrap();
+                *counter = count;
+                self.count_tx.send_replace(*counter);
+            }
+            Counter::Atomic(counter) => {
+                counter.store(count, Ordering::Relaxed);
+                self.count_tx.send_replace(count);
+            }
+            // The first shard holds the count, the others start over
+            Counter::Sharded(shards) => {
+                for (index, shard) in shards.iter().enumerate() {
+                    let value = if index == 0 { count } else { 0 };
+                    shard.0.store(value, Ordering::Relaxed);
+                }
+                self.count_tx.send_replace(count);
+            }
+        }
+    }
+}
+
+/// Model checks with loom, which runs each closure once per possible
+/// interleaving of its threads. Only built with `--cfg loom`; see the README.
+#[cfg(all(test, loom))]
+mod tests {
+    use super::*;
+    use crate::futures_examples::WaitForStateMachine;
+    use crate::metrics::InMemorySink;
+    use loom::thread;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::task::Context;
+
+    fn state() -> Arc<State> {
+        state_with(CounterKind::Mutex)
+    }
+
+    fn state_with(counter: CounterKind) -> Arc<State> {
+        Arc::new(State::with_counter(Arc::new(InMemorySink::default()), counter))
+    }
+
+    #[test]
+    fn concurrent_increments_are_never_lost() {
+        for kind in [CounterKind::Mutex, CounterKind::Atomic, CounterKind::Sharded] {
+            loom::model(move || {
+                let state = state_with(kind);
+                let mut count = state.watch_count();
+                let other = {
+                    let state = state.clone();
+                    thread::spawn(move || state.increment())
+                };
+                let mine = state.increment();
+                let theirs = other.join().unwrap();
+
+                // Each caller saw its own increment, whichever went first; the
+                // sharded counter only promises the total
+                let mut seen = [mine, theirs];
+                seen.sort();
+                if kind != CounterKind::Sharded {
+                    assert_eq!(seen, [1, 2]);
+                }
+                assert_eq!(state.count(), 2);
+                // Published out of order or not, the watcher ends up at the total
+                assert_eq!(*count.borrow_and_update(), 2);
+            });
+        }
+    }
+
+    #[test]
+    fn connection_ids_are_unique() {
+        loom::model(|| {
+            let state = state();
+            let other = {
+                let state = state.clone();
+                thread::spawn(move || state.next_connection_id())
+            };
+            let mine = state.next_connection_id();
+            assert_ne!(mine, other.join().unwrap());
+        });
+    }
+
+    /// Records whether the future asked to be polled again.
+    #[derive(Default)]
+    struct Flag(AtomicU64);
+
+    impl std::task::Wake for Flag {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// A future that waits forever (say, by spinning) never finishes under
+    /// loom, so the watcher is polled by hand. Whenever it is pending, it must
+    /// already have been woken, or be woken by the change it waits for: a
+    /// pending poll nobody wakes is a missed wakeup.
+    #[test]
+    fn the_watcher_never_misses_an_increment() {
+        loom::model(|| {
+            let state = state();
+            let Counter::Mutex(counter) = &state.counter else { unreachable!() };
+            *counter.lock().unwrap() = 3;
+            let flag = Arc::new(Flag::default());
+            let waker = Arc::clone(&flag).into();
+            let mut cx = Context::from_waker(&waker);
+            let mut watcher = WaitForStateMachine::new(state.clone());
+            // At 3 requests the watcher moves on to waiting for 5
+            assert!(Pin::new(&mut watcher).poll(&mut cx).is_pending());
+            flag.0.store(0, Ordering::SeqCst);
+
+            let threads: Vec<_> = (0..2)
+                .map(|_| {
+                    let state = state.clone();
+                    thread::spawn(move || state.increment())
+                })
+                .collect();
+            let raced = Pin::new(&mut watcher).poll(&mut cx);
+            for thread in threads {
+                thread.join().unwrap();
+            }
+            if raced.is_pending() {
+                assert!(flag.0.load(Ordering::SeqCst) > 0, "pending without a wakeup");
+                assert!(Pin::new(&mut watcher).poll(&mut cx).is_ready());
+            }
+        });
+    }
+}
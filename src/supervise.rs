@@ -0,0 +1,172 @@
+//! Restarting long-lived background tasks that panic.
+//!
+//! A task spawned with `tokio::spawn` that panics is simply gone: nobody
+//! awaits its `JoinHandle`, so the logger, the console or the scheduler
+//! would stop without a word, and the server would carry on without them.
+//! [`spawn`] runs such a task under a supervisor that awaits the handle,
+//! and on a panic prints it and starts the task again:
+//!
+//! ```text
+//! run ─ panics ─ 1s ─ run ─ panics ─ 2s ─ run ─ ... ─ panics ─ 60s ─ run
+//! ```
+//!
+//! The pause doubles with every panic in a row, so a task that panics at
+//! once is not restarted in a tight loop; after a minute of running
+//! without one, the next panic is back to the shortest pause. The
+//! restarts are printed rather than sent to the logger, which may be the
+//! task that panicked. A task that returns is not restarted, and neither
+//! is anything once the server drains.
+//!
+//! Whatever a task keeps between runs, like the receiving end of its
+//! channel, the factory shares with every run behind a
+//! `tokio::sync::Mutex`: unlike `std::sync::Mutex`, it is not poisoned
+//! when the run holding it panics.
+
+use crate::metrics::{self, Metrics};
+use crate::readiness::{self, Phase};
+use crate::retry::Policy;
+use crate::task;
+use std::any::Any;
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+/// The pause before a restart, by panics in a row; the supervisor never gives up.
+const BACKOFF: Policy = Policy {
+    max_attempts: u32::MAX,
+    initial_delay: Duration::from_secs(1),
+    max_delay: Duration::from_secs(60),
+    jitter: 0.0,
+};
+
+/// A task that ran this long before panicking starts over at the shortest pause.
+const STABLE: Duration = Duration::from_secs(60);
+
+/// Spawns the task `make` returns, and again every time it panics, until
+/// the server starts draining.
+pub fn spawn<F, Fut>(
+    name: &'static str,
+    metrics: Metrics,
+    mut phase: watch::Receiver<Phase>,
+    mut make: F,
+) -> JoinHandle<()>
+where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    task::spawn(&format!("{} supervisor", name), async move {
+        let mut panics = 0;
+        loop {
+            let started = Instant::now();
+            let error = match task::spawn(name, make()).await {
+                Ok(()) => {
+                    if *phase.borrow() != Phase::Draining {
+                        println!("[SUPERVISOR] {} stopped", name);
+                    }
+                    return;
+                }
+                // Aborted: the runtime is shutting down
+                Err(error) if error.is_cancelled() => return,
+                Err(error) => error,
+            };
+            metrics.incr(metrics::TASKS_PANICKED, 1);
+            let message = panic_message(error.into_panic());
+            if *phase.borrow() == Phase::Draining {
+                println!("[SUPERVISOR] {} panicked: {}", name, message);
+                return;
+            }
+
+            if started.elapsed() >= STABLE {
+                panics = 0;
+            }
+            panics += 1;
+            let pause = BACKOFF.backoff(panics);
+            println!(
+                "[SUPERVISOR] {} panicked: {}; restarting in {}s",
+                name,
+                message,
+                pause.as_secs_f64()
+            );
+            tokio::select! {
+                _ = tokio::time::sleep(pause) => {}
+                _ = readiness::wait_draining(&mut phase) => return,
+            }
+            metrics.incr(metrics::TASKS_RESTARTED, 1);
+        }
+    })
+}
+
+/// The message a task panicked with.
+pub fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    // `panic!` payloads are a `&str` or a `String`, depending on formatting
+    payload
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::InMemorySink;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn counter(metrics: &Metrics, name: &str) -> i64 {
+        metrics.snapshot().iter().find(|m| m.name == name).map_or(0, |m| m.value)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_task_that_panics_is_restarted_after_a_growing_pause() {
+        let metrics: Metrics = Arc::new(InMemorySink::default());
+        let (phase_tx, phase) = watch::channel(Phase::Ready);
+        let runs = Arc::new(AtomicU32::new(0));
+        let supervisor = {
+            let (runs, phase) = (runs.clone(), phase.clone());
+            spawn("flaky", metrics.clone(), phase.clone(), move || {
+                let mut phase = phase.clone();
+                let run = runs.fetch_add(1, Ordering::SeqCst) + 1;
+                async move {
+                    if run < 3 {
+                        panic!("run {} failed", run);
+                    }
+                    readiness::wait_draining(&mut phase).await;
+                }
+            })
+        };
+
+        // Restarted after 1s, then after 2s more
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+        assert_eq!(counter(&metrics, metrics::TASKS_PANICKED), 2);
+        assert_eq!(counter(&metrics, metrics::TASKS_RESTARTED), 2);
+
+        // The third run ends with the drain, and is not restarted
+        phase_tx.send_replace(Phase::Draining);
+        supervisor.await.unwrap();
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_task_that_returns_is_not_restarted() {
+        let metrics: Metrics = Arc::new(InMemorySink::default());
+        let (_phase_tx, phase) = watch::channel(Phase::Ready);
+        let runs = Arc::new(AtomicU32::new(0));
+        let counted = runs.clone();
+        spawn("short-lived", metrics.clone(), phase, move || {
+            counted.fetch_add(1, Ordering::SeqCst);
+            async {}
+        })
+        .await
+        .unwrap();
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        assert_eq!(counter(&metrics, metrics::TASKS_RESTARTED), 0);
+    }
+}
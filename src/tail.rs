@@ -0,0 +1,138 @@
+//! `TAIL`: streams the lines appended to `log.txt` to subscribed clients.
+//!
+//! One tailer task follows the file for everybody. It starts at the end of
+//! the file and checks every `POLL_INTERVAL` whether it has grown, a
+//! portable stand-in for filesystem notifications. What was appended is
+//! read and split into lines, and each line is handed to the subscribers
+//! through a [`Fanout`].
+
+use crate::fanout::{Fanout, Subscription};
+use crate::metrics::{self, Metrics};
+use crate::readiness::{self, Phase};
+use crate::task;
+use bytes::{Bytes, BytesMut};
+use std::io::SeekFrom;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::watch;
+use tokio::time::MissedTickBehavior;
+
+/// How often the file is checked for new lines.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Handle to the tailer task; cheap to clone.
+#[derive(Clone)]
+pub struct Tailer {
+    lines: Fanout,
+}
+
+impl Tailer {
+    /// Starts following `path`; the task stops when the server drains.
+    pub fn start(path: PathBuf, metrics: Metrics, phase: watch::Receiver<Phase>) -> Self {
+        let lines = Fanout::new(metrics, metrics::TAIL_SUBSCRIBERS, metrics::TAIL_LINES_MISSED);
+        let tailer = Self { lines };
+        task::spawn("log tailer", follow(path, tailer.clone(), phase));
+        tailer
+    }
+
+    /// Lines appended from now on, as `TAIL <line>` replies.
+    pub fn subscribe(&self) -> Subscription {
+        self.lines.subscribe()
+    }
+}
+
+async fn follow(path: PathBuf, tailer: Tailer, mut phase: watch::Receiver<Phase>) {
+    let mut ticker = tokio::time::interval(POLL_INTERVAL);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut file: Option<File> = None;
+    // How far the file has been read, and the start of a line still being written
+    let mut position = 0;
+    let mut partial = BytesMut::new();
+
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = readiness::wait_draining(&mut phase) => {
+                tailer.lines.close();
+                return;
+            }
+        }
+
+        // Opened lazily: the file may not exist yet
+        let Some(open) = &mut file else {
+            if let Ok(opened) = File::open(&path).await {
+                position = opened.metadata().await.map_or(0, |m| m.len());
+                file = Some(opened);
+            }
+            continue;
+        };
+        let Ok(len) = open.metadata().await.map(|m| m.len()) else {
+            file = None;
+            continue;
+        };
+        // Truncated, e.g. recreated at startup: start over from the top
+        if len < position {
+            position = 0;
+            partial.clear();
+        }
+        // Nobody listens: skip what was appended instead of reading it
+        if len == position || !tailer.lines.has_subscribers() {
+            position = len;
+            continue;
+        }
+
+        let mut appended = Vec::new();
+        let read = async {
+            open.seek(SeekFrom::Start(position)).await?;
+            (&mut *open).take(len - position).read_to_end(&mut appended).await
+        };
+        match read.await {
+            Ok(n) => position += n as u64,
+            Err(e) => {
+                eprintln!("[LOG] cannot read {}: {}", path.display(), e);
+                file = None;
+                continue;
+            }
+        }
+        partial.extend_from_slice(&appended);
+        while let Some(end) = partial.iter().position(|&b| b == b'\n') {
+            let line = partial.split_to(end + 1);
+            let line = format!("TAIL {}", String::from_utf8_lossy(&line[..end]));
+            tailer.lines.publish(Bytes::from(line));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::InMemorySink;
+    use std::sync::Arc;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn appended_lines_reach_every_subscriber() {
+        let path = std::env::temp_dir().join(format!("tail-test-{}", std::process::id()));
+        let mut file = File::create(&path).await.unwrap();
+        file.write_all(b"old line\n").await.unwrap();
+
+        let (phase_tx, phase) = watch::channel(Phase::Ready);
+        let tailer = Tailer::start(path.clone(), Arc::new(InMemorySink::default()), phase);
+        let (mut first, mut second) = (tailer.subscribe(), tailer.subscribe());
+        // Let the tailer open the file and skip what is already there
+        tokio::time::sleep(POLL_INTERVAL * 2).await;
+
+        file.write_all(b"new line\nhalf").await.unwrap();
+        assert_eq!(first.recv().await.unwrap(), "TAIL new line");
+        assert_eq!(second.recv().await.unwrap(), "TAIL new line");
+        file.write_all(b" done\n").await.unwrap();
+        assert_eq!(first.recv().await.unwrap(), "TAIL half done");
+
+        phase_tx.send_replace(Phase::Draining);
+        assert_eq!(second.recv().await.unwrap(), "TAIL half done");
+        assert!(second.recv().await.is_none());
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+}
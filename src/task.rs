@@ -0,0 +1,65 @@
+//! Task spawning with names.
+//!
+//! With the `console` feature (and `RUSTFLAGS="--cfg tokio_unstable"`),
+//! tasks are spawned through `tokio::task::Builder` so they show up by name
+//! in tokio-console. Without it, this is a plain `tokio::spawn`.
+
+use std::future::Future;
+use tokio::task::{AbortHandle, JoinHandle, JoinSet};
+
+#[cfg(all(feature = "console", not(tokio_unstable)))]
+compile_error!("the `console` feature requires building with RUSTFLAGS=\"--cfg tokio_unstable\"");
+
+#[cfg(all(feature = "console", tokio_unstable))]
+pub fn spawn<F>(name: &str, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::task::Builder::new()
+        .name(name)
+        .spawn(future)
+        .expect("failed to spawn task")
+}
+
+#[cfg(not(all(feature = "console", tokio_unstable)))]
+pub fn spawn<F>(_name: &str, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::spawn(future)
+}
+
+/// Like [`spawn`], but the task belongs to `set`: its result (or panic) is
+/// collected with `join_next`, and dropping the set aborts it.
+#[cfg(all(feature = "console", tokio_unstable))]
+pub fn spawn_in<F>(set: &mut JoinSet<F::Output>, name: &str, future: F) -> AbortHandle
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    set.build_task()
+        .name(name)
+        .spawn(future)
+        .expect("failed to spawn task")
+}
+
+#[cfg(not(all(feature = "console", tokio_unstable)))]
+pub fn spawn_in<F>(set: &mut JoinSet<F::Output>, _name: &str, future: F) -> AbortHandle
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    set.spawn(future)
+}
+
+/// Starts the console-subscriber server that tokio-console connects to
+/// (by default on 127.0.0.1:6669).
+#[cfg(feature = "console")]
+pub fn init_console() {
+    console_subscriber::init();
+}
+
+#[cfg(not(feature = "console"))]
+pub fn init_console() {}
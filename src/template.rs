@@ -0,0 +1,231 @@
+//! Response templates for plain (non-command) messages.
+//!
+//! The default reply is `OK: '{input}' (request #{n})`. A template is
+//! compiled once, when the configuration is loaded, into literal text and
+//! placeholders, so rendering a reply is a simple walk over the parts.
+//!
+//! Placeholders:
+//!
+//! - `{input}`: the message as received (trimmed)
+//! - `{n}`: the server-wide request counter
+//! - `{peer}`: the client's address
+//! - `{conn}`: the connection id
+//! - `{timestamp}`: Unix time in seconds
+//!
+//! `{{` and `}}` stand for literal braces.
+//!
+//! The template is the plain-text [`ResponseFormatter`]. A client can pick
+//! another [`Format`] during the handshake (`HELLO ECHO FORMAT=JSON`):
+//!
+//! ```text
+//! PLAIN     OK: 'hello' (request #7)
+//! JSON      {"conn":3,"input":"hello","request":7}
+//! COMPACT   #7 hello
+//! ```
+
+use serde_json::json;
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const DEFAULT: &str = "OK: '{input}' (request #{n})";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Input,
+    RequestNumber,
+    Peer,
+    ConnectionId,
+    Timestamp,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Field(Field),
+}
+
+/// A compiled response template.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResponseTemplate {
+    parts: Vec<Part>,
+}
+
+/// The values a template can refer to.
+pub struct Context<'a> {
+    pub input: &'a str,
+    pub request: i32,
+    pub peer: SocketAddr,
+    pub conn_id: u64,
+}
+
+impl Default for ResponseTemplate {
+    fn default() -> Self {
+        Self::compile(DEFAULT).expect("the default template is valid")
+    }
+}
+
+impl ResponseTemplate {
+    pub fn compile(text: &str) -> Result<Self, String> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = text.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err("unclosed `{`".to_string()),
+                        }
+                    }
+                    let field = match name.as_str() {
+                        "input" => Field::Input,
+                        "n" => Field::RequestNumber,
+                        "peer" => Field::Peer,
+                        "conn" => Field::ConnectionId,
+                        "timestamp" => Field::Timestamp,
+                        _ => return Err(format!("unknown placeholder {{{}}}", name)),
+                    };
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Field(field));
+                }
+                '}' => return Err("unmatched `}` (use `}}` for a literal brace)".to_string()),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+
+        Ok(Self { parts })
+    }
+
+    /// Renders one response line, including the trailing newline.
+    pub fn render(&self, ctx: &Context<'_>) -> String {
+        let mut out = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(text) => out.push_str(text),
+                Part::Field(Field::Input) => out.push_str(ctx.input),
+                Part::Field(Field::RequestNumber) => out.push_str(&ctx.request.to_string()),
+                Part::Field(Field::Peer) => out.push_str(&ctx.peer.to_string()),
+                Part::Field(Field::ConnectionId) => out.push_str(&ctx.conn_id.to_string()),
+                Part::Field(Field::Timestamp) => {
+                    let secs = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map(|d| d.as_secs())
+                        .unwrap_or(0);
+                    out.push_str(&secs.to_string());
+                }
+            }
+        }
+        out.push('\n');
+        out
+    }
+}
+
+/// Turns the reply to a plain message into the line sent back.
+pub trait ResponseFormatter: Send + Sync {
+    /// One response line, including the trailing newline.
+    fn format(&self, ctx: &Context<'_>) -> String;
+}
+
+impl ResponseFormatter for ResponseTemplate {
+    fn format(&self, ctx: &Context<'_>) -> String {
+        self.render(ctx)
+    }
+}
+
+/// One JSON object per reply, for clients that would rather not parse text.
+pub struct JsonFormatter;
+
+impl ResponseFormatter for JsonFormatter {
+    fn format(&self, ctx: &Context<'_>) -> String {
+        let reply = json!({"input": ctx.input, "request": ctx.request, "conn": ctx.conn_id});
+        format!("{}\n", reply)
+    }
+}
+
+/// The request number and the input, nothing else.
+pub struct CompactFormatter;
+
+impl ResponseFormatter for CompactFormatter {
+    fn format(&self, ctx: &Context<'_>) -> String {
+        format!("#{} {}\n", ctx.request, ctx.input)
+    }
+}
+
+/// The formatter a connection picked with `HELLO`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    /// The configured template.
+    #[default]
+    Plain,
+    Json,
+    Compact,
+}
+
+impl Format {
+    pub const ALL: [Format; 3] = [Format::Plain, Format::Json, Format::Compact];
+
+    pub fn parse(name: &str) -> Option<Format> {
+        Self::ALL.into_iter().find(|format| name.eq_ignore_ascii_case(&format.to_string()))
+    }
+
+    /// The formatter; `template` is the plain-text one.
+    pub fn formatter(self, template: &ResponseTemplate) -> &dyn ResponseFormatter {
+        match self {
+            Format::Plain => template,
+            Format::Json => &JsonFormatter,
+            Format::Compact => &CompactFormatter,
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Format::Plain => "PLAIN",
+            Format::Json => "JSON",
+            Format::Compact => "COMPACT",
+        };
+        f.write_str(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_format_renders_the_same_reply() {
+        let ctx = Context {
+            input: "it's \"quoted\"",
+            request: 7,
+            peer: SocketAddr::from(([127, 0, 0, 1], 4000)),
+            conn_id: 3,
+        };
+        let template = ResponseTemplate::default();
+        let lines: Vec<String> =
+            Format::ALL.iter().map(|format| format.formatter(&template).format(&ctx)).collect();
+        assert_eq!(lines[0], "OK: 'it's \"quoted\"' (request #7)\n");
+        assert_eq!(lines[1], concat!(r#"{"conn":3,"input":"it's \"quoted\"","request":7}"#, "\n"));
+        assert_eq!(lines[2], "#7 it's \"quoted\"\n");
+        assert_eq!(Format::parse("compact"), Some(Format::Compact));
+        assert_eq!(Format::parse("xml"), None);
+    }
+}
@@ -0,0 +1,178 @@
+//! `ThrottledStream`: caps how many bytes per second go through a stream.
+//!
+//! Like [`WaitForStateMachine`](crate::futures_examples::WaitForStateMachine), this
+//! is hand-written polling, but here "not yet" has a proper wake-up: when
+//! a direction has used up its allowance, `poll_read` / `poll_write` arm a
+//! `tokio::time::Sleep` for the moment the next byte is allowed, poll it
+//! (which registers the waker with the timer) and return `Pending`. The
+//! runtime polls the stream again once the timer fires; nothing spins.
+//!
+//! Each direction has its own token bucket: tokens are bytes, refilled at
+//! the configured rate and capped at a tenth of a second's worth, so a
+//! stream that was idle cannot burst far past the rate.
+
+use crate::transport::Transport;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep};
+
+pub struct ThrottledStream<T> {
+    inner: T,
+    // `None` when the stream is not limited
+    read: Option<Bucket>,
+    write: Option<Bucket>,
+}
+
+impl<T> ThrottledStream<T> {
+    /// Limits both directions of `inner` to `bytes_per_sec` each, if set.
+    pub fn new(inner: T, bytes_per_sec: Option<u64>) -> Self {
+        Self {
+            inner,
+            read: bytes_per_sec.map(Bucket::new),
+            write: bytes_per_sec.map(Bucket::new),
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+struct Bucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    refilled: Instant,
+    // Boxed because `Sleep` is `!Unpin`, and the stream should stay `Unpin`
+    sleep: Pin<Box<Sleep>>,
+}
+
+impl Bucket {
+    fn new(bytes_per_sec: u64) -> Self {
+        let rate = bytes_per_sec.max(1) as f64;
+        let capacity = (rate / 10.0).max(1.0);
+        let now = Instant::now();
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            refilled: now,
+            sleep: Box::pin(tokio::time::sleep_until(now)),
+        }
+    }
+
+    /// The bytes that may pass now; `Pending` until there is at least one.
+    fn poll_allowance(&mut self, cx: &mut Context<'_>) -> Poll<usize> {
+        loop {
+            let now = Instant::now();
+            let earned = now.duration_since(self.refilled).as_secs_f64() * self.rate;
+            self.tokens = (self.tokens + earned).min(self.capacity);
+            self.refilled = now;
+            if self.tokens >= 1.0 {
+                return Poll::Ready(self.tokens as usize);
+            }
+            let wait = (1.0 - self.tokens) / self.rate;
+            self.sleep.as_mut().reset(now + std::time::Duration::from_secs_f64(wait));
+            // Registers the waker; a timer that has already fired loops around
+            ready!(self.sleep.as_mut().poll(cx));
+        }
+    }
+
+    fn consume(&mut self, bytes: usize) {
+        self.tokens -= bytes as f64;
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for ThrottledStream<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let Some(bucket) = &mut this.read else {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        };
+        let allowed = ready!(bucket.poll_allowance(cx)).min(buf.remaining());
+        // Reads into a window of `buf` no larger than the allowance
+        let mut limited = ReadBuf::new(buf.initialize_unfilled_to(allowed));
+        ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+        let n = limited.filled().len();
+        buf.advance(n);
+        bucket.consume(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for ThrottledStream<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let Some(bucket) = &mut this.write else {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        };
+        let allowed = ready!(bucket.poll_allowance(cx)).min(buf.len());
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..allowed]))?;
+        bucket.consume(n);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+impl<T: Transport> Transport for ThrottledStream<T> {
+    fn peer_closed(&self) -> impl Future<Output = ()> + Send + '_ {
+        self.inner.peer_closed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn writes_are_held_to_the_rate() {
+        let (client, mut server) = tokio::io::duplex(4096);
+        let mut client = ThrottledStream::new(client, Some(1000));
+
+        // 100 bytes of burst, then 200 more at 1000 bytes per second
+        let started = Instant::now();
+        client.write_all(&[b'x'; 300]).await.unwrap();
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(180), "took {:?}", elapsed);
+
+        let mut received = [0u8; 300];
+        server.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, [b'x'; 300]);
+    }
+
+    #[tokio::test]
+    async fn reads_take_at_most_the_allowance() {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let mut server = ThrottledStream::new(server, Some(1000));
+        client.write_all(&[b'y'; 500]).await.unwrap();
+
+        let mut buf = [0u8; 500];
+        assert_eq!(server.read(&mut buf).await.unwrap(), 100);
+        let unlimited = &mut ThrottledStream::new(server.into_inner(), None);
+        assert_eq!(unlimited.read(&mut buf).await.unwrap(), 400);
+    }
+}
@@ -0,0 +1,185 @@
+//! The TLS listener, and how it picks a protocol with ALPN.
+//!
+//! One port serves several protocols. In its `ClientHello` the client lists
+//! the protocols it speaks (ALPN); the server settles on one of them, and
+//! after the handshake the connection is served by that protocol's handler:
+//!
+//! ```text
+//! echo/1  -> the text protocol, as on the main listener
+//! h2      -> echo and the counter over HTTP/2 (see crate::http2)
+//! grpc    -> the gRPC service, with the grpc feature (see crate::grpc)
+//! (none)  -> the text protocol
+//! ```
+//!
+//! A client that offers none of these fails the handshake. The negotiated
+//! protocol is recorded in the connection's registry entry, so `LIST`
+//! shows it.
+//!
+//! With `tls_client_ca` set, the listener asks for a client certificate
+//! too, and a client without one signed by that CA fails the handshake.
+//! The certificate's [`identity`] is recorded next to the protocol; if the
+//! config lists it, the connection starts out with that identity's
+//! permission, as if it had sent an `AUTH` token (see `crate::auth`).
+
+use crate::error::ServerError;
+use crate::transport::Transport;
+use std::future::Future;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::server::TlsStream;
+
+/// How long a client has to complete the handshake.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What a TLS connection speaks once the handshake is done.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alpn {
+    /// The text protocol.
+    Echo,
+    /// HTTP/2, served by the h2 endpoint.
+    H2,
+    /// gRPC, served by tonic.
+    Grpc,
+}
+
+impl Alpn {
+    /// The protocols the server accepts, in the order it prefers them.
+    pub fn offered() -> &'static [Alpn] {
+        if cfg!(feature = "grpc") {
+            &[Alpn::Echo, Alpn::H2, Alpn::Grpc]
+        } else {
+            &[Alpn::Echo, Alpn::H2]
+        }
+    }
+
+    /// The protocol ID sent in the handshake.
+    pub fn name(self) -> &'static str {
+        match self {
+            Alpn::Echo => "echo/1",
+            Alpn::H2 => "h2",
+            Alpn::Grpc => "grpc",
+        }
+    }
+
+    /// What the handshake settled on; a client without ALPN gets the text protocol.
+    pub fn negotiated(protocol: Option<&[u8]>) -> Alpn {
+        let mut offered = Alpn::offered().iter().copied();
+        protocol
+            .and_then(|id| offered.find(|alpn| alpn.name().as_bytes() == id))
+            .unwrap_or(Alpn::Echo)
+    }
+}
+
+/// Loads the certificate chain and key, and sets up ALPN; with `client_ca`,
+/// clients must present a certificate signed by it.
+pub async fn acceptor(
+    cert: &Path,
+    key: &Path,
+    client_ca: Option<&Path>,
+) -> Result<TlsAcceptor, ServerError> {
+    let certs = certificates(cert).await?;
+    let key_der = PrivateKeyDer::from_pem_slice(&read(key).await?).map_err(|e| invalid(key, e))?;
+    let provider = Arc::new(ring::default_provider());
+
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| ServerError::Config(format!("TLS protocol versions: {}", e)))?;
+    let builder = match client_ca {
+        Some(ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in certificates(ca).await? {
+                roots.add(cert).map_err(|e| invalid(ca, e))?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .map_err(|e| ServerError::Config(format!("TLS client CA: {}", e)))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder
+        .with_single_cert(certs, key_der)
+        .map_err(|e| ServerError::Config(format!("TLS certificate and key: {}", e)))?;
+    config.alpn_protocols =
+        Alpn::offered().iter().map(|alpn| alpn.name().as_bytes().to_vec()).collect();
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Who a client certificate names: the common name of its subject, or the
+/// whole subject if it has none.
+pub fn identity(cert: &CertificateDer<'_>) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert).ok()?;
+    let subject = cert.subject();
+    let name = match subject.iter_common_name().next() {
+        Some(common_name) => common_name.as_str().ok()?.to_string(),
+        None => subject.to_string(),
+    };
+    (!name.is_empty()).then_some(name)
+}
+
+async fn certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, ServerError> {
+    CertificateDer::pem_slice_iter(&read(path).await?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| invalid(path, e))
+}
+
+async fn read(path: &Path) -> Result<Vec<u8>, ServerError> {
+    tokio::fs::read(path)
+        .await
+        .map_err(|source| ServerError::File { path: path.to_path_buf(), source })
+}
+
+fn invalid(path: &Path, e: impl std::fmt::Display) -> ServerError {
+    let source = io::Error::new(io::ErrorKind::InvalidData, e.to_string());
+    ServerError::File { path: path.to_path_buf(), source }
+}
+
+/// The text protocol over TLS: whether the client is gone is still a
+/// question for the socket underneath.
+impl<S: Transport> Transport for TlsStream<S> {
+    fn peer_closed(&self) -> impl Future<Output = ()> + Send + '_ {
+        self.get_ref().0.peer_closed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{CertificateParams, DistinguishedName, DnType, KeyPair};
+
+    #[test]
+    fn unknown_or_missing_alpn_means_the_text_protocol() {
+        assert_eq!(Alpn::negotiated(Some(b"h2")), Alpn::H2);
+        assert_eq!(Alpn::negotiated(Some(b"echo/1")), Alpn::Echo);
+        assert_eq!(Alpn::negotiated(None), Alpn::Echo);
+        assert_eq!(Alpn::negotiated(Some(b"http/1.1")), Alpn::Echo);
+    }
+
+    fn certificate(subject: &[(DnType, &str)]) -> CertificateDer<'static> {
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.distinguished_name = DistinguishedName::new();
+        for (kind, value) in subject {
+            params.distinguished_name.push(kind.clone(), *value);
+        }
+        params.self_signed(&KeyPair::generate().unwrap()).unwrap().der().clone()
+    }
+
+    #[test]
+    fn the_identity_is_the_common_name_or_else_the_subject() {
+        let cert =
+            certificate(&[(DnType::OrganizationName, "Example"), (DnType::CommonName, "ops")]);
+        assert_eq!(identity(&cert).as_deref(), Some("ops"));
+        let cert = certificate(&[(DnType::OrganizationName, "Example")]);
+        assert_eq!(identity(&cert).as_deref(), Some("O=Example"));
+        assert_eq!(identity(&certificate(&[])), None);
+        assert_eq!(identity(&CertificateDer::from(vec![1, 2, 3])), None);
+    }
+}
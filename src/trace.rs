@@ -0,0 +1,181 @@
+//! Per-connection trace files.
+//!
+//! Every traced connection writes one file with one event per line:
+//!
+//! ```text
+//! <unix micros> <connection id> <tag> <payload>
+//! ```
+//!
+//! Tags are single letters to keep the files compact:
+//! `C` connected, `A` started awaiting, `R` bytes read, `W` bytes written,
+//! `M` message received, `X` closed (with the close reason).
+//!
+//! Because timestamps are absolute, `trace-dump` can merge the files of
+//! several connections and show how their tasks interleaved on the runtime.
+
+use crate::close::CloseReason;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs::File;
+use tokio::io::{AsyncWriteExt, BufWriter};
+
+/// A single event in the life of a connection.
+pub enum TraceEvent<'a> {
+    Connected(SocketAddr),
+    /// The task is about to suspend on the named operation.
+    Waiting(&'a str),
+    Read(usize),
+    Written(usize),
+    Message(&'a str),
+    Closed(CloseReason),
+}
+
+/// Writes trace events of one connection.
+///
+/// A tracer without a file is a no-op, so the handler can record events
+/// unconditionally whether tracing is enabled or not.
+pub struct ConnTracer {
+    conn_id: u64,
+    out: Option<BufWriter<File>>,
+}
+
+impl ConnTracer {
+    /// Creates `conn-<id>.trace` in `dir`, or a no-op tracer when `dir` is `None`.
+    pub async fn create(dir: Option<&Path>, conn_id: u64) -> Self {
+        let out = match dir {
+            Some(dir) => {
+                let path = dir.join(format!("conn-{}.trace", conn_id));
+                match File::create(&path).await {
+                    Ok(file) => Some(BufWriter::new(file)),
+                    Err(e) => {
+                        eprintln!("cannot create trace file {}: {}", path.display(), e);
+                        None
+                    }
+                }
+            }
+            None => None,
+        };
+
+        Self { conn_id, out }
+    }
+
+    pub async fn record(&mut self, event: TraceEvent<'_>) {
+        let Some(out) = self.out.as_mut() else {
+            return;
+        };
+
+        let (tag, payload) = match event {
+            TraceEvent::Connected(peer) => ('C', peer.to_string()),
+            TraceEvent::Waiting(what) => ('A', what.to_string()),
+            TraceEvent::Read(n) => ('R', n.to_string()),
+            TraceEvent::Written(n) => ('W', n.to_string()),
+            TraceEvent::Message(text) => ('M', escape(text)),
+            TraceEvent::Closed(reason) => ('X', reason.to_string()),
+        };
+        let line = format!("{} {} {} {}\n", now_micros(), self.conn_id, tag, payload);
+
+        // Tracing is best-effort: a failing trace file must not break the connection.
+        if let Err(e) = out.write_all(line.as_bytes()).await {
+            eprintln!("trace for connection {} disabled: {}", self.conn_id, e);
+            self.out = None;
+        }
+    }
+
+    /// Flushes buffered events. Called once when the connection ends.
+    pub async fn finish(mut self) {
+        if let Some(out) = self.out.as_mut() {
+            let _ = out.flush().await;
+        }
+    }
+}
+
+fn now_micros() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros())
+        .unwrap_or(0)
+}
+
+/// Keeps every event on a single line.
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\n', "\\n").replace('\r', "\\r")
+}
+
+fn unescape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some(other) => out.push(other),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+struct Record {
+    micros: u128,
+    conn_id: u64,
+    text: String,
+}
+
+fn parse_line(line: &str) -> Option<Record> {
+    let mut parts = line.splitn(4, ' ');
+    let micros = parts.next()?.parse().ok()?;
+    let conn_id = parts.next()?.parse().ok()?;
+    let tag = parts.next()?;
+    let payload = parts.next().unwrap_or("");
+
+    let text = match tag {
+        "C" => format!("connected from {}", payload),
+        "A" => format!("awaiting {}", payload),
+        "R" => format!("read {} bytes", payload),
+        "W" => format!("wrote {} bytes", payload),
+        "M" => format!("message {:?}", unescape(payload)),
+        "X" if payload.is_empty() => "closed".to_string(),
+        "X" => format!("closed ({})", payload),
+        _ => return None,
+    };
+
+    Some(Record { micros, conn_id, text })
+}
+
+/// Implements the `trace-dump` subcommand.
+///
+/// Events of all given files are merged by timestamp and printed relative
+/// to the earliest one, so concurrent connections show up interleaved.
+pub async fn dump(files: &[PathBuf]) -> std::io::Result<()> {
+    let mut records = Vec::new();
+
+    for path in files {
+        let content = tokio::fs::read_to_string(path).await?;
+        for (n, line) in content.lines().enumerate() {
+            match parse_line(line) {
+                Some(record) => records.push(record),
+                None => eprintln!("{}:{}: skipping malformed line", path.display(), n + 1),
+            }
+        }
+    }
+
+    // Stable sort keeps the file order for events with equal timestamps.
+    records.sort_by_key(|r| r.micros);
+
+    let Some(start) = records.first().map(|r| r.micros) else {
+        println!("(no events)");
+        return Ok(());
+    };
+
+    for record in &records {
+        let offset_ms = (record.micros - start) as f64 / 1000.0;
+        println!("{:>12.3}ms  conn#{:<4} {}", offset_ms, record.conn_id, record.text);
+    }
+
+    Ok(())
+}
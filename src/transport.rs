@@ -0,0 +1,47 @@
+//! What a connection can be served over.
+//!
+//! The protocol handler only needs a byte stream it can read and write,
+//! plus one extra question: "has the other side gone away?". TCP sockets
+//! answer it with `peek`; in-process `tokio::io::duplex` pipes cannot, so
+//! for them a departed client is noticed on the next read instead.
+
+use std::future::Future;
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream};
+use tokio::net::TcpStream;
+
+/// A byte stream the built-in protocol can be served over.
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+    /// Completes when the peer has closed its side of the connection.
+    ///
+    /// Must not consume input: pipelined data has to stay there for the
+    /// next `read`.
+    fn peer_closed(&self) -> impl Future<Output = ()> + Send + '_;
+}
+
+impl Transport for TcpStream {
+    async fn peer_closed(&self) {
+        // `peek` leaves the data in the socket
+        let mut byte = [0u8; 1];
+        match self.peek(&mut byte).await {
+            Ok(0) | Err(_) => {}
+            // More input is waiting: the peer is still there
+            Ok(_) => std::future::pending().await,
+        }
+    }
+}
+
+impl Transport for DuplexStream {
+    async fn peer_closed(&self) {
+        // A duplex pipe has no `peek`, and reading here would steal input
+        std::future::pending().await
+    }
+}
+
+/// Turmoil's simulated TCP: `peek` takes `&mut self`, so like a duplex
+/// pipe, a departed client is noticed on the next read.
+#[cfg(feature = "sim")]
+impl Transport for turmoil::net::TcpStream {
+    async fn peer_closed(&self) {
+        std::future::pending().await
+    }
+}
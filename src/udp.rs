@@ -0,0 +1,149 @@
+//! Echo and the counter over UDP, one datagram per message.
+//!
+//! ```text
+//! COUNT        -> COUNT <requests served>
+//! <anything>   -> the same bytes back   (counts as a request)
+//! ```
+//!
+//! Datagrams are read in batches rather than with one `recv_from().await`
+//! each. The task waits until the socket is readable, then takes up to
+//! [`BATCH`] datagrams with `try_recv_from`, which never awaits, and stops
+//! early once the socket is empty. The replies go out after the batch.
+//!
+//! `try_recv_from` does not spend tokio's cooperative budget, so under a
+//! flood the loop would never give the worker thread back: it calls
+//! `yield_now` after every batch, and other tasks get their turn.
+
+use crate::logger::{LogMessage, LogTx};
+use crate::metrics;
+use crate::readiness::{self, Phase};
+use crate::state::State;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::watch;
+
+/// Datagrams taken from the socket between two yields.
+pub const BATCH: usize = 32;
+
+/// The largest datagram read; longer ones are cut to this size.
+const MAX_DATAGRAM: usize = 64 * 1024;
+
+/// Serves datagrams on `socket` until the server drains.
+pub async fn serve(
+    socket: UdpSocket,
+    state: Arc<State>,
+    log_tx: LogTx,
+    phase: watch::Receiver<Phase>,
+) {
+    let mut draining = phase;
+    let mut buffer = vec![0; MAX_DATAGRAM];
+    let mut replies = Vec::with_capacity(BATCH);
+    loop {
+        let ready = tokio::select! {
+            ready = socket.readable() => ready,
+            _ = readiness::wait_draining(&mut draining) => return,
+        };
+        let received = ready.and_then(|()| {
+            try_recv_batch(&socket, &mut buffer, BATCH, |datagram, peer| {
+                replies.push((reply(datagram, &state), peer));
+            })
+        });
+        if let Err(e) = received {
+            log_tx.send(LogMessage::error(format!("UDP receive failed: {}", e))).await;
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        for (reply, peer) in replies.drain(..) {
+            // A lost reply is what UDP clients expect now and then
+            if let Err(e) = socket.send_to(&reply, peer).await {
+                let text = format!("UDP reply to {} failed: {}", peer, e);
+                log_tx.send(LogMessage::error(text)).await;
+            }
+        }
+        tokio::task::yield_now().await;
+    }
+}
+
+/// Receives the datagrams already waiting on `socket`, at most `most`,
+/// and hands each to `each`. Never awaits: returns how many it received
+/// once the socket is empty or `most` is reached.
+pub fn try_recv_batch(
+    socket: &UdpSocket,
+    buffer: &mut [u8],
+    most: usize,
+    mut each: impl FnMut(&[u8], SocketAddr),
+) -> io::Result<usize> {
+    for received in 0..most {
+        match socket.try_recv_from(buffer) {
+            Ok((len, peer)) => each(&buffer[..len], peer),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(received),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(most)
+}
+
+fn reply(datagram: &[u8], state: &State) -> Vec<u8> {
+    state.metrics.incr(metrics::MESSAGES_RECEIVED, 1);
+    if datagram.trim_ascii() == b"COUNT" {
+        return format!("COUNT {}\n", state.count()).into_bytes();
+    }
+    state.increment();
+    datagram.to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logger::{self, LogOverflow};
+    use crate::metrics::{InMemorySink, Metrics};
+
+    #[tokio::test]
+    async fn datagrams_are_echoed_and_counted() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let metrics: Metrics = Arc::new(InMemorySink::default());
+        let state = Arc::new(State::new(metrics.clone()));
+        let (log_tx, _log) = logger::channel(16, &LogOverflow::Drop, metrics);
+        let (phase_tx, phase) = watch::channel(Phase::Ready);
+        let serving = tokio::spawn(serve(server, state.clone(), log_tx, phase));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(addr).await.unwrap();
+        let mut buffer = [0; 64];
+        for message in ["hello", "world"] {
+            client.send(message.as_bytes()).await.unwrap();
+            let len = client.recv(&mut buffer).await.unwrap();
+            assert_eq!(&buffer[..len], message.as_bytes());
+        }
+        client.send(b"COUNT\n").await.unwrap();
+        let len = client.recv(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..len], b"COUNT 2\n");
+        assert_eq!(state.count(), 2);
+
+        phase_tx.send(Phase::Draining).unwrap();
+        serving.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_batch_takes_what_is_waiting_up_to_its_size() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(server.local_addr().unwrap()).await.unwrap();
+        for n in 0..5u8 {
+            client.send(&[n]).await.unwrap();
+        }
+        let mut buffer = [0; 16];
+        let mut seen = Vec::new();
+        let mut record = |datagram: &[u8], _| seen.push(datagram[0]);
+        server.readable().await.unwrap();
+        // Loopback delivers at once, but give the last datagrams a moment
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(try_recv_batch(&server, &mut buffer, 3, &mut record).unwrap(), 3);
+        assert_eq!(try_recv_batch(&server, &mut buffer, 3, &mut record).unwrap(), 2);
+        assert_eq!(try_recv_batch(&server, &mut buffer, 3, &mut record).unwrap(), 0);
+        assert_eq!(seen, [0, 1, 2, 3, 4]);
+    }
+}
@@ -0,0 +1,226 @@
+//! `PUT <name> <size>` and `GET-FILE <name>`: files in the uploads directory.
+//!
+//! ```text
+//! PUT notes.txt 12
+//! READY: send 12 bytes
+//! <12 bytes>
+//! OK: stored notes.txt (12 bytes)
+//! GET-FILE notes.txt
+//! FILE 12
+//! <12 bytes>
+//! ```
+//!
+//! Neither body is a message. The handler moves it from its read buffer
+//! into the file with `write_all` a chunk at a time, so an upload never
+//! has to fit into memory. It is written to a hidden `.part` file that is
+//! renamed once complete, so a half-written upload never shows up under
+//! its real name. Each upload has a part file of its own: two `PUT`s of
+//! the same name at once each write a whole file, and the last one to
+//! finish replaces the other. A download is copied from the file to the socket with
+//! `copy_buf`, and the `FILE` header tells the client where it ends.
+
+use crate::buffers::BUFFER_SIZE;
+use bytes::{Buf, BytesMut};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::fs::{self, File};
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+
+/// Progress is reported every time this many more bytes have been written.
+pub const PROGRESS_STEP: u64 = 1024 * 1024;
+
+/// `Some(Ok((name, size)))` for a valid `PUT`, `Some(Err(..))` for a bad one.
+pub fn parse_put(input: &str) -> Option<Result<(String, u64), String>> {
+    let mut words = input.split_whitespace();
+    if !words.next()?.eq_ignore_ascii_case("PUT") {
+        return None;
+    }
+    let usage = "usage: PUT <name> <size>".to_string();
+    let (Some(name), Some(size), None) = (words.next(), words.next(), words.next()) else {
+        return Some(Err(usage));
+    };
+    let Ok(size) = size.parse() else { return Some(Err(usage)) };
+    Some(check_name(name).map(|name| (name, size)))
+}
+
+/// `Some(Ok(name))` for a valid `GET-FILE`, `Some(Err(..))` for a bad one.
+pub fn parse_get_file(input: &str) -> Option<Result<String, String>> {
+    let mut words = input.split_whitespace();
+    if !words.next()?.eq_ignore_ascii_case("GET-FILE") {
+        return None;
+    }
+    let (Some(name), None) = (words.next(), words.next()) else {
+        return Some(Err("usage: GET-FILE <name>".to_string()));
+    };
+    Some(check_name(name))
+}
+
+/// A plain file name: no directories, nothing hidden.
+fn check_name(name: &str) -> Result<String, String> {
+    if name.starts_with('.') || name.contains(['/', '\\']) {
+        return Err(format!("invalid file name {:?}", name));
+    }
+    Ok(name.to_string())
+}
+
+/// Opens `name` in `dir` and returns it with its current length.
+pub async fn open(dir: &Path, name: &str) -> io::Result<(File, u64)> {
+    let file = File::open(dir.join(name)).await?;
+    let metadata = file.metadata().await?;
+    if !metadata.is_file() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "not a file"));
+    }
+    Ok((file, metadata.len()))
+}
+
+/// Copies exactly `len` bytes of `file` to `writer`, as announced in the header.
+///
+/// Bytes appended after `open` are left out. A file that shrank in the
+/// meantime ends the copy early, which is an `UnexpectedEof` error: the
+/// client is still waiting for the rest.
+pub async fn send<W>(file: File, len: u64, writer: &mut W) -> io::Result<u64>
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut reader = BufReader::with_capacity(BUFFER_SIZE, file.take(len));
+    let sent = tokio::io::copy_buf(&mut reader, writer).await?;
+    if sent < len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file shrank during download"));
+    }
+    Ok(sent)
+}
+
+/// Uploads started by this process, which numbers their part files.
+static UPLOADS: AtomicU64 = AtomicU64::new(0);
+
+/// A file being received.
+pub struct Upload {
+    file: File,
+    part: PathBuf,
+    path: PathBuf,
+    size: u64,
+    written: u64,
+    reported: u64,
+}
+
+impl Upload {
+    /// Creates `dir` if needed and starts writing `name` in it.
+    pub async fn create(dir: &Path, name: &str, size: u64) -> io::Result<Upload> {
+        fs::create_dir_all(dir).await?;
+        // Unique to this upload, even among servers sharing the directory
+        let n = UPLOADS.fetch_add(1, Ordering::Relaxed);
+        let part = dir.join(format!(".{}.{}-{}.part", name, std::process::id(), n));
+        let file = File::create_new(&part).await?;
+        Ok(Upload { file, part, path: dir.join(name), size, written: 0, reported: 0 })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Bytes still expected from the client.
+    pub fn remaining(&self) -> u64 {
+        self.size - self.written
+    }
+
+    /// Writes the part of `buf` that belongs to the upload and removes it
+    /// from `buf`; anything after the body stays there.
+    pub async fn write_from(&mut self, buf: &mut BytesMut) -> io::Result<()> {
+        let n = buf.len().min(usize::try_from(self.remaining()).unwrap_or(usize::MAX));
+        self.file.write_all(&buf[..n]).await?;
+        buf.advance(n);
+        self.written += n as u64;
+        Ok(())
+    }
+
+    /// The bytes written so far, once per `PROGRESS_STEP`.
+    pub fn progress(&mut self) -> Option<u64> {
+        if self.written < self.reported + PROGRESS_STEP || self.remaining() == 0 {
+            return None;
+        }
+        self.reported = self.written;
+        Some(self.written)
+    }
+
+    /// Flushes the file and moves it to its real name.
+    pub async fn finish(mut self) -> io::Result<u64> {
+        self.file.flush().await?;
+        fs::rename(&self.part, &self.path).await?;
+        Ok(self.written)
+    }
+
+    /// Removes what was written so far.
+    pub async fn abort(self) {
+        drop(self.file);
+        let _ = fs::remove_file(&self.part).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn put_takes_a_plain_name_and_a_size() {
+        assert_eq!(parse_put("PUT a.txt 5"), Some(Ok(("a.txt".to_string(), 5))));
+        assert_eq!(parse_put("GET a"), None);
+        assert!(parse_put("PUT a.txt").unwrap().is_err());
+        assert!(parse_put("PUT ../etc/passwd 5").unwrap().is_err());
+        assert!(parse_put("PUT .hidden 5").unwrap().is_err());
+        assert_eq!(parse_get_file("get-file a.txt"), Some(Ok("a.txt".to_string())));
+        assert!(parse_get_file("GET-FILE a b").unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn the_body_ends_where_it_says() {
+        let dir = std::env::temp_dir().join(format!("upload-test-{}", std::process::id()));
+        let mut upload = Upload::create(&dir, "a.txt", 5).await.unwrap();
+
+        // The body arrives in two pieces, followed by the next command
+        let mut buf = BytesMut::from(&b"hel"[..]);
+        upload.write_from(&mut buf).await.unwrap();
+        assert_eq!(upload.remaining(), 2);
+        buf.extend_from_slice(b"loSTATS\n");
+        upload.write_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..], b"STATS\n");
+
+        let path = upload.path().to_path_buf();
+        assert_eq!(upload.finish().await.unwrap(), 5);
+        assert_eq!(fs::read(&path).await.unwrap(), b"hello");
+
+        // Only the announced length is sent
+        let (file, len) = open(&dir, "a.txt").await.unwrap();
+        let mut sent = Vec::new();
+        assert_eq!(send(file, len - 1, &mut sent).await.unwrap(), 4);
+        assert_eq!(sent, b"hell");
+        let (file, len) = open(&dir, "a.txt").await.unwrap();
+        let error = send(file, len + 1, &mut Vec::new()).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn uploads_of_the_same_name_do_not_mix() {
+        let dir = std::env::temp_dir().join(format!("upload-race-{}", std::process::id()));
+        let mut first = Upload::create(&dir, "a.txt", 5).await.unwrap();
+        let mut second = Upload::create(&dir, "a.txt", 5).await.unwrap();
+        assert_ne!(first.part, second.part);
+
+        // Interleaved, as two connections would write them
+        first.write_from(&mut BytesMut::from(&b"aa"[..])).await.unwrap();
+        second.write_from(&mut BytesMut::from(&b"bbb"[..])).await.unwrap();
+        first.write_from(&mut BytesMut::from(&b"aaa"[..])).await.unwrap();
+        second.write_from(&mut BytesMut::from(&b"bb"[..])).await.unwrap();
+
+        first.finish().await.unwrap();
+        assert_eq!(fs::read(dir.join("a.txt")).await.unwrap(), b"aaaaa");
+        second.finish().await.unwrap();
+        assert_eq!(fs::read(dir.join("a.txt")).await.unwrap(), b"bbbbb");
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
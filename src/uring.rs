@@ -0,0 +1,89 @@
+//! The io_uring backend, `--runtime uring`: Linux only, with the `uring` feature.
+//!
+//! The main listener's sockets are accepted, read and written with
+//! tokio-uring. Each of those operations is submitted to the kernel's ring
+//! and completes there, where the default backend waits for epoll to report
+//! the socket ready and then makes the system call itself. The buffers are
+//! owned: one goes to the kernel with each read or write and comes back
+//! with the result.
+//!
+//! Everything above the socket is shared with the default backend. Each
+//! connection is an ordinary `Connection` over an in-process pipe, served by
+//! `Connection::serve` with the same protocol and state; [`pump`] moves the
+//! bytes between the pipe and the socket. The pipe costs a copy in each
+//! direction, which `benches/backends.rs` measures along with the rest.
+//!
+//! tokio-uring brings its own runtime ([`start`]): a current_thread Tokio
+//! runtime with a `LocalSet`, which polls the ring whenever it parks. The
+//! other listeners and endpoints run on it too, with epoll as usual.
+
+use crate::buffers::BUFFER_SIZE;
+use crate::listener::SocketOptions;
+use socket2::SockRef;
+use std::future::Future;
+use std::io;
+use std::net::Shutdown;
+use std::os::fd::{AsRawFd, BorrowedFd};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio_uring::buf::IoBuf;
+
+pub use tokio_uring::net::{TcpListener, TcpStream};
+
+/// Runs `future` to completion on tokio-uring's runtime.
+pub fn start<F: Future>(future: F) -> F::Output {
+    tokio_uring::start(future)
+}
+
+/// Sets the socket options on an accepted stream.
+pub fn apply(options: &SocketOptions, stream: &TcpStream) -> io::Result<()> {
+    // SAFETY: the descriptor belongs to `stream`, which outlives the borrow
+    let fd = unsafe { BorrowedFd::borrow_raw(stream.as_raw_fd()) };
+    options.apply_to(SockRef::from(&fd))
+}
+
+/// Writes `reply` to a connection that will not be served, then closes it.
+pub async fn refuse(stream: TcpStream, reply: &'static str) {
+    let _ = stream.write_all(reply).await;
+}
+
+/// Moves bytes between `stream` and `pipe`, the server's end of a
+/// connection, until one of them is done.
+///
+/// The client's EOF reaches the server as the pipe's. Once the server
+/// closes the pipe, the replies still in it are written and the socket is
+/// shut down. An error is a disconnect: the server sees it as EOF.
+pub async fn pump(stream: TcpStream, pipe: DuplexStream) -> io::Result<()> {
+    let (mut replies, mut requests) = tokio::io::split(pipe);
+    let inbound = async {
+        let mut buffer = vec![0; BUFFER_SIZE];
+        loop {
+            // The buffer goes to the kernel and comes back with the result
+            let (read, returned) = stream.read(buffer).await;
+            buffer = returned;
+            match read? {
+                0 => break,
+                n => requests.write_all(&buffer[..n]).await?,
+            }
+        }
+        requests.shutdown().await?;
+        // Done reading, but the server may still be replying
+        std::future::pending::<io::Result<()>>().await
+    };
+    let outbound = async {
+        let mut buffer = vec![0; BUFFER_SIZE];
+        loop {
+            let n = replies.read(&mut buffer).await?;
+            if n == 0 {
+                break;
+            }
+            let (written, returned) = stream.write_all(buffer.slice(..n)).await;
+            buffer = returned.into_inner();
+            written?;
+        }
+        stream.shutdown(Shutdown::Write)
+    };
+    tokio::select! {
+        result = inbound => result,
+        result = outbound => result,
+    }
+}
@@ -0,0 +1,216 @@
+//! Virtual hosts on the TLS listener, picked by SNI.
+//!
+//! In its `ClientHello` a TLS client names the host it wants (SNI). With
+//! virtual hosts configured, that name picks how the connection is served:
+//!
+//! ```text
+//! --tls-host "kv.example.com mode=kv rate=50"
+//! --tls-host "*.example.com banner=Welcome to the example service"
+//! ```
+//!
+//! A host is its name, exact or `*.` and a suffix for any subdomain, then
+//! any of:
+//!
+//! ```text
+//! mode=<mode>    skip the HELLO handshake and serve in this mode (ECHO, KV, JSON)
+//! rate=<n>       the connection's message rate limit, instead of --rate
+//! burst=<n>      its burst, for rate
+//! banner=<text>  a first line sent to the client; the rest of the spec
+//! ```
+//!
+//! An exact name wins over a wildcard, and a longer wildcard over a
+//! shorter one. A client that names no host, or one not listed, is served
+//! as if there were no virtual hosts. The host is recorded in the
+//! connection's registry entry, so `LIST` shows it, and `STATS` counts the
+//! connections of every host. What a host does is looked up in the config
+//! at every request, so a reload reaches connections already routed to it.
+
+use crate::handshake::Mode;
+use crate::rate_limit::Limit;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VirtualHost {
+    /// The SNI name it serves, lowercase; `*.example.com` for subdomains.
+    pub name: String,
+    pub mode: Option<Mode>,
+    pub limit: Option<Limit>,
+    pub banner: Option<String>,
+}
+
+impl VirtualHost {
+    /// Parses `<name> [mode=<mode>] [rate=<n>] [burst=<n>] [banner=<text>]`.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let spec = spec.trim();
+        let (name, mut rest) = spec.split_once(' ').unwrap_or((spec, ""));
+        let wildcard = name.strip_prefix("*.").unwrap_or(name);
+        if wildcard.is_empty() || name.contains('=') || wildcard.contains('*') {
+            return Err(format!("a virtual host must start with its name: {:?}", spec));
+        }
+        let mut host = VirtualHost {
+            name: name.to_ascii_lowercase(),
+            mode: None,
+            limit: None,
+            banner: None,
+        };
+        let (mut rate, mut burst) = (None, None);
+        while let Some((key, value)) = rest.trim_start().split_once('=') {
+            // The banner takes the rest of the spec, spaces and all
+            if key == "banner" {
+                host.banner = Some(value.to_string());
+                break;
+            }
+            let (value, after) = value.split_once(' ').unwrap_or((value, ""));
+            rest = after;
+            let number = || {
+                value
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|n| *n > 0.0)
+                    .ok_or_else(|| format!("{} of {} expects a positive number", key, name))
+            };
+            match key {
+                "mode" => {
+                    let mode = Mode::parse(value);
+                    host.mode = Some(mode.ok_or_else(|| format!("unknown mode {}", value))?);
+                }
+                "rate" => rate = Some(number()?),
+                "burst" => {
+                    let name = format!("burst of {}", name);
+                    burst = Some(Limit::check_burst(&name, number()?)?);
+                }
+                _ => return Err(format!("unknown virtual host setting {:?}", key)),
+            }
+        }
+        if !rest.trim().is_empty() && host.banner.is_none() {
+            return Err(format!("expected `key=value` settings after {}: {:?}", name, rest));
+        }
+        match (rate, burst) {
+            (Some(rate), burst) => host.limit = Some(Limit::new(rate, burst)),
+            (None, Some(_)) => return Err(format!("burst of {} needs a rate", name)),
+            (None, None) => {}
+        }
+        Ok(host)
+    }
+
+    fn matches(&self, server_name: &str) -> bool {
+        match self.name.strip_prefix('*') {
+            Some(suffix) => server_name.len() > suffix.len() && server_name.ends_with(suffix),
+            None => self.name == server_name,
+        }
+    }
+}
+
+/// The host serving `server_name`, if any: an exact name, or else the
+/// longest wildcard that matches.
+pub fn route<'a>(hosts: &'a [VirtualHost], server_name: &str) -> Option<&'a VirtualHost> {
+    let server_name = server_name.to_ascii_lowercase();
+    hosts
+        .iter()
+        .filter(|host| host.matches(&server_name))
+        .max_by_key(|host| (!host.name.starts_with('*'), host.name.len()))
+}
+
+/// The host called `name`, as the current config defines it.
+pub fn find<'a>(hosts: &'a [VirtualHost], name: &str) -> Option<&'a VirtualHost> {
+    hosts.iter().find(|host| host.name == name)
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct HostStats {
+    connections: u64,
+    active: u64,
+}
+
+/// Connections per virtual host, for `STATS`.
+#[derive(Default)]
+pub struct VhostStats {
+    hosts: Arc<Mutex<BTreeMap<String, HostStats>>>,
+}
+
+impl VhostStats {
+    /// A connection was routed to `name`; it counts as active until the
+    /// guard is dropped.
+    pub fn connected(&self, name: &str) -> VhostGuard {
+        let mut hosts = self.hosts.lock().unwrap();
+        let stats = hosts.entry(name.to_string()).or_default();
+        stats.connections += 1;
+        stats.active += 1;
+        VhostGuard { hosts: self.hosts.clone(), name: name.to_string() }
+    }
+
+    /// One `VHOST <name> connections=<n> active=<n>` line per host seen.
+    pub fn stat_lines(&self) -> String {
+        let hosts = self.hosts.lock().unwrap();
+        hosts
+            .iter()
+            .map(|(name, stats)| {
+                let HostStats { connections, active } = stats;
+                format!("VHOST {} connections={} active={}\n", name, connections, active)
+            })
+            .collect()
+    }
+}
+
+/// Keeps a connection counted as active on its host, however it ends.
+pub struct VhostGuard {
+    hosts: Arc<Mutex<BTreeMap<String, HostStats>>>,
+    name: String,
+}
+
+impl Drop for VhostGuard {
+    fn drop(&mut self) {
+        if let Some(stats) = self.hosts.lock().unwrap().get_mut(&self.name) {
+            stats.active -= 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hosts_are_parsed_with_their_settings() {
+        let spec = "KV.example.com mode=kv rate=5 banner=Hi, use KV =)";
+        let host = VirtualHost::parse(spec).unwrap();
+        assert_eq!(host.name, "kv.example.com");
+        assert_eq!(host.mode, Some(Mode::Kv));
+        assert_eq!(host.limit, Some(Limit::new(5.0, None)));
+        assert_eq!(host.banner.as_deref(), Some("Hi, use KV =)"));
+        assert_eq!(VirtualHost::parse("*.example.com").unwrap().mode, None);
+
+        assert!(VirtualHost::parse("mode=kv").is_err());
+        assert!(VirtualHost::parse("a.example.com *.b").is_err());
+        assert!(VirtualHost::parse("a.*.com").is_err());
+        assert!(VirtualHost::parse("*example.com").is_err());
+        assert!(VirtualHost::parse("a.example.com mode=chat").is_err());
+        assert!(VirtualHost::parse("a.example.com rate=0").is_err());
+        assert!(VirtualHost::parse("a.example.com burst=5").is_err());
+        assert!(VirtualHost::parse("a.example.com color=blue").is_err());
+    }
+
+    #[test]
+    fn exact_names_win_over_wildcards_and_longer_wildcards_over_shorter() {
+        let hosts: Vec<_> = ["*.example.com", "api.example.com", "*.eu.example.com"]
+            .into_iter()
+            .map(|spec| VirtualHost::parse(spec).unwrap())
+            .collect();
+        let routed = |name| route(&hosts, name).map(|host| host.name.as_str());
+        assert_eq!(routed("API.example.com"), Some("api.example.com"));
+        assert_eq!(routed("www.example.com"), Some("*.example.com"));
+        assert_eq!(routed("api.eu.example.com"), Some("*.eu.example.com"));
+        assert_eq!(routed("example.com"), None);
+        assert_eq!(routed("example.org"), None);
+    }
+
+    #[test]
+    fn active_connections_are_counted_until_the_guard_drops() {
+        let stats = VhostStats::default();
+        let first = stats.connected("api.example.com");
+        let _second = stats.connected("api.example.com");
+        drop(first);
+        assert_eq!(stats.stat_lines(), "VHOST api.example.com connections=2 active=1\n");
+    }
+}
@@ -0,0 +1,376 @@
+//! A write-ahead log for the key-value store.
+//!
+//! Snapshots alone lose every change made since the last one when the
+//! server crashes. With a [`Durability`] other than `none`, every command
+//! that changes the store is also appended to `<state file>.wal`, one
+//! record per line:
+//!
+//! ```text
+//! 41    SET color blue
+//! 42    DEL size
+//! 43    SET a 1    SET b 2
+//! ```
+//!
+//! A record holds the sequence number of the change, then what it did to
+//! the store, separated by tabs (spaces above). A transaction is a single
+//! record, so it is replayed whole or not at all. `GETSET` and a `CAS` that
+//! matched are logged as the `SET` they amount to, and a key that expires
+//! as a `DEL`. TTLs are not logged: like the ones in snapshots, replayed
+//! keys have none.
+//!
+//! The records go over a channel to a writer task, which appends them
+//! through a buffer and flushes it whenever the channel runs empty. When
+//! it calls `fsync` depends on the durability:
+//!
+//! - `async`: once a second. A crash of the process loses nothing that was
+//!   flushed, a crash of the machine up to the last second of changes.
+//! - `sync`: after every batch, and a client is answered only once its
+//!   change is on disk. Changes arriving together share one `fsync`.
+//!
+//! On startup the snapshot is loaded first, then the records after it
+//! replayed. Each snapshot remembers the last sequence number it includes;
+//! once it is saved, the writer compacts the log in the background by
+//! rewriting it without the records the snapshot covers.
+
+use crate::metrics::{self, Metrics};
+use crate::task;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::{mpsc, watch};
+
+/// How often `async` durability calls `fsync`.
+const FSYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The most messages the writer takes from its channel per batch.
+const BATCH: usize = 256;
+
+/// How soon a change to the store is on disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Durability {
+    /// No log: only the snapshots are saved.
+    #[default]
+    None,
+    /// Logged at once, on disk within a second; clients do not wait.
+    Async,
+    /// Logged and on disk before the client is answered.
+    Sync,
+}
+
+impl Durability {
+    /// `none`, `async` or `sync`, as the config writes them.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "none" => Ok(Self::None),
+            "async" => Ok(Self::Async),
+            "sync" => Ok(Self::Sync),
+            _ => Err("durability must be `none`, `async` or `sync`".to_string()),
+        }
+    }
+}
+
+/// The log kept next to a state file.
+pub fn path(state_file: &Path) -> PathBuf {
+    let mut name = state_file.as_os_str().to_owned();
+    name.push(".wal");
+    PathBuf::from(name)
+}
+
+/// What a command did to the store, as far as replaying it is concerned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Set(String, String),
+    Del(String),
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Set(key, value) => write!(f, "SET {} {}", key, value),
+            Self::Del(key) => write!(f, "DEL {}", key),
+        }
+    }
+}
+
+impl Change {
+    fn parse(text: &str) -> Option<Self> {
+        let words: Vec<&str> = text.split(' ').collect();
+        match words[..] {
+            ["SET", key, value] => Some(Self::Set(key.to_string(), value.to_string())),
+            ["DEL", key] => Some(Self::Del(key.to_string())),
+            _ => None,
+        }
+    }
+}
+
+/// The changes of one command, or of one transaction, numbered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub seq: u64,
+    pub changes: Vec<Change>,
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.seq)?;
+        for change in &self.changes {
+            write!(f, "\t{}", change)?;
+        }
+        Ok(())
+    }
+}
+
+impl Record {
+    /// Reads a record back from its line, as written to the log.
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.split('\t');
+        let seq = fields.next()?.parse().ok()?;
+        let changes = fields.map(Change::parse).collect::<Option<Vec<_>>>()?;
+        if changes.is_empty() {
+            return None;
+        }
+        Some(Self { seq, changes })
+    }
+}
+
+enum Message {
+    Append(Record),
+    /// A snapshot including every record up to this one has been saved.
+    Compact(u64),
+}
+
+/// The sending side of the log; cheap to clone.
+#[derive(Clone)]
+pub struct Wal {
+    tx: mpsc::UnboundedSender<Message>,
+    // The last record known to be on disk
+    synced: watch::Receiver<u64>,
+    durability: Durability,
+}
+
+impl Wal {
+    /// Opens the log at `path` for appending, and spawns the task writing
+    /// to it. `seq` is the last record already in it.
+    pub async fn open(
+        path: PathBuf,
+        durability: Durability,
+        seq: u64,
+        metrics: Metrics,
+    ) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path).await?;
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (synced_tx, synced) = watch::channel(seq);
+        let writer = Writer { path, durability, synced_tx, metrics };
+        task::spawn("wal writer", writer.run(file, rx));
+        Ok(Self { tx, synced, durability })
+    }
+
+    /// Queues a record. Called under the store's lock, so the records
+    /// reach the channel in the order of their numbers.
+    pub fn append(&self, record: Record) {
+        // Only fails once the writer has given up, which it already reported
+        let _ = self.tx.send(Message::Append(record));
+    }
+
+    /// Drops the records up to `seq` from the log, in the background.
+    pub fn compact(&self, seq: u64) {
+        let _ = self.tx.send(Message::Compact(seq));
+    }
+
+    /// With `sync` durability, waits until record `seq` is on disk; with
+    /// `async`, returns at once.
+    pub async fn synced(&self, seq: u64) -> Result<(), String> {
+        if self.durability != Durability::Sync {
+            return Ok(());
+        }
+        let mut synced = self.synced.clone();
+        match synced.wait_for(|synced| *synced >= seq).await {
+            Ok(_) => Ok(()),
+            Err(_) => Err("the write-ahead log failed, the change may be lost".to_string()),
+        }
+    }
+}
+
+/// Reads the records in the log at `path` after `covered`, the last one
+/// the snapshot includes. A missing log has none.
+///
+/// A crash can leave the last record half-written. It was never
+/// acknowledged, so it is cut off rather than refused.
+pub async fn replay(path: &Path, covered: u64) -> io::Result<Vec<Record>> {
+    let bytes = match tokio::fs::read(path).await {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let complete = bytes.iter().rposition(|b| *b == b'\n').map_or(0, |end| end + 1);
+    if complete < bytes.len() {
+        let file = OpenOptions::new().write(true).open(path).await?;
+        file.set_len(complete as u64).await?;
+        file.sync_all().await?;
+    }
+
+    let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+    let text = std::str::from_utf8(&bytes[..complete]).map_err(|e| invalid(e.to_string()))?;
+    let mut records = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let record = Record::parse(line)
+            .ok_or_else(|| invalid(format!("line {}: not a log record", index + 1)))?;
+        if record.seq > covered {
+            records.push(record);
+        }
+    }
+    Ok(records)
+}
+
+struct Writer {
+    path: PathBuf,
+    durability: Durability,
+    synced_tx: watch::Sender<u64>,
+    metrics: Metrics,
+}
+
+impl Writer {
+    /// Writes records until the store is dropped or a write fails. On a
+    /// failure the changes go on in memory, unlogged: `sync` clients are
+    /// told, since their waits end with an error.
+    async fn run(self, file: File, rx: mpsc::UnboundedReceiver<Message>) {
+        if let Err(e) = self.write(file, rx).await {
+            let path = self.path.display();
+            eprintln!("[WAL] cannot write {}, changes are no longer logged: {}", path, e);
+        }
+    }
+
+    async fn write(&self, file: File, mut rx: mpsc::UnboundedReceiver<Message>) -> io::Result<()> {
+        let mut file = BufWriter::new(file);
+        let mut batch = Vec::with_capacity(BATCH);
+        let mut written = *self.synced_tx.borrow();
+        let mut fsyncs = tokio::time::interval(FSYNC_INTERVAL);
+        fsyncs.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                received = rx.recv_many(&mut batch, BATCH) => {
+                    if received == 0 {
+                        return self.sync(&mut file, written).await;
+                    }
+                }
+                _ = fsyncs.tick(), if written > *self.synced_tx.borrow() => {
+                    self.sync(&mut file, written).await?;
+                    continue;
+                }
+            }
+
+            for message in batch.drain(..) {
+                match message {
+                    Message::Append(record) => {
+                        file.write_all(format!("{}\n", record).as_bytes()).await?;
+                        written = record.seq;
+                        self.metrics.incr(metrics::WAL_RECORDS, 1);
+                    }
+                    Message::Compact(covered) => {
+                        file.flush().await?;
+                        file = BufWriter::new(compact(&self.path, covered).await?);
+                        self.metrics.incr(metrics::WAL_COMPACTIONS, 1);
+                    }
+                }
+            }
+            file.flush().await?;
+            if self.durability == Durability::Sync {
+                self.sync(&mut file, written).await?;
+            }
+        }
+    }
+
+    async fn sync(&self, file: &mut BufWriter<File>, written: u64) -> io::Result<()> {
+        file.flush().await?;
+        file.get_ref().sync_data().await?;
+        self.metrics.incr(metrics::WAL_FSYNCS, 1);
+        self.synced_tx.send_replace(written);
+        Ok(())
+    }
+}
+
+/// Rewrites the log at `path` without the records up to `covered`, the
+/// way a snapshot is written: temp file, `sync_all`, rename. Returns the
+/// new log, open for appending.
+async fn compact(path: &Path, covered: u64) -> io::Result<File> {
+    let text = tokio::fs::read_to_string(path).await?;
+    let mut kept = String::new();
+    for line in text.lines() {
+        let seq = line.split('\t').next().and_then(|seq| seq.parse::<u64>().ok());
+        if seq.is_none_or(|seq| seq > covered) {
+            kept.push_str(line);
+            kept.push('\n');
+        }
+    }
+
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut file = File::create(&tmp).await?;
+    file.write_all(kept.as_bytes()).await?;
+    file.sync_all().await?;
+    drop(file);
+    tokio::fs::rename(&tmp, path).await?;
+
+    OpenOptions::new().append(true).open(path).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::InMemorySink;
+    use std::sync::Arc;
+
+    fn record(seq: u64, changes: &[Change]) -> Record {
+        Record { seq, changes: changes.to_vec() }
+    }
+
+    fn set(key: &str, value: &str) -> Change {
+        Change::Set(key.into(), value.into())
+    }
+
+    #[test]
+    fn records_round_trip_through_their_lines() {
+        let transaction = record(43, &[set("a", "1"), Change::Del("b".into())]);
+        assert_eq!(transaction.to_string(), "43\tSET a 1\tDEL b");
+        assert_eq!(Record::parse(&transaction.to_string()), Some(transaction));
+        assert_eq!(Record::parse("7"), None);
+        assert_eq!(Record::parse("7\tINCR a"), None);
+        assert_eq!(Durability::parse("sync"), Ok(Durability::Sync));
+        assert!(Durability::parse("always").is_err());
+    }
+
+    #[tokio::test]
+    async fn records_are_replayed_after_the_snapshot_and_compacted_away() {
+        let dir = std::env::temp_dir().join(format!("wal-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let path = path(&dir.join("state.toml"));
+        let _ = tokio::fs::remove_file(&path).await;
+
+        let metrics = Arc::new(InMemorySink::default());
+        let wal = Wal::open(path.clone(), Durability::Sync, 0, metrics).await.unwrap();
+        wal.append(record(1, &[set("a", "1")]));
+        wal.append(record(2, &[set("b", "2"), Change::Del("a".into())]));
+        wal.append(record(3, &[set("c", "3")]));
+        wal.synced(3).await.unwrap();
+        assert_eq!(replay(&path, 1).await.unwrap().len(), 2);
+
+        // A record cut off by a crash is dropped, and the log repaired
+        let mut file = OpenOptions::new().append(true).open(&path).await.unwrap();
+        file.write_all(b"4\tSET d").await.unwrap();
+        drop(file);
+        let replayed = replay(&path, 0).await.unwrap();
+        assert_eq!(replayed.iter().map(|r| r.seq).collect::<Vec<_>>(), [1, 2, 3]);
+
+        // Once a snapshot covers the first two, only the third is left
+        wal.compact(2);
+        wal.append(record(4, &[set("d", "4")]));
+        wal.synced(4).await.unwrap();
+        let text = tokio::fs::read_to_string(&path).await.unwrap();
+        assert_eq!(text, "3\tSET c 3\n4\tSET d 4\n");
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
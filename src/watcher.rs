@@ -0,0 +1,183 @@
+//! Watching a directory: `notify` events as an async stream.
+//!
+//! `notify` reports filesystem changes from a thread of its own, through a
+//! callback. The callback is the bridge into async code: it sends each
+//! event into a bounded `mpsc` channel with `blocking_send` (fine on a
+//! thread outside the runtime; it waits while the channel is full), and
+//! the receiving end, wrapped in a `ReceiverStream`, is an ordinary
+//! `Stream` for a task to consume. That task logs every created,
+//! modified, renamed or removed file and offers it to `WATCH` subscribers.
+
+use crate::error::ServerError;
+use crate::fanout::{Fanout, Subscription};
+use crate::logger::{LogMessage, LogTx};
+use crate::metrics::{self, Metrics};
+use crate::readiness::{self, Phase};
+use crate::task;
+use bytes::Bytes;
+use notify::event::{ModifyKind, RenameMode};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher as _};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use tokio::sync::{mpsc, watch};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{Stream, StreamExt};
+
+/// Events `notify` may queue before its thread waits for the reporter.
+const EVENT_BUFFER: usize = 256;
+
+/// Handle to the watcher task; cheap to clone.
+#[derive(Clone)]
+pub struct Watcher {
+    dir: PathBuf,
+    changes: Fanout,
+}
+
+impl Watcher {
+    /// Starts watching `dir` and everything below it.
+    ///
+    /// Fails if the directory cannot be watched, e.g. because it does not
+    /// exist. The watch ends when the server drains.
+    pub fn start(
+        dir: &Path,
+        log_tx: LogTx,
+        metrics: Metrics,
+        phase: watch::Receiver<Phase>,
+    ) -> Result<Watcher, ServerError> {
+        let watch_error = |source| ServerError::Watch { path: dir.to_path_buf(), source };
+        let (tx, rx) = mpsc::channel(EVENT_BUFFER);
+        let mut notifier = notify::recommended_watcher(move |event| {
+            // Runs on the notify thread; fails only once the reporter is gone
+            let _ = tx.blocking_send(event);
+        })
+        .map_err(watch_error)?;
+        notifier.watch(dir, RecursiveMode::Recursive).map_err(watch_error)?;
+
+        let changes = Fanout::new(
+            metrics.clone(),
+            metrics::WATCH_SUBSCRIBERS,
+            metrics::WATCH_LINES_MISSED,
+        );
+        let watcher = Watcher { dir: dir.to_path_buf(), changes };
+        let events = ReceiverStream::new(rx);
+        let reporter = report(notifier, events, watcher.clone(), log_tx, metrics, phase);
+        task::spawn("fs watcher", reporter);
+        println!("Watching {} for changes", dir.display());
+        Ok(watcher)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Changes from now on, as `WATCH <change> <path>` replies.
+    pub fn subscribe(&self) -> Subscription {
+        self.changes.subscribe()
+    }
+}
+
+/// What happened to a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    Created,
+    Modified,
+    Renamed,
+    Removed,
+}
+
+impl Change {
+    /// The change an event reports; `None` for reads and metadata updates.
+    pub fn of(kind: &EventKind) -> Option<Change> {
+        match kind {
+            EventKind::Create(_) => Some(Change::Created),
+            // Also reported as two events, one for each name
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => None,
+            EventKind::Modify(ModifyKind::Name(_)) => Some(Change::Renamed),
+            EventKind::Modify(ModifyKind::Metadata(_)) => None,
+            EventKind::Modify(_) => Some(Change::Modified),
+            EventKind::Remove(_) => Some(Change::Removed),
+            EventKind::Access(_) | EventKind::Any | EventKind::Other => None,
+        }
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Change::Created => "created",
+            Change::Modified => "modified",
+            Change::Renamed => "renamed",
+            Change::Removed => "removed",
+        })
+    }
+}
+
+/// Logs and publishes the events until the server drains.
+async fn report(
+    // Only held: dropping it stops the notify thread
+    _notifier: RecommendedWatcher,
+    events: impl Stream<Item = notify::Result<Event>>,
+    watcher: Watcher,
+    log_tx: LogTx,
+    metrics: Metrics,
+    mut phase: watch::Receiver<Phase>,
+) {
+    tokio::pin!(events);
+    loop {
+        let event = tokio::select! {
+            event = events.next() => match event {
+                Some(event) => event,
+                None => return,
+            },
+            _ = readiness::wait_draining(&mut phase) => {
+                watcher.changes.close();
+                return;
+            }
+        };
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => {
+                let text = format!("watching {} failed: {}", watcher.dir.display(), e);
+                log_tx.send(LogMessage::error(text)).await;
+                continue;
+            }
+        };
+        let Some(change) = Change::of(&event.kind) else { continue };
+        for path in &event.paths {
+            metrics.incr(metrics::FS_EVENTS, 1);
+            log_tx.send(LogMessage::info(format!("watch: {} {}", change, path.display()))).await;
+            // Subscribers see paths relative to the watched directory
+            let relative = path.strip_prefix(&watcher.dir).unwrap_or(path);
+            let line = format!("WATCH {} {}", change, relative.display());
+            watcher.changes.publish(Bytes::from(line));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logger::{self, LogOverflow};
+    use crate::metrics::InMemorySink;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn a_new_file_is_reported_to_subscribers_and_the_log() {
+        let dir = std::env::temp_dir().join(format!("watch-test-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let metrics: Metrics = Arc::new(InMemorySink::default());
+        let (log_tx, mut log) = logger::channel(16, &LogOverflow::Wait, metrics.clone());
+        let (_phase_tx, phase) = watch::channel(Phase::Ready);
+
+        let watcher = Watcher::start(&dir, log_tx, metrics, phase).unwrap();
+        let mut changes = watcher.subscribe();
+        tokio::fs::write(dir.join("new.txt"), b"hello").await.unwrap();
+
+        let first = tokio::time::timeout(Duration::from_secs(5), changes.recv()).await;
+        assert_eq!(first.unwrap().unwrap(), "WATCH created new.txt");
+        let logged = log.next().await.unwrap();
+        assert_eq!(logged.text, format!("watch: created {}", dir.join("new.txt").display()));
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -0,0 +1,248 @@
+//! Hex dumps of every byte a connection moves: `--wire-debug <dir>`.
+//!
+//! For chasing framing and protocol bugs with a misbehaving client, every
+//! connection writes what it reads and what it writes, as it happens, to
+//! `<dir>/conn-<id>.hex`:
+//!
+//! ```text
+//! # connection 7 127.0.0.1:51234 1718000000000000
+//! 0.000412 read 8
+//!   0000  53 45 54 20 61 20 31 0a                           |SET a 1.|
+//! 0.000530 write 3
+//!   0000  4f 4b 0a                                          |OK.|
+//! ```
+//!
+//! The header has the connection id, the peer and the time of the accept in
+//! microseconds since the epoch; each entry the seconds since the accept,
+//! the direction and the byte count, then the bytes sixteen to a line.
+//!
+//! The bytes are taken where `CountingStream` counts them: above TLS, the
+//! bandwidth limit and `--chaos`, so what the codecs see. Unlike a
+//! `--record`ing, a dump is not meant to be read back, only by people, so
+//! each file stops at `--wire-debug-max-bytes`, with a last line saying so;
+//! a connection that streams for hours does not fill the disk.
+
+use crate::task;
+use bytes::{Bytes, BytesMut};
+use std::fmt::Write as _;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs::File;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+/// Bytes shown on one line of a dump.
+const LINE: usize = 16;
+
+#[derive(Debug, Clone, Copy)]
+enum Direction {
+    Read,
+    Write,
+}
+
+struct Entry {
+    /// Time since the connection was accepted.
+    at: Duration,
+    direction: Direction,
+    data: Bytes,
+}
+
+/// Hands the bytes one connection moves to the task writing its dump.
+///
+/// Like `crate::record::Recorder`, through an unbounded channel, since
+/// `poll_read` and `poll_write` cannot wait for a file write.
+pub struct WireDump {
+    accepted: Instant,
+    entries: mpsc::UnboundedSender<Entry>,
+}
+
+impl WireDump {
+    /// Creates `conn-<id>.hex` in `dir`, to be at most `max_bytes` long, or
+    /// `None` when that fails; the connection is served either way.
+    pub async fn create(
+        dir: &Path,
+        conn_id: u64,
+        peer: SocketAddr,
+        max_bytes: u64,
+    ) -> Option<WireDump> {
+        let path = dir.join(format!("conn-{}.hex", conn_id));
+        let file = match File::create(&path).await {
+            Ok(file) => file,
+            Err(e) => {
+                eprintln!("cannot create wire dump {}: {}", path.display(), e);
+                return None;
+            }
+        };
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_micros() as u64);
+        let header = format!("# connection {} {} {}\n", conn_id, peer, started);
+        let (entries, received) = mpsc::unbounded_channel();
+        task::spawn("wire dump", async move {
+            if let Err(e) = write_dump(file, header, max_bytes, received).await {
+                eprintln!("wire dump {} stopped: {}", path.display(), e);
+            }
+        });
+        Some(WireDump { accepted: Instant::now(), entries })
+    }
+
+    /// Dumps bytes read from the client.
+    pub fn read(&self, data: &[u8]) {
+        self.dump(Direction::Read, || Bytes::copy_from_slice(data));
+    }
+
+    /// Dumps bytes written to the client.
+    pub fn written(&self, data: &[u8]) {
+        self.dump(Direction::Write, || Bytes::copy_from_slice(data));
+    }
+
+    /// Dumps the first `n` bytes of a vectored write, the ones it wrote.
+    pub fn written_vectored(&self, bufs: &[io::IoSlice<'_>], mut n: usize) {
+        self.dump(Direction::Write, || {
+            let mut data = BytesMut::with_capacity(n);
+            for buf in bufs {
+                let take = buf.len().min(n);
+                data.extend_from_slice(&buf[..take]);
+                n -= take;
+            }
+            data.freeze()
+        });
+    }
+
+    fn dump(&self, direction: Direction, data: impl FnOnce() -> Bytes) {
+        // Closed once the file is full: no need to copy the bytes any more
+        if self.entries.is_closed() {
+            return;
+        }
+        let entry = Entry { at: self.accepted.elapsed(), direction, data: data() };
+        if !entry.data.is_empty() {
+            let _ = self.entries.send(entry);
+        }
+    }
+}
+
+/// Writes entries until the connection drops its `WireDump`, or the file
+/// would grow past `max_bytes`.
+async fn write_dump(
+    file: File,
+    header: String,
+    max_bytes: u64,
+    mut entries: mpsc::UnboundedReceiver<Entry>,
+) -> io::Result<()> {
+    let mut out = BufWriter::new(file);
+    out.write_all(header.as_bytes()).await?;
+    let mut size = header.len() as u64;
+    while let Some(entry) = entries.recv().await {
+        let text = format_entry(&entry);
+        size += text.len() as u64;
+        if size > max_bytes {
+            let note = format!("# the dump stops here, at its cap of {} bytes\n", max_bytes);
+            out.write_all(note.as_bytes()).await?;
+            break;
+        }
+        out.write_all(text.as_bytes()).await?;
+        // Flushed whenever the connection pauses, so a live dump can be followed
+        if entries.is_empty() {
+            out.flush().await?;
+        }
+    }
+    out.flush().await
+}
+
+fn format_entry(entry: &Entry) -> String {
+    let direction = match entry.direction {
+        Direction::Read => "read",
+        Direction::Write => "write",
+    };
+    let mut text = format!(
+        "{}.{:06} {} {}\n",
+        entry.at.as_secs(),
+        entry.at.subsec_micros(),
+        direction,
+        entry.data.len()
+    );
+    hex_lines(&mut text, &entry.data);
+    text
+}
+
+/// Appends `data` to `text` the way `hexdump -C` shows it: the offset,
+/// the bytes in hex, then the printable ones as they are.
+fn hex_lines(text: &mut String, data: &[u8]) {
+    for (index, line) in data.chunks(LINE).enumerate() {
+        let _ = write!(text, "  {:04x} ", index * LINE);
+        for byte in line {
+            let _ = write!(text, " {:02x}", byte);
+        }
+        let padding = 3 * (LINE - line.len());
+        let printable: String = line
+            .iter()
+            .map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' })
+            .collect();
+        let _ = writeln!(text, "{:padding$}  |{}|", "", printable, padding = padding);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::counting::CountingStream;
+    use crate::metrics::InMemorySink;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn bytes_are_shown_in_hex_and_as_text() {
+        let mut text = String::new();
+        hex_lines(&mut text, b"HELLO text\r\n\x00\xffwith more than a line");
+        assert_eq!(
+            text,
+            "  0000  48 45 4c 4c 4f 20 74 65 78 74 0d 0a 00 ff 77 69  |HELLO text....wi|\n\
+             \x20 0010  74 68 20 6d 6f 72 65 20 74 68 61 6e 20 61 20 6c  |th more than a l|\n\
+             \x20 0020  69 6e 65                                         |ine|\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn both_directions_are_dumped_up_to_the_cap() {
+        let dir = std::env::temp_dir().join(format!("wiredump-test-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let peer: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+
+        let (mut client, server) = tokio::io::duplex(1024);
+        let dump = WireDump::create(&dir, 7, peer, 250).await;
+        let metrics = Arc::new(InMemorySink::default());
+        let mut server = CountingStream::new(server, metrics).with_dump(dump);
+        let mut buf = [0u8; 64];
+        client.write_all(b"COUNT\n").await.unwrap();
+        assert_eq!(server.read(&mut buf).await.unwrap(), 6);
+        server.write_all(b"OK: 1\n").await.unwrap();
+        // Past the cap: neither this entry nor any later one is written
+        server.write_all(&[b'x'; 64]).await.unwrap();
+        server.write_all(b"OK\n").await.unwrap();
+        drop(server);
+
+        // The file is complete once the writer task has seen the channel close
+        let path = dir.join("conn-7.hex");
+        let dump = loop {
+            if let Ok(dump) = tokio::fs::read_to_string(&path).await
+                && dump.ends_with("bytes\n")
+            {
+                break dump;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+
+        let lines: Vec<&str> = dump.lines().collect();
+        assert!(lines[0].starts_with("# connection 7 127.0.0.1:4000 "), "{}", lines[0]);
+        assert!(lines[1].ends_with(" read 6"), "{}", lines[1]);
+        assert_eq!(lines[2], format!("  0000  43 4f 55 4e 54 0a{:30}  |COUNT.|", ""));
+        assert!(lines[3].ends_with(" write 6"), "{}", lines[3]);
+        assert_eq!(lines[4], format!("  0000  4f 4b 3a 20 31 0a{:30}  |OK: 1.|", ""));
+        assert_eq!(lines[5], "# the dump stops here, at its cap of 250 bytes");
+        assert_eq!(lines.len(), 6);
+    }
+}
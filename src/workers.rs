@@ -0,0 +1,116 @@
+//! A fixed pool of blocking threads for CPU-heavy commands.
+//!
+//! Async tasks must not run long computations: a task that does not reach
+//! an `.await` keeps its runtime thread, and every other task scheduled on
+//! that thread waits. So `HASH <data>` is not computed by the connection
+//! task. It is sent to the pool instead:
+//!
+//! ```text
+//! connection task --(bounded mpsc)--> worker thread
+//!        ^                                 |
+//!        +----------(oneshot)--------------+
+//! ```
+//!
+//! The workers are started with `spawn_blocking` and take jobs from one
+//! shared queue. The queue is bounded: when every worker is busy and the
+//! queue is full, the command fails right away with `ERR: busy` instead of
+//! piling up work nobody may wait for any more.
+
+use crate::metrics::{self, Metrics};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, oneshot};
+
+/// How many times the digest is fed back into itself; makes `HASH` slow
+/// enough (milliseconds) to matter.
+pub const HASH_ROUNDS: u32 = 2_000_000;
+
+struct Job {
+    data: String,
+    reply: oneshot::Sender<u64>,
+}
+
+/// Why a job did not produce a result.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PoolError {
+    /// The queue is full.
+    Busy,
+    /// The pool has shut down or the worker failed.
+    Gone,
+}
+
+/// Handle to the pool; cheap to clone. The workers exit once every handle
+/// is dropped.
+#[derive(Clone)]
+pub struct WorkerPool {
+    jobs: mpsc::Sender<Job>,
+    metrics: Metrics,
+}
+
+impl WorkerPool {
+    pub fn start(workers: usize, queue: usize, metrics: Metrics) -> Self {
+        let (jobs, rx) = mpsc::channel(queue);
+        // Workers take turns on the receiver; the lock is only held while
+        // waiting for the next job, never while computing
+        let rx = Arc::new(Mutex::new(rx));
+
+        for _ in 0..workers {
+            let rx = rx.clone();
+            let metrics = metrics.clone();
+            tokio::task::spawn_blocking(move || worker(rx, metrics));
+        }
+        Self { jobs, metrics }
+    }
+
+    /// Hashes `data` on a worker thread.
+    pub async fn hash(&self, data: String) -> Result<u64, PoolError> {
+        let (reply, result) = oneshot::channel();
+        self.jobs.try_send(Job { data, reply }).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => {
+                self.metrics.incr(metrics::WORKER_JOBS_REJECTED, 1);
+                PoolError::Busy
+            }
+            mpsc::error::TrySendError::Closed(_) => PoolError::Gone,
+        })?;
+        self.metrics.adjust(metrics::WORKER_QUEUE_DEPTH, 1);
+
+        // Only this await suspends the connection task; the runtime thread
+        // is free for other tasks while the worker computes
+        result.await.map_err(|_| PoolError::Gone)
+    }
+}
+
+/// The body of one worker thread.
+fn worker(rx: Arc<Mutex<mpsc::Receiver<Job>>>, metrics: Metrics) {
+    loop {
+        // `blocking_recv` is fine here: this is a blocking thread, not a runtime one
+        let job = rx.lock().unwrap().blocking_recv();
+        let Some(job) = job else {
+            return;
+        };
+        metrics.adjust(metrics::WORKER_QUEUE_DEPTH, -1);
+
+        // The client may have disconnected while the job was queued
+        if job.reply.is_closed() {
+            continue;
+        }
+        let _ = job.reply.send(hash(job.data.as_bytes()));
+        metrics.incr(metrics::WORKER_JOBS_DONE, 1);
+    }
+}
+
+/// Iterated 64-bit FNV-1a: hash the input, then hash the digest
+/// `HASH_ROUNDS` times. Not a cryptographic hash, just deliberate work.
+pub fn hash(data: &[u8]) -> u64 {
+    const OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    let fnv = |bytes: &[u8]| {
+        bytes.iter().fold(OFFSET, |h, b| (h ^ *b as u64).wrapping_mul(PRIME))
+    };
+
+    let mut digest = fnv(data);
+    for _ in 0..HASH_ROUNDS {
+        digest = fnv(&digest.to_le_bytes());
+    }
+    digest
+}
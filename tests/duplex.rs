@@ -0,0 +1,1172 @@
+//! End-to-end tests of the text protocol, served over in-memory pipes.
+//!
+//! `Server::duplex` gives each test a connection without going through
+//! TCP, so the tests do not depend on free ports or socket timing.
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, DuplexStream, Lines};
+use tokio::io::{ReadHalf, WriteHalf};
+use tokio::time::Instant;
+use tokio_examples::auth::{AuthToken, Permission};
+use tokio_examples::backpressure::Backpressure;
+use tokio_examples::framing::Framing;
+use tokio_examples::middleware::Layer;
+use tokio_examples::protocol::{Protocol, Response, Utf8Mode};
+use tokio_examples::rate_limit::{Limit, OnExceed};
+use tokio_examples::throttle::ThrottledStream;
+use tokio_examples::{CloseReason, Server, ServerConfig};
+
+struct Client {
+    lines: Lines<BufReader<ReadHalf<DuplexStream>>>,
+    writer: WriteHalf<DuplexStream>,
+}
+
+impl Client {
+    fn new(stream: DuplexStream) -> Self {
+        let (reader, writer) = tokio::io::split(stream);
+        Self { lines: BufReader::new(reader).lines(), writer }
+    }
+
+    /// Sends one message and returns the first line of the reply.
+    async fn request(&mut self, message: &str) -> String {
+        self.writer.write_all(format!("{}\n", message).as_bytes()).await.unwrap();
+        self.lines.next_line().await.unwrap().expect("server closed the connection")
+    }
+}
+
+async fn server() -> Server {
+    server_with(ServerConfig::default()).await
+}
+
+async fn server_with(config: ServerConfig) -> Server {
+    // Port 0: the TCP listener is bound but unused, so tests never collide
+    let config = ServerConfig {
+        listen: SocketAddr::from(([127, 0, 0, 1], 0)),
+        ..config
+    };
+    Server::bind(config).await.unwrap()
+}
+
+#[tokio::test]
+async fn plain_messages_are_answered_and_counted() {
+    let server = server().await;
+    let (client, conn) = server.duplex();
+    tokio::spawn(conn.serve());
+
+    let mut client = Client::new(client);
+    assert_eq!(client.request("hello").await, "OK: 'hello' (request #1)");
+    assert_eq!(client.request("again").await, "OK: 'again' (request #2)");
+}
+
+#[tokio::test]
+async fn connections_share_the_key_value_store() {
+    let server = server().await;
+    let (first, conn) = server.duplex();
+    tokio::spawn(conn.serve());
+    let (second, conn) = server.duplex();
+    tokio::spawn(conn.serve());
+
+    let mut first = Client::new(first);
+    let mut second = Client::new(second);
+    assert_eq!(first.request("SET color blue").await, "OK");
+    assert_eq!(second.request("GET color").await, "VALUE blue");
+    assert_eq!(second.request("CAS color red green").await, "MISMATCH VALUE blue");
+}
+
+#[tokio::test]
+async fn queued_commands_run_together_on_exec() {
+    let server = server().await;
+    let (first, conn) = server.duplex();
+    tokio::spawn(conn.serve());
+    let (second, conn) = server.duplex();
+    tokio::spawn(conn.serve());
+    let (mut first, mut second) = (Client::new(first), Client::new(second));
+
+    assert_eq!(first.request("MULTI").await, "OK");
+    assert_eq!(first.request("SET from alice").await, "QUEUED");
+    assert_eq!(first.request("GETSET to bob").await, "QUEUED");
+    // Nothing has run yet
+    assert_eq!(second.request("GET from").await, "NIL");
+    assert_eq!(first.request("EXEC").await, "OK");
+    assert_eq!(first.lines.next_line().await.unwrap().unwrap(), "NIL");
+    assert_eq!(first.lines.next_line().await.unwrap().unwrap(), "END");
+    assert_eq!(second.request("GET to").await, "VALUE bob");
+
+    // One refused command and EXEC runs none of them
+    assert_eq!(first.request("MULTI").await, "OK");
+    assert_eq!(first.request("DEL from").await, "QUEUED");
+    assert_eq!(first.request("STATS").await, "ERR: only key-value commands can be queued");
+    let aborted = first.request("EXEC").await;
+    assert_eq!(aborted, "ERR: transaction aborted, a queued command was refused");
+    assert_eq!(second.request("GET from").await, "VALUE alice");
+
+    assert_eq!(first.request("MULTI").await, "OK");
+    assert_eq!(first.request("DEL from").await, "QUEUED");
+    assert_eq!(first.request("DISCARD").await, "OK: discarded 1 command(s)");
+    assert_eq!(first.request("EXEC").await, "ERR: EXEC without MULTI");
+}
+
+#[tokio::test]
+async fn dropping_the_client_ends_the_connection() {
+    let server = server().await;
+    let (client, conn) = server.duplex();
+    let handler = tokio::spawn(conn.serve());
+
+    drop(client);
+    assert_eq!(handler.await.unwrap(), CloseReason::ClientEof);
+}
+
+#[tokio::test]
+async fn binary_input_is_a_protocol_error() {
+    let server = server().await;
+    let (client, conn) = server.duplex();
+    let handler = tokio::spawn(conn.serve());
+
+    let mut client = Client::new(client);
+    assert_eq!(client.request("\u{1}\u{2}").await, "ERR: binary data is not supported");
+    drop(client);
+    assert_eq!(handler.await.unwrap(), CloseReason::ProtocolError);
+}
+
+#[tokio::test]
+async fn a_server_side_close_is_a_half_close() {
+    let server = server().await;
+    let (client, conn) = server.duplex();
+    let handler = tokio::spawn(conn.serve());
+
+    let mut client = Client::new(client);
+    assert_eq!(client.request("\u{1}").await, "ERR: binary data is not supported");
+    // The server shut down its write half: EOF right after the error line
+    assert_eq!(client.lines.next_line().await.unwrap(), None);
+
+    // ...but still reads, so a client can finish sending before it closes
+    client.writer.write_all(b"late data\n").await.unwrap();
+    client.writer.shutdown().await.unwrap();
+    assert_eq!(handler.await.unwrap(), CloseReason::ProtocolError);
+}
+
+/// A server for tests on paused time, where the clock jumps ahead whenever
+/// every task waits on a timer: timeouts pass at once, and exactly.
+async fn paused_server(config: ServerConfig) -> Server {
+    // `HASH` workers are blocking threads, which would keep the clock still
+    server_with(ServerConfig { hash_workers: 0, ..config }).await
+}
+
+#[tokio::test(start_paused = true)]
+async fn an_idle_connection_is_closed_when_its_timeout_runs_out() {
+    let idle_timeout = Some(Duration::from_secs(30));
+    let server = paused_server(ServerConfig { idle_timeout, ..Default::default() }).await;
+    let (client, conn) = server.duplex();
+    let handler = tokio::spawn(conn.serve());
+
+    let mut client = Client::new(client);
+    assert_eq!(client.request("hello").await, "OK: 'hello' (request #1)");
+    let start = Instant::now();
+    let bye = client.lines.next_line().await.unwrap();
+    assert_eq!(bye.as_deref(), Some("BYE: idle timeout"));
+    assert_eq!(start.elapsed(), Duration::from_secs(30));
+    drop(client);
+    assert_eq!(handler.await.unwrap(), CloseReason::IdleTimeout);
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_quiet_client_is_pinged_and_kept_while_it_answers() {
+    let heartbeat_idle = Some(Duration::from_secs(30));
+    let server = paused_server(ServerConfig { heartbeat_idle, ..Default::default() }).await;
+    let (client, conn) = server.duplex();
+    tokio::spawn(conn.serve());
+
+    let mut client = Client::new(client);
+    let start = Instant::now();
+    assert_eq!(client.lines.next_line().await.unwrap().as_deref(), Some("PING"));
+    assert_eq!(start.elapsed(), Duration::from_secs(30));
+    // `PONG` gets no reply of its own; the next request is still #1
+    client.writer.write_all(b"PONG\n").await.unwrap();
+    assert_eq!(client.request("hello").await, "OK: 'hello' (request #1)");
+
+    let answered = Instant::now();
+    assert_eq!(client.lines.next_line().await.unwrap().as_deref(), Some("PING"));
+    assert_eq!(answered.elapsed(), Duration::from_secs(30));
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_client_that_does_not_answer_the_ping_is_closed() {
+    let config = ServerConfig {
+        heartbeat_idle: Some(Duration::from_secs(30)),
+        heartbeat_timeout: Duration::from_secs(5),
+        ..Default::default()
+    };
+    let server = paused_server(config).await;
+    let (client, conn) = server.duplex();
+    let handler = tokio::spawn(conn.serve());
+
+    let mut client = Client::new(client);
+    let start = Instant::now();
+    assert_eq!(client.lines.next_line().await.unwrap().as_deref(), Some("PING"));
+    let bye = client.lines.next_line().await.unwrap();
+    assert_eq!(bye.as_deref(), Some("BYE: no PONG in time"));
+    assert_eq!(start.elapsed(), Duration::from_secs(35));
+    drop(client);
+    assert_eq!(handler.await.unwrap(), CloseReason::HeartbeatTimeout);
+}
+
+/// The handler's read waits in a `select!`; every other branch that wins
+/// drops it. Bytes already read stay buffered, so a message can be split
+/// around a branch that won.
+#[tokio::test(start_paused = true)]
+async fn a_partial_message_survives_the_branches_that_win() {
+    let heartbeat_idle = Some(Duration::from_secs(30));
+    let server = paused_server(ServerConfig { heartbeat_idle, ..Default::default() }).await;
+    let (client, conn) = server.duplex();
+    tokio::spawn(conn.serve());
+
+    let mut client = Client::new(client);
+    client.writer.write_all(b"hel").await.unwrap();
+    // The heartbeat's branch wins while half a message is buffered
+    assert_eq!(client.lines.next_line().await.unwrap().as_deref(), Some("PING"));
+    client.writer.write_all(b"lo\nPONG\n").await.unwrap();
+    assert_eq!(client.lines.next_line().await.unwrap().unwrap(), "OK: 'hello' (request #1)");
+}
+
+#[tokio::test(start_paused = true)]
+async fn cancelling_the_token_interrupts_a_request() {
+    let server = paused_server(ServerConfig::default()).await;
+    let (client, conn) = server.duplex();
+    let token = conn.token();
+    let handler = tokio::spawn(conn.serve());
+
+    let mut client = Client::new(client);
+    client.writer.write_all(b"SLEEP 60000\n").await.unwrap();
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let start = Instant::now();
+    token.cancel();
+    let bye = client.lines.next_line().await.unwrap();
+    assert_eq!(bye.as_deref(), Some("BYE: disconnected by an administrator"));
+    assert_eq!(start.elapsed(), Duration::ZERO);
+    drop(client);
+    assert_eq!(handler.await.unwrap(), CloseReason::AdminKill);
+}
+
+#[tokio::test(start_paused = true)]
+async fn published_messages_reach_the_subscribers_of_their_topic() {
+    let server = paused_server(ServerConfig::default()).await;
+    let (subscriber, conn) = server.duplex();
+    tokio::spawn(conn.serve());
+    let (publisher, conn) = server.duplex();
+    tokio::spawn(conn.serve());
+    let mut publisher = Client::new(publisher);
+
+    // Held for the first subscriber, who gets it on subscribing
+    let held = publisher.request("PUBLISH ttl=60 deploys v1 is out").await;
+    assert_eq!(held, "OK: no subscribers, held for 60s");
+    let mut subscriber = Client::new(subscriber);
+    let subscribed = subscriber.request("SUBSCRIBE deploys").await;
+    assert_eq!(subscribed, "OK: subscribed to deploys, send anything to stop");
+    let held = subscriber.lines.next_line().await.unwrap().unwrap();
+    assert_eq!(held, "MESSAGE deploys v1 is out");
+
+    let start = Instant::now();
+    let delayed = publisher.request("PUBLISH-AFTER 10 deploys v2 is out").await;
+    assert!(delayed.ends_with("publishes in 10s"), "{}", delayed);
+    let message = subscriber.lines.next_line().await.unwrap().unwrap();
+    assert_eq!(message, "MESSAGE deploys v2 is out");
+    assert_eq!(start.elapsed(), Duration::from_secs(10));
+
+    assert_eq!(subscriber.request("stop").await, "END: feed stopped");
+    let dropped = publisher.request("PUBLISH deploys v3 is out").await;
+    assert_eq!(dropped, "OK: no subscribers, dropped");
+}
+
+#[tokio::test]
+async fn durable_messages_wait_for_an_ack_and_go_to_the_next_consumer() {
+    let dir = std::env::temp_dir().join(format!("duplex-queues-{}", std::process::id()));
+    let config = ServerConfig {
+        queue_dir: Some(dir.clone()),
+        durable_topics: vec!["jobs".to_string()],
+        ..Default::default()
+    };
+    let server = server_with(config).await;
+    let (publisher, conn) = server.duplex();
+    tokio::spawn(conn.serve());
+    let (first, conn) = server.duplex();
+    tokio::spawn(conn.serve());
+    let (second, conn) = server.duplex();
+    tokio::spawn(conn.serve());
+
+    // Queued without a consumer, and delivered once one comes
+    let mut publisher = Client::new(publisher);
+    assert_eq!(publisher.request("PUBLISH jobs build 41").await, "OK: queued as #1");
+    assert_eq!(publisher.request("PUBLISH jobs build 42").await, "OK: queued as #2");
+    let refused = publisher.request("PUBLISH ttl=5 jobs build 43").await;
+    assert_eq!(refused, "ERR: jobs is durable, its messages cannot wait or expire");
+    let mut first = Client::new(first);
+    assert_eq!(first.request("CONSUME jobs").await, "OK: consuming jobs, ACK <id> each message");
+    assert_eq!(first.lines.next_line().await.unwrap().unwrap(), "MESSAGE jobs 1 build 41");
+    assert_eq!(first.lines.next_line().await.unwrap().unwrap(), "MESSAGE jobs 2 build 42");
+    assert_eq!(first.request("ACK 1").await, "OK: acknowledged #1");
+    assert_eq!(first.request("ACK 1").await, "ERR: message #1 is not waiting for an ACK from you");
+
+    // Anything else stops the consumer; what it did not acknowledge goes to the next
+    assert_eq!(first.request("stop").await, "END: consumer stopped");
+    assert_eq!(first.lines.next_line().await.unwrap().unwrap(), "OK: 'stop' (request #1)");
+    let mut second = Client::new(second);
+    assert_eq!(second.request("CONSUME jobs").await, "OK: consuming jobs, ACK <id> each message");
+    assert_eq!(second.lines.next_line().await.unwrap().unwrap(), "MESSAGE jobs 2 build 42");
+    assert_eq!(second.request("ACK 2").await, "OK: acknowledged #2");
+    tokio::fs::remove_dir_all(&dir).await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_rate_limited_client_is_slowed_down_to_the_rate() {
+    let config = ServerConfig {
+        conn_limit: Some(Limit::new(2.0, Some(1.0))),
+        on_limit: OnExceed::Delay,
+        ..Default::default()
+    };
+    let server = paused_server(config).await;
+    let (client, conn) = server.duplex();
+    tokio::spawn(conn.serve());
+
+    // Two tokens a second and no burst: a reply every 500ms
+    let mut client = Client::new(client);
+    let start = Instant::now();
+    for n in 1..=3 {
+        let reply = client.request("ping").await;
+        assert_eq!(reply, format!("OK: 'ping' (request #{})", n));
+        assert_eq!(start.elapsed(), Duration::from_millis(500) * (n - 1));
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn shutdown_waits_a_while_for_the_client_to_close() {
+    let server = paused_server(ServerConfig::default()).await;
+    let (client, conn) = server.duplex();
+    let handler = tokio::spawn(conn.serve());
+    let mut client = Client::new(client);
+    assert_eq!(client.request("hello").await, "OK: 'hello' (request #1)");
+
+    let start = Instant::now();
+    server.shutdown();
+    let bye = client.lines.next_line().await.unwrap();
+    assert_eq!(bye.as_deref(), Some("BYE: server is shutting down"));
+    assert_eq!(client.lines.next_line().await.unwrap(), None);
+    // The client keeps its end open, so the server gives up after the 2s grace period
+    assert_eq!(handler.await.unwrap(), CloseReason::ServerShutdown);
+    assert_eq!(start.elapsed(), Duration::from_secs(2));
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_kicked_client_gets_the_grace_period_to_close_its_side() {
+    let config = ServerConfig { close_grace: Duration::from_millis(500), ..Default::default() };
+    let server = paused_server(config).await;
+    let (polite, conn) = server.duplex();
+    let (polite_token, polite_handler) = (conn.token(), tokio::spawn(conn.serve()));
+    let (stubborn, conn) = server.duplex();
+    let (stubborn_token, stubborn_handler) = (conn.token(), tokio::spawn(conn.serve()));
+    let (observer, conn) = server.duplex();
+    tokio::spawn(conn.serve());
+
+    // One closes as soon as it has read the last line, the other never does
+    let mut polite = Client::new(polite);
+    assert_eq!(polite.request("hello").await, "OK: 'hello' (request #1)");
+    polite_token.cancel();
+    let bye = polite.lines.next_line().await.unwrap();
+    assert_eq!(bye.as_deref(), Some("BYE: disconnected by an administrator"));
+    assert_eq!(polite.lines.next_line().await.unwrap(), None);
+    drop(polite);
+    assert_eq!(polite_handler.await.unwrap(), CloseReason::AdminKill);
+
+    let mut stubborn = Client::new(stubborn);
+    let start = Instant::now();
+    stubborn_token.cancel();
+    assert!(stubborn.lines.next_line().await.unwrap().unwrap().starts_with("BYE"));
+    assert_eq!(stubborn_handler.await.unwrap(), CloseReason::AdminKill);
+    assert_eq!(start.elapsed(), Duration::from_millis(500));
+
+    let mut observer = Client::new(observer);
+    assert_eq!(stat(&mut observer, "drain_client_closed").await, 1);
+    assert_eq!(stat(&mut observer, "drain_forced").await, 1);
+}
+
+#[tokio::test]
+async fn lines_are_messages_however_they_arrive() {
+    let server = server().await;
+    let (client, conn) = server.duplex();
+    tokio::spawn(conn.serve());
+
+    let mut client = Client::new(client);
+    // Two lines in one write, then one line in two writes
+    client.writer.write_all(b"one\ntwo\nthr").await.unwrap();
+    assert_eq!(client.lines.next_line().await.unwrap().unwrap(), "OK: 'one' (request #1)");
+    assert_eq!(client.lines.next_line().await.unwrap().unwrap(), "OK: 'two' (request #2)");
+    assert_eq!(client.request("ee").await, "OK: 'three' (request #3)");
+}
+
+#[tokio::test]
+async fn a_slow_client_is_served_all_the_same() {
+    let server = server().await;
+    let (client, conn) = server.duplex();
+    tokio::spawn(conn.serve());
+
+    // 20 bytes at a time, every 50ms: the line reaches the server in pieces
+    let mut client = ThrottledStream::new(client, Some(200));
+    client.write_all(b"SET color a-rather-long-blue-value\n").await.unwrap();
+    let mut reply = [0u8; 3];
+    client.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply, b"OK\n");
+}
+
+#[tokio::test]
+async fn an_oversized_line_is_refused_and_skipped() {
+    let server = server_with(ServerConfig { max_message: 16, ..ServerConfig::default() }).await;
+    let (client, conn) = server.duplex();
+    tokio::spawn(conn.serve());
+
+    let mut client = Client::new(client);
+    let long = "x".repeat(100);
+    assert_eq!(client.request(&long).await, "ERR: message too long, the limit is 16 bytes");
+    // The rest of the long line was discarded; the next one is served normally
+    assert_eq!(client.request("short").await, "OK: 'short' (request #1)");
+}
+
+#[tokio::test]
+async fn an_oversized_line_can_end_the_connection() {
+    let config = ServerConfig {
+        max_message: 16,
+        disconnect_oversized: true,
+        ..ServerConfig::default()
+    };
+    let server = server_with(config).await;
+    let (client, conn) = server.duplex();
+    let handler = tokio::spawn(conn.serve());
+
+    let mut client = Client::new(client);
+    assert_eq!(client.request(&"x".repeat(100)).await, "BYE: message too long");
+    drop(client);
+    assert_eq!(handler.await.unwrap(), CloseReason::MessageTooLong);
+}
+
+#[tokio::test]
+async fn strict_utf8_refuses_invalid_messages() {
+    let config = ServerConfig { utf8: Utf8Mode::Strict, ..ServerConfig::default() };
+    let server = server_with(config).await;
+    let (client, conn) = server.duplex();
+    tokio::spawn(conn.serve());
+
+    let mut client = Client::new(client);
+    client.writer.write_all(b"caf\xc3\n").await.unwrap();
+    let reply = client.lines.next_line().await.unwrap();
+    assert_eq!(reply.as_deref(), Some("ERR: invalid UTF-8 at byte 3"));
+    // The message was dropped, not counted, and the connection goes on
+    assert_eq!(client.request("caf\u{e9}").await, "OK: 'caf\u{e9}' (request #1)");
+
+    let config = ServerConfig { utf8: Utf8Mode::StrictClose, ..ServerConfig::default() };
+    let server = server_with(config).await;
+    let (client, conn) = server.duplex();
+    let handler = tokio::spawn(conn.serve());
+
+    let mut client = Client::new(client);
+    client.writer.write_all(b"\xff\n").await.unwrap();
+    let reply = client.lines.next_line().await.unwrap();
+    assert_eq!(reply.as_deref(), Some("ERR: invalid UTF-8 at byte 0"));
+    drop(client);
+    assert_eq!(handler.await.unwrap(), CloseReason::ProtocolError);
+}
+
+#[tokio::test]
+async fn a_client_that_does_not_read_is_disconnected() {
+    let config = ServerConfig {
+        outbox_limit: 4096,
+        backpressure: Backpressure::Disconnect,
+        ..ServerConfig::default()
+    };
+    let server = server_with(config).await;
+    let (mut client, conn) = server.duplex();
+    let handler = tokio::spawn(conn.serve());
+
+    // Requests but no reads: the replies fill the pipe, then the outbox.
+    // The write fails once the server has given up on the client.
+    let _ = client.write_all("ping\n".repeat(20_000).as_bytes()).await;
+    assert_eq!(handler.await.unwrap(), CloseReason::SlowClient);
+}
+
+#[tokio::test]
+async fn a_slow_client_can_lose_its_oldest_replies() {
+    let config = ServerConfig {
+        outbox_limit: 4096,
+        backpressure: Backpressure::DropOldest,
+        ..ServerConfig::default()
+    };
+    let server = server_with(config).await;
+    let (client, conn) = server.duplex();
+    let handler = tokio::spawn(conn.serve());
+
+    // More requests than the pipe holds, so the server has read most of
+    // them, and fallen behind on the replies, before anything is read
+    let mut client = Client::new(client);
+    client.writer.write_all("ping\n".repeat(20_000).as_bytes()).await.unwrap();
+    client.writer.shutdown().await.unwrap();
+
+    let mut replies = Vec::new();
+    while let Some(line) = client.lines.next_line().await.unwrap() {
+        replies.push(line);
+    }
+    assert_eq!(handler.await.unwrap(), CloseReason::ClientEof);
+    // Every reply is whole, and the newest ones made it
+    assert!(replies.len() < 20_000, "{} replies", replies.len());
+    assert!(replies.iter().all(|reply| reply.starts_with("OK: 'ping' (request #")));
+    assert_eq!(replies.last().unwrap(), "OK: 'ping' (request #20000)");
+}
+
+#[tokio::test(start_paused = true)]
+async fn pipelined_requests_run_at_once_and_reply_in_order() {
+    let server = paused_server(ServerConfig { max_in_flight: 2, ..Default::default() }).await;
+    let (client, conn) = server.duplex();
+    let handler = tokio::spawn(conn.serve());
+
+    let mut client = Client::new(client);
+    let start = Instant::now();
+    client.writer.write_all(b"SLEEP 200\nSLEEP 10\nSLEEP 100\nhello\n").await.unwrap();
+    client.writer.shutdown().await.unwrap();
+    let mut replies = Vec::new();
+    while let Some(line) = client.lines.next_line().await.unwrap() {
+        replies.push(line);
+    }
+    // Two at a time: the third sleep starts when the second is done
+    assert_eq!(replies, [
+        "OK: slept 200ms",
+        "OK: slept 10ms",
+        "OK: slept 100ms",
+        "OK: 'hello' (request #1)",
+    ]);
+    assert_eq!(start.elapsed(), Duration::from_millis(200));
+    assert_eq!(handler.await.unwrap(), CloseReason::ClientEof);
+}
+
+/// Sends `STATS` and picks `messages_received` out of the reply.
+async fn messages_received(client: &mut Client) -> u64 {
+    stat(client, "messages_received").await
+}
+
+/// Sends `STATS` and picks the metric `name` out of the reply.
+async fn stat(client: &mut Client, name: &str) -> u64 {
+    let prefix = format!("STAT {} ", name);
+    let mut line = client.request("STATS").await;
+    let mut value = None;
+    while line != "END" {
+        if let Some(n) = line.strip_prefix(&prefix) {
+            value = n.parse().ok();
+        }
+        line = client.lines.next_line().await.unwrap().unwrap();
+    }
+    value.unwrap_or(0)
+}
+
+fn auth_config() -> ServerConfig {
+    ServerConfig {
+        require_auth: true,
+        auth_tokens: vec![
+            AuthToken::new("ops", "secret", Permission::Admin),
+            AuthToken::new("ci", "peek", Permission::ReadOnly),
+        ],
+        ..ServerConfig::default()
+    }
+}
+
+#[tokio::test]
+async fn a_token_is_asked_for_before_anything_else() {
+    let server = server_with(auth_config()).await;
+    let (client, conn) = server.duplex();
+    tokio::spawn(conn.serve());
+
+    let mut client = Client::new(client);
+    let refused = client.request("hello").await;
+    assert_eq!(refused, "ERR: authenticate first, with AUTH <token>");
+    assert_eq!(client.request("AUTH guess").await, "ERR: wrong token");
+    assert_eq!(client.request("AUTH secret").await, "OK: authenticated as ops (admin)");
+    assert_eq!(client.request("hello").await, "OK: 'hello' (request #1)");
+    // The metrics layer comes first, so refused requests count too
+    assert_eq!(messages_received(&mut client).await, 5);
+}
+
+#[tokio::test]
+async fn a_read_only_token_cannot_change_anything() {
+    let server = server_with(auth_config()).await;
+    let (client, conn) = server.duplex();
+    tokio::spawn(conn.serve());
+
+    let mut client = Client::new(client);
+    assert_eq!(client.request("AUTH peek").await, "OK: authenticated as ci (read-only)");
+    assert_eq!(client.request("GET color").await, "NIL");
+    assert_eq!(client.request("SET color blue").await, "ERR: the token is read-only");
+    assert_eq!(client.request("GET color").await, "NIL");
+}
+
+#[tokio::test(start_paused = true)]
+async fn slowmode_delays_replies_and_only_admins_may_set_it() {
+    let server = paused_server(auth_config()).await;
+    let connect = || {
+        let (client, conn) = server.duplex();
+        tokio::spawn(conn.serve());
+        Client::new(client)
+    };
+    let (mut ci, mut ops) = (connect(), connect());
+    assert_eq!(ci.request("AUTH peek").await, "OK: authenticated as ci (read-only)");
+    let refused = "ERR: only an admin token or certificate may do that";
+    assert_eq!(ci.request("SLOWMODE 100").await, refused);
+    assert_eq!(ops.request("AUTH secret").await, "OK: authenticated as ops (admin)");
+    assert_eq!(ops.request("SLOWMODE 250").await, "OK: this connection's replies wait 250ms");
+
+    let start = Instant::now();
+    assert_eq!(ops.request("hello").await, "OK: 'hello' (request #1)");
+    assert_eq!(start.elapsed(), Duration::from_millis(250));
+    // Only that connection was slowed down
+    let start = Instant::now();
+    assert_eq!(ci.request("GET color").await, "NIL");
+    assert_eq!(start.elapsed(), Duration::ZERO);
+
+    // `ALL` slows down every connection without a delay of its own
+    assert_eq!(ops.request("SLOWMODE ALL 1000").await, "OK: every reply waits 1000ms");
+    let start = Instant::now();
+    assert_eq!(ci.request("GET color").await, "NIL");
+    assert_eq!(start.elapsed(), Duration::from_secs(1));
+    assert_eq!(ops.request("SLOWMODE").await, "SLOWMODE 250ms (all connections 1000ms)");
+    assert!(ops.request("SLOWMODE 61000").await.starts_with("ERR: usage: SLOWMODE"));
+}
+
+#[tokio::test(start_paused = true)]
+async fn wrong_tokens_are_throttled_per_address() {
+    let server = paused_server(auth_config()).await;
+    let (client, conn) = server.duplex();
+    tokio::spawn(conn.serve());
+
+    let mut client = Client::new(client);
+    for _ in 0..5 {
+        assert_eq!(client.request("AUTH guess").await, "ERR: wrong token");
+    }
+    // Not even the right token is checked now
+    let throttled = client.request("AUTH secret").await;
+    assert!(throttled.starts_with("ERR: too many wrong tokens, retry in "), "{}", throttled);
+
+    // Another connection from the same address is no better off
+    let (other, conn) = server.duplex();
+    tokio::spawn(conn.serve());
+    let mut other = Client::new(other);
+    assert!(other.request("AUTH secret").await.starts_with("ERR: too many wrong tokens"));
+
+    tokio::time::advance(Duration::from_secs(12)).await;
+    assert_eq!(client.request("AUTH secret").await, "OK: authenticated as ops (admin)");
+}
+
+#[tokio::test]
+async fn the_middleware_runs_in_the_configured_order() {
+    let config = ServerConfig { middleware: vec![Layer::Auth, Layer::Metrics], ..auth_config() };
+    let server = server_with(config).await;
+    let (client, conn) = server.duplex();
+    tokio::spawn(conn.serve());
+
+    // Only what got past `auth` is counted, `STATS` included
+    let mut client = Client::new(client);
+    assert!(client.request("hello").await.starts_with("ERR: authenticate first"));
+    assert!(client.request("AUTH secret").await.starts_with("OK: authenticated"));
+    assert_eq!(messages_received(&mut client).await, 1);
+}
+
+#[tokio::test]
+async fn leaving_auth_out_of_the_chain_does_not_switch_it_off() {
+    let config = ServerConfig { middleware: vec![Layer::Metrics, Layer::Log], ..auth_config() };
+    let server = server_with(config).await;
+    let (client, conn) = server.duplex();
+    tokio::spawn(conn.serve());
+
+    // With tokens configured, `auth` runs first anyway
+    let mut client = Client::new(client);
+    let refused = client.request("hello").await;
+    assert_eq!(refused, "ERR: authenticate first, with AUTH <token>");
+    assert_eq!(client.request("AUTH secret").await, "OK: authenticated as ops (admin)");
+    assert_eq!(client.request("hello").await, "OK: 'hello' (request #1)");
+}
+
+#[tokio::test(start_paused = true)]
+async fn only_admins_may_set_slowmode_whatever_the_chain() {
+    let middleware = vec![Layer::Metrics, Layer::Log];
+    let server = paused_server(ServerConfig { middleware, ..Default::default() }).await;
+    let (client, conn) = server.duplex();
+    tokio::spawn(conn.serve());
+
+    let mut client = Client::new(client);
+    let refused = "ERR: only an admin token or certificate may do that";
+    assert_eq!(client.request("SLOWMODE ALL 60000").await, refused);
+    let start = Instant::now();
+    assert_eq!(client.request("hello").await, "OK: 'hello' (request #1)");
+    assert_eq!(start.elapsed(), Duration::ZERO);
+}
+
+#[tokio::test]
+async fn a_read_only_token_cannot_write_whatever_the_chain() {
+    let config = ServerConfig { middleware: vec![Layer::Metrics, Layer::Log], ..auth_config() };
+    let server = server_with(config).await;
+    let (client, conn) = server.duplex();
+    tokio::spawn(conn.serve());
+
+    let mut client = Client::new(client);
+    assert_eq!(client.request("AUTH peek").await, "OK: authenticated as ci (read-only)");
+    assert_eq!(client.request("SET color blue").await, "ERR: the token is read-only");
+    assert_eq!(client.request("PUBLISH news hi").await, "ERR: the token is read-only");
+    assert_eq!(client.request("GET color").await, "NIL");
+}
+
+/// A config whose request script, at a path of its own, is `source`.
+async fn script_config(source: &str) -> ServerConfig {
+    // Tests run at once, each with a script of its own
+    static SCRIPTS: AtomicUsize = AtomicUsize::new(0);
+    let n = SCRIPTS.fetch_add(1, Ordering::Relaxed);
+    let name = format!("duplex-script-{}-{}.rhai", std::process::id(), n);
+    let path = std::env::temp_dir().join(name);
+    tokio::fs::write(&path, source).await.unwrap();
+    let middleware = vec![Layer::Metrics, Layer::Script, Layer::Log];
+    ServerConfig { script: Some(path), middleware, ..ServerConfig::default() }
+}
+
+#[cfg(feature = "scripting")]
+#[tokio::test]
+async fn the_request_script_rewrites_and_answers_messages() {
+    let source = r#"
+        fn on_message(message, peer) {
+            if message == "ping" { return #{ reply: "pong" }; }
+            if message.starts_with("shout ") { return message.sub_string(6).to_upper(); }
+            if message == "fail" { throw "no"; }
+            ()
+        }
+    "#;
+    let config = script_config(source).await;
+    let path = config.script.clone().unwrap();
+    let server = server_with(config).await;
+    tokio::fs::remove_file(path).await.unwrap();
+    let (client, conn) = server.duplex();
+    tokio::spawn(conn.serve());
+
+    let mut client = Client::new(client);
+    assert_eq!(client.request("hello").await, "OK: 'hello' (request #1)");
+    assert_eq!(client.request("shout hello").await, "OK: 'HELLO' (request #2)");
+    assert_eq!(client.request("ping").await, "pong");
+    assert_eq!(client.request("fail").await, "ERR: the request script failed");
+    assert_eq!(stat(&mut client, "script_errors").await, 1);
+}
+
+#[cfg(feature = "scripting")]
+#[tokio::test]
+async fn a_script_cannot_turn_a_read_into_a_write_for_a_read_only_token() {
+    let source = r#"
+        fn on_message(message, peer) {
+            if message.starts_with("GET ") { return "SET " + message.sub_string(4) + " v"; }
+            if message == "slow" { return "SLOWMODE ALL 60000"; }
+            ()
+        }
+    "#;
+    let config = script_config(source).await;
+    let path = config.script.clone().unwrap();
+    // The script runs after `auth`, which only sees what the client sent
+    let middleware = vec![Layer::Metrics, Layer::Auth, Layer::Script, Layer::Log];
+    let auth_tokens = auth_config().auth_tokens;
+    let server = server_with(ServerConfig { middleware, auth_tokens, ..config }).await;
+    tokio::fs::remove_file(path).await.unwrap();
+    let (client, conn) = server.duplex();
+    tokio::spawn(conn.serve());
+
+    let mut client = Client::new(client);
+    assert_eq!(client.request("AUTH peek").await, "OK: authenticated as ci (read-only)");
+    assert_eq!(client.request("GET color").await, "ERR: the token is read-only");
+    let refused = "ERR: only an admin token or certificate may do that";
+    assert_eq!(client.request("slow").await, refused);
+}
+
+#[cfg(not(feature = "scripting"))]
+#[tokio::test]
+async fn a_request_script_needs_the_scripting_feature() {
+    let config = script_config("fn on_message(message, peer) { () }").await;
+    let path = config.script.clone().unwrap();
+    let bound = Server::bind(config).await;
+    tokio::fs::remove_file(path).await.unwrap();
+    let Err(e) = bound else { panic!("served without an engine") };
+    assert!(e.to_string().contains("--features scripting"), "{}", e);
+}
+
+#[cfg(feature = "sqlite")]
+#[tokio::test]
+async fn history_returns_the_newest_logged_messages() {
+    let path = std::env::temp_dir().join(format!("duplex-history-{}.db", std::process::id()));
+    let auth_tokens = auth_config().auth_tokens;
+    let config = ServerConfig { history_db: Some(path.clone()), auth_tokens, ..Default::default() };
+    let server = server_with(config).await;
+    let connect = || {
+        let (client, conn) = server.duplex();
+        tokio::spawn(conn.serve());
+        Client::new(client)
+    };
+    let (mut client, mut stranger) = (connect(), connect());
+
+    // Other clients' messages are for admins only
+    let refused = "ERR: only an admin token or certificate may do that";
+    assert_eq!(stranger.request("HISTORY 100").await, refused);
+    assert_eq!(client.request("AUTH secret").await, "OK: authenticated as ops (admin)");
+    assert_eq!(client.request("hello").await, "OK: 'hello' (request #1)");
+    assert_eq!(client.request("world").await, "OK: 'world' (request #2)");
+    // The logger prints in batches, and the rows are written after that
+    let lines = loop {
+        let mut lines = vec![client.request("HISTORY 100").await];
+        while lines.last().unwrap() != "END" {
+            lines.push(client.lines.next_line().await.unwrap().unwrap());
+        }
+        if lines.iter().any(|line| line.ends_with(" world")) {
+            break lines;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    };
+    // After the server's own line about the `AUTH`
+    let lines = &lines[lines.iter().position(|line| line.ends_with(" hello")).unwrap()..];
+    assert!(lines[0].starts_with("LINE ") && lines[0].ends_with(" hello"), "{}", lines[0]);
+    assert!(lines[1].ends_with(" world"), "{}", lines[1]);
+    assert!(client.request("HISTORY").await.starts_with("ERR: usage: HISTORY <n>"));
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+    }
+}
+
+#[cfg(not(feature = "sqlite"))]
+#[tokio::test]
+async fn history_needs_the_sqlite_feature() {
+    let auth_tokens = auth_config().auth_tokens;
+    let server = server_with(ServerConfig { auth_tokens, ..Default::default() }).await;
+    let (client, conn) = server.duplex();
+    tokio::spawn(conn.serve());
+    let mut client = Client::new(client);
+    assert_eq!(client.request("AUTH secret").await, "OK: authenticated as ops (admin)");
+    let reply = client.request("HISTORY 5").await;
+    assert_eq!(reply, "ERR: no history, start with --history-db");
+
+    let config = ServerConfig { history_db: Some("history.db".into()), ..Default::default() };
+    let Err(e) = Server::bind(config).await else { panic!("logged without a database") };
+    assert!(e.to_string().contains("--features sqlite"), "{}", e);
+}
+
+#[tokio::test]
+async fn the_log_can_be_followed_from_outside() {
+    let server = server().await;
+    let mut log = server.logger().subscribe();
+    let (client, conn) = server.duplex();
+    tokio::spawn(conn.serve());
+
+    let mut client = Client::new(client);
+    assert_eq!(client.request("hello").await, "OK: 'hello' (request #1)");
+    assert_eq!(client.request("world").await, "OK: 'world' (request #2)");
+    // The client lines, as the logger printed them
+    let mut printed = Vec::new();
+    while printed.len() < 2 {
+        printed.push(log.recv().await.unwrap().line);
+    }
+    assert_eq!(printed, ["[LOG] hello", "[LOG] world"]);
+}
+
+/// Writes one length-prefixed frame and reads the payload of the reply.
+async fn frame_request(stream: &mut DuplexStream, payload: &[u8]) -> Vec<u8> {
+    let mut frame = (payload.len() as u32).to_be_bytes().to_vec();
+    frame.extend_from_slice(payload);
+    stream.write_all(&frame).await.unwrap();
+
+    let len = stream.read_u32().await.unwrap();
+    let mut reply = vec![0; len as usize];
+    stream.read_exact(&mut reply).await.unwrap();
+    reply
+}
+
+#[tokio::test]
+async fn length_prefixed_payloads_may_contain_newlines() {
+    let server = server().await.with_framing(Framing::LengthPrefixed);
+    let (mut client, conn) = server.duplex();
+    tokio::spawn(conn.serve());
+
+    let reply = frame_request(&mut client, b"two\nlines").await;
+    assert_eq!(reply, b"OK: 'two\nlines' (request #1)");
+    assert_eq!(frame_request(&mut client, b"SET color blue").await, b"OK");
+}
+
+#[cfg(feature = "msgpack")]
+#[tokio::test]
+async fn a_messagepack_first_frame_switches_the_connection_to_messagepack() {
+    let server = server().await.with_framing(Framing::LengthPrefixed);
+    let (mut client, conn) = server.duplex();
+    tokio::spawn(conn.serve());
+
+    let mut request = async |cmd: &str, args: &[&str]| {
+        let payload = rmp_serde::to_vec_named(&serde_json::json!({"cmd": cmd, "args": args}));
+        let reply = frame_request(&mut client, &payload.unwrap()).await;
+        rmp_serde::from_slice::<Response>(&reply).unwrap()
+    };
+    let response = request("SET", &["color", "blue"]).await;
+    assert_eq!(response, Response { ok: true, data: "OK".into(), request_no: 1 });
+    assert_eq!(request("GET", &["color"]).await.data, "VALUE blue");
+    assert!(!request("GET", &[]).await.ok);
+
+    // Once negotiated, text is a malformed request
+    let reply = frame_request(&mut client, b"GET color").await;
+    let response: Response = rmp_serde::from_slice(&reply).unwrap();
+    assert_eq!(response.data["error"], "malformed_msgpack");
+}
+
+#[cfg(feature = "msgpack")]
+#[tokio::test]
+async fn a_text_frame_in_syriac_stays_text() {
+    let server = server().await.with_framing(Framing::LengthPrefixed);
+    let (mut client, conn) = server.duplex();
+    tokio::spawn(conn.serve());
+
+    // U+0700 is encoded 0xDC 0x80, and 0xDC is also MessagePack's Array16
+    let reply = frame_request(&mut client, "\u{700}".as_bytes()).await;
+    assert_eq!(String::from_utf8(reply).unwrap(), "OK: '\u{700}' (request #1)");
+    assert_eq!(frame_request(&mut client, b"SET color blue").await, b"OK");
+}
+
+#[tokio::test]
+async fn an_oversized_frame_ends_the_connection() {
+    let config = ServerConfig { max_message: 16, ..ServerConfig::default() };
+    let server = server_with(config).await.with_framing(Framing::LengthPrefixed);
+    let (mut client, conn) = server.duplex();
+    let handler = tokio::spawn(conn.serve());
+
+    // Only the prefix is needed: there is no way to skip the claimed bytes
+    client.write_all(&1000u32.to_be_bytes()).await.unwrap();
+    assert_eq!(handler.await.unwrap(), CloseReason::MessageTooLong);
+}
+
+#[tokio::test]
+async fn json_requests_get_typed_responses() {
+    let server = server().await.with_protocol(Protocol::Json);
+    let (client, conn) = server.duplex();
+    tokio::spawn(conn.serve());
+
+    let mut client = Client::new(client);
+    let reply = client.request(r#"{"cmd":"SET","args":["color","blue"]}"#).await;
+    let response: Response = serde_json::from_str(&reply).unwrap();
+    assert_eq!(response, Response { ok: true, data: "OK".into(), request_no: 1 });
+
+    let reply = client.request(r#"{"cmd":"GET","args":["color"#).await;
+    let response: Response = serde_json::from_str(&reply).unwrap();
+    assert!(!response.ok);
+    assert_eq!(response.data["error"], "malformed_json");
+    assert_eq!(response.request_no, 2);
+}
+
+#[tokio::test]
+async fn the_handshake_picks_a_mode() {
+    let config = ServerConfig { handshake: true, ..ServerConfig::default() };
+    let server = server_with(config).await;
+    let (client, conn) = server.duplex();
+    tokio::spawn(conn.serve());
+
+    let mut client = Client::new(client);
+    let banner = client.lines.next_line().await.unwrap().unwrap();
+    let expected = "WELCOME tokio-examples/1 MODES ECHO KV JSON OPTIONS GZIP \
+                    FORMAT=PLAIN,JSON,COMPACT SESSION ";
+    assert!(banner.starts_with(expected), "{}", banner);
+    assert_eq!(client.request("SET color blue").await, "ERR: choose a mode first: HELLO <mode>");
+    assert_eq!(client.request("HELLO KV").await, "OK: mode KV");
+    assert_eq!(client.request("SET color blue").await, "OK");
+    assert!(client.request("STATS").await.starts_with("ERR: KV mode only takes"));
+}
+
+#[tokio::test]
+async fn plain_messages_are_answered_in_the_picked_format() {
+    let config = ServerConfig { handshake: true, ..ServerConfig::default() };
+    let server = server_with(config).await;
+    for (hello, reply) in [
+        ("HELLO ECHO", "OK: 'hi' (request #1)"),
+        ("HELLO ECHO FORMAT=JSON", r#"{"conn":2,"input":"hi","request":2}"#),
+        ("HELLO ECHO FORMAT=COMPACT", "#3 hi"),
+    ] {
+        let (client, conn) = server.duplex();
+        tokio::spawn(conn.serve());
+        let mut client = Client::new(client);
+        client.lines.next_line().await.unwrap();
+        assert!(client.request(hello).await.starts_with("OK: mode ECHO"));
+        assert_eq!(client.request("hi").await, reply);
+    }
+}
+
+#[tokio::test]
+async fn a_session_is_resumed_on_a_new_connection() {
+    let config = ServerConfig { handshake: true, ..ServerConfig::default() };
+    let server = server_with(config).await;
+    let (client, conn) = server.duplex();
+    let first = tokio::spawn(conn.serve());
+
+    let mut client = Client::new(client);
+    let banner = client.lines.next_line().await.unwrap().unwrap();
+    let id = banner.rsplit(' ').next().unwrap().to_string();
+    assert_eq!(client.request("HELLO KV").await, "OK: mode KV");
+    assert_eq!(client.request("SET color blue").await, "OK");
+    drop(client);
+    // The session is saved once the first connection is done
+    first.await.unwrap();
+
+    let (client, conn) = server.duplex();
+    tokio::spawn(conn.serve());
+    let mut client = Client::new(client);
+    client.lines.next_line().await.unwrap();
+    let expected = format!(
+        "OK: resumed session {}, 1 message(s), 14 byte(s), last \"SET color blue\", mode KV",
+        id,
+    );
+    assert_eq!(client.request(&format!("RESUME {}", id)).await, expected);
+    // No HELLO needed: the mode came back with the session
+    assert_eq!(client.request("GET color").await, "VALUE blue");
+    let again = client.request(&format!("RESUME {}", id)).await;
+    assert_eq!(again, format!("ERR: no session {} to resume", id));
+}
+
+#[tokio::test]
+async fn long_replies_are_gzipped_when_asked_for() {
+    let config = ServerConfig { handshake: true, gzip_threshold: 64, ..ServerConfig::default() };
+    let server = server_with(config).await;
+    let (client, conn) = server.duplex();
+    tokio::spawn(conn.serve());
+
+    let mut client = Client::new(client);
+    client.lines.next_line().await.unwrap();
+    assert_eq!(client.request("HELLO ECHO GZIP").await, "OK: mode ECHO GZIP");
+    assert_eq!(client.request("short").await, "OK: 'short' (request #1)");
+
+    let long = "a".repeat(200);
+    let expected = format!("OK: '{}' (request #2)\n", long);
+    let header = client.request(&long).await;
+    assert_eq!(header, format!("GZIP {}", expected.len()));
+    // The gzip member ends by itself; the next reply follows right after it
+    client.writer.write_all(b"short\n").await.unwrap();
+    client.writer.shutdown().await.unwrap();
+    let mut rest = Vec::new();
+    client.lines.get_mut().read_to_end(&mut rest).await.unwrap();
+
+    let mut decoder = flate2::bufread::GzDecoder::new(&rest[..]);
+    let mut decoded = String::new();
+    std::io::Read::read_to_string(&mut decoder, &mut decoded).unwrap();
+    assert_eq!(decoded, expected);
+    assert_eq!(decoder.into_inner(), b"OK: 'short' (request #3)\n");
+}
+
+#[tokio::test]
+async fn put_stores_the_body_and_serves_what_follows() {
+    let dir = std::env::temp_dir().join(format!("duplex-uploads-{}", std::process::id()));
+    let config =
+        ServerConfig { upload_dir: dir.clone(), max_upload: 64, ..ServerConfig::default() };
+    let server = server_with(config).await;
+    let (client, conn) = server.duplex();
+    tokio::spawn(conn.serve());
+
+    let mut client = Client::new(client);
+    assert!(client.request("PUT big.bin 65").await.starts_with("ERR: file too large"));
+    assert_eq!(client.request("PUT notes.txt 12").await, "READY: send 12 bytes");
+    // The body may hold newlines, and the next command may follow right away
+    client.writer.write_all(b"line 1\nline2SET a 1\n").await.unwrap();
+    let stored = client.lines.next_line().await.unwrap().unwrap();
+    assert_eq!(stored, "OK: stored notes.txt (12 bytes)");
+    assert_eq!(client.lines.next_line().await.unwrap().unwrap(), "OK");
+
+    let contents = tokio::fs::read(dir.join("notes.txt")).await.unwrap();
+    assert_eq!(contents, b"line 1\nline2");
+    tokio::fs::remove_dir_all(&dir).await.unwrap();
+}
+
+#[tokio::test]
+async fn get_file_sends_a_length_and_the_contents() {
+    let dir = std::env::temp_dir().join(format!("duplex-downloads-{}", std::process::id()));
+    tokio::fs::create_dir_all(&dir).await.unwrap();
+    tokio::fs::write(dir.join("notes.txt"), b"line 1\nline 2\n").await.unwrap();
+    let config = ServerConfig { upload_dir: dir.clone(), ..ServerConfig::default() };
+    let server = server_with(config).await;
+    let (client, conn) = server.duplex();
+    let handler = tokio::spawn(conn.serve());
+
+    let mut client = Client::new(client);
+    assert_eq!(client.request("GET-FILE missing.txt").await, "ERR: no file named missing.txt");
+    assert_eq!(client.request("GET-FILE notes.txt").await, "FILE 14");
+    let mut contents = [0u8; 14];
+    client.lines.get_mut().read_exact(&mut contents).await.unwrap();
+    assert_eq!(&contents, b"line 1\nline 2\n");
+    assert_eq!(client.request("SET a 1").await, "OK");
+
+    // Hanging up halfway through a download just ends the connection
+    tokio::fs::write(dir.join("big.bin"), vec![b'x'; 1 << 20]).await.unwrap();
+    client.writer.write_all(b"GET-FILE big.bin\n").await.unwrap();
+    assert_eq!(client.lines.next_line().await.unwrap().unwrap(), "FILE 1048576");
+    drop(client);
+    assert_eq!(handler.await.unwrap(), CloseReason::ClientEof);
+    tokio::fs::remove_dir_all(&dir).await.unwrap();
+}
+
+#[tokio::test]
+async fn proxy_mode_pipes_to_the_upstream() {
+    // An upstream that answers every line in upper case
+    let upstream = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let config = ServerConfig {
+        proxy: vec![upstream.local_addr().unwrap().to_string()],
+        ..ServerConfig::default()
+    };
+    // Health checks connect too, so every connection is served
+    tokio::spawn(async move {
+        loop {
+            let (socket, _) = upstream.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (reader, mut writer) = socket.into_split();
+                let mut lines = BufReader::new(reader).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    let reply = format!("{}\n", line.to_uppercase());
+                    writer.write_all(reply.as_bytes()).await.unwrap();
+                }
+            });
+        }
+    });
+
+    let server = server_with(config).await;
+    let (client, conn) = server.duplex();
+    let handler = tokio::spawn(conn.serve());
+
+    let mut client = Client::new(client);
+    assert_eq!(client.request("STATS").await, "STATS");
+    assert_eq!(client.request("through the proxy").await, "THROUGH THE PROXY");
+
+    drop(client);
+    assert_eq!(handler.await.unwrap(), CloseReason::ClientEof);
+}
+
+#[tokio::test]
+async fn a_quota_is_shared_by_the_connections_of_a_client_until_it_authenticates() {
+    let config = ServerConfig {
+        require_auth: false,
+        quota: Some(Limit::new(0.001, Some(4.0))),
+        on_limit: OnExceed::Reject,
+        ..auth_config()
+    };
+    let server = server_with(config).await;
+    let (first, conn) = server.duplex();
+    tokio::spawn(conn.serve());
+    let (second, conn) = server.duplex();
+    tokio::spawn(conn.serve());
+    let (mut first, mut second) = (Client::new(first), Client::new(second));
+
+    for _ in 0..2 {
+        assert!(first.request("ping").await.starts_with("OK: 'ping'"));
+        assert!(second.request("ping").await.starts_with("OK: 'ping'"));
+    }
+    // Four between the two connections of the address
+    let refused = first.request("ping").await;
+    assert!(refused.starts_with("ERR: rate limit exceeded, retry in "), "{}", refused);
+
+    // Authenticated, the client spends the token's quota instead
+    assert_eq!(second.request("AUTH secret").await, "OK: authenticated as ops (admin)");
+    for _ in 0..4 {
+        assert!(second.request("ping").await.starts_with("OK: 'ping'"));
+    }
+    assert!(second.request("ping").await.starts_with("ERR: rate limit exceeded"));
+}
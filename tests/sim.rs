@@ -0,0 +1,183 @@
+//! Deterministic simulation tests, run with `cargo test --features sim`.
+//!
+//! turmoil runs the server and its clients as hosts of a simulated network,
+//! all on one thread with simulated time. Latency, partitions and timeouts
+//! play out the same way on every run, in milliseconds of real time.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::time::{Instant, timeout};
+use turmoil::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use turmoil::net::{TcpListener, TcpStream};
+use turmoil::{Builder, Sim};
+use tokio_examples::{Server, ServerConfig};
+
+const PORT: u16 = 7000;
+
+fn sim() -> Sim<'static> {
+    Builder::new()
+        .simulation_duration(Duration::from_secs(60))
+        // Every message takes exactly this long, so timings are exact
+        .min_message_latency(Duration::from_millis(50))
+        .max_message_latency(Duration::from_millis(50))
+        // `Server::bind` also binds a real listener, which stays unused
+        .enable_tokio_io()
+        .build()
+}
+
+/// Starts the server on the simulated host `server`, optionally shutting
+/// it down after `shutdown_after`. A function makes the configuration, since
+/// a host can be started more than once.
+fn server(sim: &mut Sim, config: fn() -> ServerConfig, shutdown_after: Option<Duration>) {
+    sim.host("server", move || {
+        // Time only moves while no blocking task is running, and `HASH` workers
+        // are blocking tasks that never finish, so the pool stays empty
+        let listen = SocketAddr::from(([127, 0, 0, 1], 0));
+        let config = ServerConfig { listen, hash_workers: 0, ..config() };
+        async move {
+            let server = Server::bind(config).await?;
+            let listener = TcpListener::bind(("0.0.0.0", PORT)).await?;
+            let shutdown = async {
+                match shutdown_after {
+                    Some(delay) => {
+                        tokio::time::sleep(delay).await;
+                        server.shutdown();
+                    }
+                    None => std::future::pending().await,
+                }
+            };
+            let (served, ()) = tokio::join!(server.serve_simulated(listener), shutdown);
+            served?;
+            Ok(())
+        }
+    });
+}
+
+struct Client {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    writer: OwnedWriteHalf,
+}
+
+impl Client {
+    async fn connect() -> std::io::Result<Self> {
+        let (reader, writer) = TcpStream::connect(("server", PORT)).await?.into_split();
+        Ok(Self { lines: BufReader::new(reader).lines(), writer })
+    }
+
+    async fn line(&mut self) -> std::io::Result<Option<String>> {
+        self.lines.next_line().await
+    }
+
+    async fn request(&mut self, message: &str) -> std::io::Result<String> {
+        self.writer.write_all(format!("{}\n", message).as_bytes()).await?;
+        let reply = self.line().await?;
+        reply.ok_or_else(|| std::io::ErrorKind::UnexpectedEof.into())
+    }
+}
+
+/// Like the `client` binary: every attempt has a timeout, and after one
+/// the connection is suspect, so the next attempt reconnects.
+/// Returns the reply and the number of attempts it took.
+async fn request_with_retry(message: &str) -> (String, u32) {
+    const TIMEOUT: Duration = Duration::from_secs(1);
+    const BACKOFF: Duration = Duration::from_millis(500);
+    for attempt in 1.. {
+        let reply = timeout(TIMEOUT, async {
+            let mut client = Client::connect().await?;
+            client.request(message).await
+        });
+        if let Ok(Ok(reply)) = reply.await {
+            return (reply, attempt);
+        }
+        tokio::time::sleep(BACKOFF).await;
+    }
+    unreachable!()
+}
+
+#[test]
+fn replies_take_a_round_trip_of_latency() {
+    let mut sim = sim();
+    server(&mut sim, ServerConfig::default, None);
+    sim.client("client", async {
+        let mut client = Client::connect().await?;
+        let start = Instant::now();
+        assert_eq!(client.request("hello").await?, "OK: 'hello' (request #1)");
+        // 50ms there and 50ms back, to the millisecond
+        assert_eq!(start.elapsed(), Duration::from_millis(100));
+        assert_eq!(client.request("again").await?, "OK: 'again' (request #2)");
+        Ok(())
+    });
+    sim.run().unwrap();
+}
+
+#[test]
+fn an_idle_client_is_disconnected() {
+    let mut sim = sim();
+    let config =
+        || ServerConfig { idle_timeout: Some(Duration::from_secs(5)), ..Default::default() };
+    server(&mut sim, config, None);
+    sim.client("client", async {
+        let mut client = Client::connect().await?;
+        assert_eq!(client.request("hello").await?, "OK: 'hello' (request #1)");
+
+        let start = Instant::now();
+        assert_eq!(client.line().await?.as_deref(), Some("BYE: idle timeout"));
+        // The timeout started when the message arrived, 50ms before the reply
+        assert_eq!(start.elapsed(), Duration::from_millis(4950 + 50));
+        assert_eq!(client.line().await?, None);
+        Ok(())
+    });
+    sim.run().unwrap();
+}
+
+#[test]
+fn a_request_lost_in_a_partition_is_retried() {
+    let mut sim = sim();
+    server(&mut sim, ServerConfig::default, None);
+    sim.client("client", async {
+        let mut client = Client::connect().await?;
+        assert_eq!(client.request("before").await?, "OK: 'before' (request #1)");
+
+        // Messages between the two hosts are dropped until the repair
+        turmoil::partition("client", "server");
+        tokio::spawn(async {
+            tokio::time::sleep(Duration::from_millis(2750)).await;
+            turmoil::repair("client", "server");
+        });
+        let lost = timeout(Duration::from_secs(1), client.request("lost")).await;
+        assert!(lost.is_err(), "{:?}", lost);
+
+        // Connecting across the partition is refused at once, so attempts
+        // at 1s, 1.5s, 2s and 2.5s fail; the one at 3s gets through.
+        // The lost message never reached the server, so it was not counted.
+        let (reply, attempts) = request_with_retry("after").await;
+        assert_eq!(reply, "OK: 'after' (request #2)");
+        assert_eq!(attempts, 5);
+        Ok(())
+    });
+    sim.run().unwrap();
+}
+
+#[test]
+fn shutdown_says_goodbye_and_stops_accepting() {
+    let mut sim = sim();
+    server(&mut sim, ServerConfig::default, Some(Duration::from_secs(10)));
+    sim.client("client", async {
+        let mut client = Client::connect().await?;
+        assert_eq!(client.request("hello").await?, "OK: 'hello' (request #1)");
+
+        let goodbye = client.line().await?;
+        assert_eq!(goodbye.as_deref(), Some("BYE: server is shutting down"));
+        assert_eq!(client.line().await?, None);
+        drop(client);
+
+        // Once its last connection is closed, the server is gone: nothing
+        // answers a new connection any more
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let reconnect = timeout(Duration::from_secs(1), Client::connect()).await;
+        assert!(!matches!(reconnect, Ok(Ok(_))));
+        Ok(())
+    });
+    sim.run().unwrap();
+}
@@ -0,0 +1,561 @@
+//! End-to-end tests over real TCP connections.
+//!
+//! Each test starts a whole server in-process, the way `main` does, on an
+//! ephemeral port: `server::start` binds `127.0.0.1:0` and reports the
+//! port the OS picked, so tests can run in parallel without colliding.
+
+use std::collections::BTreeSet;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::TcpStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::task::JoinHandle;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::{
+    CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName,
+};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use rcgen::{
+    BasicConstraints, CertificateParams, CertifiedIssuer, DnType, ExtendedKeyUsagePurpose, IsCa,
+    KeyPair,
+};
+use tokio_examples::auth::CertIdentity;
+use tokio_examples::config::RuntimeMode;
+use tokio_examples::listeners::{ListenerMode, ListenerSpec, Listeners};
+use tokio_examples::shedding::{ShedPolicy, Thresholds};
+use tokio_examples::vhost::VirtualHost;
+use tokio_examples::{ServerConfig, ServerError, ServerHandle, server};
+
+/// A server running in the background for the length of a test.
+struct TestServer {
+    handle: ServerHandle,
+    listeners: Arc<Listeners>,
+    task: JoinHandle<Result<(), ServerError>>,
+}
+
+impl TestServer {
+    async fn start(config: ServerConfig) -> Self {
+        // Without the console, a test never waits on the terminal's stdin
+        let listen = SocketAddr::from(([127, 0, 0, 1], 0));
+        let config = ServerConfig { listen, console: false, ..config };
+        let running = server::start(config).await.unwrap();
+        let handle = running.handle();
+        let listeners = running.listeners();
+        let task = tokio::spawn(running.wait());
+        Self { handle, listeners, task }
+    }
+
+    async fn connect(&self) -> Client {
+        Self::connect_to(self.handle.local_addr()).await
+    }
+
+    /// Connects to the first of the other listeners in `mode`.
+    async fn connect_listener(&self, mode: ListenerMode) -> Client {
+        Self::connect_to(self.listeners.local_addr(mode).unwrap()).await
+    }
+
+    async fn connect_to(addr: SocketAddr) -> Client {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (reader, writer) = stream.into_split();
+        Client { lines: BufReader::new(reader).lines(), writer }
+    }
+
+    /// Shuts the server down and waits until every connection is closed.
+    async fn stop(self) {
+        self.handle.shutdown();
+        self.task.await.unwrap().unwrap();
+    }
+}
+
+struct Client {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    writer: OwnedWriteHalf,
+}
+
+impl Client {
+    async fn send(&mut self, message: &str) {
+        self.writer.write_all(format!("{}\n", message).as_bytes()).await.unwrap();
+    }
+
+    async fn line(&mut self) -> Option<String> {
+        self.lines.next_line().await.unwrap()
+    }
+
+    /// Sends one message and returns the first line of the reply.
+    async fn request(&mut self, message: &str) -> String {
+        self.send(message).await;
+        self.line().await.expect("server closed the connection")
+    }
+}
+
+/// The request number in an echo reply, `OK: '<input>' (request #<n>)`.
+fn request_number(reply: &str) -> u64 {
+    let n = reply.rsplit_once('#').and_then(|(_, n)| n.strip_suffix(')'));
+    n.and_then(|n| n.parse().ok()).unwrap_or_else(|| panic!("not an echo reply: {}", reply))
+}
+
+#[tokio::test]
+async fn the_chosen_port_is_reported() {
+    let server = TestServer::start(ServerConfig::default()).await;
+    assert_ne!(server.handle.local_addr().port(), 0);
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn messages_are_echoed_with_a_rising_counter() {
+    let server = TestServer::start(ServerConfig::default()).await;
+    let mut client = server.connect().await;
+
+    assert_eq!(client.request("hello").await, "OK: 'hello' (request #1)");
+    assert_eq!(client.request("hello again").await, "OK: 'hello again' (request #2)");
+    assert_eq!(client.request("  padded  ").await, "OK: 'padded' (request #3)");
+    drop(client);
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn clients_share_one_counter() {
+    let server = TestServer::start(ServerConfig::default()).await;
+    let mut first = server.connect().await;
+    let mut second = server.connect().await;
+
+    assert_eq!(first.request("a").await, "OK: 'a' (request #1)");
+    assert_eq!(second.request("b").await, "OK: 'b' (request #2)");
+    assert_eq!(first.request("c").await, "OK: 'c' (request #3)");
+    drop((first, second));
+    server.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_clients_get_distinct_request_numbers() {
+    const CLIENTS: u64 = 10;
+    const MESSAGES: u64 = 20;
+    let server = TestServer::start(ServerConfig::default()).await;
+
+    let mut clients = Vec::new();
+    for i in 0..CLIENTS {
+        let mut client = server.connect().await;
+        clients.push(tokio::spawn(async move {
+            let mut numbers = Vec::new();
+            for j in 0..MESSAGES {
+                numbers.push(request_number(&client.request(&format!("{}-{}", i, j)).await));
+            }
+            numbers
+        }));
+    }
+
+    let mut all = BTreeSet::new();
+    for client in clients {
+        let numbers = client.await.unwrap();
+        // Each client's own requests are counted in the order it sent them
+        assert!(numbers.is_sorted(), "{:?}", numbers);
+        all.extend(numbers);
+    }
+    // No number was handed out twice, and none was skipped
+    assert_eq!(all, (1..=CLIENTS * MESSAGES).collect());
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn shutdown_says_goodbye_to_open_connections() {
+    let server = TestServer::start(ServerConfig::default()).await;
+    let addr = server.handle.local_addr();
+    let mut client = server.connect().await;
+    assert_eq!(client.request("hello").await, "OK: 'hello' (request #1)");
+
+    // The server waits for the client to close too, so stop in the background
+    let stopped = tokio::spawn(server.stop());
+    assert_eq!(client.line().await.as_deref(), Some("BYE: server is shutting down"));
+    assert_eq!(client.line().await, None);
+    drop(client);
+    stopped.await.unwrap();
+    assert!(TcpStream::connect(addr).await.is_err());
+}
+
+#[tokio::test]
+async fn acceptors_share_the_ephemeral_port() {
+    let config =
+        ServerConfig { runtime: RuntimeMode::MultiAcceptor, workers: 2, ..Default::default() };
+    let server = TestServer::start(config).await;
+
+    // The kernel spreads connections over both sockets; all of them answer
+    let mut numbers = Vec::new();
+    for _ in 0..8 {
+        let mut client = server.connect().await;
+        numbers.push(request_number(&client.request("ping").await));
+    }
+    assert_eq!(numbers, (1..=8).collect::<Vec<_>>());
+    server.stop().await;
+}
+
+#[cfg(all(target_os = "linux", feature = "uring"))]
+#[tokio::test]
+async fn the_uring_backend_serves_and_drains_like_the_default_one() {
+    let listen = SocketAddr::from(([127, 0, 0, 1], 0));
+    let runtime = RuntimeMode::Uring;
+    let config = ServerConfig { listen, console: false, runtime, ..Default::default() };
+    // tokio-uring's runtime is one of its own, so the server gets a thread
+    let (handle_tx, handle_rx) = tokio::sync::oneshot::channel();
+    let thread = std::thread::spawn(move || {
+        tokio_examples::uring::start(async move {
+            let running = server::start(config).await.unwrap();
+            let _ = handle_tx.send(running.handle());
+            running.wait().await
+        })
+    });
+    let handle: ServerHandle = handle_rx.await.unwrap();
+
+    let mut client = TestServer::connect_to(handle.local_addr()).await;
+    assert_eq!(client.request("SET color blue").await, "OK");
+    assert_eq!(client.request("GET color").await, "VALUE blue");
+    assert_eq!(client.request("hello").await, "OK: 'hello' (request #1)");
+    handle.shutdown();
+    assert_eq!(client.line().await.as_deref(), Some("BYE: server is shutting down"));
+    assert_eq!(client.line().await, None);
+    drop(client);
+    thread.join().unwrap().unwrap();
+}
+
+#[cfg(not(all(target_os = "linux", feature = "uring")))]
+#[tokio::test]
+async fn the_uring_backend_needs_its_feature() {
+    let listen = SocketAddr::from(([127, 0, 0, 1], 0));
+    let runtime = RuntimeMode::Uring;
+    let config = ServerConfig { listen, console: false, runtime, ..Default::default() };
+    let Err(ServerError::Config(e)) = server::start(config).await else {
+        panic!("the uring backend started without the feature");
+    };
+    assert_eq!(e, "--runtime uring needs a build on Linux with --features uring");
+}
+
+#[tokio::test]
+async fn listeners_share_the_state_and_count_their_own_connections() {
+    let any_port = |mode| ListenerSpec { mode, addr: SocketAddr::from(([127, 0, 0, 1], 0)) };
+    let listeners = vec![any_port(ListenerMode::Text), any_port(ListenerMode::Json)];
+    let server = TestServer::start(ServerConfig { listeners, ..Default::default() }).await;
+
+    let mut text = server.connect_listener(ListenerMode::Text).await;
+    assert_eq!(text.request("SET color blue").await, "OK");
+    let mut json = server.connect_listener(ListenerMode::Json).await;
+    let reply = json.request(r#"{"cmd":"GET","args":["color"]}"#).await;
+    assert!(reply.contains(r#""data":"VALUE blue""#), "{}", reply);
+    let mut second = server.connect_listener(ListenerMode::Json).await;
+    second.request(r#"{"cmd":"COUNT"}"#).await;
+
+    // Both were bound to port 0 and got ports of their own
+    let stats = server.listeners.stats();
+    assert_ne!(stats[0].addr, stats[1].addr);
+    let accepted: Vec<_> = stats.iter().map(|l| (l.mode, l.accepted)).collect();
+    assert_eq!(accepted, [(ListenerMode::Text, 1), (ListenerMode::Json, 2)]);
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn connections_over_the_threshold_are_shed_or_wait() {
+    let shedding = Thresholds { connections: Some(1), ..Default::default() };
+    let server = TestServer::start(ServerConfig { shedding, ..Default::default() }).await;
+    let mut first = server.connect().await;
+    assert_eq!(first.request("hello").await, "OK: 'hello' (request #1)");
+
+    let mut shed = server.connect().await;
+    assert_eq!(shed.line().await.unwrap(), "ERR: server overloaded, try again later");
+    assert_eq!(shed.line().await, None);
+    drop(first);
+    server.stop().await;
+
+    let config = ServerConfig { shedding, shed_policy: ShedPolicy::Delay, ..Default::default() };
+    let server = TestServer::start(config).await;
+    let mut first = server.connect().await;
+    assert_eq!(first.request("hello").await, "OK: 'hello' (request #1)");
+    // Connected, but not served until the first client leaves
+    let mut delayed = server.connect().await;
+    delayed.send("waiting").await;
+    let waited = tokio::time::timeout(std::time::Duration::from_millis(200), delayed.line());
+    assert!(waited.await.is_err());
+    drop(first);
+    assert_eq!(delayed.line().await.unwrap(), "OK: 'waiting' (request #2)");
+    drop(delayed);
+    server.stop().await;
+}
+
+/// Asks `client` for `key` until the reply is `expected`, for a second at most.
+async fn eventually(client: &mut Client, key: &str, expected: &str) {
+    for _ in 0..50 {
+        if client.request(&format!("GET {}", key)).await == expected {
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    panic!("GET {} never answered {}", key, expected);
+}
+
+#[tokio::test]
+async fn a_replica_follows_the_primary_and_refuses_writes() {
+    let primary = TestServer::start(ServerConfig::default()).await;
+    let mut writer = primary.connect().await;
+    assert_eq!(writer.request("SET color blue").await, "OK");
+    assert_eq!(writer.request("SET size 3").await, "OK");
+    assert_eq!(writer.request("hello").await, "OK: 'hello' (request #1)");
+
+    // The snapshot, then the changes made after it
+    let replica_of = Some(primary.handle.local_addr().to_string());
+    let replica = TestServer::start(ServerConfig { replica_of, ..Default::default() }).await;
+    let mut reader = replica.connect().await;
+    eventually(&mut reader, "color", "VALUE blue").await;
+    assert_eq!(writer.request("DEL size").await, "DELETED");
+    assert_eq!(writer.request("MULTI").await, "OK");
+    writer.request("SET a 1").await;
+    writer.request("GETSET color red").await;
+    assert_eq!(writer.request("EXEC").await, "OK");
+    assert_eq!(writer.line().await.unwrap(), "VALUE blue");
+    assert_eq!(writer.line().await.unwrap(), "END");
+    eventually(&mut reader, "color", "VALUE red").await;
+    assert_eq!(reader.request("GET size").await, "NIL");
+    assert_eq!(reader.request("GET a").await, "VALUE 1");
+
+    // Writes are the primary's; plain messages get its count
+    let refused = format!("ERR: read-only replica of {}", primary.handle.local_addr());
+    assert_eq!(reader.request("SET color green").await, refused);
+    assert_eq!(writer.request("again").await, "OK: 'again' (request #2)");
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    assert_eq!(reader.request("count me").await, "OK: 'count me' (request #2)");
+    drop(reader);
+    replica.stop().await;
+    drop(writer);
+    primary.stop().await;
+}
+
+/// A self-signed certificate for `localhost` and two of its subdomains,
+/// written to a directory of its own.
+fn certificate(name: &str) -> (PathBuf, CertificateDer<'static>) {
+    let dir = std::env::temp_dir().join(format!("tcp-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let names = ["localhost", "kv.localhost", "www.localhost"].map(String::from);
+    let rcgen::CertifiedKey { cert, signing_key } =
+        rcgen::generate_simple_self_signed(names.to_vec()).unwrap();
+    std::fs::write(dir.join("cert.pem"), cert.pem()).unwrap();
+    std::fs::write(dir.join("key.pem"), signing_key.serialize_pem()).unwrap();
+    (dir, cert.der().clone())
+}
+
+/// A client certificate and its key.
+type ClientCert = (CertificateDer<'static>, PrivateKeyDer<'static>);
+
+/// A CA for client certificates.
+fn client_ca() -> CertifiedIssuer<'static, KeyPair> {
+    let mut params = CertificateParams::new(Vec::new()).unwrap();
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.distinguished_name.push(DnType::CommonName, "test client CA");
+    CertifiedIssuer::self_signed(params, KeyPair::generate().unwrap()).unwrap()
+}
+
+/// A client certificate for `name`, signed by `ca`.
+fn client_certificate(ca: &CertifiedIssuer<'static, KeyPair>, name: &str) -> ClientCert {
+    let mut params = CertificateParams::new(Vec::new()).unwrap();
+    params.distinguished_name.push(DnType::CommonName, name);
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+    let key = KeyPair::generate().unwrap();
+    let cert = params.signed_by(&key, ca).unwrap();
+    let key = PrivateKeyDer::from(PrivatePkcs8KeyDer::from(key.serialize_der()));
+    (cert.der().clone(), key)
+}
+
+/// Connects over TLS, trusting only `cert`, and offers one ALPN protocol.
+async fn connect_tls(
+    addr: SocketAddr,
+    cert: &CertificateDer<'static>,
+    alpn: &str,
+) -> TlsStream<TcpStream> {
+    connect_tls_as(addr, cert, alpn, None).await
+}
+
+/// Like `connect_tls`, presenting `client` if the server asks for a certificate.
+async fn connect_tls_as(
+    addr: SocketAddr,
+    cert: &CertificateDer<'static>,
+    alpn: &str,
+    client: Option<ClientCert>,
+) -> TlsStream<TcpStream> {
+    connect_tls_to(addr, cert, "localhost", alpn, client).await
+}
+
+/// Like `connect_tls_as`, naming `host` with SNI.
+async fn connect_tls_to(
+    addr: SocketAddr,
+    cert: &CertificateDer<'static>,
+    host: &str,
+    alpn: &str,
+    client: Option<ClientCert>,
+) -> TlsStream<TcpStream> {
+    let mut roots = RootCertStore::empty();
+    roots.add(cert.clone()).unwrap();
+    let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots);
+    let mut config = match client {
+        Some((cert, key)) => builder.with_client_auth_cert(vec![cert], key).unwrap(),
+        None => builder.with_no_client_auth(),
+    };
+    config.alpn_protocols = vec![alpn.as_bytes().to_vec()];
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let name = ServerName::try_from(host.to_string()).unwrap();
+    TlsConnector::from(Arc::new(config)).connect(name, stream).await.unwrap()
+}
+
+/// Sends one line over TLS and returns the reply; `None` if the server
+/// closed the connection, or the handshake failed after all.
+async fn tls_request(stream: TlsStream<TcpStream>, message: &str) -> Option<String> {
+    let (reader, mut writer) = tokio::io::split(stream);
+    writer.write_all(format!("{}\n", message).as_bytes()).await.ok()?;
+    BufReader::new(reader).lines().next_line().await.ok().flatten()
+}
+
+#[tokio::test]
+async fn the_tls_listener_routes_by_alpn() {
+    let (dir, cert) = certificate("alpn");
+    let config = ServerConfig {
+        tls_listen: Some(SocketAddr::from(([127, 0, 0, 1], 0))),
+        tls_cert: Some(dir.join("cert.pem")),
+        tls_key: Some(dir.join("key.pem")),
+        ..Default::default()
+    };
+    let server = TestServer::start(config).await;
+    let addr = server.listeners.local_addr(ListenerMode::Tls).unwrap();
+
+    // `echo/1` is the text protocol
+    let stream = connect_tls(addr, &cert, "echo/1").await;
+    assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"echo/1"[..]));
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut lines = BufReader::new(reader).lines();
+    writer.write_all(b"hello\n").await.unwrap();
+    assert_eq!(lines.next_line().await.unwrap().unwrap(), "OK: 'hello' (request #1)");
+
+    // `h2` on the same port is the HTTP/2 endpoint, with the same counter
+    let stream = connect_tls(addr, &cert, "h2").await;
+    let (client, connection) = h2::client::handshake(stream).await.unwrap();
+    tokio::spawn(connection);
+    let mut client = client.ready().await.unwrap();
+    let request = http::Request::get("https://localhost/count").body(()).unwrap();
+    let (response, _) = client.send_request(request, true).unwrap();
+    let mut body = response.await.unwrap().into_body();
+    let mut count = Vec::new();
+    while let Some(chunk) = body.data().await {
+        count.extend_from_slice(&chunk.unwrap());
+    }
+    assert_eq!(count, b"1\n");
+
+    drop((client, lines, writer));
+    server.stop().await;
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn client_certificates_grant_the_permission_of_their_identity() {
+    let (dir, cert) = certificate("mtls");
+    let ca = client_ca();
+    std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+    let config = ServerConfig {
+        tls_listen: Some(SocketAddr::from(([127, 0, 0, 1], 0))),
+        tls_cert: Some(dir.join("cert.pem")),
+        tls_key: Some(dir.join("key.pem")),
+        tls_client_ca: Some(dir.join("ca.pem")),
+        tls_identities: vec![
+            CertIdentity::parse("ops:admin").unwrap(),
+            CertIdentity::parse("dashboard:read-only").unwrap(),
+        ],
+        require_auth: true,
+        ..Default::default()
+    };
+    let server = TestServer::start(config).await;
+    let addr = server.listeners.local_addr(ListenerMode::Tls).unwrap();
+    let connect = |name: &str| {
+        let client = client_certificate(&ca, name);
+        connect_tls_as(addr, &cert, "echo/1", Some(client))
+    };
+
+    // No AUTH needed: the certificate is enough, with what its identity may do
+    let reply = tls_request(connect("ops").await, "SET color blue").await;
+    assert_eq!(reply.as_deref(), Some("OK"));
+    let reply = tls_request(connect("dashboard").await, "SET color red").await;
+    assert_eq!(reply.as_deref(), Some("ERR: the certificate is read-only"));
+    let reply = tls_request(connect("dashboard").await, "GET color").await;
+    assert_eq!(reply.as_deref(), Some("VALUE blue"));
+    // A valid certificate for an identity the config does not list is no token
+    let reply = tls_request(connect("guest").await, "GET color").await;
+    assert_eq!(reply.as_deref(), Some("ERR: authenticate first, with AUTH <token>"));
+
+    // Without a certificate from the CA, the handshake fails
+    let stranger = client_certificate(&client_ca(), "ops");
+    let anonymous = connect_tls_as(addr, &cert, "echo/1", None).await;
+    assert_eq!(tls_request(anonymous, "GET color").await, None);
+    let forged = connect_tls_as(addr, &cert, "echo/1", Some(stranger)).await;
+    assert_eq!(tls_request(forged, "GET color").await, None);
+
+    server.stop().await;
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn the_tls_listener_routes_by_sni_to_virtual_hosts() {
+    let (dir, cert) = certificate("sni");
+    let config = ServerConfig {
+        tls_listen: Some(SocketAddr::from(([127, 0, 0, 1], 0))),
+        tls_cert: Some(dir.join("cert.pem")),
+        tls_key: Some(dir.join("key.pem")),
+        tls_hosts: vec![
+            VirtualHost::parse("kv.localhost mode=kv banner=Welcome to KV").unwrap(),
+            VirtualHost::parse("*.localhost banner=Hi there").unwrap(),
+        ],
+        ..Default::default()
+    };
+    let server = TestServer::start(config).await;
+    let addr = server.listeners.local_addr(ListenerMode::Tls).unwrap();
+    let connect = async |host| {
+        let stream = connect_tls_to(addr, &cert, host, "echo/1", None).await;
+        let (reader, writer) = tokio::io::split(stream);
+        (BufReader::new(reader).lines(), writer)
+    };
+
+    // Greeted by the host, and already in its mode
+    let (mut kv, mut kv_writer) = connect("kv.localhost").await;
+    assert_eq!(kv.next_line().await.unwrap().unwrap(), "Welcome to KV");
+    kv_writer.write_all(b"hello\nSET color blue\n").await.unwrap();
+    let refused = "ERR: KV mode only takes key-value commands and MULTI";
+    assert_eq!(kv.next_line().await.unwrap().unwrap(), refused);
+    assert_eq!(kv.next_line().await.unwrap().unwrap(), "OK");
+
+    // Any other subdomain gets the wildcard host, which keeps the defaults
+    let (mut www, mut www_writer) = connect("www.localhost").await;
+    assert_eq!(www.next_line().await.unwrap().unwrap(), "Hi there");
+    www_writer.write_all(b"hello\n").await.unwrap();
+    assert_eq!(www.next_line().await.unwrap().unwrap(), "OK: 'hello' (request #1)");
+
+    // No host for `localhost` itself: served as if there were no hosts
+    let (mut plain, mut plain_writer) = connect("localhost").await;
+    plain_writer.write_all(b"hello\n").await.unwrap();
+    assert_eq!(plain.next_line().await.unwrap().unwrap(), "OK: 'hello' (request #2)");
+
+    let mut client = server.connect().await;
+    client.send("STATS").await;
+    let mut stats = Vec::new();
+    while let Some(line) = client.line().await.filter(|line| line != "END") {
+        stats.push(line);
+    }
+    for expected in [
+        "STAT tls_vhost_routed 2",
+        "STAT tls_vhost_unmatched 1",
+        "VHOST *.localhost connections=1 active=1",
+        "VHOST kv.localhost connections=1 active=1",
+    ] {
+        assert!(stats.iter().any(|line| line == expected), "{} not in {:?}", expected, stats);
+    }
+
+    drop((kv, kv_writer, www, www_writer, plain, plain_writer, client));
+    server.stop().await;
+    std::fs::remove_dir_all(dir).unwrap();
+}